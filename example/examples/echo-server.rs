@@ -65,6 +65,7 @@ fn main() {
                 let public_key = include_bytes!("test-public-key.der").to_vec();
                 secio::SecioConfig {
                     key: secio::SecioKeyPair::rsa_from_pkcs8(private_key, public_key).unwrap(),
+                    cpu_pool: None,
                 }
             };
 
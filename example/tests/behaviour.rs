@@ -0,0 +1,156 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Two behaviours combined in a `BehaviourSwarm`, sharing the connections of a `ConnectionReuse`.
+//! Checks that the substreams, the dialing failures and the events are routed to the behaviour
+//! they belong to.
+
+extern crate futures;
+extern crate libp2p_swarm as swarm;
+extern crate libp2p_tcp_transport as tcp;
+extern crate multiplex;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use futures::{Async, Future, Stream};
+use std::collections::VecDeque;
+use std::io::Error as IoError;
+use swarm::{BehaviourSwarm, CombinedBehaviour, CombinedEvent, Endpoint, Multiaddr};
+use swarm::{NetworkBehaviour, NetworkBehaviourAction, SimpleProtocol, Transport};
+use tcp::TcpConfig;
+use tokio_core::reactor::Core;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RecorderEvent {
+    Substream(Endpoint),
+    DialFailure(Multiaddr),
+}
+
+// Behaviour that negotiates a protocol named `name`, dials the addresses of `to_dial`, and
+// reports everything that happens to it as an event.
+struct Recorder {
+    name: &'static str,
+    to_dial: VecDeque<Multiaddr>,
+    events: VecDeque<RecorderEvent>,
+}
+
+impl Recorder {
+    fn new(name: &'static str) -> Recorder {
+        Recorder {
+            name: name,
+            to_dial: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+}
+
+fn discard<C>(_: C) -> Result<(), IoError> {
+    Ok(())
+}
+
+impl<C> NetworkBehaviour<C> for Recorder
+where
+    C: AsyncRead + AsyncWrite,
+{
+    type Upgrade = SimpleProtocol<fn(C) -> Result<(), IoError>>;
+    type OutEvent = RecorderEvent;
+
+    fn upgrade(&self) -> Self::Upgrade {
+        SimpleProtocol::new(self.name, discard::<C> as fn(C) -> Result<(), IoError>)
+    }
+
+    fn inject_substream(&mut self, _: (), _: Multiaddr, endpoint: Endpoint) {
+        self.events.push_back(RecorderEvent::Substream(endpoint));
+    }
+
+    fn inject_dial_failure(&mut self, addr: &Multiaddr, _: Self::Upgrade, _: &IoError) {
+        self.events.push_back(RecorderEvent::DialFailure(addr.clone()));
+    }
+
+    fn poll(&mut self) -> Async<NetworkBehaviourAction<Self::Upgrade, Self::OutEvent>> {
+        if let Some(addr) = self.to_dial.pop_front() {
+            let upgrade = NetworkBehaviour::<C>::upgrade(self);
+            return Async::Ready(NetworkBehaviourAction::Dial {
+                addr: addr,
+                upgrade: upgrade,
+            });
+        }
+        match self.events.pop_front() {
+            Some(event) => Async::Ready(NetworkBehaviourAction::GenerateEvent(event)),
+            None => Async::NotReady,
+        }
+    }
+}
+
+#[test]
+fn events_routed_to_their_behaviour() {
+    let mut core = Core::new().unwrap();
+    let transport = |core: &Core| {
+        TcpConfig::new(core.handle())
+            .with_upgrade(multiplex::MultiplexConfig)
+            .into_connection_reuse()
+    };
+
+    let server_behaviour =
+        CombinedBehaviour::new(Recorder::new("/first"), Recorder::new("/second"));
+    let mut server = BehaviourSwarm::new(transport(&core), server_behaviour);
+    let listen_addr = server
+        .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+
+    // Both behaviours of the client dial the server, and the second one also dials an address
+    // that the transport doesn't support.
+    let unsupported: Multiaddr = "/ip4/127.0.0.1/udp/1234".parse().unwrap();
+    let mut first = Recorder::new("/first");
+    first.to_dial.push_back(listen_addr.clone());
+    let mut second = Recorder::new("/second");
+    second.to_dial.push_back(unsupported.clone());
+    second.to_dial.push_back(listen_addr);
+    let client = BehaviourSwarm::new(transport(&core), CombinedBehaviour::new(first, second));
+
+    let server_events = server.take(2).collect();
+    let client_events = client.take(3).collect();
+    let (mut server_events, client_events) =
+        core.run(server_events.join(client_events)).unwrap();
+
+    server_events.sort_by_key(|event| match *event {
+        CombinedEvent::First(_) => 0,
+        CombinedEvent::Second(_) => 1,
+    });
+    assert_eq!(
+        server_events,
+        vec![
+            CombinedEvent::First(RecorderEvent::Substream(Endpoint::Listener)),
+            CombinedEvent::Second(RecorderEvent::Substream(Endpoint::Listener)),
+        ]
+    );
+
+    assert_eq!(client_events.len(), 3);
+    assert!(client_events.contains(&CombinedEvent::First(RecorderEvent::Substream(
+        Endpoint::Dialer
+    ))));
+    assert!(client_events.contains(&CombinedEvent::Second(RecorderEvent::Substream(
+        Endpoint::Dialer
+    ))));
+    assert!(client_events.contains(&CombinedEvent::Second(RecorderEvent::DialFailure(
+        unsupported
+    ))));
+}
@@ -0,0 +1,157 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Smoke test of the whole stack: two nodes connect over TCP, encrypt the connection with secio,
+//! multiplex it with mplex, then identify and ping each other on substreams of this connection.
+//!
+//! This test is meant to catch the changes in one crate that break the others. It doesn't check
+//! the protocols in depth, as each crate has its own tests for that.
+
+extern crate futures;
+extern crate libp2p_identify as identify;
+extern crate libp2p_ping as ping;
+extern crate libp2p_secio as secio;
+extern crate libp2p_swarm as swarm;
+extern crate libp2p_tcp_transport as tcp;
+extern crate multiplex;
+extern crate tokio_core;
+
+use futures::{Future, Stream};
+use identify::{IdentifyInfo, IdentifyOutput, IdentifyProtocolConfig, PublicKey};
+use swarm::transport::{EitherSocket, WithLocalIdentity};
+use swarm::{ConnectionReuse, SwarmEvent, Transport, UpgradeExt, UpgradedNode};
+use tcp::TcpConfig;
+use tokio_core::reactor::{Core, Handle};
+
+// Builds the transport of a node: TCP, then secio, then mplex.
+fn transport(
+    handle: Handle,
+    private_key: &[u8],
+    public_key: Vec<u8>,
+) -> ConnectionReuse<
+    UpgradedNode<WithLocalIdentity<TcpConfig>, secio::SecioConfig>,
+    multiplex::MultiplexConfig,
+> {
+    let key = secio::SecioKeyPair::rsa_from_pkcs8(private_key, public_key).unwrap();
+    let identity = key.local_identity();
+    let secio = secio::SecioConfig {
+        key: key,
+        cpu_pool: None,
+    };
+
+    TcpConfig::new(handle)
+        .with_local_identity(identity)
+        .with_upgrade(secio)
+        .with_upgrade(multiplex::MultiplexConfig)
+        .into_connection_reuse()
+}
+
+#[test]
+fn full_stack() {
+    let mut core = Core::new().unwrap();
+
+    let server_public_key = include_bytes!("../../libp2p-secio/tests/test-public-key.der").to_vec();
+    let server_transport = transport(
+        core.handle(),
+        include_bytes!("../../libp2p-secio/tests/test-private-key.pk8"),
+        server_public_key.clone(),
+    );
+    let client_transport = transport(
+        core.handle(),
+        include_bytes!("../../libp2p-secio/tests/test-private-key-2.pk8"),
+        include_bytes!("../../libp2p-secio/tests/test-public-key-2.der").to_vec(),
+    );
+
+    // The server answers the pings and the identify requests of the client.
+    let info = IdentifyInfo {
+        public_key: PublicKey::Rsa(server_public_key),
+        protocol_version: "smoke-test/1.0.0".to_owned(),
+        agent_version: "smoke-test-server".to_owned(),
+        listen_addrs: Vec::new(),
+        protocols: vec!["/ipfs/ping/1.0.0".to_owned(), "/ipfs/id/1.0.0".to_owned()],
+        signed_record: None,
+        metadata: Vec::new(),
+    };
+    let (server, server_future) = swarm::swarm(
+        server_transport,
+        ping::Ping.or_upgrade(IdentifyProtocolConfig::new()),
+        move |output, _| match output {
+            EitherSocket::First((_pinger, service)) => service,
+            EitherSocket::Second(IdentifyOutput::Sender {
+                sender,
+                observed_addr,
+            }) => sender.send(info.clone(), &observed_addr),
+            EitherSocket::Second(IdentifyOutput::RemoteInfo { .. }) => {
+                panic!("the server never dials")
+            }
+        },
+    );
+    let listen_addr = server
+        .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    let server_events = server.events();
+    core.handle().spawn(server_future.map_err(|err| panic!("{:?}", err)));
+
+    // The client first identifies the server, then pings it on the same connection.
+    let ping_transport = client_transport.clone();
+    let ping_addr = listen_addr.clone();
+    let client = client_transport
+        .with_upgrade(IdentifyProtocolConfig::new())
+        .dial(listen_addr)
+        .unwrap_or_else(|_| panic!())
+        .map(|(output, _)| match output {
+            IdentifyOutput::RemoteInfo { info, observed_addr } => {
+                assert_eq!(info.agent_version, "smoke-test-server");
+                assert!(info.protocols.contains(&"/ipfs/ping/1.0.0".to_owned()));
+                assert!(observed_addr.is_some());
+            }
+            IdentifyOutput::Sender { .. } => panic!("the client is the dialer"),
+        })
+        .and_then(move |()| {
+            ping_transport
+                .with_upgrade(ping::Ping)
+                .dial(ping_addr)
+                .unwrap_or_else(|_| panic!())
+        })
+        .and_then(|((mut pinger, service), _)| {
+            pinger
+                .ping()
+                .map_err(|_| panic!("the ping service stopped"))
+                .select(service)
+                .map(|_| ())
+                .map_err(|(err, _)| err)
+        });
+    core.run(client).unwrap();
+
+    // Once the client has dropped its transport, and with it the connection, the handlers of the
+    // server finish without any error. The swarm sees one handler per substream, so there is one
+    // event for identify and one for ping even though they share the same connection.
+    let closed = server_events
+        .filter_map(|event| match event {
+            SwarmEvent::ConnectionClosed { cause, .. } => Some(cause),
+            _ => None,
+        })
+        .take(2)
+        .collect();
+    let causes = core.run(closed).unwrap();
+    for cause in causes {
+        assert!(cause.is_none(), "handler failed: {:?}", cause);
+    }
+}
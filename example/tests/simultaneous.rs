@@ -0,0 +1,272 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Two nodes using secio and identify dial each other at the same time. Both of them must keep
+//! the same connection and close the other one.
+//!
+//! Each node derives its own peer ID from `SecioKeyPair::local_identity`, and the peer ID of the
+//! other node from what the *identify* protocol reports. The test fails if the two don't agree.
+
+extern crate futures;
+extern crate libp2p_identify as identify;
+extern crate libp2p_peerstore as peerstore;
+extern crate libp2p_secio as secio;
+extern crate libp2p_swarm as swarm;
+extern crate libp2p_tcp_transport as tcp;
+extern crate multiplex;
+extern crate tokio_core;
+
+use futures::{future, Future, Stream};
+use identify::{IdentifyInfo, IdentifyOutput, IdentifyProtocolConfig, IdentifyTransport, PublicKey};
+use peerstore::memory_peerstore::MemoryPeerstore;
+use std::io::Error as IoError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use swarm::transport::{EitherSocket, WithLocalIdentity};
+use swarm::{kept_endpoint, peer_id_of, ConnectionReuse, Endpoint, LocalIdentity, Multiaddr};
+use swarm::{MuxedTransport, PeerId, SimpleProtocol, SwarmEvent, Transport, UpgradeExt};
+use swarm::UpgradedNode;
+use tcp::TcpConfig;
+use tokio_core::reactor::Core;
+
+type Reuse = ConnectionReuse<
+    UpgradedNode<WithLocalIdentity<TcpConfig>, secio::SecioConfig>,
+    multiplex::MultiplexConfig,
+>;
+type Substream = <Reuse as Transport>::RawConn;
+
+// Transport of the nodes. Dialing and listening go through an `IdentifyTransport`, so that the
+// swarm sees the remotes as `/p2p/...`.
+//
+// The substreams that the remote opens on a connection that we dialed are taken from the
+// `ConnectionReuse` directly. An `IdentifyTransport` identifies the remote again on each of them,
+// including on the substreams that the remote opens in order to identify us, and two nodes that
+// both use it would wait for each other forever.
+#[derive(Clone)]
+struct Node {
+    identify: IdentifyTransport<Reuse, Arc<MemoryPeerstore>>,
+    reuse: Reuse,
+}
+
+impl Transport for Node {
+    type RawConn = Substream;
+    type Listener = Box<Stream<Item = Self::ListenerUpgrade, Error = IoError>>;
+    type ListenerUpgrade = Box<Future<Item = (Self::RawConn, Multiaddr), Error = IoError>>;
+    type Dial = Box<Future<Item = (Self::RawConn, Multiaddr), Error = IoError>>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+        let reuse = self.reuse;
+        self.identify.listen_on(addr).map_err(move |(identify, addr)| {
+            let node = Node {
+                identify: identify,
+                reuse: reuse,
+            };
+            (node, addr)
+        })
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+        let reuse = self.reuse;
+        self.identify.dial(addr).map_err(move |(identify, addr)| {
+            let node = Node {
+                identify: identify,
+                reuse: reuse,
+            };
+            (node, addr)
+        })
+    }
+
+    fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.identify.nat_traversal(server, observed)
+    }
+
+    fn local_identity(&self) -> LocalIdentity {
+        self.identify.local_identity()
+    }
+}
+
+impl MuxedTransport for Node {
+    type Incoming = <Reuse as MuxedTransport>::Incoming;
+    type IncomingUpgrade = <Reuse as MuxedTransport>::IncomingUpgrade;
+
+    fn next_incoming(self) -> Self::Incoming {
+        self.reuse.next_incoming()
+    }
+}
+
+// Protocol whose substreams stay open until the swarm closes them.
+fn hold(socket: Substream) -> Result<Substream, IoError> {
+    Ok(socket)
+}
+
+// A running node: its peer ID, the address it listens on, a function that dials with its swarm,
+// and all the events produced by its swarm so far.
+struct Running {
+    peer_id: PeerId,
+    listen_addr: Multiaddr,
+    dial: Box<Fn(Multiaddr)>,
+    events: Arc<Mutex<Vec<SwarmEvent>>>,
+}
+
+fn start(core: &Core, private_key: &[u8], public_key: Vec<u8>) -> Running {
+    let key = secio::SecioKeyPair::rsa_from_pkcs8(private_key, public_key.clone()).unwrap();
+    let identity = key.local_identity();
+    let peer_id = PeerId::from_public_key(identity.public_key().unwrap());
+    let secio = secio::SecioConfig {
+        key: key,
+        cpu_pool: None,
+    };
+    let reuse = TcpConfig::new(core.handle())
+        .with_local_identity(identity)
+        .with_upgrade(secio)
+        .with_upgrade(multiplex::MultiplexConfig)
+        .into_connection_reuse();
+    let node = Node {
+        identify: IdentifyTransport::new(reuse.clone(), Arc::new(MemoryPeerstore::empty())),
+        reuse: reuse,
+    };
+
+    let info = IdentifyInfo {
+        public_key: PublicKey::Rsa(public_key),
+        protocol_version: "simultaneous-test/1.0.0".to_owned(),
+        agent_version: "simultaneous-test".to_owned(),
+        listen_addrs: Vec::new(),
+        protocols: vec!["/hold/1.0.0".to_owned(), "/ipfs/id/1.0.0".to_owned()],
+        signed_record: None,
+        metadata: Vec::new(),
+    };
+    let upgrade = SimpleProtocol::new("/hold/1.0.0", hold as fn(_) -> _)
+        .or_upgrade(IdentifyProtocolConfig::new());
+    let (controller, future) = swarm::swarm(node, upgrade.clone(), move |output, _| {
+        match output {
+            EitherSocket::First(socket) => {
+                let held = future::empty().map(move |()| drop(socket));
+                Box::new(held) as Box<Future<Item = (), Error = IoError>>
+            }
+            EitherSocket::Second(IdentifyOutput::Sender {
+                sender,
+                observed_addr,
+            }) => sender.send(info.clone(), &observed_addr),
+            EitherSocket::Second(IdentifyOutput::RemoteInfo { .. }) => {
+                panic!("the swarms never dial with identify")
+            }
+        }
+    });
+
+    let listen_addr = controller
+        .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    core.handle().spawn(controller.events().for_each(move |event| {
+        recorded.lock().unwrap().push(event);
+        Ok(())
+    }));
+    core.handle().spawn(future.map_err(|err| panic!("{:?}", err)));
+
+    let dial = move |addr| controller.dial_to_handler(addr, upgrade.clone()).unwrap();
+    Running {
+        peer_id: peer_id,
+        listen_addr: listen_addr,
+        dial: Box::new(dial),
+        events: events,
+    }
+}
+
+// Returns the connections with a `/p2p/` address that are open according to `events`, and
+// whether our own dialing attempt has finished.
+fn open_connections(events: &[SwarmEvent]) -> (Vec<(PeerId, Endpoint)>, bool) {
+    let mut open = Vec::new();
+    let mut dial_finished = false;
+    for event in events {
+        match *event {
+            SwarmEvent::ConnectionEstablished {
+                ref remote_addr,
+                endpoint,
+            } => {
+                if endpoint == Endpoint::Dialer {
+                    dial_finished = true;
+                }
+                if let Some(peer) = peer_id_of(remote_addr) {
+                    open.push((peer, endpoint));
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                ref remote_addr,
+                endpoint,
+                ..
+            } => {
+                if let Some(peer) = peer_id_of(remote_addr) {
+                    let closed = (peer, endpoint);
+                    if let Some(n) = open.iter().position(|c| *c == closed) {
+                        open.remove(n);
+                    }
+                }
+            }
+            SwarmEvent::DialFailure { .. } => dial_finished = true,
+            _ => {}
+        }
+    }
+    (open, dial_finished)
+}
+
+#[test]
+fn one_connection_survives() {
+    let mut core = Core::new().unwrap();
+
+    let first = start(
+        &core,
+        include_bytes!("../../libp2p-secio/tests/test-private-key.pk8"),
+        include_bytes!("../../libp2p-secio/tests/test-public-key.der").to_vec(),
+    );
+    let second = start(
+        &core,
+        include_bytes!("../../libp2p-secio/tests/test-private-key-2.pk8"),
+        include_bytes!("../../libp2p-secio/tests/test-public-key-2.der").to_vec(),
+    );
+
+    (first.dial)(second.listen_addr.clone());
+    (second.dial)(first.listen_addr.clone());
+
+    // Wait until both dialing attempts have finished and each node has a single connection left.
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let (first_open, second_open) = loop {
+        let (first_open, first_done) = open_connections(&first.events.lock().unwrap());
+        let (second_open, second_done) = open_connections(&second.events.lock().unwrap());
+        if first_done && second_done && first_open.len() == 1 && second_open.len() == 1 {
+            break (first_open, second_open);
+        }
+        assert!(
+            Instant::now() < deadline,
+            "connections still open: {:?} and {:?}",
+            first_open,
+            second_open
+        );
+        core.turn(Some(Duration::from_millis(100)));
+    };
+
+    // The identify protocol reported the same peer IDs as the ones derived from the local
+    // identities, and both nodes kept the connection dialed by the greatest peer ID.
+    assert_eq!(first_open[0].0, second.peer_id);
+    assert_eq!(second_open[0].0, first.peer_id);
+    let kept = kept_endpoint(&first.peer_id, &second.peer_id).unwrap();
+    assert_eq!(first_open[0].1, kept);
+    assert_ne!(second_open[0].1, kept);
+}
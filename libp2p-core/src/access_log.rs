@@ -0,0 +1,368 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `AccessLog` connection upgrade, which records every negotiated substream.
+//!
+//! Wrapping an upgrade with `UpgradeExt::with_access_log` makes it so that, whenever the
+//! upgrade is applied, an `AccessLogEntry` is produced once the socket is closed or destroyed.
+//! The entry contains the address of the remote, the name of the negotiated protocol, when the
+//! substream was opened and for how long, the number of bytes exchanged, and why it was closed.
+//!
+//! Entries are passed to an `AccessLogSink`, which is implemented on closures and can be
+//! implemented manually in order to write the entries to a file or to a database.
+//!
+//! > **Note**: The remote is identified by its multiaddress, as the upgrades don't know about
+//! >           peer IDs.
+
+use bytes::Bytes;
+use futures::Poll;
+use multiaddr::Multiaddr;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::{ConnectionUpgrade, Endpoint, LocalIdentity};
+
+/// Destination of the entries of an `AccessLog`.
+pub trait AccessLogSink {
+    /// Records an entry.
+    fn log(&self, entry: AccessLogEntry);
+}
+
+impl<F> AccessLogSink for F
+where
+    F: Fn(AccessLogEntry),
+{
+    #[inline]
+    fn log(&self, entry: AccessLogEntry) {
+        self(entry)
+    }
+}
+
+/// A single entry of the access log. Corresponds to one substream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogEntry {
+    /// Address of the remote.
+    pub remote_addr: Multiaddr,
+    /// Whether we opened the substream or the remote did.
+    pub endpoint: Endpoint,
+    /// Name of the negotiated protocol.
+    pub protocol: Bytes,
+    /// When the protocol was negotiated.
+    pub opened_at: SystemTime,
+    /// How long the substream stayed open.
+    pub duration: Duration,
+    /// Number of bytes read from the socket.
+    pub bytes_received: u64,
+    /// Number of bytes written to the socket.
+    pub bytes_sent: u64,
+    /// Why the substream was closed.
+    pub close_reason: CloseReason,
+}
+
+/// Reason why a substream of an access log has been closed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The remote closed its side of the substream.
+    RemoteClosed,
+    /// We shut down our side of the substream.
+    LocalClosed,
+    /// Reading or writing produced an error.
+    Error(IoErrorKind),
+    /// The socket was destroyed without having been closed.
+    Dropped,
+}
+
+/// Implementation of `ConnectionUpgrade` that records the substreams it upgrades. Returned by
+/// `UpgradeExt::with_access_log`.
+pub struct AccessLog<U, S> {
+    upgrade: U,
+    sink: Arc<S>,
+}
+
+impl<U, S> AccessLog<U, S> {
+    /// Builds a new `AccessLog` that sends its entries to `sink`.
+    #[inline]
+    pub fn new(upgrade: U, sink: S) -> AccessLog<U, S> {
+        AccessLog {
+            upgrade: upgrade,
+            sink: Arc::new(sink),
+        }
+    }
+}
+
+impl<U, S> Clone for AccessLog<U, S>
+where
+    U: Clone,
+{
+    #[inline]
+    fn clone(&self) -> Self {
+        AccessLog {
+            upgrade: self.upgrade.clone(),
+            sink: self.sink.clone(),
+        }
+    }
+}
+
+impl<C, U, S> ConnectionUpgrade<C> for AccessLog<U, S>
+where
+    C: AsyncRead + AsyncWrite,
+    U: ConnectionUpgrade<LoggedSocket<C, S>>,
+    S: AccessLogSink,
+{
+    type NamesIter = AccessLogNames<U::NamesIter>;
+    type UpgradeIdentifier = (Bytes, U::UpgradeIdentifier);
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        AccessLogNames {
+            inner: self.upgrade.protocol_names(),
+        }
+    }
+
+    type Output = U::Output;
+    type Future = U::Future;
+
+    #[inline]
+    fn upgrade(
+        self,
+        socket: C,
+        (protocol, id): Self::UpgradeIdentifier,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        let socket = LoggedSocket {
+            inner: socket,
+            sink: self.sink,
+            remote_addr: remote_addr.clone(),
+            endpoint: ty,
+            protocol: protocol,
+            opened_at: SystemTime::now(),
+            opened_instant: Instant::now(),
+            bytes_received: 0,
+            bytes_sent: 0,
+            close_reason: None,
+        };
+
+        self.upgrade.upgrade(socket, id, ty, remote_addr, local_identity)
+    }
+}
+
+/// Iterator returned by `AccessLog::protocol_names`. Remembers the name of each protocol in its
+/// identifier.
+pub struct AccessLogNames<I> {
+    inner: I,
+}
+
+impl<I, Id> Iterator for AccessLogNames<I>
+where
+    I: Iterator<Item = (Bytes, Id)>,
+{
+    type Item = (Bytes, (Bytes, Id));
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(name, id)| (name.clone(), (name, id)))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Socket passed to the upgrade wrapped by an `AccessLog`. Produces an entry when destroyed.
+pub struct LoggedSocket<C, S>
+where
+    S: AccessLogSink,
+{
+    inner: C,
+    sink: Arc<S>,
+    remote_addr: Multiaddr,
+    endpoint: Endpoint,
+    protocol: Bytes,
+    opened_at: SystemTime,
+    opened_instant: Instant,
+    bytes_received: u64,
+    bytes_sent: u64,
+    // The first reason why the socket was closed, if any.
+    close_reason: Option<CloseReason>,
+}
+
+impl<C, S> LoggedSocket<C, S>
+where
+    S: AccessLogSink,
+{
+    // Records the result of an I/O operation. `WouldBlock` isn't an error and is ignored.
+    fn record_err(&mut self, err: &IoError) {
+        if err.kind() != IoErrorKind::WouldBlock && self.close_reason.is_none() {
+            self.close_reason = Some(CloseReason::Error(err.kind()));
+        }
+    }
+}
+
+impl<C, S> Read for LoggedSocket<C, S>
+where
+    C: Read,
+    S: AccessLogSink,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        match self.inner.read(buf) {
+            Ok(0) if !buf.is_empty() => {
+                if self.close_reason.is_none() {
+                    self.close_reason = Some(CloseReason::RemoteClosed);
+                }
+                Ok(0)
+            }
+            Ok(num_read) => {
+                self.bytes_received += num_read as u64;
+                Ok(num_read)
+            }
+            Err(err) => {
+                self.record_err(&err);
+                Err(err)
+            }
+        }
+    }
+}
+
+impl<C, S> AsyncRead for LoggedSocket<C, S>
+where
+    C: AsyncRead,
+    S: AccessLogSink,
+{
+    #[inline]
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<C, S> Write for LoggedSocket<C, S>
+where
+    C: Write,
+    S: AccessLogSink,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        match self.inner.write(buf) {
+            Ok(num_written) => {
+                self.bytes_sent += num_written as u64;
+                Ok(num_written)
+            }
+            Err(err) => {
+                self.record_err(&err);
+                Err(err)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        let result = self.inner.flush();
+        if let Err(ref err) = result {
+            self.record_err(err);
+        }
+        result
+    }
+}
+
+impl<C, S> AsyncWrite for LoggedSocket<C, S>
+where
+    C: AsyncWrite,
+    S: AccessLogSink,
+{
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        let result = self.inner.shutdown();
+        match result {
+            Ok(ref ready) if ready.is_ready() && self.close_reason.is_none() => {
+                self.close_reason = Some(CloseReason::LocalClosed);
+            }
+            Err(ref err) => self.record_err(err),
+            _ => (),
+        }
+        result
+    }
+}
+
+impl<C, S> Drop for LoggedSocket<C, S>
+where
+    S: AccessLogSink,
+{
+    fn drop(&mut self) {
+        self.sink.log(AccessLogEntry {
+            remote_addr: self.remote_addr.clone(),
+            endpoint: self.endpoint,
+            protocol: self.protocol.clone(),
+            opened_at: self.opened_at,
+            duration: self.opened_instant.elapsed(),
+            bytes_received: self.bytes_received,
+            bytes_sent: self.bytes_sent,
+            close_reason: self.close_reason.unwrap_or(CloseReason::Dropped),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessLog, AccessLogEntry, CloseReason};
+    use futures::Future;
+    use multiaddr::Multiaddr;
+    use parking_lot::Mutex;
+    use std::io::{Cursor, Error as IoError, Read, Write};
+    use std::sync::Arc;
+    use transport::{ConnectionUpgrade, Endpoint, LocalIdentity, SimpleProtocol};
+
+    fn exchange<S: Read + Write>(mut socket: S) -> Result<(), IoError> {
+        let mut buf = Vec::new();
+        socket.read_to_end(&mut buf)?;
+        socket.write_all(&[9, 8])?;
+        Ok(())
+    }
+
+    #[test]
+    fn entry_produced() {
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let entries2 = entries.clone();
+        let upgrade = AccessLog::new(
+            SimpleProtocol::new("/test/1.0.0", exchange),
+            move |entry: AccessLogEntry| entries2.lock().push(entry),
+        );
+
+        let (name, id) = ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&upgrade)
+            .next()
+            .unwrap();
+        assert_eq!(name, "/test/1.0.0");
+
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let socket = Cursor::new(vec![1, 2, 3]);
+        upgrade
+            .upgrade(socket, id, Endpoint::Listener, &addr, &LocalIdentity::unknown())
+            .wait()
+            .unwrap();
+
+        let entries = entries.lock();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].remote_addr, addr);
+        assert_eq!(entries[0].endpoint, Endpoint::Listener);
+        assert_eq!(entries[0].protocol, "/test/1.0.0");
+        assert_eq!(entries[0].bytes_received, 3);
+        assert_eq!(entries[0].bytes_sent, 2);
+        assert_eq!(entries[0].close_reason, CloseReason::RemoteClosed);
+    }
+}
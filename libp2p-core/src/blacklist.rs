@@ -0,0 +1,566 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `Blacklist` struct and the `BlacklistTransport` wrapper.
+//!
+//! A `Blacklist` is a list of IP ranges (in CIDR notation, for example `10.0.0.0/8`) and of
+//! multiaddress patterns (for example `/ip4/*/tcp/22`). A `BlacklistTransport` wraps around any
+//! `Transport` and refuses to dial or to accept connections from the multiaddresses that match the
+//! blacklist, so that the rules are enforced in a single place whatever the underlying transports
+//! are.
+//!
+//! # Patterns
+//!
+//! A pattern is compared with the textual representation of a multiaddress, segment by segment.
+//! A `*` segment matches any single segment, and a final `**` segment matches any number of
+//! remaining segments. For example `/ip4/*/tcp/22` matches `/ip4/1.2.3.4/tcp/22` but not
+//! `/ip4/1.2.3.4/tcp/22/ws`, while `/ip4/*/tcp/22/**` matches both.
+//!
+//! # Example
+//!
+//! ```
+//! use libp2p_core::Blacklist;
+//!
+//! // A public node that never wants to connect to private networks, nor to port 22.
+//! let blacklist = Blacklist::new()
+//!     .deny_private_ranges()
+//!     .deny_pattern("/ip4/*/tcp/22".parse().unwrap());
+//!
+//! assert!(blacklist.is_denied(&"/ip4/192.168.1.2/tcp/4001".parse().unwrap()));
+//! assert!(blacklist.is_denied(&"/ip4/1.2.3.4/tcp/22".parse().unwrap()));
+//! assert!(!blacklist.is_denied(&"/ip4/1.2.3.4/tcp/4001".parse().unwrap()));
+//! ```
+//!
+//! > **Note**: The check on incoming connections happens after the underlying transport has
+//! >           produced the connection. You should therefore wrap the blacklist around the raw
+//! >           transport, before calling `with_upgrade`, so that no handshake is performed with a
+//! >           denied remote.
+
+use futures::{future, Async, Future, IntoFuture, Poll, Stream};
+use multiaddr::{AddrComponent, Multiaddr};
+use std::error;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::Arc;
+use transport::{LocalIdentity, MuxedTransport, Transport};
+
+/// List of IP ranges and multiaddress patterns that must not be dialed or accepted.
+///
+/// See [the module-level documentation](index.html).
+#[derive(Debug, Clone, Default)]
+pub struct Blacklist {
+    ranges: Vec<IpRange>,
+    patterns: Vec<MultiaddrPattern>,
+}
+
+impl Blacklist {
+    /// Builds an empty blacklist, which doesn't deny anything.
+    #[inline]
+    pub fn new() -> Blacklist {
+        Blacklist {
+            ranges: Vec::new(),
+            patterns: Vec::new(),
+        }
+    }
+
+    /// Denies all the multiaddresses whose IP address belongs to `range`.
+    #[inline]
+    pub fn deny_range(mut self, range: IpRange) -> Blacklist {
+        self.ranges.push(range);
+        self
+    }
+
+    /// Denies all the multiaddresses that match `pattern`.
+    #[inline]
+    pub fn deny_pattern(mut self, pattern: MultiaddrPattern) -> Blacklist {
+        self.patterns.push(pattern);
+        self
+    }
+
+    /// Denies the private IPv4 ranges of RFC 1918, the shared address space of RFC 6598 and the
+    /// IPv6 unique local addresses.
+    pub fn deny_private_ranges(self) -> Blacklist {
+        let ranges = [
+            IpRange::new(Ipv4Addr::new(10, 0, 0, 0).into(), 8),
+            IpRange::new(Ipv4Addr::new(172, 16, 0, 0).into(), 12),
+            IpRange::new(Ipv4Addr::new(192, 168, 0, 0).into(), 16),
+            IpRange::new(Ipv4Addr::new(100, 64, 0, 0).into(), 10),
+            IpRange::new(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0).into(), 7),
+        ];
+
+        ranges.iter().fold(self, |blacklist, range| {
+            blacklist.deny_range(range.clone().expect("the prefix lengths above are valid"))
+        })
+    }
+
+    /// Returns the IP ranges that are denied, in the order in which they were added.
+    #[inline]
+    pub fn ranges(&self) -> &[IpRange] {
+        &self.ranges
+    }
+
+    /// Returns the multiaddress patterns that are denied, in the order in which they were added.
+    #[inline]
+    pub fn patterns(&self) -> &[MultiaddrPattern] {
+        &self.patterns
+    }
+
+    /// Returns true if `addr` matches any of the rules of the blacklist.
+    pub fn is_denied(&self, addr: &Multiaddr) -> bool {
+        if !self.ranges.is_empty() {
+            for component in addr.iter() {
+                let ip: IpAddr = match component {
+                    AddrComponent::IP4(ip) => ip.into(),
+                    AddrComponent::IP6(ip) => ip.into(),
+                    _ => continue,
+                };
+
+                if self.ranges.iter().any(|range| range.contains(&ip)) {
+                    return true;
+                }
+            }
+        }
+
+        if !self.patterns.is_empty() {
+            let addr = addr.to_string();
+            if self.patterns.iter().any(|pattern| pattern.matches_str(&addr)) {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+/// Range of IP addresses, in the CIDR notation. Can be parsed from a string such as
+/// `192.168.0.0/16` or `fc00::/7`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// Builds the range of the addresses whose first `prefix_len` bits are the same as the ones of
+    /// `network`.
+    ///
+    /// Returns an error if `prefix_len` is larger than the number of bits of the address.
+    pub fn new(network: IpAddr, prefix_len: u8) -> Result<IpRange, BlacklistParseError> {
+        let max_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        if prefix_len > max_len {
+            return Err(BlacklistParseError::InvalidPrefixLength);
+        }
+
+        Ok(IpRange {
+            network,
+            prefix_len,
+        })
+    }
+
+    /// Returns true if `ip` belongs to this range.
+    ///
+    /// An IPv4 address never belongs to an IPv6 range and vice versa.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (&self.network, ip) {
+            (&IpAddr::V4(ref network), &IpAddr::V4(ref ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (&IpAddr::V6(ref network), &IpAddr::V6(ref ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = BlacklistParseError;
+
+    fn from_str(s: &str) -> Result<IpRange, BlacklistParseError> {
+        let mut parts = s.splitn(2, '/');
+        let network = parts
+            .next()
+            .and_then(|network| network.parse::<IpAddr>().ok())
+            .ok_or(BlacklistParseError::InvalidIpAddress)?;
+
+        match parts.next() {
+            Some(prefix_len) => {
+                let prefix_len = prefix_len
+                    .parse()
+                    .map_err(|_| BlacklistParseError::InvalidPrefixLength)?;
+                IpRange::new(network, prefix_len)
+            }
+            // A single address.
+            None => {
+                let prefix_len = if network.is_ipv4() { 32 } else { 128 };
+                IpRange::new(network, prefix_len)
+            }
+        }
+    }
+}
+
+impl fmt::Display for IpRange {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+// Returns true if the first `prefix_len` bits of `a` and `b` are equal.
+fn prefix_matches(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    if a[..full_bytes] != b[..full_bytes] {
+        return false;
+    }
+
+    let remaining_bits = prefix_len % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - remaining_bits);
+    (a[full_bytes] & mask) == (b[full_bytes] & mask)
+}
+
+/// Pattern that matches the textual representation of multiaddresses.
+///
+/// See [the module-level documentation](index.html) for the syntax.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiaddrPattern {
+    segments: Vec<String>,
+}
+
+impl MultiaddrPattern {
+    /// Returns true if `addr` matches the pattern.
+    #[inline]
+    pub fn matches(&self, addr: &Multiaddr) -> bool {
+        self.matches_str(&addr.to_string())
+    }
+
+    fn matches_str(&self, addr: &str) -> bool {
+        // We skip the empty segment before the leading `/`.
+        let mut addr_segments = addr.split('/').skip(1);
+
+        for segment in self.segments.iter() {
+            if segment == "**" {
+                return true;
+            }
+
+            match addr_segments.next() {
+                Some(addr_segment) if segment == "*" || segment == addr_segment => (),
+                _ => return false,
+            }
+        }
+
+        addr_segments.next().is_none()
+    }
+}
+
+impl FromStr for MultiaddrPattern {
+    type Err = BlacklistParseError;
+
+    fn from_str(s: &str) -> Result<MultiaddrPattern, BlacklistParseError> {
+        if !s.starts_with('/') {
+            return Err(BlacklistParseError::InvalidPattern);
+        }
+
+        let segments: Vec<String> = s.split('/').skip(1).map(|s| s.to_owned()).collect();
+        let double_star_pos = segments.iter().position(|s| s == "**");
+        if segments.iter().any(|s| s.is_empty())
+            || double_star_pos.map(|pos| pos != segments.len() - 1).unwrap_or(false)
+        {
+            return Err(BlacklistParseError::InvalidPattern);
+        }
+
+        Ok(MultiaddrPattern { segments })
+    }
+}
+
+impl fmt::Display for MultiaddrPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for segment in self.segments.iter() {
+            write!(f, "/{}", segment)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error while parsing an `IpRange` or a `MultiaddrPattern`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlacklistParseError {
+    /// The IP address of the range is invalid.
+    InvalidIpAddress,
+    /// The prefix length of the range is not a number, or is too large for the address.
+    InvalidPrefixLength,
+    /// The pattern doesn't start with `/`, contains an empty segment, or contains a `**` that is
+    /// not the last segment.
+    InvalidPattern,
+}
+
+impl fmt::Display for BlacklistParseError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", error::Error::description(self))
+    }
+}
+
+impl error::Error for BlacklistParseError {
+    #[inline]
+    fn description(&self) -> &str {
+        match *self {
+            BlacklistParseError::InvalidIpAddress => "invalid IP address in range",
+            BlacklistParseError::InvalidPrefixLength => "invalid prefix length in range",
+            BlacklistParseError::InvalidPattern => "invalid multiaddr pattern",
+        }
+    }
+}
+
+/// Wraps around a `Transport` and denies the dialing attempts and the incoming connections whose
+/// multiaddress matches a `Blacklist`.
+///
+/// Dialing a denied multiaddress produces a future that immediately fails with an error of kind
+/// `PermissionDenied`. The incoming connections from a denied multiaddress are closed and fail
+/// with the same kind of error.
+#[derive(Debug, Clone)]
+pub struct BlacklistTransport<T> {
+    inner: T,
+    blacklist: Arc<Blacklist>,
+}
+
+impl<T> BlacklistTransport<T> {
+    /// Wraps around `inner` and enforces `blacklist`.
+    #[inline]
+    pub fn new(inner: T, blacklist: Blacklist) -> BlacklistTransport<T> {
+        BlacklistTransport {
+            inner,
+            blacklist: Arc::new(blacklist),
+        }
+    }
+
+    /// Returns the blacklist that is enforced.
+    #[inline]
+    pub fn blacklist(&self) -> &Blacklist {
+        &self.blacklist
+    }
+}
+
+impl<T> Transport for BlacklistTransport<T>
+where
+    T: Transport,
+{
+    type RawConn = T::RawConn;
+    type Listener = BlacklistListener<T::Listener>;
+    type ListenerUpgrade = BlacklistUpgrade<T::ListenerUpgrade>;
+    type Dial = future::Either<
+        future::FutureResult<(T::RawConn, Multiaddr), IoError>,
+        <T::Dial as IntoFuture>::Future,
+    >;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+        let blacklist = self.blacklist;
+        match self.inner.listen_on(addr) {
+            Ok((listener, addr)) => {
+                let listener = BlacklistListener {
+                    inner: listener,
+                    blacklist,
+                };
+                Ok((listener, addr))
+            }
+            Err((inner, addr)) => Err((BlacklistTransport { inner, blacklist }, addr)),
+        }
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+        if self.blacklist.is_denied(&addr) {
+            return Ok(future::Either::A(future::err(denied_error(&addr))));
+        }
+
+        let blacklist = self.blacklist;
+        match self.inner.dial(addr) {
+            Ok(dial) => Ok(future::Either::B(dial.into_future())),
+            Err((inner, addr)) => Err((BlacklistTransport { inner, blacklist }, addr)),
+        }
+    }
+
+    #[inline]
+    fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.nat_traversal(server, observed)
+    }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.inner.local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.inner.reports_peer_ids()
+    }
+}
+
+impl<T> MuxedTransport for BlacklistTransport<T>
+where
+    T: MuxedTransport,
+{
+    // The substreams opened by nodes that we dialed ourselves went through the blacklist when
+    // dialing.
+    type Incoming = T::Incoming;
+    type IncomingUpgrade = T::IncomingUpgrade;
+
+    #[inline]
+    fn next_incoming(self) -> Self::Incoming {
+        self.inner.next_incoming()
+    }
+}
+
+/// Stream of incoming connections returned by `BlacklistTransport::listen_on`.
+pub struct BlacklistListener<S> {
+    inner: S,
+    blacklist: Arc<Blacklist>,
+}
+
+impl<S, F, O> Stream for BlacklistListener<S>
+where
+    S: Stream<Item = F, Error = IoError>,
+    F: Future<Item = (O, Multiaddr), Error = IoError>,
+{
+    type Item = BlacklistUpgrade<F>;
+    type Error = IoError;
+
+    #[inline]
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let upgrade = match try_ready!(self.inner.poll()) {
+            Some(upgrade) => upgrade,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        Ok(Async::Ready(Some(BlacklistUpgrade {
+            inner: upgrade,
+            blacklist: self.blacklist.clone(),
+        })))
+    }
+}
+
+/// Incoming connection that fails if the remote matches the blacklist.
+pub struct BlacklistUpgrade<F> {
+    inner: F,
+    blacklist: Arc<Blacklist>,
+}
+
+impl<F, O> Future for BlacklistUpgrade<F>
+where
+    F: Future<Item = (O, Multiaddr), Error = IoError>,
+{
+    type Item = (O, Multiaddr);
+    type Error = IoError;
+
+    #[inline]
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let (connection, remote_addr) = try_ready!(self.inner.poll());
+        if self.blacklist.is_denied(&remote_addr) {
+            // Dropping `connection` closes it.
+            return Err(denied_error(&remote_addr));
+        }
+
+        Ok(Async::Ready((connection, remote_addr)))
+    }
+}
+
+#[inline]
+fn denied_error(addr: &Multiaddr) -> IoError {
+    IoError::new(
+        IoErrorKind::PermissionDenied,
+        format!("multiaddr {} is blacklisted", addr),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Blacklist, BlacklistParseError, IpRange, MultiaddrPattern};
+    use multiaddr::Multiaddr;
+
+    #[test]
+    fn ip_range_contains() {
+        let range: IpRange = "172.16.0.0/12".parse().unwrap();
+        assert!(range.contains(&"172.16.0.1".parse().unwrap()));
+        assert!(range.contains(&"172.31.255.255".parse().unwrap()));
+        assert!(!range.contains(&"172.32.0.0".parse().unwrap()));
+        assert!(!range.contains(&"::1".parse().unwrap()));
+
+        let range: IpRange = "fc00::/7".parse().unwrap();
+        assert!(range.contains(&"fd12:3456::1".parse().unwrap()));
+        assert!(!range.contains(&"fe80::1".parse().unwrap()));
+
+        let range: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(range.contains(&"8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_ip_range() {
+        assert_eq!(
+            "10.0.0.0/33".parse::<IpRange>(),
+            Err(BlacklistParseError::InvalidPrefixLength)
+        );
+        assert_eq!(
+            "10.0.0/8".parse::<IpRange>(),
+            Err(BlacklistParseError::InvalidIpAddress)
+        );
+    }
+
+    #[test]
+    fn pattern_matching() {
+        let pattern: MultiaddrPattern = "/ip4/*/tcp/22".parse().unwrap();
+        assert!(pattern.matches(&"/ip4/1.2.3.4/tcp/22".parse::<Multiaddr>().unwrap()));
+        assert!(!pattern.matches(&"/ip4/1.2.3.4/tcp/23".parse::<Multiaddr>().unwrap()));
+        assert!(!pattern.matches(&"/ip4/1.2.3.4/tcp/22/ws".parse::<Multiaddr>().unwrap()));
+
+        let pattern: MultiaddrPattern = "/ip6/**".parse().unwrap();
+        assert!(pattern.matches(&"/ip6/::1/tcp/22/ws".parse::<Multiaddr>().unwrap()));
+        assert!(!pattern.matches(&"/ip4/1.2.3.4/tcp/22".parse::<Multiaddr>().unwrap()));
+
+        assert!("ip4/*".parse::<MultiaddrPattern>().is_err());
+        assert!("/**/tcp/22".parse::<MultiaddrPattern>().is_err());
+    }
+
+    #[test]
+    fn private_ranges_denied() {
+        let blacklist = Blacklist::new().deny_private_ranges();
+        assert!(blacklist.is_denied(&"/ip4/10.1.2.3/tcp/1".parse().unwrap()));
+        assert!(blacklist.is_denied(&"/ip4/192.168.0.1/tcp/1".parse().unwrap()));
+        assert!(!blacklist.is_denied(&"/ip4/8.8.8.8/tcp/1".parse().unwrap()));
+        assert!(!Blacklist::new().is_denied(&"/ip4/10.1.2.3/tcp/1".parse().unwrap()));
+    }
+
+    #[test]
+    fn display_roundtrip() {
+        for range in &["10.0.0.0/8", "fc00::/7", "1.2.3.4/32"] {
+            assert_eq!(range.parse::<IpRange>().unwrap().to_string(), *range);
+        }
+        for pattern in &["/ip4/*/tcp/22", "/ip6/**"] {
+            assert_eq!(pattern.parse::<MultiaddrPattern>().unwrap().to_string(), *pattern);
+        }
+    }
+}
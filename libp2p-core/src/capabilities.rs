@@ -0,0 +1,247 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `CapabilityMatrix` struct, which remembers what was negotiated with each remote.
+//!
+//! When a connection is opened, several upgrades are usually negotiated on top of each other:
+//! first a security protocol such as *secio*, then a stream muxer such as *mplex*. Each of these
+//! upgrades can be wrapped with `CapabilityMatrix::record`, after which the matrix knows, for
+//! each remote address, which protocol was picked for each layer and which other protocols we
+//! supported for that layer but didn't end up using.
+//!
+//! The transport that was used is given by the multiaddress of the remote.
+//!
+//! This information is meant to be shown to node operators, in order to understand for example
+//! why a given protocol is never used with some peers.
+//!
+//! > **Note**: The remote is identified by its multiaddress, as the upgrades are negotiated before
+//! >           the peer ID of the remote is known.
+
+use bytes::Bytes;
+use fnv::FnvHashMap;
+use multiaddr::Multiaddr;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::{ConnectionUpgrade, Endpoint, LocalIdentity};
+
+/// Remembers which protocols were negotiated with each remote.
+///
+/// Cloning a `CapabilityMatrix` gives access to the same matrix.
+#[derive(Debug, Clone, Default)]
+pub struct CapabilityMatrix {
+    entries: Arc<Mutex<FnvHashMap<Multiaddr, Vec<NegotiatedLayer>>>>,
+}
+
+/// What has been negotiated for one layer of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NegotiatedLayer {
+    /// Name of the layer that was passed to `CapabilityMatrix::record`, eg. `security`.
+    pub layer: &'static str,
+    /// Name of the protocol that was negotiated.
+    pub protocol: Bytes,
+    /// Whether we were dialing or listening when negotiating.
+    pub endpoint: Endpoint,
+    /// Protocols that we support for this layer but that weren't negotiated.
+    ///
+    /// Multistream-select doesn't tell us which protocols the remote supports, therefore there is
+    /// no way to know whether the remote supports any of these alternatives.
+    pub unused: Vec<Bytes>,
+}
+
+impl CapabilityMatrix {
+    /// Builds a new empty matrix.
+    #[inline]
+    pub fn new() -> CapabilityMatrix {
+        CapabilityMatrix::default()
+    }
+
+    /// Wraps around `upgrade` so that the protocol it negotiates is recorded in the matrix under
+    /// the name `layer`.
+    ///
+    /// If a protocol was already recorded for this layer and this remote, it is replaced.
+    #[inline]
+    pub fn record<U>(&self, layer: &'static str, upgrade: U) -> RecordCapability<U> {
+        RecordCapability {
+            upgrade: upgrade,
+            layer: layer,
+            matrix: self.clone(),
+        }
+    }
+
+    /// Returns the layers that were negotiated with the given remote, in the order in which they
+    /// were first negotiated.
+    pub fn get(&self, remote_addr: &Multiaddr) -> Vec<NegotiatedLayer> {
+        self.entries
+            .lock()
+            .get(remote_addr)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns the content of the whole matrix.
+    pub fn entries(&self) -> Vec<(Multiaddr, Vec<NegotiatedLayer>)> {
+        self.entries
+            .lock()
+            .iter()
+            .map(|(addr, layers)| (addr.clone(), layers.clone()))
+            .collect()
+    }
+
+    /// Forgets about a remote. Since the matrix has no way to know when a connection is closed,
+    /// this should be called in order to prevent the matrix from growing forever.
+    #[inline]
+    pub fn remove(&self, remote_addr: &Multiaddr) {
+        self.entries.lock().remove(remote_addr);
+    }
+
+    fn insert(&self, remote_addr: &Multiaddr, negotiated: NegotiatedLayer) {
+        let mut entries = self.entries.lock();
+        let layers = entries.entry(remote_addr.clone()).or_insert_with(Vec::new);
+        if let Some(existing) = layers.iter_mut().find(|l| l.layer == negotiated.layer) {
+            *existing = negotiated;
+            return;
+        }
+        layers.push(negotiated);
+    }
+}
+
+/// Implementation of `ConnectionUpgrade` that records the negotiated protocol in a
+/// `CapabilityMatrix`. Returned by `CapabilityMatrix::record`.
+#[derive(Debug, Clone)]
+pub struct RecordCapability<U> {
+    upgrade: U,
+    layer: &'static str,
+    matrix: CapabilityMatrix,
+}
+
+impl<C, U> ConnectionUpgrade<C> for RecordCapability<U>
+where
+    C: AsyncRead + AsyncWrite,
+    U: ConnectionUpgrade<C>,
+{
+    type NamesIter = RecordCapabilityNames<U::NamesIter>;
+    type UpgradeIdentifier = (Bytes, U::UpgradeIdentifier);
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        RecordCapabilityNames {
+            inner: self.upgrade.protocol_names(),
+        }
+    }
+
+    type Output = U::Output;
+    type Future = U::Future;
+
+    fn upgrade(
+        self,
+        socket: C,
+        (protocol, id): Self::UpgradeIdentifier,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        let unused = self.upgrade
+            .protocol_names()
+            .map(|(name, _)| name)
+            .filter(|name| *name != protocol)
+            .collect();
+
+        self.matrix.insert(
+            remote_addr,
+            NegotiatedLayer {
+                layer: self.layer,
+                protocol: protocol,
+                endpoint: ty,
+                unused: unused,
+            },
+        );
+
+        self.upgrade
+            .upgrade(socket, id, ty, remote_addr, local_identity)
+    }
+}
+
+/// Iterator returned by `RecordCapability::protocol_names`. Remembers the name of each protocol
+/// in its identifier.
+pub struct RecordCapabilityNames<I> {
+    inner: I,
+}
+
+impl<I, Id> Iterator for RecordCapabilityNames<I>
+where
+    I: Iterator<Item = (Bytes, Id)>,
+{
+    type Item = (Bytes, (Bytes, Id));
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(name, id)| (name.clone(), (name, id)))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CapabilityMatrix;
+    use futures::Future;
+    use multiaddr::Multiaddr;
+    use std::io::{Cursor, Error as IoError};
+    use transport::{ConnectionUpgrade, Endpoint, LocalIdentity, SimpleProtocol, UpgradeExt};
+
+    fn nothing(_: Cursor<Vec<u8>>) -> Result<(), IoError> {
+        Ok(())
+    }
+
+    #[test]
+    fn negotiated_and_unused_recorded() {
+        let matrix = CapabilityMatrix::new();
+        let upgrade = matrix.record(
+            "muxer",
+            SimpleProtocol::new("/mplex/6.7.0", nothing)
+                .or_upgrade(SimpleProtocol::new("/yamux/1.0.0", nothing)),
+        );
+
+        let (name, id) = ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&upgrade)
+            .nth(1)
+            .unwrap();
+        assert_eq!(name, "/yamux/1.0.0");
+
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        upgrade
+            .upgrade(Cursor::new(vec![]), id, Endpoint::Dialer, &addr, &LocalIdentity::unknown())
+            .wait()
+            .unwrap();
+
+        let layers = matrix.get(&addr);
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].layer, "muxer");
+        assert_eq!(layers[0].protocol, "/yamux/1.0.0");
+        assert_eq!(layers[0].endpoint, Endpoint::Dialer);
+        assert_eq!(layers[0].unused, vec!["/mplex/6.7.0"]);
+
+        matrix.remove(&addr);
+        assert!(matrix.entries().is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `PcapngCapture` interceptor, which records the data of a connection in the pcapng
+//! format, in order to analyze it with Wireshark.
+//!
+//! Only available with the `capture` feature. Enabling this feature in a build without debug
+//! assertions, such as a release build, is a compilation error, as the capture contains
+//! everything that the protocol sends and receives.
+//!
+//! Interceptors see the data of the upgrade they wrap, after the layers below it have processed
+//! it. In order to capture the frames before they are encrypted, intercept an upgrade that is
+//! applied on top of the security layer, such as the muxer or a protocol of a muxed substream.
+//! Secio has no Wireshark dissector, so capturing below it and exporting its session keys
+//! wouldn't help analyzing the protocols.
+//!
+//! Each chunk of data is written as an enhanced packet block, with the direction of the data in
+//! its flags and the endpoint and the address of the remote in its comment. The link type is
+//! `USER0`, for which a dissector of the captured protocol can be configured in Wireshark.
+
+use futures::Poll;
+use multiaddr::Multiaddr;
+use parking_lot::Mutex;
+use std::io::{Error as IoError, Read, Write};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_io::{AsyncRead, AsyncWrite};
+use interceptor::{Direction, Interceptor};
+use transport::Endpoint;
+
+// Link type for private use, as defined in the pcapng specification.
+const LINKTYPE_USER0: u16 = 147;
+
+/// Interceptor that writes the data of the connections to `W` in the pcapng format.
+///
+/// Cloning it is cheap, and all the clones write to the same capture.
+pub struct PcapngCapture<W> {
+    writer: Arc<Mutex<W>>,
+    // If set, only the connections with this remote are captured.
+    only: Option<Multiaddr>,
+}
+
+impl<W> Clone for PcapngCapture<W> {
+    #[inline]
+    fn clone(&self) -> Self {
+        PcapngCapture {
+            writer: self.writer.clone(),
+            only: self.only.clone(),
+        }
+    }
+}
+
+impl<W> PcapngCapture<W>
+where
+    W: Write,
+{
+    /// Starts a capture by writing the header of a pcapng file to `writer`.
+    pub fn new(mut writer: W) -> Result<PcapngCapture<W>, IoError> {
+        writer.write_all(&section_header_block())?;
+        writer.write_all(&interface_description_block())?;
+        Ok(PcapngCapture {
+            writer: Arc::new(Mutex::new(writer)),
+            only: None,
+        })
+    }
+
+    /// Only captures the connections with `remote_addr`. The other connections are passed
+    /// through untouched.
+    #[inline]
+    pub fn only(mut self, remote_addr: Multiaddr) -> PcapngCapture<W> {
+        self.only = Some(remote_addr);
+        self
+    }
+}
+
+impl<C, W> Interceptor<C> for PcapngCapture<W>
+where
+    C: AsyncRead + AsyncWrite,
+    W: Write,
+{
+    type Output = CapturedSocket<C, W>;
+
+    fn intercept(&self, socket: C, endpoint: Endpoint, remote_addr: &Multiaddr) -> Self::Output {
+        let captured = match self.only {
+            Some(ref only) => only == remote_addr,
+            None => true,
+        };
+
+        let endpoint = match endpoint {
+            Endpoint::Dialer => "dialer",
+            Endpoint::Listener => "listener",
+        };
+
+        CapturedSocket {
+            inner: socket,
+            writer: if captured { Some(self.writer.clone()) } else { None },
+            comment: format!("{} {}", endpoint, remote_addr),
+        }
+    }
+}
+
+/// Socket produced by the `PcapngCapture` interceptor.
+pub struct CapturedSocket<C, W> {
+    inner: C,
+    // `None` if this connection isn't captured.
+    writer: Option<Arc<Mutex<W>>>,
+    comment: String,
+}
+
+impl<C, W> CapturedSocket<C, W>
+where
+    W: Write,
+{
+    fn record(&self, direction: Direction, data: &[u8]) {
+        if let Some(ref writer) = self.writer {
+            let block = enhanced_packet_block(direction, data, &self.comment);
+            // A broken capture must not break the connection.
+            if let Err(err) = writer.lock().write_all(&block) {
+                debug!(target: "libp2p-core", "Failed to write to the capture: {:?}", err);
+            }
+        }
+    }
+}
+
+impl<C, W> Read for CapturedSocket<C, W>
+where
+    C: Read,
+    W: Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let num_read = self.inner.read(buf)?;
+        if num_read != 0 {
+            self.record(Direction::Inbound, &buf[..num_read]);
+        }
+        Ok(num_read)
+    }
+}
+
+impl<C, W> AsyncRead for CapturedSocket<C, W>
+where
+    C: AsyncRead,
+    W: Write,
+{
+    #[inline]
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<C, W> Write for CapturedSocket<C, W>
+where
+    C: Write,
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let num_written = self.inner.write(buf)?;
+        if num_written != 0 {
+            self.record(Direction::Outbound, &buf[..num_written]);
+        }
+        Ok(num_written)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), IoError> {
+        if let Some(ref writer) = self.writer {
+            if let Err(err) = writer.lock().flush() {
+                debug!(target: "libp2p-core", "Failed to flush the capture: {:?}", err);
+            }
+        }
+        self.inner.flush()
+    }
+}
+
+impl<C, W> AsyncWrite for CapturedSocket<C, W>
+where
+    C: AsyncWrite,
+    W: Write,
+{
+    #[inline]
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
+
+fn section_header_block() -> Vec<u8> {
+    let mut body = Vec::new();
+    put_u32(&mut body, 0x1a2b3c4d); // Byte-order magic.
+    put_u16(&mut body, 1); // Major version.
+    put_u16(&mut body, 0); // Minor version.
+    put_u32(&mut body, 0xffffffff); // Unknown section length, on 64 bits.
+    put_u32(&mut body, 0xffffffff);
+    block(0x0a0d0d0a, body)
+}
+
+fn interface_description_block() -> Vec<u8> {
+    let mut body = Vec::new();
+    put_u16(&mut body, LINKTYPE_USER0);
+    put_u16(&mut body, 0); // Reserved.
+    put_u32(&mut body, 0); // No limit on the size of the packets.
+    block(0x00000001, body)
+}
+
+fn enhanced_packet_block(direction: Direction, data: &[u8], comment: &str) -> Vec<u8> {
+    // The default resolution of the timestamps is the microsecond.
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000_000 + u64::from(d.subsec_nanos() / 1_000))
+        .unwrap_or(0);
+
+    let mut body = Vec::new();
+    put_u32(&mut body, 0); // Interface ID.
+    put_u32(&mut body, (timestamp >> 32) as u32);
+    put_u32(&mut body, timestamp as u32);
+    put_u32(&mut body, data.len() as u32); // Captured length.
+    put_u32(&mut body, data.len() as u32); // Original length.
+    put_padded(&mut body, data);
+
+    // The `epb_flags` option, whose two lowest bits are the direction.
+    put_u16(&mut body, 2);
+    put_u16(&mut body, 4);
+    put_u32(&mut body, match direction {
+        Direction::Inbound => 1,
+        Direction::Outbound => 2,
+    });
+    // The `opt_comment` option.
+    put_u16(&mut body, 1);
+    put_u16(&mut body, comment.len() as u16);
+    put_padded(&mut body, comment.as_bytes());
+    // The `opt_endofopt` option.
+    put_u32(&mut body, 0);
+
+    block(0x00000006, body)
+}
+
+// Surrounds `body` with the type and the length of the block.
+fn block(ty: u32, body: Vec<u8>) -> Vec<u8> {
+    let len = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    put_u32(&mut block, ty);
+    put_u32(&mut block, len);
+    block.extend(body);
+    put_u32(&mut block, len);
+    block
+}
+
+#[inline]
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+}
+
+#[inline]
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    put_u16(buf, value as u16);
+    put_u16(buf, (value >> 16) as u16);
+}
+
+// Appends `data`, then zeroes until the length of `buf` is a multiple of 4.
+fn put_padded(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(data);
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PcapngCapture;
+    use interceptor::Interceptor;
+    use std::io::{Cursor, Write};
+    use transport::Endpoint;
+
+    #[test]
+    fn blocks_written() {
+        let capture = PcapngCapture::new(Vec::new()).unwrap();
+        let addr = "/ip4/1.2.3.4/tcp/5".parse().unwrap();
+        let mut socket = capture.intercept(Cursor::new(Vec::new()), Endpoint::Dialer, &addr);
+        socket.write_all(b"hello").unwrap();
+        drop(socket);
+
+        let data = capture.writer.lock().clone();
+        // Section header (28 bytes) and interface description (20 bytes).
+        assert_eq!(&data[..4], &[0x0a, 0x0d, 0x0d, 0x0a]);
+        assert_eq!(&data[28..32], &[1, 0, 0, 0]);
+        // Enhanced packet: 28 bytes of header, "hello" padded to 8 bytes, 8 bytes of flags, the
+        // padded comment and its header, the end of the options and the length of the block.
+        let packet = &data[48..];
+        assert_eq!(&packet[..4], &[6, 0, 0, 0]);
+        assert_eq!(packet.len(), 28 + 8 + 8 + (4 + 28) + 4 + 4);
+        assert_eq!(&packet[4..8], &[84, 0, 0, 0]);
+        assert_eq!(&packet[28..33], b"hello");
+    }
+}
@@ -0,0 +1,400 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `Clock` trait, which is the source of time of all the timeouts and intervals.
+//!
+//! Components that need to wait, such as `TransportTimeout`, are generic over a `Clock` instead
+//! of using `tokio-timer` directly. By default they use a `TokioClock`, which is backed by
+//! `tokio-timer`. In tests, they can be given a `ManualClock` instead, whose time only moves
+//! forward when `ManualClock::advance` is called, which makes tests deterministic and fast.
+//! Platforms where `tokio-timer` is not available can implement `Clock` themselves.
+//!
+//! On top of `Clock`, this module provides the `ClockTimeout` future and the `ClockInterval`
+//! stream.
+
+use futures::{Async, Future, Poll, Stream};
+use futures::task::{self, Task};
+use parking_lot::Mutex;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_timer::{Sleep, Timer};
+
+// Longest sleep handed to `tokio-timer` at once. The default wheel of `tokio-timer` refuses
+// sleeps longer than its number of slots times its tick duration, which is 409.6 seconds.
+// Longer delays are made of several sleeps.
+const MAX_SLEEP_SECS: u64 = 400;
+
+lazy_static! {
+    // Timer shared by all the `TokioClock`s built with `new`.
+    static ref DEFAULT_TIMER: Timer = Timer::default();
+}
+
+/// Source of time for timeouts and intervals.
+pub trait Clock: Clone {
+    /// Future that is resolved once a duration has elapsed.
+    type Delay: Future<Item = (), Error = IoError>;
+
+    /// Returns a future that is resolved once `duration` has elapsed.
+    fn delay(&self, duration: Duration) -> Self::Delay;
+
+    /// Returns the current time according to this clock.
+    fn now(&self) -> Instant;
+}
+
+/// Implementation of `Clock` backed by `tokio-timer`.
+///
+/// All the clocks built with `new` share the same timer, whose background thread is spawned the
+/// first time one of them is built.
+#[derive(Clone)]
+pub struct TokioClock {
+    timer: Timer,
+}
+
+impl Default for TokioClock {
+    #[inline]
+    fn default() -> TokioClock {
+        TokioClock::with_timer(DEFAULT_TIMER.clone())
+    }
+}
+
+impl TokioClock {
+    /// Returns a `TokioClock` that uses the default timer.
+    #[inline]
+    pub fn new() -> TokioClock {
+        TokioClock::default()
+    }
+
+    /// Builds a `TokioClock` that uses an existing `Timer`.
+    ///
+    /// The timer must accept sleeps of at least 400 seconds, which the default configuration of
+    /// `tokio-timer` does.
+    #[inline]
+    pub fn with_timer(timer: Timer) -> TokioClock {
+        TokioClock { timer: timer }
+    }
+}
+
+impl fmt::Debug for TokioClock {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TokioClock").finish()
+    }
+}
+
+impl Clock for TokioClock {
+    type Delay = TokioDelay;
+
+    #[inline]
+    fn delay(&self, duration: Duration) -> Self::Delay {
+        let max = Duration::from_secs(MAX_SLEEP_SECS);
+        let first = if duration > max { max } else { duration };
+        TokioDelay {
+            inner: self.timer.sleep(first),
+            timer: self.timer.clone(),
+            remaining: duration - first,
+        }
+    }
+
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Future returned by `TokioClock::delay`.
+pub struct TokioDelay {
+    inner: Sleep,
+    timer: Timer,
+    // Time left to wait once `inner` has elapsed.
+    remaining: Duration,
+}
+
+impl Future for TokioDelay {
+    type Item = ();
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<(), IoError> {
+        loop {
+            try_ready!(self.inner
+                .poll()
+                .map_err(|err| IoError::new(IoErrorKind::Other, err)));
+
+            if self.remaining == Duration::new(0, 0) {
+                return Ok(Async::Ready(()));
+            }
+
+            let max = Duration::from_secs(MAX_SLEEP_SECS);
+            let next = if self.remaining > max { max } else { self.remaining };
+            self.remaining -= next;
+            self.inner = self.timer.sleep(next);
+        }
+    }
+}
+
+/// Implementation of `Clock` whose time only moves when `advance` is called.
+///
+/// Cloning a `ManualClock` gives access to the same time.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    inner: Arc<Mutex<ManualClockInner>>,
+}
+
+#[derive(Debug)]
+struct ManualClockInner {
+    // Moment the clock was created, from which `now` is counted.
+    start: Instant,
+    // Time elapsed since the clock was created.
+    now: Duration,
+    // Tasks that are waiting for the time to move forward.
+    waiting: Vec<Task>,
+}
+
+impl Default for ManualClockInner {
+    #[inline]
+    fn default() -> ManualClockInner {
+        ManualClockInner {
+            start: Instant::now(),
+            now: Duration::new(0, 0),
+            waiting: Vec::new(),
+        }
+    }
+}
+
+impl ManualClock {
+    /// Builds a new `ManualClock`.
+    #[inline]
+    pub fn new() -> ManualClock {
+        ManualClock::default()
+    }
+
+    /// Moves the time forward by `duration`, and wakes up the delays that have elapsed.
+    pub fn advance(&self, duration: Duration) {
+        let waiting = {
+            let mut inner = self.inner.lock();
+            inner.now += duration;
+            mem::replace(&mut inner.waiting, Vec::new())
+        };
+
+        for task in waiting {
+            task.notify();
+        }
+    }
+
+    /// Returns the total duration the clock has been advanced by.
+    #[inline]
+    pub fn elapsed(&self) -> Duration {
+        self.inner.lock().now
+    }
+}
+
+impl Clock for ManualClock {
+    type Delay = ManualDelay;
+
+    #[inline]
+    fn delay(&self, duration: Duration) -> Self::Delay {
+        ManualDelay {
+            deadline: self.inner.lock().now + duration,
+            clock: self.clone(),
+        }
+    }
+
+    #[inline]
+    fn now(&self) -> Instant {
+        let inner = self.inner.lock();
+        inner.start + inner.now
+    }
+}
+
+/// Future returned by `ManualClock::delay`.
+#[derive(Debug)]
+pub struct ManualDelay {
+    clock: ManualClock,
+    deadline: Duration,
+}
+
+impl Future for ManualDelay {
+    type Item = ();
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<(), IoError> {
+        let mut inner = self.clock.inner.lock();
+        if inner.now >= self.deadline {
+            Ok(Async::Ready(()))
+        } else {
+            inner.waiting.push(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Wraps around a future and makes it fail with an error of kind `TimedOut` if it takes too long.
+pub struct ClockTimeout<F, D> {
+    inner: F,
+    // `None` if there is no timeout.
+    delay: Option<D>,
+}
+
+impl<F, D> ClockTimeout<F, D> {
+    /// Wraps around `future`. If `timeout` is `Some`, the future fails once this duration has
+    /// elapsed on `clock`.
+    #[inline]
+    pub fn new<C>(future: F, clock: &C, timeout: Option<Duration>) -> ClockTimeout<F, D>
+    where
+        C: Clock<Delay = D>,
+    {
+        ClockTimeout {
+            inner: future,
+            delay: timeout.map(|timeout| clock.delay(timeout)),
+        }
+    }
+}
+
+impl<F, D> Future for ClockTimeout<F, D>
+where
+    F: Future<Error = IoError>,
+    D: Future<Item = (), Error = IoError>,
+{
+    type Item = F::Item;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner.poll()? {
+            Async::Ready(val) => return Ok(Async::Ready(val)),
+            Async::NotReady => (),
+        }
+
+        if let Some(ref mut delay) = self.delay {
+            try_ready!(delay.poll());
+            return Err(IoError::new(IoErrorKind::TimedOut, "timeout elapsed"));
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+/// Stream that produces `()` every time a period elapses. Never ends.
+pub struct ClockInterval<C>
+where
+    C: Clock,
+{
+    clock: C,
+    period: Duration,
+    delay: C::Delay,
+}
+
+impl<C> ClockInterval<C>
+where
+    C: Clock,
+{
+    /// Builds a stream that produces an element every `period`, the first one after `period`.
+    #[inline]
+    pub fn new(clock: C, period: Duration) -> ClockInterval<C> {
+        let delay = clock.delay(period);
+        ClockInterval {
+            clock: clock,
+            period: period,
+            delay: delay,
+        }
+    }
+}
+
+impl<C> Stream for ClockInterval<C>
+where
+    C: Clock,
+{
+    type Item = ();
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Option<()>, IoError> {
+        try_ready!(self.delay.poll());
+        self.delay = self.clock.delay(self.period);
+        Ok(Async::Ready(Some(())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, ClockInterval, ClockTimeout, ManualClock, TokioClock};
+    use futures::{future, Async, Future, Stream};
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn manual_timeout() {
+        let clock = ManualClock::new();
+
+        let ok = ClockTimeout::new(
+            future::ok::<_, IoError>(5),
+            &clock,
+            Some(Duration::from_secs(1)),
+        );
+        assert_eq!(ok.wait().unwrap(), 5);
+
+        let timeout = ClockTimeout::new(
+            future::empty::<(), IoError>(),
+            &clock,
+            Some(Duration::from_secs(10)),
+        );
+        let clock2 = clock.clone();
+        let background = thread::spawn(move || {
+            // The timeout doesn't fire until the clock has been advanced enough, no matter how
+            // long we actually wait.
+            thread::sleep(Duration::from_millis(50));
+            clock2.advance(Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(50));
+            clock2.advance(Duration::from_secs(5));
+        });
+        assert_eq!(timeout.wait().unwrap_err().kind(), IoErrorKind::TimedOut);
+        assert_eq!(clock.elapsed(), Duration::from_secs(10));
+        background.join().unwrap();
+    }
+
+    #[test]
+    fn manual_interval() {
+        let clock = ManualClock::new();
+        let interval = ClockInterval::new(clock.clone(), Duration::from_secs(3));
+        clock.advance(Duration::from_secs(3));
+        let (tick, interval) = interval.into_future().map_err(|(err, _)| err).wait().unwrap();
+        assert_eq!(tick, Some(()));
+        clock.advance(Duration::from_secs(3));
+        let (tick, _) = interval.into_future().map_err(|(err, _)| err).wait().unwrap();
+        assert_eq!(tick, Some(()));
+    }
+
+    #[test]
+    fn manual_now() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        clock.advance(Duration::from_secs(7));
+        assert_eq!(clock.now() - start, Duration::from_secs(7));
+    }
+
+    #[test]
+    fn tokio_long_delay() {
+        // Longer than what the default wheel of `tokio-timer` accepts in a single sleep.
+        let mut delay = TokioClock::new().delay(Duration::from_secs(1000));
+        let polled = future::lazy(move || Ok::<_, ()>(delay.poll())).wait().unwrap();
+        assert_eq!(polled.unwrap(), Async::NotReady);
+    }
+}
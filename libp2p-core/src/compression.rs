@@ -0,0 +1,362 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `Compress` connection upgrade, which lets a protocol negotiate compression of its
+//! payload on a per-substream basis.
+//!
+//! Wrapping an upgrade with `UpgradeExt::with_compression` advertises each of its protocol names
+//! twice: once with the name of the compression algorithm appended, for example
+//! `/myproto/1.0.0/zstd`, and once unchanged. The compressed variants are preferred. If the
+//! remote supports them, the socket goes through the algorithm before being passed to the inner
+//! upgrade. Otherwise the protocol is used uncompressed, which keeps compatibility with remotes
+//! that don't support compression.
+//!
+//! Contrary to compressing the whole connection, only the protocols that move compressible data
+//! opt in, and the others don't pay for it.
+//!
+//! The algorithm is a `Compression`. `Zstd` is provided.
+//!
+//! This module is only compiled with the `compression` feature.
+
+use bytes::{Bytes, BytesMut};
+use futures::{future, Async, Poll};
+use multiaddr::Multiaddr;
+use std::io::{BufReader, Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use tokio_io::io::{ReadHalf, WriteHalf};
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::{ConnectionUpgrade, EitherSocket, Endpoint, LocalIdentity};
+use zstd::stream::{Decoder as ZstdDecoder, Encoder as ZstdEncoder};
+
+/// Algorithm that compresses the data of a socket.
+pub trait Compression<C> {
+    /// Socket that compresses the data written to it and decompresses the data read from it.
+    type Output: AsyncRead + AsyncWrite;
+
+    /// Name of the algorithm, appended to the protocol names. For example `zstd`.
+    fn name(&self) -> &str;
+
+    /// Wraps around `socket`. Called once for each substream that negotiated compression.
+    fn wrap(&self, socket: C) -> Result<Self::Output, IoError>;
+}
+
+/// Implementation of `ConnectionUpgrade` that negotiates compression for the substreams of an
+/// upgrade. Returned by `UpgradeExt::with_compression`.
+///
+/// The inner upgrade receives an `EitherSocket` whose `Second` variant is used if compression
+/// was negotiated.
+#[derive(Debug, Copy, Clone)]
+pub struct Compress<U, Z> {
+    upgrade: U,
+    compression: Z,
+}
+
+impl<U, Z> Compress<U, Z> {
+    /// Builds a new `Compress`.
+    #[inline]
+    pub fn new(upgrade: U, compression: Z) -> Compress<U, Z> {
+        Compress {
+            upgrade: upgrade,
+            compression: compression,
+        }
+    }
+}
+
+impl<C, U, Z> ConnectionUpgrade<C> for Compress<U, Z>
+where
+    C: AsyncRead + AsyncWrite,
+    Z: Compression<C>,
+    U: ConnectionUpgrade<EitherSocket<C, Z::Output>>,
+    U::UpgradeIdentifier: Clone,
+{
+    type NamesIter = CompressNames<U::NamesIter, U::UpgradeIdentifier>;
+    type UpgradeIdentifier = (bool, U::UpgradeIdentifier);
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        let mut suffix = BytesMut::with_capacity(self.compression.name().len() + 1);
+        suffix.extend_from_slice(b"/");
+        suffix.extend_from_slice(self.compression.name().as_bytes());
+
+        CompressNames {
+            inner: self.upgrade.protocol_names(),
+            suffix: suffix.freeze(),
+            pending: None,
+        }
+    }
+
+    type Output = U::Output;
+    type Future = future::Either<U::Future, future::FutureResult<U::Output, IoError>>;
+
+    fn upgrade(
+        self,
+        socket: C,
+        (compressed, id): Self::UpgradeIdentifier,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        let socket = if compressed {
+            trace!(target: "libp2p-core", "Compressing substream with {} using {}", remote_addr,
+                   self.compression.name());
+            match self.compression.wrap(socket) {
+                Ok(socket) => EitherSocket::Second(socket),
+                Err(err) => return future::Either::B(future::err(err)),
+            }
+        } else {
+            EitherSocket::First(socket)
+        };
+
+        let future = self.upgrade.upgrade(socket, id, ty, remote_addr, local_identity);
+        future::Either::A(future)
+    }
+}
+
+/// Iterator returned by `Compress::protocol_names`. Produces the compressed variant of each name
+/// of the inner upgrade, followed by the name itself.
+pub struct CompressNames<I, Id> {
+    inner: I,
+    suffix: Bytes,
+    // Uncompressed variant of the last name that was produced, to produce next.
+    pending: Option<(Bytes, Id)>,
+}
+
+impl<I, Id> Iterator for CompressNames<I, Id>
+where
+    I: Iterator<Item = (Bytes, Id)>,
+    Id: Clone,
+{
+    type Item = (Bytes, (bool, Id));
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((name, id)) = self.pending.take() {
+            return Some((name, (false, id)));
+        }
+
+        let (name, id) = match self.inner.next() {
+            Some(next) => next,
+            None => return None,
+        };
+        let mut compressed = BytesMut::with_capacity(name.len() + self.suffix.len());
+        compressed.extend_from_slice(&name);
+        compressed.extend_from_slice(&self.suffix);
+        self.pending = Some((name, id.clone()));
+        Some((compressed.freeze(), (true, id)))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let pending = if self.pending.is_some() { 1 } else { 0 };
+        let (min, max) = self.inner.size_hint();
+        (min * 2 + pending, max.map(|max| max * 2 + pending))
+    }
+}
+
+/// Zstandard compression. Advertised as `zstd`.
+#[derive(Debug, Copy, Clone)]
+pub struct Zstd {
+    level: i32,
+}
+
+impl Zstd {
+    /// Uses the given compression level, between 1 and 21. Higher levels compress better but
+    /// are slower.
+    #[inline]
+    pub fn with_level(level: i32) -> Zstd {
+        Zstd { level: level }
+    }
+}
+
+impl Default for Zstd {
+    /// Uses the default level of the zstd library, which is a good compromise for data sent over
+    /// the network.
+    #[inline]
+    fn default() -> Zstd {
+        Zstd::with_level(0)
+    }
+}
+
+impl<C> Compression<C> for Zstd
+where
+    C: AsyncRead + AsyncWrite,
+{
+    type Output = ZstdSocket<C>;
+
+    #[inline]
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn wrap(&self, socket: C) -> Result<Self::Output, IoError> {
+        let (reader, writer) = socket.split();
+        Ok(ZstdSocket {
+            reader: ZstdDecoder::new(reader)?,
+            writer: ZstdWriter::Encoding(ZstdEncoder::new(writer, self.level)?),
+        })
+    }
+}
+
+/// Socket produced by `Zstd`.
+///
+/// Each `flush` ends a block, so that the remote can decompress everything that was written
+/// until then. `shutdown` ends the stream of compressed data before closing the socket.
+pub struct ZstdSocket<C> {
+    reader: ZstdDecoder<BufReader<ReadHalf<C>>>,
+    writer: ZstdWriter<C>,
+}
+
+enum ZstdWriter<C> {
+    Encoding(ZstdEncoder<WriteHalf<C>>),
+    // The end of the compressed stream has been written.
+    Finished(WriteHalf<C>),
+    // Only happens if finishing the stream panicked or failed with something else than
+    // `WouldBlock`.
+    Poisoned,
+}
+
+impl<C> Read for ZstdSocket<C>
+where
+    C: AsyncRead,
+{
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        self.reader.read(buf)
+    }
+}
+
+impl<C> AsyncRead for ZstdSocket<C>
+where
+    C: AsyncRead + AsyncWrite,
+{
+    #[inline]
+    unsafe fn prepare_uninitialized_buffer(&self, _: &mut [u8]) -> bool {
+        true
+    }
+}
+
+impl<C> Write for ZstdSocket<C>
+where
+    C: AsyncWrite,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        match self.writer {
+            ZstdWriter::Encoding(ref mut encoder) => encoder.write(buf),
+            _ => Err(IoError::new(IoErrorKind::BrokenPipe, "the socket has been shut down")),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        match self.writer {
+            ZstdWriter::Encoding(ref mut encoder) => encoder.flush(),
+            ZstdWriter::Finished(ref mut writer) => writer.flush(),
+            ZstdWriter::Poisoned => Err(IoErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+impl<C> AsyncWrite for ZstdSocket<C>
+where
+    C: AsyncRead + AsyncWrite,
+{
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        loop {
+            match ::std::mem::replace(&mut self.writer, ZstdWriter::Poisoned) {
+                ZstdWriter::Encoding(encoder) => match encoder.try_finish() {
+                    Ok(writer) => self.writer = ZstdWriter::Finished(writer),
+                    Err((encoder, err)) => {
+                        if err.kind() != IoErrorKind::WouldBlock {
+                            return Err(err);
+                        }
+                        self.writer = ZstdWriter::Encoding(encoder);
+                        return Ok(Async::NotReady);
+                    }
+                },
+                ZstdWriter::Finished(mut writer) => {
+                    let result = writer.shutdown();
+                    self.writer = ZstdWriter::Finished(writer);
+                    return result;
+                }
+                ZstdWriter::Poisoned => return Err(IoErrorKind::BrokenPipe.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::Future;
+    use std::io::{Cursor, Error as IoError};
+    use tokio_io::{AsyncRead, AsyncWrite};
+    use transport::EitherSocket;
+    use {Compression, ConnectionUpgrade, Endpoint, LocalIdentity, SimpleProtocol, UpgradeExt};
+
+    // Compression that doesn't change the data, in order to test the negotiation alone.
+    #[derive(Clone)]
+    struct Identity;
+
+    impl<C> Compression<C> for Identity
+    where
+        C: AsyncRead + AsyncWrite,
+    {
+        type Output = C;
+
+        fn name(&self) -> &str {
+            "identity"
+        }
+
+        fn wrap(&self, socket: C) -> Result<C, IoError> {
+            Ok(socket)
+        }
+    }
+
+    #[test]
+    fn compressed_names_first() {
+        let upgrade = SimpleProtocol::new("/myproto/1.0.0", |socket| Ok::<_, IoError>(socket))
+            .with_compression(Identity);
+        let names = ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&upgrade)
+            .map(|(name, (compressed, _))| (name, compressed))
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![
+            (Bytes::from("/myproto/1.0.0/identity"), true),
+            (Bytes::from("/myproto/1.0.0"), false),
+        ]);
+    }
+
+    #[test]
+    fn compressed_socket_passed() {
+        let upgrade = SimpleProtocol::new("/myproto/1.0.0", |socket| Ok::<_, IoError>(socket))
+            .with_compression(Identity);
+        let addr = "/ip4/1.2.3.4/tcp/5".parse().unwrap();
+
+        let mut names = ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&upgrade);
+        for &expect_compressed in [true, false].iter() {
+            let (_, id) = names.next().unwrap();
+            let output = upgrade.clone()
+                .upgrade(Cursor::new(Vec::new()), id, Endpoint::Dialer, &addr,
+                         &LocalIdentity::unknown())
+                .wait()
+                .unwrap();
+            match output {
+                EitherSocket::First(_) => assert!(!expect_compressed),
+                EitherSocket::Second(_) => assert!(expect_compressed),
+            }
+        }
+    }
+}
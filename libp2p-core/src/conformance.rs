@@ -0,0 +1,409 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains `check_upgrade`, which runs a standard battery of checks against an implementation
+//! of `ConnectionUpgrade`.
+//!
+//! This is meant to be used in the tests of crates that implement a protocol on top of this
+//! stack. Each check upgrades a `TestSocket` that plays the role of a misbehaving remote, both as
+//! the dialer and as the listener, and verifies that the upgrade neither panics nor stays stuck
+//! once the remote has nothing more to say. The result is a `ConformanceReport` that lists the
+//! outcome of each check.
+//!
+//! The checks are:
+//!
+//! - `ProtocolNames`: the upgrade advertises at least one name, every name starts with `/`,
+//!   doesn't contain a newline (which multistream-select can't transmit), and appears only once.
+//! - `ImmediateEof`: the remote closes the substream without sending anything.
+//! - `GarbageInput`: the remote sends random bytes, then closes the substream.
+//! - `Reset`: every read and write fails with `ConnectionReset`.
+//! - `SlowPeer`: same as `GarbageInput`, but the remote reads and writes a single byte at a time
+//!   and isn't ready one call out of two.
+//! - `SilentPeer`: the remote never sends anything. The upgrade is polled a few times, then
+//!   destroyed. Since time doesn't advance during the check, this only verifies that neither
+//!   operation panics.
+//!
+//! The sockets are in-memory objects driven by the check itself, so no transport and no event
+//! loop are needed.
+//!
+//! # Example
+//!
+//! ```
+//! extern crate libp2p_core;
+//!
+//! use libp2p_core::{check_upgrade, SimpleProtocol, TestSocket};
+//! use std::io::{Error as IoError, Read};
+//!
+//! # fn main() {
+//! let report = check_upgrade(|| {
+//!     SimpleProtocol::new("/my-proto/1.0.0", |mut socket: TestSocket| {
+//!         let mut buf = [0; 4];
+//!         let _ = socket.read(&mut buf);
+//!         Ok::<_, IoError>(())
+//!     })
+//! });
+//!
+//! assert!(report.is_ok(), "{:?}", report.failures());
+//! # }
+//! ```
+
+use futures::{executor, Async, Future, Poll};
+use futures::executor::Notify;
+use std::collections::{HashSet, VecDeque};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::{ConnectionUpgrade, Endpoint, LocalIdentity};
+
+// Maximum number of times an upgrade is polled before it is considered stuck.
+const MAX_POLLS: usize = 1024;
+
+/// A check performed by `check_upgrade`. See the module-level documentation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ConformanceCheck {
+    /// The names advertised by the upgrade are valid.
+    ProtocolNames,
+    /// The remote closes the substream immediately.
+    ImmediateEof,
+    /// The remote sends random bytes.
+    GarbageInput,
+    /// The substream is reset.
+    Reset,
+    /// The remote reads and writes slowly.
+    SlowPeer,
+    /// The remote never answers.
+    SilentPeer,
+}
+
+/// Outcome of a single check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckOutcome {
+    /// The upgrade behaved correctly.
+    Passed,
+    /// The upgrade misbehaved. Contains a description of the problem.
+    Failed(String),
+}
+
+/// Result of `check_upgrade`.
+#[derive(Debug, Clone)]
+pub struct ConformanceReport {
+    /// Outcome of each check. The endpoint is `None` for the checks that don't upgrade a socket.
+    pub results: Vec<(ConformanceCheck, Option<Endpoint>, CheckOutcome)>,
+}
+
+impl ConformanceReport {
+    /// Returns true if all the checks passed.
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.results.iter().all(|&(_, _, ref outcome)| *outcome == CheckOutcome::Passed)
+    }
+
+    /// Returns the checks that failed, with the reason of the failure.
+    pub fn failures(&self) -> Vec<(ConformanceCheck, Option<Endpoint>, &str)> {
+        self.results
+            .iter()
+            .filter_map(|&(check, endpoint, ref outcome)| match *outcome {
+                CheckOutcome::Passed => None,
+                CheckOutcome::Failed(ref reason) => Some((check, endpoint, &reason[..])),
+            })
+            .collect()
+    }
+}
+
+/// Runs all the checks against the upgrade returned by `make_upgrade`, which is called once per
+/// check.
+///
+/// The checks that upgrade a socket use the first name returned by `protocol_names`.
+pub fn check_upgrade<U, F>(make_upgrade: F) -> ConformanceReport
+where
+    F: Fn() -> U,
+    U: ConnectionUpgrade<TestSocket>,
+{
+    let mut results = Vec::new();
+    results.push((ConformanceCheck::ProtocolNames, None, check_names(&make_upgrade())));
+
+    let checks = [
+        ConformanceCheck::ImmediateEof,
+        ConformanceCheck::GarbageInput,
+        ConformanceCheck::Reset,
+        ConformanceCheck::SlowPeer,
+        ConformanceCheck::SilentPeer,
+    ];
+
+    for &endpoint in &[Endpoint::Dialer, Endpoint::Listener] {
+        for &check in &checks {
+            let outcome = run_check(make_upgrade(), check, endpoint);
+            results.push((check, Some(endpoint), outcome));
+        }
+    }
+
+    ConformanceReport { results }
+}
+
+// Checks the names returned by `protocol_names`.
+fn check_names<U>(upgrade: &U) -> CheckOutcome
+where
+    U: ConnectionUpgrade<TestSocket>,
+{
+    let names = match panic::catch_unwind(AssertUnwindSafe(|| {
+        upgrade.protocol_names().map(|(name, _)| name).collect::<Vec<_>>()
+    })) {
+        Ok(names) => names,
+        Err(_) => return CheckOutcome::Failed("protocol_names panicked".to_owned()),
+    };
+
+    if names.is_empty() {
+        return CheckOutcome::Failed("no protocol name advertised".to_owned());
+    }
+
+    let mut seen = HashSet::new();
+    for name in names {
+        if !name.starts_with(b"/") {
+            return CheckOutcome::Failed(format!("{:?} doesn't start with `/`", name));
+        }
+        if name.contains(&b'\n') {
+            return CheckOutcome::Failed(format!("{:?} contains a newline", name));
+        }
+        if !seen.insert(name.clone()) {
+            return CheckOutcome::Failed(format!("{:?} is advertised multiple times", name));
+        }
+    }
+
+    CheckOutcome::Passed
+}
+
+// Upgrades a `TestSocket` corresponding to `check`, and verifies the behaviour of the upgrade.
+fn run_check<U>(upgrade: U, check: ConformanceCheck, endpoint: Endpoint) -> CheckOutcome
+where
+    U: ConnectionUpgrade<TestSocket>,
+{
+    let socket = match check {
+        ConformanceCheck::ImmediateEof => TestSocket::new(Vec::new()),
+        ConformanceCheck::GarbageInput => TestSocket::new(garbage()),
+        ConformanceCheck::Reset => TestSocket::reset(),
+        ConformanceCheck::SlowPeer => TestSocket::new(garbage()).slow(),
+        ConformanceCheck::SilentPeer => TestSocket::silent(),
+        ConformanceCheck::ProtocolNames => unreachable!("doesn't upgrade a socket"),
+    };
+
+    let addr = "/ip4/127.0.0.1/tcp/1".parse().expect("valid multiaddress");
+    let result = panic::catch_unwind(AssertUnwindSafe(move || {
+        let id = match upgrade.protocol_names().next() {
+            Some((_, id)) => id,
+            None => return None,
+        };
+        let future = upgrade.upgrade(socket, id, endpoint, &addr, &LocalIdentity::unknown());
+        Some(poll_bounded(future))
+    }));
+
+    match (check, result) {
+        (_, Err(_)) => CheckOutcome::Failed("the upgrade panicked".to_owned()),
+        (_, Ok(None)) => CheckOutcome::Failed("no protocol name advertised".to_owned()),
+        (ConformanceCheck::SilentPeer, Ok(Some(_))) => CheckOutcome::Passed,
+        (_, Ok(Some(Async::NotReady))) => CheckOutcome::Failed(
+            "the upgrade is still in progress after the remote stopped sending data".to_owned(),
+        ),
+        (_, Ok(Some(Async::Ready(())))) => CheckOutcome::Passed,
+    }
+}
+
+// Polls `future` until it finishes or `MAX_POLLS` is reached, then destroys it. Errors produced
+// by the upgrade are a valid outcome and are ignored.
+fn poll_bounded<F>(future: F) -> Async<()>
+where
+    F: Future,
+{
+    struct NoopNotify;
+    impl Notify for NoopNotify {
+        fn notify(&self, _: usize) {}
+    }
+
+    let notify = Arc::new(NoopNotify);
+    let mut future = executor::spawn(future);
+    for _ in 0..MAX_POLLS {
+        match future.poll_future_notify(&notify, 0) {
+            Ok(Async::NotReady) => (),
+            Ok(Async::Ready(_)) | Err(_) => return Async::Ready(()),
+        }
+    }
+    Async::NotReady
+}
+
+// Deterministic pseudo-random bytes.
+fn garbage() -> Vec<u8> {
+    let mut state: u32 = 0x1234_5678;
+    (0..512)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// In-memory socket that plays the role of the remote during the checks of `check_upgrade`.
+pub struct TestSocket {
+    // Data that remains to be read. `None` if the remote never sends anything.
+    to_read: Option<VecDeque<u8>>,
+    // If true, all operations fail with `ConnectionReset`.
+    reset: bool,
+    // If true, reads and writes handle one byte at a time and every other call isn't ready.
+    slow: bool,
+    // Used to alternate between ready and not ready when `slow` is true.
+    ready: bool,
+    written: Vec<u8>,
+}
+
+impl TestSocket {
+    // Socket from which `data` can be read, followed by the end of the stream.
+    fn new(data: Vec<u8>) -> TestSocket {
+        TestSocket {
+            to_read: Some(data.into_iter().collect()),
+            reset: false,
+            slow: false,
+            ready: false,
+            written: Vec::new(),
+        }
+    }
+
+    // Socket on which every operation fails.
+    fn reset() -> TestSocket {
+        TestSocket {
+            reset: true,
+            ..TestSocket::new(Vec::new())
+        }
+    }
+
+    // Socket that is never ready for reading.
+    fn silent() -> TestSocket {
+        TestSocket {
+            to_read: None,
+            ..TestSocket::new(Vec::new())
+        }
+    }
+
+    // Makes the socket slow.
+    fn slow(self) -> TestSocket {
+        TestSocket { slow: true, ..self }
+    }
+
+    /// Returns the data that the upgrade has written to the socket so far.
+    #[inline]
+    pub fn written(&self) -> &[u8] {
+        &self.written
+    }
+
+    // Returns an error if the operation can't proceed now.
+    fn check_ready(&mut self) -> Result<(), IoError> {
+        if self.reset {
+            return Err(IoErrorKind::ConnectionReset.into());
+        }
+        if self.slow {
+            self.ready = !self.ready;
+            if !self.ready {
+                return Err(IoErrorKind::WouldBlock.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for TestSocket {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        self.check_ready()?;
+        let max = if self.slow { 1 } else { buf.len() };
+        match self.to_read {
+            Some(ref mut data) => {
+                let len = data.len().min(max).min(buf.len());
+                for (dest, byte) in buf.iter_mut().zip(data.drain(..len)) {
+                    *dest = byte;
+                }
+                Ok(len)
+            }
+            None => Err(IoErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl AsyncRead for TestSocket {}
+
+impl Write for TestSocket {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.check_ready()?;
+        let len = if self.slow { buf.len().min(1) } else { buf.len() };
+        self.written.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), IoError> {
+        if self.reset {
+            return Err(IoErrorKind::ConnectionReset.into());
+        }
+        Ok(())
+    }
+}
+
+impl AsyncWrite for TestSocket {
+    #[inline]
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        self.flush()?;
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_upgrade, ConformanceCheck, TestSocket};
+    use std::io::{Error as IoError, Read, Write};
+    use transport::{Endpoint, SimpleProtocol};
+
+    #[test]
+    fn well_behaved_upgrade_passes() {
+        let report = check_upgrade(|| {
+            SimpleProtocol::new("/echo/1.0.0", |mut socket: TestSocket| {
+                let mut buf = [0; 16];
+                let len = socket.read(&mut buf).unwrap_or(0);
+                let _ = socket.write(&buf[..len]);
+                Ok::<_, IoError>(())
+            })
+        });
+        assert!(report.is_ok(), "{:?}", report.failures());
+    }
+
+    #[test]
+    fn misbehaving_upgrade_fails() {
+        let report = check_upgrade(|| {
+            SimpleProtocol::new("invalid-name", |mut socket: TestSocket| {
+                let mut buf = [0; 16];
+                socket.read_exact(&mut buf).unwrap();
+                Ok::<_, IoError>(())
+            })
+        });
+
+        let failures = report.failures().into_iter().map(|(c, e, _)| (c, e)).collect::<Vec<_>>();
+        assert!(failures.contains(&(ConformanceCheck::ProtocolNames, None)));
+        assert!(failures.contains(&(ConformanceCheck::ImmediateEof, Some(Endpoint::Dialer))));
+        assert!(failures.contains(&(ConformanceCheck::Reset, Some(Endpoint::Listener))));
+        assert!(!failures.contains(&(ConformanceCheck::GarbageInput, Some(Endpoint::Dialer))));
+    }
+}
@@ -42,14 +42,14 @@
 use fnv::FnvHashMap;
 use futures::future::{self, FutureResult, IntoFuture};
 use futures::{Async, Future, Poll, Stream};
-use futures::stream::Fuse as StreamFuse;
+use futures::stream::{self, Fuse as StreamFuse};
 use futures::sync::mpsc;
 use multiaddr::Multiaddr;
 use muxing::StreamMuxer;
 use parking_lot::Mutex;
 use std::io::Error as IoError;
 use std::sync::Arc;
-use transport::{ConnectionUpgrade, MuxedTransport, Transport, UpgradedNode};
+use transport::{ConnectionUpgrade, LocalIdentity, MuxedTransport, Transport, UpgradedNode};
 
 /// Allows reusing the same muxed connection multiple times.
 ///
@@ -111,6 +111,37 @@ where
     }
 }
 
+impl<T, C> ConnectionReuse<T, C>
+where
+    T: Transport,
+    C: ConnectionUpgrade<T::RawConn>,
+    C::Output: StreamMuxer,
+{
+    /// Returns the addresses of the remotes we have a muxed connection with, whether we dialed
+    /// them or they dialed us. Dialing one of these addresses opens a new substream on the
+    /// existing connection.
+    ///
+    /// > **Note**: A connection is only forgotten once it is replaced with a new connection to the
+    /// >           same address, therefore connections closed by the remote are still reported.
+    pub fn connected_addrs(&self) -> Vec<Multiaddr> {
+        self.shared.lock().active_connections.keys().cloned().collect()
+    }
+
+    /// Returns a transport that only opens substreams on the connections of this
+    /// `ConnectionReuse`. Dialing an address that we have no connection with fails, instead of
+    /// opening a new connection, and listening isn't supported.
+    #[inline]
+    pub fn existing_only(&self) -> ExistingConnections<T, C>
+    where
+        T: Clone,
+        C: Clone,
+    {
+        ExistingConnections {
+            reuse: self.clone(),
+        }
+    }
+}
+
 impl<T, C> Transport for ConnectionReuse<T, C>
 where
     T: Transport + 'static,                     // TODO: 'static :(
@@ -197,6 +228,16 @@ where
     fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
         self.inner.transport().nat_traversal(server, observed)
     }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.inner.transport().local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.inner.transport().reports_peer_ids()
+    }
 }
 
 impl<T, C> MuxedTransport for ConnectionReuse<T, C>
@@ -219,6 +260,71 @@ where
     }
 }
 
+/// Transport that opens substreams on the existing connections of a `ConnectionReuse`, and never
+/// opens new connections.
+///
+/// Created with `ConnectionReuse::existing_only`.
+#[derive(Clone)]
+pub struct ExistingConnections<T, C>
+where
+    T: Transport,
+    C: ConnectionUpgrade<T::RawConn>,
+    C::Output: StreamMuxer,
+{
+    reuse: ConnectionReuse<T, C>,
+}
+
+impl<T, C> Transport for ExistingConnections<T, C>
+where
+    T: Transport + 'static,                     // TODO: 'static :(
+    C: ConnectionUpgrade<T::RawConn> + 'static, // TODO: 'static :(
+    C: Clone,
+    C::Output: StreamMuxer + Clone,
+    C::NamesIter: Clone, // TODO: not elegant
+{
+    type RawConn = <C::Output as StreamMuxer>::Substream;
+    type Listener = stream::Empty<Self::ListenerUpgrade, IoError>;
+    type ListenerUpgrade = FutureResult<(Self::RawConn, Multiaddr), IoError>;
+    type Dial = Box<Future<Item = (Self::RawConn, Multiaddr), Error = IoError>>;
+
+    #[inline]
+    fn listen_on(self, addr: Multiaddr) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+        Err((self, addr))
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+        let connec = self.reuse
+            .shared
+            .lock()
+            .active_connections
+            .get(&addr)
+            .map(|c| c.clone());
+
+        match connec {
+            Some(connec) => {
+                let future = connec.outbound().map(|s| (s, addr));
+                Ok(Box::new(future) as Box<_>)
+            }
+            None => Err((self, addr)),
+        }
+    }
+
+    #[inline]
+    fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.reuse.nat_traversal(server, observed)
+    }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.reuse.local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.reuse.reports_peer_ids()
+    }
+}
+
 /// Implementation of `Stream` for the connections incoming from listening on a specific address.
 pub struct ConnectionReuseListener<S, F, M>
 where
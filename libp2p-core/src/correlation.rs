@@ -0,0 +1,292 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `Correlate` connection upgrade, which gives an identifier to every substream.
+//!
+//! Wrapping an upgrade with `UpgradeExt::with_correlation_id` assigns a new `CorrelationId` to
+//! each socket that goes through the upgrade. The identifier is included in the logs of the
+//! negotiation, of every read and write on the socket, and of its closing. It is also produced
+//! alongside the output of the upgrade, so that the code that uses the substream can include it
+//! in its own logs and events. Searching for the identifier then shows the whole life of a request
+//! across the layers.
+//!
+//! Logs are produced with the `libp2p-core` target. The negotiation and the closing are logged at
+//! the `debug` level, and each read and write at the `trace` level.
+
+use bytes::Bytes;
+use futures::{Async, Future, Poll};
+use multiaddr::Multiaddr;
+use std::fmt;
+use std::io::{Error as IoError, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::{ConnectionUpgrade, Endpoint, LocalIdentity};
+
+// Source of the values of `CorrelationId::next`.
+static NEXT_CORRELATION_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Identifier of a substream, unique within the process.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CorrelationId(usize);
+
+impl CorrelationId {
+    /// Generates a new identifier, different from all the ones that have been generated before.
+    #[inline]
+    pub fn next() -> CorrelationId {
+        CorrelationId(NEXT_CORRELATION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Returns the numeric value of the identifier.
+    #[inline]
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Wraps around an upgrade and assigns a `CorrelationId` to each socket that it upgrades.
+///
+/// The output of the upgrade is a tuple of the identifier and of the output of the inner upgrade.
+#[derive(Debug, Copy, Clone)]
+pub struct Correlate<U> {
+    upgrade: U,
+}
+
+impl<U> Correlate<U> {
+    /// Builds a new `Correlate`.
+    #[inline]
+    pub fn new(upgrade: U) -> Correlate<U> {
+        Correlate { upgrade: upgrade }
+    }
+}
+
+impl<C, U> ConnectionUpgrade<C> for Correlate<U>
+where
+    C: AsyncRead + AsyncWrite,
+    U: ConnectionUpgrade<CorrelatedSocket<C>>,
+{
+    type NamesIter = CorrelateNames<U::NamesIter>;
+    type UpgradeIdentifier = (Bytes, U::UpgradeIdentifier);
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        CorrelateNames {
+            inner: self.upgrade.protocol_names(),
+        }
+    }
+
+    type Output = (CorrelationId, U::Output);
+    type Future = CorrelateFuture<U::Future>;
+
+    fn upgrade(
+        self,
+        socket: C,
+        (protocol, id): Self::UpgradeIdentifier,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        let correlation_id = CorrelationId::next();
+        debug!(target: "libp2p-core", "{} negotiated {:?} with {} as {:?}", correlation_id,
+               protocol, remote_addr, ty);
+
+        let socket = CorrelatedSocket {
+            inner: socket,
+            id: correlation_id,
+        };
+
+        CorrelateFuture {
+            inner: self.upgrade.upgrade(socket, id, ty, remote_addr, local_identity),
+            id: correlation_id,
+        }
+    }
+}
+
+/// Iterator returned by `Correlate::protocol_names`. Remembers the name of each protocol in its
+/// identifier.
+pub struct CorrelateNames<I> {
+    inner: I,
+}
+
+impl<I, Id> Iterator for CorrelateNames<I>
+where
+    I: Iterator<Item = (Bytes, Id)>,
+{
+    type Item = (Bytes, (Bytes, Id));
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(name, id)| (name.clone(), (name, id)))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Future returned by `Correlate::upgrade`.
+pub struct CorrelateFuture<F> {
+    inner: F,
+    id: CorrelationId,
+}
+
+impl<F> Future for CorrelateFuture<F>
+where
+    F: Future<Error = IoError>,
+{
+    type Item = (CorrelationId, F::Item);
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner.poll() {
+            Ok(Async::Ready(output)) => {
+                debug!(target: "libp2p-core", "{} upgrade finished", self.id);
+                Ok(Async::Ready((self.id, output)))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => {
+                debug!(target: "libp2p-core", "{} upgrade failed: {:?}", self.id, err);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Socket passed to the upgrade wrapped by a `Correlate`. Logs its activity along with its
+/// `CorrelationId`.
+pub struct CorrelatedSocket<C> {
+    inner: C,
+    id: CorrelationId,
+}
+
+impl<C> CorrelatedSocket<C> {
+    /// Returns the identifier of the socket.
+    #[inline]
+    pub fn correlation_id(&self) -> CorrelationId {
+        self.id
+    }
+}
+
+impl<C> Read for CorrelatedSocket<C>
+where
+    C: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let result = self.inner.read(buf);
+        match result {
+            Ok(0) if !buf.is_empty() => {
+                debug!(target: "libp2p-core", "{} closed by remote", self.id)
+            }
+            Ok(n) => trace!(target: "libp2p-core", "{} read {} bytes", self.id, n),
+            Err(ref err) => trace!(target: "libp2p-core", "{} read error: {:?}", self.id, err),
+        }
+        result
+    }
+}
+
+impl<C> AsyncRead for CorrelatedSocket<C>
+where
+    C: AsyncRead,
+{
+    #[inline]
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<C> Write for CorrelatedSocket<C>
+where
+    C: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let result = self.inner.write(buf);
+        match result {
+            Ok(n) => trace!(target: "libp2p-core", "{} wrote {} bytes", self.id, n),
+            Err(ref err) => trace!(target: "libp2p-core", "{} write error: {:?}", self.id, err),
+        }
+        result
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+
+impl<C> AsyncWrite for CorrelatedSocket<C>
+where
+    C: AsyncWrite,
+{
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        let result = self.inner.shutdown();
+        if let Ok(Async::Ready(())) = result {
+            debug!(target: "libp2p-core", "{} closed by us", self.id);
+        }
+        result
+    }
+}
+
+impl<C> Drop for CorrelatedSocket<C> {
+    #[inline]
+    fn drop(&mut self) {
+        trace!(target: "libp2p-core", "{} destroyed", self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Correlate, CorrelatedSocket};
+    use futures::Future;
+    use std::io::{Cursor, Error as IoError};
+    use transport::{ConnectionUpgrade, Endpoint, LocalIdentity, SimpleProtocol};
+
+    #[test]
+    fn ids_are_distinct_and_visible() {
+        let upgrade = Correlate::new(SimpleProtocol::new(
+            "/test/1.0.0",
+            |socket: CorrelatedSocket<Cursor<Vec<u8>>>| Ok::<_, IoError>(socket.correlation_id()),
+        ));
+        let addr = "/ip4/1.2.3.4/tcp/5".parse().unwrap();
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let (_, id) = ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&upgrade)
+                .next()
+                .unwrap();
+            let socket = Cursor::new(Vec::new());
+            let (id, inner_id) = upgrade
+                .clone()
+                .upgrade(socket, id, Endpoint::Dialer, &addr, &LocalIdentity::unknown())
+                .wait()
+                .unwrap();
+            assert_eq!(id, inner_id);
+            ids.push(id);
+        }
+
+        assert_ne!(ids[0], ids[1]);
+    }
+}
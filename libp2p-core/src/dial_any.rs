@@ -0,0 +1,237 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `dial_any` function, which dials a list of candidate multiaddresses of the same
+//! node one by one until one of them succeeds.
+//!
+//! The candidates are tried in the order in which they are produced by the iterator, which means
+//! that the iterator should produce the preferred addresses first. If all the candidates fail, the
+//! future produces a `DialAnyError` that contains the reason of the failure of each candidate.
+//!
+//! Calling `with_concurrency` on the returned future allows several candidates to be dialed at
+//! the same time. The first attempt that succeeds wins, and the other attempts are cancelled.
+//!
+//! > **Note**: Combine this with `Transport::with_timeout` in order to abort slow attempts and
+//! >           move quickly to the next candidate.
+
+use futures::{Async, Future, Poll};
+use multiaddr::Multiaddr;
+use std::error;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::mem;
+
+/// Dials the multiaddresses produced by `addrs` one by one, until one of them succeeds.
+///
+/// `dial` is called for each candidate, and must return the future of the dialing attempt or give
+/// back the multiaddress if it is not supported. For a `Transport`, this is typically
+/// `|addr| transport.clone().dial(addr).map_err(|(_, addr)| addr)`.
+///
+/// The future resolves to the output of the first successful attempt. If every attempt fails, or
+/// if `addrs` is empty, an error containing the failure of each candidate is produced instead.
+#[inline]
+pub fn dial_any<I, D, F>(addrs: I, dial: D) -> DialAny<I::IntoIter, D, F>
+where
+    I: IntoIterator<Item = Multiaddr>,
+    D: FnMut(Multiaddr) -> Result<F, Multiaddr>,
+    F: Future<Error = IoError>,
+{
+    DialAny {
+        remaining: addrs.into_iter(),
+        dial: dial,
+        concurrency: 1,
+        current: Vec::new(),
+        attempts: Vec::new(),
+    }
+}
+
+/// Future returned by `dial_any`.
+pub struct DialAny<I, D, F> {
+    remaining: I,
+    dial: D,
+    // Maximum number of elements in `current`.
+    concurrency: usize,
+    current: Vec<(Multiaddr, F)>,
+    attempts: Vec<(Multiaddr, DialAttemptError)>,
+}
+
+impl<I, D, F> DialAny<I, D, F> {
+    /// Allows up to `concurrency` candidates to be dialed at the same time. Defaults to 1, which
+    /// means that a candidate is only dialed after the previous one has failed.
+    ///
+    /// Candidates are still started in order. As soon as an attempt succeeds, the other ones are
+    /// destroyed, which cancels them. The failures reported by `DialAnyError` are then in the
+    /// order in which the attempts failed, which isn't necessarily the order of the candidates.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `concurrency` is 0.
+    #[inline]
+    pub fn with_concurrency(mut self, concurrency: usize) -> DialAny<I, D, F> {
+        assert_ne!(concurrency, 0, "the concurrency of dial_any must be at least 1");
+        self.concurrency = concurrency;
+        self
+    }
+}
+
+impl<I, D, F> Future for DialAny<I, D, F>
+where
+    I: Iterator<Item = Multiaddr>,
+    D: FnMut(Multiaddr) -> Result<F, Multiaddr>,
+    F: Future<Error = IoError>,
+{
+    type Item = F::Item;
+    type Error = DialAnyError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            // Start new attempts until we reach the concurrency limit or run out of candidates.
+            let mut exhausted = false;
+            while self.current.len() < self.concurrency {
+                let addr = match self.remaining.next() {
+                    Some(addr) => addr,
+                    None => {
+                        exhausted = true;
+                        break;
+                    }
+                };
+
+                match (self.dial)(addr.clone()) {
+                    Ok(attempt) => self.current.push((addr, attempt)),
+                    Err(addr) => {
+                        self.attempts
+                            .push((addr, DialAttemptError::MultiaddrNotSupported));
+                    }
+                }
+            }
+
+            if self.current.is_empty() {
+                debug_assert!(exhausted);
+                return Err(DialAnyError {
+                    attempts: mem::replace(&mut self.attempts, Vec::new()),
+                });
+            }
+
+            let mut failed = false;
+            let mut n = 0;
+            while n < self.current.len() {
+                match self.current[n].1.poll() {
+                    Ok(Async::Ready(output)) => {
+                        // Dropping the other attempts cancels them.
+                        self.current.clear();
+                        return Ok(Async::Ready(output));
+                    }
+                    Ok(Async::NotReady) => n += 1,
+                    Err(err) => {
+                        let (addr, _) = self.current.remove(n);
+                        self.attempts.push((addr, DialAttemptError::Io(err)));
+                        failed = true;
+                    }
+                }
+            }
+
+            // If an attempt failed, we may be able to start a new one.
+            if self.current.is_empty() || (failed && !exhausted) {
+                continue;
+            }
+
+            return Ok(Async::NotReady);
+        }
+    }
+}
+
+/// Reason why dialing a single candidate failed.
+#[derive(Debug)]
+pub enum DialAttemptError {
+    /// The multiaddress is not supported by the transport.
+    MultiaddrNotSupported,
+    /// The dialing attempt started but produced an error.
+    Io(IoError),
+}
+
+impl fmt::Display for DialAttemptError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &DialAttemptError::MultiaddrNotSupported => {
+                write!(f, "multiaddr not supported by the transport")
+            }
+            &DialAttemptError::Io(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Error produced by `dial_any` when none of the candidates could be dialed.
+#[derive(Debug)]
+pub struct DialAnyError {
+    attempts: Vec<(Multiaddr, DialAttemptError)>,
+}
+
+impl DialAnyError {
+    /// Returns the candidates that have been tried, in order, with the reason of their failure.
+    ///
+    /// Empty if no candidate was passed to `dial_any`.
+    #[inline]
+    pub fn attempts(&self) -> &[(Multiaddr, DialAttemptError)] {
+        &self.attempts
+    }
+
+    /// Turns this error into the list of candidates and the reason of their failure.
+    #[inline]
+    pub fn into_attempts(self) -> Vec<(Multiaddr, DialAttemptError)> {
+        self.attempts
+    }
+}
+
+impl fmt::Display for DialAnyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.attempts.is_empty() {
+            return write!(f, "no multiaddr to dial");
+        }
+
+        write!(f, "failed to dial all the multiaddrs:")?;
+        for &(ref addr, ref err) in self.attempts.iter() {
+            write!(f, " {}: {};", addr, err)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for DialAnyError {
+    #[inline]
+    fn description(&self) -> &str {
+        "failed to dial all the multiaddrs"
+    }
+
+    #[inline]
+    fn cause(&self) -> Option<&error::Error> {
+        match self.attempts.last() {
+            Some(&(_, DialAttemptError::Io(ref err))) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<DialAnyError> for IoError {
+    #[inline]
+    fn from(err: DialAnyError) -> IoError {
+        IoError::new(IoErrorKind::Other, err)
+    }
+}
@@ -0,0 +1,355 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `DialHistoryTransport` struct, which remembers the outcome of recent dialing
+//! attempts.
+//!
+//! Wrapping a transport in a `DialHistoryTransport` makes it record every call to `dial` in a
+//! `DialHistory`, alongside with when it happened, how long it took and how it ended. The
+//! `DialHistory` can then be queried at any time, which makes it possible to answer questions
+//! such as "why can't I connect to this node" without having to enable logging and reproduce the
+//! problem.
+//!
+//! Attempts are grouped by the multiaddress that was dialed. If the wrapped transport accepts
+//! multiaddresses of the form `/p2p/...`, for example an `IdentifyTransport`, then this
+//! corresponds to one entry per peer.
+//!
+//! Only the most recent attempts are kept for each multiaddress. Note however that there is no
+//! limit to the number of multiaddresses; call `DialHistory::remove` or `DialHistory::clear` if
+//! necessary.
+
+use fnv::FnvHashMap;
+use futures::{Async, Future, IntoFuture, Poll};
+use multiaddr::Multiaddr;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use transport::{LocalIdentity, MuxedTransport, Transport};
+
+/// Storage for the most recent dialing attempts. Cloning a `DialHistory` gives access to the same
+/// storage.
+#[derive(Debug, Clone)]
+pub struct DialHistory {
+    inner: Arc<Mutex<DialHistoryInner>>,
+}
+
+#[derive(Debug)]
+struct DialHistoryInner {
+    // Maximum number of attempts to keep for each multiaddress.
+    max_per_addr: usize,
+    attempts: FnvHashMap<Multiaddr, VecDeque<DialAttempt>>,
+}
+
+/// A single dialing attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DialAttempt {
+    /// When `dial` was called.
+    pub started_at: SystemTime,
+    /// How long it took to reach the outcome.
+    pub duration: Duration,
+    /// How the attempt ended.
+    pub outcome: DialOutcome,
+}
+
+/// How a dialing attempt ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DialOutcome {
+    /// The connection has been successfully opened.
+    Success,
+    /// The dialing attempt produced an error.
+    Failed {
+        /// Kind of the error.
+        kind: IoErrorKind,
+        /// Description of the error.
+        message: String,
+    },
+    /// The transport doesn't support dialing this multiaddress.
+    Unsupported,
+    /// The future was destroyed before it finished.
+    Cancelled,
+}
+
+impl DialHistory {
+    /// Builds an empty `DialHistory` that keeps at most `max_per_addr` attempts for each
+    /// multiaddress.
+    #[inline]
+    pub fn new(max_per_addr: usize) -> DialHistory {
+        DialHistory {
+            inner: Arc::new(Mutex::new(DialHistoryInner {
+                max_per_addr: max_per_addr,
+                attempts: FnvHashMap::default(),
+            })),
+        }
+    }
+
+    /// Returns the attempts to dial `addr` that are still in the history, from the oldest to the
+    /// most recent. Attempts that are still in progress are not included.
+    pub fn attempts(&self, addr: &Multiaddr) -> Vec<DialAttempt> {
+        self.inner
+            .lock()
+            .attempts
+            .get(addr)
+            .map(|attempts| attempts.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the list of multiaddresses for which there is at least one attempt.
+    pub fn addrs(&self) -> Vec<Multiaddr> {
+        self.inner.lock().attempts.keys().cloned().collect()
+    }
+
+    /// Removes all the attempts to dial `addr`.
+    #[inline]
+    pub fn remove(&self, addr: &Multiaddr) {
+        self.inner.lock().attempts.remove(addr);
+    }
+
+    /// Removes all the attempts from the history.
+    #[inline]
+    pub fn clear(&self) {
+        self.inner.lock().attempts.clear();
+    }
+
+    fn record(&self, addr: Multiaddr, attempt: DialAttempt) {
+        let mut inner = self.inner.lock();
+        if inner.max_per_addr == 0 {
+            return;
+        }
+
+        let max = inner.max_per_addr;
+        let attempts = inner.attempts.entry(addr).or_insert_with(VecDeque::new);
+        while attempts.len() >= max {
+            attempts.pop_front();
+        }
+        attempts.push_back(attempt);
+    }
+}
+
+/// Wraps around a `Transport` and records the dialing attempts in a `DialHistory`.
+///
+/// See [the module-level documentation](index.html).
+#[derive(Debug, Clone)]
+pub struct DialHistoryTransport<T> {
+    inner: T,
+    history: DialHistory,
+}
+
+impl<T> DialHistoryTransport<T> {
+    /// Wraps around `transport` and records its dialing attempts in `history`.
+    #[inline]
+    pub fn new(transport: T, history: DialHistory) -> DialHistoryTransport<T> {
+        DialHistoryTransport {
+            inner: transport,
+            history: history,
+        }
+    }
+
+    /// Returns the history where the attempts are recorded.
+    #[inline]
+    pub fn history(&self) -> &DialHistory {
+        &self.history
+    }
+}
+
+impl<T> Transport for DialHistoryTransport<T>
+where
+    T: Transport,
+{
+    type RawConn = T::RawConn;
+    type Listener = T::Listener;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = DialHistoryFuture<<T::Dial as IntoFuture>::Future>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+        match self.inner.listen_on(addr) {
+            Ok(val) => Ok(val),
+            Err((inner, addr)) => {
+                let transport = DialHistoryTransport {
+                    inner,
+                    history: self.history,
+                };
+                Err((transport, addr))
+            }
+        }
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+        let started_at = SystemTime::now();
+        let started_instant = Instant::now();
+
+        match self.inner.dial(addr.clone()) {
+            Ok(dial) => Ok(DialHistoryFuture {
+                inner: dial.into_future(),
+                record: Some((self.history, addr, started_at, started_instant)),
+            }),
+            Err((inner, addr)) => {
+                self.history.record(
+                    addr.clone(),
+                    DialAttempt {
+                        started_at,
+                        duration: started_instant.elapsed(),
+                        outcome: DialOutcome::Unsupported,
+                    },
+                );
+
+                let transport = DialHistoryTransport {
+                    inner,
+                    history: self.history,
+                };
+                Err((transport, addr))
+            }
+        }
+    }
+
+    #[inline]
+    fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.nat_traversal(server, observed)
+    }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.inner.local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.inner.reports_peer_ids()
+    }
+}
+
+impl<T> MuxedTransport for DialHistoryTransport<T>
+where
+    T: MuxedTransport,
+{
+    type Incoming = T::Incoming;
+    type IncomingUpgrade = T::IncomingUpgrade;
+
+    #[inline]
+    fn next_incoming(self) -> Self::Incoming {
+        self.inner.next_incoming()
+    }
+}
+
+/// Future returned by `DialHistoryTransport::dial`. Records the outcome of the attempt when it
+/// finishes or when it is destroyed.
+pub struct DialHistoryFuture<F> {
+    inner: F,
+    // Where to record the attempt, and when it started. `None` once the attempt has been recorded.
+    record: Option<(DialHistory, Multiaddr, SystemTime, Instant)>,
+}
+
+impl<F> DialHistoryFuture<F> {
+    fn finish(&mut self, outcome: DialOutcome) {
+        if let Some((history, addr, started_at, started_instant)) = self.record.take() {
+            let attempt = DialAttempt {
+                started_at,
+                duration: started_instant.elapsed(),
+                outcome,
+            };
+            history.record(addr, attempt);
+        }
+    }
+}
+
+impl<F> Future for DialHistoryFuture<F>
+where
+    F: Future<Error = IoError>,
+{
+    type Item = F::Item;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner.poll() {
+            Ok(Async::Ready(val)) => {
+                self.finish(DialOutcome::Success);
+                Ok(Async::Ready(val))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => {
+                self.finish(DialOutcome::Failed {
+                    kind: err.kind(),
+                    message: err.to_string(),
+                });
+                Err(err)
+            }
+        }
+    }
+}
+
+impl<F> Drop for DialHistoryFuture<F> {
+    #[inline]
+    fn drop(&mut self) {
+        self.finish(DialOutcome::Cancelled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DialHistory, DialHistoryTransport, DialOutcome};
+    use futures::{future, Future};
+    use multiaddr::Multiaddr;
+    use raw_stream::RawStreamTransport;
+    use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind};
+    use transport::Transport;
+
+    #[test]
+    fn attempts_recorded() {
+        let good: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let bad: Multiaddr = "/ip4/127.0.0.1/tcp/2".parse().unwrap();
+        let unsupported: Multiaddr = "/ip4/127.0.0.1/udp/3".parse().unwrap();
+
+        let (good2, bad2) = (good.clone(), bad.clone());
+        let raw = RawStreamTransport::dial_only(move |addr: &Multiaddr| {
+            if *addr == good2 {
+                Some(future::ok(Cursor::new(Vec::<u8>::new())))
+            } else if *addr == bad2 {
+                Some(future::err(IoError::new(IoErrorKind::ConnectionRefused, "refused")))
+            } else {
+                None
+            }
+        });
+
+        let history = DialHistory::new(2);
+        let transport = DialHistoryTransport::new(raw, history.clone());
+
+        for _ in 0..3 {
+            transport.clone().dial(good.clone()).unwrap_or_else(|_| panic!()).wait().unwrap();
+        }
+        assert!(transport.clone().dial(bad.clone()).unwrap_or_else(|_| panic!()).wait().is_err());
+        assert!(transport.clone().dial(unsupported.clone()).is_err());
+        drop(transport.clone().dial(good.clone()).unwrap_or_else(|_| panic!()));
+
+        let good_attempts = history.attempts(&good);
+        assert_eq!(good_attempts.len(), 2);
+        assert_eq!(good_attempts[0].outcome, DialOutcome::Success);
+        assert_eq!(good_attempts[1].outcome, DialOutcome::Cancelled);
+
+        let bad_attempts = history.attempts(&bad);
+        assert_eq!(bad_attempts.len(), 1);
+        match bad_attempts[0].outcome {
+            DialOutcome::Failed { kind, .. } => assert_eq!(kind, IoErrorKind::ConnectionRefused),
+            _ => panic!(),
+        }
+
+        assert_eq!(history.attempts(&unsupported)[0].outcome, DialOutcome::Unsupported);
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `Interceptor` trait, which makes it possible to wrap around the socket of any
+//! connection upgrade.
+//!
+//! An interceptor is given the socket after the protocol has been negotiated and before it is
+//! passed to the upgrade. It can return a wrapper around this socket, which allows observing or
+//! modifying all the data that the protocol sends and receives. This can be used for metrics,
+//! validation, or injecting faults in tests.
+//!
+//! Interceptors are applied with `UpgradeExt::intercept`, and can be stacked by calling this
+//! method multiple times. The interceptor that is applied last sees the raw data first when
+//! receiving, and last when sending.
+//!
+//! The `observe` function builds an interceptor that reports all the data passing through the
+//! socket to a closure.
+
+use futures::Poll;
+use multiaddr::Multiaddr;
+use std::io::{Error as IoError, Read, Write};
+use std::sync::Arc;
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::{ConnectionUpgrade, Endpoint, LocalIdentity};
+
+/// Wraps around the socket of a connection upgrade.
+///
+/// Implemented on all the closures that take the socket, the endpoint and the remote address, and
+/// return a new socket.
+pub trait Interceptor<C> {
+    /// Socket that is passed to the upgrade.
+    type Output: AsyncRead + AsyncWrite;
+
+    /// Wraps around `socket`. Called once for each successful protocol negotiation.
+    fn intercept(&self, socket: C, endpoint: Endpoint, remote_addr: &Multiaddr) -> Self::Output;
+}
+
+impl<C, F, O> Interceptor<C> for F
+where
+    F: Fn(C, Endpoint, &Multiaddr) -> O,
+    O: AsyncRead + AsyncWrite,
+{
+    type Output = O;
+
+    #[inline]
+    fn intercept(&self, socket: C, endpoint: Endpoint, remote_addr: &Multiaddr) -> O {
+        self(socket, endpoint, remote_addr)
+    }
+}
+
+/// Implementation of `ConnectionUpgrade` that passes the socket through an `Interceptor` before
+/// upgrading it. Returned by `UpgradeExt::intercept`.
+#[derive(Debug, Copy, Clone)]
+pub struct Intercept<U, I> {
+    upgrade: U,
+    interceptor: I,
+}
+
+impl<U, I> Intercept<U, I> {
+    /// Builds a new `Intercept`.
+    #[inline]
+    pub fn new(upgrade: U, interceptor: I) -> Intercept<U, I> {
+        Intercept {
+            upgrade: upgrade,
+            interceptor: interceptor,
+        }
+    }
+}
+
+impl<C, U, I> ConnectionUpgrade<C> for Intercept<U, I>
+where
+    C: AsyncRead + AsyncWrite,
+    I: Interceptor<C>,
+    U: ConnectionUpgrade<I::Output>,
+{
+    type NamesIter = U::NamesIter;
+    type UpgradeIdentifier = U::UpgradeIdentifier;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        self.upgrade.protocol_names()
+    }
+
+    type Output = U::Output;
+    type Future = U::Future;
+
+    #[inline]
+    fn upgrade(
+        self,
+        socket: C,
+        id: Self::UpgradeIdentifier,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        let socket = self.interceptor.intercept(socket, ty, remote_addr);
+        self.upgrade.upgrade(socket, id, ty, remote_addr, local_identity)
+    }
+}
+
+/// Direction of the data passed to the closure of `observe`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// The data has been received from the remote.
+    Inbound,
+    /// The data is being sent to the remote.
+    Outbound,
+}
+
+/// Builds an `Interceptor` that calls `observer` with all the data that is received or sent on
+/// the socket.
+///
+/// The closure is shared between all the connections.
+#[inline]
+pub fn observe<F>(observer: F) -> Observe<F>
+where
+    F: Fn(Endpoint, &Multiaddr, Direction, &[u8]),
+{
+    Observe {
+        observer: Arc::new(observer),
+    }
+}
+
+/// Interceptor returned by `observe`.
+pub struct Observe<F> {
+    observer: Arc<F>,
+}
+
+impl<F> Clone for Observe<F> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Observe {
+            observer: self.observer.clone(),
+        }
+    }
+}
+
+impl<C, F> Interceptor<C> for Observe<F>
+where
+    C: AsyncRead + AsyncWrite,
+    F: Fn(Endpoint, &Multiaddr, Direction, &[u8]),
+{
+    type Output = ObservedSocket<C, F>;
+
+    #[inline]
+    fn intercept(&self, socket: C, endpoint: Endpoint, remote_addr: &Multiaddr) -> Self::Output {
+        ObservedSocket {
+            inner: socket,
+            observer: self.observer.clone(),
+            endpoint: endpoint,
+            remote_addr: remote_addr.clone(),
+        }
+    }
+}
+
+/// Socket produced by the `Observe` interceptor.
+pub struct ObservedSocket<C, F> {
+    inner: C,
+    observer: Arc<F>,
+    endpoint: Endpoint,
+    remote_addr: Multiaddr,
+}
+
+impl<C, F> ObservedSocket<C, F> {
+    /// Returns the address of the remote.
+    #[inline]
+    pub fn remote_addr(&self) -> &Multiaddr {
+        &self.remote_addr
+    }
+}
+
+impl<C, F> Read for ObservedSocket<C, F>
+where
+    C: Read,
+    F: Fn(Endpoint, &Multiaddr, Direction, &[u8]),
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let num_read = self.inner.read(buf)?;
+        if num_read != 0 {
+            (self.observer)(
+                self.endpoint,
+                &self.remote_addr,
+                Direction::Inbound,
+                &buf[..num_read],
+            );
+        }
+        Ok(num_read)
+    }
+}
+
+impl<C, F> AsyncRead for ObservedSocket<C, F>
+where
+    C: AsyncRead,
+    F: Fn(Endpoint, &Multiaddr, Direction, &[u8]),
+{
+    #[inline]
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<C, F> Write for ObservedSocket<C, F>
+where
+    C: Write,
+    F: Fn(Endpoint, &Multiaddr, Direction, &[u8]),
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let num_written = self.inner.write(buf)?;
+        if num_written != 0 {
+            (self.observer)(
+                self.endpoint,
+                &self.remote_addr,
+                Direction::Outbound,
+                &buf[..num_written],
+            );
+        }
+        Ok(num_written)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+
+impl<C, F> AsyncWrite for ObservedSocket<C, F>
+where
+    C: AsyncWrite,
+    F: Fn(Endpoint, &Multiaddr, Direction, &[u8]),
+{
+    #[inline]
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{observe, Direction, Intercept};
+    use futures::Future;
+    use multiaddr::Multiaddr;
+    use parking_lot::Mutex;
+    use std::io::{Cursor, Error as IoError, Read, Write};
+    use std::sync::Arc;
+    use transport::{ConnectionUpgrade, Endpoint, LocalIdentity, SimpleProtocol};
+
+    fn exchange<S: Read + Write>(mut socket: S) -> Result<[u8; 3], IoError> {
+        let mut buf = [0; 3];
+        socket.read_exact(&mut buf)?;
+        socket.write_all(&[9, 8])?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn observe_reports_data() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = seen.clone();
+        let interceptor = observe(move |endpoint, _: &Multiaddr, dir, data: &[u8]| {
+            seen2.lock().push((endpoint, dir, data.to_vec()));
+        });
+
+        let upgrade = SimpleProtocol::new("/test/1.0.0", exchange);
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let socket = Cursor::new(vec![1, 2, 3]);
+        let out = Intercept::new(upgrade, interceptor)
+            .upgrade(socket, (), Endpoint::Dialer, &addr, &LocalIdentity::unknown())
+            .wait()
+            .unwrap();
+        assert_eq!(out, [1, 2, 3]);
+
+        assert_eq!(
+            *seen.lock(),
+            vec![
+                (Endpoint::Dialer, Direction::Inbound, vec![1, 2, 3]),
+                (Endpoint::Dialer, Direction::Outbound, vec![9, 8]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `LazyUpgrade` connection upgrade, which only builds the upgrade it wraps once a
+//! protocol has been negotiated.
+//!
+//! Upgrades are normally built when the transport is created and are cloned for every
+//! connection. For protocols that hold a lot of state and are rarely used, it can be preferable
+//! to only create the upgrade when the remote actually wants to use it. A `LazyUpgrade` knows the
+//! names of the protocols in advance, and calls a closure to build the actual upgrade after the
+//! negotiation succeeded.
+
+use bytes::Bytes;
+use futures::future::{self, Either, FutureResult};
+use multiaddr::Multiaddr;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::sync::Arc;
+use std::vec::IntoIter as VecIntoIter;
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::{ConnectionUpgrade, Endpoint, LocalIdentity};
+
+/// Implementation of `ConnectionUpgrade` that builds the upgrade to apply only after the protocol
+/// has been negotiated.
+#[derive(Debug)]
+pub struct LazyUpgrade<F> {
+    names: Arc<Vec<Bytes>>,
+    factory: Arc<F>,
+}
+
+impl<F> LazyUpgrade<F> {
+    /// Builds a `LazyUpgrade` that advertises `names`, and calls `factory` to create the upgrade
+    /// whenever one of them is negotiated.
+    ///
+    /// The names must be a subset of the ones returned by the `protocol_names` method of the
+    /// upgrade produced by `factory`, otherwise the upgrade will fail.
+    pub fn new<I, N>(names: I, factory: F) -> LazyUpgrade<F>
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<Bytes>,
+    {
+        LazyUpgrade {
+            names: Arc::new(names.into_iter().map(Into::into).collect()),
+            factory: Arc::new(factory),
+        }
+    }
+}
+
+impl<F> Clone for LazyUpgrade<F> {
+    #[inline]
+    fn clone(&self) -> Self {
+        LazyUpgrade {
+            names: self.names.clone(),
+            factory: self.factory.clone(),
+        }
+    }
+}
+
+impl<C, F, U> ConnectionUpgrade<C> for LazyUpgrade<F>
+where
+    C: AsyncRead + AsyncWrite,
+    F: Fn() -> U,
+    U: ConnectionUpgrade<C>,
+{
+    type NamesIter = VecIntoIter<(Bytes, Bytes)>;
+    type UpgradeIdentifier = Bytes;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        self.names
+            .iter()
+            .map(|name| (name.clone(), name.clone()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    type Output = U::Output;
+    type Future = Either<U::Future, FutureResult<U::Output, IoError>>;
+
+    fn upgrade(
+        self,
+        socket: C,
+        name: Bytes,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        let upgrade = (self.factory)();
+        let id = upgrade
+            .protocol_names()
+            .find(|&(ref n, _)| *n == name)
+            .map(|(_, id)| id);
+
+        match id {
+            Some(id) => Either::A(upgrade.upgrade(socket, id, ty, remote_addr, local_identity)),
+            None => {
+                let err = IoError::new(
+                    IoErrorKind::Other,
+                    "negotiated protocol isn't supported by the lazily-built upgrade",
+                );
+                Either::B(future::err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LazyUpgrade;
+    use futures::Future;
+    use multiaddr::Multiaddr;
+    use std::io::{Cursor, Error as IoError};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use transport::{ConnectionUpgrade, Endpoint, LocalIdentity, SimpleProtocol};
+
+    fn consume(_: Cursor<Vec<u8>>) -> Result<u32, IoError> {
+        Ok(12)
+    }
+
+    #[test]
+    fn factory_called_on_upgrade() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls2 = calls.clone();
+        let upgrade = LazyUpgrade::new(vec!["/test/1.0.0"], move || {
+            calls2.fetch_add(1, Ordering::SeqCst);
+            SimpleProtocol::new("/test/1.0.0", consume)
+        });
+
+        let (name, id) = ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&upgrade)
+            .next()
+            .unwrap();
+        assert_eq!(name, "/test/1.0.0");
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let out = upgrade
+            .upgrade(Cursor::new(vec![]), id, Endpoint::Listener, &addr, &LocalIdentity::unknown())
+            .wait()
+            .unwrap();
+        assert_eq!(out, 12);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
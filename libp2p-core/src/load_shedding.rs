@@ -0,0 +1,338 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `LoadShedder` struct, which refuses inbound substreams by order of priority when
+//! too many of them are being upgraded at the same time.
+//!
+//! Each upgrade is wrapped with `LoadShedder::wrap` and given a `Priority`. All the upgrades
+//! wrapped by the same `LoadShedder` share a counter of the inbound upgrades that are in
+//! progress. Once this counter reaches a certain fraction of the limit passed to
+//! `LoadShedder::new`, new inbound substreams of the protocols whose priority is too low are
+//! immediately closed, while the protocols with a higher priority continue to be accepted.
+//!
+//! The number of substreams that were refused is recorded for each protocol name, and can be
+//! retrieved with `LoadShedder::shed_counts`.
+//!
+//! > **Note**: Only the substreams opened by the remote are subject to load shedding. The
+//! >           substreams that we open ourselves are not counted.
+//!
+//! > **Note**: Only the upgrades are counted, not the lifetime of the substreams. A slot is
+//! >           released as soon as the upgrade of its substream has finished, even if the output
+//! >           of the upgrade is then used for a long time. The limit therefore bounds the work
+//! >           spent on negotiations and handshakes, and not the number of open substreams.
+
+use bytes::Bytes;
+use fnv::FnvHashMap;
+use futures::{Future, Poll};
+use futures::future::{self, Either, FutureResult};
+use multiaddr::Multiaddr;
+use parking_lot::Mutex;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::{ConnectionUpgrade, Endpoint, LocalIdentity};
+
+/// Priority of a protocol wrapped by a `LoadShedder`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Refused once half of the limit is reached.
+    Low,
+    /// Refused once three quarters of the limit are reached.
+    Normal,
+    /// Only refused once the limit is reached.
+    High,
+}
+
+impl Priority {
+    // Returns the number of upgrades in progress above which this priority is refused.
+    fn threshold(&self, limit: usize) -> usize {
+        match *self {
+            Priority::Low => limit / 2,
+            Priority::Normal => limit - limit / 4,
+            Priority::High => limit,
+        }
+    }
+}
+
+/// Shared state of the upgrades that are subject to load shedding.
+///
+/// Cloning a `LoadShedder` is cheap, and the clones share the same state.
+#[derive(Debug, Clone)]
+pub struct LoadShedder {
+    inner: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    // Maximum number of inbound upgrades in progress.
+    limit: usize,
+    // Number of inbound upgrades currently in progress.
+    in_progress: AtomicUsize,
+    // Number of substreams refused for each protocol.
+    shed: Mutex<FnvHashMap<Bytes, u64>>,
+}
+
+impl LoadShedder {
+    /// Builds a new `LoadShedder` that accepts at most `limit` inbound upgrades in progress.
+    #[inline]
+    pub fn new(limit: usize) -> LoadShedder {
+        LoadShedder {
+            inner: Arc::new(Shared {
+                limit: limit,
+                in_progress: AtomicUsize::new(0),
+                shed: Mutex::new(FnvHashMap::default()),
+            }),
+        }
+    }
+
+    /// Wraps around an upgrade so that its inbound substreams are subject to load shedding.
+    #[inline]
+    pub fn wrap<U>(&self, upgrade: U, priority: Priority) -> LoadShed<U> {
+        LoadShed {
+            upgrade: upgrade,
+            priority: priority,
+            shedder: self.clone(),
+        }
+    }
+
+    /// Returns the number of inbound upgrades that are currently in progress. The substreams
+    /// whose upgrade has finished are not included.
+    #[inline]
+    pub fn in_progress(&self) -> usize {
+        self.inner.in_progress.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of substreams that have been refused, for each protocol name.
+    #[inline]
+    pub fn shed_counts(&self) -> FnvHashMap<Bytes, u64> {
+        self.inner.shed.lock().clone()
+    }
+
+    // Tries to reserve a slot for an upgrade of the given priority.
+    fn try_acquire(&self, priority: Priority) -> Option<InProgressGuard> {
+        let threshold = priority.threshold(self.inner.limit);
+        let mut current = self.inner.in_progress.load(Ordering::SeqCst);
+        loop {
+            if current >= threshold {
+                return None;
+            }
+
+            let result = self.inner.in_progress.compare_exchange(
+                current,
+                current + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+            match result {
+                Ok(_) => {
+                    return Some(InProgressGuard {
+                        shared: self.inner.clone(),
+                    })
+                }
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+/// Implementation of `ConnectionUpgrade` produced by `LoadShedder::wrap`.
+#[derive(Debug, Clone)]
+pub struct LoadShed<U> {
+    upgrade: U,
+    priority: Priority,
+    shedder: LoadShedder,
+}
+
+impl<C, U> ConnectionUpgrade<C> for LoadShed<U>
+where
+    C: AsyncRead + AsyncWrite,
+    U: ConnectionUpgrade<C>,
+{
+    type NamesIter = LoadShedNames<U::NamesIter>;
+    type UpgradeIdentifier = (Bytes, U::UpgradeIdentifier);
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        LoadShedNames {
+            inner: self.upgrade.protocol_names(),
+        }
+    }
+
+    type Output = U::Output;
+    type Future = Either<LoadShedFuture<U::Future>, FutureResult<U::Output, IoError>>;
+
+    fn upgrade(
+        self,
+        socket: C,
+        (name, id): Self::UpgradeIdentifier,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        let guard = match ty {
+            Endpoint::Dialer => None,
+            Endpoint::Listener => match self.shedder.try_acquire(self.priority) {
+                Some(guard) => Some(guard),
+                None => {
+                    *self.shedder.inner.shed.lock().entry(name).or_insert(0) += 1;
+                    let err = IoError::new(IoErrorKind::Other, "substream refused because of load");
+                    return Either::B(future::err(err));
+                }
+            },
+        };
+
+        let inner = self.upgrade.upgrade(socket, id, ty, remote_addr, local_identity);
+        Either::A(LoadShedFuture {
+            inner: inner,
+            _guard: guard,
+        })
+    }
+}
+
+/// Iterator returned by `LoadShed::protocol_names`. Remembers the name of each protocol in its
+/// identifier.
+pub struct LoadShedNames<I> {
+    inner: I,
+}
+
+impl<I, Id> Iterator for LoadShedNames<I>
+where
+    I: Iterator<Item = (Bytes, Id)>,
+{
+    type Item = (Bytes, (Bytes, Id));
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(name, id)| (name.clone(), (name, id)))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Future of an upgrade wrapped by a `LoadShedder`. Releases its slot once it is finished or
+/// destroyed.
+pub struct LoadShedFuture<F> {
+    inner: F,
+    _guard: Option<InProgressGuard>,
+}
+
+impl<F> Future for LoadShedFuture<F>
+where
+    F: Future,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    #[inline]
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        // The slot is released when the future is destroyed, which happens right after it
+        // finishes.
+        self.inner.poll()
+    }
+}
+
+// Decrements the number of upgrades in progress when destroyed.
+struct InProgressGuard {
+    shared: Arc<Shared>,
+}
+
+impl Drop for InProgressGuard {
+    #[inline]
+    fn drop(&mut self) {
+        self.shared.in_progress.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LoadShedder, Priority};
+    use bytes::Bytes;
+    use futures::{future, Future};
+    use multiaddr::Multiaddr;
+    use std::io::{Cursor, Error as IoError};
+    use transport::{ConnectionUpgrade, Endpoint, LocalIdentity, SimpleProtocol};
+
+    fn never(_: Cursor<Vec<u8>>) -> future::Empty<(), IoError> {
+        future::empty()
+    }
+
+    #[test]
+    fn low_priority_shed_first() {
+        let shedder = LoadShedder::new(4);
+        let low = shedder.wrap(SimpleProtocol::new("/low", never), Priority::Low);
+        let high = shedder.wrap(SimpleProtocol::new("/high", never), Priority::High);
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let identity = LocalIdentity::unknown();
+
+        let upgrade = |u: &super::LoadShed<_>, name: &'static str| {
+            u.clone().upgrade(
+                Cursor::new(vec![]),
+                (Bytes::from(name), ()),
+                Endpoint::Listener,
+                &addr,
+                &identity,
+            )
+        };
+
+        let mut pending = Vec::new();
+        pending.push(upgrade(&low, "/low"));
+        pending.push(upgrade(&low, "/low"));
+        assert_eq!(shedder.in_progress(), 2);
+
+        // Half of the limit is reached, so low-priority substreams are now refused.
+        assert!(upgrade(&low, "/low").wait().is_err());
+        pending.push(upgrade(&high, "/high"));
+        pending.push(upgrade(&high, "/high"));
+        assert_eq!(shedder.in_progress(), 4);
+        assert!(upgrade(&high, "/high").wait().is_err());
+
+        let counts = shedder.shed_counts();
+        assert_eq!(counts.get(&Bytes::from("/low")), Some(&1));
+        assert_eq!(counts.get(&Bytes::from("/high")), Some(&1));
+
+        pending.clear();
+        assert_eq!(shedder.in_progress(), 0);
+    }
+
+    #[test]
+    fn slot_released_once_upgraded() {
+        let shedder = LoadShedder::new(1);
+        let protocol = SimpleProtocol::new("/ready", |_: Cursor<Vec<u8>>| Ok::<_, IoError>(()));
+        let upgrade = shedder.wrap(protocol, Priority::High);
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+
+        for _ in 0..2 {
+            let future = upgrade.clone().upgrade(
+                Cursor::new(vec![]),
+                (Bytes::from("/ready"), ()),
+                Endpoint::Listener,
+                &addr,
+                &LocalIdentity::unknown(),
+            );
+            // The output is still alive, but the slot has been released.
+            let _output = future.wait().unwrap();
+            assert_eq!(shedder.in_progress(), 0);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use base58::ToBase58;
+use multihash;
+use std::fmt;
+
+/// Identifier of a peer of the network.
+///
+/// The data is a multihash of the public key of the peer.
+// TODO: maybe keep things in decoded version?
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct PeerId {
+    multihash: Vec<u8>,
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PeerId({})", self.multihash.to_base58())
+    }
+}
+
+impl PeerId {
+    /// Builds a `PeerId` from a public key.
+    #[inline]
+    pub fn from_public_key(public_key: &[u8]) -> PeerId {
+        let data = multihash::encode(multihash::Hash::SHA2256, public_key)
+            .expect("sha2-256 is always supported");
+        PeerId { multihash: data }
+    }
+
+    /// Checks whether `data` is a valid `PeerId`. If so, returns the `PeerId`. If not, returns
+    /// back the data as an error.
+    #[inline]
+    pub fn from_bytes(data: Vec<u8>) -> Result<PeerId, Vec<u8>> {
+        match multihash::decode(&data) {
+            Ok(_) => Ok(PeerId { multihash: data }),
+            Err(_) => Err(data),
+        }
+    }
+
+    /// Returns a raw bytes representation of this `PeerId`.
+    #[inline]
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.multihash
+    }
+
+    /// Returns a raw bytes representation of this `PeerId`.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.multihash
+    }
+
+    /// Returns the raw bytes of the hash of this `PeerId`.
+    #[inline]
+    pub fn hash(&self) -> &[u8] {
+        let multihash::Multihash { digest, .. } =
+            multihash::decode(&self.multihash).expect("our inner value should always be valid");
+        digest
+    }
+
+    /// Checks whether the public key passed as parameter matches the public key of this `PeerId`.
+    pub fn is_public_key(&self, public_key: &[u8]) -> bool {
+        let multihash::Multihash { alg, .. } =
+            multihash::decode(&self.multihash).expect("our inner value should always be valid");
+        let compare = multihash::encode(alg, public_key).expect("unsupported multihash algorithm"); // TODO: what to do here?
+        compare == self.multihash
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `RawStreamTransport` struct, which turns functions that open raw streams into a
+//! `Transport`.
+//!
+//! The user provides a function that opens an `AsyncRead + AsyncWrite` stream for the
+//! multiaddresses it supports, and optionally a function that listens for incoming streams. This
+//! makes it possible to reach the remotes through code that isn't a `Transport`, such as a
+//! tunnel or a proxy, without implementing the trait. The multiaddresses are only passed through.
+//!
+//! > **Note**: The `multiaddr` crate has no protocol that names a serial port or a Bluetooth
+//! >           device, and a multiaddress can't be made of arbitrary components. Links that
+//! >           aren't reachable through an existing kind of multiaddress, such as serial or
+//! >           Bluetooth LE links, therefore can't be addressed by this transport.
+//!
+//! # Example
+//!
+//! ```
+//! extern crate futures;
+//! extern crate libp2p_core;
+//!
+//! use futures::future;
+//! use libp2p_core::{Multiaddr, RawStreamTransport, Transport};
+//! use std::io::{Cursor, Error as IoError};
+//!
+//! # fn main() {
+//! let remote: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+//! let through_tunnel = remote.clone();
+//!
+//! // In real code this would open a stream to the remote through the tunnel.
+//! let transport = RawStreamTransport::dial_only(move |addr: &Multiaddr| {
+//!     if *addr == through_tunnel {
+//!         Some(future::ok::<_, IoError>(Cursor::new(Vec::<u8>::new())))
+//!     } else {
+//!         None
+//!     }
+//! });
+//!
+//! assert!(transport.clone().dial(remote).is_ok());
+//! assert!(transport.dial("/ip4/1.2.3.4/tcp/5".parse().unwrap()).is_err());
+//! # }
+//! ```
+
+use futures::{Async, Future, IntoFuture, Poll, Stream};
+use futures::future::{self, FutureResult};
+use futures::stream;
+use multiaddr::Multiaddr;
+use std::io::Error as IoError;
+use std::sync::Arc;
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::Transport;
+
+/// Implementation of `Transport` on top of user-provided functions that open raw streams.
+///
+/// See [the module-level documentation](index.html).
+pub struct RawStreamTransport<D, L> {
+    dial: Arc<D>,
+    listen: Option<Arc<L>>,
+}
+
+impl<D, L> RawStreamTransport<D, L> {
+    /// Builds a `RawStreamTransport` that can dial and listen.
+    ///
+    /// `dial` is called with the multiaddress to dial, and must return `None` if the multiaddress
+    /// is not supported. `listen` is called with the multiaddress to listen on, and must return
+    /// `None` if the multiaddress is not supported, or a stream of incoming connections and the
+    /// multiaddresses of their remotes.
+    #[inline]
+    pub fn new(dial: D, listen: L) -> RawStreamTransport<D, L> {
+        RawStreamTransport {
+            dial: Arc::new(dial),
+            listen: Some(Arc::new(listen)),
+        }
+    }
+}
+
+impl<D, Df, S> RawStreamTransport<D, NoListen<S>>
+where
+    D: Fn(&Multiaddr) -> Option<Df>,
+    Df: IntoFuture<Item = S, Error = IoError>,
+{
+    /// Builds a `RawStreamTransport` that can only dial. Listening always fails.
+    #[inline]
+    pub fn dial_only(dial: D) -> Self {
+        RawStreamTransport {
+            dial: Arc::new(dial),
+            listen: None,
+        }
+    }
+}
+
+/// Type of the listening function of a `RawStreamTransport` built with `dial_only`.
+pub type NoListen<S> = fn(&Multiaddr) -> Option<stream::Empty<(S, Multiaddr), IoError>>;
+
+impl<D, L> Clone for RawStreamTransport<D, L> {
+    #[inline]
+    fn clone(&self) -> Self {
+        RawStreamTransport {
+            dial: self.dial.clone(),
+            listen: self.listen.clone(),
+        }
+    }
+}
+
+impl<D, Df, L, Ls, S> Transport for RawStreamTransport<D, L>
+where
+    D: Fn(&Multiaddr) -> Option<Df>,
+    Df: IntoFuture<Item = S, Error = IoError>,
+    L: Fn(&Multiaddr) -> Option<Ls>,
+    Ls: Stream<Item = (S, Multiaddr), Error = IoError>,
+    S: AsyncRead + AsyncWrite,
+{
+    type RawConn = S;
+    type Listener = RawStreamListener<Ls>;
+    type ListenerUpgrade = FutureResult<(S, Multiaddr), IoError>;
+    type Dial = RawStreamDial<Df::Future>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+        let incoming = match self.listen {
+            Some(ref listen) => listen(&addr),
+            None => None,
+        };
+
+        match incoming {
+            Some(incoming) => Ok((RawStreamListener { inner: incoming }, addr)),
+            None => Err((self, addr)),
+        }
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+        match (self.dial)(&addr) {
+            Some(dial) => Ok(RawStreamDial {
+                inner: dial.into_future(),
+                addr: Some(addr),
+            }),
+            None => Err((self, addr)),
+        }
+    }
+
+    #[inline]
+    fn nat_traversal(&self, _: &Multiaddr, _: &Multiaddr) -> Option<Multiaddr> {
+        // The multiaddresses are opaque to us.
+        None
+    }
+}
+
+/// Future returned by `RawStreamTransport::dial`.
+pub struct RawStreamDial<F> {
+    inner: F,
+    addr: Option<Multiaddr>,
+}
+
+impl<F> Future for RawStreamDial<F>
+where
+    F: Future<Error = IoError>,
+{
+    type Item = (F::Item, Multiaddr);
+    type Error = IoError;
+
+    #[inline]
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let stream = try_ready!(self.inner.poll());
+        let addr = self.addr
+            .take()
+            .expect("poll() called again after the future finished");
+        Ok(Async::Ready((stream, addr)))
+    }
+}
+
+/// Stream returned by `RawStreamTransport::listen_on`.
+pub struct RawStreamListener<S> {
+    inner: S,
+}
+
+impl<S, T> Stream for RawStreamListener<S>
+where
+    S: Stream<Item = (T, Multiaddr), Error = IoError>,
+{
+    type Item = FutureResult<(T, Multiaddr), IoError>;
+    type Error = IoError;
+
+    #[inline]
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match try_ready!(self.inner.poll()) {
+            Some(incoming) => Ok(Async::Ready(Some(future::ok(incoming)))),
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RawStreamTransport;
+    use futures::{future, stream, Future, Stream};
+    use multiaddr::Multiaddr;
+    use std::io::{Cursor, Error as IoError};
+    use transport::Transport;
+
+    #[test]
+    fn dial_and_listen() {
+        let link: Multiaddr = "/ip4/127.0.0.1/udp/9".parse().unwrap();
+        let remote: Multiaddr = "/ip4/127.0.0.2/udp/9".parse().unwrap();
+
+        let (link_dial, link_listen, remote_listen) = (link.clone(), link.clone(), remote.clone());
+        let transport = RawStreamTransport::new(
+            move |addr: &Multiaddr| {
+                if *addr == link_dial {
+                    Some(future::ok::<_, IoError>(Cursor::new(vec![1, 2, 3])))
+                } else {
+                    None
+                }
+            },
+            move |addr: &Multiaddr| {
+                if *addr == link_listen {
+                    let incoming = (Cursor::new(vec![4, 5, 6]), remote_listen.clone());
+                    Some(stream::once::<_, IoError>(Ok(incoming)))
+                } else {
+                    None
+                }
+            },
+        );
+
+        let (stream, addr) = transport.clone().dial(link.clone()).unwrap_or_else(|_| panic!())
+            .wait()
+            .unwrap();
+        assert_eq!(stream.into_inner(), vec![1, 2, 3]);
+        assert_eq!(addr, link);
+
+        let (listener, _) = transport.clone().listen_on(link.clone()).unwrap_or_else(|_| panic!());
+        let incoming = listener.collect().wait().unwrap();
+        assert_eq!(incoming.len(), 1);
+        let (stream, addr) = incoming.into_iter().next().unwrap().wait().unwrap();
+        assert_eq!(stream.into_inner(), vec![4, 5, 6]);
+        assert_eq!(addr, remote);
+
+        assert!(transport.clone().dial(remote.clone()).is_err());
+        assert!(transport.listen_on(remote).is_err());
+    }
+}
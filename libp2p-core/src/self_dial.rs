@@ -0,0 +1,333 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `SelfDialGuard` wrapper, which refuses to dial the local node.
+//!
+//! Discovery mechanisms frequently report our own addresses back to us, and connecting to
+//! ourselves confuses the protocol handlers. A `SelfDialGuard` remembers every multiaddress that
+//! has been successfully listened on through it, and optionally the local peer ID. Dialing one of
+//! these addresses, or any multiaddress that contains the local peer ID (for example a relayed
+//! address going through ourselves), fails with a `SelfDialError`.
+//!
+//! If we listen on an unspecified IP address (`0.0.0.0` or `::`), dialing the loopback address or
+//! the unspecified address on the same port is considered a self-dial as well.
+
+use futures::future::{self, IntoFuture};
+use multiaddr::{AddrComponent, Multiaddr};
+use parking_lot::Mutex;
+use std::error;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::sync::Arc;
+use transport::{LocalIdentity, MuxedTransport, Transport};
+
+/// Wraps around a `Transport` and refuses to dial the local node.
+///
+/// See [the module-level documentation](index.html).
+#[derive(Debug, Clone)]
+pub struct SelfDialGuard<T> {
+    inner: T,
+    // Addresses we are listening on. Shared between all the clones.
+    listen_addrs: Arc<Mutex<Vec<Multiaddr>>>,
+    // Bytes representation of the local peer ID, if known.
+    local_peer_id: Option<Arc<Vec<u8>>>,
+}
+
+impl<T> SelfDialGuard<T> {
+    /// Wraps around `inner`. Only the addresses we listen on are checked.
+    #[inline]
+    pub fn new(inner: T) -> SelfDialGuard<T> {
+        SelfDialGuard {
+            inner,
+            listen_addrs: Arc::new(Mutex::new(Vec::new())),
+            local_peer_id: None,
+        }
+    }
+
+    /// Wraps around `inner`, and also refuses to dial multiaddresses that contain a `/p2p` or
+    /// `/ipfs` component equal to `local_peer_id`.
+    ///
+    /// `local_peer_id` is the bytes representation of the peer ID, as returned by
+    /// `PeerId::into_bytes()`.
+    #[inline]
+    pub fn with_local_peer_id(inner: T, local_peer_id: Vec<u8>) -> SelfDialGuard<T> {
+        SelfDialGuard {
+            inner,
+            listen_addrs: Arc::new(Mutex::new(Vec::new())),
+            local_peer_id: Some(Arc::new(local_peer_id)),
+        }
+    }
+
+    /// Returns the list of multiaddresses that we are listening on through this transport.
+    #[inline]
+    pub fn listen_addrs(&self) -> Vec<Multiaddr> {
+        self.listen_addrs.lock().clone()
+    }
+
+    /// Checks whether dialing `addr` would connect to ourselves.
+    pub fn check(&self, addr: &Multiaddr) -> Result<(), SelfDialError> {
+        if let Some(ref local_peer_id) = self.local_peer_id {
+            let contains_peer_id = addr.iter().any(|component| match component {
+                AddrComponent::P2P(ref id) | AddrComponent::IPFS(ref id) => {
+                    id == &**local_peer_id
+                }
+                _ => false,
+            });
+
+            if contains_peer_id {
+                return Err(SelfDialError::LocalPeerId(addr.clone()));
+            }
+        }
+
+        let listen_addrs = self.listen_addrs.lock();
+        if listen_addrs
+            .iter()
+            .any(|listen_addr| is_same_listener(listen_addr, addr))
+        {
+            return Err(SelfDialError::ListenAddress(addr.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+impl<T> Transport for SelfDialGuard<T>
+where
+    T: Transport,
+{
+    type RawConn = T::RawConn;
+    type Listener = T::Listener;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = future::Either<
+        future::FutureResult<(T::RawConn, Multiaddr), IoError>,
+        <T::Dial as IntoFuture>::Future,
+    >;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+        let listen_addrs = self.listen_addrs;
+        let local_peer_id = self.local_peer_id;
+
+        match self.inner.listen_on(addr) {
+            Ok((listener, new_addr)) => {
+                listen_addrs.lock().push(new_addr.clone());
+                Ok((listener, new_addr))
+            }
+            Err((inner, addr)) => {
+                let guard = SelfDialGuard {
+                    inner,
+                    listen_addrs,
+                    local_peer_id,
+                };
+                Err((guard, addr))
+            }
+        }
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+        if let Err(err) = self.check(&addr) {
+            let err = IoError::new(IoErrorKind::Other, err);
+            return Ok(future::Either::A(future::err(err)));
+        }
+
+        let listen_addrs = self.listen_addrs;
+        let local_peer_id = self.local_peer_id;
+
+        match self.inner.dial(addr) {
+            Ok(dial) => Ok(future::Either::B(dial.into_future())),
+            Err((inner, addr)) => {
+                let guard = SelfDialGuard {
+                    inner,
+                    listen_addrs,
+                    local_peer_id,
+                };
+                Err((guard, addr))
+            }
+        }
+    }
+
+    #[inline]
+    fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.nat_traversal(server, observed)
+    }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.inner.local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.inner.reports_peer_ids()
+    }
+}
+
+impl<T> MuxedTransport for SelfDialGuard<T>
+where
+    T: MuxedTransport,
+{
+    type Incoming = T::Incoming;
+    type IncomingUpgrade = T::IncomingUpgrade;
+
+    #[inline]
+    fn next_incoming(self) -> Self::Incoming {
+        self.inner.next_incoming()
+    }
+}
+
+// Returns true if dialing `dialed` would reach the listener at `listen_addr`.
+fn is_same_listener(listen_addr: &Multiaddr, dialed: &Multiaddr) -> bool {
+    let mut listen_iter = listen_addr.iter();
+    let mut dialed_iter = dialed.iter();
+
+    loop {
+        let same = match (listen_iter.next(), dialed_iter.next()) {
+            (Some(AddrComponent::IP4(listen)), Some(AddrComponent::IP4(dialed))) => {
+                listen == dialed || (listen.is_unspecified()
+                    && (dialed.is_loopback() || dialed.is_unspecified()))
+            }
+            (Some(AddrComponent::IP6(listen)), Some(AddrComponent::IP6(dialed))) => {
+                listen == dialed || (listen.is_unspecified()
+                    && (dialed.is_loopback() || dialed.is_unspecified()))
+            }
+            (Some(listen), Some(dialed)) => listen == dialed,
+            (None, None) => return true,
+            _ => return false,
+        };
+
+        if !same {
+            return false;
+        }
+    }
+}
+
+/// Error produced when trying to dial the local node.
+///
+/// When produced by `SelfDialGuard`, this error is wrapped inside an `io::Error` of kind `Other`.
+/// Use `SelfDialError::from_io_error` to retrieve it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelfDialError {
+    /// The multiaddress is one of the addresses we are listening on.
+    ListenAddress(Multiaddr),
+    /// The multiaddress contains the local peer ID.
+    LocalPeerId(Multiaddr),
+}
+
+impl SelfDialError {
+    /// If `err` wraps around a `SelfDialError`, returns it.
+    #[inline]
+    pub fn from_io_error(err: &IoError) -> Option<&SelfDialError> {
+        err.get_ref().and_then(|inner| inner.downcast_ref::<SelfDialError>())
+    }
+
+    /// Returns the multiaddress that was refused.
+    #[inline]
+    pub fn multiaddr(&self) -> &Multiaddr {
+        match self {
+            &SelfDialError::ListenAddress(ref addr) => addr,
+            &SelfDialError::LocalPeerId(ref addr) => addr,
+        }
+    }
+}
+
+impl fmt::Display for SelfDialError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", error::Error::description(self), self.multiaddr())
+    }
+}
+
+impl error::Error for SelfDialError {
+    #[inline]
+    fn description(&self) -> &str {
+        match self {
+            &SelfDialError::ListenAddress(_) => "refused to dial one of our own listen addresses",
+            &SelfDialError::LocalPeerId(_) => "refused to dial our own peer ID",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SelfDialError, SelfDialGuard};
+    use futures::Future;
+    use futures::future;
+    use multiaddr::Multiaddr;
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+    use transport::{DeniedTransport, Transport};
+
+    // Transport that pretends to listen on and dial anything.
+    #[derive(Debug, Clone)]
+    struct Dummy;
+    impl Transport for Dummy {
+        type RawConn = <DeniedTransport as Transport>::RawConn;
+        type Listener = <DeniedTransport as Transport>::Listener;
+        type ListenerUpgrade = <DeniedTransport as Transport>::ListenerUpgrade;
+        type Dial = future::FutureResult<(Self::RawConn, Multiaddr), IoError>;
+        fn listen_on(
+            self,
+            addr: Multiaddr,
+        ) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+            Ok((Box::new(::futures::stream::empty()), addr))
+        }
+        fn dial(self, _: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+            Ok(future::err(IoError::new(IoErrorKind::ConnectionRefused, "dummy")))
+        }
+        fn nat_traversal(&self, _: &Multiaddr, _: &Multiaddr) -> Option<Multiaddr> {
+            None
+        }
+    }
+
+    fn dial_err(guard: &SelfDialGuard<Dummy>, addr: &str) -> IoError {
+        let addr = addr.parse::<Multiaddr>().unwrap();
+        guard.clone().dial(addr).unwrap_or_else(|_| panic!()).wait().err().unwrap()
+    }
+
+    #[test]
+    fn refuses_listen_addr() {
+        let guard = SelfDialGuard::new(Dummy);
+        let _ = guard
+            .clone()
+            .listen_on("/ip4/0.0.0.0/tcp/1234".parse().unwrap())
+            .unwrap_or_else(|_| panic!());
+
+        let err = dial_err(&guard, "/ip4/127.0.0.1/tcp/1234");
+        match SelfDialError::from_io_error(&err) {
+            Some(&SelfDialError::ListenAddress(_)) => (),
+            _ => panic!(),
+        }
+
+        let err = dial_err(&guard, "/ip4/127.0.0.1/tcp/1235");
+        assert!(SelfDialError::from_io_error(&err).is_none());
+        assert_eq!(err.kind(), IoErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn refuses_local_peer_id() {
+        let guard = SelfDialGuard::with_local_peer_id(Dummy, vec![0x12, 0x20, 1, 2, 3]);
+        let mut addr: Multiaddr = "/ip4/1.2.3.4/tcp/1234".parse().unwrap();
+        addr.append(::multiaddr::AddrComponent::P2P(vec![0x12, 0x20, 1, 2, 3]));
+
+        let err = guard.clone().dial(addr).unwrap_or_else(|_| panic!()).wait().err().unwrap();
+        match SelfDialError::from_io_error(&err) {
+            Some(&SelfDialError::LocalPeerId(_)) => (),
+            _ => panic!(),
+        }
+    }
+}
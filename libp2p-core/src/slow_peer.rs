@@ -0,0 +1,554 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `SlowPeerDetect` connection upgrade, which detects the remotes that don't read
+//! the data we send them fast enough.
+//!
+//! A remote that reads slowly makes the data we want to send it pile up in our buffers, for
+//! example the backlog of messages of a gossip protocol. Wrapping an upgrade with
+//! `UpgradeExt::with_slow_peer_detection` measures, on each substream, how fast the remote
+//! accepts our data while writes are blocked.
+//!
+//! The measure starts when a write is refused with `WouldBlock`, and stops as soon as a write is
+//! entirely accepted. If a whole window elapses before that and the remote accepted fewer bytes
+//! than the configured minimum rate, a `SlowPeerEvent` is sent to a `SlowPeerSink`. The
+//! substream can then optionally be reset, in which case all subsequent reads and writes produce
+//! an error of kind `ConnectionReset`.
+//!
+//! Independently, a write timeout makes writes fail with an error of kind `TimedOut` when the
+//! remote hasn't accepted a single byte for a given duration.
+
+use bytes::Bytes;
+use clock::{Clock, TokioClock};
+use futures::{Async, Poll};
+use multiaddr::Multiaddr;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::{ConnectionUpgrade, Endpoint, LocalIdentity};
+
+/// Configuration of a `SlowPeerDetect`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowPeerConfig {
+    // Minimum number of bytes per second that the remote must accept while writes are blocked.
+    min_rate: u64,
+    // Duration over which the rate is measured.
+    window: Duration,
+    // Maximum duration during which the remote can accept no byte at all.
+    write_timeout: Option<Duration>,
+    // Whether to reset the substreams of slow remotes.
+    reset: bool,
+}
+
+impl SlowPeerConfig {
+    /// Builds a configuration where the remote must accept at least `min_rate` bytes per second
+    /// while writes are blocked.
+    ///
+    /// By default the rate is measured over windows of 10 seconds, there is no write timeout, and
+    /// the substreams of slow remotes are not reset.
+    #[inline]
+    pub fn new(min_rate: u64) -> SlowPeerConfig {
+        SlowPeerConfig {
+            min_rate: min_rate,
+            window: Duration::from_secs(10),
+            write_timeout: None,
+            reset: false,
+        }
+    }
+
+    /// Sets the duration over which the rate is measured. A remote is only reported after writes
+    /// have stayed blocked or partial for this long.
+    #[inline]
+    pub fn with_window(mut self, window: Duration) -> SlowPeerConfig {
+        self.window = window;
+        self
+    }
+
+    /// Makes writes fail with an error of kind `TimedOut` if they are refused with `WouldBlock`
+    /// for longer than `timeout`, without any byte being accepted in between.
+    #[inline]
+    pub fn with_write_timeout(mut self, timeout: Duration) -> SlowPeerConfig {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// If true, the substream is reset after its remote has been reported as slow.
+    #[inline]
+    pub fn with_reset(mut self, reset: bool) -> SlowPeerConfig {
+        self.reset = reset;
+        self
+    }
+
+    // Number of bytes that the remote must accept during a window.
+    fn min_bytes_per_window(&self) -> u64 {
+        let nanos = u64::from(self.window.subsec_nanos());
+        self.min_rate
+            .saturating_mul(self.window.as_secs())
+            .saturating_add(self.min_rate.saturating_mul(nanos) / 1_000_000_000)
+    }
+}
+
+/// Destination of the events of a `SlowPeerDetect`.
+pub trait SlowPeerSink {
+    /// Called when the remote of a substream is detected as slow.
+    fn slow_peer(&self, event: SlowPeerEvent);
+}
+
+impl<F> SlowPeerSink for F
+where
+    F: Fn(SlowPeerEvent),
+{
+    #[inline]
+    fn slow_peer(&self, event: SlowPeerEvent) {
+        self(event)
+    }
+}
+
+/// Produced when the remote of a substream doesn't read our data fast enough.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowPeerEvent {
+    /// Address of the remote.
+    pub remote_addr: Multiaddr,
+    /// Name of the negotiated protocol.
+    pub protocol: Bytes,
+    /// Number of bytes that the remote accepted during the window.
+    pub bytes_written: u64,
+    /// Duration of the window.
+    pub window: Duration,
+    /// Whether the substream has been reset as a consequence.
+    pub reset: bool,
+}
+
+/// Implementation of `ConnectionUpgrade` that detects slow remotes on the substreams it upgrades.
+/// Returned by `UpgradeExt::with_slow_peer_detection`.
+pub struct SlowPeerDetect<U, S, C = TokioClock> {
+    upgrade: U,
+    config: SlowPeerConfig,
+    sink: Arc<S>,
+    clock: C,
+}
+
+impl<U, S> SlowPeerDetect<U, S> {
+    /// Builds a new `SlowPeerDetect` that sends its events to `sink`.
+    #[inline]
+    pub fn new(upgrade: U, config: SlowPeerConfig, sink: S) -> SlowPeerDetect<U, S> {
+        SlowPeerDetect {
+            upgrade: upgrade,
+            config: config,
+            sink: Arc::new(sink),
+            clock: TokioClock::new(),
+        }
+    }
+}
+
+impl<U, S, C> SlowPeerDetect<U, S, C> {
+    /// Measures the time with the given `Clock` instead.
+    #[inline]
+    pub fn with_clock<C2>(self, clock: C2) -> SlowPeerDetect<U, S, C2>
+    where
+        C2: Clock,
+    {
+        SlowPeerDetect {
+            upgrade: self.upgrade,
+            config: self.config,
+            sink: self.sink,
+            clock: clock,
+        }
+    }
+}
+
+impl<U, S, C> Clone for SlowPeerDetect<U, S, C>
+where
+    U: Clone,
+    C: Clone,
+{
+    #[inline]
+    fn clone(&self) -> Self {
+        SlowPeerDetect {
+            upgrade: self.upgrade.clone(),
+            config: self.config.clone(),
+            sink: self.sink.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl<T, U, S, C> ConnectionUpgrade<T> for SlowPeerDetect<U, S, C>
+where
+    T: AsyncRead + AsyncWrite,
+    U: ConnectionUpgrade<SlowPeerSocket<T, S, C>>,
+    S: SlowPeerSink,
+    C: Clock,
+{
+    type NamesIter = SlowPeerNames<U::NamesIter>;
+    type UpgradeIdentifier = (Bytes, U::UpgradeIdentifier);
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        SlowPeerNames {
+            inner: self.upgrade.protocol_names(),
+        }
+    }
+
+    type Output = U::Output;
+    type Future = U::Future;
+
+    #[inline]
+    fn upgrade(
+        self,
+        socket: T,
+        (protocol, id): Self::UpgradeIdentifier,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        let socket = SlowPeerSocket {
+            inner: socket,
+            config: self.config,
+            sink: self.sink,
+            clock: self.clock,
+            remote_addr: remote_addr.clone(),
+            protocol: protocol,
+            window: None,
+            write_timeout: None,
+            reset: false,
+        };
+
+        self.upgrade.upgrade(socket, id, ty, remote_addr, local_identity)
+    }
+}
+
+/// Iterator returned by `SlowPeerDetect::protocol_names`. Remembers the name of each protocol in
+/// its identifier.
+pub struct SlowPeerNames<I> {
+    inner: I,
+}
+
+impl<I, Id> Iterator for SlowPeerNames<I>
+where
+    I: Iterator<Item = (Bytes, Id)>,
+{
+    type Item = (Bytes, (Bytes, Id));
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(name, id)| (name.clone(), (name, id)))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Socket passed to the upgrade wrapped by a `SlowPeerDetect`.
+pub struct SlowPeerSocket<T, S, C>
+where
+    C: Clock,
+{
+    inner: T,
+    config: SlowPeerConfig,
+    sink: Arc<S>,
+    clock: C,
+    remote_addr: Multiaddr,
+    protocol: Bytes,
+    // End of the current measurement window, and number of bytes written since it started.
+    // `None` if writes aren't blocked.
+    window: Option<(C::Delay, u64)>,
+    // Fires when the write timeout elapses. `None` if the last write wasn't refused.
+    write_timeout: Option<C::Delay>,
+    // True if the substream has been reset because the remote is slow.
+    reset: bool,
+}
+
+impl<T, S, C> SlowPeerSocket<T, S, C>
+where
+    S: SlowPeerSink,
+    C: Clock,
+{
+    // Called when a write has been refused with `WouldBlock`. Returns an error if the write
+    // timeout has elapsed or if the substream has to be reset.
+    fn on_blocked(&mut self) -> Result<(), IoError> {
+        if let Some(timeout) = self.config.write_timeout {
+            let clock = &self.clock;
+            let delay = self.write_timeout.get_or_insert_with(|| clock.delay(timeout));
+            if let Async::Ready(()) = delay.poll()? {
+                debug!(target: "libp2p-core", "Write timeout with {}", self.remote_addr);
+                return Err(IoError::new(IoErrorKind::TimedOut, "write timeout elapsed"));
+            }
+        }
+
+        let bytes_written = match self.window {
+            Some((ref mut end, bytes_written)) => match end.poll()? {
+                Async::Ready(()) => bytes_written,
+                Async::NotReady => return Ok(()),
+            },
+            None => {
+                self.window = Some((self.start_window()?, 0));
+                return Ok(());
+            }
+        };
+
+        // The window has elapsed while writes are still blocked ; start the next one.
+        self.window = Some((self.start_window()?, 0));
+
+        if bytes_written >= self.config.min_bytes_per_window() {
+            return Ok(());
+        }
+
+        debug!(target: "libp2p-core", "{} only accepted {} bytes in {:?} on {:?}",
+               self.remote_addr, bytes_written, self.config.window, self.protocol);
+        self.reset = self.config.reset;
+        self.sink.slow_peer(SlowPeerEvent {
+            remote_addr: self.remote_addr.clone(),
+            protocol: self.protocol.clone(),
+            bytes_written: bytes_written,
+            window: self.config.window,
+            reset: self.reset,
+        });
+
+        if self.reset {
+            Err(reset_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Builds the delay that ends a new measurement window. It is polled once so that the current
+    // task is notified when the window elapses.
+    fn start_window(&self) -> Result<C::Delay, IoError> {
+        let mut end = self.clock.delay(self.config.window);
+        end.poll()?;
+        Ok(end)
+    }
+}
+
+impl<T, S, C> Read for SlowPeerSocket<T, S, C>
+where
+    T: Read,
+    C: Clock,
+{
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if self.reset {
+            return Err(reset_error());
+        }
+
+        self.inner.read(buf)
+    }
+}
+
+impl<T, S, C> AsyncRead for SlowPeerSocket<T, S, C>
+where
+    T: AsyncRead,
+    C: Clock,
+{
+    #[inline]
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<T, S, C> Write for SlowPeerSocket<T, S, C>
+where
+    T: Write,
+    S: SlowPeerSink,
+    C: Clock,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        if self.reset {
+            return Err(reset_error());
+        }
+
+        match self.inner.write(buf) {
+            Ok(num_written) => {
+                self.write_timeout = None;
+                if num_written == buf.len() {
+                    // The remote accepted everything, which means that writes are no longer
+                    // blocked.
+                    self.window = None;
+                } else if let Some((_, ref mut bytes_written)) = self.window {
+                    *bytes_written += num_written as u64;
+                }
+                Ok(num_written)
+            }
+            Err(ref err) if err.kind() == IoErrorKind::WouldBlock => {
+                self.on_blocked()?;
+                Err(IoErrorKind::WouldBlock.into())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), IoError> {
+        if self.reset {
+            return Err(reset_error());
+        }
+
+        self.inner.flush()
+    }
+}
+
+impl<T, S, C> AsyncWrite for SlowPeerSocket<T, S, C>
+where
+    T: AsyncWrite,
+    S: SlowPeerSink,
+    C: Clock,
+{
+    #[inline]
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
+
+// Error produced by the operations on a substream that has been reset.
+#[inline]
+fn reset_error() -> IoError {
+    IoError::new(IoErrorKind::ConnectionReset, "substream reset because the remote is too slow")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SlowPeerConfig, SlowPeerDetect, SlowPeerEvent, SlowPeerSocket};
+    use clock::ManualClock;
+    use futures::{future, Future, Poll};
+    use multiaddr::Multiaddr;
+    use parking_lot::Mutex;
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio_io::{AsyncRead, AsyncWrite};
+    use transport::{ConnectionUpgrade, Endpoint, LocalIdentity, SimpleProtocol};
+
+    // Socket whose writes are alternately refused with `WouldBlock`, and accepted one byte at a
+    // time if `drains` is true.
+    struct SlowSocket {
+        drains: bool,
+        blocked: bool,
+    }
+
+    impl Read for SlowSocket {
+        fn read(&mut self, _: &mut [u8]) -> Result<usize, IoError> {
+            Ok(0)
+        }
+    }
+
+    impl AsyncRead for SlowSocket {}
+
+    impl Write for SlowSocket {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+            self.blocked = !self.blocked;
+            if self.blocked || !self.drains || buf.is_empty() {
+                Err(IoErrorKind::WouldBlock.into())
+            } else {
+                Ok(1)
+            }
+        }
+
+        fn flush(&mut self) -> Result<(), IoError> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for SlowSocket {
+        fn shutdown(&mut self) -> Poll<(), IoError> {
+            Ok(().into())
+        }
+    }
+
+    // Upgrades `socket` and returns it.
+    fn upgrade<T>(
+        config: SlowPeerConfig,
+        clock: &ManualClock,
+        socket: SlowSocket,
+        sink: T,
+    ) -> SlowPeerSocket<SlowSocket, T, ManualClock>
+    where
+        T: Fn(SlowPeerEvent),
+    {
+        let upgrade = SlowPeerDetect::new(
+            SimpleProtocol::new("/test/1.0.0", |socket| Ok::<_, IoError>(socket)),
+            config,
+            sink,
+        ).with_clock(clock.clone());
+
+        let (_, id) = ConnectionUpgrade::<SlowSocket>::protocol_names(&upgrade).next().unwrap();
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        upgrade
+            .upgrade(socket, id, Endpoint::Dialer, &addr, &LocalIdentity::unknown())
+            .wait()
+            .unwrap()
+    }
+
+    // Writes `buf` from within a task, as the socket registers delays.
+    fn write<W: Write>(socket: &mut W, buf: &[u8]) -> Result<usize, IoError> {
+        future::lazy(|| Ok::<_, ()>(socket.write(buf))).wait().unwrap()
+    }
+
+    #[test]
+    fn write_timeout() {
+        let clock = ManualClock::new();
+        let config = SlowPeerConfig::new(0).with_write_timeout(Duration::from_secs(5));
+        let socket = SlowSocket { drains: false, blocked: false };
+        let mut socket = upgrade(config, &clock, socket, |_: SlowPeerEvent| panic!());
+
+        let err = write(&mut socket, &[1, 2, 3]).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::WouldBlock);
+        clock.advance(Duration::from_secs(4));
+        let err = write(&mut socket, &[1, 2, 3]).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::WouldBlock);
+        clock.advance(Duration::from_secs(1));
+        let err = write(&mut socket, &[1, 2, 3]).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn slow_peer_reset() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events2 = events.clone();
+        let clock = ManualClock::new();
+        let config = SlowPeerConfig::new(100)
+            .with_window(Duration::from_secs(1))
+            .with_reset(true);
+        let socket = SlowSocket { drains: true, blocked: false };
+        let mut socket = upgrade(config, &clock, socket, move |ev| events2.lock().push(ev));
+
+        // Starts the window.
+        assert_eq!(write(&mut socket, &[1, 2, 3]).unwrap_err().kind(), IoErrorKind::WouldBlock);
+        assert_eq!(write(&mut socket, &[1, 2, 3]).unwrap(), 1);
+        clock.advance(Duration::from_secs(1));
+        assert!(events.lock().is_empty());
+
+        let err = write(&mut socket, &[2, 3]).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::ConnectionReset);
+        {
+            let events = events.lock();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].protocol, "/test/1.0.0");
+            assert_eq!(events[0].bytes_written, 1);
+            assert!(events[0].reset);
+        }
+
+        let mut buf = [0; 4];
+        assert_eq!(socket.read(&mut buf).unwrap_err().kind(), IoErrorKind::ConnectionReset);
+    }
+}
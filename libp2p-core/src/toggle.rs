@@ -0,0 +1,465 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `Toggleable` connection upgrade, which makes it possible to enable or disable a
+//! protocol at runtime.
+//!
+//! A `Toggleable` wraps around an upgrade and is controlled with a `ToggleHandle`. While the
+//! protocol is disabled, it is no longer advertised during the *multistream-select* negotiation,
+//! which makes new negotiations for it fail. It is also possible to disable the protocol with
+//! `ToggleHandle::disable_and_close`, in which case the substreams that are already open produce
+//! an error the next time they are read from or written to. The tasks that are waiting for one
+//! of these substreams to become readable or writable are woken up.
+//!
+//! ```
+//! extern crate libp2p_core;
+//!
+//! use libp2p_core::{Toggleable, PlainTextConfig};
+//!
+//! # fn main() {
+//! let upgrade = Toggleable::new(PlainTextConfig);
+//! let handle = upgrade.handle();
+//! // `upgrade` can now be passed to `with_upgrade`.
+//! handle.disable();
+//! assert!(!handle.is_enabled());
+//! # }
+//! ```
+//!
+//! > **Note**: Disabling a protocol doesn't remove it from the list of protocols reported by
+//! >           the *identify* protocol, as this list is provided by the user.
+
+use fnv::FnvHashMap;
+use futures::future::{self, Either, FutureResult};
+use futures::task::{self, Task};
+use futures::Poll;
+use multiaddr::Multiaddr;
+use parking_lot::Mutex;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::{ConnectionUpgrade, Endpoint, LocalIdentity};
+
+const ENABLED: usize = 0;
+const DISABLED: usize = 1;
+
+/// Implementation of `ConnectionUpgrade` that can be enabled or disabled at runtime through a
+/// `ToggleHandle`.
+#[derive(Debug, Clone)]
+pub struct Toggleable<U> {
+    upgrade: U,
+    handle: ToggleHandle,
+}
+
+impl<U> Toggleable<U> {
+    /// Wraps around an upgrade. The protocol starts enabled.
+    #[inline]
+    pub fn new(upgrade: U) -> Toggleable<U> {
+        Toggleable {
+            upgrade: upgrade,
+            handle: ToggleHandle {
+                state: Arc::new(AtomicUsize::new(ENABLED)),
+                close_generation: Arc::new(AtomicUsize::new(0)),
+                blocked: Arc::new(Mutex::new(Blocked::default())),
+            },
+        }
+    }
+
+    /// Returns a handle that controls this upgrade and all its clones.
+    #[inline]
+    pub fn handle(&self) -> ToggleHandle {
+        self.handle.clone()
+    }
+}
+
+/// Controls whether a `Toggleable` is enabled. Cloning a `ToggleHandle` is cheap.
+#[derive(Debug, Clone)]
+pub struct ToggleHandle {
+    state: Arc<AtomicUsize>,
+    // Incremented every time `disable_and_close` is called. A substream is closed if the value
+    // is different from the one at the time it was opened.
+    close_generation: Arc<AtomicUsize>,
+    // Tasks waiting for a substream to become readable or writable.
+    blocked: Arc<Mutex<Blocked>>,
+}
+
+#[derive(Debug, Default)]
+struct Blocked {
+    // Identifier to give to the next substream.
+    next_id: usize,
+    // The task that last got a `WouldBlock` from each substream, by identifier of the substream.
+    tasks: FnvHashMap<usize, Task>,
+}
+
+impl ToggleHandle {
+    /// Enables the protocol. Has no effect on the substreams closed by `disable_and_close`.
+    #[inline]
+    pub fn enable(&self) {
+        self.state.store(ENABLED, Ordering::SeqCst);
+    }
+
+    /// Disables the protocol. The substreams that are already open keep working.
+    #[inline]
+    pub fn disable(&self) {
+        self.state.store(DISABLED, Ordering::SeqCst);
+    }
+
+    /// Disables the protocol and closes all the substreams that use it. The tasks waiting for
+    /// these substreams are notified.
+    pub fn disable_and_close(&self) {
+        self.state.store(DISABLED, Ordering::SeqCst);
+        self.close_generation.fetch_add(1, Ordering::SeqCst);
+        let tasks = {
+            let mut blocked = self.blocked.lock();
+            ::std::mem::replace(&mut blocked.tasks, Default::default())
+        };
+        for (_, task) in tasks {
+            task.notify();
+        }
+    }
+
+    /// Returns true if the protocol is enabled.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == ENABLED
+    }
+}
+
+impl<C, U> ConnectionUpgrade<C> for Toggleable<U>
+where
+    C: AsyncRead + AsyncWrite,
+    U: ConnectionUpgrade<ToggledSocket<C>>,
+{
+    type NamesIter = ToggleNames<U::NamesIter>;
+    type UpgradeIdentifier = U::UpgradeIdentifier;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        if self.handle.is_enabled() {
+            ToggleNames(Some(self.upgrade.protocol_names()))
+        } else {
+            ToggleNames(None)
+        }
+    }
+
+    type Output = U::Output;
+    type Future = Either<U::Future, FutureResult<U::Output, IoError>>;
+
+    fn upgrade(
+        self,
+        socket: C,
+        id: Self::UpgradeIdentifier,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        // The protocol could have been disabled during the negotiation.
+        if !self.handle.is_enabled() {
+            let err = IoError::new(IoErrorKind::ConnectionRefused, "protocol is disabled");
+            return Either::B(future::err(err));
+        }
+
+        // Substreams are only closed if `disable_and_close` is called after they are opened.
+        let generation = self.handle.close_generation.load(Ordering::SeqCst);
+        let id = {
+            let mut blocked = self.handle.blocked.lock();
+            blocked.next_id = blocked.next_id.wrapping_add(1);
+            blocked.next_id
+        };
+        let socket = ToggledSocket {
+            inner: socket,
+            close_generation: self.handle.close_generation.clone(),
+            generation: generation,
+            blocked: self.handle.blocked.clone(),
+            id: id,
+        };
+
+        Either::A(self.upgrade.upgrade(socket, id, ty, remote_addr, local_identity))
+    }
+}
+
+/// Iterator returned by `Toggleable::protocol_names`. Empty if the protocol is disabled.
+pub struct ToggleNames<I>(Option<I>);
+
+impl<I> Iterator for ToggleNames<I>
+where
+    I: Iterator,
+{
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        match self.0 {
+            Some(ref mut inner) => inner.next(),
+            None => None,
+        }
+    }
+}
+
+/// Socket passed to the upgrade wrapped by a `Toggleable`.
+pub struct ToggledSocket<C> {
+    inner: C,
+    close_generation: Arc<AtomicUsize>,
+    // Value of `close_generation` when the substream was opened.
+    generation: usize,
+    blocked: Arc<Mutex<Blocked>>,
+    // Identifier of this substream in `blocked`.
+    id: usize,
+}
+
+impl<C> ToggledSocket<C> {
+    // Returns an error if `disable_and_close` has been called.
+    fn check(&self) -> Result<(), IoError> {
+        if self.close_generation.load(Ordering::SeqCst) != self.generation {
+            Err(IoError::new(IoErrorKind::ConnectionAborted, "protocol has been disabled"))
+        } else {
+            Ok(())
+        }
+    }
+
+    // If `result` is a `WouldBlock` error, registers the current task so that
+    // `disable_and_close` wakes it up.
+    fn park<R>(&self, result: Result<R, IoError>) -> Result<R, IoError> {
+        if let Err(ref err) = result {
+            if err.kind() == IoErrorKind::WouldBlock {
+                self.blocked.lock().tasks.insert(self.id, task::current());
+                // `disable_and_close` may have been called before the task was registered.
+                self.check()?;
+            }
+        }
+        result
+    }
+}
+
+impl<C> Drop for ToggledSocket<C> {
+    #[inline]
+    fn drop(&mut self) {
+        self.blocked.lock().tasks.remove(&self.id);
+    }
+}
+
+impl<C> Read for ToggledSocket<C>
+where
+    C: Read,
+{
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        self.check()?;
+        let result = self.inner.read(buf);
+        self.park(result)
+    }
+}
+
+impl<C> AsyncRead for ToggledSocket<C>
+where
+    C: AsyncRead,
+{
+    #[inline]
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<C> Write for ToggledSocket<C>
+where
+    C: Write,
+{
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.check()?;
+        let result = self.inner.write(buf);
+        self.park(result)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.check()?;
+        let result = self.inner.flush();
+        self.park(result)
+    }
+}
+
+impl<C> AsyncWrite for ToggledSocket<C>
+where
+    C: AsyncWrite,
+{
+    #[inline]
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ToggledSocket, Toggleable};
+    use futures::executor::{self, Notify};
+    use futures::{Future, Poll};
+    use multiaddr::Multiaddr;
+    use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio_io::io::read;
+    use tokio_io::{AsyncRead, AsyncWrite};
+    use transport::{ConnectionUpgrade, Endpoint, LocalIdentity, SimpleProtocol};
+
+    type Socket = ToggledSocket<Cursor<Vec<u8>>>;
+
+    fn passthrough(socket: Socket) -> Result<Socket, IoError> {
+        Ok(socket)
+    }
+
+    #[test]
+    fn names_hidden_when_disabled() {
+        let upgrade = Toggleable::new(SimpleProtocol::new("/test/1.0.0", passthrough));
+        let handle = upgrade.handle();
+        assert_eq!(
+            ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&upgrade).count(),
+            1
+        );
+        handle.disable();
+        assert_eq!(
+            ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&upgrade).count(),
+            0
+        );
+        handle.enable();
+        assert_eq!(
+            ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&upgrade).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn disable_and_close() {
+        let upgrade = Toggleable::new(SimpleProtocol::new("/test/1.0.0", passthrough));
+        let handle = upgrade.handle();
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+
+        let identity = LocalIdentity::unknown();
+
+        let mut socket = upgrade
+            .clone()
+            .upgrade(Cursor::new(vec![1, 2]), (), Endpoint::Dialer, &addr, &identity)
+            .wait()
+            .unwrap();
+        let mut buf = [0; 1];
+        assert!(socket.read(&mut buf).is_ok());
+
+        handle.disable_and_close();
+        assert!(socket.read(&mut buf).is_err());
+
+        // Re-enabling doesn't revive a closed substream.
+        handle.enable();
+        assert!(socket.read(&mut buf).is_err());
+
+        handle.disable();
+        assert!(upgrade
+            .upgrade(Cursor::new(vec![]), (), Endpoint::Dialer, &addr, &identity)
+            .wait()
+            .is_err());
+    }
+
+    #[test]
+    fn re_enabled_before_use() {
+        let upgrade = Toggleable::new(SimpleProtocol::new("/test/1.0.0", passthrough));
+        let handle = upgrade.handle();
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let identity = LocalIdentity::unknown();
+
+        let mut socket = upgrade
+            .clone()
+            .upgrade(Cursor::new(vec![1, 2]), (), Endpoint::Dialer, &addr, &identity)
+            .wait()
+            .unwrap();
+
+        // The substream isn't used between the two calls, but must still be closed.
+        handle.disable_and_close();
+        handle.enable();
+        let mut buf = [0; 1];
+        assert!(socket.read(&mut buf).is_err());
+
+        // Substreams opened afterwards aren't affected.
+        let mut socket = upgrade
+            .upgrade(Cursor::new(vec![1, 2]), (), Endpoint::Dialer, &addr, &identity)
+            .wait()
+            .unwrap();
+        assert!(socket.read(&mut buf).is_ok());
+    }
+
+    // Socket on which nothing ever happens.
+    struct Idle;
+
+    impl Read for Idle {
+        fn read(&mut self, _: &mut [u8]) -> Result<usize, IoError> {
+            Err(IoErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncRead for Idle {}
+
+    impl Write for Idle {
+        fn write(&mut self, _: &[u8]) -> Result<usize, IoError> {
+            Err(IoErrorKind::WouldBlock.into())
+        }
+
+        fn flush(&mut self) -> Result<(), IoError> {
+            Err(IoErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncWrite for Idle {
+        fn shutdown(&mut self) -> Poll<(), IoError> {
+            Err(IoErrorKind::WouldBlock.into())
+        }
+    }
+
+    struct Flag(AtomicBool);
+
+    impl Notify for Flag {
+        fn notify(&self, _: usize) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn close_wakes_up_pending_read() {
+        let upgrade = Toggleable::new(SimpleProtocol::new(
+            "/test/1.0.0",
+            |socket: ToggledSocket<Idle>| Ok::<_, IoError>(socket),
+        ));
+        let handle = upgrade.handle();
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let socket = upgrade
+            .upgrade(Idle, (), Endpoint::Dialer, &addr, &LocalIdentity::unknown())
+            .wait()
+            .unwrap_or_else(|_| panic!());
+
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let mut pending = executor::spawn(read(socket, [0; 1]));
+        assert!(pending.poll_future_notify(&flag, 0).unwrap().is_not_ready());
+
+        // Only closing the substreams ends the read.
+        handle.disable();
+        assert!(!flag.0.load(Ordering::SeqCst));
+        handle.disable_and_close();
+        assert!(flag.0.load(Ordering::SeqCst));
+        let err = pending.poll_future_notify(&flag, 0).err().unwrap();
+        assert_eq!(err.kind(), IoErrorKind::ConnectionAborted);
+    }
+}
@@ -0,0 +1,550 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `TrafficShaper` struct, which caps the rate at which the data of the inbound
+//! substreams is read, for each protocol.
+//!
+//! Some protocols are expensive to decode, and a remote that floods us with messages can make us
+//! spend most of our CPU time decoding them. A `TrafficShaper` holds a list of rate limits, in
+//! bytes per second, by protocol name. A limit applies either to each remote separately, or to
+//! all the substreams of the protocol together. For example:
+//!
+//! ```
+//! use libp2p_core::TrafficShaper;
+//!
+//! let shaper = TrafficShaper::new()
+//!     .limit_per_remote("/ipfs/id/1.0.0", 1024)
+//!     .limit_total("/ipfs/bitswap/1.1.0", 5 * 1024 * 1024);
+//! ```
+//!
+//! Upgrades wrapped with `TrafficShaper::wrap` read from their substreams of these protocols at
+//! most at the given rate. Once the allowance is exhausted, reading produces `WouldBlock` until
+//! enough time has passed, so that the remote is eventually slowed down by the flow control of
+//! the underlying transport or muxer rather than by us buffering its data. Each limit allows a
+//! burst of one second worth of data.
+//!
+//! > **Note**: Only the substreams opened by the remote are shaped. The substreams that we open
+//! >           ourselves are read at full speed.
+
+use bytes::Bytes;
+use clock::{Clock, TokioClock};
+use fnv::FnvHashMap;
+use futures::{Async, Future, Poll};
+use multiaddr::Multiaddr;
+use parking_lot::Mutex;
+use std::cmp;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::{ConnectionUpgrade, Endpoint, LocalIdentity};
+
+/// Rate limits of the inbound substreams, by protocol.
+///
+/// Cloning a `TrafficShaper` is cheap, and the clones share the same limits and allowances.
+#[derive(Debug, Clone, Default)]
+pub struct TrafficShaper {
+    inner: Arc<Mutex<FnvHashMap<Bytes, Rule>>>,
+}
+
+#[derive(Debug, Default)]
+struct Rule {
+    // Limit of each remote, in bytes per second.
+    per_remote: Option<u64>,
+    // Allowance of each remote that currently has a substream of this protocol, and the number
+    // of such substreams.
+    remotes: FnvHashMap<Multiaddr, (Bucket, usize)>,
+    // Allowance of all the substreams together.
+    total: Option<Bucket>,
+}
+
+impl TrafficShaper {
+    /// Builds a new `TrafficShaper` without any limit.
+    #[inline]
+    pub fn new() -> TrafficShaper {
+        TrafficShaper::default()
+    }
+
+    /// Limits each remote to `bytes_per_sec` bytes per second on the protocol named `protocol`.
+    pub fn limit_per_remote<N>(self, protocol: N, bytes_per_sec: u64) -> TrafficShaper
+    where
+        N: Into<Bytes>,
+    {
+        {
+            let mut rules = self.inner.lock();
+            let rule = rules.entry(protocol.into()).or_insert_with(Rule::default);
+            rule.per_remote = Some(bytes_per_sec);
+            rule.remotes.clear();
+        }
+        self
+    }
+
+    /// Limits all the substreams of the protocol named `protocol` together to `bytes_per_sec`
+    /// bytes per second.
+    pub fn limit_total<N>(self, protocol: N, bytes_per_sec: u64) -> TrafficShaper
+    where
+        N: Into<Bytes>,
+    {
+        {
+            let mut rules = self.inner.lock();
+            let rule = rules.entry(protocol.into()).or_insert_with(Rule::default);
+            rule.total = Some(Bucket::new(bytes_per_sec));
+        }
+        self
+    }
+
+    /// Wraps around an upgrade so that its inbound substreams are shaped.
+    #[inline]
+    pub fn wrap<U>(&self, upgrade: U) -> InboundShaping<U> {
+        InboundShaping {
+            upgrade: upgrade,
+            shaper: self.clone(),
+            clock: TokioClock::new(),
+        }
+    }
+
+    // Registers a new substream from `remote`. Returns false if `protocol` isn't limited.
+    fn register(&self, protocol: &Bytes, remote: &Multiaddr) -> bool {
+        let mut rules = self.inner.lock();
+        let rule = match rules.get_mut(protocol) {
+            Some(rule) => rule,
+            None => return false,
+        };
+
+        if let Some(rate) = rule.per_remote {
+            rule.remotes
+                .entry(remote.clone())
+                .or_insert_with(|| (Bucket::new(rate), 0))
+                .1 += 1;
+        }
+
+        true
+    }
+
+    // Unregisters a substream previously registered with `register`.
+    fn unregister(&self, protocol: &Bytes, remote: &Multiaddr) {
+        let mut rules = self.inner.lock();
+        if let Some(rule) = rules.get_mut(protocol) {
+            let remove = match rule.remotes.get_mut(remote) {
+                Some(&mut (_, ref mut num)) => {
+                    *num -= 1;
+                    *num == 0
+                }
+                None => false,
+            };
+            if remove {
+                rule.remotes.remove(remote);
+            }
+        }
+    }
+
+    // Returns the number of bytes that can be read at `now`, or the duration after which more
+    // can be read if the allowance is exhausted. `wanted` is the number of bytes that the caller
+    // would like to read.
+    fn allowance(
+        &self,
+        protocol: &Bytes,
+        remote: &Multiaddr,
+        wanted: u64,
+        now: Instant,
+    ) -> Result<u64, Duration> {
+        let mut rules = self.inner.lock();
+        let rule = match rules.get_mut(protocol) {
+            Some(rule) => rule,
+            None => return Ok(wanted),
+        };
+
+        let mut allowed = wanted;
+        let mut wait = Duration::new(0, 0);
+        {
+            let buckets = rule.total
+                .iter_mut()
+                .chain(rule.remotes.get_mut(remote).map(|entry| &mut entry.0));
+            for bucket in buckets {
+                bucket.refill(now);
+                allowed = cmp::min(allowed, bucket.tokens);
+                if bucket.tokens == 0 {
+                    wait = cmp::max(wait, bucket.refill_delay(wanted));
+                }
+            }
+        }
+
+        if allowed == 0 {
+            Err(wait)
+        } else {
+            Ok(allowed)
+        }
+    }
+
+    // Removes `num` bytes from the allowances.
+    fn consume(&self, protocol: &Bytes, remote: &Multiaddr, num: u64) {
+        let mut rules = self.inner.lock();
+        if let Some(rule) = rules.get_mut(protocol) {
+            if let Some(ref mut bucket) = rule.total {
+                bucket.tokens = bucket.tokens.saturating_sub(num);
+            }
+            if let Some(&mut (ref mut bucket, _)) = rule.remotes.get_mut(remote) {
+                bucket.tokens = bucket.tokens.saturating_sub(num);
+            }
+        }
+    }
+}
+
+// Token bucket that fills at a constant rate, up to one second worth of tokens.
+#[derive(Debug)]
+struct Bucket {
+    // Number of tokens added per second, which is also the capacity.
+    rate: u64,
+    // Number of bytes that can currently be read.
+    tokens: u64,
+    // Moment when `tokens` was last updated, according to the clock of the substreams. `None`
+    // until the bucket is used for the first time.
+    last_refill: Option<Instant>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        Bucket {
+            rate: rate,
+            tokens: rate,
+            last_refill: None,
+        }
+    }
+
+    // Adds the tokens generated since the last refill.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = match self.last_refill {
+            Some(last) if now > last => now.duration_since(last),
+            Some(_) => return,
+            None => {
+                self.last_refill = Some(now);
+                return;
+            }
+        };
+        let nanos = elapsed.as_secs()
+            .saturating_mul(1_000_000_000)
+            .saturating_add(u64::from(elapsed.subsec_nanos()));
+        let new_tokens = self.rate.saturating_mul(nanos) / 1_000_000_000;
+        if new_tokens > 0 {
+            self.tokens = cmp::min(self.rate, self.tokens.saturating_add(new_tokens));
+            self.last_refill = Some(now);
+        }
+    }
+
+    // Returns the time it takes to generate enough tokens for reading `wanted` bytes, with a
+    // minimum of a tenth of the rate in order to not wake up too often.
+    fn refill_delay(&self, wanted: u64) -> Duration {
+        if self.rate == 0 {
+            // Nothing can ever be read ; wait a long time.
+            return Duration::from_secs(3600);
+        }
+
+        let tokens = cmp::min(self.rate, cmp::max(wanted, self.rate / 10));
+        let nanos = tokens.saturating_mul(1_000_000_000) / self.rate;
+        Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+    }
+}
+
+/// Implementation of `ConnectionUpgrade` that shapes the inbound substreams it upgrades.
+/// Returned by `TrafficShaper::wrap`.
+#[derive(Debug, Clone)]
+pub struct InboundShaping<U, C = TokioClock> {
+    upgrade: U,
+    shaper: TrafficShaper,
+    clock: C,
+}
+
+impl<U, C> InboundShaping<U, C> {
+    /// Uses the given `Clock` to wait until the allowance has been replenished.
+    #[inline]
+    pub fn with_clock<C2>(self, clock: C2) -> InboundShaping<U, C2>
+    where
+        C2: Clock,
+    {
+        InboundShaping {
+            upgrade: self.upgrade,
+            shaper: self.shaper,
+            clock: clock,
+        }
+    }
+}
+
+impl<T, U, C> ConnectionUpgrade<T> for InboundShaping<U, C>
+where
+    T: AsyncRead + AsyncWrite,
+    U: ConnectionUpgrade<ShapedSocket<T, C>>,
+    C: Clock,
+{
+    type NamesIter = InboundShapingNames<U::NamesIter>;
+    type UpgradeIdentifier = (Bytes, U::UpgradeIdentifier);
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        InboundShapingNames {
+            inner: self.upgrade.protocol_names(),
+        }
+    }
+
+    type Output = U::Output;
+    type Future = U::Future;
+
+    #[inline]
+    fn upgrade(
+        self,
+        socket: T,
+        (protocol, id): Self::UpgradeIdentifier,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        let shaper = if ty == Endpoint::Listener && self.shaper.register(&protocol, remote_addr) {
+            Some(self.shaper)
+        } else {
+            None
+        };
+
+        let socket = ShapedSocket {
+            inner: socket,
+            shaper: shaper,
+            clock: self.clock,
+            protocol: protocol,
+            remote_addr: remote_addr.clone(),
+            delay: None,
+        };
+
+        self.upgrade.upgrade(socket, id, ty, remote_addr, local_identity)
+    }
+}
+
+/// Iterator returned by `InboundShaping::protocol_names`. Remembers the name of each protocol in
+/// its identifier.
+pub struct InboundShapingNames<I> {
+    inner: I,
+}
+
+impl<I, Id> Iterator for InboundShapingNames<I>
+where
+    I: Iterator<Item = (Bytes, Id)>,
+{
+    type Item = (Bytes, (Bytes, Id));
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(name, id)| (name.clone(), (name, id)))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Socket passed to the upgrade wrapped by an `InboundShaping`.
+pub struct ShapedSocket<T, C>
+where
+    C: Clock,
+{
+    inner: T,
+    // `None` if the substream isn't shaped.
+    shaper: Option<TrafficShaper>,
+    clock: C,
+    protocol: Bytes,
+    remote_addr: Multiaddr,
+    // Fires when the allowance may have been replenished.
+    delay: Option<C::Delay>,
+}
+
+impl<T, C> Read for ShapedSocket<T, C>
+where
+    T: Read,
+    C: Clock,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let shaper = match self.shaper {
+            Some(ref shaper) => shaper,
+            None => return self.inner.read(buf),
+        };
+
+        if buf.is_empty() {
+            return self.inner.read(buf);
+        }
+
+        let wanted = buf.len() as u64;
+        let now = self.clock.now();
+        let allowed = match shaper.allowance(&self.protocol, &self.remote_addr, wanted, now) {
+            Ok(allowed) => allowed,
+            Err(wait) => {
+                if let Some(ref mut delay) = self.delay {
+                    if let Async::NotReady = delay.poll()? {
+                        return Err(IoErrorKind::WouldBlock.into());
+                    }
+                }
+
+                trace!(target: "libp2p-core", "Inbound allowance of {:?} exhausted for {} ; \
+                                               waiting {:?}", self.protocol, self.remote_addr,
+                                               wait);
+                let mut delay = self.clock.delay(wait);
+                // Registers the current task, so that it is notified when the delay elapses.
+                delay.poll()?;
+                self.delay = Some(delay);
+                return Err(IoErrorKind::WouldBlock.into());
+            }
+        };
+
+        self.delay = None;
+        let allowed = cmp::min(allowed, buf.len() as u64) as usize;
+        let num_read = self.inner.read(&mut buf[..allowed])?;
+        shaper.consume(&self.protocol, &self.remote_addr, num_read as u64);
+        Ok(num_read)
+    }
+}
+
+impl<T, C> AsyncRead for ShapedSocket<T, C>
+where
+    T: AsyncRead,
+    C: Clock,
+{
+    #[inline]
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<T, C> Write for ShapedSocket<T, C>
+where
+    T: Write,
+    C: Clock,
+{
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.inner.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+
+impl<T, C> AsyncWrite for ShapedSocket<T, C>
+where
+    T: AsyncWrite,
+    C: Clock,
+{
+    #[inline]
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
+
+impl<T, C> Drop for ShapedSocket<T, C>
+where
+    C: Clock,
+{
+    fn drop(&mut self) {
+        if let Some(ref shaper) = self.shaper {
+            shaper.unregister(&self.protocol, &self.remote_addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ShapedSocket, TrafficShaper};
+    use clock::ManualClock;
+    use futures::{future, Future};
+    use multiaddr::Multiaddr;
+    use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind, Read};
+    use std::time::Duration;
+    use transport::{ConnectionUpgrade, Endpoint, LocalIdentity, SimpleProtocol};
+
+    // Upgrades a socket that contains 16 bytes, opened by `remote` with the given protocol.
+    fn upgrade(
+        shaper: &TrafficShaper,
+        protocol: &'static str,
+        remote: &str,
+        ty: Endpoint,
+        clock: &ManualClock,
+    ) -> ShapedSocket<Cursor<Vec<u8>>, ManualClock> {
+        let upgrade = shaper
+            .wrap(SimpleProtocol::new(protocol, |socket| Ok::<_, IoError>(socket)))
+            .with_clock(clock.clone());
+        let (_, id) = ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&upgrade)
+            .next()
+            .unwrap();
+        let addr: Multiaddr = remote.parse().unwrap();
+        upgrade
+            .upgrade(Cursor::new(vec![0; 16]), id, ty, &addr, &LocalIdentity::unknown())
+            .wait()
+            .unwrap()
+    }
+
+    // Reads from `socket` from within a task, as the socket registers delays.
+    fn read<R: Read>(socket: &mut R) -> Result<usize, IoError> {
+        let mut buf = [0; 16];
+        future::lazy(|| Ok::<_, ()>(socket.read(&mut buf))).wait().unwrap()
+    }
+
+    const REMOTE1: &str = "/ip4/1.2.3.4/tcp/1";
+    const REMOTE2: &str = "/ip4/5.6.7.8/tcp/1";
+
+    #[test]
+    fn per_remote_limit() {
+        let clock = ManualClock::new();
+        let shaper = TrafficShaper::new().limit_per_remote("/test/1.0.0", 4);
+        let mut socket1 = upgrade(&shaper, "/test/1.0.0", REMOTE1, Endpoint::Listener, &clock);
+        let mut socket2 = upgrade(&shaper, "/test/1.0.0", REMOTE2, Endpoint::Listener, &clock);
+
+        assert_eq!(read(&mut socket1).unwrap(), 4);
+        assert_eq!(read(&mut socket1).unwrap_err().kind(), IoErrorKind::WouldBlock);
+        // Another remote has its own allowance.
+        assert_eq!(read(&mut socket2).unwrap(), 4);
+
+        // Other protocols and outbound substreams are not limited.
+        let mut socket3 = upgrade(&shaper, "/other/1.0.0", REMOTE1, Endpoint::Listener, &clock);
+        assert_eq!(read(&mut socket3).unwrap(), 16);
+        let mut socket4 = upgrade(&shaper, "/test/1.0.0", REMOTE1, Endpoint::Dialer, &clock);
+        assert_eq!(read(&mut socket4).unwrap(), 16);
+    }
+
+    #[test]
+    fn total_limit() {
+        let clock = ManualClock::new();
+        let shaper = TrafficShaper::new().limit_total("/test/1.0.0", 6);
+        let mut socket1 = upgrade(&shaper, "/test/1.0.0", REMOTE1, Endpoint::Listener, &clock);
+        let mut socket2 = upgrade(&shaper, "/test/1.0.0", REMOTE2, Endpoint::Listener, &clock);
+
+        assert_eq!(read(&mut socket1).unwrap(), 6);
+        assert_eq!(read(&mut socket2).unwrap_err().kind(), IoErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn refilled_by_clock() {
+        let clock = ManualClock::new();
+        let shaper = TrafficShaper::new().limit_per_remote("/test/1.0.0", 8);
+        let mut socket = upgrade(&shaper, "/test/1.0.0", REMOTE1, Endpoint::Listener, &clock);
+
+        assert_eq!(read(&mut socket).unwrap(), 8);
+        assert_eq!(read(&mut socket).unwrap_err().kind(), IoErrorKind::WouldBlock);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(read(&mut socket).unwrap(), 4);
+        assert_eq!(read(&mut socket).unwrap_err().kind(), IoErrorKind::WouldBlock);
+    }
+}
@@ -29,17 +29,26 @@
 //! `UpgradedNode::or_upgrade` methods, you can combine multiple transports and/or upgrades
 //! together in a complex chain of protocols negotiation.
 
+use access_log::AccessLog;
 use bytes::Bytes;
+#[cfg(feature = "compression")]
+use compression::Compress;
 use connection_reuse::ConnectionReuse;
+use correlation::Correlate;
 use futures::{stream, Async, Poll, Stream};
 use futures::future::{self, FromErr, Future, FutureResult, IntoFuture};
+use interceptor::Intercept;
 use multiaddr::Multiaddr;
 use multistream_select;
 use muxing::StreamMuxer;
+use slow_peer::{SlowPeerConfig, SlowPeerDetect};
 use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind, Read, Write};
 use std::iter;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio_io::{AsyncRead, AsyncWrite};
+use transport_timeout::TransportTimeout;
+use upgrade_timeout::UpgradeWithTimeout;
 
 /// A transport is an object that can be used to produce connections by listening or dialing a
 /// peer.
@@ -102,6 +111,26 @@ pub trait Transport {
     /// doesn't recognize the protocols, or if `server` and `observed` are related.
     fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr>;
 
+    /// Returns the identity of the local node. It is passed to every upgrade applied on top of
+    /// this transport with `with_upgrade`.
+    ///
+    /// The default implementation returns `LocalIdentity::unknown()`. Implementations that wrap
+    /// around another transport should forward the call to it.
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        LocalIdentity::unknown()
+    }
+
+    /// Returns true if the multiaddresses that this transport yields for the remotes, both when
+    /// dialing and when listening, contain the peer ID of the remote as `/p2p/<peer-id>`.
+    ///
+    /// The default implementation returns `false`. Implementations that wrap around another
+    /// transport and don't modify the multiaddresses should forward the call to it.
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        false
+    }
+
     /// Builds a new struct that implements `Transport` that contains both `self` and `other`.
     ///
     /// The returned object will redirect its calls to `self`, except that if `listen_on` or `dial`
@@ -143,6 +172,34 @@ pub trait Transport {
     {
         DummyMuxing { inner: self }
     }
+
+    /// Wraps this transport so that `identity` is passed to the upgrades applied on top of it.
+    ///
+    /// This makes it possible for protocols to include the identity of the local node in their
+    /// messages, without having to pass it to each of them individually.
+    #[inline]
+    fn with_local_identity(self, identity: LocalIdentity) -> WithLocalIdentity<Self>
+    where
+        Self: Sized,
+    {
+        WithLocalIdentity {
+            inner: self,
+            identity: identity,
+        }
+    }
+
+    /// Wraps this transport so that any dialing attempt or incoming connection that doesn't
+    /// finish within `timeout` is aborted and produces an error of kind `TimedOut`.
+    ///
+    /// If `self` is the result of a call to `with_upgrade`, then the negotiation of the upgrade is
+    /// part of the budget as well.
+    #[inline]
+    fn with_timeout(self, timeout: Duration) -> TransportTimeout<Self>
+    where
+        Self: Sized,
+    {
+        TransportTimeout::new(self, timeout)
+    }
 }
 
 /// Extension trait for `Transport`. Implemented on structs that provide a `Transport` on which
@@ -258,6 +315,21 @@ where
 
         self.1.nat_traversal(server, observed)
     }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        let first = self.0.local_identity();
+        if first.public_key().is_some() {
+            return first;
+        }
+
+        self.1.local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.0.reports_peer_ids() && self.1.reports_peer_ids()
+    }
 }
 
 /// Implementation of `ConnectionUpgrade`. Convenient to use with small protocols.
@@ -341,7 +413,14 @@ where
     type Future = FromErr<O::Future, IoError>;
 
     #[inline]
-    fn upgrade(self, socket: C, _: (), _: Endpoint, _: &Multiaddr) -> Self::Future {
+    fn upgrade(
+        self,
+        socket: C,
+        _: (),
+        _: Endpoint,
+        _: &Multiaddr,
+        _: &LocalIdentity,
+    ) -> Self::Future {
         let upgrade = &self.upgrade;
         upgrade(socket).into_future().from_err()
     }
@@ -598,12 +677,16 @@ pub trait ConnectionUpgrade<C: AsyncRead + AsyncWrite> {
     ///
     /// Because performing the upgrade may not be instantaneous (eg. it may require a handshake),
     /// this function returns a future instead of the direct output.
+    ///
+    /// `local_identity` is the identity of the local node, as returned by the
+    /// `Transport::local_identity` method of the underlying transport.
     fn upgrade(
         self,
         socket: C,
         id: Self::UpgradeIdentifier,
         ty: Endpoint,
         remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
     ) -> Self::Future;
 }
 
@@ -616,6 +699,40 @@ pub enum Endpoint {
     Listener,
 }
 
+/// Identity of the local node, passed to the upgrades.
+///
+/// Cloning a `LocalIdentity` is cheap.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LocalIdentity {
+    public_key: Option<Arc<Vec<u8>>>,
+}
+
+impl LocalIdentity {
+    /// Builds a `LocalIdentity` from the public key of the local node, in the protobuf encoding
+    /// that is sent to remotes during the secio handshake and whose hash is the peer ID.
+    #[inline]
+    pub fn new(public_key: Vec<u8>) -> LocalIdentity {
+        LocalIdentity {
+            public_key: Some(Arc::new(public_key)),
+        }
+    }
+
+    /// Builds a `LocalIdentity` for a node whose identity is unknown.
+    #[inline]
+    pub fn unknown() -> LocalIdentity {
+        LocalIdentity { public_key: None }
+    }
+
+    /// Returns the public key of the local node, if known.
+    ///
+    /// > **Note**: The peer ID of the local node can be derived from the public key with
+    /// >           `PeerId::from_public_key`.
+    #[inline]
+    pub fn public_key(&self) -> Option<&[u8]> {
+        self.public_key.as_ref().map(|key| &key[..])
+    }
+}
+
 /// Implementation of `ConnectionUpgrade` that always fails to negotiate.
 #[derive(Debug, Copy, Clone)]
 pub struct DeniedConnectionUpgrade;
@@ -635,7 +752,14 @@ where
     }
 
     #[inline]
-    fn upgrade(self, _: C, _: Self::UpgradeIdentifier, _: Endpoint, _: &Multiaddr) -> Self::Future {
+    fn upgrade(
+        self,
+        _: C,
+        _: Self::UpgradeIdentifier,
+        _: Endpoint,
+        _: &Multiaddr,
+        _: &LocalIdentity,
+    ) -> Self::Future {
         unreachable!("the denied connection upgrade always fails to negotiate")
     }
 }
@@ -647,6 +771,48 @@ pub trait UpgradeExt {
     fn or_upgrade<T>(self, other: T) -> OrUpgrade<Self, T>
     where
         Self: Sized;
+
+    /// Wraps around the upgrade so that the socket goes through `interceptor` before being
+    /// upgraded. See the `Interceptor` trait.
+    fn intercept<I>(self, interceptor: I) -> Intercept<Self, I>
+    where
+        Self: Sized;
+
+    /// Wraps around the upgrade so that an entry is sent to `sink` for each substream that it
+    /// upgrades. See the `AccessLog` struct.
+    fn with_access_log<S>(self, sink: S) -> AccessLog<Self, S>
+    where
+        Self: Sized;
+
+    /// Wraps around the upgrade so that each substream it upgrades gets a `CorrelationId`,
+    /// which is included in the logs and in the output. See the `Correlate` struct.
+    fn with_correlation_id(self) -> Correlate<Self>
+    where
+        Self: Sized;
+
+    /// Wraps around the upgrade so that the remotes that read too slowly on the substreams it
+    /// upgrades are reported to `sink`. See the `SlowPeerDetect` struct.
+    fn with_slow_peer_detection<S>(self, config: SlowPeerConfig, sink: S) -> SlowPeerDetect<Self, S>
+    where
+        Self: Sized;
+
+    /// Wraps around the upgrade so that its substreams are compressed with `compression` if the
+    /// remote supports it. See the `Compress` struct.
+    ///
+    /// Only available with the `compression` feature.
+    #[cfg(feature = "compression")]
+    fn with_compression<Z>(self, compression: Z) -> Compress<Self, Z>
+    where
+        Self: Sized;
+
+    /// Wraps around the upgrade so that it fails with an error of kind `TimedOut` if it takes
+    /// longer than `timeout`. See the `UpgradeWithTimeout` struct.
+    ///
+    /// > **Note**: Not named `with_timeout` in order not to be ambiguous with
+    /// >           `Transport::with_timeout` when both traits are in scope.
+    fn with_upgrade_timeout(self, timeout: Duration) -> UpgradeWithTimeout<Self>
+    where
+        Self: Sized;
 }
 
 impl<T> UpgradeExt for T {
@@ -654,6 +820,41 @@ impl<T> UpgradeExt for T {
     fn or_upgrade<U>(self, other: U) -> OrUpgrade<Self, U> {
         OrUpgrade(self, other)
     }
+
+    #[inline]
+    fn intercept<I>(self, interceptor: I) -> Intercept<Self, I> {
+        Intercept::new(self, interceptor)
+    }
+
+    #[inline]
+    fn with_access_log<S>(self, sink: S) -> AccessLog<Self, S> {
+        AccessLog::new(self, sink)
+    }
+
+    #[inline]
+    fn with_correlation_id(self) -> Correlate<Self> {
+        Correlate::new(self)
+    }
+
+    #[inline]
+    fn with_slow_peer_detection<S>(
+        self,
+        config: SlowPeerConfig,
+        sink: S,
+    ) -> SlowPeerDetect<Self, S> {
+        SlowPeerDetect::new(self, config, sink)
+    }
+
+    #[inline]
+    #[cfg(feature = "compression")]
+    fn with_compression<Z>(self, compression: Z) -> Compress<Self, Z> {
+        Compress::new(self, compression)
+    }
+
+    #[inline]
+    fn with_upgrade_timeout(self, timeout: Duration) -> UpgradeWithTimeout<Self> {
+        UpgradeWithTimeout::new(self, timeout)
+    }
 }
 
 /// See `or_upgrade()`.
@@ -687,14 +888,15 @@ where
         id: Self::UpgradeIdentifier,
         ty: Endpoint,
         remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
     ) -> Self::Future {
         match id {
-            EitherUpgradeIdentifier::First(id) => {
-                EitherConnUpgrFuture::First(self.0.upgrade(socket, id, ty, remote_addr))
-            }
-            EitherUpgradeIdentifier::Second(id) => {
-                EitherConnUpgrFuture::Second(self.1.upgrade(socket, id, ty, remote_addr))
-            }
+            EitherUpgradeIdentifier::First(id) => EitherConnUpgrFuture::First(
+                self.0.upgrade(socket, id, ty, remote_addr, local_identity),
+            ),
+            EitherUpgradeIdentifier::Second(id) => EitherConnUpgrFuture::Second(
+                self.1.upgrade(socket, id, ty, remote_addr, local_identity),
+            ),
         }
     }
 }
@@ -800,7 +1002,7 @@ where
     type NamesIter = iter::Once<(Bytes, ())>;
 
     #[inline]
-    fn upgrade(self, i: C, _: (), _: Endpoint, _: &Multiaddr) -> Self::Future {
+    fn upgrade(self, i: C, _: (), _: Endpoint, _: &Multiaddr, _: &LocalIdentity) -> Self::Future {
         future::ok(i)
     }
 
@@ -864,6 +1066,79 @@ where
     fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
         self.inner.nat_traversal(server, observed)
     }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.inner.local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.inner.reports_peer_ids()
+    }
+}
+
+/// Implements the `Transport` trait. Passes a `LocalIdentity` to the upgrades.
+///
+/// See the `Transport::with_local_identity` method.
+#[derive(Debug, Clone)]
+pub struct WithLocalIdentity<T> {
+    inner: T,
+    identity: LocalIdentity,
+}
+
+impl<T> Transport for WithLocalIdentity<T>
+where
+    T: Transport,
+{
+    type RawConn = T::RawConn;
+    type Listener = T::Listener;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = T::Dial;
+
+    #[inline]
+    fn listen_on(self, addr: Multiaddr) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+        let identity = self.identity;
+        self.inner
+            .listen_on(addr)
+            .map_err(move |(inner, addr)| (WithLocalIdentity { inner, identity }, addr))
+    }
+
+    #[inline]
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+        let identity = self.identity;
+        self.inner
+            .dial(addr)
+            .map_err(move |(inner, addr)| (WithLocalIdentity { inner, identity }, addr))
+    }
+
+    #[inline]
+    fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.nat_traversal(server, observed)
+    }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.identity.clone()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.inner.reports_peer_ids()
+    }
+}
+
+impl<T> MuxedTransport for WithLocalIdentity<T>
+where
+    T: MuxedTransport,
+{
+    type Incoming = T::Incoming;
+    type IncomingUpgrade = T::IncomingUpgrade;
+
+    #[inline]
+    fn next_incoming(self) -> Self::Incoming {
+        self.inner.next_incoming()
+    }
 }
 
 /// Implements the `Transport` trait. Dials or listens, then upgrades any dialed or received
@@ -909,6 +1184,7 @@ where
     ) -> Result<Box<Future<Item = (C::Output, Multiaddr), Error = IoError> + 'a>, (Self, Multiaddr)>
     {
         let upgrade = self.upgrade;
+        let local_identity = self.transports.local_identity();
 
         let dialed_fut = match self.transports.dial(addr.clone()) {
             Ok(f) => f.into_future(),
@@ -932,7 +1208,13 @@ where
                 negotiated.map(|(upgrade_id, conn)| (upgrade_id, conn, upgrade, client_addr))
             })
             .and_then(move |(upgrade_id, connection, upgrade, client_addr)| {
-                let f = upgrade.upgrade(connection, upgrade_id, Endpoint::Dialer, &client_addr);
+                let f = upgrade.upgrade(
+                    connection,
+                    upgrade_id,
+                    Endpoint::Dialer,
+                    &client_addr,
+                    &local_identity,
+                );
                 f.map(|v| (v, client_addr))
             });
 
@@ -959,6 +1241,7 @@ where
         C: Clone,
     {
         let upgrade = self.upgrade;
+        let local_identity = self.transports.local_identity();
 
         let future = self.transports.next_incoming().map(|future| {
             // Try to negotiate the protocol.
@@ -972,7 +1255,13 @@ where
                     negotiated.map(move |(upgrade_id, conn)| (upgrade_id, conn, upgrade, addr))
                 })
                 .and_then(move |(upgrade_id, connection, upgrade, addr)| {
-                    let upg = upgrade.upgrade(connection, upgrade_id, Endpoint::Dialer, &addr);
+                    let upg = upgrade.upgrade(
+                        connection,
+                        upgrade_id,
+                        Endpoint::Dialer,
+                        &addr,
+                        &local_identity,
+                    );
                     upg.map(|u| (u, addr))
                 });
 
@@ -1009,6 +1298,7 @@ where
         C: Clone,
     {
         let upgrade = self.upgrade;
+        let local_identity = self.transports.local_identity();
 
         let (listening_stream, new_addr) = match self.transports.listen_on(addr) {
             Ok((l, new_addr)) => (l, new_addr),
@@ -1028,6 +1318,7 @@ where
         // `stream` can only produce an `Err` if `listening_stream` produces an `Err`.
         let stream = listening_stream.map(move |connection| {
             let upgrade = upgrade.clone();
+            let local_identity = local_identity.clone();
             let connection = connection
                     // Try to negotiate the protocol
                     .and_then(move |(connection, remote_addr)| {
@@ -1041,6 +1332,7 @@ where
                                     upgrade_id,
                                     Endpoint::Listener,
                                     &remote_addr,
+                                    &local_identity,
                                 );
                                 fut.map(move |c| (c, remote_addr))
                             })
@@ -1081,6 +1373,16 @@ where
     fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
         self.transports.nat_traversal(server, observed)
     }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.transports.local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.transports.reports_peer_ids()
+    }
 }
 
 impl<T, C> MuxedTransport for UpgradedNode<T, C>
@@ -0,0 +1,260 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `TransportTimeout` struct. Wraps around a `Transport` and aborts every connection
+//! attempt that takes longer than a certain duration.
+//!
+//! The duration is a budget for the whole future returned by the underlying transport. If the
+//! underlying transport is an `UpgradedNode` or a `ConnectionReuse`, then this includes the time
+//! needed to negotiate and apply the security layer and the muxer, and not just the time needed to
+//! open the raw connection.
+//!
+//! When the budget is exceeded, the future produces an error of kind `TimedOut`. This makes it
+//! possible to give up on a slow address and try another one quickly, instead of waiting for the
+//! operating system's timeout.
+
+use clock::{Clock, ClockTimeout, TokioClock};
+use futures::{Async, Future, Poll, Stream};
+use futures::future::IntoFuture;
+use multiaddr::Multiaddr;
+use std::fmt;
+use std::io::Error as IoError;
+use std::time::Duration;
+use transport::{LocalIdentity, MuxedTransport, Transport};
+
+/// Wraps around a `Transport` and adds a timeout to all the dialing attempts and to all the
+/// incoming connections.
+///
+/// The time is measured with a `TokioClock` by default. Use `with_clock` to use another `Clock`.
+///
+/// See [the module-level documentation](index.html).
+#[derive(Clone)]
+pub struct TransportTimeout<T, C = TokioClock> {
+    inner: T,
+    outgoing_timeout: Option<Duration>,
+    incoming_timeout: Option<Duration>,
+    clock: C,
+}
+
+impl<T> TransportTimeout<T> {
+    /// Wraps around a `Transport` and uses `timeout` as the budget for both the dialing attempts
+    /// and the incoming connections.
+    #[inline]
+    pub fn new(trans: T, timeout: Duration) -> Self {
+        TransportTimeout {
+            inner: trans,
+            outgoing_timeout: Some(timeout),
+            incoming_timeout: Some(timeout),
+            clock: TokioClock::new(),
+        }
+    }
+
+    /// Wraps around a `Transport` and only applies a timeout to the dialing attempts.
+    #[inline]
+    pub fn with_outgoing_timeout(trans: T, timeout: Duration) -> Self {
+        TransportTimeout {
+            inner: trans,
+            outgoing_timeout: Some(timeout),
+            incoming_timeout: None,
+            clock: TokioClock::new(),
+        }
+    }
+
+    /// Wraps around a `Transport` and only applies a timeout to the incoming connections.
+    #[inline]
+    pub fn with_incoming_timeout(trans: T, timeout: Duration) -> Self {
+        TransportTimeout {
+            inner: trans,
+            outgoing_timeout: None,
+            incoming_timeout: Some(timeout),
+            clock: TokioClock::new(),
+        }
+    }
+}
+
+impl<T, C> TransportTimeout<T, C> {
+    /// Returns a reference to the inner `Transport`.
+    #[inline]
+    pub fn transport(&self) -> &T {
+        &self.inner
+    }
+
+    /// Replaces the `Clock` used to measure the timeouts.
+    #[inline]
+    pub fn with_clock<C2>(self, clock: C2) -> TransportTimeout<T, C2> {
+        TransportTimeout {
+            inner: self.inner,
+            outgoing_timeout: self.outgoing_timeout,
+            incoming_timeout: self.incoming_timeout,
+            clock: clock,
+        }
+    }
+}
+
+impl<T, C> fmt::Debug for TransportTimeout<T, C>
+where
+    T: fmt::Debug,
+{
+    #[inline]
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("TransportTimeout")
+            .field("inner", &self.inner)
+            .field("outgoing_timeout", &self.outgoing_timeout)
+            .field("incoming_timeout", &self.incoming_timeout)
+            .finish()
+    }
+}
+
+impl<T, C> Transport for TransportTimeout<T, C>
+where
+    T: Transport,
+    C: Clock,
+{
+    type RawConn = T::RawConn;
+    type Listener = TimeoutListener<T::Listener, C>;
+    type ListenerUpgrade = ClockTimeout<T::ListenerUpgrade, C::Delay>;
+    type Dial = ClockTimeout<<T::Dial as IntoFuture>::Future, C::Delay>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+        match self.inner.listen_on(addr) {
+            Ok((listener, addr)) => {
+                let listener = TimeoutListener {
+                    inner: listener,
+                    timeout: self.incoming_timeout,
+                    clock: self.clock,
+                };
+
+                Ok((listener, addr))
+            }
+            Err((inner, addr)) => {
+                let transport = TransportTimeout {
+                    inner,
+                    outgoing_timeout: self.outgoing_timeout,
+                    incoming_timeout: self.incoming_timeout,
+                    clock: self.clock,
+                };
+
+                Err((transport, addr))
+            }
+        }
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+        match self.inner.dial(addr) {
+            Ok(dial) => Ok(ClockTimeout::new(
+                dial.into_future(),
+                &self.clock,
+                self.outgoing_timeout,
+            )),
+            Err((inner, addr)) => {
+                let transport = TransportTimeout {
+                    inner,
+                    outgoing_timeout: self.outgoing_timeout,
+                    incoming_timeout: self.incoming_timeout,
+                    clock: self.clock,
+                };
+
+                Err((transport, addr))
+            }
+        }
+    }
+
+    #[inline]
+    fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.nat_traversal(server, observed)
+    }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.inner.local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.inner.reports_peer_ids()
+    }
+}
+
+impl<T, C> MuxedTransport for TransportTimeout<T, C>
+where
+    T: MuxedTransport,
+    C: Clock,
+{
+    type Incoming = TimeoutIncoming<T::Incoming, C>;
+    type IncomingUpgrade = ClockTimeout<T::IncomingUpgrade, C::Delay>;
+
+    #[inline]
+    fn next_incoming(self) -> Self::Incoming {
+        TimeoutIncoming {
+            inner: self.inner.next_incoming(),
+            timeout: self.incoming_timeout,
+            clock: self.clock,
+        }
+    }
+}
+
+/// Wraps around a `Stream` of incoming connections, and applies a timeout to each of them.
+pub struct TimeoutListener<InnerStream, C = TokioClock> {
+    inner: InnerStream,
+    timeout: Option<Duration>,
+    clock: C,
+}
+
+impl<InnerStream, C> Stream for TimeoutListener<InnerStream, C>
+where
+    InnerStream: Stream<Error = IoError>,
+    InnerStream::Item: Future<Error = IoError>,
+    C: Clock,
+{
+    type Item = ClockTimeout<InnerStream::Item, C::Delay>;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let upgrade = match try_ready!(self.inner.poll()) {
+            Some(upgrade) => upgrade,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        Ok(Async::Ready(Some(ClockTimeout::new(upgrade, &self.clock, self.timeout))))
+    }
+}
+
+/// Wraps around a `Future` that produces an incoming substream, and applies a timeout to the
+/// upgrade of this substream.
+pub struct TimeoutIncoming<InnerFut, C = TokioClock> {
+    inner: InnerFut,
+    timeout: Option<Duration>,
+    clock: C,
+}
+
+impl<InnerFut, C> Future for TimeoutIncoming<InnerFut, C>
+where
+    InnerFut: Future<Error = IoError>,
+    InnerFut::Item: Future<Error = IoError>,
+    C: Clock,
+{
+    type Item = ClockTimeout<InnerFut::Item, C::Delay>;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let upgrade = try_ready!(self.inner.poll());
+        Ok(Async::Ready(ClockTimeout::new(upgrade, &self.clock, self.timeout)))
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `UpgradeWithTimeout` connection upgrade, which aborts an upgrade that takes too
+//! long.
+//!
+//! Wrapping an upgrade with `UpgradeExt::with_upgrade_timeout` bounds the time that the future
+//! returned by `ConnectionUpgrade::upgrade` can take. If the deadline is reached, the future
+//! produces an error of kind `TimedOut` and the inner future is dropped, which closes the socket.
+//! This protects against remotes that accept a protocol and then stall during its handshake.
+//!
+//! The negotiation of the protocol name happens before `upgrade` is called, and is therefore not
+//! covered by this timeout. In order to bound the negotiation as well, wrap the `UpgradedNode`
+//! with `Transport::with_timeout`. This puts a budget on the opening of the connection, the
+//! negotiation and the upgrade, while `UpgradeWithTimeout` can put a tighter one on a specific
+//! protocol, including on the substreams opened over a muxed connection.
+
+use clock::{Clock, ClockTimeout, TokioClock};
+use multiaddr::Multiaddr;
+use std::io::Error as IoError;
+use std::time::Duration;
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::{ConnectionUpgrade, Endpoint, LocalIdentity};
+
+/// Wraps around an upgrade and makes it fail with an error of kind `TimedOut` if it doesn't
+/// complete within a certain duration.
+///
+/// The time is measured with a `TokioClock` by default. Use `with_clock` to use another `Clock`.
+///
+/// See [the module-level documentation](index.html).
+#[derive(Debug, Copy, Clone)]
+pub struct UpgradeWithTimeout<U, C = TokioClock> {
+    upgrade: U,
+    timeout: Duration,
+    clock: C,
+}
+
+impl<U> UpgradeWithTimeout<U> {
+    /// Wraps around `upgrade` and aborts it if it takes longer than `timeout`.
+    #[inline]
+    pub fn new(upgrade: U, timeout: Duration) -> UpgradeWithTimeout<U> {
+        UpgradeWithTimeout {
+            upgrade: upgrade,
+            timeout: timeout,
+            clock: TokioClock::new(),
+        }
+    }
+}
+
+impl<U, C> UpgradeWithTimeout<U, C> {
+    /// Uses `clock` to measure the time instead of the current one.
+    #[inline]
+    pub fn with_clock<D>(self, clock: D) -> UpgradeWithTimeout<U, D>
+    where
+        D: Clock,
+    {
+        UpgradeWithTimeout {
+            upgrade: self.upgrade,
+            timeout: self.timeout,
+            clock: clock,
+        }
+    }
+
+    /// Returns the duration after which the upgrade is aborted.
+    #[inline]
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+impl<S, U, C> ConnectionUpgrade<S> for UpgradeWithTimeout<U, C>
+where
+    S: AsyncRead + AsyncWrite,
+    U: ConnectionUpgrade<S>,
+    C: Clock,
+{
+    type NamesIter = U::NamesIter;
+    type UpgradeIdentifier = U::UpgradeIdentifier;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        self.upgrade.protocol_names()
+    }
+
+    type Output = U::Output;
+    type Future = ClockTimeout<U::Future, C::Delay>;
+
+    #[inline]
+    fn upgrade(
+        self,
+        socket: S,
+        id: Self::UpgradeIdentifier,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        let future = self.upgrade.upgrade(socket, id, ty, remote_addr, local_identity);
+        ClockTimeout::new(future, &self.clock, Some(self.timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, Future};
+    use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind};
+    use std::time::Duration;
+    use {ConnectionUpgrade, Endpoint, LocalIdentity, ManualClock, SimpleProtocol, UpgradeExt};
+
+    #[test]
+    fn stalled_upgrade_aborted() {
+        let clock = ManualClock::new();
+        let upgrade = SimpleProtocol::new("/stall/1.0.0", |_| future::empty::<(), IoError>())
+            .with_upgrade_timeout(Duration::from_secs(10))
+            .with_clock(clock.clone());
+        let addr = "/ip4/1.2.3.4/tcp/5".parse().unwrap();
+
+        let (_, id) = ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&upgrade)
+            .next()
+            .unwrap();
+        let mut future = upgrade.upgrade(Cursor::new(Vec::new()), id, Endpoint::Dialer, &addr,
+                                         &LocalIdentity::unknown());
+
+        clock.advance(Duration::from_secs(5));
+        let pending = future::lazy(|| Ok::<_, ()>(future.poll().unwrap().is_not_ready()));
+        assert!(pending.wait().unwrap());
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(future.wait().unwrap_err().kind(), IoErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn fast_upgrade_passes() {
+        let upgrade = SimpleProtocol::new("/fast/1.0.0", |_| Ok::<_, IoError>(12))
+            .with_upgrade_timeout(Duration::from_secs(10))
+            .with_clock(ManualClock::new());
+        let addr = "/ip4/1.2.3.4/tcp/5".parse().unwrap();
+
+        let (_, id) = ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&upgrade)
+            .next()
+            .unwrap();
+        let output = upgrade
+            .upgrade(Cursor::new(Vec::new()), id, Endpoint::Listener, &addr,
+                     &LocalIdentity::unknown())
+            .wait()
+            .unwrap();
+        assert_eq!(output, 12);
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+extern crate futures;
+extern crate libp2p_core;
+extern crate libp2p_tcp_transport;
+extern crate tokio_core;
+
+use futures::future::Future;
+use futures::Stream;
+use libp2p_core::{dial_any, DialAttemptError, Multiaddr, Transport};
+use libp2p_tcp_transport::TcpConfig;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+
+// Returns a TCP multiaddress on which nobody is listening.
+fn closed_port_addr() -> Multiaddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    drop(listener);
+    format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+}
+
+#[test]
+fn falls_back_to_next_candidate() {
+    let mut core = Core::new().unwrap();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+    let listener_port = listener.local_addr().unwrap().port();
+    let good_addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", listener_port)
+        .parse()
+        .unwrap();
+
+    let candidates = vec![
+        "/ip4/127.0.0.1/udp/1234".parse::<Multiaddr>().unwrap(),
+        closed_port_addr(),
+        good_addr.clone(),
+    ];
+
+    let transport = TcpConfig::new(core.handle());
+    let client = dial_any(candidates, move |addr| {
+        transport.clone().dial(addr).map_err(|(_, addr)| addr)
+    }).map_err(|err| panic!("{}", err));
+
+    let server = listener
+        .incoming()
+        .into_future()
+        .map(|_| ())
+        .map_err(|(err, _)| panic!("{:?}", err));
+
+    let ((_, dialed_addr), ()) = core.run(client.join(server)).unwrap();
+    assert_eq!(dialed_addr, good_addr);
+}
+
+#[test]
+fn aggregates_errors() {
+    let mut core = Core::new().unwrap();
+
+    let unsupported = "/ip4/127.0.0.1/udp/1234".parse::<Multiaddr>().unwrap();
+    let closed = closed_port_addr();
+    let candidates = vec![unsupported.clone(), closed.clone()];
+
+    let transport = TcpConfig::new(core.handle());
+    let client = dial_any(candidates, move |addr| {
+        transport.clone().dial(addr).map_err(|(_, addr)| addr)
+    });
+
+    let err = match core.run(client) {
+        Ok(_) => panic!("dialing should have failed"),
+        Err(err) => err,
+    };
+
+    let attempts = err.into_attempts();
+    assert_eq!(attempts.len(), 2);
+    match attempts[0] {
+        (ref addr, DialAttemptError::MultiaddrNotSupported) => assert_eq!(*addr, unsupported),
+        _ => panic!(),
+    }
+    match attempts[1] {
+        (ref addr, DialAttemptError::Io(_)) => assert_eq!(*addr, closed),
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn concurrent_attempts() {
+    let mut core = Core::new().unwrap();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+    let listener_port = listener.local_addr().unwrap().port();
+    let good_addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", listener_port)
+        .parse()
+        .unwrap();
+
+    // The first candidate never answers, so the dialing would hang if it wasn't concurrent.
+    let candidates = vec![
+        "/ip4/10.255.255.1/tcp/1".parse::<Multiaddr>().unwrap(),
+        closed_port_addr(),
+        good_addr.clone(),
+    ];
+
+    let transport = TcpConfig::new(core.handle());
+    let client = dial_any(candidates, move |addr| {
+        transport.clone().dial(addr).map_err(|(_, addr)| addr)
+    }).with_concurrency(3)
+        .map_err(|err| panic!("{}", err));
+
+    let server = listener
+        .incoming()
+        .into_future()
+        .map(|_| ())
+        .map_err(|(err, _)| panic!("{:?}", err));
+
+    let ((_, dialed_addr), ()) = core.run(client.join(server)).unwrap();
+    assert_eq!(dialed_addr, good_addr);
+}
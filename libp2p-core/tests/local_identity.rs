@@ -0,0 +1,119 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+extern crate bytes;
+extern crate futures;
+extern crate libp2p_core;
+extern crate libp2p_tcp_transport;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use bytes::Bytes;
+use futures::future::{self, Future, FutureResult};
+use futures::Stream;
+use libp2p_core::{ConnectionUpgrade, Endpoint, LocalIdentity, Multiaddr, Transport};
+use libp2p_tcp_transport::TcpConfig;
+use std::io::Error as IoError;
+use std::iter;
+use tokio_core::reactor::Core;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+// Upgrade whose output is the public key found in the local identity.
+#[derive(Debug, Clone)]
+struct CaptureIdentity;
+impl<C> ConnectionUpgrade<C> for CaptureIdentity
+where
+    C: AsyncRead + AsyncWrite,
+{
+    type NamesIter = iter::Once<(Bytes, ())>;
+    type UpgradeIdentifier = ();
+    type Output = Option<Vec<u8>>;
+    type Future = FutureResult<Self::Output, IoError>;
+
+    fn protocol_names(&self) -> Self::NamesIter {
+        iter::once((Bytes::from("/capture/1.0.0"), ()))
+    }
+
+    fn upgrade(
+        self,
+        _: C,
+        _: (),
+        _: Endpoint,
+        _: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        future::ok(local_identity.public_key().map(|key| key.to_vec()))
+    }
+}
+
+#[test]
+fn identity_passed_to_upgrades() {
+    let mut core = Core::new().unwrap();
+
+    let listener = TcpConfig::new(core.handle())
+        .with_local_identity(LocalIdentity::new(vec![1, 2, 3]))
+        .with_upgrade(CaptureIdentity);
+    let (listener, addr) = listener
+        .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap_or_else(|_| panic!());
+
+    let dialer = TcpConfig::new(core.handle())
+        .with_local_identity(LocalIdentity::new(vec![4, 5, 6]))
+        .with_upgrade(CaptureIdentity);
+
+    let server = listener
+        .into_future()
+        .map_err(|(err, _)| err)
+        .and_then(|(upgrade, _)| upgrade.unwrap())
+        .map(|(output, _)| output);
+    let client = dialer
+        .dial(addr)
+        .unwrap_or_else(|_| panic!())
+        .map(|(output, _)| output);
+
+    let (server_output, client_output) = core.run(server.join(client)).unwrap();
+    assert_eq!(server_output, Some(vec![1, 2, 3]));
+    assert_eq!(client_output, Some(vec![4, 5, 6]));
+}
+
+#[test]
+fn identity_unknown_by_default() {
+    let mut core = Core::new().unwrap();
+
+    let (listener, addr) = TcpConfig::new(core.handle())
+        .with_upgrade(CaptureIdentity)
+        .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap_or_else(|_| panic!());
+    let dialer = TcpConfig::new(core.handle()).with_upgrade(CaptureIdentity);
+
+    let server = listener
+        .into_future()
+        .map_err(|(err, _)| err)
+        .and_then(|(upgrade, _)| upgrade.unwrap())
+        .map(|(output, _)| output);
+    let client = dialer
+        .dial(addr)
+        .unwrap_or_else(|_| panic!())
+        .map(|(output, _)| output);
+
+    let (server_output, client_output) = core.run(server.join(client)).unwrap();
+    assert_eq!(server_output, None);
+    assert_eq!(client_output, None);
+}
@@ -20,7 +20,7 @@
 
 extern crate bytes;
 extern crate futures;
-extern crate libp2p_swarm;
+extern crate libp2p_core;
 extern crate libp2p_tcp_transport;
 extern crate multiplex;
 extern crate tokio_core;
@@ -29,7 +29,7 @@ extern crate tokio_io;
 use bytes::BytesMut;
 use futures::future::Future;
 use futures::{Sink, Stream};
-use libp2p_swarm::{Multiaddr, MuxedTransport, StreamMuxer, Transport};
+use libp2p_core::{Multiaddr, MuxedTransport, StreamMuxer, Transport};
 use libp2p_tcp_transport::TcpConfig;
 use tokio_core::reactor::Core;
 use tokio_io::codec::length_delimited::Framed;
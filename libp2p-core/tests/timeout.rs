@@ -0,0 +1,84 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+extern crate futures;
+extern crate libp2p_core;
+extern crate libp2p_tcp_transport;
+extern crate tokio_core;
+
+use futures::future::Future;
+use libp2p_core::{Multiaddr, PlainTextConfig, Transport};
+use libp2p_tcp_transport::TcpConfig;
+use std::io::ErrorKind as IoErrorKind;
+use std::time::Duration;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+
+#[test]
+fn dial_aborted_when_upgrade_too_slow() {
+    // The remote accepts the TCP connection but never answers the protocol negotiation. The
+    // budget must cover the upgrade and abort the dialing attempt.
+
+    let mut core = Core::new().unwrap();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+    let listener_addr = listener.local_addr().unwrap();
+    let addr = format!("/ip4/127.0.0.1/tcp/{}", listener_addr.port())
+        .parse::<Multiaddr>()
+        .unwrap();
+
+    let transport = TcpConfig::new(core.handle())
+        .with_upgrade(PlainTextConfig)
+        .with_timeout(Duration::from_millis(500));
+
+    let future = transport
+        .dial(addr)
+        .unwrap_or_else(|_| panic!())
+        .map(|_| ());
+
+    match core.run(future) {
+        Err(err) => assert_eq!(err.kind(), IoErrorKind::TimedOut),
+        Ok(_) => panic!("dialing should have timed out"),
+    }
+
+    drop(listener);
+}
+
+#[test]
+fn dial_within_budget_succeeds() {
+    let mut core = Core::new().unwrap();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+    let listener_addr = listener.local_addr().unwrap();
+    let addr = format!("/ip4/127.0.0.1/tcp/{}", listener_addr.port())
+        .parse::<Multiaddr>()
+        .unwrap();
+
+    let transport = TcpConfig::new(core.handle()).with_timeout(Duration::from_secs(5));
+
+    let server = listener
+        .incoming()
+        .into_future()
+        .map(|_| ())
+        .map_err(|(err, _)| err);
+    let client = transport.dial(addr).unwrap_or_else(|_| panic!()).map(|_| ());
+
+    core.run(server.join(client)).unwrap();
+}
@@ -37,7 +37,7 @@
 //!
 
 extern crate futures;
-extern crate libp2p_swarm as swarm;
+extern crate libp2p_core;
 #[macro_use]
 extern crate log;
 extern crate multiaddr;
@@ -50,7 +50,7 @@ use multiaddr::{AddrComponent, Multiaddr};
 use std::fmt;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 use std::net::IpAddr;
-use swarm::Transport;
+use libp2p_core::{LocalIdentity, Transport};
 use tokio_dns::{CpuPoolResolver, Resolver};
 
 /// Represents the configuration for a DNS transport capability of libp2p.
@@ -179,6 +179,16 @@ where
         // as well.
         self.inner.nat_traversal(server, observed)
     }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.inner.local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.inner.reports_peer_ids()
+    }
 }
 
 // How to resolve ; to an IPv4 address or an IPv6 address?
@@ -228,7 +238,7 @@ mod tests {
     use futures::{future, Future};
     use multiaddr::{AddrComponent, Multiaddr};
     use std::io::Error as IoError;
-    use swarm::Transport;
+    use libp2p_core::Transport;
 
     #[test]
     fn basic_resolve() {
@@ -0,0 +1,629 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+// TODO: use this once stable ; for now we just copy-paste the content of the README.md
+//#![doc(include = "../README.md")]
+
+//! Serves HTTP/1.1 requests over libp2p substreams.
+//!
+//! Services that already speak HTTP, such as a REST API, can be exposed to the peers of a node
+//! without running a separate web server and tunnelling the connections to it. Each substream
+//! negotiated with the `/http/1.1` protocol carries plain HTTP/1.1 requests and responses, whose
+//! types come from the `http` crate.
+//!
+//! # Usage
+//!
+//! Create an `HttpConfig` with the handler of the requests, and use it as a connection upgrade.
+//!
+//! - The listener gets an `HttpServer`, which is a future that reads the requests of the remote,
+//!   passes them to the handler and writes back the responses. It must be driven to completion,
+//!   and finishes when the remote closes the substream.
+//! - The dialer gets an `HttpClient`, on which requests can be sent one after the other.
+//!
+//! The substream is kept alive between the requests, unless the request or the response has a
+//! `Connection: close` header or the request uses HTTP/1.0. A handler that fails produces a
+//! `500 Internal Server Error` response, after which the substream is closed.
+//!
+//! # Limitations
+//!
+//! This is a minimal implementation. Bodies must have a `Content-Length`: the chunked transfer
+//! encoding isn't supported, and a message without a length is considered as having an empty
+//! body. The size of the messages is limited, and larger messages close the substream with an
+//! error. Pipelined requests are processed one at a time.
+//!
+//! # Example
+//!
+//! ```no_run
+//! extern crate futures;
+//! extern crate http;
+//! extern crate libp2p_core;
+//! extern crate libp2p_http;
+//! extern crate libp2p_tcp_transport;
+//! extern crate tokio_core;
+//!
+//! use futures::{Future, Stream};
+//! use http::{Request, Response};
+//! use libp2p_core::Transport;
+//! use libp2p_http::{HttpConfig, HttpSubstream};
+//!
+//! # fn main() {
+//! let mut core = tokio_core::reactor::Core::new().unwrap();
+//!
+//! let config = HttpConfig::new(|request: Request<Vec<u8>>| {
+//!     Response::builder()
+//!         .body(format!("hello from {}", request.uri()).into_bytes())
+//!         .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+//! });
+//!
+//! let handle = core.handle();
+//! let (listener, _) = libp2p_tcp_transport::TcpConfig::new(core.handle())
+//!     .with_upgrade(config)
+//!     .listen_on("/ip4/0.0.0.0/tcp/8080".parse().unwrap()).unwrap_or_else(|_| panic!());
+//!
+//! let future = listener.for_each(|upgrade| {
+//!     upgrade.map(|(substream, _)| match substream {
+//!         HttpSubstream::Server(server) => handle.spawn(server.map_err(|_| ())),
+//!         HttpSubstream::Client(_) => unreachable!("we are the listener"),
+//!     })
+//! });
+//!
+//! core.run(future).unwrap();
+//! # }
+//! ```
+
+extern crate bytes;
+extern crate futures;
+extern crate http;
+extern crate httparse;
+extern crate libp2p_core;
+extern crate tokio_io;
+
+use bytes::Bytes;
+use futures::{future, Async, Future, IntoFuture, Poll};
+use futures::future::{loop_fn, FutureResult, Loop};
+use http::header::{CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::{HeaderMap, Request, Response, StatusCode, Version};
+use libp2p_core::{ConnectionUpgrade, Endpoint, LocalIdentity, Multiaddr};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read};
+use std::iter;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{shutdown, write_all};
+
+/// Name of the protocol negotiated on the substreams.
+pub const PROTOCOL_NAME: &'static [u8] = b"/http/1.1";
+
+/// Default maximum size of a message, headers included, in bytes.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+/// Maximum number of headers of a message.
+const MAX_HEADERS: usize = 64;
+
+/// Configuration of the HTTP protocol. Implements `ConnectionUpgrade`.
+///
+/// `H` is the handler of the requests of the remotes. It is only used on the listener side.
+#[derive(Debug, Clone)]
+pub struct HttpConfig<H> {
+    handler: H,
+    max_message_size: usize,
+}
+
+impl<H> HttpConfig<H> {
+    /// Builds a configuration that passes the requests to `handler`.
+    #[inline]
+    pub fn new(handler: H) -> HttpConfig<H> {
+        HttpConfig {
+            handler: handler,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+
+    /// Sets the maximum size of the messages that are received, in bytes. Defaults to 1 MiB.
+    #[inline]
+    pub fn with_max_message_size(mut self, max: usize) -> HttpConfig<H> {
+        self.max_message_size = max;
+        self
+    }
+}
+
+impl<C, H, F> ConnectionUpgrade<C> for HttpConfig<H>
+where
+    C: AsyncRead + AsyncWrite + 'static, // TODO: 'static :-/
+    H: FnMut(Request<Vec<u8>>) -> F + 'static, // TODO: 'static :-/
+    F: IntoFuture<Item = Response<Vec<u8>>, Error = IoError> + 'static, // TODO: 'static :-/
+{
+    type NamesIter = iter::Once<(Bytes, ())>;
+    type UpgradeIdentifier = ();
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        iter::once((Bytes::from(PROTOCOL_NAME), ()))
+    }
+
+    type Output = HttpSubstream<C>;
+    type Future = FutureResult<HttpSubstream<C>, IoError>;
+
+    fn upgrade(
+        self,
+        socket: C,
+        _: (),
+        endpoint: Endpoint,
+        _: &Multiaddr,
+        _: &LocalIdentity,
+    ) -> Self::Future {
+        let substream = match endpoint {
+            Endpoint::Dialer => HttpSubstream::Client(HttpClient {
+                socket: socket,
+                buffer: Vec::new(),
+                max_message_size: self.max_message_size,
+            }),
+            Endpoint::Listener => {
+                HttpSubstream::Server(serve(socket, self.handler, self.max_message_size))
+            }
+        };
+
+        future::ok(substream)
+    }
+}
+
+/// Output of the upgrade of an `HttpConfig`.
+pub enum HttpSubstream<C> {
+    /// We opened the substream and can send requests on it.
+    Client(HttpClient<C>),
+    /// The remote opened the substream, and its requests are served by the handler.
+    Server(HttpServer),
+}
+
+/// Future that serves the requests of the remote on a substream.
+///
+/// Finishes when the remote closes the substream, or after a response that closes it.
+#[must_use = "futures do nothing unless polled"]
+pub struct HttpServer {
+    inner: Box<Future<Item = (), Error = IoError>>,
+}
+
+impl Future for HttpServer {
+    type Item = ();
+    type Error = IoError;
+
+    #[inline]
+    fn poll(&mut self) -> Poll<(), IoError> {
+        self.inner.poll()
+    }
+}
+
+fn serve<C, H, F>(socket: C, handler: H, max_message_size: usize) -> HttpServer
+where
+    C: AsyncRead + AsyncWrite + 'static, // TODO: 'static :-/
+    H: FnMut(Request<Vec<u8>>) -> F + 'static, // TODO: 'static :-/
+    F: IntoFuture<Item = Response<Vec<u8>>, Error = IoError> + 'static, // TODO: 'static :-/
+{
+    let future = loop_fn(
+        (socket, Vec::new(), handler),
+        move |(socket, buffer, mut handler)| {
+            read_message(socket, buffer, max_message_size, parse_request).and_then(
+                move |(message, socket, buffer)| {
+                    let request = match message {
+                        Some((head, body)) => head.map(move |()| body),
+                        None => {
+                            // The remote has closed its side of the substream.
+                            return Box::new(future::ok(Loop::Break(socket)))
+                                as Box<Future<Item = _, Error = _>>;
+                        }
+                    };
+
+                    let request_keep_alive = keep_alive(request.version(), request.headers());
+                    let future = handler(request)
+                        .into_future()
+                        .then(|result| {
+                            // Any error of the handler is reported as a server error, and the
+                            // substream is then closed.
+                            Ok::<_, IoError>(result.unwrap_or_else(|_| {
+                                let mut response = Response::new(Vec::new());
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                response
+                                    .headers_mut()
+                                    .insert(CONNECTION, "close".parse().expect("valid header"));
+                                response
+                            }))
+                        })
+                        .and_then(move |response| {
+                            let keep_alive = request_keep_alive
+                                && keep_alive(Version::HTTP_11, response.headers());
+                            write_all(socket, encode_response(&response, keep_alive))
+                                .map(move |(socket, _)| (socket, keep_alive))
+                        })
+                        .map(move |(socket, keep_alive)| {
+                            if keep_alive {
+                                Loop::Continue((socket, buffer, handler))
+                            } else {
+                                Loop::Break(socket)
+                            }
+                        });
+                    Box::new(future) as Box<_>
+                },
+            )
+        },
+    ).and_then(|socket| shutdown(socket).map(|_| ()));
+
+    HttpServer {
+        inner: Box::new(future),
+    }
+}
+
+/// Substream on which we can send requests to the remote.
+pub struct HttpClient<C> {
+    socket: C,
+    // Data received after the end of the previous response.
+    buffer: Vec<u8>,
+    max_message_size: usize,
+}
+
+impl<C> HttpClient<C>
+where
+    C: AsyncRead + AsyncWrite + 'static, // TODO: 'static :-/
+{
+    /// Sends a request to the remote, then waits for its response. Produces the response and the
+    /// client, which can be used for the next request unless the response closed the substream.
+    ///
+    /// Only the path and the query of the URI of the request are sent.
+    pub fn request(
+        self,
+        request: Request<Vec<u8>>,
+    ) -> Box<Future<Item = (Response<Vec<u8>>, HttpClient<C>), Error = IoError>> {
+        let HttpClient {
+            socket,
+            buffer,
+            max_message_size,
+        } = self;
+
+        let future = write_all(socket, encode_request(&request))
+            .and_then(move |(socket, _)| {
+                read_message(socket, buffer, max_message_size, parse_response)
+            })
+            .and_then(move |(message, socket, buffer)| match message {
+                Some((head, body)) => {
+                    let client = HttpClient {
+                        socket: socket,
+                        buffer: buffer,
+                        max_message_size: max_message_size,
+                    };
+                    Ok((head.map(move |()| body), client))
+                }
+                None => Err(IoError::new(
+                    IoErrorKind::UnexpectedEof,
+                    "substream closed before the response was received",
+                )),
+            });
+
+        // The "Rust doesn't have impl Trait yet" tax.
+        Box::new(future)
+    }
+}
+
+// Returns true if the substream stays open after a message with these version and headers.
+fn keep_alive(version: Version, headers: &HeaderMap) -> bool {
+    let close = headers
+        .get_all(CONNECTION)
+        .iter()
+        .any(|value| value.as_bytes().eq_ignore_ascii_case(b"close"));
+    version == Version::HTTP_11 && !close
+}
+
+// Parses the head of a message at the start of `buffer`. Returns the head, its length, and the
+// length of the body that follows, or `None` if the head isn't complete yet.
+type ParseHead<T> = fn(&[u8]) -> Result<Option<(T, usize, usize)>, IoError>;
+
+fn parse_request(buffer: &[u8]) -> Result<Option<(Request<()>, usize, usize)>, IoError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    let head_len = match request.parse(buffer).map_err(invalid_data)? {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial => return Ok(None),
+    };
+
+    let mut builder = Request::builder();
+    builder
+        .method(request.method.expect("complete request has a method"))
+        .uri(request.path.expect("complete request has a path"))
+        .version(version(request.version));
+    for header in request.headers.iter() {
+        builder.header(header.name, header.value);
+    }
+    let head = builder.body(()).map_err(invalid_data)?;
+    let body_len = body_len(head.headers())?;
+    Ok(Some((head, head_len, body_len)))
+}
+
+fn parse_response(buffer: &[u8]) -> Result<Option<(Response<()>, usize, usize)>, IoError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    let head_len = match response.parse(buffer).map_err(invalid_data)? {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial => return Ok(None),
+    };
+
+    let mut builder = Response::builder();
+    builder
+        .status(response.code.expect("complete response has a status"))
+        .version(version(response.version));
+    for header in response.headers.iter() {
+        builder.header(header.name, header.value);
+    }
+    let head = builder.body(()).map_err(invalid_data)?;
+    let body_len = body_len(head.headers())?;
+    Ok(Some((head, head_len, body_len)))
+}
+
+#[inline]
+fn version(minor: Option<u8>) -> Version {
+    match minor {
+        Some(0) => Version::HTTP_10,
+        _ => Version::HTTP_11,
+    }
+}
+
+// Returns the length of the body announced by `headers`.
+fn body_len(headers: &HeaderMap) -> Result<usize, IoError> {
+    if headers.contains_key(TRANSFER_ENCODING) {
+        return Err(IoError::new(
+            IoErrorKind::InvalidData,
+            "transfer encodings aren't supported",
+        ));
+    }
+
+    match headers.get(CONTENT_LENGTH) {
+        Some(len) => len.to_str()
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| IoError::new(IoErrorKind::InvalidData, "invalid content length")),
+        None => Ok(0),
+    }
+}
+
+fn encode_request(request: &Request<Vec<u8>>) -> Vec<u8> {
+    let target = request
+        .uri()
+        .path_and_query()
+        .map(|target| target.as_str())
+        .unwrap_or("/");
+    let mut out = format!("{} {} HTTP/1.1\r\n", request.method(), target).into_bytes();
+    encode_headers_and_body(&mut out, request.headers(), request.body(), true);
+    out
+}
+
+fn encode_response(response: &Response<Vec<u8>>, keep_alive: bool) -> Vec<u8> {
+    let status = response.status();
+    let reason = status.canonical_reason().unwrap_or("");
+    let mut out = format!("HTTP/1.1 {} {}\r\n", status.as_u16(), reason).into_bytes();
+    encode_headers_and_body(&mut out, response.headers(), response.body(), keep_alive);
+    out
+}
+
+// The length of the body always comes from the body itself, and the `Connection` header from
+// `keep_alive`.
+fn encode_headers_and_body(out: &mut Vec<u8>, headers: &HeaderMap, body: &[u8], keep_alive: bool) {
+    for (name, value) in headers.iter() {
+        if name == CONTENT_LENGTH || name == CONNECTION {
+            continue;
+        }
+        out.extend_from_slice(name.as_str().as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(value.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+
+    if !keep_alive {
+        out.extend_from_slice(b"connection: close\r\n");
+    }
+    out.extend_from_slice(format!("content-length: {}\r\n\r\n", body.len()).as_bytes());
+    out.extend_from_slice(body);
+}
+
+// Produces the next message of the socket, with its body. Also produces the socket, and the data
+// that has been received after the message. The message is `None` if the remote closed the
+// substream before starting a new message.
+fn read_message<C, T>(
+    socket: C,
+    buffer: Vec<u8>,
+    max_size: usize,
+    parse: ParseHead<T>,
+) -> ReadMessage<C, T> {
+    ReadMessage {
+        inner: Some((socket, buffer)),
+        max_size: max_size,
+        parse: parse,
+    }
+}
+
+struct ReadMessage<C, T> {
+    inner: Option<(C, Vec<u8>)>,
+    max_size: usize,
+    parse: ParseHead<T>,
+}
+
+impl<C, T> Future for ReadMessage<C, T>
+where
+    C: AsyncRead,
+{
+    type Item = (Option<(T, Vec<u8>)>, C, Vec<u8>);
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Self::Item, IoError> {
+        loop {
+            // The head is parsed again each time data is received, which is fine for messages
+            // of this size.
+            let parsed = {
+                let buffer = &self.inner.as_ref().expect("future polled after completion").1;
+                match (self.parse)(buffer)? {
+                    Some((head, head_len, body_len)) => {
+                        if head_len + body_len > self.max_size {
+                            return Err(too_large());
+                        }
+                        if buffer.len() >= head_len + body_len {
+                            Some((head, head_len, body_len))
+                        } else {
+                            None
+                        }
+                    }
+                    None if buffer.len() >= self.max_size => return Err(too_large()),
+                    None => None,
+                }
+            };
+
+            if let Some((head, head_len, body_len)) = parsed {
+                let (socket, mut buffer) = self.inner.take().expect("checked above");
+                let rest = buffer.split_off(head_len + body_len);
+                let body = buffer.split_off(head_len);
+                return Ok(Async::Ready((Some((head, body)), socket, rest)));
+            }
+
+            let mut chunk = [0; 4096];
+            let num_read = {
+                let socket = &mut self.inner.as_mut().expect("checked above").0;
+                match socket.read(&mut chunk) {
+                    Ok(num_read) => num_read,
+                    Err(ref err) if err.kind() == IoErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady);
+                    }
+                    Err(err) => return Err(err),
+                }
+            };
+
+            if num_read == 0 {
+                let (socket, buffer) = self.inner.take().expect("checked above");
+                if buffer.is_empty() {
+                    return Ok(Async::Ready((None, socket, buffer)));
+                }
+                return Err(IoError::new(
+                    IoErrorKind::UnexpectedEof,
+                    "substream closed in the middle of a message",
+                ));
+            }
+
+            self.inner.as_mut().expect("checked above").1.extend_from_slice(&chunk[..num_read]);
+        }
+    }
+}
+
+#[inline]
+fn invalid_data<E>(err: E) -> IoError
+where
+    E: Into<Box<::std::error::Error + Send + Sync>>,
+{
+    IoError::new(IoErrorKind::InvalidData, err)
+}
+
+#[inline]
+fn too_large() -> IoError {
+    IoError::new(IoErrorKind::InvalidData, "message exceeds the maximum size")
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tokio_core;
+
+    use self::tokio_core::net::{TcpListener, TcpStream};
+    use self::tokio_core::reactor::Core;
+    use futures::{Future, Stream};
+    use http::{Request, Response, StatusCode};
+    use libp2p_core::{ConnectionUpgrade, Endpoint, LocalIdentity};
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+    use {parse_request, HttpConfig, HttpSubstream};
+
+    // Handler that answers with the method, the path and the body of the request, and fails for
+    // the `/fail` path.
+    fn echo(request: Request<Vec<u8>>) -> Result<Response<Vec<u8>>, IoError> {
+        if request.uri().path() == "/fail" {
+            return Err(IoError::new(IoErrorKind::Other, "failure"));
+        }
+        let mut body = format!("{} {} ", request.method(), request.uri()).into_bytes();
+        body.extend_from_slice(request.body());
+        Ok(Response::new(body))
+    }
+
+    #[test]
+    fn partial_request() {
+        let request = b"POST /a HTTP/1.1\r\ncontent-length: 3\r\n\r\nabc";
+        assert!(parse_request(&request[..20]).unwrap().is_none());
+        let (head, head_len, body_len) = parse_request(request).unwrap().unwrap();
+        assert_eq!(head.uri().path(), "/a");
+        assert_eq!((head_len, body_len), (request.len() - 3, 3));
+    }
+
+    #[test]
+    fn requests_on_same_substream() {
+        let mut core = Core::new().unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(c, _)| {
+                HttpConfig::new(echo).upgrade(
+                    c.unwrap().0,
+                    (),
+                    Endpoint::Listener,
+                    &"/ip4/127.0.0.1/tcp/10000".parse().unwrap(),
+                    &LocalIdentity::unknown(),
+                )
+            })
+            .and_then(|substream| match substream {
+                HttpSubstream::Server(server) => server,
+                HttpSubstream::Client(_) => panic!(),
+            });
+
+        let client = TcpStream::connect(&listener_addr, &core.handle())
+            .and_then(|c| {
+                HttpConfig::new(echo).upgrade(
+                    c,
+                    (),
+                    Endpoint::Dialer,
+                    &"/ip4/127.0.0.1/tcp/10000".parse().unwrap(),
+                    &LocalIdentity::unknown(),
+                )
+            })
+            .and_then(|substream| match substream {
+                HttpSubstream::Client(client) => {
+                    let request = Request::post("/first").body(b"body".to_vec()).unwrap();
+                    client.request(request)
+                }
+                HttpSubstream::Server(_) => panic!(),
+            })
+            .and_then(|(response, client)| {
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.body(), b"POST /first body");
+                let request = Request::get("/second?a=b").body(Vec::new()).unwrap();
+                client.request(request)
+            })
+            .and_then(|(response, client)| {
+                assert_eq!(response.body(), b"GET /second?a=b ");
+                let request = Request::get("/fail").body(Vec::new()).unwrap();
+                client.request(request)
+            })
+            .map(|(response, _)| {
+                assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            });
+
+        // The server finishes after closing the substream because of the failure.
+        core.run(server.join(client)).unwrap();
+    }
+}
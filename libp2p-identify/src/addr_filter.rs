@@ -0,0 +1,272 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `ListenAddrFilter` struct, which removes some of our listen addresses before they
+//! are sent to a remote.
+//!
+//! A node that is reachable from the Internet usually also listens on `127.0.0.1` or on an
+//! address of its local network. Advertising these addresses is useless at best, and at worst
+//! makes remotes dial an unrelated machine that happens to use the same address on their side.
+//!
+//! The filter can also take into account the address of the remote. For example, a remote that
+//! connected from the Internet over IPv4 has no use for our IPv6 or local network addresses.
+
+use libp2p_core::{Blacklist, IpRange};
+use multiaddr::{AddrComponent, Multiaddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Decides which of our listen addresses are sent to the remote.
+///
+/// The filter is a `Blacklist` of the IP ranges that must not be advertised. Addresses that don't
+/// contain any IP address, for example `/dns4/...`, are always sent.
+#[derive(Debug, Clone)]
+pub struct ListenAddrFilter {
+    denied: Blacklist,
+    // If true, the address of the remote is used to remove the addresses it can't reach.
+    remote_hint: bool,
+}
+
+impl ListenAddrFilter {
+    /// Only sends addresses that are reachable from the Internet. Removes the loopback,
+    /// unspecified and link-local addresses, plus the private ranges of
+    /// `Blacklist::deny_private_ranges`.
+    pub fn public_only() -> ListenAddrFilter {
+        let ranges = [
+            IpRange::new(Ipv4Addr::new(169, 254, 0, 0).into(), 16),
+            IpRange::new(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0).into(), 10),
+        ];
+
+        let denied = ranges.iter().fold(
+            local_only_ranges().deny_private_ranges(),
+            |blacklist, range| {
+                blacklist.deny_range(range.clone().expect("the prefix lengths above are valid"))
+            },
+        );
+
+        ListenAddrFilter {
+            denied,
+            remote_hint: false,
+        }
+    }
+
+    /// Sends the addresses that are reachable from the local network. Only removes the loopback
+    /// and unspecified addresses.
+    #[inline]
+    pub fn lan_ok() -> ListenAddrFilter {
+        ListenAddrFilter {
+            denied: local_only_ranges(),
+            remote_hint: false,
+        }
+    }
+
+    /// Sends all the addresses. This is the default.
+    #[inline]
+    pub fn everything() -> ListenAddrFilter {
+        ListenAddrFilter {
+            denied: Blacklist::new(),
+            remote_hint: false,
+        }
+    }
+
+    /// Removes the addresses that are denied by `blacklist`.
+    #[inline]
+    pub fn custom(blacklist: Blacklist) -> ListenAddrFilter {
+        ListenAddrFilter {
+            denied: blacklist,
+            remote_hint: false,
+        }
+    }
+
+    /// In addition to the rules of the filter, uses the address of the remote as a hint of which
+    /// addresses it can reach:
+    ///
+    /// - A remote that connected over IPv4 doesn't receive our IPv6 addresses.
+    /// - A remote that connected from a public address doesn't receive our loopback and local
+    ///   network addresses.
+    /// - A remote that connected from the local network doesn't receive our loopback addresses.
+    ///
+    /// This only applies to `filter_for_remote`.
+    #[inline]
+    pub fn with_remote_hint(mut self) -> ListenAddrFilter {
+        self.remote_hint = true;
+        self
+    }
+
+    /// Returns true if `with_remote_hint` has been called, in which case the addresses that are
+    /// sent depend on the remote.
+    #[inline]
+    pub fn has_remote_hint(&self) -> bool {
+        self.remote_hint
+    }
+
+    /// Returns true if `addr` can be sent to the remote.
+    #[inline]
+    pub fn allows(&self, addr: &Multiaddr) -> bool {
+        !self.denied.is_denied(addr)
+    }
+
+    /// Returns the addresses of `addrs` that can be sent to the remote, in the same order.
+    pub fn filter(&self, addrs: Vec<Multiaddr>) -> Vec<Multiaddr> {
+        addrs.into_iter().filter(|addr| self.allows(addr)).collect()
+    }
+
+    /// Same as `filter`, but also applies the hint of `with_remote_hint`, if enabled, based on
+    /// `remote`, which is the address of the remote as we see it.
+    pub fn filter_for_remote(&self, addrs: Vec<Multiaddr>, remote: &Multiaddr) -> Vec<Multiaddr> {
+        let remote_ip = match (self.remote_hint, first_ip(remote)) {
+            (true, Some(ip)) => ip,
+            _ => return self.filter(addrs),
+        };
+
+        let (lan_ok, public_only) = (ListenAddrFilter::lan_ok(), ListenAddrFilter::public_only());
+        let scope = |ip: &IpAddr| {
+            let addr = match *ip {
+                IpAddr::V4(ip) => Multiaddr::from(AddrComponent::IP4(ip)),
+                IpAddr::V6(ip) => Multiaddr::from(AddrComponent::IP6(ip)),
+            };
+            if !lan_ok.allows(&addr) {
+                Scope::Machine
+            } else if !public_only.allows(&addr) {
+                Scope::LocalNetwork
+            } else {
+                Scope::Internet
+            }
+        };
+
+        let remote_scope = scope(&remote_ip);
+        addrs
+            .into_iter()
+            .filter(|addr| self.allows(addr))
+            .filter(|addr| {
+                let ip = match first_ip(addr) {
+                    Some(ip) => ip,
+                    None => return true,
+                };
+                if remote_ip.is_ipv4() && ip.is_ipv6() {
+                    return false;
+                }
+                scope(&ip) >= remote_scope
+            })
+            .collect()
+    }
+}
+
+// How far an address can be reached from. Ordered from the most restricted to the least.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Scope {
+    Machine,
+    LocalNetwork,
+    Internet,
+}
+
+// Returns the first IP address contained in `addr`, if any.
+fn first_ip(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter()
+        .filter_map(|component| match component {
+            AddrComponent::IP4(ip) => Some(ip.into()),
+            AddrComponent::IP6(ip) => Some(ip.into()),
+            _ => None,
+        })
+        .next()
+}
+
+impl Default for ListenAddrFilter {
+    #[inline]
+    fn default() -> ListenAddrFilter {
+        ListenAddrFilter::everything()
+    }
+}
+
+// Blacklist of the addresses that can't be reached from another machine.
+fn local_only_ranges() -> Blacklist {
+    let ranges = [
+        IpRange::new(Ipv4Addr::new(127, 0, 0, 0).into(), 8),
+        IpRange::new(Ipv4Addr::new(0, 0, 0, 0).into(), 32),
+        IpRange::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1).into(), 128),
+        IpRange::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(), 128),
+    ];
+
+    ranges.iter().fold(Blacklist::new(), |blacklist, range| {
+        blacklist.deny_range(range.clone().expect("the prefix lengths above are valid"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ListenAddrFilter;
+    use multiaddr::Multiaddr;
+
+    fn addrs() -> Vec<Multiaddr> {
+        vec![
+            "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+            "/ip6/::1/tcp/4001".parse().unwrap(),
+            "/ip4/192.168.1.5/tcp/4001".parse().unwrap(),
+            "/ip4/169.254.2.3/tcp/4001".parse().unwrap(),
+            "/ip4/80.81.82.83/tcp/4001".parse().unwrap(),
+            "/dns4/example.com/tcp/4001".parse().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn presets() {
+        let all = addrs();
+
+        assert_eq!(ListenAddrFilter::everything().filter(all.clone()), all);
+        assert_eq!(
+            ListenAddrFilter::lan_ok().filter(all.clone()),
+            all[2..].to_vec()
+        );
+        assert_eq!(
+            ListenAddrFilter::public_only().filter(all.clone()),
+            all[4..].to_vec()
+        );
+    }
+
+    #[test]
+    fn remote_hint() {
+        let mut all = addrs();
+        all.push("/ip6/2001:db8::1/tcp/4001".parse().unwrap());
+        let filter = ListenAddrFilter::everything().with_remote_hint();
+
+        let remote = "/ip4/1.2.3.4/tcp/5000".parse().unwrap();
+        assert_eq!(
+            filter.filter_for_remote(all.clone(), &remote),
+            vec![all[4].clone(), all[5].clone()]
+        );
+
+        let remote = "/ip6/2001:db8::2/tcp/5000".parse().unwrap();
+        assert_eq!(
+            filter.filter_for_remote(all.clone(), &remote),
+            vec![all[4].clone(), all[5].clone(), all[6].clone()]
+        );
+
+        let remote = "/ip4/192.168.1.20/tcp/5000".parse().unwrap();
+        assert_eq!(filter.filter_for_remote(all.clone(), &remote), all[2..6].to_vec());
+
+        let remote = "/ip4/127.0.0.1/tcp/5000".parse().unwrap();
+        assert_eq!(
+            filter.filter_for_remote(all.clone(), &remote),
+            vec![all[0].clone(), all[2].clone(), all[3].clone(), all[4].clone(), all[5].clone()]
+        );
+
+        // Without the hint, the remote is ignored.
+        assert_eq!(ListenAddrFilter::everything().filter_for_remote(all.clone(), &remote), all);
+    }
+}
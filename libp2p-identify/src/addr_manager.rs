@@ -0,0 +1,410 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `ExternalAddrManager` struct, which keeps track of the addresses under which the
+//! local node might be reachable.
+//!
+//! Candidate external addresses come from several sources: the addresses observed by remotes
+//! through *identify*, the addresses set in the configuration of the node, and the port mappings
+//! made by a UPnP client. The manager merges the same address reported by several sources into
+//! one entry, and gives each entry a level of confidence:
+//!
+//! - The configured addresses are confirmed right away, as the operator knows best.
+//! - The observed addresses are confirmed once enough distinct peers report them, as decided by
+//!   an `ExternalAddrVoting`.
+//! - The other addresses, such as the UPnP mappings, stay candidates until `confirm` is called,
+//!   for example after a remote managed to dial them.
+//!
+//! Behind a NAT, the address observed by a remote usually has the port that the NAT picked for
+//! the outgoing connection, which can't be dialed. `report_observed_and_translate` additionally
+//! substitutes the observed IP address into our internal listen addresses, while keeping our
+//! listening port, and adds the results as candidates. This relies on
+//! `Transport::nat_traversal`.
+//!
+//! The confirmed addresses are the ones that should be advertised, for example in the
+//! `listen_addrs` of the `IdentifyInfo` that we send.
+
+use external_addr::{ExternalAddrEvent, ExternalAddrVoting};
+use libp2p_core::Transport;
+use libp2p_peerstore::PeerId;
+use multiaddr::{AddrComponent, Multiaddr};
+
+/// Level of confidence in an external address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AddrConfidence {
+    /// We might be reachable under this address, but this hasn't been verified.
+    Candidate,
+    /// We are reachable under this address.
+    Confirmed,
+}
+
+/// Source of an external address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AddrSource {
+    /// The address is part of the configuration of the node.
+    Config,
+    /// A UPnP client mapped a port of the router to us.
+    Upnp,
+    /// At least one remote observed us under this address.
+    Observed,
+    /// The address is one of our listen addresses, translated with an observed address.
+    Translated,
+}
+
+/// Tracks the candidate external addresses of the local node and how confident we are in them.
+#[derive(Debug, Clone)]
+pub struct ExternalAddrManager {
+    voting: ExternalAddrVoting,
+    // One entry per distinct address, in the order in which they were first reported.
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    addr: Multiaddr,
+    configured: bool,
+    upnp: bool,
+    translated: bool,
+    // True if `confirm` has been called for this address.
+    verified: bool,
+}
+
+impl ExternalAddrManager {
+    /// Builds a new manager. An observed address is confirmed once `observation_threshold`
+    /// distinct peers have reported it.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `observation_threshold` is 0.
+    #[inline]
+    pub fn new(observation_threshold: usize) -> ExternalAddrManager {
+        ExternalAddrManager {
+            voting: ExternalAddrVoting::new(observation_threshold),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Adds an address from the configuration of the node. Returns the changes in the set of
+    /// confirmed addresses.
+    pub fn add_configured(&mut self, addr: Multiaddr) -> Vec<ExternalAddrEvent> {
+        self.update(move |manager| manager.entry(addr).configured = true)
+    }
+
+    /// Removes an address previously added with `add_configured`.
+    pub fn remove_configured(&mut self, addr: &Multiaddr) -> Vec<ExternalAddrEvent> {
+        self.update(|manager| manager.modify(addr, |entry| entry.configured = false))
+    }
+
+    /// Adds an address that a UPnP client mapped to us.
+    pub fn add_upnp(&mut self, addr: Multiaddr) -> Vec<ExternalAddrEvent> {
+        self.update(move |manager| manager.entry(addr).upnp = true)
+    }
+
+    /// Removes an address previously added with `add_upnp`, for example because the mapping
+    /// expired.
+    pub fn remove_upnp(&mut self, addr: &Multiaddr) -> Vec<ExternalAddrEvent> {
+        self.update(|manager| manager.modify(addr, |entry| entry.upnp = false))
+    }
+
+    /// Records that `peer_id` observes us as `observed_addr`, replacing the previous observation
+    /// of this peer.
+    pub fn report_observed(
+        &mut self,
+        peer_id: PeerId,
+        observed_addr: Multiaddr,
+    ) -> Vec<ExternalAddrEvent> {
+        self.update(move |manager| {
+            manager.entry(observed_addr.clone());
+            manager.voting.report(peer_id, observed_addr);
+        })
+    }
+
+    /// Same as `report_observed`, but also translates the internal addresses among
+    /// `listen_addrs` by substituting the IP address of `observed_addr`, and adds the results as
+    /// candidates.
+    ///
+    /// The listen addresses that are already public are left alone, as are all of them if
+    /// `observed_addr` is itself an internal address.
+    pub fn report_observed_and_translate<T>(
+        &mut self,
+        peer_id: PeerId,
+        observed_addr: Multiaddr,
+        listen_addrs: &[Multiaddr],
+        transport: &T,
+    ) -> Vec<ExternalAddrEvent>
+    where
+        T: Transport,
+    {
+        let translated = if is_internal(&observed_addr) {
+            Vec::new()
+        } else {
+            listen_addrs
+                .iter()
+                .filter(|addr| is_internal(addr))
+                .filter_map(|addr| transport.nat_traversal(addr, &observed_addr))
+                .collect::<Vec<_>>()
+        };
+
+        self.update(move |manager| {
+            for addr in translated {
+                manager.entry(addr).translated = true;
+            }
+            manager.entry(observed_addr.clone());
+            manager.voting.report(peer_id, observed_addr);
+        })
+    }
+
+    /// Removes an address that has been added by `report_observed_and_translate`.
+    pub fn remove_translated(&mut self, addr: &Multiaddr) -> Vec<ExternalAddrEvent> {
+        self.update(|manager| manager.modify(addr, |entry| entry.translated = false))
+    }
+
+    /// Removes the observation of a peer, for example because we disconnected from it.
+    pub fn remove_peer(&mut self, peer_id: &PeerId) -> Vec<ExternalAddrEvent> {
+        self.update(|manager| {
+            manager.voting.remove_peer(peer_id);
+        })
+    }
+
+    /// Marks an address as confirmed, for example because a remote managed to dial us with it.
+    /// Does nothing if no source has reported this address.
+    pub fn confirm(&mut self, addr: &Multiaddr) -> Vec<ExternalAddrEvent> {
+        self.update(|manager| manager.modify(addr, |entry| entry.verified = true))
+    }
+
+    /// Returns the confirmed addresses, in the order in which they were first reported.
+    pub fn confirmed(&self) -> Vec<Multiaddr> {
+        self.entries
+            .iter()
+            .filter(|entry| self.is_confirmed(entry))
+            .map(|entry| entry.addr.clone())
+            .collect()
+    }
+
+    /// Returns the addresses that aren't confirmed yet.
+    pub fn candidates(&self) -> Vec<Multiaddr> {
+        self.entries
+            .iter()
+            .filter(|entry| !self.is_confirmed(entry))
+            .map(|entry| entry.addr.clone())
+            .collect()
+    }
+
+    /// Returns how confident we are in `addr`, or `None` if no source reported it.
+    pub fn confidence(&self, addr: &Multiaddr) -> Option<AddrConfidence> {
+        self.entries.iter().find(|entry| entry.addr == *addr).map(|entry| {
+            if self.is_confirmed(entry) {
+                AddrConfidence::Confirmed
+            } else {
+                AddrConfidence::Candidate
+            }
+        })
+    }
+
+    /// Returns the sources that currently report `addr`.
+    pub fn sources(&self, addr: &Multiaddr) -> Vec<AddrSource> {
+        let entry = match self.entries.iter().find(|entry| entry.addr == *addr) {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+
+        let mut sources = Vec::new();
+        if entry.configured {
+            sources.push(AddrSource::Config);
+        }
+        if entry.upnp {
+            sources.push(AddrSource::Upnp);
+        }
+        if self.voting.votes(addr) > 0 {
+            sources.push(AddrSource::Observed);
+        }
+        if entry.translated {
+            sources.push(AddrSource::Translated);
+        }
+        sources
+    }
+
+    fn is_confirmed(&self, entry: &Entry) -> bool {
+        entry.configured || entry.verified || self.voting.confirmed().contains(&entry.addr)
+    }
+
+    // Returns the entry of `addr`, creating it if necessary.
+    fn entry(&mut self, addr: Multiaddr) -> &mut Entry {
+        let existing = self.entries.iter().position(|entry| entry.addr == addr);
+        let position = match existing {
+            Some(position) => position,
+            None => {
+                self.entries.push(Entry {
+                    addr: addr,
+                    configured: false,
+                    upnp: false,
+                    translated: false,
+                    verified: false,
+                });
+                self.entries.len() - 1
+            }
+        };
+        &mut self.entries[position]
+    }
+
+    // Calls `f` on the entry of `addr`, if it exists.
+    fn modify<F>(&mut self, addr: &Multiaddr, f: F)
+    where
+        F: FnOnce(&mut Entry),
+    {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.addr == *addr) {
+            f(entry);
+        }
+    }
+
+    // Applies a modification, removes the entries that aren't reported by any source anymore,
+    // and returns the changes in the set of confirmed addresses.
+    fn update<F>(&mut self, f: F) -> Vec<ExternalAddrEvent>
+    where
+        F: FnOnce(&mut ExternalAddrManager),
+    {
+        let before = self.confirmed();
+        f(self);
+
+        let voting = &self.voting;
+        self.entries.retain(|entry| {
+            entry.configured || entry.upnp || entry.translated || voting.votes(&entry.addr) > 0
+        });
+
+        let after = self.confirmed();
+        let mut events = Vec::new();
+        for addr in before.iter().filter(|addr| !after.contains(addr)) {
+            events.push(ExternalAddrEvent::Unconfirmed(addr.clone()));
+        }
+        for addr in after.iter().filter(|addr| !before.contains(addr)) {
+            events.push(ExternalAddrEvent::Confirmed(addr.clone()));
+        }
+        events
+    }
+}
+
+// Returns true if the IP address of `addr` is unspecified, or can only be reached from the local
+// machine or network.
+fn is_internal(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(AddrComponent::IP4(ip)) => {
+            ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local()
+        }
+        Some(AddrComponent::IP6(ip)) => {
+            let first = ip.segments()[0];
+            // Unique local (fc00::/7) and link-local (fe80::/10) addresses.
+            ip.is_unspecified() || ip.is_loopback() || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate libp2p_tcp_transport;
+    extern crate tokio_core;
+
+    use {AddrConfidence, AddrSource, ExternalAddrEvent, ExternalAddrManager};
+    use libp2p_peerstore::PeerId;
+    use multiaddr::Multiaddr;
+    use self::libp2p_tcp_transport::TcpConfig;
+    use self::tokio_core::reactor::Core;
+
+    #[test]
+    fn sources_merged_and_confirmed() {
+        let addr: Multiaddr = "/ip4/80.81.82.83/tcp/500".parse().unwrap();
+        let configured: Multiaddr = "/dns4/example.com/tcp/500".parse().unwrap();
+        let peer1 = PeerId::from_public_key(&[1]);
+        let peer2 = PeerId::from_public_key(&[2]);
+
+        let mut manager = ExternalAddrManager::new(2);
+        assert_eq!(
+            manager.add_configured(configured.clone()),
+            vec![ExternalAddrEvent::Confirmed(configured.clone())]
+        );
+
+        // The UPnP mapping and the observation are the same address.
+        assert!(manager.add_upnp(addr.clone()).is_empty());
+        assert!(manager.report_observed(peer1.clone(), addr.clone()).is_empty());
+        assert_eq!(manager.sources(&addr), vec![AddrSource::Upnp, AddrSource::Observed]);
+        assert_eq!(manager.confidence(&addr), Some(AddrConfidence::Candidate));
+        assert_eq!(manager.candidates(), vec![addr.clone()]);
+
+        assert_eq!(
+            manager.report_observed(peer2.clone(), addr.clone()),
+            vec![ExternalAddrEvent::Confirmed(addr.clone())]
+        );
+        assert_eq!(manager.confirmed(), vec![configured.clone(), addr.clone()]);
+
+        // Once the mapping and the observations are gone, the address is forgotten.
+        assert_eq!(
+            manager.remove_peer(&peer1),
+            vec![ExternalAddrEvent::Unconfirmed(addr.clone())]
+        );
+        assert!(manager.remove_upnp(&addr).is_empty());
+        assert!(manager.remove_peer(&peer2).is_empty());
+        assert_eq!(manager.confidence(&addr), None);
+    }
+
+    #[test]
+    fn manual_confirmation() {
+        let addr: Multiaddr = "/ip4/80.81.82.83/tcp/500".parse().unwrap();
+
+        let mut manager = ExternalAddrManager::new(3);
+        assert!(manager.confirm(&addr).is_empty());
+        manager.add_upnp(addr.clone());
+        assert_eq!(manager.confirm(&addr), vec![ExternalAddrEvent::Confirmed(addr.clone())]);
+        assert_eq!(manager.confidence(&addr), Some(AddrConfidence::Confirmed));
+    }
+
+    #[test]
+    fn listen_addrs_translated() {
+        let core = Core::new().unwrap();
+        let transport = TcpConfig::new(core.handle());
+        let listen_addrs = vec![
+            "/ip4/0.0.0.0/tcp/4001".parse::<Multiaddr>().unwrap(),
+            "/ip4/192.168.1.5/tcp/4002".parse().unwrap(),
+            "/ip4/90.91.92.93/tcp/4003".parse().unwrap(),
+        ];
+        let observed: Multiaddr = "/ip4/80.81.82.83/tcp/31337".parse().unwrap();
+
+        let mut manager = ExternalAddrManager::new(2);
+        let peer = PeerId::from_public_key(&[1]);
+        let events = manager.report_observed_and_translate(
+            peer,
+            observed.clone(),
+            &listen_addrs,
+            &transport,
+        );
+        assert!(events.is_empty());
+
+        let translated1: Multiaddr = "/ip4/80.81.82.83/tcp/4001".parse().unwrap();
+        let translated2: Multiaddr = "/ip4/80.81.82.83/tcp/4002".parse().unwrap();
+        assert_eq!(manager.candidates(), vec![translated1.clone(), translated2, observed]);
+        assert_eq!(manager.sources(&translated1), vec![AddrSource::Translated]);
+
+        // An internal observed address isn't used for the translation.
+        let peer = PeerId::from_public_key(&[2]);
+        let internal: Multiaddr = "/ip4/10.0.0.1/tcp/1000".parse().unwrap();
+        manager.report_observed_and_translate(peer, internal, &listen_addrs, &transport);
+        assert_eq!(manager.candidates().len(), 4);
+    }
+}
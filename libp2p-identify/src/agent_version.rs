@@ -0,0 +1,84 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `AgentVersion` struct, which builds the `agent_version` field of `IdentifyInfo`.
+//!
+//! The agent version is free-form, but is most useful to operators when it follows the usual
+//! `name/version` convention, as it is then possible to tell which nodes of a fleet run which
+//! release.
+
+use std::fmt;
+
+/// Version of this implementation, included in the agent versions built by `AgentVersion`.
+const LIBP2P_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+/// Agent version of a node, in the `name/version` form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentVersion(String);
+
+impl AgentVersion {
+    /// Builds an agent version for an application, followed by the version of *rust-libp2p*.
+    /// For example `my-app/1.2.0 rust-libp2p/0.1.0`.
+    pub fn default_with(app_name: &str, app_version: &str) -> AgentVersion {
+        AgentVersion(format!("{}/{} rust-libp2p/{}", app_name, app_version, LIBP2P_VERSION))
+    }
+
+    /// Returns the agent version as a string.
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for AgentVersion {
+    /// Only contains the version of *rust-libp2p*, for example `rust-libp2p/0.1.0`. This is sent
+    /// when the `agent_version` of an `IdentifyInfo` is left empty.
+    #[inline]
+    fn default() -> AgentVersion {
+        AgentVersion(format!("rust-libp2p/{}", LIBP2P_VERSION))
+    }
+}
+
+impl fmt::Display for AgentVersion {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<AgentVersion> for String {
+    #[inline]
+    fn from(version: AgentVersion) -> String {
+        version.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use AgentVersion;
+
+    #[test]
+    fn format() {
+        let version = AgentVersion::default_with("my-app", "1.2.0");
+        let expected = format!("my-app/1.2.0 rust-libp2p/{}", env!("CARGO_PKG_VERSION"));
+        assert_eq!(version.as_str(), expected);
+        assert!(AgentVersion::default().as_str().starts_with("rust-libp2p/"));
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `IdentifyCache` struct, which remembers the information sent by remotes.
+//!
+//! Opening an *identify* substream every time we need to know the protocols or the listen
+//! addresses of a remote is wasteful. Instead, the results of the protocol can be stored in an
+//! `IdentifyCache`, and are then available until their time-to-live expires.
+//!
+//! Optionally, entries can be considered stale before they expire. Stale entries are still
+//! returned, but looking them up triggers a re-identification in the background, similar to the
+//! `stale-while-revalidate` directive of HTTP caching. This keeps queries fast while refreshing
+//! the information of the peers that are actually used.
+
+use libp2p_peerstore::PeerId;
+use multiaddr::Multiaddr;
+use parking_lot::Mutex;
+use protocol::IdentifyInfo;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Stores the last `IdentifyInfo` received from each peer, for a limited time.
+///
+/// Cloning an `IdentifyCache` gives access to the same entries.
+#[derive(Clone)]
+pub struct IdentifyCache {
+    ttl: Duration,
+    stale_after: Option<Duration>,
+    revalidate: Option<Arc<Fn(&PeerId, &IdentifyInfo) + Send + Sync>>,
+    entries: Arc<Mutex<HashMap<PeerId, CacheEntry>>>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    info: IdentifyInfo,
+    observed_addr: Multiaddr,
+    stale: Option<Instant>,
+    expires: Instant,
+    // True if the revalidation has been triggered and we are waiting for a new `insert`.
+    revalidating: bool,
+}
+
+impl IdentifyCache {
+    /// Builds an empty cache whose entries expire `ttl` after they have been inserted.
+    #[inline]
+    pub fn new(ttl: Duration) -> IdentifyCache {
+        IdentifyCache {
+            ttl: ttl,
+            stale_after: None,
+            revalidate: None,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Considers entries stale once `stale_after` has elapsed since they have been inserted.
+    ///
+    /// Stale entries are still returned by `get` until their time-to-live expires, but the first
+    /// lookup of a stale entry calls the function passed to `with_revalidate`, which is expected
+    /// to identify the peer again and `insert` the result. Has no effect if `stale_after` is
+    /// larger than the time-to-live.
+    #[inline]
+    pub fn with_stale_after(mut self, stale_after: Duration) -> IdentifyCache {
+        self.stale_after = Some(stale_after);
+        self
+    }
+
+    /// Sets the function that is called when a stale entry is looked up. It receives the peer and
+    /// the information we have about it, for example to dial one of its listen addresses.
+    ///
+    /// The function is called at most once per inserted entry, and must not block.
+    #[inline]
+    pub fn with_revalidate<F>(mut self, revalidate: F) -> IdentifyCache
+    where
+        F: Fn(&PeerId, &IdentifyInfo) + Send + Sync + 'static,
+    {
+        self.revalidate = Some(Arc::new(revalidate));
+        self
+    }
+
+    /// Stores the information received from a remote, alongside with the address the remote
+    /// observes for us. Replaces the previous entry of the same peer, if any.
+    ///
+    /// The peer is determined from the public key contained in `info`, and is returned.
+    pub fn insert(&self, info: IdentifyInfo, observed_addr: Multiaddr) -> PeerId {
+        let peer_id = info.public_key.to_peer_id();
+        let now = Instant::now();
+        let entry = CacheEntry {
+            info: info,
+            observed_addr: observed_addr,
+            stale: self.stale_after.map(|stale_after| now + stale_after),
+            expires: now + self.ttl,
+            revalidating: false,
+        };
+        self.entries.lock().insert(peer_id.clone(), entry);
+        peer_id
+    }
+
+    /// Returns the information of a peer and the address it observes for us, if we have an entry
+    /// that hasn't expired yet.
+    ///
+    /// If the entry is stale, it is still returned and the revalidation is triggered.
+    pub fn get(&self, peer_id: &PeerId) -> Option<(IdentifyInfo, Multiaddr)> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        let (result, revalidate) = match entries.get_mut(peer_id) {
+            Some(entry) => {
+                if entry.expires <= now {
+                    (None, false)
+                } else {
+                    let revalidate = match entry.stale {
+                        Some(stale) => stale <= now && !entry.revalidating,
+                        None => false,
+                    };
+                    if revalidate {
+                        entry.revalidating = true;
+                    }
+                    let result = (entry.info.clone(), entry.observed_addr.clone());
+                    (Some(result), revalidate)
+                }
+            }
+            None => return None,
+        };
+
+        match result {
+            Some(result) => {
+                // The lock is released before calling the user's function, in case it accesses
+                // the cache.
+                drop(entries);
+                if revalidate {
+                    if let Some(ref revalidate) = self.revalidate {
+                        trace!(target: "libp2p-identify", "Revalidating stale entry of {:?}",
+                               peer_id);
+                        revalidate(peer_id, &result.0);
+                    }
+                }
+                Some(result)
+            }
+            None => {
+                entries.remove(peer_id);
+                None
+            }
+        }
+    }
+
+    /// Returns true if we have an entry for this peer that is stale but hasn't expired yet.
+    pub fn is_stale(&self, peer_id: &PeerId) -> bool {
+        let now = Instant::now();
+        match self.entries.lock().get(peer_id) {
+            Some(entry) => entry.expires > now && entry.stale.map_or(false, |stale| stale <= now),
+            None => false,
+        }
+    }
+
+    /// Removes the entry of a peer, for example because we know that its information changed.
+    #[inline]
+    pub fn remove(&self, peer_id: &PeerId) {
+        self.entries.lock().remove(peer_id);
+    }
+
+    /// Removes all the entries that have expired.
+    ///
+    /// Expired entries are never returned, but they are only removed when queried. Call this
+    /// method regularly if you insert a lot of different peers.
+    pub fn purge_expired(&self) {
+        let now = Instant::now();
+        self.entries.lock().retain(|_, entry| entry.expires > now);
+    }
+}
+
+impl fmt::Debug for IdentifyCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IdentifyCache")
+            .field("ttl", &self.ttl)
+            .field("stale_after", &self.stale_after)
+            .field("entries", &self.entries)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {IdentifyCache, IdentifyInfo, PublicKey};
+    use multiaddr::Multiaddr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn info(public_key: Vec<u8>) -> IdentifyInfo {
+        IdentifyInfo {
+            public_key: PublicKey::Rsa(public_key),
+            protocol_version: "proto_version".to_owned(),
+            agent_version: "agent_version".to_owned(),
+            listen_addrs: vec![],
+            protocols: vec!["proto1".to_owned()],
+            signed_record: None,
+            metadata: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn insert_and_expire() {
+        let observed: Multiaddr = "/ip4/1.2.3.4/tcp/5".parse().unwrap();
+
+        let cache = IdentifyCache::new(Duration::from_secs(3600));
+        let peer_id = cache.insert(info(vec![1, 2, 3]), observed.clone());
+        assert_eq!(peer_id, PublicKey::Rsa(vec![1, 2, 3]).to_peer_id());
+        let (cached, cached_addr) = cache.get(&peer_id).unwrap();
+        assert_eq!(cached.protocols, vec!["proto1".to_owned()]);
+        assert_eq!(cached_addr, observed);
+        cache.remove(&peer_id);
+        assert!(cache.get(&peer_id).is_none());
+
+        let cache = IdentifyCache::new(Duration::from_secs(0));
+        let peer_id = cache.insert(info(vec![1, 2, 3]), observed);
+        assert!(cache.get(&peer_id).is_none());
+    }
+
+    #[test]
+    fn stale_while_revalidate() {
+        let observed: Multiaddr = "/ip4/1.2.3.4/tcp/5".parse().unwrap();
+        let revalidations = Arc::new(AtomicUsize::new(0));
+
+        let cache = {
+            let revalidations = revalidations.clone();
+            IdentifyCache::new(Duration::from_secs(3600))
+                .with_stale_after(Duration::from_secs(0))
+                .with_revalidate(move |_, info| {
+                    assert_eq!(info.protocols, vec!["proto1".to_owned()]);
+                    revalidations.fetch_add(1, Ordering::SeqCst);
+                })
+        };
+
+        let peer_id = cache.insert(info(vec![1, 2, 3]), observed.clone());
+        assert!(cache.is_stale(&peer_id));
+        assert!(cache.get(&peer_id).is_some());
+        assert!(cache.get(&peer_id).is_some());
+        assert_eq!(revalidations.load(Ordering::SeqCst), 1);
+
+        // Inserting the new information allows the next revalidation.
+        cache.insert(info(vec![1, 2, 3]), observed);
+        assert!(cache.get(&peer_id).is_some());
+        assert_eq!(revalidations.load(Ordering::SeqCst), 2);
+    }
+}
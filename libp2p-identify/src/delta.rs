@@ -0,0 +1,366 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Implementation of the *identify/delta* protocol.
+//!
+//! Instead of sending the whole `IdentifyInfo` again whenever something changes, a node can send
+//! an `IdentifyDelta` that only contains the protocols and listen addresses that were added or
+//! removed. The dialer sends the delta, and the listener applies it on the information it already
+//! knows about the dialer.
+//!
+//! The message is the one used by other implementations, which wrap the delta inside of an
+//! `Identify` message (field 7) containing only the added (field 1) and removed (field 2)
+//! protocols. The added and removed listen addresses are sent as fields 3 and 4 of the delta,
+//! which other implementations ignore.
+
+use bytes::{Bytes, BytesMut};
+use futures::{future, Future, Sink, Stream};
+use libp2p_core::{ConnectionUpgrade, Endpoint, LocalIdentity};
+use multiaddr::Multiaddr;
+use protobuf::{CodedInputStream, CodedOutputStream, ProtobufError, UnknownFields};
+use protobuf::repeated::RepeatedField;
+use protobuf::rt;
+use protocol::{convert_codec_error, IdentifyInfo, DEFAULT_MAX_FRAME_SIZE};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::iter;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use varint::VarintCodec;
+
+/// Changes in the information of a node.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdentifyDelta {
+    /// Protocols that are now supported.
+    pub added_protocols: Vec<String>,
+    /// Protocols that are no longer supported.
+    pub removed_protocols: Vec<String>,
+    /// Addresses that the node is now listening on.
+    pub added_listen_addrs: Vec<Multiaddr>,
+    /// Addresses that the node is no longer listening on.
+    pub removed_listen_addrs: Vec<Multiaddr>,
+}
+
+impl IdentifyDelta {
+    /// Computes the delta that turns `old` into `new`.
+    ///
+    /// Only the protocols and the listen addresses are compared.
+    pub fn between(old: &IdentifyInfo, new: &IdentifyInfo) -> IdentifyDelta {
+        fn diff<T: Clone + PartialEq>(from: &[T], to: &[T]) -> Vec<T> {
+            to.iter().filter(|v| !from.contains(v)).cloned().collect()
+        }
+
+        IdentifyDelta {
+            added_protocols: diff(&old.protocols, &new.protocols),
+            removed_protocols: diff(&new.protocols, &old.protocols),
+            added_listen_addrs: diff(&old.listen_addrs, &new.listen_addrs),
+            removed_listen_addrs: diff(&new.listen_addrs, &old.listen_addrs),
+        }
+    }
+
+    /// Returns true if the delta doesn't contain any change.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.added_protocols.is_empty() && self.removed_protocols.is_empty()
+            && self.added_listen_addrs.is_empty()
+            && self.removed_listen_addrs.is_empty()
+    }
+
+    /// Applies the delta on `info`.
+    pub fn apply(&self, info: &mut IdentifyInfo) {
+        info.protocols.retain(|p| !self.removed_protocols.contains(p));
+        for proto in &self.added_protocols {
+            if !info.protocols.contains(proto) {
+                info.protocols.push(proto.clone());
+            }
+        }
+
+        info.listen_addrs.retain(|a| !self.removed_listen_addrs.contains(a));
+        for addr in &self.added_listen_addrs {
+            if !info.listen_addrs.contains(addr) {
+                info.listen_addrs.push(addr.clone());
+            }
+        }
+    }
+
+    // Encodes the delta, wrapped in an `Identify` message.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut delta = Vec::new();
+        {
+            let mut os = CodedOutputStream::vec(&mut delta);
+            for proto in &self.added_protocols {
+                os.write_string(1, proto).expect("writing to a Vec never fails");
+            }
+            for proto in &self.removed_protocols {
+                os.write_string(2, proto).expect("writing to a Vec never fails");
+            }
+            for addr in &self.added_listen_addrs {
+                os.write_bytes(3, &addr.to_bytes()).expect("writing to a Vec never fails");
+            }
+            for addr in &self.removed_listen_addrs {
+                os.write_bytes(4, &addr.to_bytes()).expect("writing to a Vec never fails");
+            }
+            os.flush().expect("writing to a Vec never fails");
+        }
+
+        let mut message = Vec::new();
+        {
+            let mut os = CodedOutputStream::vec(&mut message);
+            os.write_bytes(7, &delta).expect("writing to a Vec never fails");
+            os.flush().expect("writing to a Vec never fails");
+        }
+        message
+    }
+
+    // Decodes an `Identify` message containing a delta. Fields other than the delta are ignored.
+    fn from_bytes(bytes: &[u8]) -> Result<IdentifyDelta, ProtobufError> {
+        let mut delta_bytes = None;
+        {
+            let mut is = CodedInputStream::from_bytes(bytes);
+            let mut unknown = UnknownFields::new();
+            while !is.eof()? {
+                let (field_number, wire_type) = is.read_tag_unpack()?;
+                match field_number {
+                    7 => {
+                        let mut field = Default::default();
+                        rt::read_singular_bytes_into(wire_type, &mut is, &mut field)?;
+                        delta_bytes = field.into_option();
+                    }
+                    _ => rt::read_unknown_or_skip_group(field_number, wire_type, &mut is,
+                                                        &mut unknown)?,
+                }
+            }
+        }
+
+        let delta_bytes = delta_bytes.unwrap_or_default();
+        let mut is = CodedInputStream::from_bytes(&delta_bytes);
+        let mut unknown = UnknownFields::new();
+        let mut added_protocols = RepeatedField::new();
+        let mut removed_protocols = RepeatedField::new();
+        let mut added_listen_addrs = RepeatedField::new();
+        let mut removed_listen_addrs = RepeatedField::new();
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => rt::read_repeated_string_into(wire_type, &mut is, &mut added_protocols)?,
+                2 => rt::read_repeated_string_into(wire_type, &mut is, &mut removed_protocols)?,
+                3 => rt::read_repeated_bytes_into(wire_type, &mut is, &mut added_listen_addrs)?,
+                4 => rt::read_repeated_bytes_into(wire_type, &mut is, &mut removed_listen_addrs)?,
+                _ => rt::read_unknown_or_skip_group(field_number, wire_type, &mut is,
+                                                    &mut unknown)?,
+            }
+        }
+
+        Ok(IdentifyDelta {
+            added_protocols: added_protocols.into_vec(),
+            removed_protocols: removed_protocols.into_vec(),
+            added_listen_addrs: bytes_to_multiaddrs(added_listen_addrs.into_vec())?,
+            removed_listen_addrs: bytes_to_multiaddrs(removed_listen_addrs.into_vec())?,
+        })
+    }
+}
+
+// Turns a list of raw multiaddresses into `Multiaddr`s.
+fn bytes_to_multiaddrs(list: Vec<Vec<u8>>) -> Result<Vec<Multiaddr>, ProtobufError> {
+    list.into_iter()
+        .map(|bytes| {
+            Multiaddr::from_bytes(bytes)
+                .map_err(|err| ProtobufError::IoError(IoError::new(IoErrorKind::InvalidData, err)))
+        })
+        .collect()
+}
+
+/// Configuration for an upgrade to the *identify/delta* protocol.
+#[derive(Debug, Clone)]
+pub struct IdentifyDeltaProtocolConfig;
+
+/// Output of the *identify/delta* connection upgrade.
+pub enum IdentifyDeltaOutput<T> {
+    /// We opened the substream and need to send a delta. Happens when we are the dialer.
+    Sender {
+        /// Object used to send the delta to the remote.
+        sender: IdentifyDeltaSender<T>,
+    },
+
+    /// The remote sent us a delta. Happens when we are the listener.
+    RemoteDelta {
+        /// The changes in the information of the remote.
+        delta: IdentifyDelta,
+    },
+}
+
+/// Object used to send a delta to the remote.
+pub struct IdentifyDeltaSender<T> {
+    inner: Framed<T, VarintCodec<Vec<u8>>>,
+}
+
+impl<'a, T> IdentifyDeltaSender<T>
+where
+    T: AsyncWrite + 'a,
+{
+    /// Sends the delta to the remote. Returns a future that is signalled whenever the delta has
+    /// been sent.
+    pub fn send(self, delta: IdentifyDelta) -> Box<Future<Item = (), Error = IoError> + 'a> {
+        trace!(target: "libp2p-identify", "Sending identify delta: {:?}", delta);
+        let future = self.inner.send(delta.to_bytes()).map(|_| ());
+        Box::new(future) as Box<_>
+    }
+}
+
+impl<C> ConnectionUpgrade<C> for IdentifyDeltaProtocolConfig
+where
+    C: AsyncRead + AsyncWrite + 'static,
+{
+    type NamesIter = iter::Once<(Bytes, Self::UpgradeIdentifier)>;
+    type UpgradeIdentifier = ();
+    type Output = IdentifyDeltaOutput<C>;
+    type Future = Box<Future<Item = Self::Output, Error = IoError>>;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        iter::once((Bytes::from("/p2p/id/delta/1.0.0"), ()))
+    }
+
+    fn upgrade(
+        self,
+        socket: C,
+        _: (),
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        _: &LocalIdentity,
+    ) -> Self::Future {
+        trace!(target: "libp2p-identify", "Upgrading delta connection with {:?} as {:?}",
+               remote_addr, ty);
+
+        let socket = socket.framed(VarintCodec::with_max_len(DEFAULT_MAX_FRAME_SIZE));
+
+        match ty {
+            Endpoint::Dialer => {
+                let sender = IdentifyDeltaSender { inner: socket };
+                Box::new(future::ok(IdentifyDeltaOutput::Sender { sender })) as Box<_>
+            }
+
+            Endpoint::Listener => {
+                let future = socket
+                    .into_future()
+                    .map(|(msg, _)| msg)
+                    .map_err(|(err, _)| convert_codec_error(err))
+                    .and_then(|msg: Option<BytesMut>| {
+                        let msg = match msg {
+                            Some(msg) => msg,
+                            None => {
+                                debug!(target: "libp2p-identify", "Identify delta stream closed \
+                                                                   before receiving the delta");
+                                return Err(IoErrorKind::InvalidData.into());
+                            }
+                        };
+
+                        let delta = IdentifyDelta::from_bytes(&msg)
+                            .map_err(|err| IoError::new(IoErrorKind::InvalidData, err))?;
+                        trace!(target: "libp2p-identify", "Received identify delta: {:?}", delta);
+                        Ok(IdentifyDeltaOutput::RemoteDelta { delta })
+                    });
+
+                Box::new(future) as Box<_>
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate libp2p_tcp_transport;
+    extern crate tokio_core;
+
+    use self::libp2p_tcp_transport::TcpConfig;
+    use self::tokio_core::reactor::Core;
+    use {IdentifyDelta, IdentifyDeltaOutput, IdentifyDeltaProtocolConfig, IdentifyInfo};
+    use PublicKey;
+    use futures::{Future, Stream};
+    use libp2p_core::Transport;
+
+    fn info(protocols: &[&str], addrs: &[&str]) -> IdentifyInfo {
+        IdentifyInfo {
+            public_key: PublicKey::Rsa(vec![1, 2, 3]),
+            protocol_version: "proto_version".to_owned(),
+            agent_version: "agent_version".to_owned(),
+            listen_addrs: addrs.iter().map(|a| a.parse().unwrap()).collect(),
+            protocols: protocols.iter().map(|p| p.to_string()).collect(),
+            signed_record: None,
+            metadata: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn between_and_apply() {
+        let old = info(&["/a", "/b"], &["/ip4/1.2.3.4/tcp/1"]);
+        let new = info(&["/b", "/c"], &["/ip4/1.2.3.4/tcp/1", "/ip4/5.6.7.8/tcp/2"]);
+
+        let delta = IdentifyDelta::between(&old, &new);
+        assert_eq!(delta.added_protocols, vec!["/c".to_string()]);
+        assert_eq!(delta.removed_protocols, vec!["/a".to_string()]);
+        assert_eq!(delta.added_listen_addrs, vec!["/ip4/5.6.7.8/tcp/2".parse().unwrap()]);
+        assert!(delta.removed_listen_addrs.is_empty());
+
+        let mut applied = old.clone();
+        delta.apply(&mut applied);
+        assert_eq!(applied.protocols, new.protocols);
+        assert_eq!(applied.listen_addrs, new.listen_addrs);
+
+        assert!(IdentifyDelta::between(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn delta_transfer() {
+        let mut core = Core::new().unwrap();
+        let transport = TcpConfig::new(core.handle()).with_upgrade(IdentifyDeltaProtocolConfig);
+
+        let delta = IdentifyDelta {
+            added_protocols: vec!["/c".to_string()],
+            removed_protocols: vec!["/a".to_string()],
+            added_listen_addrs: vec![],
+            removed_listen_addrs: vec!["/ip6/::1/udp/1000".parse().unwrap()],
+        };
+
+        let (listener, addr) = transport
+            .clone()
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap_or_else(|_| panic!());
+
+        let expected = delta.clone();
+        let server = listener
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(|(client, _)| client.unwrap().map(|v| v.0))
+            .map(move |output| match output {
+                IdentifyDeltaOutput::RemoteDelta { delta } => assert_eq!(delta, expected),
+                _ => panic!(),
+            });
+
+        let client = transport
+            .dial(addr)
+            .unwrap_or_else(|_| panic!())
+            .and_then(move |(output, _)| match output {
+                IdentifyDeltaOutput::Sender { sender } => sender.send(delta),
+                _ => panic!(),
+            });
+
+        core.run(server.join(client)).unwrap();
+    }
+}
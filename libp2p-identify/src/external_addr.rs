@@ -0,0 +1,163 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `ExternalAddrVoting` struct, which determines our external address from what
+//! remotes observe.
+//!
+//! When running the *identify* protocol, the remote tells us the address it sees for us. A single
+//! remote can't be trusted, as it could be lying or located on the same local network as us.
+//! Instead, `ExternalAddrVoting` counts how many distinct peers report each address, and
+//! considers an address as confirmed once a configurable number of peers agree on it.
+
+use libp2p_peerstore::PeerId;
+use multiaddr::Multiaddr;
+use std::collections::HashMap;
+
+/// Aggregates the addresses observed by remotes, and confirms the ones that enough peers agree on.
+///
+/// Each peer has one vote, which is the last address it reported.
+#[derive(Debug, Clone)]
+pub struct ExternalAddrVoting {
+    // Number of distinct peers that must report an address for it to be confirmed.
+    threshold: usize,
+    // Last address reported by each peer.
+    votes: HashMap<PeerId, Multiaddr>,
+    // Addresses that are currently confirmed.
+    confirmed: Vec<Multiaddr>,
+}
+
+/// Event produced by `ExternalAddrVoting`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExternalAddrEvent {
+    /// Enough peers agree on this address, which is now considered as our external address.
+    Confirmed(Multiaddr),
+    /// Not enough peers report this previously-confirmed address anymore.
+    Unconfirmed(Multiaddr),
+}
+
+impl ExternalAddrVoting {
+    /// Builds a new `ExternalAddrVoting` that confirms an address once `threshold` distinct peers
+    /// have reported it.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `threshold` is 0.
+    #[inline]
+    pub fn new(threshold: usize) -> ExternalAddrVoting {
+        assert_ne!(threshold, 0, "the threshold of ExternalAddrVoting can't be 0");
+        ExternalAddrVoting {
+            threshold: threshold,
+            votes: HashMap::new(),
+            confirmed: Vec::new(),
+        }
+    }
+
+    /// Records that `peer_id` observes us as `observed_addr`, replacing the previous vote of this
+    /// peer. Returns the changes in the set of confirmed addresses.
+    pub fn report(&mut self, peer_id: PeerId, observed_addr: Multiaddr) -> Vec<ExternalAddrEvent> {
+        let previous = self.votes.insert(peer_id, observed_addr.clone());
+        let mut events = Vec::new();
+
+        if let Some(previous) = previous {
+            if previous == observed_addr {
+                return events;
+            }
+            events.extend(self.refresh(previous));
+        }
+
+        events.extend(self.refresh(observed_addr));
+        events
+    }
+
+    /// Removes the vote of a peer, for example because we disconnected from it. Returns the
+    /// changes in the set of confirmed addresses.
+    pub fn remove_peer(&mut self, peer_id: &PeerId) -> Vec<ExternalAddrEvent> {
+        match self.votes.remove(peer_id) {
+            Some(addr) => self.refresh(addr).into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the addresses that are currently confirmed, in the order in which they were
+    /// confirmed.
+    #[inline]
+    pub fn confirmed(&self) -> &[Multiaddr] {
+        &self.confirmed
+    }
+
+    /// Returns the number of distinct peers that currently report `addr`.
+    pub fn votes(&self, addr: &Multiaddr) -> usize {
+        self.votes.values().filter(|a| *a == addr).count()
+    }
+
+    // Updates whether `addr` is confirmed after its number of votes changed.
+    fn refresh(&mut self, addr: Multiaddr) -> Option<ExternalAddrEvent> {
+        let enough_votes = self.votes(&addr) >= self.threshold;
+        let position = self.confirmed.iter().position(|a| *a == addr);
+
+        match (enough_votes, position) {
+            (true, None) => {
+                self.confirmed.push(addr.clone());
+                Some(ExternalAddrEvent::Confirmed(addr))
+            }
+            (false, Some(position)) => {
+                self.confirmed.remove(position);
+                Some(ExternalAddrEvent::Unconfirmed(addr))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {ExternalAddrEvent, ExternalAddrVoting};
+    use libp2p_peerstore::PeerId;
+    use multiaddr::Multiaddr;
+
+    #[test]
+    fn confirmed_by_distinct_peers() {
+        let addr: Multiaddr = "/ip4/80.81.82.83/tcp/500".parse().unwrap();
+        let other: Multiaddr = "/ip4/10.0.0.1/tcp/500".parse().unwrap();
+        let peer1 = PeerId::from_public_key(&[1]);
+        let peer2 = PeerId::from_public_key(&[2]);
+
+        let mut voting = ExternalAddrVoting::new(2);
+        assert!(voting.report(peer1.clone(), addr.clone()).is_empty());
+        // The same peer reporting twice only counts once.
+        assert!(voting.report(peer1.clone(), addr.clone()).is_empty());
+        assert!(voting.confirmed().is_empty());
+
+        assert_eq!(
+            voting.report(peer2.clone(), addr.clone()),
+            vec![ExternalAddrEvent::Confirmed(addr.clone())]
+        );
+        assert_eq!(voting.confirmed(), &[addr.clone()]);
+
+        assert_eq!(
+            voting.report(peer2.clone(), other.clone()),
+            vec![ExternalAddrEvent::Unconfirmed(addr.clone())]
+        );
+        assert!(voting.confirmed().is_empty());
+
+        assert!(voting.remove_peer(&peer1).is_empty());
+        assert_eq!(voting.votes(&other), 1);
+    }
+}
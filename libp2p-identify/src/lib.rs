@@ -38,10 +38,11 @@
 //!
 //! If you dial a multiaddr of the form `/p2p/...`, then the `IdentifyTransport` will look into
 //! the `Peerstore` for any known multiaddress for this peer and try to dial them using the
-//! underlying transport. If you dial any other multiaddr, then it will dial this multiaddr using
-//! the underlying transport, then negotiate the *identify* protocol with the remote in order to
-//! obtain its ID, then add it to the peerstore, and finally dial the same multiaddr again and
-//! return the connection.
+//! underlying transport. Several of them are dialed at the same time (see
+//! `with_dial_concurrency`), and the first connection that succeeds is kept. If you dial any
+//! other multiaddr, then it will dial this multiaddr using the underlying transport, then
+//! negotiate the *identify* protocol with the remote in order to obtain its ID, then add it to
+//! the peerstore, and finally dial the same multiaddr again and return the connection.
 //!
 //! Listening doesn't support multiaddresses of the form `/p2p/...` (because that wouldn't make
 //! sense). Any address passed to `listen_on` will be passed directly to the underlying transport.
@@ -52,6 +53,14 @@
 //! only returns the connection. From the exterior, the multiaddress of the remote is of the form
 //! `/p2p/...`. If the remote doesn't support the *identify* protocol, then the socket is closed.
 //!
+//! Each time a remote is identified, its public key and the list of protocols it supports are
+//! written to the peerstore, along with the address we reached it at and the addresses it
+//! listens on.
+//!
+//! With `with_protocol_changes`, a `ProtocolChangeRouter` is notified whenever a remote starts
+//! or stops supporting a protocol compared to what the peerstore knew, so that routing layers
+//! can add or remove the peer right away.
+//!
 //! Because of the behaviour of `IdentifyProtocol`, it is recommended to build it on top of a
 //! `ConnectionReuse`.
 //!
@@ -60,25 +69,135 @@
 //! The `IdentifyProtocolConfig` struct implements the `ConnectionUpgrade` trait. Using it will
 //! negotiate the *identify* protocol.
 //!
+//! By default the protocol is negotiated under the name `/ipfs/id/1.0.0`. Use
+//! `with_protocol_name` and `add_protocol_name` to use other names, for example for a network that
+//! runs its own flavour of the protocol.
+//!
 //! The output of the upgrade is a `IdentifyOutput`. If we are the dialer, then `IdentifyOutput`
 //! will contain the information sent by the remote. If we are the listener, then it will contain
 //! a `IdentifySender` struct that can be used to transmit back to the remote the information about
 //! it.
+//!
+//! Alternatively, the `IdentifyService` struct is built once with a closure that provides our
+//! information. It answers the queries of remotes automatically, and its `query` method dials a
+//! remote and produces the information it sends.
+//! `with_throttle` bounds the number of queries it answers for each remote, and
+//! `with_response_cache` avoids encoding the same information again for every query.
+//!
+//! `AgentVersion::default_with` builds an agent version that contains the name and version of
+//! the application and of *rust-libp2p*. If `agent_version` is left empty, the version of
+//! *rust-libp2p* alone is sent.
+//!
+//! The public key of the remote is a `PublicKey`, which is sent in the protobuf encoding used by
+//! other implementations and from which the `PeerId` of the remote can be derived.
+//!
+//! If the address we dial ends with `/p2p/...`, then the dialer checks that the public key sent
+//! by the remote matches this peer ID. On a mismatch the upgrade fails with an `IoError` that
+//! wraps an `IdentifyError`.
+//!
+//! The listen addresses sent by `IdentifySender` go through a `ListenAddrFilter`, configured with
+//! `IdentifyProtocolConfig::with_listen_addr_filter`. Presets are available to send only the
+//! addresses reachable from the Internet, the ones reachable from the local network, or all of
+//! them, which is the default. With `ListenAddrFilter::with_remote_hint`, the address of the
+//! remote is also taken into account, so that for example a remote connected over IPv4 doesn't
+//! receive our IPv6 addresses.
+//!
+//! Nodes that don't want to disclose where they can be reached can use
+//! `IdentifyProtocolConfig::with_privacy` to send only their agent and protocol information.
+//!
+//! ## Signed peer records
+//!
+//! `IdentifyInfo::signed_record` can carry a `SignedPeerRecord`, which is a list of listen
+//! addresses signed with the identity key of the node. Contrary to `listen_addrs`, a verified
+//! record can't have been forged by a third party. The signature scheme is provided by the user
+//! through the `RecordSigner` and `RecordVerifier` traits.
+//!
+//! ## Metadata
+//!
+//! Applications can attach their own values to the identify message, for example the genesis
+//! hash of a chain, by implementing the `IdentifyMetadata` trait. The values are set with
+//! `IdentifyInfo::set_metadata` before sending, and read back by the remote with
+//! `IdentifyInfo::metadata`.
+//!
+//! ## Pushing information
+//!
+//! The `IdentifyPushProtocolConfig` struct implements the `/ipfs/id/push/1.0.0` protocol, where
+//! the roles are reversed: the dialer sends its own information to the listener. This is meant to
+//! be used after our listen addresses or supported protocols have changed. Like
+//! `IdentifyProtocolConfig`, it parses the pushed messages leniently and limits their size,
+//! which can be changed with `with_parsing` and `with_max_frame_size`.
+//!
+//! ## Sending changes
+//!
+//! The `IdentifyDeltaProtocolConfig` struct implements the `/p2p/id/delta/1.0.0` protocol, which
+//! is similar to the push protocol but only sends the protocols and addresses that were added or
+//! removed, in the form of an `IdentifyDelta`.
+//!
+//! ## Caching
+//!
+//! The `IdentifyCache` struct stores the information received from each peer for a configurable
+//! time-to-live, so that it can be looked up without opening a new substream.
+//! With `with_stale_after`, entries that are old but not expired are still returned while a
+//! function set with `with_revalidate` is asked to identify the peer again.
+//!
+//! ## External address
+//!
+//! The `ExternalAddrVoting` struct collects the addresses that remotes observe for us, and
+//! confirms an address as our external address once enough distinct peers agree on it.
+//! The `ExternalAddrManager` struct builds on top of it in order to merge these observations
+//! with the addresses from the configuration and from UPnP, and tells which ones are confirmed
+//! and which ones are only candidates. It can also translate our internal listen addresses into
+//! public ones by using the IP address that remotes observe.
+//!
+//! ## Periodic identification
+//!
+//! The `PeriodicIdentify` struct is a stream that regularly runs the *identify* protocol over the
+//! connections of a `ConnectionReuse`, so that changes in the information of the remotes are
+//! noticed without having to open the substreams manually.
 
 extern crate bytes;
 extern crate futures;
 extern crate libp2p_peerstore;
-extern crate libp2p_swarm;
+extern crate libp2p_core;
 #[macro_use]
 extern crate log;
 extern crate multiaddr;
+extern crate parking_lot;
 extern crate protobuf;
 extern crate tokio_io;
 extern crate varint;
 
-pub use self::protocol::{IdentifyInfo, IdentifyOutput, IdentifyProtocolConfig, IdentifySender};
+pub use self::addr_filter::ListenAddrFilter;
+pub use self::addr_manager::{AddrConfidence, AddrSource, ExternalAddrManager};
+pub use self::agent_version::AgentVersion;
+pub use self::cache::IdentifyCache;
+pub use self::protocol::{IdentifyInfo, IdentifyOutput, IdentifyParsing, IdentifyProtocolConfig};
+pub use self::protocol::{EncodedIdentifyInfo, IdentifyFuture, IdentifyPrivacy, IdentifySender};
+pub use self::protocol::{IdentifyError, IdentifyPushOutput, IdentifyPushProtocolConfig};
+pub use self::delta::{IdentifyDelta, IdentifyDeltaOutput, IdentifyDeltaProtocolConfig};
+pub use self::delta::IdentifyDeltaSender;
+pub use self::external_addr::{ExternalAddrEvent, ExternalAddrVoting};
+pub use self::metadata::IdentifyMetadata;
+pub use self::periodic::{PeriodicIdentify, PeriodicIdentifyEvent};
+pub use self::record::{PeerRecord, RecordError, RecordSigner, RecordVerifier, SignedPeerRecord};
+pub use self::protocol_changes::{ProtocolChange, ProtocolChangeRouter, ProtocolStatus};
+pub use self::public_key::{PublicKey, PublicKeyError};
+pub use self::record::PEER_RECORD_DOMAIN;
+pub use self::service::{IdentifyService, IdentifyServiceOutput};
 pub use self::transport::IdentifyTransport;
 
+mod addr_filter;
+mod addr_manager;
+mod agent_version;
+mod cache;
+mod delta;
+mod external_addr;
+mod metadata;
+mod periodic;
 mod protocol;
+mod protocol_changes;
+mod public_key;
+mod record;
+mod service;
 mod structs_proto;
 mod transport;
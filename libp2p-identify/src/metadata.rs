@@ -0,0 +1,119 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `IdentifyMetadata` trait, which makes it possible to attach application-specific
+//! values to the identify message.
+//!
+//! Each value is stored in `IdentifyInfo::metadata` under a key, for example the genesis hash of
+//! a blockchain or the identifier of a shard. Applications can compare these values right after
+//! the identification, without negotiating a protocol of their own.
+//!
+//! On the wire, each entry is an extension field of the `Identify` message that contains a key
+//! and a value. Implementations that don't know about this extension ignore it.
+
+use protobuf::{CodedInputStream, CodedOutputStream, ProtobufError, UnknownFields};
+use protobuf::rt;
+
+/// A value that can be stored in `IdentifyInfo::metadata`.
+///
+/// ```
+/// use libp2p_identify::IdentifyMetadata;
+///
+/// struct ShardId(u8);
+///
+/// impl IdentifyMetadata for ShardId {
+///     type Error = ();
+///
+///     fn key() -> &'static str {
+///         "/example/shard"
+///     }
+///
+///     fn to_bytes(&self) -> Vec<u8> {
+///         vec![self.0]
+///     }
+///
+///     fn from_bytes(bytes: &[u8]) -> Result<ShardId, ()> {
+///         match bytes {
+///             &[id] => Ok(ShardId(id)),
+///             _ => Err(()),
+///         }
+///     }
+/// }
+/// ```
+pub trait IdentifyMetadata: Sized {
+    /// Error produced when decoding the value fails.
+    type Error;
+
+    /// Key under which the value is stored. Should be namespaced with the name of the application
+    /// in order to avoid collisions, for example `/polkadot/genesis`.
+    fn key() -> &'static str;
+
+    /// Encodes the value.
+    fn to_bytes(&self) -> Vec<u8>;
+
+    /// Decodes a value produced by `to_bytes`, possibly by another node.
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Self::Error>;
+}
+
+// Encodes an entry of the metadata.
+pub fn encode_entry(key: &str, value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    {
+        let mut os = CodedOutputStream::vec(&mut out);
+        os.write_string(1, key).expect("writing to a Vec never fails");
+        os.write_bytes(2, value).expect("writing to a Vec never fails");
+        os.flush().expect("writing to a Vec never fails");
+    }
+    out
+}
+
+// Decodes an entry produced by `encode_entry`.
+pub fn decode_entry(bytes: &[u8]) -> Result<(String, Vec<u8>), ProtobufError> {
+    let mut key = Default::default();
+    let mut value = Default::default();
+    let mut unknown = UnknownFields::new();
+
+    let mut is = CodedInputStream::from_bytes(bytes);
+    while !is.eof()? {
+        let (field_number, wire_type) = is.read_tag_unpack()?;
+        match field_number {
+            1 => rt::read_singular_string_into(wire_type, &mut is, &mut key)?,
+            2 => rt::read_singular_bytes_into(wire_type, &mut is, &mut value)?,
+            _ => rt::read_unknown_or_skip_group(field_number, wire_type, &mut is, &mut unknown)?,
+        }
+    }
+
+    let key: String = key.into_option().unwrap_or_default();
+    let value: Vec<u8> = value.into_option().unwrap_or_default();
+    Ok((key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_entry, encode_entry};
+
+    #[test]
+    fn entry_round_trip() {
+        let bytes = encode_entry("/example/genesis", &[1, 2, 3]);
+        let (key, value) = decode_entry(&bytes).unwrap();
+        assert_eq!(key, "/example/genesis");
+        assert_eq!(value, vec![1, 2, 3]);
+    }
+}
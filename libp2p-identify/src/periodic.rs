@@ -0,0 +1,306 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `PeriodicIdentify` stream, which regularly runs the *identify* protocol against
+//! the remotes we are connected to.
+//!
+//! The information of a remote (its listen addresses, its supported protocols, etc.) can change
+//! over time. Every time the interval elapses, `PeriodicIdentify` opens an *identify* substream
+//! on each connection of its `ConnectionReuse`, negotiated with a clone of the
+//! `IdentifyProtocolConfig` it was built with, and produces the results as a stream of
+//! `PeriodicIdentifyEvent`s. The substreams are only opened on the existing connections (see
+//! `ConnectionReuse::existing_only`), so remotes we aren't connected to are never dialed. A
+//! connection closed by the remote is still known to the `ConnectionReuse` (see
+//! `ConnectionReuse::connected_addrs`), and identifying it fails with a `Failed` event.
+
+use futures::{Async, Future, Poll, Stream};
+use libp2p_core::{Clock, ClockInterval, ConnectionReuse, ConnectionUpgrade, StreamMuxer};
+use libp2p_core::{TokioClock, Transport};
+use multiaddr::Multiaddr;
+use protocol::{IdentifyInfo, IdentifyOutput, IdentifyProtocolConfig};
+use std::io::Error as IoError;
+use std::time::Duration;
+
+/// Stream that periodically identifies the remotes of a `ConnectionReuse`. Never ends.
+///
+/// The interval is measured with the `Clock` `C`, and `Ck` is the clock of the
+/// `IdentifyProtocolConfig`.
+pub struct PeriodicIdentify<T, M, C = TokioClock, Ck = TokioClock>
+where
+    T: Transport,
+    M: ConnectionUpgrade<T::RawConn>,
+    M::Output: StreamMuxer,
+{
+    transport: ConnectionReuse<T, M>,
+    config: IdentifyProtocolConfig<Ck>,
+    interval: ClockInterval<C>,
+    pending: Vec<Box<Future<Item = PeriodicIdentifyEvent, Error = ()>>>,
+}
+
+/// Event produced by `PeriodicIdentify`.
+#[derive(Debug)]
+pub enum PeriodicIdentifyEvent {
+    /// A remote has been successfully identified.
+    Identified {
+        /// Address of the remote.
+        addr: Multiaddr,
+        /// Information sent by the remote.
+        info: IdentifyInfo,
+        /// Address the remote sees for us, if it sent a valid one.
+        observed_addr: Option<Multiaddr>,
+    },
+
+    /// Identifying a remote failed.
+    Failed {
+        /// Address of the remote.
+        addr: Multiaddr,
+        /// The error that happened.
+        error: IoError,
+    },
+}
+
+impl<T, M, Ck> PeriodicIdentify<T, M, TokioClock, Ck>
+where
+    T: Transport + Clone + 'static,             // TODO: 'static :-/
+    M: ConnectionUpgrade<T::RawConn> + 'static, // TODO: 'static :-/
+    M: Clone,
+    M::Output: StreamMuxer + Clone,
+    <M::Output as StreamMuxer>::Substream: 'static, // TODO: 'static :-/
+    M::NamesIter: Clone,
+    Ck: Clock + 'static, // TODO: 'static :-/
+{
+    /// Builds a new `PeriodicIdentify` that identifies the remotes `transport` is connected to
+    /// every `interval`, with the given configuration of the protocol.
+    #[inline]
+    pub fn new(
+        transport: ConnectionReuse<T, M>,
+        config: IdentifyProtocolConfig<Ck>,
+        interval: Duration,
+    ) -> PeriodicIdentify<T, M, TokioClock, Ck> {
+        PeriodicIdentify::with_clock(transport, config, interval, TokioClock::new())
+    }
+}
+
+impl<T, M, C, Ck> PeriodicIdentify<T, M, C, Ck>
+where
+    T: Transport + Clone + 'static,             // TODO: 'static :-/
+    M: ConnectionUpgrade<T::RawConn> + 'static, // TODO: 'static :-/
+    M: Clone,
+    M::Output: StreamMuxer + Clone,
+    <M::Output as StreamMuxer>::Substream: 'static, // TODO: 'static :-/
+    M::NamesIter: Clone,
+    C: Clock,
+    Ck: Clock + 'static, // TODO: 'static :-/
+{
+    /// Same as `new`, but the interval is measured with the given `Clock`.
+    pub fn with_clock(
+        transport: ConnectionReuse<T, M>,
+        config: IdentifyProtocolConfig<Ck>,
+        interval: Duration,
+        clock: C,
+    ) -> PeriodicIdentify<T, M, C, Ck> {
+        PeriodicIdentify {
+            transport: transport,
+            config: config,
+            interval: ClockInterval::new(clock, interval),
+            pending: Vec::new(),
+        }
+    }
+
+    // Starts identifying all the remotes we are connected to.
+    fn identify_all(&mut self) {
+        for addr in self.transport.connected_addrs() {
+            trace!(target: "libp2p-identify", "Periodic re-identify of {}", addr);
+            let upgrade = self.transport.existing_only().with_upgrade(self.config.clone());
+            let future = match upgrade.dial(addr.clone()) {
+                Ok(dial) => dial,
+                Err((_, addr)) => {
+                    // The connection has been replaced or closed in the meanwhile. We never open
+                    // a new one.
+                    trace!(target: "libp2p-identify", "No connection left with {}", addr);
+                    continue;
+                }
+            };
+
+            let future = future.then(move |result| {
+                Ok(match result {
+                    Ok((IdentifyOutput::RemoteInfo { info, observed_addr }, _)) => {
+                        PeriodicIdentifyEvent::Identified {
+                            addr: addr,
+                            info: info,
+                            observed_addr: observed_addr,
+                        }
+                    }
+                    Ok((IdentifyOutput::Sender { .. }, _)) => unreachable!(
+                        "the identify protocol guarantees that we receive remote information \
+                         when we dial a node"
+                    ),
+                    Err(error) => PeriodicIdentifyEvent::Failed {
+                        addr: addr,
+                        error: error,
+                    },
+                })
+            });
+
+            self.pending.push(Box::new(future));
+        }
+    }
+}
+
+impl<T, M, C, Ck> Stream for PeriodicIdentify<T, M, C, Ck>
+where
+    T: Transport + Clone + 'static,             // TODO: 'static :-/
+    M: ConnectionUpgrade<T::RawConn> + 'static, // TODO: 'static :-/
+    M: Clone,
+    M::Output: StreamMuxer + Clone,
+    <M::Output as StreamMuxer>::Substream: 'static, // TODO: 'static :-/
+    M::NamesIter: Clone,
+    C: Clock,
+    Ck: Clock + 'static, // TODO: 'static :-/
+{
+    type Item = PeriodicIdentifyEvent;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(()))) => self.identify_all(),
+                Ok(Async::Ready(None)) => unreachable!("an interval never ends"),
+                Ok(Async::NotReady) => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        for n in (0..self.pending.len()).rev() {
+            let mut pending = self.pending.swap_remove(n);
+            match pending.poll() {
+                Ok(Async::Ready(event)) => return Ok(Async::Ready(Some(event))),
+                Ok(Async::NotReady) => self.pending.push(pending),
+                Err(()) => unreachable!("the pending futures never produce an error"),
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate libp2p_tcp_transport;
+    extern crate multiplex;
+    extern crate tokio_core;
+
+    use self::libp2p_tcp_transport::TcpConfig;
+    use self::tokio_core::reactor::Core;
+    use {IdentifyInfo, IdentifyOutput, IdentifyParsing, IdentifyProtocolConfig};
+    use {PeriodicIdentify, PeriodicIdentifyEvent, PublicKey};
+    use futures::{future, Future, Stream};
+    use libp2p_core::{ManualClock, Transport};
+    use std::time::Duration;
+
+    // Configuration that only supports a protocol name of its own, so that identifying with the
+    // default configuration fails.
+    fn config() -> IdentifyProtocolConfig {
+        IdentifyProtocolConfig::new().with_protocol_name("/test/id/1.0.0", IdentifyParsing::Strict)
+    }
+
+    #[test]
+    fn identifies_connected_peers() {
+        let mut core = Core::new().unwrap();
+
+        let (listener, addr) = TcpConfig::new(core.handle())
+            .with_upgrade(multiplex::MultiplexConfig)
+            .into_connection_reuse()
+            .with_upgrade(config())
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap_or_else(|_| panic!());
+
+        let server = listener
+            .for_each(|upgrade| {
+                upgrade.and_then(|(output, addr)| match output {
+                    IdentifyOutput::Sender { sender, .. } => sender.send(
+                        IdentifyInfo {
+                            public_key: PublicKey::Rsa(vec![1, 2, 3]),
+                            protocol_version: "proto".to_owned(),
+                            agent_version: "agent".to_owned(),
+                            listen_addrs: vec![],
+                            protocols: vec![],
+                            signed_record: None,
+                            metadata: Vec::new(),
+                        },
+                        &addr,
+                    ),
+                    _ => panic!(),
+                })
+            })
+            .map_err(|_| ());
+        core.handle().spawn(server);
+
+        let transport = TcpConfig::new(core.handle())
+            .with_upgrade(multiplex::MultiplexConfig)
+            .into_connection_reuse();
+
+        // Open the connection that the identifications reuse.
+        let connection = transport
+            .clone()
+            .with_upgrade(config())
+            .dial(addr.clone())
+            .unwrap_or_else(|_| panic!());
+        core.run(connection).unwrap();
+        assert_eq!(transport.connected_addrs(), vec![addr.clone()]);
+
+        let interval = Duration::from_millis(50);
+        let periodic = PeriodicIdentify::new(transport.clone(), config(), interval);
+        let events = core.run(periodic.take(2).collect()).unwrap();
+        for event in events {
+            match event {
+                PeriodicIdentifyEvent::Identified { addr: a, info, .. } => {
+                    assert_eq!(a, addr);
+                    assert_eq!(info.public_key, PublicKey::Rsa(vec![1, 2, 3]));
+                }
+                PeriodicIdentifyEvent::Failed { error, .. } => panic!("{:?}", error),
+            }
+        }
+        assert_eq!(transport.connected_addrs(), vec![addr]);
+    }
+
+    #[test]
+    fn no_dial_without_connection() {
+        let core = Core::new().unwrap();
+        let transport = TcpConfig::new(core.handle())
+            .with_upgrade(multiplex::MultiplexConfig)
+            .into_connection_reuse();
+
+        let clock = ManualClock::new();
+        let mut periodic = PeriodicIdentify::with_clock(
+            transport,
+            IdentifyProtocolConfig::new(),
+            Duration::from_secs(10),
+            clock.clone(),
+        );
+        clock.advance(Duration::from_secs(10));
+
+        let pending = future::lazy(move || {
+            assert!(periodic.poll().unwrap().is_not_ready());
+            Ok::<_, ()>(periodic.pending.len())
+        });
+        assert_eq!(pending.wait().unwrap(), 0);
+    }
+}
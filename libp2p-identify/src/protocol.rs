@@ -18,52 +18,271 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
+use addr_filter::ListenAddrFilter;
+use agent_version::AgentVersion;
 use bytes::{Bytes, BytesMut};
-use futures::{future, Future, Sink, Stream};
-use libp2p_swarm::{ConnectionUpgrade, Endpoint};
+use futures::{future, Async, Future, Poll, Sink, Stream};
+use futures::stream::StreamFuture;
+use libp2p_core::{Clock, ClockTimeout, ConnectionUpgrade, Endpoint, LocalIdentity, TokioClock};
+use libp2p_peerstore::PeerId;
 use log::Level;
+use metadata::{self, IdentifyMetadata};
 use multiaddr::Multiaddr;
 use protobuf::Message as ProtobufMessage;
 use protobuf::core::parse_from_bytes as protobuf_parse_from_bytes;
 use protobuf::repeated::RepeatedField;
+use public_key::PublicKey;
+use std::error::Error;
+use std::fmt;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 use std::iter;
+use std::time::Duration;
+use std::vec::IntoIter as VecIntoIter;
 use structs_proto;
+use transport::multiaddr_to_peerid;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::Framed;
-use varint::VarintCodec;
+use varint::{FrameTooLarge, VarintCodec};
+
+/// Maximum size of an incoming identify message, unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 4096;
 
 /// Configuration for an upgrade to the identity protocol.
+///
+/// By default, only `/ipfs/id/1.0.0` is supported. Other protocol names can be advertised in
+/// order to interoperate with networks that use their own name, such as `/polkadot/id/1.0.0`.
+///
+/// The `Ck` parameter is the `Clock` that measures the timeout set with `with_timeout`.
 #[derive(Debug, Clone)]
-pub struct IdentifyProtocolConfig;
+pub struct IdentifyProtocolConfig<Ck = TokioClock> {
+    // List of protocol names that we support, by order of preference, and how to parse the
+    // messages received through each of them.
+    protocols: Vec<(Bytes, IdentifyParsing)>,
+    // Maximum size of the message we accept from the remote.
+    max_frame_size: usize,
+    // Maximum duration of the exchange of information, and the clock that measures it.
+    deadline: Option<(Duration, Ck)>,
+    // Filter applied to our listen addresses before they are sent to the remote.
+    addr_filter: ListenAddrFilter,
+    // Which parts of our information are kept out of the messages we send.
+    privacy: IdentifyPrivacy,
+}
+
+impl IdentifyProtocolConfig {
+    /// Builds a configuration that supports `/ipfs/id/1.0.0` with lenient parsing, as some
+    /// implementations omit the observed address or send listen addresses that we can't parse.
+    #[inline]
+    pub fn new() -> IdentifyProtocolConfig {
+        IdentifyProtocolConfig {
+            protocols: vec![(Bytes::from("/ipfs/id/1.0.0"), IdentifyParsing::Lenient)],
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            deadline: None,
+            addr_filter: ListenAddrFilter::everything(),
+            privacy: IdentifyPrivacy::Disclose,
+        }
+    }
+
+    /// Sets a deadline for the exchange of information. As a dialer, the upgrade fails with an
+    /// error of kind `TimedOut` if the information of the remote isn't received in time. As a
+    /// listener, the same happens to the future returned by `IdentifySender::send` if the
+    /// information can't be sent in time.
+    ///
+    /// By default there is no deadline.
+    #[inline]
+    pub fn with_timeout(self, timeout: Duration) -> IdentifyProtocolConfig {
+        self.with_timeout_on(timeout, TokioClock::new())
+    }
+}
+
+impl<Ck> IdentifyProtocolConfig<Ck> {
+    /// Same as `with_timeout`, but the time is measured with the given `Clock`.
+    #[inline]
+    pub fn with_timeout_on<Ck2>(self, timeout: Duration, clock: Ck2) -> IdentifyProtocolConfig<Ck2>
+    where
+        Ck2: Clock,
+    {
+        IdentifyProtocolConfig {
+            protocols: self.protocols,
+            max_frame_size: self.max_frame_size,
+            deadline: Some((timeout, clock)),
+            addr_filter: self.addr_filter,
+            privacy: self.privacy,
+        }
+    }
+
+    /// Sets the filter applied to the listen addresses of the `IdentifyInfo` passed to
+    /// `IdentifySender::send`. For example `ListenAddrFilter::public_only()` prevents a node
+    /// reachable from the Internet from advertising `127.0.0.1` or its local network addresses.
+    ///
+    /// By default all the addresses are sent.
+    #[inline]
+    pub fn with_listen_addr_filter(mut self, filter: ListenAddrFilter) -> Self {
+        self.addr_filter = filter;
+        self
+    }
+
+    /// Sets which parts of the `IdentifyInfo` passed to `IdentifySender::send` are kept out of
+    /// the message, for nodes that only want to reveal their agent and protocols.
+    ///
+    /// By default everything is sent.
+    #[inline]
+    pub fn with_privacy(mut self, privacy: IdentifyPrivacy) -> Self {
+        self.privacy = privacy;
+        self
+    }
+
+    /// Encodes `info` with the filter and the privacy settings of this configuration, so that it
+    /// can be sent to many remotes with `IdentifySender::send_encoded`.
+    ///
+    /// The hint of `ListenAddrFilter::with_remote_hint` isn't applied, as the remote isn't known.
+    #[inline]
+    pub fn encode(&self, info: IdentifyInfo) -> EncodedIdentifyInfo {
+        EncodedIdentifyInfo {
+            bytes: encode_info_inner(info, None, &self.addr_filter, self.privacy),
+        }
+    }
+
+    /// Returns the filter applied to the listen addresses that are sent.
+    #[inline]
+    pub fn listen_addr_filter(&self) -> &ListenAddrFilter {
+        &self.addr_filter
+    }
+
+    /// Sets the maximum size in bytes of the message we accept from the remote. If the remote
+    /// announces a larger message, the upgrade fails with an `IdentifyError::FrameTooLarge` before
+    /// anything is buffered.
+    ///
+    /// The default value is 4096 bytes.
+    #[inline]
+    pub fn with_max_frame_size(mut self, max: usize) -> Self {
+        self.max_frame_size = max;
+        self
+    }
+
+    /// Replaces all the protocol names we support with the given one. Messages received through
+    /// this protocol are parsed according to `parsing`.
+    ///
+    /// The default protocol name, `/ipfs/id/1.0.0`, uses `IdentifyParsing::Lenient`.
+    #[inline]
+    pub fn with_protocol_name<N>(mut self, name: N, parsing: IdentifyParsing) -> Self
+    where
+        N: Into<Bytes>,
+    {
+        self.protocols = vec![(name.into(), parsing)];
+        self
+    }
+
+    /// Adds a protocol name to advertise, in addition to the ones already supported.
+    ///
+    /// When dialing, the names are tried in the order in which they were added. Messages received
+    /// through the protocol negotiated with this name are parsed according to `parsing`.
+    #[inline]
+    pub fn add_protocol_name<N>(mut self, name: N, parsing: IdentifyParsing) -> Self
+    where
+        N: Into<Bytes>,
+    {
+        self.protocols.push((name.into(), parsing));
+        self
+    }
+}
+
+impl Default for IdentifyProtocolConfig {
+    #[inline]
+    fn default() -> IdentifyProtocolConfig {
+        IdentifyProtocolConfig::new()
+    }
+}
+
+/// How to parse the messages of a version of the identify protocol.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IdentifyParsing {
+    /// Any listen address that fails to parse makes the whole message invalid, and so does a
+    /// missing or invalid observed address.
+    Strict,
+    /// Listen addresses that fail to parse are ignored, and a missing or invalid observed address
+    /// is reported as `None`. Useful for implementations that may send addresses with protocols
+    /// that we don't know about.
+    Lenient,
+}
+
+/// Which parts of our information are sent to remotes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IdentifyPrivacy {
+    /// Everything is sent.
+    Disclose,
+    /// The listen addresses and the signed peer record, which contains them, aren't sent.
+    HideListenAddrs,
+    /// Same as `HideListenAddrs`, and the public key isn't sent either.
+    ///
+    /// > **Note**: The public key is mandatory in the identify protocol, and remotes will
+    /// >           consider the message as invalid. Only use this if the remotes are known to
+    /// >           not need our identity, for example because it was already authenticated by
+    /// >           an encryption layer.
+    HideListenAddrsAndKey,
+}
+
+impl Default for IdentifyPrivacy {
+    #[inline]
+    fn default() -> IdentifyPrivacy {
+        IdentifyPrivacy::Disclose
+    }
+}
 
 /// Output of the connection upgrade.
-pub enum IdentifyOutput<T> {
+pub enum IdentifyOutput<T, Ck = TokioClock> {
     /// We obtained information from the remote. Happens when we are the dialer.
     RemoteInfo {
         info: IdentifyInfo,
-        /// Address the remote sees for us.
-        observed_addr: Multiaddr,
+        /// Address the remote sees for us. Can only be `None` with lenient parsing, if the remote
+        /// didn't send a valid address.
+        observed_addr: Option<Multiaddr>,
     },
 
     /// We opened a connection to the remote and need to send it information. Happens when we are
     /// the listener.
     Sender {
         /// Object used to send identify info to the client.
-        sender: IdentifySender<T>,
+        sender: IdentifySender<T, Ck>,
         /// Observed multiaddress of the client.
         observed_addr: Multiaddr,
     },
 }
 
 /// Object used to send back information to the client.
-pub struct IdentifySender<T> {
+pub struct IdentifySender<T, Ck = TokioClock> {
     inner: Framed<T, VarintCodec<Vec<u8>>>,
+    // Deadline for sending the information, and the clock that measures it.
+    deadline: Option<(Duration, Ck)>,
+    // Filter applied to our listen addresses before sending them.
+    addr_filter: ListenAddrFilter,
+    // Which parts of our information are kept out of the message.
+    privacy: IdentifyPrivacy,
 }
 
-impl<'a, T> IdentifySender<T>
+impl<T, Ck> IdentifySender<T, Ck> {
+    /// Replaces the filter applied to the listen addresses that are sent. The filter is
+    /// initially the one of the configuration that produced this sender. Senders produced by
+    /// `IdentifyPushProtocolConfig` send all the addresses unless this method is called.
+    #[inline]
+    pub fn with_listen_addr_filter(mut self, filter: ListenAddrFilter) -> Self {
+        self.addr_filter = filter;
+        self
+    }
+
+    /// Replaces which parts of the information are kept out of the message. Same as for the
+    /// filter, this is initially taken from the configuration, and push senders send everything.
+    #[inline]
+    pub fn with_privacy(mut self, privacy: IdentifyPrivacy) -> Self {
+        self.privacy = privacy;
+        self
+    }
+}
+
+impl<'a, T, Ck> IdentifySender<T, Ck>
 where
     T: AsyncWrite + 'a,
+    Ck: Clock,
+    Ck::Delay: 'a,
 {
     /// Sends back information to the client. Returns a future that is signalled whenever the
     /// info have been sent.
@@ -75,115 +294,559 @@ where
         debug!(target: "libp2p-identify", "Sending identify info to client");
         trace!(target: "libp2p-identify", "Sending: {:?}", info);
 
-        let listen_addrs = info.listen_addrs
+        let bytes = encode_info(info, observed_addr, &self.addr_filter, self.privacy);
+        let future = self.inner.send(bytes).map(|_| ());
+        with_deadline(future, self.deadline)
+    }
+
+    /// Same as `send`, but sends information that has already been encoded. The filter and the
+    /// privacy settings of the sender are ignored, as they were applied when encoding.
+    pub fn send_encoded(
+        self,
+        info: &EncodedIdentifyInfo,
+        observed_addr: &Multiaddr,
+    ) -> Box<Future<Item = (), Error = IoError> + 'a> {
+        debug!(target: "libp2p-identify", "Sending pre-encoded identify info to client");
+        let future = self.inner.send(info.with_observed_addr(observed_addr)).map(|_| ());
+        with_deadline(future, self.deadline)
+    }
+}
+
+/// `IdentifyInfo` that has been encoded once, in order to be sent to many remotes without being
+/// encoded again. Built with `IdentifyProtocolConfig::encode`.
+#[derive(Debug, Clone)]
+pub struct EncodedIdentifyInfo {
+    // The message, without the observed address.
+    bytes: Vec<u8>,
+}
+
+impl EncodedIdentifyInfo {
+    // Returns the message with the observed address appended. Fields of a protobuf message can
+    // appear in any order, so this is the same as encoding the message with this address.
+    fn with_observed_addr(&self, observed_addr: &Multiaddr) -> Vec<u8> {
+        let addr = observed_addr.to_bytes();
+        let mut bytes = Vec::with_capacity(self.bytes.len() + addr.len() + 6);
+        bytes.extend_from_slice(&self.bytes);
+        // Key of the `observedAddr` field: field number 4, length-delimited.
+        bytes.push((OBSERVED_ADDR_FIELD << 3 | 2) as u8);
+        let mut len = addr.len();
+        while len >= 0x80 {
+            bytes.push((len as u8) | 0x80);
+            len >>= 7;
+        }
+        bytes.push(len as u8);
+        bytes.extend_from_slice(&addr);
+        bytes
+    }
+}
+
+// Builds the message that `IdentifySender` sends.
+fn encode_info(
+    info: IdentifyInfo,
+    observed_addr: &Multiaddr,
+    addr_filter: &ListenAddrFilter,
+    privacy: IdentifyPrivacy,
+) -> Vec<u8> {
+    encode_info_inner(info, Some(observed_addr), addr_filter, privacy)
+}
+
+// Builds the message, with the observed address if it is known. If it isn't, the hint of the
+// filter can't be applied either.
+fn encode_info_inner(
+    info: IdentifyInfo,
+    observed_addr: Option<&Multiaddr>,
+    addr_filter: &ListenAddrFilter,
+    privacy: IdentifyPrivacy,
+) -> Vec<u8> {
+    let mut message = structs_proto::Identify::new();
+    if info.agent_version.is_empty() {
+        message.set_agentVersion(AgentVersion::default().into());
+    } else {
+        message.set_agentVersion(info.agent_version);
+    }
+    message.set_protocolVersion(info.protocol_version);
+    if privacy != IdentifyPrivacy::HideListenAddrsAndKey {
+        message.set_publicKey(info.public_key.to_protobuf_encoding());
+    }
+    if privacy == IdentifyPrivacy::Disclose {
+        let listen_addrs = match observed_addr {
+            Some(observed_addr) => addr_filter.filter_for_remote(info.listen_addrs, observed_addr),
+            None => addr_filter.filter(info.listen_addrs),
+        };
+        let listen_addrs = listen_addrs
             .into_iter()
             .map(|addr| addr.into_bytes())
             .collect();
-
-        let mut message = structs_proto::Identify::new();
-        message.set_agentVersion(info.agent_version);
-        message.set_protocolVersion(info.protocol_version);
-        message.set_publicKey(info.public_key);
         message.set_listenAddrs(listen_addrs);
+        if let Some(record) = info.signed_record {
+            message.mut_unknown_fields().add_length_delimited(SIGNED_RECORD_FIELD, record);
+        }
+    }
+    if let Some(observed_addr) = observed_addr {
         message.set_observedAddr(observed_addr.to_bytes());
-        message.set_protocols(RepeatedField::from_vec(info.protocols));
-
-        let bytes = message
-            .write_to_bytes()
-            .expect("writing protobuf failed ; should never happen");
-
-        let future = self.inner.send(bytes).map(|_| ());
-        Box::new(future) as Box<_>
     }
+    message.set_protocols(RepeatedField::from_vec(info.protocols));
+    for (key, value) in info.metadata {
+        let entry = metadata::encode_entry(&key, &value);
+        message.mut_unknown_fields().add_length_delimited(METADATA_FIELD, entry);
+    }
+
+    message
+        .write_to_bytes()
+        .expect("writing protobuf failed ; should never happen")
 }
 
 /// Information sent from the listener to the dialer.
 #[derive(Debug, Clone)]
 pub struct IdentifyInfo {
-    /// Public key of the node in the DER format.
-    pub public_key: Vec<u8>,
+    /// Public key of the node.
+    pub public_key: PublicKey,
     /// Version of the "global" protocol, eg. `ipfs/1.0.0` or `polkadot/1.0.0`.
     pub protocol_version: String,
     /// Name and version of the client. Can be thought as similar to the `User-Agent` header
     /// of HTTP.
+    ///
+    /// Usually built with `AgentVersion::default_with`. If empty, `AgentVersion::default()` is
+    /// sent instead.
     pub agent_version: String,
     /// Addresses that the node is listening on.
     pub listen_addrs: Vec<Multiaddr>,
     /// Protocols supported by the node, eg. `/ipfs/ping/1.0.0`.
     pub protocols: Vec<String>,
+    /// Signed peer record of the node, as produced by `SignedPeerRecord::to_bytes`. The record
+    /// hasn't been verified; use `SignedPeerRecord::from_identify_info` before trusting it.
+    pub signed_record: Option<Vec<u8>>,
+    /// Application-specific values, by key. See `IdentifyMetadata`. Keys are unique.
+    pub metadata: Vec<(String, Vec<u8>)>,
+}
+
+impl IdentifyInfo {
+    /// Returns the raw value stored under `key`, if any.
+    pub fn raw_metadata(&self, key: &str) -> Option<&[u8]> {
+        self.metadata
+            .iter()
+            .find(|&&(ref k, _)| k == key)
+            .map(|&(_, ref value)| &value[..])
+    }
+
+    /// Decodes the value of type `M`. Returns `None` if the remote didn't send any.
+    #[inline]
+    pub fn metadata<M>(&self) -> Option<Result<M, M::Error>>
+    where
+        M: IdentifyMetadata,
+    {
+        self.raw_metadata(M::key()).map(M::from_bytes)
+    }
+
+    /// Stores `value` in the metadata, replacing the previous value of the same type.
+    pub fn set_metadata<M>(&mut self, value: &M)
+    where
+        M: IdentifyMetadata,
+    {
+        let key = M::key();
+        let bytes = value.to_bytes();
+        if let Some(entry) = self.metadata.iter_mut().find(|&&mut (ref k, _)| k == key) {
+            entry.1 = bytes;
+            return;
+        }
+        self.metadata.push((key.to_owned(), bytes));
+    }
+}
+
+// Number of the field of the `Identify` message that contains the signed peer record. Isn't part
+// of our `structs.proto`, which predates it, so it is handled as an unknown field.
+const SIGNED_RECORD_FIELD: u32 = 8;
+// Number of the extension field of the `Identify` message that contains an entry of the metadata.
+// May be repeated. The number is far from the ones of the standard fields, so that it doesn't
+// collide with the fields added to the protocol in the future.
+const METADATA_FIELD: u32 = 100;
+// Number of the field of the `Identify` message that contains the observed address.
+const OBSERVED_ADDR_FIELD: u32 = 4;
+
+impl<C, Ck> ConnectionUpgrade<C> for IdentifyProtocolConfig<Ck>
+where
+    C: AsyncRead + AsyncWrite,
+    Ck: Clock,
+{
+    type NamesIter = VecIntoIter<(Bytes, Self::UpgradeIdentifier)>;
+    type UpgradeIdentifier = IdentifyParsing;
+    type Output = IdentifyOutput<C, Ck>;
+    type Future = IdentifyFuture<C, Ck>;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        self.protocols.clone().into_iter()
+    }
+
+    fn upgrade(
+        self,
+        socket: C,
+        parsing: IdentifyParsing,
+        ty: Endpoint,
+        observed_addr: &Multiaddr,
+        _: &LocalIdentity,
+    ) -> Self::Future {
+        trace!(target: "libp2p-identify", "Upgrading connection with {:?} as {:?}",
+               observed_addr, ty);
+
+        let socket = socket.framed(VarintCodec::with_max_len(self.max_frame_size));
+
+        let inner = match ty {
+            Endpoint::Dialer => {
+                let observed_addr_log = if log_enabled!(target: "libp2p-identify", Level::Debug) {
+                    Some(observed_addr.clone())
+                } else {
+                    None
+                };
+
+                IdentifyFutureInner::Dialer {
+                    socket: socket.into_future(),
+                    parsing,
+                    // If we dialed a specific peer, we check that it is the one that answers.
+                    expected_peer_id: multiaddr_to_peerid(observed_addr.clone()).ok(),
+                    observed_addr_log,
+                    delay: self.deadline.map(|(timeout, clock)| clock.delay(timeout)),
+                }
+            }
+
+            Endpoint::Listener => {
+                let sender = IdentifySender {
+                    inner: socket,
+                    deadline: self.deadline,
+                    addr_filter: self.addr_filter,
+                    privacy: self.privacy,
+                };
+
+                IdentifyFutureInner::Listener(Some(IdentifyOutput::Sender {
+                    sender,
+                    observed_addr: observed_addr.clone(),
+                }))
+            }
+        };
+
+        IdentifyFuture { inner }
+    }
+}
+
+/// Future returned by the upgrade of `IdentifyProtocolConfig`.
+///
+/// If we are the dialer, waits for the information sent by the remote. If we are the listener,
+/// immediately produces an `IdentifySender`.
+pub struct IdentifyFuture<C, Ck = TokioClock>
+where
+    Ck: Clock,
+{
+    inner: IdentifyFutureInner<C, Ck>,
+}
+
+enum IdentifyFutureInner<C, Ck>
+where
+    Ck: Clock,
+{
+    Dialer {
+        socket: StreamFuture<Framed<C, VarintCodec<Vec<u8>>>>,
+        parsing: IdentifyParsing,
+        expected_peer_id: Option<PeerId>,
+        observed_addr_log: Option<Multiaddr>,
+        // Fires when the deadline of the exchange is reached, if any.
+        delay: Option<Ck::Delay>,
+    },
+    // Contains `None` once the output has been produced.
+    Listener(Option<IdentifyOutput<C, Ck>>),
 }
 
-impl<C> ConnectionUpgrade<C> for IdentifyProtocolConfig
+impl<C, Ck> Future for IdentifyFuture<C, Ck>
+where
+    C: AsyncRead + AsyncWrite,
+    Ck: Clock,
+{
+    type Item = IdentifyOutput<C, Ck>;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner {
+            IdentifyFutureInner::Dialer {
+                ref mut socket,
+                parsing,
+                ref expected_peer_id,
+                ref observed_addr_log,
+                ref mut delay,
+            } => {
+                match socket.poll() {
+                    Ok(Async::Ready((msg, _))) => {
+                        let output = process_remote_info(msg, parsing, expected_peer_id,
+                                                         observed_addr_log)?;
+                        return Ok(Async::Ready(output));
+                    }
+                    Ok(Async::NotReady) => (),
+                    Err((err, _)) => return Err(convert_codec_error(err)),
+                }
+
+                if let Some(ref mut delay) = *delay {
+                    match delay.poll()? {
+                        Async::Ready(()) => {
+                            return Err(IoError::new(IoErrorKind::TimedOut, "timeout elapsed"))
+                        }
+                        Async::NotReady => (),
+                    }
+                }
+
+                Ok(Async::NotReady)
+            }
+
+            IdentifyFutureInner::Listener(ref mut output) => {
+                let output = output.take().expect("future polled after it has finished");
+                Ok(Async::Ready(output))
+            }
+        }
+    }
+}
+
+// Processes the message received by the dialer.
+fn process_remote_info<C, Ck>(
+    msg: Option<BytesMut>,
+    parsing: IdentifyParsing,
+    expected_peer_id: &Option<PeerId>,
+    observed_addr_log: &Option<Multiaddr>,
+) -> Result<IdentifyOutput<C, Ck>, IoError> {
+    debug!(target: "libp2p-identify", "Received identify message from {:?}",
+           observed_addr_log
+               .as_ref()
+               .expect("Programmer error: expected `observed_addr_log' to be \
+                        non-None since debug log level is enabled"));
+
+    let msg = match msg {
+        Some(msg) => msg,
+        None => {
+            debug!(target: "libp2p-identify", "Identify protocol stream closed before receiving \
+                                               info");
+            return Err(IoErrorKind::InvalidData.into());
+        }
+    };
+
+    let (info, observed_addr) = match parse_proto_msg(msg, parsing) {
+        Ok(v) => v,
+        Err(err) => {
+            debug!(target: "libp2p-identify", "Failed to parse protobuf message ; error = {:?}",
+                   err);
+            return Err(err);
+        }
+    };
+
+    if let Some(ref expected) = *expected_peer_id {
+        let actual = info.public_key.to_peer_id();
+        if actual != *expected {
+            debug!(target: "libp2p-identify", "Expected {:?} but remote is {:?}", expected,
+                   actual);
+            let err = IdentifyError::PublicKeyMismatch {
+                expected: expected.clone(),
+                actual,
+            };
+            return Err(IoError::new(IoErrorKind::InvalidData, err));
+        }
+    }
+
+    trace!(target: "libp2p-identify", "Remote observes us as {:?}", observed_addr);
+    trace!(target: "libp2p-identify", "Information received: {:?}", info);
+
+    Ok(IdentifyOutput::RemoteInfo {
+        info,
+        observed_addr,
+    })
+}
+
+/// Error produced by the identify protocol.
+///
+/// This error is wrapped inside the `IoError` returned by the upgrade, and can be retreived by
+/// calling `get_ref()` and downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentifyError {
+    /// We dialed an address of the form `/p2p/...`, but the public key sent by the remote doesn't
+    /// correspond to that peer ID.
+    PublicKeyMismatch {
+        /// Peer ID we dialed.
+        expected: PeerId,
+        /// Peer ID corresponding to the public key that the remote sent.
+        actual: PeerId,
+    },
+    /// The remote announced a message larger than the maximum we accept.
+    FrameTooLarge {
+        /// Size of the message announced by the remote.
+        len: usize,
+        /// Maximum size that we accept.
+        max: usize,
+    },
+}
+
+impl fmt::Display for IdentifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IdentifyError::PublicKeyMismatch {
+                ref expected,
+                ref actual,
+            } => write!(f, "expected remote {:?} but got {:?}", expected, actual),
+            IdentifyError::FrameTooLarge { len, max } => {
+                write!(f, "message of {} bytes exceeds the maximum of {} bytes", len, max)
+            }
+        }
+    }
+}
+
+impl Error for IdentifyError {
+    #[inline]
+    fn description(&self) -> &str {
+        match *self {
+            IdentifyError::PublicKeyMismatch { .. } => {
+                "public key of the remote doesn't match the dialed peer ID"
+            }
+            IdentifyError::FrameTooLarge { .. } => "message from the remote is too large",
+        }
+    }
+}
+
+/// Configuration for an upgrade to the *identify/push* protocol.
+///
+/// With this protocol, the dialer sends its own information to the listener instead of waiting
+/// to be queried. This is used to notify connected remotes when our listen addresses or supported
+/// protocols change.
+#[derive(Debug, Clone)]
+pub struct IdentifyPushProtocolConfig {
+    // How to parse the messages pushed by the remote.
+    parsing: IdentifyParsing,
+    // Maximum size of the message we accept from the remote.
+    max_frame_size: usize,
+}
+
+impl IdentifyPushProtocolConfig {
+    /// Builds a configuration with lenient parsing, like `IdentifyProtocolConfig::new`.
+    #[inline]
+    pub fn new() -> IdentifyPushProtocolConfig {
+        IdentifyPushProtocolConfig {
+            parsing: IdentifyParsing::Lenient,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Sets how the messages pushed by the remote are parsed.
+    ///
+    /// Contrary to the regular identify protocol, the observed address is optional when pushing,
+    /// and a missing one is reported as `None` even with `IdentifyParsing::Strict`.
+    #[inline]
+    pub fn with_parsing(mut self, parsing: IdentifyParsing) -> Self {
+        self.parsing = parsing;
+        self
+    }
+
+    /// Sets the maximum size in bytes of the message we accept from the remote. If the remote
+    /// announces a larger message, the upgrade fails with an `IdentifyError::FrameTooLarge` before
+    /// anything is buffered.
+    ///
+    /// The default value is 4096 bytes.
+    #[inline]
+    pub fn with_max_frame_size(mut self, max: usize) -> Self {
+        self.max_frame_size = max;
+        self
+    }
+}
+
+impl Default for IdentifyPushProtocolConfig {
+    #[inline]
+    fn default() -> IdentifyPushProtocolConfig {
+        IdentifyPushProtocolConfig::new()
+    }
+}
+
+/// Output of the *identify/push* connection upgrade.
+pub enum IdentifyPushOutput<T> {
+    /// We opened the substream and need to push our information. Happens when we are the dialer.
+    Sender {
+        /// Object used to send our info to the remote.
+        sender: IdentifySender<T>,
+    },
+
+    /// The remote pushed its information to us. Happens when we are the listener.
+    RemoteInfo {
+        info: IdentifyInfo,
+        /// Address the remote sees for us, if it included one.
+        observed_addr: Option<Multiaddr>,
+    },
+}
+
+impl<C> ConnectionUpgrade<C> for IdentifyPushProtocolConfig
 where
     C: AsyncRead + AsyncWrite + 'static,
 {
     type NamesIter = iter::Once<(Bytes, Self::UpgradeIdentifier)>;
     type UpgradeIdentifier = ();
-    type Output = IdentifyOutput<C>;
+    type Output = IdentifyPushOutput<C>;
     type Future = Box<Future<Item = Self::Output, Error = IoError>>;
 
     #[inline]
     fn protocol_names(&self) -> Self::NamesIter {
-        iter::once((Bytes::from("/ipfs/id/1.0.0"), ()))
+        iter::once((Bytes::from("/ipfs/id/push/1.0.0"), ()))
     }
 
-    fn upgrade(self, socket: C, _: (), ty: Endpoint, observed_addr: &Multiaddr) -> Self::Future {
-        trace!(target: "libp2p-identify", "Upgrading connection with {:?} as {:?}",
-               observed_addr, ty);
+    fn upgrade(
+        self,
+        socket: C,
+        _: (),
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        _: &LocalIdentity,
+    ) -> Self::Future {
+        trace!(target: "libp2p-identify", "Upgrading push connection with {:?} as {:?}",
+               remote_addr, ty);
 
-        let socket = socket.framed(VarintCodec::default());
-        let observed_addr_log = if log_enabled!(target: "libp2p-identify", Level::Debug) {
-            Some(observed_addr.clone())
-        } else {
-            None
-        };
+        let socket = socket.framed(VarintCodec::with_max_len(self.max_frame_size));
+        let parsing = self.parsing;
 
         match ty {
             Endpoint::Dialer => {
+                let sender = IdentifySender {
+                    inner: socket,
+                    deadline: None,
+                    addr_filter: ListenAddrFilter::everything(),
+                    privacy: IdentifyPrivacy::Disclose,
+                };
+                Box::new(future::ok(IdentifyPushOutput::Sender { sender: sender })) as Box<_>
+            }
+
+            Endpoint::Listener => {
                 let future = socket
                     .into_future()
                     .map(|(msg, _)| msg)
-                    .map_err(|(err, _)| err)
-                    .and_then(|msg| {
-                        debug!(target: "libp2p-identify", "Received identify message from {:?}",
-                               observed_addr_log
-                                   .expect("Programmer error: expected `observed_addr_log' to be \
-                                            non-None since debug log level is enabled"));
-                        if let Some(msg) = msg {
-                            let (info, observed_addr) = match parse_proto_msg(msg) {
-                                Ok(v) => v,
-                                Err(err) => {
-                                    debug!(target: "libp2p-identify",
-                                           "Failed to parse protobuf message ; error = {:?}", err);
-                                    return Err(err.into());
-                                }
-                            };
-
-                            trace!(target: "libp2p-identify", "Remote observes us as {:?}",
-                                   observed_addr);
-                            trace!(target: "libp2p-identify", "Information received: {:?}", info);
-
-                            Ok(IdentifyOutput::RemoteInfo {
-                                info,
-                                observed_addr,
-                            })
-                        } else {
-                            debug!(target: "libp2p-identify", "Identify protocol stream closed \
-                                                               before receiving info");
-                            Err(IoErrorKind::InvalidData.into())
-                        }
-                    });
+                    .map_err(|(err, _)| convert_codec_error(err))
+                    .and_then(move |msg| {
+                        let msg = match msg {
+                            Some(msg) => msg,
+                            None => {
+                                debug!(target: "libp2p-identify", "Identify push stream closed \
+                                                                   before receiving info");
+                                return Err(IoErrorKind::InvalidData.into());
+                            }
+                        };
 
-                Box::new(future) as Box<_>
-            }
-
-            Endpoint::Listener => {
-                let sender = IdentifySender { inner: socket };
+                        // Contrary to the regular identify protocol, the observed address is
+                        // optional when pushing, whatever the parsing.
+                        let (info, observed_addr) = match parsing {
+                            IdentifyParsing::Strict => {
+                                let (info, observed_addr) = parse_proto_msg_raw(msg, parsing)?;
+                                let observed_addr = if observed_addr.is_empty() {
+                                    None
+                                } else {
+                                    Some(bytes_to_multiaddr(observed_addr)?)
+                                };
+                                (info, observed_addr)
+                            }
+                            IdentifyParsing::Lenient => parse_proto_msg(msg, parsing)?,
+                        };
 
-                let future = future::ok(IdentifyOutput::Sender {
-                    sender,
-                    observed_addr: observed_addr.clone(),
-                });
+                        trace!(target: "libp2p-identify", "Information pushed: {:?}", info);
+                        Ok(IdentifyPushOutput::RemoteInfo {
+                            info: info,
+                            observed_addr: observed_addr,
+                        })
+                    });
 
                 Box::new(future) as Box<_>
             }
@@ -193,41 +856,134 @@ where
 
 // Turns a protobuf message into an `IdentifyInfo` and an observed address. If something bad
 // happens, turn it into an `IoError`.
-fn parse_proto_msg(msg: BytesMut) -> Result<(IdentifyInfo, Multiaddr), IoError> {
-    match protobuf_parse_from_bytes::<structs_proto::Identify>(&msg) {
-        Ok(mut msg) => {
-            // Turn a `Vec<u8>` into a `Multiaddr`. If something bad happens, turn it into
-            // an `IoError`.
-            fn bytes_to_multiaddr(bytes: Vec<u8>) -> Result<Multiaddr, IoError> {
-                Multiaddr::from_bytes(bytes)
-                    .map_err(|err| IoError::new(IoErrorKind::InvalidData, err))
+fn parse_proto_msg(
+    msg: BytesMut,
+    parsing: IdentifyParsing,
+) -> Result<(IdentifyInfo, Option<Multiaddr>), IoError> {
+    let (info, observed_addr) = parse_proto_msg_raw(msg, parsing)?;
+    let observed_addr = match parsing {
+        IdentifyParsing::Strict if observed_addr.is_empty() => {
+            return Err(IoError::new(IoErrorKind::InvalidData, "missing observed address"));
+        }
+        IdentifyParsing::Strict => Some(bytes_to_multiaddr(observed_addr)?),
+        IdentifyParsing::Lenient if observed_addr.is_empty() => None,
+        IdentifyParsing::Lenient => match bytes_to_multiaddr(observed_addr) {
+            Ok(addr) => Some(addr),
+            Err(err) => {
+                debug!(target: "libp2p-identify", "Ignoring invalid observed address ; \
+                                                   error = {:?}", err);
+                None
             }
+        },
+    };
+    Ok((info, observed_addr))
+}
 
+// Same as `parse_proto_msg`, but leaves the observed address as raw bytes.
+fn parse_proto_msg_raw(
+    msg: BytesMut,
+    parsing: IdentifyParsing,
+) -> Result<(IdentifyInfo, Vec<u8>), IoError> {
+    match protobuf_parse_from_bytes::<structs_proto::Identify>(&msg) {
+        Ok(mut msg) => {
             let listen_addrs = {
                 let mut addrs = Vec::new();
                 for addr in msg.take_listenAddrs().into_iter() {
-                    addrs.push(bytes_to_multiaddr(addr)?);
+                    match (bytes_to_multiaddr(addr), parsing) {
+                        (Ok(addr), _) => addrs.push(addr),
+                        (Err(err), IdentifyParsing::Strict) => return Err(err),
+                        (Err(err), IdentifyParsing::Lenient) => {
+                            debug!(target: "libp2p-identify", "Ignoring invalid listen address ; \
+                                                               error = {:?}", err);
+                        }
+                    }
                 }
                 addrs
             };
 
-            let observed_addr = bytes_to_multiaddr(msg.take_observedAddr())?;
+            let signed_record = msg.get_unknown_fields()
+                .get(SIGNED_RECORD_FIELD)
+                .and_then(|values| values.length_delimited.last().cloned());
+
+            let mut metadata: Vec<(String, Vec<u8>)> = Vec::new();
+            let entries = msg.get_unknown_fields()
+                .get(METADATA_FIELD)
+                .map(|values| &values.length_delimited[..])
+                .unwrap_or(&[]);
+            for entry in entries {
+                match metadata::decode_entry(entry) {
+                    Ok((key, value)) => {
+                        // The first value sent for a key wins.
+                        if !metadata.iter().any(|&(ref k, _)| *k == key) {
+                            metadata.push((key, value));
+                        }
+                    }
+                    Err(err) => {
+                        debug!(target: "libp2p-identify", "Ignoring invalid metadata entry ; \
+                                                           error = {:?}", err);
+                    }
+                }
+            }
+
+            let public_key = PublicKey::from_protobuf_encoding(&msg.take_publicKey())
+                .map_err(|err| IoError::new(IoErrorKind::InvalidData, err))?;
 
             let info = IdentifyInfo {
-                public_key: msg.take_publicKey(),
+                public_key: public_key,
                 protocol_version: msg.take_protocolVersion(),
                 agent_version: msg.take_agentVersion(),
                 listen_addrs: listen_addrs,
                 protocols: msg.take_protocols().into_vec(),
+                signed_record: signed_record,
+                metadata: metadata,
             };
 
-            Ok((info, observed_addr))
+            Ok((info, msg.take_observedAddr()))
         }
 
         Err(err) => Err(IoError::new(IoErrorKind::InvalidData, err)),
     }
 }
 
+// Applies a deadline to `future`, if `deadline` is `Some`.
+fn with_deadline<'a, F, Ck>(
+    future: F,
+    deadline: Option<(Duration, Ck)>,
+) -> Box<Future<Item = F::Item, Error = IoError> + 'a>
+where
+    F: Future<Error = IoError> + 'a,
+    Ck: Clock,
+    Ck::Delay: 'a,
+{
+    match deadline {
+        Some((timeout, clock)) => {
+            Box::new(ClockTimeout::new(future, &clock, Some(timeout))) as Box<_>
+        }
+        None => Box::new(future) as Box<_>,
+    }
+}
+
+// Turns the error produced by the codec when a frame is too large into an `IdentifyError`. Other
+// errors are returned as-is.
+pub fn convert_codec_error(err: IoError) -> IoError {
+    let too_large = err.get_ref()
+        .and_then(|err| err.downcast_ref::<FrameTooLarge>())
+        .map(|err| (err.len, err.max));
+    match too_large {
+        Some((len, max)) => {
+            debug!(target: "libp2p-identify", "Remote announced a message of {} bytes, which is \
+                                               more than the maximum of {}", len, max);
+            IoError::new(IoErrorKind::InvalidData, IdentifyError::FrameTooLarge { len, max })
+        }
+        None => err,
+    }
+}
+
+// Turn a `Vec<u8>` into a `Multiaddr`. If something bad happens, turn it into an `IoError`.
+fn bytes_to_multiaddr(bytes: Vec<u8>) -> Result<Multiaddr, IoError> {
+    Multiaddr::from_bytes(bytes).map_err(|err| IoError::new(IoErrorKind::InvalidData, err))
+}
+
 #[cfg(test)]
 mod tests {
     extern crate libp2p_tcp_transport;
@@ -235,10 +991,20 @@ mod tests {
 
     use self::libp2p_tcp_transport::TcpConfig;
     use self::tokio_core::reactor::Core;
-    use {IdentifyInfo, IdentifyOutput, IdentifyProtocolConfig};
+    use bytes::Bytes;
+    use {IdentifyInfo, IdentifyOutput, IdentifyParsing, IdentifyProtocolConfig};
+    use {IdentifyError, IdentifyPushOutput, IdentifyPushProtocolConfig, PublicKey};
+    use {AgentVersion, IdentifyMetadata, IdentifyPrivacy, ListenAddrFilter};
     use futures::{Future, Stream};
-    use libp2p_swarm::Transport;
+    use libp2p_core::{ConnectionUpgrade, Endpoint, LocalIdentity, Transport};
+    use multiaddr::{AddrComponent, Multiaddr};
+    use protobuf::Message;
+    use protobuf::core::parse_from_bytes as protobuf_parse_from_bytes;
+    use protobuf::repeated::RepeatedField;
+    use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind, Read, Write};
     use std::sync::mpsc;
+    use std::time::Duration;
+    use tokio_io::{AsyncRead, AsyncWrite};
     use std::thread;
 
     #[test]
@@ -250,7 +1016,8 @@ mod tests {
 
         let bg_thread = thread::spawn(move || {
             let mut core = Core::new().unwrap();
-            let transport = TcpConfig::new(core.handle()).with_upgrade(IdentifyProtocolConfig);
+            let transport =
+                TcpConfig::new(core.handle()).with_upgrade(IdentifyProtocolConfig::new());
 
             let (listener, addr) = transport
                 .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
@@ -264,7 +1031,7 @@ mod tests {
                 .and_then(|identify| match identify {
                     IdentifyOutput::Sender { sender, .. } => sender.send(
                         IdentifyInfo {
-                            public_key: vec![1, 2, 3, 4, 5, 7],
+                            public_key: PublicKey::Rsa(vec![1, 2, 3, 4, 5, 7]),
                             protocol_version: "proto_version".to_owned(),
                             agent_version: "agent_version".to_owned(),
                             listen_addrs: vec![
@@ -272,6 +1039,8 @@ mod tests {
                                 "/ip6/::1/udp/1000".parse().unwrap(),
                             ],
                             protocols: vec!["proto1".to_string(), "proto2".to_string()],
+                            signed_record: None,
+                            metadata: Vec::new(),
                         },
                         &"/ip4/100.101.102.103/tcp/5000".parse().unwrap(),
                     ),
@@ -282,7 +1051,7 @@ mod tests {
         });
 
         let mut core = Core::new().unwrap();
-        let transport = TcpConfig::new(core.handle()).with_upgrade(IdentifyProtocolConfig);
+        let transport = TcpConfig::new(core.handle()).with_upgrade(IdentifyProtocolConfig::new());
 
         let future = transport
             .dial(rx.recv().unwrap())
@@ -294,9 +1063,9 @@ mod tests {
                 } => {
                     assert_eq!(
                         observed_addr,
-                        "/ip4/100.101.102.103/tcp/5000".parse().unwrap()
+                        Some("/ip4/100.101.102.103/tcp/5000".parse().unwrap())
                     );
-                    assert_eq!(info.public_key, &[1, 2, 3, 4, 5, 7]);
+                    assert_eq!(info.public_key, PublicKey::Rsa(vec![1, 2, 3, 4, 5, 7]));
                     assert_eq!(info.protocol_version, "proto_version");
                     assert_eq!(info.agent_version, "agent_version");
                     assert_eq!(
@@ -318,4 +1087,413 @@ mod tests {
         let _ = core.run(future).unwrap();
         bg_thread.join().unwrap();
     }
+
+    #[test]
+    fn push_transfer() {
+        // The dialer pushes its info to the listener.
+        let mut core = Core::new().unwrap();
+        let transport = TcpConfig::new(core.handle())
+            .with_upgrade(IdentifyPushProtocolConfig::new());
+
+        let (listener, addr) = transport
+            .clone()
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap_or_else(|_| panic!());
+
+        let server = listener
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(|(client, _)| client.unwrap().map(|v| v.0))
+            .map(|push| match push {
+                IdentifyPushOutput::RemoteInfo {
+                    info,
+                    observed_addr,
+                } => {
+                    assert_eq!(info.agent_version, "agent_version");
+                    assert_eq!(info.protocols, &["proto1".to_string()]);
+                    assert_eq!(
+                        observed_addr,
+                        Some("/ip4/100.101.102.103/tcp/5000".parse().unwrap())
+                    );
+                }
+                _ => panic!(),
+            });
+
+        let client = transport
+            .dial(addr)
+            .unwrap_or_else(|_| panic!())
+            .and_then(|(push, _)| match push {
+                IdentifyPushOutput::Sender { sender } => sender.send(
+                    IdentifyInfo {
+                        public_key: PublicKey::Rsa(vec![1, 2, 3]),
+                        protocol_version: "proto_version".to_owned(),
+                        agent_version: "agent_version".to_owned(),
+                        listen_addrs: vec![],
+                        protocols: vec!["proto1".to_string()],
+                        signed_record: None,
+                        metadata: Vec::new(),
+                    },
+                    &"/ip4/100.101.102.103/tcp/5000".parse().unwrap(),
+                ),
+                _ => panic!(),
+            });
+
+        core.run(server.join(client)).unwrap();
+    }
+
+    // Builds the bytes that a dialer pushes, without any observed address.
+    fn push_message(listen_addrs: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut message = ::structs_proto::Identify::new();
+        message.set_publicKey(PublicKey::Rsa(vec![1, 2, 3]).to_protobuf_encoding());
+        message.set_listenAddrs(RepeatedField::from_vec(listen_addrs));
+        let bytes = message.write_to_bytes().unwrap();
+        assert!(bytes.len() < 128);
+        let mut out = vec![bytes.len() as u8];
+        out.extend(bytes);
+        out
+    }
+
+    #[test]
+    fn push_without_observed_addr() {
+        let addr = "/ip4/1.2.3.4/tcp/5".parse::<Multiaddr>().unwrap();
+        let valid = "/ip4/80.81.82.83/tcp/500".parse::<Multiaddr>().unwrap();
+        let listen_addrs = || vec![valid.to_bytes(), vec![0xff, 0xff]];
+
+        // The default parsing is lenient, and ignores the invalid listen address.
+        let output = IdentifyPushProtocolConfig::new()
+            .upgrade(Cursor::new(push_message(listen_addrs())), (), Endpoint::Listener, &addr,
+                     &LocalIdentity::unknown())
+            .wait()
+            .unwrap();
+        match output {
+            IdentifyPushOutput::RemoteInfo { info, observed_addr } => {
+                assert_eq!(info.listen_addrs, vec![valid.clone()]);
+                assert_eq!(observed_addr, None);
+            }
+            _ => panic!(),
+        }
+
+        // With strict parsing, the observed address is still optional.
+        let output = IdentifyPushProtocolConfig::new()
+            .with_parsing(IdentifyParsing::Strict)
+            .upgrade(Cursor::new(push_message(vec![valid.to_bytes()])), (), Endpoint::Listener,
+                     &addr, &LocalIdentity::unknown())
+            .wait()
+            .unwrap();
+        match output {
+            IdentifyPushOutput::RemoteInfo { observed_addr, .. } => assert_eq!(observed_addr, None),
+            _ => panic!(),
+        }
+
+        let result = IdentifyPushProtocolConfig::new()
+            .with_parsing(IdentifyParsing::Strict)
+            .upgrade(Cursor::new(push_message(listen_addrs())), (), Endpoint::Listener, &addr,
+                     &LocalIdentity::unknown())
+            .wait();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn push_frame_too_large() {
+        let message = push_message(vec![]);
+        let len = message[0] as usize;
+        let addr = "/ip4/1.2.3.4/tcp/5".parse::<Multiaddr>().unwrap();
+
+        let err = IdentifyPushProtocolConfig::new()
+            .with_max_frame_size(len - 1)
+            .upgrade(Cursor::new(message), (), Endpoint::Listener, &addr,
+                     &LocalIdentity::unknown())
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.get_ref().and_then(|e| e.downcast_ref::<IdentifyError>()),
+            Some(&IdentifyError::FrameTooLarge { len, max: len - 1 })
+        );
+    }
+
+    // Builds the bytes that a listener sends when its public key is `public_key`.
+    fn listener_message(public_key: PublicKey, listen_addrs: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut message = ::structs_proto::Identify::new();
+        message.set_publicKey(public_key.to_protobuf_encoding());
+        message.set_listenAddrs(RepeatedField::from_vec(listen_addrs));
+        message.set_observedAddr("/ip4/1.2.3.4/tcp/5".parse::<Multiaddr>().unwrap().to_bytes());
+        let bytes = message.write_to_bytes().unwrap();
+        assert!(bytes.len() < 128);
+        let mut out = vec![bytes.len() as u8];
+        out.extend(bytes);
+        out
+    }
+
+    #[test]
+    fn dialed_peer_id_checked() {
+        let parsing = IdentifyParsing::Strict;
+        let expected = PublicKey::Rsa(vec![1, 2, 3]).to_peer_id();
+        let addr: Multiaddr = AddrComponent::P2P(expected.clone().into_bytes()).into();
+
+        let socket = Cursor::new(listener_message(PublicKey::Rsa(vec![1, 2, 3]), vec![]));
+        let output = IdentifyProtocolConfig::new()
+            .upgrade(socket, parsing, Endpoint::Dialer, &addr, &LocalIdentity::unknown())
+            .wait()
+            .unwrap();
+        match output {
+            IdentifyOutput::RemoteInfo { info, .. } => {
+                assert_eq!(info.public_key, PublicKey::Rsa(vec![1, 2, 3]))
+            }
+            _ => panic!(),
+        }
+
+        let socket = Cursor::new(listener_message(PublicKey::Rsa(vec![4, 5, 6]), vec![]));
+        let err = IdentifyProtocolConfig::new()
+            .upgrade(socket, parsing, Endpoint::Dialer, &addr, &LocalIdentity::unknown())
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.get_ref().and_then(|e| e.downcast_ref::<IdentifyError>()),
+            Some(&IdentifyError::PublicKeyMismatch {
+                expected,
+                actual: PublicKey::Rsa(vec![4, 5, 6]).to_peer_id(),
+            })
+        );
+    }
+
+    #[test]
+    fn signed_record_received() {
+        let mut message = ::structs_proto::Identify::new();
+        message.set_publicKey(PublicKey::Rsa(vec![1, 2, 3]).to_protobuf_encoding());
+        message.set_observedAddr("/ip4/1.2.3.4/tcp/5".parse::<Multiaddr>().unwrap().to_bytes());
+        message.mut_unknown_fields().add_length_delimited(8, vec![7, 8, 9]);
+        let bytes = message.write_to_bytes().unwrap();
+
+        let (info, _) = super::parse_proto_msg(bytes.into(), IdentifyParsing::Strict).unwrap();
+        assert_eq!(info.signed_record, Some(vec![7, 8, 9]));
+    }
+
+    struct Genesis(Vec<u8>);
+
+    impl IdentifyMetadata for Genesis {
+        type Error = ();
+
+        fn key() -> &'static str {
+            "/test/genesis"
+        }
+
+        fn to_bytes(&self) -> Vec<u8> {
+            self.0.clone()
+        }
+
+        fn from_bytes(bytes: &[u8]) -> Result<Genesis, ()> {
+            Ok(Genesis(bytes.to_vec()))
+        }
+    }
+
+    #[test]
+    fn metadata_received() {
+        let mut message = ::structs_proto::Identify::new();
+        message.set_publicKey(PublicKey::Rsa(vec![1, 2, 3]).to_protobuf_encoding());
+        message.set_observedAddr("/ip4/1.2.3.4/tcp/5".parse::<Multiaddr>().unwrap().to_bytes());
+        let entries: Vec<(&str, Vec<u8>)> = vec![
+            ("/test/genesis", vec![1, 2]),
+            ("/test/genesis", vec![3, 4]),
+            ("/other", vec![5, 6]),
+        ];
+        for (key, value) in entries {
+            let entry = ::metadata::encode_entry(key, &value);
+            message.mut_unknown_fields().add_length_delimited(100, entry);
+        }
+        let bytes = message.write_to_bytes().unwrap();
+
+        let (mut info, _) = super::parse_proto_msg(bytes.into(), IdentifyParsing::Strict).unwrap();
+        assert_eq!(info.metadata.len(), 2);
+        assert_eq!(info.metadata::<Genesis>().unwrap().unwrap().0, vec![1, 2]);
+        assert_eq!(info.raw_metadata("/other"), Some(&[5, 6][..]));
+
+        info.set_metadata(&Genesis(vec![7]));
+        assert_eq!(info.metadata.len(), 2);
+        assert_eq!(info.metadata::<Genesis>().unwrap().unwrap().0, vec![7]);
+    }
+
+    #[test]
+    fn custom_protocol_names() {
+        let config = IdentifyProtocolConfig::new()
+            .with_protocol_name("/polkadot/id/1.0.0", IdentifyParsing::Strict)
+            .add_protocol_name("/polkadot/id/2.0.0", IdentifyParsing::Lenient);
+        let names = ConnectionUpgrade::<Cursor<Vec<u8>>>::protocol_names(&config)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                (Bytes::from("/polkadot/id/1.0.0"), IdentifyParsing::Strict),
+                (Bytes::from("/polkadot/id/2.0.0"), IdentifyParsing::Lenient),
+            ]
+        );
+    }
+
+    #[test]
+    fn lenient_parsing_skips_invalid_addrs() {
+        let valid = "/ip4/80.81.82.83/tcp/500".parse::<Multiaddr>().unwrap();
+        let listen_addrs = vec![vec![0xff, 0xff, 0xff], valid.to_bytes()];
+        let addr = "/ip4/1.2.3.4/tcp/5".parse::<Multiaddr>().unwrap();
+
+        let message = listener_message(PublicKey::Rsa(vec![1, 2, 3]), listen_addrs.clone());
+        let socket = Cursor::new(message);
+        let result = IdentifyProtocolConfig::new()
+            .upgrade(socket, IdentifyParsing::Strict, Endpoint::Dialer, &addr,
+                     &LocalIdentity::unknown())
+            .wait();
+        assert!(result.is_err());
+
+        let socket = Cursor::new(listener_message(PublicKey::Rsa(vec![1, 2, 3]), listen_addrs));
+        let output = IdentifyProtocolConfig::new()
+            .upgrade(socket, IdentifyParsing::Lenient, Endpoint::Dialer, &addr,
+                     &LocalIdentity::unknown())
+            .wait()
+            .unwrap();
+        match output {
+            IdentifyOutput::RemoteInfo { info, .. } => assert_eq!(info.listen_addrs, vec![valid]),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn lenient_parsing_missing_observed_addr() {
+        let mut message = ::structs_proto::Identify::new();
+        message.set_publicKey(PublicKey::Rsa(vec![1, 2, 3]).to_protobuf_encoding());
+        let bytes = message.write_to_bytes().unwrap();
+
+        assert!(super::parse_proto_msg(bytes.clone().into(), IdentifyParsing::Strict).is_err());
+        let (_, observed_addr) =
+            super::parse_proto_msg(bytes.into(), IdentifyParsing::Lenient).unwrap();
+        assert_eq!(observed_addr, None);
+    }
+
+    #[test]
+    fn privacy() {
+        let info = IdentifyInfo {
+            public_key: PublicKey::Rsa(vec![1, 2, 3]),
+            protocol_version: "proto_version".to_owned(),
+            agent_version: "agent_version".to_owned(),
+            listen_addrs: vec!["/ip4/80.81.82.83/tcp/500".parse().unwrap()],
+            protocols: vec!["proto1".to_owned()],
+            signed_record: Some(vec![4, 5, 6]),
+            metadata: Vec::new(),
+        };
+        let observed_addr = "/ip4/100.101.102.103/tcp/5000".parse().unwrap();
+        let filter = ListenAddrFilter::everything();
+
+        let bytes = super::encode_info(info.clone(), &observed_addr, &filter,
+                                       IdentifyPrivacy::HideListenAddrs);
+        let (received, _) = super::parse_proto_msg(bytes.into(), IdentifyParsing::Strict).unwrap();
+        assert_eq!(received.public_key, info.public_key);
+        assert_eq!(received.protocols, info.protocols);
+        assert!(received.listen_addrs.is_empty());
+        assert!(received.signed_record.is_none());
+
+        let bytes = super::encode_info(info, &observed_addr, &filter,
+                                       IdentifyPrivacy::HideListenAddrsAndKey);
+        let message = protobuf_parse_from_bytes::<::structs_proto::Identify>(&bytes).unwrap();
+        assert!(message.get_publicKey().is_empty());
+        assert_eq!(message.get_agentVersion(), "agent_version");
+    }
+
+    #[test]
+    fn empty_agent_version_replaced() {
+        let info = IdentifyInfo {
+            public_key: PublicKey::Rsa(vec![1, 2, 3]),
+            protocol_version: "proto_version".to_owned(),
+            agent_version: String::new(),
+            listen_addrs: vec![],
+            protocols: vec![],
+            signed_record: None,
+            metadata: Vec::new(),
+        };
+        let observed_addr = "/ip4/100.101.102.103/tcp/5000".parse().unwrap();
+
+        let bytes = super::encode_info(info, &observed_addr, &ListenAddrFilter::everything(),
+                                       IdentifyPrivacy::Disclose);
+        let (received, _) = super::parse_proto_msg(bytes.into(), IdentifyParsing::Strict).unwrap();
+        assert_eq!(received.agent_version, AgentVersion::default().as_str());
+    }
+
+    #[test]
+    fn pre_encoded_info() {
+        let info = IdentifyInfo {
+            public_key: PublicKey::Rsa(vec![1, 2, 3]),
+            protocol_version: "proto_version".to_owned(),
+            agent_version: "agent_version".to_owned(),
+            listen_addrs: vec!["/ip4/80.81.82.83/tcp/500".parse().unwrap()],
+            protocols: vec!["proto1".to_owned()],
+            signed_record: None,
+            metadata: Vec::new(),
+        };
+        let observed_addr: Multiaddr = "/ip4/100.101.102.103/tcp/5000".parse().unwrap();
+
+        let encoded = IdentifyProtocolConfig::new().encode(info.clone());
+        let bytes = encoded.with_observed_addr(&observed_addr);
+        let (received, received_addr) =
+            super::parse_proto_msg(bytes.into(), IdentifyParsing::Strict).unwrap();
+        assert_eq!(received_addr, Some(observed_addr));
+        assert_eq!(received.listen_addrs, info.listen_addrs);
+        assert_eq!(received.protocols, info.protocols);
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let message = listener_message(PublicKey::Rsa(vec![1, 2, 3]), vec![]);
+        let len = message[0] as usize;
+        let addr = "/ip4/1.2.3.4/tcp/5".parse::<Multiaddr>().unwrap();
+
+        let err = IdentifyProtocolConfig::new()
+            .with_max_frame_size(len - 1)
+            .upgrade(Cursor::new(message), IdentifyParsing::Strict, Endpoint::Dialer, &addr,
+                     &LocalIdentity::unknown())
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.get_ref().and_then(|e| e.downcast_ref::<IdentifyError>()),
+            Some(&IdentifyError::FrameTooLarge { len, max: len - 1 })
+        );
+    }
+
+    // Socket that never receives anything.
+    struct Silent;
+
+    impl Read for Silent {
+        fn read(&mut self, _: &mut [u8]) -> Result<usize, IoError> {
+            Err(IoErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Silent {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), IoError> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Silent {}
+
+    impl AsyncWrite for Silent {
+        fn shutdown(&mut self) -> Result<::futures::Async<()>, IoError> {
+            Ok(::futures::Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn dialer_times_out() {
+        let addr = "/ip4/1.2.3.4/tcp/5".parse::<Multiaddr>().unwrap();
+        let err = IdentifyProtocolConfig::new()
+            .with_timeout(Duration::from_millis(50))
+            .upgrade(Silent, IdentifyParsing::Strict, Endpoint::Dialer, &addr,
+                     &LocalIdentity::unknown())
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), IoErrorKind::TimedOut);
+    }
 }
@@ -0,0 +1,198 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Detects the protocols that a peer starts or stops supporting, and notifies the subsystems
+//! that are interested in them.
+//!
+//! Routing layers such as a DHT, a pub-sub mesh or a relay usually only use peers that support
+//! their protocol. When the *identify* protocol reveals that a peer added or removed support for
+//! such a protocol, a `ProtocolChangeRouter` lets these layers update their tables immediately,
+//! instead of waiting for a request to fail or for their own timeouts.
+
+use libp2p_peerstore::PeerId;
+use std::fmt;
+use std::sync::Arc;
+
+/// Difference between two lists of protocols reported by a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolChange {
+    /// Peer whose protocols changed.
+    pub peer_id: PeerId,
+    /// Protocols that the peer now supports and didn't before.
+    pub added: Vec<String>,
+    /// Protocols that the peer supported before and doesn't anymore.
+    pub removed: Vec<String>,
+}
+
+impl ProtocolChange {
+    /// Compares the previous and the new list of protocols of `peer_id`. Returns `None` if no
+    /// protocol was added or removed.
+    pub fn between(peer_id: PeerId, old: &[String], new: &[String]) -> Option<ProtocolChange> {
+        let added = new.iter()
+            .filter(|proto| !old.contains(proto))
+            .cloned()
+            .collect::<Vec<_>>();
+        let removed = old.iter()
+            .filter(|proto| !new.contains(proto))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if added.is_empty() && removed.is_empty() {
+            return None;
+        }
+
+        Some(ProtocolChange {
+            peer_id: peer_id,
+            added: added,
+            removed: removed,
+        })
+    }
+}
+
+/// Whether a peer started or stopped supporting a protocol.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProtocolStatus {
+    /// The peer now supports the protocol.
+    Added,
+    /// The peer doesn't support the protocol anymore.
+    Removed,
+}
+
+/// Dispatches `ProtocolChange`s to the subsystems that registered for them.
+///
+/// Cloning a `ProtocolChangeRouter` gives a router with the same subscribers.
+///
+/// ```
+/// use libp2p_identify::{ProtocolChangeRouter, ProtocolStatus};
+///
+/// let router = ProtocolChangeRouter::new()
+///     .on_protocol("/ipfs/kad/1.0.0", |peer_id, status| match status {
+///         ProtocolStatus::Added => println!("add {:?} to the routing table", peer_id),
+///         ProtocolStatus::Removed => println!("remove {:?} from the routing table", peer_id),
+///     });
+/// # drop(router);
+/// ```
+#[derive(Clone, Default)]
+pub struct ProtocolChangeRouter {
+    all: Vec<Arc<Fn(&ProtocolChange) + Send + Sync>>,
+    by_protocol: Vec<(String, Arc<Fn(&PeerId, ProtocolStatus) + Send + Sync>)>,
+}
+
+impl ProtocolChangeRouter {
+    /// Builds a router without any subscriber.
+    #[inline]
+    pub fn new() -> ProtocolChangeRouter {
+        ProtocolChangeRouter::default()
+    }
+
+    /// Calls `handler` whenever the peer starts or stops supporting the protocol named `name`.
+    pub fn on_protocol<N, F>(mut self, name: N, handler: F) -> ProtocolChangeRouter
+    where
+        N: Into<String>,
+        F: Fn(&PeerId, ProtocolStatus) + Send + Sync + 'static,
+    {
+        self.by_protocol.push((name.into(), Arc::new(handler)));
+        self
+    }
+
+    /// Calls `handler` with every change, whatever the protocols involved.
+    pub fn on_any_change<F>(mut self, handler: F) -> ProtocolChangeRouter
+    where
+        F: Fn(&ProtocolChange) + Send + Sync + 'static,
+    {
+        self.all.push(Arc::new(handler));
+        self
+    }
+
+    /// Notifies the subscribers of `change`.
+    ///
+    /// This is called automatically by the `IdentifyTransport`. Call it manually when receiving
+    /// information through other means, for example the push protocol.
+    pub fn notify(&self, change: &ProtocolChange) {
+        trace!(target: "libp2p-identify", "Protocols of {:?} changed ; added: {:?} ; removed: {:?}",
+               change.peer_id, change.added, change.removed);
+
+        for handler in self.all.iter() {
+            handler(change);
+        }
+
+        for &(ref name, ref handler) in self.by_protocol.iter() {
+            if change.added.contains(name) {
+                handler(&change.peer_id, ProtocolStatus::Added);
+            } else if change.removed.contains(name) {
+                handler(&change.peer_id, ProtocolStatus::Removed);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ProtocolChangeRouter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let protocols = self.by_protocol.iter().map(|&(ref name, _)| name).collect::<Vec<_>>();
+        f.debug_struct("ProtocolChangeRouter")
+            .field("protocols", &protocols)
+            .field("any_change", &self.all.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {ProtocolChange, ProtocolChangeRouter, ProtocolStatus, PublicKey};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn between() {
+        let peer_id = PublicKey::Rsa(vec![1, 2, 3]).to_peer_id();
+        let old = vec!["/a".to_owned(), "/b".to_owned()];
+        let new = vec!["/b".to_owned(), "/c".to_owned()];
+
+        let change = ProtocolChange::between(peer_id.clone(), &old, &new).unwrap();
+        assert_eq!(change.added, vec!["/c".to_owned()]);
+        assert_eq!(change.removed, vec!["/a".to_owned()]);
+        assert!(ProtocolChange::between(peer_id, &old, &old).is_none());
+    }
+
+    #[test]
+    fn routing() {
+        let peer_id = PublicKey::Rsa(vec![1, 2, 3]).to_peer_id();
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let router = {
+            let events1 = events.clone();
+            let events2 = events.clone();
+            ProtocolChangeRouter::new()
+                .on_protocol("/kad", move |_, status| {
+                    events1.lock().unwrap().push(("/kad", status))
+                })
+                .on_protocol("/relay", move |_, status| {
+                    events2.lock().unwrap().push(("/relay", status))
+                })
+        };
+
+        let old = vec!["/kad".to_owned()];
+        let new = vec!["/relay".to_owned(), "/other".to_owned()];
+        router.notify(&ProtocolChange::between(peer_id, &old, &new).unwrap());
+
+        let events = events.lock().unwrap();
+        assert_eq!(*events, vec![("/kad", ProtocolStatus::Removed),
+                                 ("/relay", ProtocolStatus::Added)]);
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `PublicKey` enum, which is the public key of a node as sent in the *identify*
+//! protocol.
+//!
+//! On the wire, keys use the encoding shared by the libp2p implementations: a protobuf message
+//! whose field 1 is the type of the key and whose field 2 is the key itself. The peer ID of a
+//! node is the hash of this encoding.
+
+use libp2p_peerstore::PeerId;
+use protobuf::{CodedInputStream, CodedOutputStream, ProtobufError, UnknownFields};
+use protobuf::rt;
+use protobuf::wire_format::WireType;
+use std::error::Error;
+use std::fmt;
+
+/// Public key of a node.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PublicKey {
+    /// RSA key, in the DER format.
+    Rsa(Vec<u8>),
+    /// Ed25519 key, as its 32 bytes.
+    Ed25519(Vec<u8>),
+    /// Secp256k1 key, in its compressed form.
+    Secp256k1(Vec<u8>),
+}
+
+impl PublicKey {
+    /// Decodes a key from its protobuf encoding.
+    pub fn from_protobuf_encoding(bytes: &[u8]) -> Result<PublicKey, PublicKeyError> {
+        let mut is = CodedInputStream::from_bytes(bytes);
+        let mut unknown = UnknownFields::new();
+        let mut key_type = None;
+        let mut data = Default::default();
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    if wire_type != WireType::WireTypeVarint {
+                        return Err(rt::unexpected_wire_type(wire_type).into());
+                    }
+                    key_type = Some(is.read_uint32()?);
+                }
+                2 => rt::read_singular_bytes_into(wire_type, &mut is, &mut data)?,
+                _ => rt::read_unknown_or_skip_group(field_number, wire_type, &mut is,
+                                                    &mut unknown)?,
+            }
+        }
+
+        let data = match data.into_option() {
+            Some(data) => data,
+            None => return Err(PublicKeyError::MissingField),
+        };
+
+        match key_type {
+            Some(0) => Ok(PublicKey::Rsa(data)),
+            Some(1) => Ok(PublicKey::Ed25519(data)),
+            Some(2) => Ok(PublicKey::Secp256k1(data)),
+            Some(other) => Err(PublicKeyError::UnsupportedKeyType(other)),
+            None => Err(PublicKeyError::MissingField),
+        }
+    }
+
+    /// Encodes the key in its protobuf encoding.
+    pub fn to_protobuf_encoding(&self) -> Vec<u8> {
+        let (key_type, data) = match *self {
+            PublicKey::Rsa(ref data) => (0, data),
+            PublicKey::Ed25519(ref data) => (1, data),
+            PublicKey::Secp256k1(ref data) => (2, data),
+        };
+
+        let mut out = Vec::new();
+        {
+            let mut os = CodedOutputStream::vec(&mut out);
+            os.write_uint32(1, key_type).expect("writing to a Vec never fails");
+            os.write_bytes(2, data).expect("writing to a Vec never fails");
+            os.flush().expect("writing to a Vec never fails");
+        }
+        out
+    }
+
+    /// Returns the key itself, without its type.
+    #[inline]
+    pub fn as_raw(&self) -> &[u8] {
+        match *self {
+            PublicKey::Rsa(ref data) => data,
+            PublicKey::Ed25519(ref data) => data,
+            PublicKey::Secp256k1(ref data) => data,
+        }
+    }
+
+    /// Builds the peer ID corresponding to this key.
+    #[inline]
+    pub fn to_peer_id(&self) -> PeerId {
+        PeerId::from_public_key(&self.to_protobuf_encoding())
+    }
+}
+
+/// Error while decoding a `PublicKey`.
+#[derive(Debug)]
+pub enum PublicKeyError {
+    /// The key isn't a valid protobuf message.
+    Protobuf(ProtobufError),
+    /// The type or the content of the key is missing.
+    MissingField,
+    /// The type of the key isn't one we know about.
+    UnsupportedKeyType(u32),
+}
+
+impl From<ProtobufError> for PublicKeyError {
+    #[inline]
+    fn from(err: ProtobufError) -> PublicKeyError {
+        PublicKeyError::Protobuf(err)
+    }
+}
+
+impl fmt::Display for PublicKeyError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PublicKeyError::Protobuf(ref err) => write!(f, "invalid public key: {}", err),
+            PublicKeyError::MissingField => write!(f, "{}", self.description()),
+            PublicKeyError::UnsupportedKeyType(ty) => write!(f, "unsupported key type {}", ty),
+        }
+    }
+}
+
+impl Error for PublicKeyError {
+    #[inline]
+    fn description(&self) -> &str {
+        match *self {
+            PublicKeyError::Protobuf(_) => "public key isn't a valid protobuf message",
+            PublicKeyError::MissingField => "type or content of the public key is missing",
+            PublicKeyError::UnsupportedKeyType(_) => "unsupported type of public key",
+        }
+    }
+
+    #[inline]
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            PublicKeyError::Protobuf(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {PublicKey, PublicKeyError};
+
+    #[test]
+    fn encoding_round_trip() {
+        let keys = vec![
+            PublicKey::Rsa(vec![1, 2, 3]),
+            PublicKey::Ed25519(vec![4; 32]),
+            PublicKey::Secp256k1(vec![5; 33]),
+        ];
+        for key in keys {
+            let bytes = key.to_protobuf_encoding();
+            assert_eq!(PublicKey::from_protobuf_encoding(&bytes).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn known_encoding() {
+        let bytes = [0x08, 0x01, 0x12, 0x02, 0xaa, 0xbb];
+        let key = PublicKey::from_protobuf_encoding(&bytes).unwrap();
+        assert_eq!(key, PublicKey::Ed25519(vec![0xaa, 0xbb]));
+        assert_eq!(key.to_protobuf_encoding(), bytes.to_vec());
+    }
+
+    #[test]
+    fn unsupported_type() {
+        match PublicKey::from_protobuf_encoding(&[0x08, 0x03, 0x12, 0x01, 0x00]) {
+            Err(PublicKeyError::UnsupportedKeyType(3)) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+}
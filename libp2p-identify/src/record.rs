@@ -0,0 +1,452 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Signed peer records, which allow a node to prove that it is the author of the listen addresses
+//! that are advertised in its name.
+//!
+//! A `PeerRecord` contains the peer ID of a node, a sequence number and its listen addresses. The
+//! node signs it with its identity key, which produces a `SignedPeerRecord`. The encoding follows
+//! the *envelope* format of the other implementations: the signature covers the domain string
+//! `libp2p-routing-state`, the type of the payload and the payload itself.
+//!
+//! This crate doesn't do any cryptography by itself. Signing is performed by an implementation of
+//! `RecordSigner`, and the signature is checked by an implementation of `RecordVerifier`.
+
+use libp2p_peerstore::PeerId;
+use multiaddr::Multiaddr;
+use protobuf::{CodedInputStream, CodedOutputStream, ProtobufError, UnknownFields};
+use protobuf::repeated::RepeatedField;
+use protobuf::rt;
+use protobuf::wire_format::WireType;
+use protocol::IdentifyInfo;
+use public_key::{PublicKey, PublicKeyError};
+use std::error::Error;
+use std::fmt;
+use std::io::Error as IoError;
+
+/// Domain string covered by the signature of a peer record.
+pub const PEER_RECORD_DOMAIN: &'static str = "libp2p-routing-state";
+
+// Multicodec of the payload of a peer record, as registered for `libp2p-peer-record`.
+const PEER_RECORD_PAYLOAD_TYPE: &'static [u8] = &[0x03, 0x01];
+
+/// Routing information about a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerRecord {
+    /// Identity of the node.
+    pub peer_id: PeerId,
+    /// Increases every time the node produces a new record. Between two valid records of the same
+    /// node, the one with the highest number is the most recent.
+    pub seq: u64,
+    /// Addresses that the node is listening on.
+    pub addrs: Vec<Multiaddr>,
+}
+
+/// Produces signatures with the identity key of the local node.
+pub trait RecordSigner {
+    /// Returns the public key of the local node.
+    fn public_key(&self) -> &PublicKey;
+
+    /// Signs `message`.
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, IoError>;
+}
+
+/// Checks signatures produced by remote nodes.
+pub trait RecordVerifier {
+    /// Returns true if `signature` is a valid signature of `message` by `public_key`.
+    fn verify(&self, public_key: &PublicKey, message: &[u8], signature: &[u8]) -> bool;
+}
+
+impl<F> RecordVerifier for F
+where
+    F: Fn(&PublicKey, &[u8], &[u8]) -> bool,
+{
+    #[inline]
+    fn verify(&self, public_key: &PublicKey, message: &[u8], signature: &[u8]) -> bool {
+        self(public_key, message, signature)
+    }
+}
+
+/// A `PeerRecord` signed by the node it describes.
+///
+/// A `SignedPeerRecord` can only be obtained by signing a record or by decoding and verifying
+/// one, therefore its content can be trusted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedPeerRecord {
+    record: PeerRecord,
+    public_key: PublicKey,
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl SignedPeerRecord {
+    /// Builds and signs a record containing the given sequence number and listen addresses. The
+    /// peer ID of the record is derived from the public key of `signer`.
+    pub fn sign<S>(seq: u64, addrs: Vec<Multiaddr>, signer: &S) -> Result<SignedPeerRecord, IoError>
+    where
+        S: RecordSigner,
+    {
+        let public_key = signer.public_key().clone();
+        let record = PeerRecord {
+            peer_id: public_key.to_peer_id(),
+            seq: seq,
+            addrs: addrs,
+        };
+
+        let payload = encode_record(&record);
+        let signature = signer.sign(&signed_message(&payload))?;
+
+        Ok(SignedPeerRecord {
+            record,
+            public_key,
+            payload,
+            signature,
+        })
+    }
+
+    /// Decodes a signed record produced by `to_bytes`, and verifies its signature.
+    pub fn from_bytes<V>(bytes: &[u8], verifier: &V) -> Result<SignedPeerRecord, RecordError>
+    where
+        V: RecordVerifier,
+    {
+        let mut is = CodedInputStream::from_bytes(bytes);
+        let mut unknown = UnknownFields::new();
+        let mut public_key = Default::default();
+        let mut payload_type = Default::default();
+        let mut payload = Default::default();
+        let mut signature = Default::default();
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => rt::read_singular_bytes_into(wire_type, &mut is, &mut public_key)?,
+                2 => rt::read_singular_bytes_into(wire_type, &mut is, &mut payload_type)?,
+                3 => rt::read_singular_bytes_into(wire_type, &mut is, &mut payload)?,
+                5 => rt::read_singular_bytes_into(wire_type, &mut is, &mut signature)?,
+                _ => rt::read_unknown_or_skip_group(field_number, wire_type, &mut is,
+                                                    &mut unknown)?,
+            }
+        }
+
+        let public_key: Vec<u8> = public_key.into_option().unwrap_or_default();
+        let public_key = PublicKey::from_protobuf_encoding(&public_key)?;
+        let payload_type: Vec<u8> = payload_type.into_option().unwrap_or_default();
+        let payload: Vec<u8> = payload.into_option().unwrap_or_default();
+        let signature: Vec<u8> = signature.into_option().unwrap_or_default();
+
+        if payload_type != PEER_RECORD_PAYLOAD_TYPE {
+            return Err(RecordError::WrongPayloadType);
+        }
+
+        if !verifier.verify(&public_key, &signed_message(&payload), &signature) {
+            return Err(RecordError::InvalidSignature);
+        }
+
+        let record = decode_record(&payload)?;
+        if record.peer_id != public_key.to_peer_id() {
+            return Err(RecordError::PeerIdMismatch);
+        }
+
+        Ok(SignedPeerRecord {
+            record,
+            public_key,
+            payload,
+            signature,
+        })
+    }
+
+    /// Decodes and verifies the signed record of an `IdentifyInfo`, if any. In addition to the
+    /// checks of `from_bytes`, the record must have been signed with the key of `info`.
+    pub fn from_identify_info<V>(
+        info: &IdentifyInfo,
+        verifier: &V,
+    ) -> Result<Option<SignedPeerRecord>, RecordError>
+    where
+        V: RecordVerifier,
+    {
+        let bytes = match info.signed_record {
+            Some(ref bytes) => bytes,
+            None => return Ok(None),
+        };
+
+        let record = SignedPeerRecord::from_bytes(bytes, verifier)?;
+        if record.public_key != info.public_key {
+            return Err(RecordError::PeerIdMismatch);
+        }
+        Ok(Some(record))
+    }
+
+    /// Encodes the signed record, for example in order to put it in `IdentifyInfo::signed_record`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let public_key = self.public_key.to_protobuf_encoding();
+        let mut out = Vec::new();
+        {
+            let mut os = CodedOutputStream::vec(&mut out);
+            os.write_bytes(1, &public_key).expect("writing to a Vec never fails");
+            os.write_bytes(2, PEER_RECORD_PAYLOAD_TYPE).expect("writing to a Vec never fails");
+            os.write_bytes(3, &self.payload).expect("writing to a Vec never fails");
+            os.write_bytes(5, &self.signature).expect("writing to a Vec never fails");
+            os.flush().expect("writing to a Vec never fails");
+        }
+        out
+    }
+
+    /// Returns the record.
+    #[inline]
+    pub fn record(&self) -> &PeerRecord {
+        &self.record
+    }
+
+    /// Returns the public key that signed the record.
+    #[inline]
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    /// Destroys the `SignedPeerRecord` and returns the record.
+    #[inline]
+    pub fn into_record(self) -> PeerRecord {
+        self.record
+    }
+}
+
+/// Error while decoding or verifying a signed peer record.
+#[derive(Debug)]
+pub enum RecordError {
+    /// The envelope or the record isn't a valid protobuf message.
+    Protobuf(ProtobufError),
+    /// The public key of the envelope is invalid.
+    InvalidPublicKey(PublicKeyError),
+    /// The envelope doesn't contain a peer record.
+    WrongPayloadType,
+    /// The signature doesn't match the public key and the content of the envelope.
+    InvalidSignature,
+    /// The peer ID of the record isn't the one of the key that signed it, or the key isn't the
+    /// one of the node that sent the record.
+    PeerIdMismatch,
+    /// The peer ID of the record is invalid.
+    InvalidPeerId,
+    /// An address of the record is invalid.
+    InvalidMultiaddr,
+}
+
+impl From<ProtobufError> for RecordError {
+    #[inline]
+    fn from(err: ProtobufError) -> RecordError {
+        RecordError::Protobuf(err)
+    }
+}
+
+impl From<PublicKeyError> for RecordError {
+    #[inline]
+    fn from(err: PublicKeyError) -> RecordError {
+        RecordError::InvalidPublicKey(err)
+    }
+}
+
+impl fmt::Display for RecordError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecordError::Protobuf(ref err) => write!(f, "invalid signed record: {}", err),
+            RecordError::InvalidPublicKey(ref err) => write!(f, "invalid signed record: {}", err),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for RecordError {
+    #[inline]
+    fn description(&self) -> &str {
+        match *self {
+            RecordError::Protobuf(_) => "signed record isn't a valid protobuf message",
+            RecordError::InvalidPublicKey(_) => "invalid public key in signed record",
+            RecordError::WrongPayloadType => "envelope doesn't contain a peer record",
+            RecordError::InvalidSignature => "invalid signature of peer record",
+            RecordError::PeerIdMismatch => "peer record wasn't signed by the node it describes",
+            RecordError::InvalidPeerId => "invalid peer ID in peer record",
+            RecordError::InvalidMultiaddr => "invalid multiaddress in peer record",
+        }
+    }
+
+    #[inline]
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            RecordError::Protobuf(ref err) => Some(err),
+            RecordError::InvalidPublicKey(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// Builds the message that is actually signed, which is each of the domain, the payload type and
+// the payload prefixed with its length.
+fn signed_message(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    {
+        let mut os = CodedOutputStream::vec(&mut out);
+        for part in &[PEER_RECORD_DOMAIN.as_bytes(), PEER_RECORD_PAYLOAD_TYPE, payload] {
+            os.write_raw_varint64(part.len() as u64).expect("writing to a Vec never fails");
+            os.write_raw_bytes(part).expect("writing to a Vec never fails");
+        }
+        os.flush().expect("writing to a Vec never fails");
+    }
+    out
+}
+
+// Encodes a `PeerRecord`. Each address is wrapped in a message of its own, as it is done by other
+// implementations.
+fn encode_record(record: &PeerRecord) -> Vec<u8> {
+    let mut out = Vec::new();
+    {
+        let mut os = CodedOutputStream::vec(&mut out);
+        os.write_bytes(1, record.peer_id.as_bytes()).expect("writing to a Vec never fails");
+        os.write_uint64(2, record.seq).expect("writing to a Vec never fails");
+        for addr in &record.addrs {
+            let mut info = Vec::new();
+            {
+                let mut os = CodedOutputStream::vec(&mut info);
+                os.write_bytes(1, &addr.to_bytes()).expect("writing to a Vec never fails");
+                os.flush().expect("writing to a Vec never fails");
+            }
+            os.write_bytes(3, &info).expect("writing to a Vec never fails");
+        }
+        os.flush().expect("writing to a Vec never fails");
+    }
+    out
+}
+
+// Decodes a `PeerRecord` encoded with `encode_record`.
+fn decode_record(bytes: &[u8]) -> Result<PeerRecord, RecordError> {
+    let mut is = CodedInputStream::from_bytes(bytes);
+    let mut unknown = UnknownFields::new();
+    let mut peer_id = Default::default();
+    let mut seq = 0;
+    let mut addr_infos = RepeatedField::new();
+    while !is.eof()? {
+        let (field_number, wire_type) = is.read_tag_unpack()?;
+        match field_number {
+            1 => rt::read_singular_bytes_into(wire_type, &mut is, &mut peer_id)?,
+            2 => {
+                if wire_type != WireType::WireTypeVarint {
+                    return Err(rt::unexpected_wire_type(wire_type).into());
+                }
+                seq = is.read_uint64()?;
+            }
+            3 => rt::read_repeated_bytes_into(wire_type, &mut is, &mut addr_infos)?,
+            _ => rt::read_unknown_or_skip_group(field_number, wire_type, &mut is, &mut unknown)?,
+        }
+    }
+
+    let peer_id = PeerId::from_bytes(peer_id.into_option().unwrap_or_default())
+        .map_err(|_| RecordError::InvalidPeerId)?;
+
+    let mut addrs = Vec::with_capacity(addr_infos.len());
+    for info in addr_infos.into_iter() {
+        let mut is = CodedInputStream::from_bytes(&info);
+        let mut addr = Default::default();
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => rt::read_singular_bytes_into(wire_type, &mut is, &mut addr)?,
+                _ => rt::read_unknown_or_skip_group(field_number, wire_type, &mut is,
+                                                    &mut unknown)?,
+            }
+        }
+        let addr = Multiaddr::from_bytes(addr.into_option().unwrap_or_default())
+            .map_err(|_| RecordError::InvalidMultiaddr)?;
+        addrs.push(addr);
+    }
+
+    Ok(PeerRecord {
+        peer_id,
+        seq,
+        addrs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Error as IoError;
+    use {IdentifyInfo, PublicKey, RecordError, RecordSigner, SignedPeerRecord};
+
+    // Fake signature scheme: the signature is the public key followed by the message.
+    struct Signer(PublicKey);
+
+    impl RecordSigner for Signer {
+        fn public_key(&self) -> &PublicKey {
+            &self.0
+        }
+
+        fn sign(&self, message: &[u8]) -> Result<Vec<u8>, IoError> {
+            Ok(self.0.as_raw().iter().chain(message.iter()).cloned().collect())
+        }
+    }
+
+    fn verify(public_key: &PublicKey, message: &[u8], signature: &[u8]) -> bool {
+        let expected = public_key.as_raw().iter().chain(message.iter()).cloned();
+        signature == &expected.collect::<Vec<_>>()[..]
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let addrs = vec!["/ip4/1.2.3.4/tcp/5".parse().unwrap()];
+        let signer = Signer(PublicKey::Ed25519(vec![1, 2, 3]));
+        let signed = SignedPeerRecord::sign(7, addrs.clone(), &signer).unwrap();
+        let decoded = SignedPeerRecord::from_bytes(&signed.to_bytes(), &verify).unwrap();
+        assert_eq!(decoded, signed);
+        assert_eq!(decoded.record().peer_id, PublicKey::Ed25519(vec![1, 2, 3]).to_peer_id());
+        assert_eq!(decoded.record().seq, 7);
+        assert_eq!(decoded.record().addrs, addrs);
+    }
+
+    #[test]
+    fn tampered_record_rejected() {
+        let addrs = vec!["/ip4/1.2.3.4/tcp/5".parse().unwrap()];
+        let signer = Signer(PublicKey::Ed25519(vec![1, 2, 3]));
+        let mut bytes = SignedPeerRecord::sign(7, addrs, &signer).unwrap().to_bytes();
+        // Replaces the sequence number (field 2 of the record, with the value 7) with 8.
+        let pos = bytes.windows(2).position(|w| w == [0x10, 7]).unwrap();
+        bytes[pos + 1] = 8;
+        match SignedPeerRecord::from_bytes(&bytes, &verify) {
+            Err(RecordError::InvalidSignature) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn record_of_other_node_rejected() {
+        let addrs = vec!["/ip4/1.2.3.4/tcp/5".parse().unwrap()];
+        let signer = Signer(PublicKey::Ed25519(vec![4, 5, 6]));
+        let signed = SignedPeerRecord::sign(1, addrs, &signer).unwrap();
+        let info = IdentifyInfo {
+            public_key: PublicKey::Ed25519(vec![1, 2, 3]),
+            protocol_version: "proto".to_owned(),
+            agent_version: "agent".to_owned(),
+            listen_addrs: vec![],
+            protocols: vec![],
+            signed_record: Some(signed.to_bytes()),
+            metadata: Vec::new(),
+        };
+        match SignedPeerRecord::from_identify_info(&info, &verify) {
+            Err(RecordError::PeerIdMismatch) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,405 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `IdentifyService` struct, which answers the *identify* queries of remotes
+//! automatically.
+//!
+//! When used as a connection upgrade, `IdentifyProtocolConfig` hands back an `IdentifySender` to
+//! the listener, and it is up to the user to send the information. `IdentifyService` is instead
+//! built once with a closure that provides our `IdentifyInfo`, and sends it to every remote that
+//! opens an *identify* substream. The same object can be used to query remotes, either as part
+//! of the upgrade or through its `query` method.
+//!
+//! Since answering is automatic, a remote could open substreams in a loop in order to make us
+//! build and encode our information over and over. `with_throttle` limits the number of queries
+//! answered for each remote, and `with_response_cache` reuses the same encoded message for a
+//! while.
+
+use bytes::Bytes;
+use futures::{future, Future};
+use libp2p_core::{Clock, ConnectionUpgrade, Endpoint, LocalIdentity, TokioClock, Transport};
+use multiaddr::{AddrComponent, Multiaddr};
+use parking_lot::Mutex;
+use protocol::{EncodedIdentifyInfo, IdentifyInfo, IdentifyOutput, IdentifyParsing};
+use protocol::IdentifyProtocolConfig;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::vec::IntoIter as VecIntoIter;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Connection upgrade that answers the *identify* queries of remotes with the information
+/// provided by a closure, and that queries remotes when dialing.
+pub struct IdentifyService<F, Ck = TokioClock> {
+    config: IdentifyProtocolConfig<Ck>,
+    info: Arc<F>,
+    throttle: Option<Arc<Throttle>>,
+    cache: Option<Arc<ResponseCache>>,
+}
+
+/// Output of the `IdentifyService` upgrade.
+#[derive(Debug, Clone)]
+pub enum IdentifyServiceOutput {
+    /// We dialed the remote and obtained its information.
+    RemoteInfo {
+        /// Information sent by the remote.
+        info: IdentifyInfo,
+        /// Address the remote sees for us, if it sent a valid one.
+        observed_addr: Option<Multiaddr>,
+    },
+
+    /// The remote asked for our information, and it has been sent.
+    Answered {
+        /// Address we observe for the remote.
+        observed_addr: Multiaddr,
+    },
+}
+
+impl<F> IdentifyService<F>
+where
+    F: Fn() -> IdentifyInfo,
+{
+    /// Builds a new `IdentifyService`. `info` is called every time a remote asks for our
+    /// information, and must return up-to-date information.
+    #[inline]
+    pub fn new(info: F) -> IdentifyService<F> {
+        IdentifyService {
+            config: IdentifyProtocolConfig::new(),
+            info: Arc::new(info),
+            throttle: None,
+            cache: None,
+        }
+    }
+}
+
+impl<F, Ck> IdentifyService<F, Ck> {
+    /// Replaces the configuration of the protocol, for example in order to set a timeout or to
+    /// use other protocol names.
+    #[inline]
+    pub fn with_config<Ck2>(self, config: IdentifyProtocolConfig<Ck2>) -> IdentifyService<F, Ck2> {
+        IdentifyService {
+            config: config,
+            info: self.info,
+            throttle: self.throttle,
+            cache: self.cache,
+        }
+    }
+
+    /// Answers at most `max_queries` queries from the same remote during each `interval`. The
+    /// substreams of the other queries are closed without an answer.
+    ///
+    /// Remotes are told apart by their IP address, or by their whole address if it doesn't
+    /// contain any.
+    #[inline]
+    pub fn with_throttle(mut self, max_queries: u32, interval: Duration) -> Self {
+        self.throttle = Some(Arc::new(Throttle {
+            max_queries: max_queries,
+            interval: interval,
+            queries: Mutex::new(HashMap::new()),
+        }));
+        self
+    }
+
+    /// Calls the closure and encodes our information at most once every `ttl`, and sends the
+    /// same message to all the remotes that query us in the meantime.
+    ///
+    /// Ignored if the listen address filter of the configuration has a remote hint, as the
+    /// message then depends on the remote.
+    #[inline]
+    pub fn with_response_cache(mut self, ttl: Duration) -> Self {
+        self.cache = Some(Arc::new(ResponseCache {
+            ttl: ttl,
+            entry: Mutex::new(None),
+        }));
+        self
+    }
+
+    /// Dials `addr` on `transport` and queries the remote for its information. Produces the
+    /// information and the address the remote observes for us.
+    pub fn query<T>(
+        &self,
+        transport: T,
+        addr: Multiaddr,
+    ) -> Box<Future<Item = (IdentifyInfo, Option<Multiaddr>), Error = IoError>>
+    where
+        T: Transport + 'static, // TODO: 'static :-/
+        T::RawConn: 'static,    // TODO: 'static :-/
+        F: Fn() -> IdentifyInfo + 'static,
+        Ck: Clock + 'static,
+    {
+        let dial = match transport.with_upgrade(self.clone()).dial(addr) {
+            Ok(dial) => dial,
+            Err((_, addr)) => {
+                let msg = format!("multiaddress not supported: {}", addr);
+                let err = IoError::new(IoErrorKind::Other, msg);
+                return Box::new(future::err(err)) as Box<_>;
+            }
+        };
+
+        let future = dial.map(|(output, _)| match output {
+            IdentifyServiceOutput::RemoteInfo {
+                info,
+                observed_addr,
+            } => (info, observed_addr),
+            IdentifyServiceOutput::Answered { .. } => unreachable!(
+                "the identify protocol guarantees that we receive remote information when we \
+                 dial a node"
+            ),
+        });
+
+        Box::new(future) as Box<_>
+    }
+}
+
+impl<F, Ck> Clone for IdentifyService<F, Ck>
+where
+    Ck: Clone,
+{
+    #[inline]
+    fn clone(&self) -> Self {
+        IdentifyService {
+            config: self.config.clone(),
+            info: self.info.clone(),
+            throttle: self.throttle.clone(),
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+impl<C, F, Ck> ConnectionUpgrade<C> for IdentifyService<F, Ck>
+where
+    C: AsyncRead + AsyncWrite + 'static, // TODO: 'static :-/
+    F: Fn() -> IdentifyInfo + 'static,
+    Ck: Clock + 'static,
+{
+    type NamesIter = VecIntoIter<(Bytes, Self::UpgradeIdentifier)>;
+    type UpgradeIdentifier = IdentifyParsing;
+    type Output = IdentifyServiceOutput;
+    type Future = Box<Future<Item = Self::Output, Error = IoError>>;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        ConnectionUpgrade::<C>::protocol_names(&self.config)
+    }
+
+    fn upgrade(
+        self,
+        socket: C,
+        parsing: IdentifyParsing,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        if let Some(ref throttle) = self.throttle {
+            if ty == Endpoint::Listener && !throttle.allow(remote_addr) {
+                debug!(target: "libp2p-identify", "Too many identify queries from {} ; ignoring",
+                       remote_addr);
+                let err = IoError::new(IoErrorKind::Other, "too many identify queries");
+                return Box::new(future::err(err)) as Box<_>;
+            }
+        }
+
+        let info = self.info;
+        let cache = match self.cache {
+            Some(ref cache) if !self.config.listen_addr_filter().has_remote_hint() => {
+                Some((cache.clone(), self.config.clone()))
+            }
+            _ => None,
+        };
+
+        let future = self.config
+            .upgrade(socket, parsing, ty, remote_addr, local_identity)
+            .and_then(move |output| match output {
+                IdentifyOutput::RemoteInfo {
+                    info,
+                    observed_addr,
+                } => {
+                    let output = IdentifyServiceOutput::RemoteInfo {
+                        info,
+                        observed_addr,
+                    };
+                    Box::new(future::ok(output)) as Box<Future<Item = _, Error = _>>
+                }
+                IdentifyOutput::Sender {
+                    sender,
+                    observed_addr,
+                } => {
+                    trace!(target: "libp2p-identify", "Answering identify query of {}",
+                           observed_addr);
+                    let future = match cache {
+                        Some((cache, config)) => {
+                            let encoded = cache.get_or_encode(|| config.encode((*info)()));
+                            sender.send_encoded(&encoded, &observed_addr)
+                        }
+                        None => sender.send((*info)(), &observed_addr),
+                    };
+                    let future =
+                        future.map(move |()| IdentifyServiceOutput::Answered { observed_addr });
+                    Box::new(future) as Box<Future<Item = _, Error = _>>
+                }
+            });
+
+        Box::new(future) as Box<_>
+    }
+}
+
+// Number of queries of each remote during the current interval.
+struct Throttle {
+    max_queries: u32,
+    interval: Duration,
+    // For each remote, the start of its current interval and the number of queries since then.
+    queries: Mutex<HashMap<Vec<u8>, (Instant, u32)>>,
+}
+
+// Maximum number of remotes that a `Throttle` remembers before it purges the old intervals.
+const THROTTLE_MAX_ENTRIES: usize = 1024;
+
+impl Throttle {
+    // Returns true if a query from `remote_addr` can be answered, and counts it.
+    fn allow(&self, remote_addr: &Multiaddr) -> bool {
+        let key = remote_addr
+            .iter()
+            .filter_map(|component| match component {
+                component @ AddrComponent::IP4(_) | component @ AddrComponent::IP6(_) => {
+                    Some(Multiaddr::from(component).into_bytes())
+                }
+                _ => None,
+            })
+            .next()
+            .unwrap_or_else(|| remote_addr.to_bytes());
+
+        let now = Instant::now();
+        let mut queries = self.queries.lock();
+        if queries.len() >= THROTTLE_MAX_ENTRIES {
+            let interval = self.interval;
+            queries.retain(|_, &mut (start, _)| now.duration_since(start) < interval);
+        }
+
+        let entry = queries.entry(key).or_insert((now, 0));
+        if now.duration_since(entry.0) >= self.interval {
+            *entry = (now, 0);
+        }
+        if entry.1 >= self.max_queries {
+            return false;
+        }
+        entry.1 += 1;
+        true
+    }
+}
+
+// Last message encoded by the service.
+struct ResponseCache {
+    ttl: Duration,
+    entry: Mutex<Option<(Instant, EncodedIdentifyInfo)>>,
+}
+
+impl ResponseCache {
+    // Returns the cached message, or calls `encode` if it is missing or too old.
+    fn get_or_encode<F>(&self, encode: F) -> EncodedIdentifyInfo
+    where
+        F: FnOnce() -> EncodedIdentifyInfo,
+    {
+        let now = Instant::now();
+        let mut entry = self.entry.lock();
+        if let Some((encoded_at, ref encoded)) = *entry {
+            if now.duration_since(encoded_at) < self.ttl {
+                return encoded.clone();
+            }
+        }
+
+        let encoded = encode();
+        *entry = Some((now, encoded.clone()));
+        encoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate libp2p_tcp_transport;
+    extern crate tokio_core;
+
+    use self::libp2p_tcp_transport::TcpConfig;
+    use self::tokio_core::reactor::Core;
+    use futures::{Future, Stream};
+    use libp2p_core::Transport;
+    use multiaddr::Multiaddr;
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use {IdentifyInfo, IdentifyService, IdentifyServiceOutput, PublicKey};
+
+    fn info() -> IdentifyInfo {
+        IdentifyInfo {
+            public_key: PublicKey::Ed25519(vec![1, 2, 3]),
+            protocol_version: "proto_version".to_owned(),
+            agent_version: "agent_version".to_owned(),
+            listen_addrs: vec!["/ip4/80.81.82.83/tcp/500".parse().unwrap()],
+            protocols: vec!["/proto/1.0.0".to_owned()],
+            signed_record: None,
+            metadata: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn answers_and_queries() {
+        let mut core = Core::new().unwrap();
+        let transport = TcpConfig::new(core.handle());
+        let service = IdentifyService::new(info);
+
+        let (listener, addr) = transport
+            .clone()
+            .with_upgrade(service.clone())
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap_or_else(|_| panic!());
+
+        let server = listener
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(|(upgrade, _)| upgrade.unwrap())
+            .map(|(output, _)| match output {
+                IdentifyServiceOutput::Answered { .. } => (),
+                IdentifyServiceOutput::RemoteInfo { .. } => panic!(),
+            });
+
+        let client = service.query(transport, addr);
+
+        let ((), (remote, _)) = core.run(server.join(client)).unwrap();
+        assert_eq!(remote.public_key, PublicKey::Ed25519(vec![1, 2, 3]));
+        assert_eq!(remote.protocols, vec!["/proto/1.0.0".to_owned()]);
+    }
+
+    #[test]
+    fn throttle() {
+        let throttle = super::Throttle {
+            max_queries: 2,
+            interval: Duration::from_secs(3600),
+            queries: Mutex::new(HashMap::new()),
+        };
+
+        let addr1: Multiaddr = "/ip4/1.2.3.4/tcp/5".parse().unwrap();
+        let addr1_other_port: Multiaddr = "/ip4/1.2.3.4/tcp/6".parse().unwrap();
+        let addr2: Multiaddr = "/ip4/5.6.7.8/tcp/5".parse().unwrap();
+        assert!(throttle.allow(&addr1));
+        assert!(throttle.allow(&addr1_other_port));
+        assert!(!throttle.allow(&addr1));
+        assert!(throttle.allow(&addr2));
+    }
+}
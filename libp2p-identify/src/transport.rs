@@ -18,21 +18,27 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use futures::{stream, Future, IntoFuture, Stream};
+use futures::{Future, IntoFuture, Stream};
 use libp2p_peerstore::{PeerAccess, PeerId, Peerstore};
-use libp2p_swarm::{MuxedTransport, Transport};
+use libp2p_core::{dial_any, LocalIdentity, MuxedTransport, Transport};
 use multiaddr::{AddrComponent, Multiaddr};
 use protocol::{IdentifyInfo, IdentifyOutput, IdentifyProtocolConfig};
+use protocol_changes::{ProtocolChange, ProtocolChangeRouter};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 use std::ops::Deref;
 use std::time::Duration;
 
+/// Default number of known addresses of a peer that are dialed at the same time.
+const DEFAULT_DIAL_CONCURRENCY: usize = 8;
+
 /// Implementation of `Transport`. See [the crate root description](index.html).
 #[derive(Debug, Clone)]
 pub struct IdentifyTransport<Trans, PStoreRef> {
     transport: Trans,
     peerstore: PStoreRef,
     addr_ttl: Duration,
+    protocol_changes: Option<ProtocolChangeRouter>,
+    dial_concurrency: usize,
 }
 
 impl<Trans, PStoreRef> IdentifyTransport<Trans, PStoreRef> {
@@ -52,8 +58,34 @@ impl<Trans, PStoreRef> IdentifyTransport<Trans, PStoreRef> {
             transport: transport,
             peerstore: peerstore,
             addr_ttl: ttl,
+            protocol_changes: None,
+            dial_concurrency: DEFAULT_DIAL_CONCURRENCY,
         }
     }
+
+    /// Notifies `router` whenever identifying a remote reveals that it added or removed support
+    /// for some protocols, compared to what the peerstore knew.
+    #[inline]
+    pub fn with_protocol_changes(mut self, router: ProtocolChangeRouter) -> Self {
+        self.protocol_changes = Some(router);
+        self
+    }
+
+    /// Sets how many of the known addresses of a peer are dialed at the same time when dialing
+    /// a `/p2p/...` multiaddress. The first connection that succeeds is used, and the other
+    /// attempts are cancelled.
+    ///
+    /// The default value is 8. A value of 1 dials the addresses one by one.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `concurrency` is 0.
+    #[inline]
+    pub fn with_dial_concurrency(mut self, concurrency: usize) -> Self {
+        assert_ne!(concurrency, 0, "the dial concurrency must be at least 1");
+        self.dial_concurrency = concurrency;
+        self
+    }
 }
 
 impl<Trans, PStore, PStoreRef> Transport for IdentifyTransport<Trans, PStoreRef>
@@ -79,17 +111,21 @@ where
                     transport: inner,
                     peerstore: self.peerstore,
                     addr_ttl: self.addr_ttl,
+                    protocol_changes: self.protocol_changes,
+                    dial_concurrency: self.dial_concurrency,
                 };
                 return Err((id, addr));
             }
         };
 
-        let identify_upgrade = self.transport.with_upgrade(IdentifyProtocolConfig);
+        let identify_upgrade = self.transport.with_upgrade(IdentifyProtocolConfig::new());
         let peerstore = self.peerstore;
         let addr_ttl = self.addr_ttl;
+        let protocol_changes = self.protocol_changes;
 
         let listener = listener.map(move |connec| {
             let peerstore = peerstore.clone();
+            let protocol_changes = protocol_changes.clone();
             let identify_upgrade = identify_upgrade.clone();
             let fut = connec
                 .and_then(move |(connec, client_addr)| {
@@ -113,6 +149,7 @@ where
                             &*peerstore.clone(),
                             original_addr,
                             addr_ttl,
+                            protocol_changes.as_ref(),
                         )?,
                         _ => unreachable!(
                             "the identify protocol guarantees that we receive \
@@ -133,8 +170,8 @@ where
     fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
         match multiaddr_to_peerid(addr.clone()) {
             Ok(peer_id) => {
-                // If the multiaddress is a peer ID, try each known multiaddress (taken from the
-                // peerstore) one by one.
+                // If the multiaddress is a peer ID, try the known multiaddresses (taken from the
+                // peerstore), several of them at a time.
                 let addrs = self.peerstore
                     .deref()
                     .peer(&peer_id)
@@ -144,16 +181,10 @@ where
                     .into_iter();
 
                 let transport = self.transport;
-                let future = stream::iter_ok(addrs)
-                    // Try to dial each address through the transport.
-                    .filter_map(move |addr| transport.clone().dial(addr).ok())
-                    .and_then(move |dial| dial)
-                    // Pick the first non-failing dial result.
-                    .then(|res| Ok(res))
-                    .filter_map(|res| res.ok())
-                    .into_future()
-                    .map_err(|(err, _)| err)
-                    .and_then(|(val, _)| val.ok_or(IoErrorKind::InvalidData.into())) // TODO: wrong error
+                let future = dial_any(addrs, move |addr| {
+                    transport.clone().dial(addr).map_err(|(_, addr)| addr)
+                }).with_concurrency(self.dial_concurrency)
+                    .map_err(IoError::from)
                     .map(move |(socket, _inner_client_addr)| (socket, addr));
 
                 Ok(Box::new(future) as Box<_>)
@@ -163,7 +194,9 @@ where
                 // If the multiaddress is something else, propagate it to the underlying transport
                 // and identify the node.
                 let transport = self.transport;
-                let identify_upgrade = transport.clone().with_upgrade(IdentifyProtocolConfig);
+                let identify_upgrade = transport
+                    .clone()
+                    .with_upgrade(IdentifyProtocolConfig::new());
 
                 // We dial a first time the node and upgrade it to identify.
                 let dial = match identify_upgrade.dial(addr) {
@@ -173,6 +206,8 @@ where
                             transport,
                             peerstore: self.peerstore,
                             addr_ttl: self.addr_ttl,
+                            protocol_changes: self.protocol_changes,
+                            dial_concurrency: self.dial_concurrency,
                         };
                         return Err((id, addr));
                     }
@@ -180,6 +215,7 @@ where
 
                 let peerstore = self.peerstore;
                 let addr_ttl = self.addr_ttl;
+                let protocol_changes = self.protocol_changes;
 
                 let future = dial.and_then(move |identify| {
                     // On success, store the information in the peerstore and compute the
@@ -188,7 +224,8 @@ where
                     match identify {
                         (IdentifyOutput::RemoteInfo { info, .. }, a) => {
                             old_addr = a.clone();
-                            real_addr = process_identify_info(&info, &*peerstore, a, addr_ttl)?;
+                            real_addr = process_identify_info(&info, &*peerstore, a, addr_ttl,
+                                                              protocol_changes.as_ref())?;
                         }
                         _ => unreachable!(
                             "the identify protocol guarantees that we receive \
@@ -215,6 +252,16 @@ where
     fn nat_traversal(&self, a: &Multiaddr, b: &Multiaddr) -> Option<Multiaddr> {
         self.transport.nat_traversal(a, b)
     }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.transport.local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        true
+    }
 }
 
 impl<Trans, PStore, PStoreRef> MuxedTransport for IdentifyTransport<Trans, PStoreRef>
@@ -228,9 +275,10 @@ where
 
     #[inline]
     fn next_incoming(self) -> Self::Incoming {
-        let identify_upgrade = self.transport.clone().with_upgrade(IdentifyProtocolConfig);
+        let identify_upgrade = self.transport.clone().with_upgrade(IdentifyProtocolConfig::new());
         let peerstore = self.peerstore;
         let addr_ttl = self.addr_ttl;
+        let protocol_changes = self.protocol_changes;
 
         let future = self.transport.next_incoming().map(move |incoming| {
             let future = incoming
@@ -251,7 +299,8 @@ where
                     // the form `/p2p/...`).
                     let real_addr = match identify {
                         (IdentifyOutput::RemoteInfo { info, .. }, old_addr) => {
-                            process_identify_info(&info, &*peerstore, old_addr, addr_ttl)?
+                            process_identify_info(&info, &*peerstore, old_addr, addr_ttl,
+                                                  protocol_changes.as_ref())?
                         }
                         _ => unreachable!(
                             "the identify protocol guarantees that we receive remote \
@@ -271,7 +320,7 @@ where
 
 // If the multiaddress is in the form `/p2p/...`, turn it into a `PeerId`.
 // Otherwise, return it as-is.
-fn multiaddr_to_peerid(addr: Multiaddr) -> Result<PeerId, Multiaddr> {
+pub fn multiaddr_to_peerid(addr: Multiaddr) -> Result<PeerId, Multiaddr> {
     let components = addr.iter().collect::<Vec<_>>();
     if components.len() < 1 {
         return Err(addr);
@@ -292,6 +341,9 @@ fn multiaddr_to_peerid(addr: Multiaddr) -> Result<PeerId, Multiaddr> {
 // When passed the information sent by a remote, inserts the remote into the given peerstore and
 // returns a multiaddr of the format `/p2p/...` corresponding to this node.
 //
+// The peerstore receives the public key of the remote, the protocols it supports, the address we
+// reached it at, and the addresses it says it listens on, all of them with the given TTL.
+//
 // > **Note**: This function is highly-specific, but this precise behaviour is needed in multiple
 // >           different places in the code.
 fn process_identify_info<P>(
@@ -299,14 +351,23 @@ fn process_identify_info<P>(
     peerstore: P,
     client_addr: Multiaddr,
     ttl: Duration,
+    protocol_changes: Option<&ProtocolChangeRouter>,
 ) -> Result<Multiaddr, IoError>
 where
     P: Peerstore,
 {
-    let peer_id = PeerId::from_public_key(&info.public_key);
-    peerstore
-        .peer_or_create(&peer_id)
-        .add_addr(client_addr, ttl);
+    let peer_id = info.public_key.to_peer_id();
+    let mut peer = peerstore.peer_or_create(&peer_id);
+    peer.set_public_key(info.public_key.to_protobuf_encoding());
+    if let Some(router) = protocol_changes {
+        let old = peer.protocols();
+        if let Some(change) = ProtocolChange::between(peer_id.clone(), &old, &info.protocols) {
+            router.notify(&change);
+        }
+    }
+    peer.set_protocols(info.protocols.clone());
+    peer.add_addr(client_addr, ttl);
+    peer.add_addrs(info.listen_addrs.iter().cloned(), ttl);
     Ok(AddrComponent::P2P(peer_id.into_bytes()).into())
 }
 
@@ -317,15 +378,15 @@ mod tests {
 
     use self::libp2p_tcp_transport::TcpConfig;
     use self::tokio_core::reactor::Core;
-    use IdentifyTransport;
+    use {IdentifyInfo, IdentifyTransport, ProtocolChangeRouter, ProtocolStatus, PublicKey};
     use futures::{Future, Stream};
     use libp2p_peerstore::{PeerAccess, PeerId, Peerstore};
     use libp2p_peerstore::memory_peerstore::MemoryPeerstore;
-    use libp2p_swarm::Transport;
+    use libp2p_core::Transport;
     use multiaddr::{AddrComponent, Multiaddr};
     use std::io::Error as IoError;
     use std::iter;
-    use std::sync::Arc;
+    use std::sync::{Arc, Mutex};
     use std::time::Duration;
 
     #[test]
@@ -384,4 +445,64 @@ mod tests {
 
         let _ = core.run(future).unwrap();
     }
+
+    #[test]
+    fn identify_info_stored() {
+        let public_key = PublicKey::Ed25519(vec![5, 6, 7]);
+        let info = IdentifyInfo {
+            public_key: public_key.clone(),
+            protocol_version: "proto_version".to_owned(),
+            agent_version: "agent_version".to_owned(),
+            listen_addrs: vec!["/ip4/80.81.82.83/tcp/500".parse().unwrap()],
+            protocols: vec!["/ipfs/ping/1.0.0".to_owned()],
+            signed_record: None,
+            metadata: Vec::new(),
+        };
+
+        let peerstore = MemoryPeerstore::empty();
+        let client_addr: Multiaddr = "/ip4/1.2.3.4/tcp/5".parse().unwrap();
+        let ttl = Duration::from_secs(3600);
+        let real_addr =
+            super::process_identify_info(&info, &peerstore, client_addr.clone(), ttl, None)
+                .unwrap();
+
+        let peer_id = public_key.to_peer_id();
+        assert_eq!(real_addr, AddrComponent::P2P(peer_id.clone().into_bytes()).into());
+        let peer = peerstore.peer(&peer_id).unwrap();
+        assert_eq!(peer.public_key(), Some(public_key.to_protobuf_encoding()));
+        assert_eq!(peer.protocols(), info.protocols);
+        let addrs = peer.addrs().collect::<Vec<_>>();
+        assert_eq!(addrs, vec![client_addr, info.listen_addrs[0].clone()]);
+    }
+
+    #[test]
+    fn protocol_changes_notified() {
+        let info = |protocols: Vec<&str>| IdentifyInfo {
+            public_key: PublicKey::Rsa(vec![1, 2, 3]),
+            protocol_version: "ipfs/1.0.0".to_owned(),
+            agent_version: "agent".to_owned(),
+            listen_addrs: vec![],
+            protocols: protocols.into_iter().map(|p| p.to_owned()).collect(),
+            signed_record: None,
+            metadata: Vec::new(),
+        };
+
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        let router = {
+            let statuses = statuses.clone();
+            ProtocolChangeRouter::new()
+                .on_protocol("/kad", move |_, status| statuses.lock().unwrap().push(status))
+        };
+
+        let peerstore = MemoryPeerstore::empty();
+        let client_addr: Multiaddr = "/ip4/1.2.3.4/tcp/5".parse().unwrap();
+        let ttl = Duration::from_secs(3600);
+        for protocols in vec![vec!["/kad"], vec!["/kad", "/other"], vec!["/other"]] {
+            super::process_identify_info(&info(protocols), &peerstore, client_addr.clone(), ttl,
+                                         Some(&router)).unwrap();
+        }
+
+        let statuses = statuses.lock().unwrap();
+        assert_eq!(*statuses, vec![ProtocolStatus::Added, ProtocolStatus::Removed]);
+    }
 }
@@ -0,0 +1,461 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+// TODO: use this once stable ; for now we just copy-paste the content of the README.md
+//#![doc(include = "../README.md")]
+
+//! Long-lived substreams on which a node sends notifications to a remote.
+//!
+//! Many protocols, such as the propagation of blocks or transactions in a blockchain, consist in
+//! each node pushing a stream of messages to its peers, without expecting an answer to each
+//! message. Opening a substream for each message is wasteful, and building a framed protocol on
+//! top of a raw upgrade every time is repetitive. This crate provides this building block.
+//!
+//! # Usage
+//!
+//! Create a `NotificationsConfig` with the name of your protocol and a handshake message, and use
+//! it as a connection upgrade. The handshake typically contains information, such as the best
+//! block of the node, that the remote needs before processing the notifications.
+//!
+//! - The dialer sends its handshake, then waits for the handshake of the listener. It then gets a
+//!   `NotificationsOut`, which is a `Sink` of notifications.
+//! - The listener waits for the handshake of the dialer, then sends its own. It then gets a
+//!   `NotificationsIn`, which is a `Stream` of notifications.
+//!
+//! The substream is unidirectional: only the dialer sends notifications. If both nodes want to
+//! notify each other, each of them opens its own substream. Open one substream for each remote
+//! and keep it open for as long as the connection is alive.
+//!
+//! Notifications are prefixed with their length, and notifications larger than the configured
+//! maximum are refused on both sides.
+//!
+//! # Backpressure
+//!
+//! `NotificationsOut` only buffers a small amount of data. Once its buffer is full, `start_send`
+//! returns `AsyncSink::NotReady` until the remote has read enough, which in turn happens only as
+//! fast as the remote polls its `NotificationsIn`. A slow remote therefore slows down the sender
+//! instead of making it buffer notifications indefinitely. Senders that prefer dropping
+//! notifications to waiting can do so when `start_send` returns `NotReady`.
+//!
+//! # Example
+//!
+//! ```no_run
+//! extern crate futures;
+//! extern crate libp2p_core;
+//! extern crate libp2p_notifications;
+//! extern crate libp2p_tcp_transport;
+//! extern crate tokio_core;
+//!
+//! use futures::{Future, Sink};
+//! use libp2p_core::Transport;
+//! use libp2p_notifications::{NotificationStream, NotificationsConfig};
+//!
+//! # fn main() {
+//! let mut core = tokio_core::reactor::Core::new().unwrap();
+//!
+//! let config = NotificationsConfig::new("/myapp/blocks/1", b"best block: 12".to_vec());
+//! let future = libp2p_tcp_transport::TcpConfig::new(core.handle())
+//!     .with_upgrade(config)
+//!     .dial("/ip4/127.0.0.1/tcp/12345".parse().unwrap()).unwrap_or_else(|_| panic!())
+//!     .and_then(|(stream, _)| match stream {
+//!         NotificationStream::Out(sink) => {
+//!             println!("remote handshake: {:?}", sink.remote_handshake());
+//!             sink.send(b"new block: 13".to_vec())
+//!         }
+//!         NotificationStream::In(_) => unreachable!("we are the dialer"),
+//!     });
+//!
+//! core.run(future).unwrap();
+//! # }
+//! ```
+
+extern crate bytes;
+extern crate futures;
+extern crate libp2p_core;
+extern crate tokio_io;
+extern crate varint;
+
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Sink, StartSend, Stream};
+use libp2p_core::{ConnectionUpgrade, Endpoint, LocalIdentity, Multiaddr};
+use std::cmp;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::iter;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use varint::VarintCodec;
+
+/// Default maximum size of a notification, in bytes.
+const DEFAULT_MAX_NOTIFICATION_SIZE: usize = 1024 * 1024;
+/// Default maximum size of a handshake, in bytes.
+const DEFAULT_MAX_HANDSHAKE_SIZE: usize = 16 * 1024;
+
+/// Configuration of a notifications protocol. Implements `ConnectionUpgrade`.
+#[derive(Debug, Clone)]
+pub struct NotificationsConfig {
+    protocol_name: Bytes,
+    handshake: Vec<u8>,
+    max_notification_size: usize,
+    max_handshake_size: usize,
+}
+
+impl NotificationsConfig {
+    /// Builds a configuration for the protocol of the given name. `handshake` is sent to the
+    /// remote when the substream is opened.
+    #[inline]
+    pub fn new<N>(protocol_name: N, handshake: Vec<u8>) -> NotificationsConfig
+    where
+        N: Into<Bytes>,
+    {
+        NotificationsConfig {
+            protocol_name: protocol_name.into(),
+            handshake: handshake,
+            max_notification_size: DEFAULT_MAX_NOTIFICATION_SIZE,
+            max_handshake_size: DEFAULT_MAX_HANDSHAKE_SIZE,
+        }
+    }
+
+    /// Sets the maximum size of a notification, in bytes. Defaults to 1 MiB.
+    ///
+    /// Receiving a larger notification is an error, and so is trying to send one.
+    #[inline]
+    pub fn with_max_notification_size(mut self, max: usize) -> NotificationsConfig {
+        self.max_notification_size = max;
+        self
+    }
+
+    /// Sets the maximum size of the handshake of the remote, in bytes. Defaults to 16 kiB.
+    #[inline]
+    pub fn with_max_handshake_size(mut self, max: usize) -> NotificationsConfig {
+        self.max_handshake_size = max;
+        self
+    }
+
+    /// Replaces the handshake that is sent to the remote. Useful when the handshake depends on
+    /// a state that changes over time, such as the best block of a chain.
+    #[inline]
+    pub fn set_handshake(&mut self, handshake: Vec<u8>) {
+        self.handshake = handshake;
+    }
+}
+
+impl<C> ConnectionUpgrade<C> for NotificationsConfig
+where
+    C: AsyncRead + AsyncWrite + 'static, // TODO: 'static :-/
+{
+    type NamesIter = iter::Once<(Bytes, ())>;
+    type UpgradeIdentifier = ();
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        iter::once((self.protocol_name.clone(), ()))
+    }
+
+    type Output = NotificationStream<C>;
+    type Future = Box<Future<Item = NotificationStream<C>, Error = IoError>>;
+
+    fn upgrade(
+        self,
+        socket: C,
+        _: (),
+        endpoint: Endpoint,
+        _: &Multiaddr,
+        _: &LocalIdentity,
+    ) -> Self::Future {
+        // The codec refuses frames that are larger than both limits, and the exact limit of
+        // each kind of frame is then checked once it has been received.
+        let max_frame_size = cmp::max(self.max_handshake_size, self.max_notification_size);
+        let socket = socket.framed(VarintCodec::with_max_len(max_frame_size));
+        let max_handshake_size = self.max_handshake_size;
+        let max_notification_size = self.max_notification_size;
+
+        match endpoint {
+            Endpoint::Dialer => {
+                let future = socket
+                    .send(self.handshake)
+                    .and_then(move |socket| receive_handshake(socket, max_handshake_size))
+                    .map(move |(remote_handshake, socket)| {
+                        NotificationStream::Out(NotificationsOut {
+                            inner: socket,
+                            remote_handshake: remote_handshake,
+                            max_notification_size: max_notification_size,
+                        })
+                    });
+                Box::new(future) as Box<_>
+            }
+            Endpoint::Listener => {
+                let handshake = self.handshake;
+                let future = receive_handshake(socket, max_handshake_size)
+                    .and_then(move |(remote_handshake, socket)| {
+                        socket
+                            .send(handshake)
+                            .map(move |socket| (remote_handshake, socket))
+                    })
+                    .map(move |(remote_handshake, socket)| {
+                        NotificationStream::In(NotificationsIn {
+                            inner: socket,
+                            remote_handshake: remote_handshake,
+                            max_notification_size: max_notification_size,
+                        })
+                    });
+                Box::new(future) as Box<_>
+            }
+        }
+    }
+}
+
+// Waits for the handshake of the remote.
+fn receive_handshake<C>(
+    socket: Framed<C, VarintCodec<Vec<u8>>>,
+    max_size: usize,
+) -> Box<Future<Item = (Vec<u8>, Framed<C, VarintCodec<Vec<u8>>>), Error = IoError>>
+where
+    C: AsyncRead + AsyncWrite + 'static, // TODO: 'static :-/
+{
+    let future = socket
+        .into_future()
+        .map_err(|(err, _)| err)
+        .and_then(move |(handshake, socket)| match handshake {
+            Some(handshake) => {
+                check_size(handshake.len(), max_size, "handshake")?;
+                Ok((handshake.to_vec(), socket))
+            }
+            None => Err(IoError::new(
+                IoErrorKind::UnexpectedEof,
+                "substream closed before the handshake was received",
+            )),
+        });
+    Box::new(future)
+}
+
+// Returns an error if a frame of `len` bytes is larger than `max`.
+fn check_size(len: usize, max: usize, what: &str) -> Result<(), IoError> {
+    if len > max {
+        let msg = format!("{} of {} bytes exceeds the maximum of {} bytes", what, len, max);
+        return Err(IoError::new(IoErrorKind::InvalidData, msg));
+    }
+
+    Ok(())
+}
+
+/// Output of the upgrade of a `NotificationsConfig`.
+pub enum NotificationStream<C> {
+    /// We opened the substream and can send notifications on it.
+    Out(NotificationsOut<C>),
+    /// The remote opened the substream and sends notifications on it.
+    In(NotificationsIn<C>),
+}
+
+impl<C> NotificationStream<C> {
+    /// Returns the handshake that the remote sent when the substream was opened.
+    #[inline]
+    pub fn remote_handshake(&self) -> &[u8] {
+        match *self {
+            NotificationStream::Out(ref out) => out.remote_handshake(),
+            NotificationStream::In(ref inbound) => inbound.remote_handshake(),
+        }
+    }
+}
+
+/// Sink of notifications to send to the remote.
+///
+/// Closing the sink closes the substream.
+pub struct NotificationsOut<C> {
+    inner: Framed<C, VarintCodec<Vec<u8>>>,
+    remote_handshake: Vec<u8>,
+    max_notification_size: usize,
+}
+
+impl<C> NotificationsOut<C> {
+    /// Returns the handshake that the remote sent when the substream was opened.
+    #[inline]
+    pub fn remote_handshake(&self) -> &[u8] {
+        &self.remote_handshake
+    }
+}
+
+impl<C> Sink for NotificationsOut<C>
+where
+    C: AsyncRead + AsyncWrite,
+{
+    type SinkItem = Vec<u8>;
+    type SinkError = IoError;
+
+    fn start_send(&mut self, notification: Vec<u8>) -> StartSend<Vec<u8>, IoError> {
+        check_size(notification.len(), self.max_notification_size, "notification")?;
+        self.inner.start_send(notification)
+    }
+
+    #[inline]
+    fn poll_complete(&mut self) -> Poll<(), IoError> {
+        self.inner.poll_complete()
+    }
+
+    #[inline]
+    fn close(&mut self) -> Poll<(), IoError> {
+        self.inner.close()
+    }
+}
+
+/// Stream of the notifications sent by the remote.
+///
+/// The stream ends when the remote closes the substream.
+pub struct NotificationsIn<C> {
+    inner: Framed<C, VarintCodec<Vec<u8>>>,
+    remote_handshake: Vec<u8>,
+    max_notification_size: usize,
+}
+
+impl<C> NotificationsIn<C> {
+    /// Returns the handshake that the remote sent when the substream was opened.
+    #[inline]
+    pub fn remote_handshake(&self) -> &[u8] {
+        &self.remote_handshake
+    }
+}
+
+impl<C> Stream for NotificationsIn<C>
+where
+    C: AsyncRead + AsyncWrite,
+{
+    type Item = Vec<u8>;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, IoError> {
+        match self.inner.poll()? {
+            Async::Ready(Some(notification)) => {
+                check_size(notification.len(), self.max_notification_size, "notification")?;
+                Ok(Async::Ready(Some(notification.to_vec())))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tokio_core;
+
+    use self::tokio_core::net::{TcpListener, TcpStream};
+    use self::tokio_core::reactor::Core;
+    use futures::{future, stream, Future, Sink, Stream};
+    use libp2p_core::{ConnectionUpgrade, Endpoint, LocalIdentity};
+    use std::io::Error as IoError;
+    use {NotificationStream, NotificationsConfig};
+
+    #[test]
+    fn handshake_then_notifications() {
+        let mut core = Core::new().unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e.into())
+            .and_then(|(c, _)| {
+                NotificationsConfig::new("/test/1", b"listener".to_vec()).upgrade(
+                    c.unwrap().0,
+                    (),
+                    Endpoint::Listener,
+                    &"/ip4/127.0.0.1/tcp/10000".parse().unwrap(),
+                    &LocalIdentity::unknown(),
+                )
+            })
+            .and_then(|stream| match stream {
+                NotificationStream::In(stream) => {
+                    assert_eq!(stream.remote_handshake(), b"dialer");
+                    stream.collect()
+                }
+                NotificationStream::Out(_) => panic!(),
+            });
+
+        let client = TcpStream::connect(&listener_addr, &core.handle())
+            .map_err(|e| e.into())
+            .and_then(|c| {
+                NotificationsConfig::new("/test/1", b"dialer".to_vec()).upgrade(
+                    c,
+                    (),
+                    Endpoint::Dialer,
+                    &"/ip4/127.0.0.1/tcp/10000".parse().unwrap(),
+                    &LocalIdentity::unknown(),
+                )
+            })
+            .and_then(|stream| match stream {
+                NotificationStream::Out(sink) => {
+                    assert_eq!(sink.remote_handshake(), b"listener");
+                    let notifications = vec![b"a".to_vec(), b"bc".to_vec()];
+                    sink.send_all(stream::iter_ok::<_, IoError>(notifications))
+                        .and_then(|(mut sink, _)| future::poll_fn(move || sink.close()))
+                }
+                NotificationStream::In(_) => panic!(),
+            });
+
+        let (received, ()) = core.run(server.join(client)).unwrap();
+        assert_eq!(received, vec![b"a".to_vec(), b"bc".to_vec()]);
+    }
+
+    #[test]
+    fn oversized_notification_refused() {
+        let mut core = Core::new().unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e.into())
+            .and_then(|(c, _)| {
+                NotificationsConfig::new("/test/1", Vec::new()).upgrade(
+                    c.unwrap().0,
+                    (),
+                    Endpoint::Listener,
+                    &"/ip4/127.0.0.1/tcp/10000".parse().unwrap(),
+                    &LocalIdentity::unknown(),
+                )
+            });
+
+        let client = TcpStream::connect(&listener_addr, &core.handle())
+            .map_err(|e| e.into())
+            .and_then(|c| {
+                NotificationsConfig::new("/test/1", Vec::new())
+                    .with_max_notification_size(4)
+                    .upgrade(
+                        c,
+                        (),
+                        Endpoint::Dialer,
+                        &"/ip4/127.0.0.1/tcp/10000".parse().unwrap(),
+                        &LocalIdentity::unknown(),
+                    )
+            })
+            .and_then(|stream| match stream {
+                NotificationStream::Out(sink) => sink.send(vec![0; 5]).then(|result| {
+                    assert!(result.is_err());
+                    Ok(())
+                }),
+                NotificationStream::In(_) => panic!(),
+            });
+
+        core.run(server.join(client)).unwrap();
+    }
+}
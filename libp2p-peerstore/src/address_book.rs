@@ -0,0 +1,354 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Export and import of the content of a peer store.
+//!
+//! An `AddressBook` is a snapshot of a peer store that can be written to disk or sent over the
+//! network, then loaded into another peer store. This is useful to seed a fresh node with the
+//! peers known by an existing one, or to inspect the state of the network while debugging.
+//!
+//! `AddressBook` implements `Serialize` and `Deserialize`, which means that it can be encoded with
+//! any serde format (for example CBOR with the `serde_cbor` crate). The `to_json` and `from_json`
+//! methods are provided for convenience.
+//!
+//! # Format
+//!
+//! Here is the JSON representation of an address book:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "peers": [
+//!     {
+//!       "peer_id": "QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ",
+//!       "addrs": [
+//!         { "addr": "/ip4/10.11.12.13/tcp/20000", "ttl": "permanent" },
+//!         { "addr": "/ip4/10.11.12.13/tcp/20001", "ttl": { "temporary": 3600 } }
+//!       ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! - `version` is always `1` for now. Importing an address book with another version fails.
+//! - `peer_id` is the base58 encoding of the bytes of the `PeerId`. Since the peer ID is the hash
+//!   of the public key of the peer, this is the only key information that a peer store holds.
+//! - `addr` is the string representation of the multiaddress.
+//! - `ttl` is either `"permanent"`, or `{ "temporary": <secs> }` where `<secs>` is the number of
+//!   seconds the address had left to live at the time of the export.
+//!
+//! # Example
+//!
+//! ```
+//! extern crate multiaddr;
+//! extern crate libp2p_peerstore;
+//!
+//! # fn main() {
+//! use libp2p_peerstore::memory_peerstore::MemoryPeerstore;
+//! use libp2p_peerstore::{AddressBook, PeerId, Peerstore, PeerAccess};
+//! use multiaddr::Multiaddr;
+//! use std::time::Duration;
+//!
+//! let original = MemoryPeerstore::empty();
+//! let peer_id = PeerId::from_public_key(&[1, 2, 3, 4]);
+//! let addr = "/ip4/10.11.12.13/tcp/20000".parse::<Multiaddr>().unwrap();
+//! original.peer_or_create(&peer_id).add_addr(addr.clone(), Duration::from_secs(3600));
+//!
+//! let json = AddressBook::export(&original).to_json();
+//!
+//! let new_node = MemoryPeerstore::empty();
+//! AddressBook::from_json(&json).unwrap().import(&new_node).unwrap();
+//! assert_eq!(new_node.peer(&peer_id).unwrap().addrs().collect::<Vec<_>>(), &[addr]);
+//! # }
+//! ```
+
+use base58::{FromBase58, ToBase58};
+use multiaddr::Multiaddr;
+use serde_json;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+use {PeerAccess, PeerId, Peerstore, TTL};
+
+/// Version of the format produced by `AddressBook::export`.
+const VERSION: u32 = 1;
+
+/// Snapshot of the content of a peer store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressBook {
+    /// Version of the format. Always 1 for now.
+    pub version: u32,
+    /// List of peers of the address book.
+    pub peers: Vec<AddressBookPeer>,
+}
+
+/// A peer in an `AddressBook`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressBookPeer {
+    /// Base58 encoding of the `PeerId`.
+    pub peer_id: String,
+    /// Addresses of the peer.
+    pub addrs: Vec<AddressBookAddr>,
+}
+
+/// An address in an `AddressBook`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressBookAddr {
+    /// String representation of the multiaddress.
+    pub addr: String,
+    /// How long the address has to live.
+    pub ttl: TtlClass,
+}
+
+/// How long an address of an `AddressBook` has to live.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TtlClass {
+    /// The address never expires.
+    ///
+    /// Addresses that have more than 50 years left to live are exported with this class. When
+    /// imported, they are given a time-to-live of 100 years.
+    Permanent,
+    /// The address expires after the given number of seconds.
+    Temporary(u64),
+}
+
+impl TtlClass {
+    /// Returns the class corresponding to the given time-to-live.
+    pub fn from_ttl(ttl: TTL) -> TtlClass {
+        if ttl >= Duration::from_secs(50 * 365 * 24 * 3600) {
+            TtlClass::Permanent
+        } else {
+            TtlClass::Temporary(ttl.as_secs())
+        }
+    }
+
+    /// Returns the time-to-live to use when importing an address of this class.
+    pub fn to_ttl(&self) -> TTL {
+        match *self {
+            TtlClass::Permanent => Duration::from_secs(100 * 365 * 24 * 3600),
+            TtlClass::Temporary(secs) => Duration::from_secs(secs),
+        }
+    }
+}
+
+impl AddressBook {
+    /// Builds an `AddressBook` containing all the peers of a peer store and their non-expired
+    /// addresses.
+    ///
+    /// Peers that don't have any non-expired address are omitted.
+    pub fn export<P>(peerstore: P) -> AddressBook
+    where
+        P: Peerstore + Clone,
+    {
+        let mut peers = Vec::new();
+
+        for peer_id in peerstore.clone().peers() {
+            let access = match peerstore.clone().peer(&peer_id) {
+                Some(access) => access,
+                None => continue, // The peer has been removed in the meanwhile.
+            };
+
+            let addrs = access
+                .addrs_ttl()
+                .map(|(addr, ttl)| AddressBookAddr {
+                    addr: addr.to_string(),
+                    ttl: TtlClass::from_ttl(ttl),
+                })
+                .collect::<Vec<_>>();
+
+            if addrs.is_empty() {
+                continue;
+            }
+
+            peers.push(AddressBookPeer {
+                peer_id: peer_id.as_bytes().to_base58(),
+                addrs: addrs,
+            });
+        }
+
+        // Sort the peers so that exporting the same peer store always gives the same result.
+        peers.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+
+        AddressBook {
+            version: VERSION,
+            peers: peers,
+        }
+    }
+
+    /// Adds the content of the address book to a peer store.
+    ///
+    /// The entire address book is validated before anything is written, so that the peer store
+    /// is left untouched if an error is returned. Addresses that the peer store already knows with
+    /// a longer time-to-live are left as they are.
+    pub fn import<P>(&self, peerstore: P) -> Result<(), AddressBookError>
+    where
+        P: Peerstore + Clone,
+    {
+        if self.version != VERSION {
+            return Err(AddressBookError::UnsupportedVersion(self.version));
+        }
+
+        let mut parsed = Vec::with_capacity(self.peers.len());
+        for peer in self.peers.iter() {
+            let peer_id = peer.peer_id
+                .from_base58()
+                .ok()
+                .and_then(|bytes| PeerId::from_bytes(bytes).ok())
+                .ok_or_else(|| AddressBookError::InvalidPeerId(peer.peer_id.clone()))?;
+
+            let mut addrs = Vec::with_capacity(peer.addrs.len());
+            for addr in peer.addrs.iter() {
+                let multiaddr = addr.addr
+                    .parse::<Multiaddr>()
+                    .map_err(|_| AddressBookError::InvalidMultiaddr(addr.addr.clone()))?;
+                addrs.push((multiaddr, addr.ttl.to_ttl()));
+            }
+
+            parsed.push((peer_id, addrs));
+        }
+
+        for (peer_id, addrs) in parsed {
+            let mut access = peerstore.clone().peer_or_create(&peer_id);
+            for (addr, ttl) in addrs {
+                access.add_addr(addr, ttl);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encodes the address book in JSON.
+    #[inline]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("serializing an AddressBook never fails")
+    }
+
+    /// Decodes an address book from JSON.
+    #[inline]
+    pub fn from_json(json: &str) -> Result<AddressBook, AddressBookError> {
+        serde_json::from_str(json).map_err(AddressBookError::Json)
+    }
+}
+
+/// Error that can happen when decoding or importing an `AddressBook`.
+#[derive(Debug)]
+pub enum AddressBookError {
+    /// The JSON couldn't be decoded.
+    Json(serde_json::Error),
+    /// The version of the address book is not supported.
+    UnsupportedVersion(u32),
+    /// A peer ID is not valid base58 or not a valid multihash.
+    InvalidPeerId(String),
+    /// An address is not a valid multiaddress.
+    InvalidMultiaddr(String),
+}
+
+impl fmt::Display for AddressBookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AddressBookError::Json(ref err) => write!(f, "invalid JSON: {}", err),
+            AddressBookError::UnsupportedVersion(v) => {
+                write!(f, "unsupported address book version: {}", v)
+            }
+            AddressBookError::InvalidPeerId(ref id) => write!(f, "invalid peer ID: {}", id),
+            AddressBookError::InvalidMultiaddr(ref addr) => {
+                write!(f, "invalid multiaddress: {}", addr)
+            }
+        }
+    }
+}
+
+impl Error for AddressBookError {
+    fn description(&self) -> &str {
+        match *self {
+            AddressBookError::Json(_) => "invalid JSON",
+            AddressBookError::UnsupportedVersion(_) => "unsupported address book version",
+            AddressBookError::InvalidPeerId(_) => "invalid peer ID",
+            AddressBookError::InvalidMultiaddr(_) => "invalid multiaddress",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            AddressBookError::Json(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {AddressBook, AddressBookError, PeerAccess, PeerId, Peerstore, TtlClass};
+    use memory_peerstore::MemoryPeerstore;
+    use multiaddr::Multiaddr;
+    use std::time::Duration;
+
+    #[test]
+    fn export_import_roundtrip() {
+        let original = MemoryPeerstore::empty();
+        let peer1 = PeerId::from_public_key(&[1, 2, 3, 4]);
+        let peer2 = PeerId::from_public_key(&[5, 6, 7, 8]);
+        let addr1 = "/ip4/1.2.3.4/tcp/1000".parse::<Multiaddr>().unwrap();
+        let addr2 = "/ip4/5.6.7.8/tcp/2000".parse::<Multiaddr>().unwrap();
+        original
+            .peer_or_create(&peer1)
+            .add_addr(addr1.clone(), Duration::from_secs(3600));
+        original
+            .peer_or_create(&peer2)
+            .add_addr(addr2.clone(), Duration::from_secs(200 * 365 * 24 * 3600));
+        // Peers without any address are not exported.
+        original.peer_or_create(&PeerId::from_public_key(&[9]));
+
+        let book = AddressBook::export(&original);
+        assert_eq!(book.peers.len(), 2);
+        let json = book.to_json();
+        let decoded = AddressBook::from_json(&json).unwrap();
+        assert_eq!(decoded, book);
+
+        let copy = MemoryPeerstore::empty();
+        decoded.import(&copy).unwrap();
+        let ttls = copy.peer(&peer2).unwrap().addrs_ttl().collect::<Vec<_>>();
+        assert_eq!(ttls.len(), 1);
+        assert_eq!(ttls[0].0, addr2);
+        assert_eq!(TtlClass::from_ttl(ttls[0].1), TtlClass::Permanent);
+        assert_eq!(copy.peer(&peer1).unwrap().addrs().collect::<Vec<_>>(), &[addr1]);
+    }
+
+    #[test]
+    fn import_is_atomic() {
+        let json = r#"{
+            "version": 1,
+            "peers": [
+                { "peer_id": "QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ",
+                  "addrs": [{ "addr": "/ip4/1.2.3.4/tcp/1000", "ttl": "permanent" }] },
+                { "peer_id": "QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ",
+                  "addrs": [{ "addr": "not a multiaddr", "ttl": { "temporary": 10 } }] }
+            ]
+        }"#;
+
+        let peerstore = MemoryPeerstore::empty();
+        match AddressBook::from_json(json).unwrap().import(&peerstore) {
+            Err(AddressBookError::InvalidMultiaddr(ref addr)) if addr == "not a multiaddr" => (),
+            _ => panic!(),
+        }
+        assert_eq!(peerstore.peers().count(), 0);
+    }
+}
@@ -30,6 +30,11 @@
 //! - `JsonPeerstore`: Stores the information in a single JSON file.
 //! - `MemoryPeerstore`: Stores the information in memory.
 //!
+//! The content of any peer store can be exported to and imported from an `AddressBook`, which is
+//! a snapshot in a documented JSON format. A `NodeSnapshot` additionally stores the external
+//! addresses and the bans of a node, so that it can be saved before a restart and restored
+//! afterwards.
+//!
 //! Note that the peerstore implementations do not consider information inside a peer store to be
 //! critical. In case of an error (eg. corrupted file, disk error, etc.) they will prefer to lose
 //! data rather than returning the error.
@@ -70,87 +75,29 @@
 extern crate base58;
 extern crate datastore;
 extern crate futures;
+extern crate libp2p_core;
 extern crate multiaddr;
-extern crate multihash;
 extern crate owning_ref;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
+extern crate tempfile;
 
-use std::fmt;
-use base58::ToBase58;
-
+pub use libp2p_core::PeerId;
+pub use self::address_book::{AddressBook, AddressBookAddr, AddressBookError, AddressBookPeer};
+pub use self::address_book::TtlClass;
 pub use self::peerstore::{PeerAccess, Peerstore};
+pub use self::snapshot::{BannedPeer, NodeSnapshot, RestoredState, SnapshotError};
 
 #[macro_use]
 mod peerstore_tests;
 
+mod address_book;
 pub mod json_peerstore;
 pub mod memory_peerstore;
 mod peerstore;
 mod peer_info;
+mod snapshot;
 
 pub type TTL = std::time::Duration;
-
-/// Identifier of a peer of the network.
-///
-/// The data is a multihash of the public key of the peer.
-// TODO: maybe keep things in decoded version?
-#[derive(Clone, PartialEq, Eq, Hash)]
-pub struct PeerId {
-    multihash: Vec<u8>,
-}
-
-impl fmt::Debug for PeerId {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "PeerId({})", self.multihash.to_base58())
-    }
-}
-
-impl PeerId {
-    /// Builds a `PeerId` from a public key.
-    #[inline]
-    pub fn from_public_key(public_key: &[u8]) -> PeerId {
-        let data = multihash::encode(multihash::Hash::SHA2256, public_key)
-            .expect("sha2-256 is always supported");
-        PeerId { multihash: data }
-    }
-
-    /// Checks whether `data` is a valid `PeerId`. If so, returns the `PeerId`. If not, returns
-    /// back the data as an error.
-    #[inline]
-    pub fn from_bytes(data: Vec<u8>) -> Result<PeerId, Vec<u8>> {
-        match multihash::decode(&data) {
-            Ok(_) => Ok(PeerId { multihash: data }),
-            Err(_) => Err(data),
-        }
-    }
-
-    /// Returns a raw bytes representation of this `PeerId`.
-    #[inline]
-    pub fn into_bytes(self) -> Vec<u8> {
-        self.multihash
-    }
-
-    /// Returns a raw bytes representation of this `PeerId`.
-    #[inline]
-    pub fn as_bytes(&self) -> &[u8] {
-        &self.multihash
-    }
-
-    /// Returns the raw bytes of the hash of this `PeerId`.
-    #[inline]
-    pub fn hash(&self) -> &[u8] {
-        let multihash::Multihash { digest, .. } =
-            multihash::decode(&self.multihash).expect("our inner value should always be valid");
-        digest
-    }
-
-    /// Checks whether the public key passed as parameter matches the public key of this `PeerId`.
-    pub fn is_public_key(&self, public_key: &[u8]) -> bool {
-        let multihash::Multihash { alg, .. } =
-            multihash::decode(&self.multihash).expect("our inner value should always be valid");
-        let compare = multihash::encode(alg, public_key).expect("unsupported multihash algorithm"); // TODO: what to do here?
-        compare == self.multihash
-    }
-}
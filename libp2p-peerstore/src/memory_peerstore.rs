@@ -87,12 +87,22 @@ pub struct MemoryPeerstoreAccess<'a>(
 
 impl<'a> PeerAccess for MemoryPeerstoreAccess<'a> {
     type AddrsIter = VecIntoIter<Multiaddr>;
+    type AddrsTtlIter = VecIntoIter<(Multiaddr, TTL)>;
 
     #[inline]
     fn addrs(&self) -> Self::AddrsIter {
         self.0.addrs().cloned().collect::<Vec<_>>().into_iter()
     }
 
+    #[inline]
+    fn addrs_ttl(&self) -> Self::AddrsTtlIter {
+        self.0
+            .addrs_ttl()
+            .map(|(addr, ttl)| (addr.clone(), ttl))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
     #[inline]
     fn add_addr(&mut self, addr: Multiaddr, ttl: TTL) {
         self.0
@@ -108,6 +118,26 @@ impl<'a> PeerAccess for MemoryPeerstoreAccess<'a> {
     fn clear_addrs(&mut self) {
         self.0.set_addrs(iter::empty());
     }
+
+    #[inline]
+    fn public_key(&self) -> Option<Vec<u8>> {
+        self.0.public_key().map(|key| key.to_vec())
+    }
+
+    #[inline]
+    fn set_public_key(&mut self, key: Vec<u8>) {
+        self.0.set_public_key(key);
+    }
+
+    #[inline]
+    fn protocols(&self) -> Vec<String> {
+        self.0.protocols().to_vec()
+    }
+
+    #[inline]
+    fn set_protocols(&mut self, protocols: Vec<String>) {
+        self.0.set_protocols(protocols);
+    }
 }
 
 #[cfg(test)]
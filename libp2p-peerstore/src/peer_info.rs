@@ -39,13 +39,21 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 pub struct PeerInfo {
     // Adresses, and the time at which they will be considered expired.
     addrs: Vec<(Multiaddr, SystemTime)>,
+    // Public key of the peer, in the protobuf encoding of the identify protocol.
+    public_key: Option<Vec<u8>>,
+    // Protocols that the peer supports.
+    protocols: Vec<String>,
 }
 
 impl PeerInfo {
     /// Builds a new empty `PeerInfo`.
     #[inline]
     pub fn new() -> PeerInfo {
-        PeerInfo { addrs: vec![] }
+        PeerInfo {
+            addrs: vec![],
+            public_key: None,
+            protocols: vec![],
+        }
     }
 
     /// Returns the list of the non-expired addresses stored in this `PeerInfo`.
@@ -61,6 +69,17 @@ impl PeerInfo {
         ))
     }
 
+    /// Returns the list of the non-expired addresses stored in this `PeerInfo`, alongside with
+    /// the time they still have to live.
+    // TODO: use -> impl Iterator eventually
+    #[inline]
+    pub fn addrs_ttl<'a>(&'a self) -> Box<Iterator<Item = (&'a Multiaddr, TTL)> + 'a> {
+        let now = SystemTime::now();
+        Box::new(self.addrs.iter().filter_map(move |&(ref addr, ref expires)| {
+            expires.duration_since(now).ok().map(|ttl| (addr, ttl))
+        }))
+    }
+
     /// Sets the list of addresses and their time-to-live.
     ///
     /// This removes all previously-stored addresses and replaces them with new ones.
@@ -94,6 +113,30 @@ impl PeerInfo {
 
         self.addrs.push((addr, expires));
     }
+
+    /// Returns the public key of the peer, if it is known.
+    #[inline]
+    pub fn public_key(&self) -> Option<&[u8]> {
+        self.public_key.as_ref().map(|key| &key[..])
+    }
+
+    /// Sets the public key of the peer.
+    #[inline]
+    pub fn set_public_key(&mut self, key: Vec<u8>) {
+        self.public_key = Some(key);
+    }
+
+    /// Returns the protocols that the peer supports.
+    #[inline]
+    pub fn protocols(&self) -> &[String] {
+        &self.protocols
+    }
+
+    /// Replaces the list of protocols that the peer supports.
+    #[inline]
+    pub fn set_protocols(&mut self, protocols: Vec<String>) {
+        self.protocols = protocols;
+    }
 }
 
 /// Behaviour of the `add_addr` function.
@@ -110,7 +153,7 @@ impl Serialize for PeerInfo {
     where
         S: Serializer,
     {
-        let mut s = serializer.serialize_struct("PeerInfo", 2)?;
+        let mut s = serializer.serialize_struct("PeerInfo", 3)?;
         s.serialize_field(
             "addrs",
             &self.addrs
@@ -131,6 +174,8 @@ impl Serialize for PeerInfo {
                 })
                 .collect::<Vec<_>>(),
         )?;
+        s.serialize_field("public_key", &self.public_key)?;
+        s.serialize_field("protocols", &self.protocols)?;
         s.end()
     }
 }
@@ -145,6 +190,11 @@ impl<'de> Deserialize<'de> for PeerInfo {
             #[derive(Deserialize)]
             struct Interm {
                 addrs: Vec<(String, u64)>,
+                // The fields below didn't exist in older files.
+                #[serde(default)]
+                public_key: Option<Vec<u8>>,
+                #[serde(default)]
+                protocols: Vec<String>,
             }
             Interm::deserialize(deserializer)?
         };
@@ -162,7 +212,11 @@ impl<'de> Deserialize<'de> for PeerInfo {
             out
         };
 
-        Ok(PeerInfo { addrs: addrs })
+        Ok(PeerInfo {
+            addrs: addrs,
+            public_key: interm.public_key,
+            protocols: interm.protocols,
+        })
     }
 }
 
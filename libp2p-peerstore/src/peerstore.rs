@@ -55,6 +55,8 @@ pub trait Peerstore {
 pub trait PeerAccess {
     /// Iterator returned by `addrs`.
     type AddrsIter: Iterator<Item = Multiaddr>;
+    /// Iterator returned by `addrs_ttl`.
+    type AddrsTtlIter: Iterator<Item = (Multiaddr, TTL)>;
 
     /// Returns all known and non-expired addresses for a given peer.
     ///
@@ -62,6 +64,10 @@ pub trait PeerAccess {
     /// >           the moment when you get them and the moment when you process them.
     fn addrs(&self) -> Self::AddrsIter;
 
+    /// Returns all known and non-expired addresses for a given peer, alongside with the time they
+    /// still have to live.
+    fn addrs_ttl(&self) -> Self::AddrsTtlIter;
+
     /// Adds an address to a peer.
     ///
     /// If the manager already has this address stored and with a longer TTL, then the operation
@@ -97,4 +103,17 @@ pub trait PeerAccess {
 
     /// Removes all previously stored addresses.
     fn clear_addrs(&mut self);
+
+    /// Returns the public key of the peer, if it is known.
+    fn public_key(&self) -> Option<Vec<u8>>;
+
+    /// Sets the public key of the peer. The format of the key is up to the user; the
+    /// *identify* protocol stores it in its protobuf encoding.
+    fn set_public_key(&mut self, key: Vec<u8>);
+
+    /// Returns the protocols that the peer supports, as last reported.
+    fn protocols(&self) -> Vec<String>;
+
+    /// Replaces the list of protocols that the peer supports.
+    fn set_protocols(&mut self, protocols: Vec<String>);
 }
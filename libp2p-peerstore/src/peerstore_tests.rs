@@ -128,5 +128,23 @@ macro_rules! peerstore_tests {
             thread::sleep(Duration::from_millis(2));
             assert_eq!(peer_store.peer(&peer_id).unwrap().addrs().count(), 1);
         }
+
+        #[test]
+        fn set_then_get_identity() {
+            $($stmt;)*
+            let peer_store = $create_peerstore;
+            let peer_id = PeerId::from_public_key(&[1, 2, 3]);
+            assert!(peer_store.peer_or_create(&peer_id).public_key().is_none());
+
+            {
+                let mut peer = peer_store.peer_or_create(&peer_id);
+                peer.set_public_key(vec![1, 2, 3]);
+                peer.set_protocols(vec!["/ipfs/ping/1.0.0".to_owned()]);
+            }
+
+            let peer = peer_store.peer(&peer_id).unwrap();
+            assert_eq!(peer.public_key(), Some(vec![1, 2, 3]));
+            assert_eq!(peer.protocols(), vec!["/ipfs/ping/1.0.0".to_owned()]);
+        }
     };
 }
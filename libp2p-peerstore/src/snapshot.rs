@@ -0,0 +1,450 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Snapshot of the state of a node that is worth keeping across restarts.
+//!
+//! A node that starts with an empty peer store needs to discover the network again, which can
+//! take minutes. A `NodeSnapshot` groups the state that makes a restarted node reach its usual
+//! connectivity quickly, and is stored in a single file:
+//!
+//! - The content of the peer store, as an `AddressBook`.
+//! - The addresses through which the node has been reached from the outside.
+//! - The `Blacklist` of banned IP ranges and multiaddress patterns.
+//! - The peers banned from the swarm, as returned by `PeerBans::banned_peers` in the
+//!   `libp2p-swarm` crate.
+//!
+//! A snapshot only contains information that can be rediscovered. Restoring an outdated
+//! snapshot is therefore harmless, apart from a few useless dialing attempts.
+//!
+//! # Format
+//!
+//! The file is a JSON object of the following form:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "address_book": { "version": 1, "peers": [] },
+//!   "external_addrs": ["/ip4/1.2.3.4/tcp/4001"],
+//!   "banned_ranges": ["10.0.0.0/8"],
+//!   "banned_patterns": ["/ip4/*/tcp/22"],
+//!   "banned_peers": [
+//!     { "peer_id": "QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ", "expires_in_secs": 600 }
+//!   ]
+//! }
+//! ```
+//!
+//! - `version` is always `1` for now. Loading a snapshot with another version fails.
+//! - `address_book` uses the format documented in the `AddressBook` struct.
+//! - `banned_ranges` and `banned_patterns` use the syntax of `IpRange` and `MultiaddrPattern`.
+//! - `peer_id` is the base58 encoding of the bytes of the `PeerId`, and `expires_in_secs` is the
+//!   number of seconds the ban had left to last when the snapshot was captured, or `null` if the
+//!   ban is permanent. The field is optional, so that the snapshots written before it existed
+//!   can still be loaded.
+//!
+//! # Example
+//!
+//! ```
+//! extern crate libp2p_core;
+//! extern crate libp2p_peerstore;
+//!
+//! # fn main() {
+//! use libp2p_core::Blacklist;
+//! use libp2p_peerstore::NodeSnapshot;
+//! use libp2p_peerstore::memory_peerstore::MemoryPeerstore;
+//!
+//! let peerstore = MemoryPeerstore::empty();
+//! let blacklist = Blacklist::new().deny_pattern("/ip4/*/tcp/22".parse().unwrap());
+//! let external_addr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+//!
+//! let json = NodeSnapshot::capture(&peerstore, vec![external_addr], &blacklist, Vec::new())
+//!     .to_json();
+//!
+//! // After the restart.
+//! let restored = NodeSnapshot::from_json(&json).unwrap().restore(&peerstore).unwrap();
+//! assert!(restored.blacklist.is_denied(&"/ip4/5.6.7.8/tcp/22".parse().unwrap()));
+//! assert_eq!(restored.external_addrs.len(), 1);
+//! # }
+//! ```
+
+use base58::{FromBase58, ToBase58};
+use libp2p_core::{Blacklist, IpRange, MultiaddrPattern};
+use multiaddr::Multiaddr;
+use serde_json;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+use {AddressBook, AddressBookError, PeerId, Peerstore};
+
+/// Version of the format produced by `NodeSnapshot::capture`.
+const VERSION: u32 = 1;
+
+/// Snapshot of the state of a node, meant to be written to a single file before a restart.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeSnapshot {
+    /// Version of the format. Always 1 for now.
+    pub version: u32,
+    /// Content of the peer store.
+    pub address_book: AddressBook,
+    /// String representations of the external addresses of the node.
+    pub external_addrs: Vec<String>,
+    /// String representations of the banned IP ranges.
+    pub banned_ranges: Vec<String>,
+    /// String representations of the banned multiaddress patterns.
+    pub banned_patterns: Vec<String>,
+    /// Peers banned from the swarm.
+    #[serde(default)]
+    pub banned_peers: Vec<BannedPeer>,
+}
+
+/// A peer banned from the swarm, in a `NodeSnapshot`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BannedPeer {
+    /// Base58 encoding of the `PeerId`.
+    pub peer_id: String,
+    /// Number of seconds the ban had left to last when the snapshot was captured, or `None` if
+    /// the ban is permanent.
+    pub expires_in_secs: Option<u64>,
+}
+
+/// State decoded by `NodeSnapshot::restore`, which must be passed back to the components that
+/// use it.
+#[derive(Debug, Clone)]
+pub struct RestoredState {
+    /// External addresses of the node.
+    pub external_addrs: Vec<Multiaddr>,
+    /// Banned IP ranges and multiaddress patterns, to pass to a `BlacklistTransport`.
+    pub blacklist: Blacklist,
+    /// Banned peers alongside with the duration of their ban, to pass to
+    /// `SwarmController::ban_peer`.
+    pub banned_peers: Vec<(PeerId, Option<Duration>)>,
+}
+
+impl NodeSnapshot {
+    /// Builds a snapshot from the current state of a node.
+    ///
+    /// `banned_peers` contains the banned peers alongside with the moment when their ban expires,
+    /// as returned by `PeerBans::banned_peers`. The bans that have already expired are ignored.
+    pub fn capture<P, I, B>(
+        peerstore: P,
+        external_addrs: I,
+        blacklist: &Blacklist,
+        banned_peers: B,
+    ) -> NodeSnapshot
+    where
+        P: Peerstore + Clone,
+        I: IntoIterator<Item = Multiaddr>,
+        B: IntoIterator<Item = (PeerId, Option<Instant>)>,
+    {
+        let now = Instant::now();
+        let mut banned_peers = banned_peers
+            .into_iter()
+            .filter_map(|(peer_id, expires)| {
+                let expires_in_secs = match expires {
+                    Some(expires) if expires <= now => return None,
+                    // Rounded up, so that a ban never ends earlier after a restore.
+                    Some(expires) => {
+                        let left = expires - now;
+                        Some(left.as_secs() + if left.subsec_nanos() > 0 { 1 } else { 0 })
+                    }
+                    None => None,
+                };
+                Some(BannedPeer {
+                    peer_id: peer_id.as_bytes().to_base58(),
+                    expires_in_secs: expires_in_secs,
+                })
+            })
+            .collect::<Vec<_>>();
+        banned_peers.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+
+        NodeSnapshot {
+            version: VERSION,
+            address_book: AddressBook::export(peerstore),
+            external_addrs: external_addrs.into_iter().map(|a| a.to_string()).collect(),
+            banned_ranges: blacklist.ranges().iter().map(|r| r.to_string()).collect(),
+            banned_patterns: blacklist.patterns().iter().map(|p| p.to_string()).collect(),
+            banned_peers: banned_peers,
+        }
+    }
+
+    /// Adds the peers of the snapshot to a peer store, and returns the rest of the state.
+    ///
+    /// The entire snapshot is validated before anything is written, so that the peer store is
+    /// left untouched if an error is returned.
+    pub fn restore<P>(&self, peerstore: P) -> Result<RestoredState, SnapshotError>
+    where
+        P: Peerstore + Clone,
+    {
+        if self.version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(self.version));
+        }
+
+        let mut external_addrs = Vec::with_capacity(self.external_addrs.len());
+        for addr in self.external_addrs.iter() {
+            let multiaddr = addr.parse::<Multiaddr>()
+                .map_err(|_| SnapshotError::InvalidMultiaddr(addr.clone()))?;
+            external_addrs.push(multiaddr);
+        }
+
+        let mut blacklist = Blacklist::new();
+        for range in self.banned_ranges.iter() {
+            let parsed = range.parse::<IpRange>()
+                .map_err(|_| SnapshotError::InvalidBan(range.clone()))?;
+            blacklist = blacklist.deny_range(parsed);
+        }
+        for pattern in self.banned_patterns.iter() {
+            let parsed = pattern.parse::<MultiaddrPattern>()
+                .map_err(|_| SnapshotError::InvalidBan(pattern.clone()))?;
+            blacklist = blacklist.deny_pattern(parsed);
+        }
+
+        let mut banned_peers = Vec::with_capacity(self.banned_peers.len());
+        for banned in self.banned_peers.iter() {
+            let peer_id = banned.peer_id
+                .from_base58()
+                .ok()
+                .and_then(|bytes| PeerId::from_bytes(bytes).ok())
+                .ok_or_else(|| SnapshotError::InvalidBan(banned.peer_id.clone()))?;
+            banned_peers.push((peer_id, banned.expires_in_secs.map(Duration::from_secs)));
+        }
+
+        // `import` validates the address book before writing anything.
+        self.address_book.import(peerstore).map_err(SnapshotError::AddressBook)?;
+
+        Ok(RestoredState {
+            external_addrs: external_addrs,
+            blacklist: blacklist,
+            banned_peers: banned_peers,
+        })
+    }
+
+    /// Encodes the snapshot in JSON.
+    #[inline]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("serializing a NodeSnapshot never fails")
+    }
+
+    /// Decodes a snapshot from JSON.
+    #[inline]
+    pub fn from_json(json: &str) -> Result<NodeSnapshot, SnapshotError> {
+        serde_json::from_str(json).map_err(SnapshotError::Json)
+    }
+
+    /// Writes the snapshot to `path`.
+    ///
+    /// The snapshot is first written to a temporary file in the same directory, which is then
+    /// moved to `path`. A crash in the middle of the operation therefore never leaves a truncated
+    /// snapshot behind.
+    pub fn save<P>(&self, path: P) -> Result<(), IoError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let parent = path.parent().ok_or(IoError::new(
+            IoErrorKind::Other,
+            "couldn't get parent directory of destination",
+        ))?;
+        let mut temporary_file = NamedTempFile::new_in(parent)?;
+        temporary_file.write_all(self.to_json().as_bytes())?;
+        temporary_file.sync_data()?;
+        temporary_file.persist(path)?;
+        Ok(())
+    }
+
+    /// Reads a snapshot written by `save`.
+    pub fn load<P>(path: P) -> Result<NodeSnapshot, SnapshotError>
+    where
+        P: AsRef<Path>,
+    {
+        let mut json = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut json))
+            .map_err(SnapshotError::Io)?;
+        NodeSnapshot::from_json(&json)
+    }
+}
+
+/// Error that can happen when loading or restoring a `NodeSnapshot`.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The file couldn't be read.
+    Io(IoError),
+    /// The JSON couldn't be decoded.
+    Json(serde_json::Error),
+    /// The version of the snapshot is not supported.
+    UnsupportedVersion(u32),
+    /// An external address is not a valid multiaddress.
+    InvalidMultiaddr(String),
+    /// A banned range, pattern or peer ID is not valid.
+    InvalidBan(String),
+    /// The address book is not valid.
+    AddressBook(AddressBookError),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::Io(ref err) => write!(f, "couldn't read the snapshot: {}", err),
+            SnapshotError::Json(ref err) => write!(f, "invalid JSON: {}", err),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version: {}", v)
+            }
+            SnapshotError::InvalidMultiaddr(ref addr) => {
+                write!(f, "invalid multiaddress: {}", addr)
+            }
+            SnapshotError::InvalidBan(ref ban) => write!(f, "invalid ban: {}", ban),
+            SnapshotError::AddressBook(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn description(&self) -> &str {
+        match *self {
+            SnapshotError::Io(_) => "couldn't read the snapshot",
+            SnapshotError::Json(_) => "invalid JSON",
+            SnapshotError::UnsupportedVersion(_) => "unsupported snapshot version",
+            SnapshotError::InvalidMultiaddr(_) => "invalid multiaddress",
+            SnapshotError::InvalidBan(_) => "invalid ban",
+            SnapshotError::AddressBook(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            SnapshotError::Io(ref err) => Some(err),
+            SnapshotError::Json(ref err) => Some(err),
+            SnapshotError::AddressBook(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {BannedPeer, NodeSnapshot, PeerAccess, PeerId, Peerstore, SnapshotError};
+    use libp2p_core::Blacklist;
+    use memory_peerstore::MemoryPeerstore;
+    use multiaddr::Multiaddr;
+    use std::time::{Duration, Instant};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn save_load_restore() {
+        let original = MemoryPeerstore::empty();
+        let peer_id = PeerId::from_public_key(&[1, 2, 3, 4]);
+        let addr = "/ip4/1.2.3.4/tcp/1000".parse::<Multiaddr>().unwrap();
+        original
+            .peer_or_create(&peer_id)
+            .add_addr(addr.clone(), Duration::from_secs(3600));
+        let external = "/ip4/5.6.7.8/tcp/4001".parse::<Multiaddr>().unwrap();
+        let blacklist = Blacklist::new()
+            .deny_range("10.0.0.0/8".parse().unwrap())
+            .deny_pattern("/ip4/*/tcp/22".parse().unwrap());
+
+        let banned = PeerId::from_public_key(&[5, 6, 7, 8]);
+        let bans = vec![(banned.clone(), None)];
+
+        let snapshot = NodeSnapshot::capture(&original, vec![external.clone()], &blacklist, bans);
+        let temp_file = NamedTempFile::new().unwrap();
+        snapshot.save(temp_file.path()).unwrap();
+        let loaded = NodeSnapshot::load(temp_file.path()).unwrap();
+        assert_eq!(loaded, snapshot);
+
+        let restarted = MemoryPeerstore::empty();
+        let restored = loaded.restore(&restarted).unwrap();
+        assert_eq!(restored.external_addrs, vec![external]);
+        assert!(restored.blacklist.is_denied(&"/ip4/10.1.2.3/tcp/1".parse().unwrap()));
+        assert!(restored.blacklist.is_denied(&"/ip4/1.2.3.4/tcp/22".parse().unwrap()));
+        assert_eq!(restored.banned_peers, vec![(banned, None)]);
+        assert_eq!(restarted.peer(&peer_id).unwrap().addrs().collect::<Vec<_>>(), &[addr]);
+    }
+
+    #[test]
+    fn invalid_ban_leaves_peerstore_untouched() {
+        let original = MemoryPeerstore::empty();
+        original
+            .peer_or_create(&PeerId::from_public_key(&[1, 2, 3, 4]))
+            .add_addr("/ip4/1.2.3.4/tcp/1000".parse().unwrap(), Duration::from_secs(3600));
+        let mut snapshot =
+            NodeSnapshot::capture(&original, Vec::new(), &Blacklist::new(), Vec::new());
+        snapshot.banned_ranges.push("10.0.0.0/33".to_owned());
+
+        let restarted = MemoryPeerstore::empty();
+        match snapshot.restore(&restarted) {
+            Err(SnapshotError::InvalidBan(ref ban)) if ban == "10.0.0.0/33" => (),
+            _ => panic!(),
+        }
+        assert_eq!(restarted.peers().count(), 0);
+    }
+
+    #[test]
+    fn temporary_bans() {
+        let peerstore = MemoryPeerstore::empty();
+        let active = PeerId::from_public_key(&[1, 2, 3, 4]);
+        let expired = PeerId::from_public_key(&[5, 6, 7, 8]);
+        let now = Instant::now();
+        let bans = vec![
+            (active.clone(), Some(now + Duration::from_millis(1500))),
+            (expired, Some(now - Duration::from_secs(1))),
+        ];
+
+        let snapshot = NodeSnapshot::capture(&peerstore, Vec::new(), &Blacklist::new(), bans);
+        let restored = NodeSnapshot::from_json(&snapshot.to_json())
+            .unwrap()
+            .restore(&peerstore)
+            .unwrap();
+        assert_eq!(restored.banned_peers, vec![(active, Some(Duration::from_secs(2)))]);
+    }
+
+    #[test]
+    fn missing_banned_peers() {
+        let json = r#"{
+            "version": 1,
+            "address_book": { "version": 1, "peers": [] },
+            "external_addrs": [],
+            "banned_ranges": [],
+            "banned_patterns": []
+        }"#;
+
+        let snapshot = NodeSnapshot::from_json(json).unwrap();
+        let restored = snapshot.restore(&MemoryPeerstore::empty()).unwrap();
+        assert!(restored.banned_peers.is_empty());
+    }
+
+    #[test]
+    fn invalid_banned_peer() {
+        let peerstore = MemoryPeerstore::empty();
+        let mut snapshot =
+            NodeSnapshot::capture(&peerstore, Vec::new(), &Blacklist::new(), Vec::new());
+        snapshot.banned_peers.push(BannedPeer {
+            peer_id: "not base58!".to_owned(),
+            expires_in_secs: None,
+        });
+
+        match snapshot.restore(&peerstore) {
+            Err(SnapshotError::InvalidBan(ref ban)) if ban == "not base58!" => (),
+            _ => panic!(),
+        }
+    }
+}
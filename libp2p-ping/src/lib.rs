@@ -34,6 +34,12 @@
 //! is a future that will process the data received on the socket and will be signalled only when
 //! the connection closes.
 //!
+//! # Deterministic payloads
+//!
+//! The payloads of the pings are normally generated by the operating system's random number
+//! generator. In simulations and tests, use `SeededPing` instead of `Ping` in order to generate
+//! them from a seed, so that a run can be replayed exactly.
+//!
 //! # About timeouts
 //!
 //! For technical reasons, this crate doesn't handle timeouts. The action of pinging returns a
@@ -54,20 +60,20 @@
 //! ```no_run
 //! extern crate futures;
 //! extern crate libp2p_ping;
-//! extern crate libp2p_swarm;
+//! extern crate libp2p_core;
 //! extern crate libp2p_tcp_transport;
 //! extern crate tokio_core;
 //!
 //! use futures::Future;
 //! use libp2p_ping::Ping;
-//! use libp2p_swarm::Transport;
+//! use libp2p_core::Transport;
 //!
 //! # fn main() {
 //! let mut core = tokio_core::reactor::Core::new().unwrap();
 //!
 //! let ping_finished_future = libp2p_tcp_transport::TcpConfig::new(core.handle())
 //!     .with_upgrade(Ping)
-//!     .dial("127.0.0.1:12345".parse::<libp2p_swarm::Multiaddr>().unwrap()).unwrap_or_else(|_| panic!())
+//!     .dial("127.0.0.1:12345".parse::<libp2p_core::Multiaddr>().unwrap()).unwrap_or_else(|_| panic!())
 //!     .and_then(|((mut pinger, service), _)| {
 //!         pinger.ping().map_err(|_| panic!()).select(service).map_err(|_| panic!())
 //!     });
@@ -80,7 +86,7 @@
 
 extern crate bytes;
 extern crate futures;
-extern crate libp2p_swarm;
+extern crate libp2p_core;
 #[macro_use]
 extern crate log;
 extern crate multistream_select;
@@ -92,14 +98,15 @@ use bytes::{BufMut, Bytes, BytesMut};
 use futures::{Future, Sink, Stream};
 use futures::future::{loop_fn, FutureResult, IntoFuture, Loop};
 use futures::sync::{mpsc, oneshot};
-use libp2p_swarm::Multiaddr;
-use libp2p_swarm::transport::{ConnectionUpgrade, Endpoint};
+use libp2p_core::Multiaddr;
+use libp2p_core::transport::{ConnectionUpgrade, Endpoint, LocalIdentity};
 use log::Level;
 use parking_lot::Mutex;
-use rand::Rand;
+use rand::{Rand, Rng, SeedableRng, XorShiftRng};
 use rand::os::OsRng;
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::io::Error as IoError;
 use std::iter;
 use std::sync::Arc;
@@ -135,106 +142,186 @@ where
         _: Self::UpgradeIdentifier,
         _: Endpoint,
         remote_addr: &Multiaddr,
+        _: &LocalIdentity,
     ) -> Self::Future {
-        // # How does it work?
-        //
-        // All the actual processing is performed by the *ponger*.
-        // We use a channel in order to send ping requests from the pinger to the ponger.
-
-        let (tx, rx) = mpsc::channel(8);
-        // Ignore the errors if `tx` closed. `tx` is only ever closed if the ponger is closed,
-        // which means that the connection to the remote is closed. Therefore we make the `rx`
-        // never produce anything.
-        let rx = rx.then(|r| Ok(r.ok())).filter_map(|a| a);
-
         let os_rng = match OsRng::new() {
             Ok(r) => r,
             Err(err) => return Err(err).into_future(),
         };
 
-        let pinger = Pinger {
-            send: tx,
-            os_rng: os_rng,
-        };
+        Ok(ping_upgrade(socket, remote_addr, Box::new(os_rng))).into_future()
+    }
+}
 
-        // Hashmap that associates outgoing payloads to one-shot senders.
-        // TODO: can't figure out how to make it work without using an Arc/Mutex
-        let expected_pongs = Arc::new(Mutex::new(HashMap::with_capacity(4)));
+/// Same as `Ping`, except that the payloads of the pings are generated from a seed instead of
+/// the random number generator of the operating system.
+///
+/// Each connection upgraded with a `SeededPing` (or with one of its clones) gets its own random
+/// number generator, derived from the seed and from the number of connections that have been
+/// upgraded before it.
+///
+/// > **Note**: If two nodes that ping each other use the same seed, they can produce the same
+/// >           payloads and mistake the pings of the remote for pongs. Give each node of a
+/// >           simulation a different seed.
+#[derive(Clone)]
+pub struct SeededPing {
+    rng: Arc<Mutex<XorShiftRng>>,
+}
+
+impl SeededPing {
+    /// Builds a new `SeededPing` from the given seed.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the seed is only made of zeroes.
+    #[inline]
+    pub fn new(seed: [u32; 4]) -> SeededPing {
+        SeededPing {
+            rng: Arc::new(Mutex::new(XorShiftRng::from_seed(seed))),
+        }
+    }
 
-        let sink_stream = socket
-            .framed(Codec)
-            .map(|msg| Message::Received(msg.freeze()));
-        let (sink, stream) = sink_stream.split();
+    // Builds the random number generator of the next connection.
+    #[inline]
+    fn next_rng(&self) -> XorShiftRng {
+        self.rng.lock().gen()
+    }
+}
 
-        let remote_addr = if log_enabled!(target: "libp2p-ping", Level::Debug) {
-            Some(remote_addr.clone())
-        } else {
-            None
-        };
+impl fmt::Debug for SeededPing {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SeededPing").finish()
+    }
+}
 
-        let future = loop_fn((sink, stream.select(rx)), move |(sink, stream)| {
-            let expected_pongs = expected_pongs.clone();
-            let remote_addr = remote_addr.clone();
-
-            stream
-                .into_future()
-                .map_err(|(err, _)| err)
-                .and_then(move |(message, stream)| {
-                    let mut expected_pongs = expected_pongs.lock();
-
-                    if let Some(message) = message {
-                        match message {
-                            Message::Ping(payload, finished) => {
-                                // Ping requested by the user through the `Pinger`.
-                                debug!(target: "libp2p-ping", "Sending ping to {:?} with payload {:?}",
+impl<C> ConnectionUpgrade<C> for SeededPing
+where
+    C: AsyncRead + AsyncWrite + 'static,
+{
+    type NamesIter = iter::Once<(Bytes, Self::UpgradeIdentifier)>;
+    type UpgradeIdentifier = ();
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        ConnectionUpgrade::<C>::protocol_names(&Ping)
+    }
+
+    type Output = (Pinger, Box<Future<Item = (), Error = IoError>>);
+    type Future = FutureResult<Self::Output, IoError>;
+
+    #[inline]
+    fn upgrade(
+        self,
+        socket: C,
+        _: Self::UpgradeIdentifier,
+        _: Endpoint,
+        remote_addr: &Multiaddr,
+        _: &LocalIdentity,
+    ) -> Self::Future {
+        Ok(ping_upgrade(socket, remote_addr, Box::new(self.next_rng()))).into_future()
+    }
+}
+
+// Upgrades `socket` to the ping protocol. The payloads of the pings are generated with `rng`.
+fn ping_upgrade<C>(
+    socket: C,
+    remote_addr: &Multiaddr,
+    rng: Box<Rng + Send>,
+) -> (Pinger, Box<Future<Item = (), Error = IoError>>)
+where
+    C: AsyncRead + AsyncWrite + 'static,
+{
+    // # How does it work?
+    //
+    // All the actual processing is performed by the *ponger*.
+    // We use a channel in order to send ping requests from the pinger to the ponger.
+
+    let (tx, rx) = mpsc::channel(8);
+    // Ignore the errors if `tx` closed. `tx` is only ever closed if the ponger is closed,
+    // which means that the connection to the remote is closed. Therefore we make the `rx`
+    // never produce anything.
+    let rx = rx.then(|r| Ok(r.ok())).filter_map(|a| a);
+
+    let pinger = Pinger { send: tx, rng: rng };
+
+    // Hashmap that associates outgoing payloads to one-shot senders.
+    // TODO: can't figure out how to make it work without using an Arc/Mutex
+    let expected_pongs = Arc::new(Mutex::new(HashMap::with_capacity(4)));
+
+    let sink_stream = socket
+        .framed(Codec)
+        .map(|msg| Message::Received(msg.freeze()));
+    let (sink, stream) = sink_stream.split();
+
+    let remote_addr = if log_enabled!(target: "libp2p-ping", Level::Debug) {
+        Some(remote_addr.clone())
+    } else {
+        None
+    };
+
+    let future = loop_fn((sink, stream.select(rx)), move |(sink, stream)| {
+        let expected_pongs = expected_pongs.clone();
+        let remote_addr = remote_addr.clone();
+
+        stream
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(move |(message, stream)| {
+                let mut expected_pongs = expected_pongs.lock();
+
+                if let Some(message) = message {
+                    match message {
+                        Message::Ping(payload, finished) => {
+                            // Ping requested by the user through the `Pinger`.
+                            debug!(target: "libp2p-ping", "Sending ping to {:?} with payload {:?}",
+                               remote_addr.expect("debug log level is enabled"), payload);
+                            expected_pongs.insert(payload.clone(), finished);
+                            Box::new(
+                                sink.send(payload)
+                                    .map(|sink| Loop::Continue((sink, stream))),
+                            )
+                                as Box<Future<Item = _, Error = _>>
+                        }
+                        Message::Received(payload) => {
+                            // Received a payload from the remote.
+                            if let Some(fut) = expected_pongs.remove(&payload) {
+                                // Payload was ours. Signalling future.
+                                // Errors can happen if the user closed the receiving end of
+                                // the future, which is fine to ignore.
+                                debug!(target: "libp2p-ping", "Received pong from {:?} \
+                                                           (payload={:?}) ; ping fufilled",
+                                   remote_addr.expect("debug log level is enabled"), payload);
+                                let _ = fut.send(());
+                                Box::new(Ok(Loop::Continue((sink, stream))).into_future())
+                                    as Box<Future<Item = _, Error = _>>
+                            } else {
+                                // Payload was not ours. Sending it back.
+                                debug!(target: "libp2p-ping", "Received ping from {:?} \
+                                                           (payload={:?}) ; sending back",
                                    remote_addr.expect("debug log level is enabled"), payload);
-                                expected_pongs.insert(payload.clone(), finished);
                                 Box::new(
                                     sink.send(payload)
                                         .map(|sink| Loop::Continue((sink, stream))),
                                 )
                                     as Box<Future<Item = _, Error = _>>
                             }
-                            Message::Received(payload) => {
-                                // Received a payload from the remote.
-                                if let Some(fut) = expected_pongs.remove(&payload) {
-                                    // Payload was ours. Signalling future.
-                                    // Errors can happen if the user closed the receiving end of
-                                    // the future, which is fine to ignore.
-                                    debug!(target: "libp2p-ping", "Received pong from {:?} \
-                                                               (payload={:?}) ; ping fufilled",
-                                       remote_addr.expect("debug log level is enabled"), payload);
-                                    let _ = fut.send(());
-                                    Box::new(Ok(Loop::Continue((sink, stream))).into_future())
-                                        as Box<Future<Item = _, Error = _>>
-                                } else {
-                                    // Payload was not ours. Sending it back.
-                                    debug!(target: "libp2p-ping", "Received ping from {:?} \
-                                                               (payload={:?}) ; sending back",
-                                       remote_addr.expect("debug log level is enabled"), payload);
-                                    Box::new(
-                                        sink.send(payload)
-                                            .map(|sink| Loop::Continue((sink, stream))),
-                                    )
-                                        as Box<Future<Item = _, Error = _>>
-                                }
-                            }
                         }
-                    } else {
-                        Box::new(Ok(Loop::Break(())).into_future())
-                            as Box<Future<Item = _, Error = _>>
                     }
-                })
-        });
+                } else {
+                    Box::new(Ok(Loop::Break(())).into_future())
+                        as Box<Future<Item = _, Error = _>>
+                }
+            })
+    });
 
-        Ok((pinger, Box::new(future) as Box<_>)).into_future()
-    }
+    (pinger, Box::new(future) as Box<_>)
 }
 
 /// Controller for the ping service. Makes it possible to send pings to the remote.
 pub struct Pinger {
     send: mpsc::Sender<Message>,
-    os_rng: OsRng,
+    rng: Box<Rng + Send>,
 }
 
 impl Pinger {
@@ -244,7 +331,7 @@ impl Pinger {
     ///           timeout yourself when you call this function.
     pub fn ping(&mut self) -> Box<Future<Item = (), Error = Box<Error + Send + Sync>>> {
         let (tx, rx) = oneshot::channel();
-        let payload: [u8; 32] = Rand::rand(&mut self.os_rng);
+        let payload: [u8; 32] = Rand::rand(&mut self.rng);
         debug!(target: "libp2p-ping", "Preparing for ping with payload {:?}", payload);
         // Ignore errors if the ponger has been already destroyed. The returned future will never
         // be signalled.
@@ -301,11 +388,12 @@ mod tests {
     use self::tokio_core::net::TcpListener;
     use self::tokio_core::net::TcpStream;
     use self::tokio_core::reactor::Core;
-    use super::Ping;
+    use super::{Ping, SeededPing};
     use futures::future::join_all;
     use futures::Future;
     use futures::Stream;
-    use libp2p_swarm::transport::{ConnectionUpgrade, Endpoint};
+    use libp2p_core::transport::{ConnectionUpgrade, Endpoint, LocalIdentity};
+    use rand::Rng;
 
     #[test]
     fn ping_pong() {
@@ -324,6 +412,7 @@ mod tests {
                     (),
                     Endpoint::Listener,
                     &"/ip4/127.0.0.1/tcp/10000".parse().unwrap(),
+                    &LocalIdentity::unknown(),
                 )
             })
             .and_then(|(mut pinger, service)| {
@@ -342,6 +431,7 @@ mod tests {
                     (),
                     Endpoint::Dialer,
                     &"/ip4/127.0.0.1/tcp/10000".parse().unwrap(),
+                    &LocalIdentity::unknown(),
                 )
             })
             .and_then(|(mut pinger, service)| {
@@ -373,6 +463,7 @@ mod tests {
                     (),
                     Endpoint::Listener,
                     &"/ip4/127.0.0.1/tcp/10000".parse().unwrap(),
+                    &LocalIdentity::unknown(),
                 )
             })
             .and_then(|(_, service)| service.map_err(|_| panic!()));
@@ -385,6 +476,7 @@ mod tests {
                     (),
                     Endpoint::Dialer,
                     &"/ip4/127.0.0.1/tcp/1000".parse().unwrap(),
+                    &LocalIdentity::unknown(),
                 )
             })
             .and_then(|(mut pinger, service)| {
@@ -400,4 +492,67 @@ mod tests {
 
         core.run(server.select(client)).unwrap_or_else(|_| panic!());
     }
+
+    #[test]
+    fn seeded_ping_is_reproducible() {
+        let a = SeededPing::new([1, 2, 3, 4]);
+        let b = SeededPing::new([1, 2, 3, 4]);
+        let c = SeededPing::new([5, 6, 7, 8]);
+
+        let mut a1 = a.next_rng();
+        let mut a2 = a.clone().next_rng();
+        let mut b1 = b.next_rng();
+        let mut b2 = b.next_rng();
+        let mut c1 = c.next_rng();
+
+        let a1 = a1.gen::<[u8; 32]>();
+        assert_eq!(a1, b1.gen::<[u8; 32]>());
+        assert_eq!(a2.gen::<[u8; 32]>(), b2.gen::<[u8; 32]>());
+        assert_ne!(a1, c1.gen::<[u8; 32]>());
+    }
+
+    #[test]
+    fn seeded_ping_pong() {
+        let mut core = Core::new().unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e.into())
+            .and_then(|(c, _)| {
+                SeededPing::new([1, 1, 1, 1]).upgrade(
+                    c.unwrap().0,
+                    (),
+                    Endpoint::Listener,
+                    &"/ip4/127.0.0.1/tcp/10000".parse().unwrap(),
+                    &LocalIdentity::unknown(),
+                )
+            })
+            .and_then(|(_, service)| service.map_err(|_| panic!()));
+
+        let client = TcpStream::connect(&listener_addr, &core.handle())
+            .map_err(|e| e.into())
+            .and_then(|c| {
+                SeededPing::new([2, 2, 2, 2]).upgrade(
+                    c,
+                    (),
+                    Endpoint::Dialer,
+                    &"/ip4/127.0.0.1/tcp/10000".parse().unwrap(),
+                    &LocalIdentity::unknown(),
+                )
+            })
+            .and_then(|(mut pinger, service)| {
+                pinger
+                    .ping()
+                    .map_err(|_| panic!())
+                    .select(service)
+                    .map(|_| ())
+                    .map_err(|_| panic!())
+            });
+
+        core.run(server.select(client)).unwrap_or_else(|_| panic!());
+    }
 }
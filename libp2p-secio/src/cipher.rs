@@ -0,0 +1,89 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Information about the cipher used by a secio connection.
+//!
+//! The AES implementation of `rust-crypto` uses the AES-NI instructions when the CPU supports
+//! them, and a constant-time software implementation otherwise. The check is performed at
+//! runtime. The structs of this module report which implementation is in use, so that it can be
+//! verified on deployed nodes.
+
+use crypto::aes::KeySize;
+
+/// Cipher and implementation used by a secio connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CipherInfo {
+    /// The cipher negotiated with the remote.
+    pub cipher: Cipher,
+    /// The implementation of this cipher running on the local machine.
+    pub implementation: CipherImplementation,
+}
+
+/// Cipher negotiated during a secio handshake.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Cipher {
+    /// AES with a 128 bits key in CTR mode.
+    Aes128,
+    /// AES with a 256 bits key in CTR mode.
+    Aes256,
+}
+
+/// Implementation of the cipher running on the local machine.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CipherImplementation {
+    /// Hardware-accelerated with the AES-NI instructions.
+    AesNi,
+    /// Portable software implementation.
+    Software,
+}
+
+/// Builds the `CipherInfo` corresponding to a cipher chosen during the handshake.
+pub fn cipher_info(key_size: KeySize) -> CipherInfo {
+    let cipher = match key_size {
+        KeySize::KeySize128 => Cipher::Aes128,
+        KeySize::KeySize256 => Cipher::Aes256,
+        KeySize::KeySize192 => unreachable!("AES-192 is never negotiated by secio"),
+    };
+
+    CipherInfo {
+        cipher: cipher,
+        implementation: CipherImplementation::detect(),
+    }
+}
+
+impl CipherImplementation {
+    /// Returns the implementation of AES that `rust-crypto` uses on this machine.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn detect() -> CipherImplementation {
+        if ::crypto::util::supports_aesni() {
+            CipherImplementation::AesNi
+        } else {
+            CipherImplementation::Software
+        }
+    }
+
+    /// Returns the implementation of AES that `rust-crypto` uses on this machine.
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    #[inline]
+    pub fn detect() -> CipherImplementation {
+        // `rust-crypto` only has hardware acceleration for x86 and x86_64.
+        CipherImplementation::Software
+    }
+}
@@ -25,6 +25,7 @@ use crypto::aes::{ctr, KeySize};
 use error::SecioError;
 use futures::Future;
 use futures::future;
+use futures_cpupool::CpuPool;
 use futures::sink::Sink;
 use futures::stream::Stream;
 use keys_proto::{KeyType as KeyTypeProtobuf, PublicKey as PublicKeyProtobuf};
@@ -52,12 +53,18 @@ use untrusted::Input as UntrustedInput;
 /// be paired with `local_public_key`. Any mismatch somewhere will produce a `SecioError`.
 ///
 /// On success, returns an object that implements the `Sink` and `Stream` trait whose items are
-/// buffers of data, plus the public key of the remote.
+/// buffers of data, plus the public key of the remote, plus our local nonce, plus the chosen
+/// cipher. The first frame produced by the returned codec is expected to be equal to this nonce,
+/// and it is the responsibility of the caller to check it.
+///
+/// If `cpu_pool` is `Some`, the RSA signature of our proposition and the verification of the
+/// remote's signature are performed on this pool instead of the current thread.
 pub fn handshake<'a, S: 'a>(
     socket: S,
     local_public_key: Vec<u8>,
     local_private_key: Arc<RSAKeyPair>,
-) -> Box<Future<Item = (FullCodec<S>, Vec<u8>), Error = SecioError> + 'a>
+    cpu_pool: Option<CpuPool>,
+) -> Box<Future<Item = (FullCodec<S>, Vec<u8>, [u8; 16], KeySize), Error = SecioError> + 'a>
 where
     S: AsyncRead + AsyncWrite,
 {
@@ -134,6 +141,9 @@ where
         .length_field_length(4)
         .new_framed(socket);
 
+    let sign_pool = cpu_pool.clone();
+    let verify_pool = cpu_pool;
+
     let future = future::ok::<_, SecioError>(context)
         // Generate our nonce.
         .and_then(|mut context| {
@@ -295,46 +305,51 @@ where
 
         // Send the ephemeral pub key to the remote in an `Exchange` struct. The `Exchange` also
         // contains a signature of the two propositions encoded with our static public key.
-        .and_then(|(socket, mut context, tmp_priv)| {
-            let exchange = {
-                let local_tmp_pub_key = &mut context.local_tmp_pub_key[..tmp_priv.public_key_len()];
-                tmp_priv.compute_public_key(local_tmp_pub_key).unwrap();
-                context.local_tmp_priv_key = Some(tmp_priv);
+        .and_then(move |(socket, mut context, tmp_priv)| {
+            let local_tmp_pub_key = {
+                let key = &mut context.local_tmp_pub_key[..tmp_priv.public_key_len()];
+                tmp_priv.compute_public_key(key).unwrap();
+                key.to_vec()
+            };
+            context.local_tmp_priv_key = Some(tmp_priv);
+
+            let mut data_to_sign = context.local_proposition_bytes.clone();
+            data_to_sign.extend_from_slice(&context.remote_proposition_bytes);
+            data_to_sign.extend_from_slice(&local_tmp_pub_key);
+
+            let private_key = context.local_private_key.clone();
+            let signature = run_crypto(&sign_pool, move || {
+                let mut state = match RSASigningState::new(private_key.clone()) {
+                    Ok(s) => s,
+                    Err(_) => {
+                        debug!(target: "libp2p-secio", "failed to sign local exchange");
+                        return Err(SecioError::SigningFailure);
+                    },
+                };
+                let mut signature = vec![0; private_key.public_modulus_len()];
+                match state.sign(&RSA_PKCS1_SHA256, &rand::SystemRandom::new(), &data_to_sign,
+                                 &mut signature)
+                {
+                    Ok(_) => (),
+                    Err(_) => {
+                        debug!(target: "libp2p-secio", "failed to sign local exchange");
+                        return Err(SecioError::SigningFailure);
+                    },
+                };
 
-                let mut data_to_sign = context.local_proposition_bytes.clone();
-                data_to_sign.extend_from_slice(&context.remote_proposition_bytes);
-                data_to_sign.extend_from_slice(local_tmp_pub_key);
+                Ok(signature)
+            });
 
+            signature.map(move |signature| {
                 let mut exchange = Exchange::new();
-                exchange.set_epubkey(local_tmp_pub_key.to_vec());
-                exchange.set_signature({
-                    let mut state = match RSASigningState::new(context.local_private_key.clone()) {
-                        Ok(s) => s,
-                        Err(_) => {
-                            debug!(target: "libp2p-secio", "failed to sign local exchange");
-                            return Err(SecioError::SigningFailure);
-                        },
-                    };
-                    let mut signature = vec![0; context.local_private_key.public_modulus_len()];
-                    match state.sign(&RSA_PKCS1_SHA256, &context.rng, &data_to_sign,
-                                     &mut signature)
-                    {
-                        Ok(_) => (),
-                        Err(_) => {
-                            debug!(target: "libp2p-secio", "failed to sign local exchange");
-                            return Err(SecioError::SigningFailure);
-                        },
-                    };
-
-                    signature
-                });
-                exchange
-            };
-
-            let local_exch = exchange.write_to_bytes()
-                .expect("can only fail if the protobuf msg is malformed, which can't happen for \
-                         this message in particular");
-            Ok((BytesMut::from(local_exch), socket, context))
+                exchange.set_epubkey(local_tmp_pub_key);
+                exchange.set_signature(signature);
+
+                let local_exch = exchange.write_to_bytes()
+                    .expect("can only fail if the protobuf msg is malformed, which can't happen \
+                             for this message in particular");
+                (BytesMut::from(local_exch), socket, context)
+            })
         })
 
         // Send our local `Exchange`.
@@ -377,28 +392,34 @@ where
         // Check the validity of the remote's `Exchange`. This verifies that the remote was really
         // the sender of its proposition, and that it is the owner of both its global and ephemeral
         // keys.
-        .and_then(|(remote_exch, socket, context)| {
-            let mut data_to_verify = context.remote_proposition_bytes.clone();
+        .and_then(move |(remote_exch, socket, context)| {
+            let mut data_to_verify = context.remote_proposition_bytes.to_vec();
             data_to_verify.extend_from_slice(&context.local_proposition_bytes);
             data_to_verify.extend_from_slice(remote_exch.get_epubkey());
 
-            // TODO: The ring library doesn't like some stuff in our DER public key, therefore
-            //       we scrap the first 24 bytes of the key. A proper fix would be to write a DER
-            //       parser, but that's not trivial.
-            match signature_verify(&RSA_PKCS1_2048_8192_SHA256,
-                                UntrustedInput::from(&context.remote_public_key[24..]),
-                                UntrustedInput::from(&data_to_verify),
-                                UntrustedInput::from(remote_exch.get_signature()))
-            {
-                Ok(()) => (),
-                Err(_) => {
-                    debug!(target: "libp2p-secio", "failed to verify the remote's signature");
-                    return Err(SecioError::SignatureVerificationFailed)
-                },
-            }
+            let remote_public_key = context.remote_public_key.clone();
+            let signature = remote_exch.get_signature().to_vec();
+            let verification = run_crypto(&verify_pool, move || {
+                // TODO: The ring library doesn't like some stuff in our DER public key, therefore
+                //       we scrap the first 24 bytes of the key. A proper fix would be to write a
+                //       DER parser, but that's not trivial.
+                match signature_verify(&RSA_PKCS1_2048_8192_SHA256,
+                                    UntrustedInput::from(&remote_public_key[24..]),
+                                    UntrustedInput::from(&data_to_verify),
+                                    UntrustedInput::from(&signature))
+                {
+                    Ok(()) => Ok(()),
+                    Err(_) => {
+                        debug!(target: "libp2p-secio", "failed to verify the remote's signature");
+                        Err(SecioError::SignatureVerificationFailed)
+                    },
+                }
+            });
 
-            trace!(target: "libp2p-secio", "successfully verified the remote's signature");
-            Ok((remote_exch, socket, context))
+            verification.map(move |()| {
+                trace!(target: "libp2p-secio", "successfully verified the remote's signature");
+                (remote_exch, socket, context)
+            })
         })
 
         // Generate a key from the local ephemeral private key and the remote ephemeral public key,
@@ -470,31 +491,34 @@ where
                 .from_err()
         })
 
-        // Check that the received nonce is correct.
-        .and_then(|(codec, context)| {
-            codec.into_future()
-                .map_err(|(e, _)| e)
-                .and_then(move |(nonce, rest)| {
-                    match nonce {
-                        Some(ref n) if n == &context.local_nonce => {
-                            trace!(target: "libp2p-secio", "secio handshake success");
-                            Ok((rest, context.remote_public_key))
-                        },
-                        None => {
-                            debug!(target: "libp2p-secio", "unexpected eof during nonce check");
-                            Err(IoError::new(IoErrorKind::BrokenPipe, "unexpected eof").into())
-                        },
-                        _ => {
-                            debug!(target: "libp2p-secio", "failed nonce verification with remote");
-                            Err(SecioError::NonceVerificationFailed)
-                        }
-                    }
-                })
+        // We don't wait for the remote to send back our own nonce. Instead the first frame
+        // produced by the codec must be our nonce, which is checked by `SecioMiddleware` on the
+        // first read. This allows the upper layers (eg. the multistream-select negotiation of the
+        // muxer) to send their first message within the same flight as the nonce, saving a
+        // round-trip. A remote that waits for the nonce check before sending anything isn't
+        // affected.
+        .map(|(codec, context)| {
+            trace!(target: "libp2p-secio", "secio handshake success ; nonce check pending");
+            let cipher = context.chosen_cipher
+                .expect("we filled this Option earlier, and extract it now");
+            (codec, context.remote_public_key, context.local_nonce, cipher)
         });
 
     Box::new(future)
 }
 
+// Runs `f` on `cpu_pool` if there is one, or immediately on the current thread otherwise.
+fn run_crypto<F, T>(cpu_pool: &Option<CpuPool>, f: F) -> Box<Future<Item = T, Error = SecioError>>
+where
+    F: FnOnce() -> Result<T, SecioError> + Send + 'static,
+    T: Send + 'static,
+{
+    match *cpu_pool {
+        Some(ref pool) => Box::new(pool.spawn_fn(f)),
+        None => Box::new(future::result(f())),
+    }
+}
+
 // Custom algorithm translated from reference implementations. Needs to be the same algorithm
 // amongst all implementations.
 fn stretch_key(key: &SigningKey, result: &mut [u8]) {
@@ -562,11 +586,13 @@ mod tests {
             .incoming()
             .into_future()
             .map_err(|(e, _)| e.into())
-            .and_then(move |(connec, _)| handshake(connec.unwrap().0, public_key1, private_key1));
+            .and_then(move |(connec, _)| {
+                handshake(connec.unwrap().0, public_key1, private_key1, None)
+            });
 
         let client = TcpStream::connect(&listener_addr, &core.handle())
             .map_err(|e| e.into())
-            .and_then(move |stream| handshake(stream, public_key2, private_key2));
+            .and_then(move |stream| handshake(stream, public_key2, private_key2, None));
 
         core.run(server.join(client)).unwrap();
     }
@@ -32,14 +32,14 @@
 //! extern crate futures;
 //! extern crate tokio_core;
 //! extern crate tokio_io;
-//! extern crate libp2p_swarm;
+//! extern crate libp2p_core;
 //! extern crate libp2p_secio;
 //! extern crate libp2p_tcp_transport;
 //!
 //! # fn main() {
 //! use futures::Future;
 //! use libp2p_secio::{SecioConfig, SecioKeyPair};
-//! use libp2p_swarm::{Multiaddr, Transport};
+//! use libp2p_core::{Multiaddr, Transport};
 //! use libp2p_tcp_transport::TcpConfig;
 //! use tokio_core::reactor::Core;
 //! use tokio_io::io::write_all;
@@ -55,6 +55,7 @@
 //!         SecioConfig {
 //!                // See the documentation of `SecioKeyPair`.
 //!             key: SecioKeyPair::rsa_from_pkcs8(private_key, public_key).unwrap(),
+//!             cpu_pool: None,
 //!         }
 //!     });
 //!
@@ -82,7 +83,8 @@
 extern crate bytes;
 extern crate crypto;
 extern crate futures;
-extern crate libp2p_swarm;
+extern crate futures_cpupool;
+extern crate libp2p_core;
 #[macro_use]
 extern crate log;
 extern crate protobuf;
@@ -92,34 +94,41 @@ extern crate rw_stream_sink;
 extern crate tokio_io;
 extern crate untrusted;
 
+pub use self::cipher::{Cipher, CipherImplementation, CipherInfo};
 pub use self::error::SecioError;
 
 use bytes::{Bytes, BytesMut};
-use futures::{Future, Poll, Sink, StartSend, Stream};
+use futures::{Async, Future, Poll, Sink, StartSend, Stream};
 use futures::stream::MapErr as StreamMapErr;
-use libp2p_swarm::Multiaddr;
+use futures_cpupool::CpuPool;
+use libp2p_core::Multiaddr;
+use protobuf::Message as ProtobufMessage;
 use ring::signature::RSAKeyPair;
 use rw_stream_sink::RwStreamSink;
 use std::error::Error;
-use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
 use std::iter;
 use std::sync::Arc;
 use tokio_io::{AsyncRead, AsyncWrite};
 use untrusted::Input;
 
 mod algo_support;
+mod cipher;
 mod codec;
 mod error;
 mod keys_proto;
 mod handshake;
 mod structs_proto;
 
-/// Implementation of the `ConnectionUpgrade` trait of `libp2p_swarm`. Automatically applies
+/// Implementation of the `ConnectionUpgrade` trait of `libp2p_core`. Automatically applies
 /// secio on any connection.
 #[derive(Clone)]
 pub struct SecioConfig {
     /// Private and public keys of the local node.
     pub key: SecioKeyPair,
+    /// If `Some`, the RSA signing and verification performed during the handshake are run on
+    /// this thread pool instead of the thread that polls the connection.
+    pub cpu_pool: Option<CpuPool>,
 }
 
 /// Private and public keys of the local node.
@@ -167,6 +176,31 @@ impl SecioKeyPair {
             },
         })
     }
+
+    /// Returns the `LocalIdentity` corresponding to the public key of this key pair.
+    ///
+    /// Pass it to `Transport::with_local_identity` so that the upgrades and the swarm know the
+    /// peer ID of the local node.
+    ///
+    /// The public key is in its protobuf encoding, which is what is sent to the remote during the
+    /// handshake and what the peer ID is the hash of. Passing it to `PeerId::from_public_key`
+    /// therefore gives the same peer ID as the one remotes compute for us, for example with the
+    /// *identify* protocol.
+    pub fn local_identity(&self) -> libp2p_core::LocalIdentity {
+        libp2p_core::LocalIdentity::new(self.public_key_in_protobuf_bytes())
+    }
+
+    // Returns the public key in its protobuf encoding.
+    fn public_key_in_protobuf_bytes(&self) -> Vec<u8> {
+        match self.inner {
+            SecioKeyPairInner::Rsa { ref public, .. } => {
+                let mut key = keys_proto::PublicKey::new();
+                key.set_Type(keys_proto::KeyType::RSA);
+                key.set_Data(public.clone());
+                key.write_to_bytes().expect("writing to a Vec never fails")
+            }
+        }
+    }
 }
 
 // Inner content of `SecioKeyPair`.
@@ -184,11 +218,11 @@ pub enum SecioPublicKey<'a> {
     Rsa(&'a [u8]),
 }
 
-impl<S> libp2p_swarm::ConnectionUpgrade<S> for SecioConfig
+impl<S> libp2p_core::ConnectionUpgrade<S> for SecioConfig
 where
     S: AsyncRead + AsyncWrite + 'static,
 {
-    type Output = RwStreamSink<StreamMapErr<SecioMiddleware<S>, fn(SecioError) -> IoError>>;
+    type Output = SecioOutput<S>;
     type Future = Box<Future<Item = Self::Output, Error = IoError>>;
     type NamesIter = iter::Once<(Bytes, ())>;
     type UpgradeIdentifier = ();
@@ -203,15 +237,20 @@ where
         self,
         incoming: S,
         _: (),
-        _: libp2p_swarm::Endpoint,
+        _: libp2p_core::Endpoint,
         remote_addr: &Multiaddr,
+        _: &libp2p_core::LocalIdentity,
     ) -> Self::Future {
         info!(target: "libp2p-secio", "starting secio upgrade with {:?}", remote_addr);
 
-        let fut = SecioMiddleware::handshake(incoming, self.key);
+        let fut = SecioMiddleware::handshake_with_cpu_pool(incoming, self.key, self.cpu_pool);
         let wrapped = fut.map(|stream_sink| {
+            let cipher = stream_sink.cipher();
             let mapped = stream_sink.map_err(map_err as fn(_) -> _);
-            RwStreamSink::new(mapped)
+            SecioOutput {
+                inner: RwStreamSink::new(mapped),
+                cipher: cipher,
+            }
         }).map_err(map_err);
         Box::new(wrapped)
     }
@@ -223,6 +262,68 @@ fn map_err(err: SecioError) -> IoError {
     IoError::new(IoErrorKind::InvalidData, err)
 }
 
+/// Output of the secio upgrade. Implements `AsyncRead` and `AsyncWrite`.
+pub struct SecioOutput<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    inner: RwStreamSink<StreamMapErr<SecioMiddleware<S>, fn(SecioError) -> IoError>>,
+    cipher: CipherInfo,
+}
+
+impl<S> SecioOutput<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    /// Returns the cipher used to encrypt the communications, and whether its implementation is
+    /// hardware-accelerated.
+    #[inline]
+    pub fn cipher(&self) -> CipherInfo {
+        self.cipher
+    }
+}
+
+impl<S> Read for SecioOutput<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S> AsyncRead for SecioOutput<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+}
+
+impl<S> Write for SecioOutput<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.inner.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+
+impl<S> AsyncWrite for SecioOutput<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    #[inline]
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
+
 /// Wraps around an object that implements `AsyncRead` and `AsyncWrite`.
 ///
 /// Implements `Sink` and `Stream` whose items are frames of data. Each frame is encoded
@@ -230,6 +331,11 @@ fn map_err(err: SecioError) -> IoError {
 pub struct SecioMiddleware<S> {
     inner: codec::FullCodec<S>,
     remote_pubkey_der: Vec<u8>,
+    // The last step of the handshake consists in receiving back our nonce. This step is performed
+    // when the stream is first polled, so that data can be sent before the check is finished.
+    // Contains `None` once the check has been performed.
+    pending_nonce: Option<[u8; 16]>,
+    cipher: CipherInfo,
 }
 
 impl<S> SecioMiddleware<S>
@@ -244,19 +350,45 @@ where
         socket: S,
         key_pair: SecioKeyPair,
     ) -> Box<Future<Item = SecioMiddleware<S>, Error = SecioError> + 'a>
+    where
+        S: 'a,
+    {
+        SecioMiddleware::handshake_with_cpu_pool(socket, key_pair, None)
+    }
+
+    /// Same as `handshake`, but the CPU-heavy cryptographic operations are run on `cpu_pool` if
+    /// it is `Some`.
+    pub fn handshake_with_cpu_pool<'a>(
+        socket: S,
+        key_pair: SecioKeyPair,
+        cpu_pool: Option<CpuPool>,
+    ) -> Box<Future<Item = SecioMiddleware<S>, Error = SecioError> + 'a>
     where
         S: 'a,
     {
         let SecioKeyPairInner::Rsa { private, public } = key_pair.inner;
 
-        let fut =
-            handshake::handshake(socket, public, private).map(|(inner, pubkey)| SecioMiddleware {
-                inner: inner,
-                remote_pubkey_der: pubkey,
+        let fut = handshake::handshake(socket, public, private, cpu_pool)
+            .map(|(inner, pubkey, nonce, key_size)| {
+                let cipher = cipher::cipher_info(key_size);
+                debug!(target: "libp2p-secio", "secio connection uses {:?}", cipher);
+                SecioMiddleware {
+                    inner: inner,
+                    remote_pubkey_der: pubkey,
+                    pending_nonce: Some(nonce),
+                    cipher: cipher,
+                }
             });
         Box::new(fut)
     }
 
+    /// Returns the cipher used to encrypt the communications, and whether its implementation is
+    /// hardware-accelerated.
+    #[inline]
+    pub fn cipher(&self) -> CipherInfo {
+        self.cipher
+    }
+
     /// Returns the public key of the remote in the `DER` format.
     #[inline]
     pub fn remote_public_key_der(&self) -> SecioPublicKey {
@@ -289,8 +421,173 @@ where
     type Item = Vec<u8>;
     type Error = SecioError;
 
-    #[inline]
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(nonce) = self.pending_nonce.take() {
+            match self.inner.poll() {
+                Ok(Async::Ready(Some(ref n))) if &n[..] == &nonce[..] => {
+                    trace!(target: "libp2p-secio", "nonce verification with remote succeeded");
+                }
+                Ok(Async::Ready(Some(_))) => {
+                    debug!(target: "libp2p-secio", "failed nonce verification with remote");
+                    return Err(SecioError::NonceVerificationFailed);
+                }
+                Ok(Async::Ready(None)) => {
+                    debug!(target: "libp2p-secio", "unexpected eof during nonce check");
+                    let err = IoError::new(IoErrorKind::BrokenPipe, "unexpected eof");
+                    return Err(err.into());
+                }
+                Ok(Async::NotReady) => {
+                    self.pending_nonce = Some(nonce);
+                    return Ok(Async::NotReady);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
         self.inner.poll()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tokio_core;
+
+    use self::tokio_core::net::{TcpListener, TcpStream};
+    use self::tokio_core::reactor::Core;
+    use bytes::BytesMut;
+    use futures::{Future, Sink, Stream};
+    use futures_cpupool::CpuPool;
+    use keys_proto;
+    use libp2p_core::{ConnectionUpgrade, Endpoint, LocalIdentity, Multiaddr};
+    use protobuf::core::parse_from_bytes as protobuf_parse_from_bytes;
+    use {SecioConfig, SecioError, SecioKeyPair, SecioMiddleware};
+
+    #[test]
+    fn data_sent_before_nonce_check() {
+        // The dialer sends data immediately after its handshake future resolves, without waiting
+        // for the nonce check. The data must still be received correctly by the listener.
+        let mut core = Core::new().unwrap();
+
+        let key1 = SecioKeyPair::rsa_from_pkcs8(
+            &include_bytes!("../tests/test-private-key.pk8")[..],
+            include_bytes!("../tests/test-public-key.der").to_vec(),
+        ).unwrap();
+        let key2 = SecioKeyPair::rsa_from_pkcs8(
+            &include_bytes!("../tests/test-private-key-2.pk8")[..],
+            include_bytes!("../tests/test-public-key-2.der").to_vec(),
+        ).unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e.into())
+            .and_then(move |(connec, _)| SecioMiddleware::handshake(connec.unwrap().0, key1))
+            .and_then(|secio| secio.into_future().map_err(|(e, _)| e))
+            .map(|(msg, _)| msg);
+
+        let client = TcpStream::connect(&listener_addr, &core.handle())
+            .map_err(|e| e.into())
+            .and_then(move |stream| SecioMiddleware::handshake(stream, key2))
+            .and_then(|secio| {
+                secio
+                    .send(BytesMut::from(&b"hello world"[..]))
+                    .map_err(SecioError::from)
+            });
+
+        let (received, _) = core.run(server.join(client)).unwrap();
+        assert_eq!(received.unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn handshake_on_cpu_pool() {
+        let mut core = Core::new().unwrap();
+        let pool = CpuPool::new(2);
+
+        let key1 = SecioKeyPair::rsa_from_pkcs8(
+            &include_bytes!("../tests/test-private-key.pk8")[..],
+            include_bytes!("../tests/test-public-key.der").to_vec(),
+        ).unwrap();
+        let key2 = SecioKeyPair::rsa_from_pkcs8(
+            &include_bytes!("../tests/test-private-key-2.pk8")[..],
+            include_bytes!("../tests/test-public-key-2.der").to_vec(),
+        ).unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        let pool1 = pool.clone();
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e.into())
+            .and_then(move |(connec, _)| {
+                SecioMiddleware::handshake_with_cpu_pool(connec.unwrap().0, key1, Some(pool1))
+            });
+
+        let client = TcpStream::connect(&listener_addr, &core.handle())
+            .map_err(|e| e.into())
+            .and_then(move |stream| {
+                SecioMiddleware::handshake_with_cpu_pool(stream, key2, Some(pool))
+            });
+
+        let (server, client) = core.run(server.join(client)).unwrap();
+        assert_eq!(server.cipher(), client.cipher());
+    }
+
+    #[test]
+    fn upgrade_output_exposes_cipher() {
+        let mut core = Core::new().unwrap();
+
+        let key1 = SecioKeyPair::rsa_from_pkcs8(
+            &include_bytes!("../tests/test-private-key.pk8")[..],
+            include_bytes!("../tests/test-public-key.der").to_vec(),
+        ).unwrap();
+        let key2 = SecioKeyPair::rsa_from_pkcs8(
+            &include_bytes!("../tests/test-private-key-2.pk8")[..],
+            include_bytes!("../tests/test-public-key-2.der").to_vec(),
+        ).unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+
+        let config1 = SecioConfig { key: key1, cpu_pool: None };
+        let addr1 = addr.clone();
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(connec, _)| {
+                let identity = LocalIdentity::unknown();
+                config1.upgrade(connec.unwrap().0, (), Endpoint::Listener, &addr1, &identity)
+            });
+
+        let config2 = SecioConfig { key: key2, cpu_pool: None };
+        let client = TcpStream::connect(&listener_addr, &core.handle())
+            .and_then(move |stream| {
+                let identity = LocalIdentity::unknown();
+                config2.upgrade(stream, (), Endpoint::Dialer, &addr, &identity)
+            });
+
+        let (server, client) = core.run(server.join(client)).unwrap();
+        assert_eq!(server.cipher(), client.cipher());
+    }
+
+    #[test]
+    fn local_identity_is_protobuf_encoded() {
+        let public = include_bytes!("../tests/test-public-key.der").to_vec();
+        let key = SecioKeyPair::rsa_from_pkcs8(
+            &include_bytes!("../tests/test-private-key.pk8")[..],
+            public.clone(),
+        ).unwrap();
+
+        let identity = key.local_identity();
+        let mut decoded: keys_proto::PublicKey =
+            protobuf_parse_from_bytes(identity.public_key().unwrap()).unwrap();
+        assert_eq!(decoded.get_Type(), keys_proto::KeyType::RSA);
+        assert_eq!(decoded.take_Data(), public);
+    }
+}
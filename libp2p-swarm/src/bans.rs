@@ -0,0 +1,157 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! List of the peers that the swarm refuses to be connected to.
+//!
+//! See `SwarmController::ban_peer`. A peer can be banned for a while or forever. Banning it
+//! closes the connections that are open with it, and the new ones are refused.
+//!
+//! The swarm only knows the identity of a remote through its multiaddress, so a ban only applies
+//! to the connections whose address contains a `/p2p/` component. This is the case of the
+//! addresses produced by the `IdentifyTransport` of the `libp2p-identify` crate, which should
+//! therefore be part of the transport.
+//!
+//! Closing a connection means dropping the future returned by the handler for it. If the
+//! transport reuses muxed connections, this closes the substreams of the handler but the
+//! underlying connection remains open until the muxer closes it.
+
+use multiaddr::AddrComponent;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use {Multiaddr, PeerId};
+
+/// Shared list of banned peers. Cloning it is cheap, and all the clones share the same list.
+#[derive(Debug, Clone, Default)]
+pub struct PeerBans {
+    // For each banned peer, the moment when the ban expires, or `None` if it is permanent.
+    bans: Arc<Mutex<HashMap<PeerId, Option<Instant>>>>,
+}
+
+impl PeerBans {
+    /// Creates an empty list.
+    #[inline]
+    pub fn new() -> PeerBans {
+        PeerBans::default()
+    }
+
+    /// Bans `peer` for `duration`, or forever if `duration` is `None`. Replaces the previous ban
+    /// of the peer, if any.
+    pub fn ban(&self, peer: PeerId, duration: Option<Duration>) {
+        let expires = duration.map(|duration| Instant::now() + duration);
+        self.bans.lock().unwrap().insert(peer, expires);
+    }
+
+    /// Lifts the ban of `peer`. Returns false if it wasn't banned.
+    pub fn unban(&self, peer: &PeerId) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        remove_expired(&mut bans);
+        bans.remove(peer).is_some()
+    }
+
+    /// Returns true if `peer` is banned.
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        remove_expired(&mut bans);
+        bans.contains_key(peer)
+    }
+
+    /// Returns the banned peer that `addr` belongs to, if any.
+    pub fn banned_peer_of(&self, addr: &Multiaddr) -> Option<PeerId> {
+        match peer_id_of(addr) {
+            Some(ref peer) if self.is_banned(peer) => Some(peer.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns the list of banned peers, alongside with the moment when their ban expires.
+    pub fn banned_peers(&self) -> Vec<(PeerId, Option<Instant>)> {
+        let mut bans = self.bans.lock().unwrap();
+        remove_expired(&mut bans);
+        bans.iter().map(|(peer, expires)| (peer.clone(), *expires)).collect()
+    }
+}
+
+fn remove_expired(bans: &mut HashMap<PeerId, Option<Instant>>) {
+    let now = Instant::now();
+    bans.retain(|_, expires| match *expires {
+        Some(expires) => expires > now,
+        None => true,
+    });
+}
+
+/// Returns the peer ID contained in the `/p2p/` component of `addr`, if any.
+pub fn peer_id_of(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter()
+        .filter_map(|component| match component {
+            AddrComponent::P2P(bytes) | AddrComponent::IPFS(bytes) => {
+                PeerId::from_bytes(bytes).ok()
+            }
+            _ => None,
+        })
+        .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerBans;
+    use multiaddr::AddrComponent;
+    use std::thread;
+    use std::time::Duration;
+    use {Multiaddr, PeerId};
+
+    #[test]
+    fn permanent_ban() {
+        let bans = PeerBans::new();
+        let peer = PeerId::from_public_key(&[1, 2, 3]);
+        bans.ban(peer.clone(), None);
+        assert!(bans.is_banned(&peer));
+        assert!(!bans.is_banned(&PeerId::from_public_key(&[4, 5, 6])));
+
+        assert!(bans.unban(&peer));
+        assert!(!bans.is_banned(&peer));
+        assert!(!bans.unban(&peer));
+    }
+
+    #[test]
+    fn ban_expires() {
+        let bans = PeerBans::new();
+        let peer = PeerId::from_public_key(&[1, 2, 3]);
+        bans.ban(peer.clone(), Some(Duration::from_millis(50)));
+        assert!(bans.is_banned(&peer));
+        assert_eq!(bans.banned_peers().len(), 1);
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(!bans.is_banned(&peer));
+        assert!(bans.banned_peers().is_empty());
+    }
+
+    #[test]
+    fn banned_peer_of_addr() {
+        let bans = PeerBans::new();
+        let peer = PeerId::from_public_key(&[1, 2, 3]);
+        bans.ban(peer.clone(), None);
+
+        let mut addr: Multiaddr = "/ip4/127.0.0.1/tcp/1234".parse().unwrap();
+        assert_eq!(bans.banned_peer_of(&addr), None);
+        addr.append(AddrComponent::P2P(peer.clone().into_bytes()));
+        assert_eq!(bans.banned_peer_of(&addr), Some(peer));
+    }
+}
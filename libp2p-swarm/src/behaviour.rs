@@ -0,0 +1,518 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Composition of several protocols over the same connections.
+//!
+//! With `swarm`, the transport is upgraded with a single `ConnectionUpgrade`, and the outputs of
+//! this upgrade are all passed to the same handler. A `NetworkBehaviour` instead bundles the
+//! upgrade of a protocol with the state that drives it. It is given every substream that has been
+//! negotiated with its upgrade, and in return asks for dialing attempts and produces events for
+//! the application.
+//!
+//! Two behaviours can be combined into one with `CombinedBehaviour`, which can itself be combined
+//! with a third one, and so on. The resulting behaviour is then driven by a `BehaviourSwarm`,
+//! which is a `Stream` of the events produced by the behaviours.
+//!
+//! In order for the behaviours to share the connections to a remote, the transport should be
+//! turned into a `ConnectionReuse` with `UpgradedNode::into_connection_reuse`. A dialing attempt
+//! then opens a new substream over the existing connection if there is one, and the substreams
+//! opened by the remote are negotiated with the protocols of all the behaviours.
+//!
+//! This crate doesn't provide behaviours for the existing protocols such as *identify* and
+//! *ping*; they are meant to be written by wrapping the upgrades of these protocols.
+
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Stream};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use tokio_io::{AsyncRead, AsyncWrite};
+use transport::{EitherConnUpgrFuture, EitherSocket, EitherUpgradeIdentifier};
+use {ConnectionUpgrade, Endpoint, LocalIdentity, Multiaddr, MuxedTransport};
+
+/// Protocol, or group of protocols, driven by a `BehaviourSwarm`.
+///
+/// `C` is the type of the substreams that the upgrade is applied to, which is the `RawConn` of
+/// the transport.
+pub trait NetworkBehaviour<C> {
+    /// Upgrade that negotiates the protocols of the behaviour.
+    type Upgrade: ConnectionUpgrade<C>;
+    /// Events produced by the behaviour for the application.
+    type OutEvent;
+
+    /// Returns the upgrade to apply to the substreams opened by remotes.
+    ///
+    /// Called again every time the swarm starts listening or waits for a new incoming substream,
+    /// so that the protocols of the behaviour can change over time.
+    fn upgrade(&self) -> Self::Upgrade;
+
+    /// Called when a substream has been negotiated. `endpoint` is `Dialer` if the substream was
+    /// opened because of a `Dial` action, and `Listener` if it was opened by the remote.
+    fn inject_substream(
+        &mut self,
+        output: <Self::Upgrade as ConnectionUpgrade<C>>::Output,
+        addr: Multiaddr,
+        endpoint: Endpoint,
+    );
+
+    /// Called when a `Dial` action has failed. `upgrade` is the one that was passed to the
+    /// action.
+    fn inject_dial_failure(&mut self, addr: &Multiaddr, upgrade: Self::Upgrade, error: &IoError);
+
+    /// Polls the behaviour for the next thing it wants the swarm to do.
+    ///
+    /// If `NotReady` is returned, the behaviour must make sure that the current task is notified
+    /// when it has something to do, as with `Future::poll`.
+    fn poll(&mut self) -> Async<NetworkBehaviourAction<Self::Upgrade, Self::OutEvent>>;
+}
+
+/// Action produced by `NetworkBehaviour::poll`.
+#[derive(Debug, Clone)]
+pub enum NetworkBehaviourAction<U, E> {
+    /// Dial `addr` and negotiate `upgrade` on the resulting substream.
+    Dial {
+        /// Address to dial.
+        addr: Multiaddr,
+        /// Upgrade to apply once connected.
+        upgrade: U,
+    },
+    /// Produce an event from the `BehaviourSwarm`.
+    GenerateEvent(E),
+}
+
+/// Combination of two behaviours, which negotiates the protocols of both of them.
+///
+/// The behaviours are polled in order, which means that `first` has priority over `second`.
+#[derive(Debug, Clone)]
+pub struct CombinedBehaviour<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> CombinedBehaviour<A, B> {
+    /// Combines `first` and `second`.
+    #[inline]
+    pub fn new(first: A, second: B) -> CombinedBehaviour<A, B> {
+        CombinedBehaviour {
+            first: first,
+            second: second,
+        }
+    }
+
+    /// Returns the first behaviour.
+    #[inline]
+    pub fn first(&mut self) -> &mut A {
+        &mut self.first
+    }
+
+    /// Returns the second behaviour.
+    #[inline]
+    pub fn second(&mut self) -> &mut B {
+        &mut self.second
+    }
+}
+
+impl<C, A, B> NetworkBehaviour<C> for CombinedBehaviour<A, B>
+where
+    C: AsyncRead + AsyncWrite,
+    A: NetworkBehaviour<C>,
+    B: NetworkBehaviour<C>,
+{
+    type Upgrade = CombinedUpgrade<A::Upgrade, B::Upgrade>;
+    type OutEvent = CombinedEvent<A::OutEvent, B::OutEvent>;
+
+    #[inline]
+    fn upgrade(&self) -> Self::Upgrade {
+        CombinedUpgrade {
+            first: Some(self.first.upgrade()),
+            second: Some(self.second.upgrade()),
+        }
+    }
+
+    #[inline]
+    fn inject_substream(
+        &mut self,
+        output: EitherSocket<
+            <A::Upgrade as ConnectionUpgrade<C>>::Output,
+            <B::Upgrade as ConnectionUpgrade<C>>::Output,
+        >,
+        addr: Multiaddr,
+        endpoint: Endpoint,
+    ) {
+        match output {
+            EitherSocket::First(output) => self.first.inject_substream(output, addr, endpoint),
+            EitherSocket::Second(output) => self.second.inject_substream(output, addr, endpoint),
+        }
+    }
+
+    fn inject_dial_failure(&mut self, addr: &Multiaddr, upgrade: Self::Upgrade, error: &IoError) {
+        if let Some(upgrade) = upgrade.first {
+            self.first.inject_dial_failure(addr, upgrade, error);
+        }
+        if let Some(upgrade) = upgrade.second {
+            self.second.inject_dial_failure(addr, upgrade, error);
+        }
+    }
+
+    fn poll(&mut self) -> Async<NetworkBehaviourAction<Self::Upgrade, Self::OutEvent>> {
+        match self.first.poll() {
+            Async::Ready(NetworkBehaviourAction::Dial { addr, upgrade }) => {
+                return Async::Ready(NetworkBehaviourAction::Dial {
+                    addr: addr,
+                    upgrade: CombinedUpgrade {
+                        first: Some(upgrade),
+                        second: None,
+                    },
+                });
+            }
+            Async::Ready(NetworkBehaviourAction::GenerateEvent(event)) => {
+                let event = CombinedEvent::First(event);
+                return Async::Ready(NetworkBehaviourAction::GenerateEvent(event));
+            }
+            Async::NotReady => (),
+        }
+
+        match self.second.poll() {
+            Async::Ready(NetworkBehaviourAction::Dial { addr, upgrade }) => {
+                Async::Ready(NetworkBehaviourAction::Dial {
+                    addr: addr,
+                    upgrade: CombinedUpgrade {
+                        first: None,
+                        second: Some(upgrade),
+                    },
+                })
+            }
+            Async::Ready(NetworkBehaviourAction::GenerateEvent(event)) => {
+                let event = CombinedEvent::Second(event);
+                Async::Ready(NetworkBehaviourAction::GenerateEvent(event))
+            }
+            Async::NotReady => Async::NotReady,
+        }
+    }
+}
+
+/// Event produced by a `CombinedBehaviour`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CombinedEvent<A, B> {
+    /// Event produced by the first behaviour.
+    First(A),
+    /// Event produced by the second behaviour.
+    Second(B),
+}
+
+/// Upgrade of a `CombinedBehaviour`.
+///
+/// Contrary to `OrUpgrade`, one of the two upgrades can be missing. This is the case for the
+/// dialing attempts, where only the protocol of the behaviour that asked for the attempt must be
+/// negotiated.
+#[derive(Debug, Copy, Clone)]
+pub struct CombinedUpgrade<A, B> {
+    first: Option<A>,
+    second: Option<B>,
+}
+
+impl<C, A, B> ConnectionUpgrade<C> for CombinedUpgrade<A, B>
+where
+    C: AsyncRead + AsyncWrite,
+    A: ConnectionUpgrade<C>,
+    B: ConnectionUpgrade<C>,
+{
+    type NamesIter = CombinedNames<A::NamesIter, B::NamesIter>;
+    type UpgradeIdentifier = EitherUpgradeIdentifier<A::UpgradeIdentifier, B::UpgradeIdentifier>;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        CombinedNames {
+            first: self.first.as_ref().map(|upgrade| upgrade.protocol_names()),
+            second: self.second.as_ref().map(|upgrade| upgrade.protocol_names()),
+        }
+    }
+
+    type Output = EitherSocket<A::Output, B::Output>;
+    type Future = EitherConnUpgrFuture<A::Future, B::Future>;
+
+    fn upgrade(
+        self,
+        socket: C,
+        id: Self::UpgradeIdentifier,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        match (id, self.first, self.second) {
+            (EitherUpgradeIdentifier::First(id), Some(first), _) => EitherConnUpgrFuture::First(
+                first.upgrade(socket, id, ty, remote_addr, local_identity),
+            ),
+            (EitherUpgradeIdentifier::Second(id), _, Some(second)) => {
+                EitherConnUpgrFuture::Second(
+                    second.upgrade(socket, id, ty, remote_addr, local_identity),
+                )
+            }
+            _ => unreachable!("identifiers are only produced for the upgrades that are present"),
+        }
+    }
+}
+
+/// Iterator returned by `CombinedUpgrade::protocol_names`.
+#[derive(Debug, Clone)]
+pub struct CombinedNames<A, B> {
+    first: Option<A>,
+    second: Option<B>,
+}
+
+impl<A, B, AId, BId> Iterator for CombinedNames<A, B>
+where
+    A: Iterator<Item = (Bytes, AId)>,
+    B: Iterator<Item = (Bytes, BId)>,
+{
+    type Item = (Bytes, EitherUpgradeIdentifier<AId, BId>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(ref mut first) = self.first {
+            if let Some((name, id)) = first.next() {
+                return Some((name, EitherUpgradeIdentifier::First(id)));
+            }
+        }
+        if let Some(ref mut second) = self.second {
+            if let Some((name, id)) = second.next() {
+                return Some((name, EitherUpgradeIdentifier::Second(id)));
+            }
+        }
+        None
+    }
+}
+
+/// Drives a `NetworkBehaviour` over a transport. Produces the events generated by the behaviour.
+///
+/// The stream never ends and never produces an error. The errors of the listeners and of the
+/// upgrades of incoming substreams are ignored, and the failed dialing attempts are reported to
+/// the behaviour.
+pub struct BehaviourSwarm<T, B>
+where
+    T: MuxedTransport + 'static, // TODO: 'static :-/
+    B: NetworkBehaviour<T::RawConn>,
+    B::Upgrade: 'static, // TODO: 'static :-/
+{
+    transport: T,
+    behaviour: B,
+    listen_addrs: Vec<Multiaddr>,
+    next_incoming: Box<
+        Future<
+            Item = Box<
+                Future<
+                    Item = (<B::Upgrade as ConnectionUpgrade<T::RawConn>>::Output, Multiaddr),
+                    Error = IoError,
+                >,
+            >,
+            Error = IoError,
+        >,
+    >,
+    listeners: Vec<
+        Box<
+            Stream<
+                Item = Box<
+                    Future<
+                        Item = (<B::Upgrade as ConnectionUpgrade<T::RawConn>>::Output, Multiaddr),
+                        Error = IoError,
+                    >,
+                >,
+                Error = IoError,
+            >,
+        >,
+    >,
+    listeners_upgrade: Vec<
+        Box<
+            Future<
+                Item = (<B::Upgrade as ConnectionUpgrade<T::RawConn>>::Output, Multiaddr),
+                Error = IoError,
+            >,
+        >,
+    >,
+    // Each dialing attempt is stored alongside with the address and the upgrade, in order to
+    // report them to the behaviour in case of failure.
+    dialers: Vec<(
+        Box<
+            Future<
+                Item = (<B::Upgrade as ConnectionUpgrade<T::RawConn>>::Output, Multiaddr),
+                Error = IoError,
+            >,
+        >,
+        Multiaddr,
+        B::Upgrade,
+    )>,
+}
+
+impl<T, B> BehaviourSwarm<T, B>
+where
+    T: MuxedTransport + Clone + 'static, // TODO: 'static :-/
+    B: NetworkBehaviour<T::RawConn>,
+    B::Upgrade: Clone + 'static, // TODO: 'static :-/
+    <B::Upgrade as ConnectionUpgrade<T::RawConn>>::NamesIter: Clone, // TODO: not elegant
+{
+    /// Creates a swarm that drives `behaviour` over `transport`.
+    pub fn new(transport: T, behaviour: B) -> BehaviourSwarm<T, B> {
+        let next_incoming = transport
+            .clone()
+            .with_upgrade(behaviour.upgrade())
+            .next_incoming();
+
+        BehaviourSwarm {
+            transport: transport,
+            behaviour: behaviour,
+            listen_addrs: Vec::new(),
+            next_incoming: next_incoming,
+            listeners: Vec::new(),
+            listeners_upgrade: Vec::new(),
+            dialers: Vec::new(),
+        }
+    }
+
+    /// Returns the behaviour.
+    #[inline]
+    pub fn behaviour(&mut self) -> &mut B {
+        &mut self.behaviour
+    }
+
+    /// Returns the addresses we are listening on.
+    #[inline]
+    pub fn listen_addrs(&self) -> &[Multiaddr] {
+        &self.listen_addrs
+    }
+
+    /// Starts listening on `multiaddr`. The incoming connections are negotiated with the upgrade
+    /// of the behaviour.
+    ///
+    /// Returns the actual address we are listening on, or the address back if the transport
+    /// doesn't support it.
+    pub fn listen_on(&mut self, multiaddr: Multiaddr) -> Result<Multiaddr, Multiaddr> {
+        match self.transport
+            .clone()
+            .with_upgrade(self.behaviour.upgrade())
+            .listen_on(multiaddr)
+        {
+            Ok((listener, new_addr)) => {
+                self.listeners.push(listener);
+                self.listen_addrs.push(new_addr.clone());
+                Ok(new_addr)
+            }
+            Err((_, multiaddr)) => Err(multiaddr),
+        }
+    }
+
+    /// Dials `multiaddr` and negotiates `upgrade`, as if the behaviour had produced a `Dial`
+    /// action.
+    pub fn dial(&mut self, multiaddr: Multiaddr, upgrade: B::Upgrade) -> Result<(), Multiaddr> {
+        match self.transport
+            .clone()
+            .with_upgrade(upgrade.clone())
+            .dial(multiaddr.clone())
+        {
+            Ok(dial) => {
+                self.dialers.push((dial, multiaddr, upgrade));
+                Ok(())
+            }
+            Err((_, multiaddr)) => Err(multiaddr),
+        }
+    }
+
+    // Polls the connections in progress and passes their outputs to the behaviour.
+    fn poll_connections(&mut self) {
+        loop {
+            match self.next_incoming.poll() {
+                Ok(Async::Ready(incoming)) => self.listeners_upgrade.push(incoming),
+                Ok(Async::NotReady) => break,
+                Err(err) => debug!("Error while waiting for an incoming substream: {:?}", err),
+            }
+            self.next_incoming = self.transport
+                .clone()
+                .with_upgrade(self.behaviour.upgrade())
+                .next_incoming();
+        }
+
+        for n in (0..self.listeners.len()).rev() {
+            let mut listener = self.listeners.swap_remove(n);
+            loop {
+                match listener.poll() {
+                    Ok(Async::Ready(Some(upgrade))) => self.listeners_upgrade.push(upgrade),
+                    Ok(Async::NotReady) => {
+                        self.listeners.push(listener);
+                        break;
+                    }
+                    Ok(Async::Ready(None)) => break,
+                    Err(err) => {
+                        debug!("Listener closed with an error: {:?}", err);
+                        break;
+                    }
+                }
+            }
+        }
+
+        for n in (0..self.listeners_upgrade.len()).rev() {
+            let mut upgrade = self.listeners_upgrade.swap_remove(n);
+            match upgrade.poll() {
+                Ok(Async::Ready((output, addr))) => {
+                    self.behaviour.inject_substream(output, addr, Endpoint::Listener)
+                }
+                Ok(Async::NotReady) => self.listeners_upgrade.push(upgrade),
+                Err(err) => debug!("Failed to upgrade an incoming connection: {:?}", err),
+            }
+        }
+
+        for n in (0..self.dialers.len()).rev() {
+            let (mut dialer, addr, upgrade) = self.dialers.swap_remove(n);
+            match dialer.poll() {
+                Ok(Async::Ready((output, addr))) => {
+                    self.behaviour.inject_substream(output, addr, Endpoint::Dialer)
+                }
+                Ok(Async::NotReady) => self.dialers.push((dialer, addr, upgrade)),
+                Err(err) => self.behaviour.inject_dial_failure(&addr, upgrade, &err),
+            }
+        }
+    }
+}
+
+impl<T, B> Stream for BehaviourSwarm<T, B>
+where
+    T: MuxedTransport + Clone + 'static, // TODO: 'static :-/
+    B: NetworkBehaviour<T::RawConn>,
+    B::Upgrade: Clone + 'static, // TODO: 'static :-/
+    <B::Upgrade as ConnectionUpgrade<T::RawConn>>::NamesIter: Clone, // TODO: not elegant
+{
+    type Item = B::OutEvent;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            self.poll_connections();
+
+            match self.behaviour.poll() {
+                Async::Ready(NetworkBehaviourAction::Dial { addr, upgrade }) => {
+                    // The new dialing attempt is polled during the next iteration.
+                    if let Err(addr) = self.dial(addr, upgrade.clone()) {
+                        let err = IoError::new(IoErrorKind::Other, "multiaddr not supported");
+                        self.behaviour.inject_dial_failure(&addr, upgrade, &err);
+                    }
+                }
+                Async::Ready(NetworkBehaviourAction::GenerateEvent(event)) => {
+                    return Ok(Async::Ready(Some(event)));
+                }
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
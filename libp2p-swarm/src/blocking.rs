@@ -0,0 +1,460 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Synchronous facade around a swarm, for applications that don't want to deal with futures.
+//!
+//! See `BlockingSwarm`.
+
+use futures::{Future, IntoFuture, Stream};
+use futures::sync::mpsc as futures_mpsc;
+use std::error;
+use std::fmt;
+use std::io::Error as IoError;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use limits::{ConnectionLimit, DialError};
+use swarm::{SwarmController, SwarmFuture};
+use tokio_core::reactor::{Core, Handle};
+use {ConnectionUpgrade, Multiaddr, MuxedTransport, PeerId};
+
+// Operation to execute on the thread of the reactor. Is only ever called once.
+type Command<T, C> = Box<FnMut(&SwarmController<T, C>, &Handle) + Send>;
+
+/// Runs a swarm on a background thread, and exposes blocking methods to control it.
+///
+/// The transport, the upgrade and the handler are built on the background thread by the closure
+/// passed to `BlockingSwarm::new`, as they usually can't be sent between threads. The handler can
+/// report events of type `E` through the `EventSender` passed to this closure, and these events
+/// can be received with `subscribe`.
+///
+/// Destroying the `BlockingSwarm` stops the swarm and waits for the background thread to end.
+///
+/// ```no_run
+/// extern crate futures;
+/// extern crate libp2p_ping;
+/// extern crate libp2p_swarm;
+/// extern crate libp2p_tcp_transport;
+///
+/// use futures::Future;
+/// use libp2p_ping::Ping;
+/// use libp2p_swarm::{BlockingSwarm, Transport};
+/// use std::time::Duration;
+///
+/// # fn main() {
+/// let swarm = BlockingSwarm::new(|handle, events| {
+///     let transport = libp2p_tcp_transport::TcpConfig::new(handle.clone()).with_dummy_muxing();
+///     libp2p_swarm::swarm(transport, Ping, move |(_, service), client_addr| {
+///         events.send(client_addr);
+///         service
+///     })
+/// }).unwrap();
+///
+/// let addr = swarm.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+/// let incoming = swarm.subscribe();
+///
+/// // Pings ourselves.
+/// swarm.send_request(addr, Ping, Duration::from_secs(5), |(mut pinger, service), _| {
+///     pinger.ping().map_err(|_| panic!()).select(service).map(|_| ()).map_err(|(err, _)| err)
+/// }).unwrap();
+///
+/// println!("Pinged by {}", incoming.next(Duration::from_secs(5)).unwrap());
+/// # }
+/// ```
+pub struct BlockingSwarm<T, C, E = ()>
+where
+    T: MuxedTransport + 'static,                // TODO: 'static :-/
+    C: ConnectionUpgrade<T::RawConn> + 'static, // TODO: 'static :-/
+{
+    commands: Option<futures_mpsc::UnboundedSender<Command<T, C>>>,
+    events: EventSender<E>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl<T, C, E> BlockingSwarm<T, C, E>
+where
+    T: MuxedTransport + Clone + 'static, // TODO: 'static :-/
+    C: ConnectionUpgrade<T::RawConn> + Clone + 'static, // TODO: 'static :-/
+    C::NamesIter: Clone,                 // TODO: not elegant
+    E: Send + 'static,
+{
+    /// Starts a background thread with a reactor, and calls `build` on it in order to create the
+    /// swarm, usually through the `swarm()` function.
+    ///
+    /// Returns an error if the reactor can't be created.
+    pub fn new<B, H, If, F>(build: B) -> Result<BlockingSwarm<T, C, E>, IoError>
+    where
+        B: FnOnce(&Handle, EventSender<E>) -> (SwarmController<T, C>, SwarmFuture<T, C, H, F>),
+        B: Send + 'static,
+        H: FnMut(C::Output, Multiaddr) -> If,
+        If: IntoFuture<Future = F, Item = (), Error = IoError>,
+        F: Future<Item = (), Error = IoError>,
+    {
+        let (commands_tx, commands_rx) = futures_mpsc::unbounded::<Command<T, C>>();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let events = EventSender {
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        };
+        let events2 = events.clone();
+
+        let thread = thread::spawn(move || {
+            let mut core = match Core::new() {
+                Ok(core) => {
+                    let _ = ready_tx.send(Ok(()));
+                    core
+                }
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err));
+                    return;
+                }
+            };
+
+            let handle = core.handle();
+            let (controller, swarm_future) = build(&handle, events2);
+            let commands = commands_rx.for_each(|mut command| {
+                command(&controller, &handle);
+                Ok(())
+            });
+
+            // Stops when the `BlockingSwarm` is destroyed, which closes the channel of commands.
+            let _ = core.run(swarm_future.map_err(|_| ()).select(commands));
+        });
+
+        match ready_rx.recv() {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => return Err(err),
+            Err(_) => panic!("the background thread panicked while building the reactor"),
+        }
+
+        Ok(BlockingSwarm {
+            commands: Some(commands_tx),
+            events: events,
+            thread: Some(thread),
+        })
+    }
+
+    /// Starts listening on `addr`, and returns the actual address that is listened on.
+    pub fn listen_on(&self, addr: Multiaddr) -> Result<Multiaddr, BlockingError> {
+        let result = self.execute(move |controller, _| controller.listen_on(addr))?;
+        result.map_err(BlockingError::MultiaddrNotSupported)
+    }
+
+    /// Dials `addr` with `upgrade`, and passes the output to the handler of the swarm.
+    ///
+    /// Returns as soon as the dialing has started. Its outcome is only known to the handler.
+    pub fn dial<Du>(&self, addr: Multiaddr, upgrade: Du) -> Result<(), BlockingError>
+    where
+        Du: ConnectionUpgrade<T::RawConn> + Clone + Send + 'static,
+        Du::Output: Into<C::Output>,
+    {
+        let result = self.execute(move |controller, _| controller.dial_to_handler(addr, upgrade))?;
+        result.map_err(BlockingError::from)
+    }
+
+    /// Dials `addr` with `upgrade`, passes the output to `request`, and waits for the future it
+    /// returns to finish, at most for `timeout`.
+    ///
+    /// Contrary to `dial`, the output isn't passed to the handler of the swarm.
+    ///
+    /// > **Note**: If the timeout elapses, the request continues in the background and its
+    /// >           result is ignored.
+    pub fn send_request<Du, Rq, Rf>(
+        &self,
+        addr: Multiaddr,
+        upgrade: Du,
+        timeout: Duration,
+        request: Rq,
+    ) -> Result<Rf::Item, BlockingError>
+    where
+        Du: ConnectionUpgrade<T::RawConn> + Send + 'static,
+        Rq: FnOnce(Du::Output, Multiaddr) -> Rf + Send + 'static,
+        Rf: IntoFuture<Error = IoError> + 'static,
+        Rf::Item: Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let dial = self.execute(move |controller, _| {
+            controller.dial_custom_handler(addr, upgrade, move |output, addr| {
+                request(output, addr).into_future().then(move |result| {
+                    let _ = tx.send(result);
+                    Ok::<_, IoError>(())
+                })
+            })
+        })?;
+        dial.map_err(BlockingError::from)?;
+
+        match rx.recv_timeout(timeout) {
+            Ok(Ok(item)) => Ok(item),
+            Ok(Err(err)) => Err(BlockingError::Io(err)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(BlockingError::Timeout),
+            // The dialing failed before `request` was called.
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(BlockingError::Stopped),
+        }
+    }
+
+    /// Returns a subscription to the events sent by the handler through its `EventSender`. Only
+    /// the events sent after this method has been called are received.
+    #[inline]
+    pub fn subscribe(&self) -> Subscription<E>
+    where
+        E: Clone,
+    {
+        self.events.subscribe()
+    }
+
+    // Runs `command` on the thread of the reactor and waits for its result.
+    fn execute<F, R>(&self, command: F) -> Result<R, BlockingError>
+    where
+        F: FnOnce(&SwarmController<T, C>, &Handle) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let mut command = Some(command);
+        let command: Command<T, C> = Box::new(move |controller, handle| {
+            if let Some(command) = command.take() {
+                let _ = tx.send(command(controller, handle));
+            }
+        });
+
+        let commands = self.commands.as_ref().expect("only taken when destroyed");
+        if commands.unbounded_send(command).is_err() {
+            return Err(BlockingError::Stopped);
+        }
+
+        rx.recv().map_err(|_| BlockingError::Stopped)
+    }
+}
+
+impl<T, C, E> Drop for BlockingSwarm<T, C, E>
+where
+    T: MuxedTransport + 'static,
+    C: ConnectionUpgrade<T::RawConn> + 'static,
+{
+    fn drop(&mut self) {
+        // Closing the channel of commands stops the background thread.
+        self.commands = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Object passed to the closure that builds the swarm of a `BlockingSwarm`, and that sends
+/// events to the subscriptions returned by `BlockingSwarm::subscribe`.
+pub struct EventSender<E> {
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<E>>>>,
+}
+
+impl<E> EventSender<E>
+where
+    E: Clone,
+{
+    /// Sends `event` to all the current subscriptions.
+    pub fn send(&self, event: E) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        // Subscriptions that have been destroyed are removed.
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    fn subscribe(&self) -> Subscription<E> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        Subscription { events: rx }
+    }
+}
+
+impl<E> Clone for EventSender<E> {
+    #[inline]
+    fn clone(&self) -> Self {
+        EventSender {
+            subscribers: self.subscribers.clone(),
+        }
+    }
+}
+
+/// Events sent by the handler of a `BlockingSwarm`. Returned by `BlockingSwarm::subscribe`.
+pub struct Subscription<E> {
+    events: mpsc::Receiver<E>,
+}
+
+impl<E> Subscription<E> {
+    /// Waits for the next event, at most for `timeout`.
+    pub fn next(&self, timeout: Duration) -> Result<E, BlockingError> {
+        self.events.recv_timeout(timeout).map_err(|err| match err {
+            mpsc::RecvTimeoutError::Timeout => BlockingError::Timeout,
+            mpsc::RecvTimeoutError::Disconnected => BlockingError::Stopped,
+        })
+    }
+
+    /// Returns the next event if one is available, without waiting.
+    #[inline]
+    pub fn try_next(&self) -> Option<E> {
+        self.events.try_recv().ok()
+    }
+}
+
+/// Error produced by the methods of `BlockingSwarm`.
+#[derive(Debug)]
+pub enum BlockingError {
+    /// The operation didn't finish in time.
+    Timeout,
+    /// The transport doesn't support this multiaddress.
+    MultiaddrNotSupported(Multiaddr),
+    /// A connection limit of the swarm has been reached.
+    Limit(ConnectionLimit),
+    /// The peer is banned.
+    Banned(PeerId),
+    /// The operation failed.
+    Io(IoError),
+    /// The swarm isn't running anymore, or the operation was aborted.
+    Stopped,
+}
+
+impl fmt::Display for BlockingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BlockingError::Timeout => write!(f, "operation timed out"),
+            BlockingError::MultiaddrNotSupported(ref addr) => {
+                write!(f, "multiaddress not supported: {}", addr)
+            }
+            BlockingError::Limit(ref limit) => write!(f, "{}", limit),
+            BlockingError::Banned(ref peer) => write!(f, "{:?} is banned", peer),
+            BlockingError::Io(ref err) => write!(f, "{}", err),
+            BlockingError::Stopped => write!(f, "the swarm isn't running"),
+        }
+    }
+}
+
+impl error::Error for BlockingError {
+    fn description(&self) -> &str {
+        match *self {
+            BlockingError::Timeout => "operation timed out",
+            BlockingError::MultiaddrNotSupported(_) => "multiaddress not supported",
+            BlockingError::Limit(_) => "connection limit reached",
+            BlockingError::Banned(_) => "the peer is banned",
+            BlockingError::Io(ref err) => err.description(),
+            BlockingError::Stopped => "the swarm isn't running",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            BlockingError::Limit(ref limit) => Some(limit),
+            BlockingError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<DialError> for BlockingError {
+    #[inline]
+    fn from(err: DialError) -> BlockingError {
+        match err {
+            DialError::MultiaddrNotSupported(addr) => BlockingError::MultiaddrNotSupported(addr),
+            DialError::Limit(limit) => BlockingError::Limit(limit),
+            DialError::Banned(peer) => BlockingError::Banned(peer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate libp2p_tcp_transport;
+
+    use super::{BlockingError, BlockingSwarm};
+    use futures::future;
+    use self::libp2p_tcp_transport::TcpConfig;
+    use std::io::Error as IoError;
+    use std::time::Duration;
+    use swarm::swarm;
+    use transport::DummyMuxing;
+    use {Multiaddr, PlainTextConfig, Transport};
+
+    // Swarm whose handler reports the address of each connection as an event.
+    fn blocking_swarm() -> BlockingSwarm<DummyMuxing<TcpConfig>, PlainTextConfig, Multiaddr> {
+        BlockingSwarm::new(|handle, events| {
+            let transport = TcpConfig::new(handle.clone()).with_dummy_muxing();
+            swarm(transport, PlainTextConfig, move |_, addr| {
+                events.send(addr);
+                Ok::<_, IoError>(())
+            })
+        }).unwrap()
+    }
+
+    #[test]
+    fn request_and_events() {
+        let swarm = blocking_swarm();
+        let addr = swarm.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let events = swarm.subscribe();
+
+        let answer = swarm
+            .send_request(addr, PlainTextConfig, Duration::from_secs(5), |_, _| {
+                Ok::<_, IoError>(5)
+            })
+            .unwrap();
+        assert_eq!(answer, 5);
+        assert!(events.next(Duration::from_secs(5)).is_ok());
+        assert!(events.try_next().is_none());
+    }
+
+    #[test]
+    fn timeouts() {
+        let swarm = blocking_swarm();
+        let addr = swarm.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+
+        let result = swarm.send_request(addr, PlainTextConfig, Duration::from_millis(100), |_, _| {
+            future::empty::<(), IoError>()
+        });
+        match result {
+            Err(BlockingError::Timeout) => (),
+            _ => panic!(),
+        }
+
+        match swarm.subscribe().next(Duration::from_millis(10)) {
+            Err(BlockingError::Timeout) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn unsupported_multiaddr() {
+        let swarm = blocking_swarm();
+        let addr: Multiaddr = "/ip4/127.0.0.1/udp/1234".parse().unwrap();
+        match swarm.listen_on(addr.clone()) {
+            Err(BlockingError::MultiaddrNotSupported(ref a)) if *a == addr => (),
+            _ => panic!(),
+        }
+        match swarm.dial(addr.clone(), PlainTextConfig) {
+            Err(BlockingError::MultiaddrNotSupported(ref a)) if *a == addr => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn stopped_once_destroyed() {
+        let swarm = blocking_swarm();
+        let events = swarm.subscribe();
+        drop(swarm);
+
+        match events.next(Duration::from_secs(5)) {
+            Err(BlockingError::Stopped) => (),
+            _ => panic!(),
+        }
+    }
+}
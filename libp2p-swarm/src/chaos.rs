@@ -0,0 +1,502 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Chaos testing hooks, enabled with the `chaos` feature.
+//!
+//! Applications built on top of *libp2p* must cope with remotes and networks that misbehave. The
+//! `Chaos` struct injects such misbehaviour on purpose: it can delay upgrades, reset substreams,
+//! drop connections and deliver the output of an upgrade twice to the handler.
+//!
+//! All the decisions are drawn from a pseudo-random generator initialized with a seed. Running
+//! the same scenario with the same seed makes the same decisions, which makes the failures found
+//! in CI reproducible.
+//!
+//! ```ignore
+//! let chaos = Chaos::new(ChaosConfig::new(1234).with_reset_probability(0.1));
+//! let upgrade = chaos.upgrade(upgrade);
+//! let mut handler = chaos.handler(handler);
+//! let (controller, future) = swarm(transport, upgrade, move |output, addr| {
+//!     handler.handle(output, addr)
+//! });
+//! ```
+
+use futures::{Async, Future, IntoFuture, Poll};
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_io::{AsyncRead, AsyncWrite};
+use {Clock, ConnectionUpgrade, Endpoint, LocalIdentity, Multiaddr, TokioClock};
+
+/// Configuration of the misbehaviour injected by `Chaos`.
+///
+/// All the probabilities are between `0.0` and `1.0`, and are `0.0` by default.
+#[derive(Debug, Clone)]
+pub struct ChaosConfig {
+    seed: u64,
+    delay_probability: f64,
+    max_delay: Duration,
+    reset_probability: f64,
+    max_bytes_before_reset: usize,
+    drop_probability: f64,
+    duplicate_probability: f64,
+}
+
+impl ChaosConfig {
+    /// Builds a configuration that doesn't inject anything, with the given seed.
+    pub fn new(seed: u64) -> ChaosConfig {
+        ChaosConfig {
+            seed: seed,
+            delay_probability: 0.0,
+            max_delay: Duration::from_secs(1),
+            reset_probability: 0.0,
+            max_bytes_before_reset: 16 * 1024,
+            drop_probability: 0.0,
+            duplicate_probability: 0.0,
+        }
+    }
+
+    /// Delays the upgrade of a substream with the given probability, by a random duration of at
+    /// most `max_delay`.
+    #[inline]
+    pub fn with_delay_probability(mut self, probability: f64, max_delay: Duration) -> Self {
+        self.delay_probability = probability;
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Resets a substream with the given probability, after a random number of bytes have been
+    /// read or written. Afterwards, all the operations on the substream fail with an error of
+    /// kind `ConnectionReset`.
+    #[inline]
+    pub fn with_reset_probability(mut self, probability: f64) -> Self {
+        self.reset_probability = probability;
+        self
+    }
+
+    /// Sets the maximum number of bytes that go through a substream before it is reset.
+    ///
+    /// The default value is 16 kiB.
+    #[inline]
+    pub fn with_max_bytes_before_reset(mut self, max: usize) -> Self {
+        self.max_bytes_before_reset = max;
+        self
+    }
+
+    /// Fails the upgrade of a substream with the given probability, with an error of kind
+    /// `ConnectionAborted`, as if the remote had dropped the connection.
+    #[inline]
+    pub fn with_drop_probability(mut self, probability: f64) -> Self {
+        self.drop_probability = probability;
+        self
+    }
+
+    /// Passes the output of an upgrade twice to the handler wrapped with `Chaos::handler`, with
+    /// the given probability.
+    #[inline]
+    pub fn with_duplicate_probability(mut self, probability: f64) -> Self {
+        self.duplicate_probability = probability;
+        self
+    }
+}
+
+/// Injects misbehaviour according to a `ChaosConfig`.
+///
+/// Cloning a `Chaos` shares the pseudo-random generator, so that the sequence of decisions only
+/// depends on the seed and on the order of the events.
+#[derive(Clone)]
+pub struct Chaos {
+    config: Arc<ChaosConfig>,
+    rng: Arc<Mutex<XorShiftRng>>,
+}
+
+impl Chaos {
+    /// Builds a new `Chaos`.
+    pub fn new(config: ChaosConfig) -> Chaos {
+        let seed = config.seed;
+        let (low, high) = (seed as u32, (seed >> 32) as u32);
+        // The seed of a `XorShiftRng` must not be entirely zero.
+        let rng = XorShiftRng::from_seed([low, high, !low, !high]);
+        Chaos {
+            config: Arc::new(config),
+            rng: Arc::new(Mutex::new(rng)),
+        }
+    }
+
+    /// Wraps around an upgrade so that the substreams it upgrades are delayed, reset or dropped.
+    #[inline]
+    pub fn upgrade<U>(&self, upgrade: U) -> ChaosUpgrade<U> {
+        self.upgrade_with_clock(upgrade, TokioClock::new())
+    }
+
+    /// Same as `upgrade`, but uses the given `Clock` in order to wait.
+    #[inline]
+    pub fn upgrade_with_clock<U, Ck>(&self, upgrade: U, clock: Ck) -> ChaosUpgrade<U, Ck>
+    where
+        Ck: Clock,
+    {
+        ChaosUpgrade {
+            upgrade: upgrade,
+            chaos: self.clone(),
+            clock: clock,
+        }
+    }
+
+    /// Wraps around the handler of a swarm so that outputs are sometimes passed to it twice.
+    #[inline]
+    pub fn handler<H>(&self, handler: H) -> ChaosHandler<H> {
+        ChaosHandler {
+            handler: handler,
+            chaos: self.clone(),
+        }
+    }
+
+    // Returns true with the given probability.
+    fn draw(&self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.lock().unwrap().next_f64() < probability
+    }
+
+    // Returns a random number between 0 and `max`, inclusive.
+    fn draw_up_to(&self, max: u64) -> u64 {
+        self.rng.lock().unwrap().next_u64() % max.saturating_add(1)
+    }
+}
+
+impl fmt::Debug for Chaos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Chaos").field("config", &self.config).finish()
+    }
+}
+
+/// Upgrade returned by `Chaos::upgrade`.
+#[derive(Clone)]
+pub struct ChaosUpgrade<U, Ck = TokioClock> {
+    upgrade: U,
+    chaos: Chaos,
+    clock: Ck,
+}
+
+impl<C, U, Ck> ConnectionUpgrade<C> for ChaosUpgrade<U, Ck>
+where
+    C: AsyncRead + AsyncWrite,
+    U: ConnectionUpgrade<ChaosSocket<C>>,
+    Ck: Clock,
+{
+    type NamesIter = U::NamesIter;
+    type UpgradeIdentifier = U::UpgradeIdentifier;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        self.upgrade.protocol_names()
+    }
+
+    type Output = U::Output;
+    type Future = ChaosFuture<U::Future, Ck::Delay>;
+
+    fn upgrade(
+        self,
+        socket: C,
+        id: Self::UpgradeIdentifier,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        let chaos = &self.chaos;
+        let config = &chaos.config;
+
+        if chaos.draw(config.drop_probability) {
+            debug!(target: "libp2p-swarm", "Chaos: dropping substream with {}", remote_addr);
+            return ChaosFuture {
+                state: ChaosFutureState::Dropped,
+            };
+        }
+
+        let reset_after = if chaos.draw(config.reset_probability) {
+            let bytes = chaos.draw_up_to(config.max_bytes_before_reset as u64) as usize;
+            debug!(target: "libp2p-swarm", "Chaos: resetting substream with {} after {} bytes",
+                   remote_addr, bytes);
+            Some(bytes)
+        } else {
+            None
+        };
+
+        let delay = if chaos.draw(config.delay_probability) {
+            let max = config.max_delay.as_secs() * 1000
+                + u64::from(config.max_delay.subsec_nanos()) / 1_000_000;
+            let delay = Duration::from_millis(chaos.draw_up_to(max));
+            debug!(target: "libp2p-swarm", "Chaos: delaying substream with {} by {:?}",
+                   remote_addr, delay);
+            Some(self.clock.delay(delay))
+        } else {
+            None
+        };
+
+        let socket = ChaosSocket {
+            inner: socket,
+            reset_after: reset_after,
+        };
+
+        let inner = self.upgrade.upgrade(socket, id, ty, remote_addr, local_identity);
+        ChaosFuture {
+            state: ChaosFutureState::Upgrading {
+                delay: delay,
+                inner: inner,
+            },
+        }
+    }
+}
+
+/// Future returned by `ChaosUpgrade::upgrade`.
+pub struct ChaosFuture<F, D> {
+    state: ChaosFutureState<F, D>,
+}
+
+enum ChaosFutureState<F, D> {
+    // The inner future is only polled once the delay has elapsed.
+    Upgrading { delay: Option<D>, inner: F },
+    Dropped,
+}
+
+impl<F, D> Future for ChaosFuture<F, D>
+where
+    F: Future<Error = IoError>,
+    D: Future<Item = (), Error = IoError>,
+{
+    type Item = F::Item;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.state {
+            ChaosFutureState::Upgrading {
+                ref mut delay,
+                ref mut inner,
+            } => {
+                if let Some(mut timeout) = delay.take() {
+                    if timeout.poll()?.is_not_ready() {
+                        *delay = Some(timeout);
+                        return Ok(Async::NotReady);
+                    }
+                }
+                inner.poll()
+            }
+            ChaosFutureState::Dropped => Err(IoError::new(
+                IoErrorKind::ConnectionAborted,
+                "connection dropped by the chaos hooks",
+            )),
+        }
+    }
+}
+
+/// Socket passed to the upgrade wrapped by a `ChaosUpgrade`. May be reset after some bytes.
+pub struct ChaosSocket<C> {
+    inner: C,
+    // Number of bytes that can still go through the socket before it is reset.
+    reset_after: Option<usize>,
+}
+
+impl<C> ChaosSocket<C> {
+    // Returns the maximum number of bytes of `len` that can go through, or an error if the
+    // socket has been reset.
+    fn allowed(&self, len: usize) -> Result<usize, IoError> {
+        match self.reset_after {
+            Some(0) if len != 0 => Err(IoError::new(
+                IoErrorKind::ConnectionReset,
+                "substream reset by the chaos hooks",
+            )),
+            Some(remaining) => Ok(len.min(remaining)),
+            None => Ok(len),
+        }
+    }
+
+    fn consumed(&mut self, len: usize) {
+        if let Some(ref mut remaining) = self.reset_after {
+            *remaining -= len;
+        }
+    }
+}
+
+impl<C> Read for ChaosSocket<C>
+where
+    C: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let len = self.allowed(buf.len())?;
+        let read = self.inner.read(&mut buf[..len])?;
+        self.consumed(read);
+        Ok(read)
+    }
+}
+
+impl<C> AsyncRead for ChaosSocket<C>
+where
+    C: AsyncRead,
+{
+    #[inline]
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<C> Write for ChaosSocket<C>
+where
+    C: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let len = self.allowed(buf.len())?;
+        let written = self.inner.write(&buf[..len])?;
+        self.consumed(written);
+        Ok(written)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.allowed(1)?;
+        self.inner.flush()
+    }
+}
+
+impl<C> AsyncWrite for ChaosSocket<C>
+where
+    C: AsyncWrite,
+{
+    #[inline]
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        self.allowed(1)?;
+        self.inner.shutdown()
+    }
+}
+
+/// Handler returned by `Chaos::handler`. Call `handle` from the handler passed to the swarm.
+pub struct ChaosHandler<H> {
+    handler: H,
+    chaos: Chaos,
+}
+
+impl<H> ChaosHandler<H> {
+    /// Passes `output` to the inner handler, sometimes twice. The returned future finishes once
+    /// all the futures returned by the inner handler have finished.
+    pub fn handle<O, F>(
+        &mut self,
+        output: O,
+        addr: Multiaddr,
+    ) -> Box<Future<Item = (), Error = IoError>>
+    where
+        H: FnMut(O, Multiaddr) -> F,
+        O: Clone,
+        F: IntoFuture<Item = (), Error = IoError>,
+        F::Future: 'static, // TODO: 'static :-/
+    {
+        if self.chaos.draw(self.chaos.config.duplicate_probability) {
+            debug!(target: "libp2p-swarm", "Chaos: duplicating output from {}", addr);
+            let first = (self.handler)(output.clone(), addr.clone()).into_future();
+            let second = (self.handler)(output, addr).into_future();
+            Box::new(first.join(second).map(|_| ()))
+        } else {
+            Box::new((self.handler)(output, addr).into_future())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chaos, ChaosConfig, ChaosFuture, ChaosFutureState, ChaosSocket};
+    use futures::{future, Async, Future};
+    use libp2p_core::ManualClock;
+    use std::cell::Cell;
+    use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind, Read};
+    use std::rc::Rc;
+    use std::time::Duration;
+    use {Clock, Multiaddr};
+
+    fn decisions(chaos: &Chaos) -> Vec<(bool, u64)> {
+        (0..64).map(|_| (chaos.draw(0.5), chaos.draw_up_to(1000))).collect()
+    }
+
+    #[test]
+    fn same_seed_same_decisions() {
+        let first = Chaos::new(ChaosConfig::new(1234));
+        let second = Chaos::new(ChaosConfig::new(1234));
+        assert_eq!(decisions(&first), decisions(&second));
+
+        let other = Chaos::new(ChaosConfig::new(4321));
+        assert_ne!(decisions(&first), decisions(&other));
+    }
+
+    #[test]
+    fn zero_seed() {
+        let chaos = Chaos::new(ChaosConfig::new(0));
+        assert!(decisions(&chaos).iter().any(|&(drawn, _)| drawn));
+    }
+
+    #[test]
+    fn never_draws_with_zero_probability() {
+        let chaos = Chaos::new(ChaosConfig::new(1234));
+        assert!((0..64).all(|_| !chaos.draw(0.0)));
+    }
+
+    #[test]
+    fn socket_reset_after_bytes() {
+        let mut socket = ChaosSocket {
+            inner: Cursor::new(vec![1, 2, 3, 4, 5]),
+            reset_after: Some(3),
+        };
+
+        let mut buf = [0; 5];
+        assert_eq!(socket.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+        let err = socket.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn delay_uses_clock() {
+        let clock = ManualClock::new();
+        let mut future = ChaosFuture {
+            state: ChaosFutureState::Upgrading {
+                delay: Some(clock.delay(Duration::from_secs(1))),
+                inner: future::ok::<_, IoError>(5),
+            },
+        };
+
+        let mut future = future::lazy(move || {
+            assert_eq!(future.poll().unwrap(), Async::NotReady);
+            Ok::<_, ()>(future)
+        }).wait()
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(future.poll().unwrap(), Async::Ready(5));
+    }
+
+    #[test]
+    fn duplicate_output() {
+        let chaos = Chaos::new(ChaosConfig::new(1234).with_duplicate_probability(1.0));
+        let calls = Rc::new(Cell::new(0));
+        let calls2 = calls.clone();
+        let mut handler = chaos.handler(move |(), _: Multiaddr| {
+            calls2.set(calls2.get() + 1);
+            Ok::<_, IoError>(())
+        });
+
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1234".parse().unwrap();
+        handler.handle((), addr).wait().unwrap();
+        assert_eq!(calls.get(), 2);
+    }
+}
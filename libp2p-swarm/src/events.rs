@@ -0,0 +1,179 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Events describing the connectivity of a swarm.
+//!
+//! Call `SwarmController::events` to obtain a stream of `SwarmEvent`s. Each call creates a new
+//! subscription, and each subscription receives all the events that happen after it has been
+//! created. The events are produced while the swarm future is polled, except for `NewListenAddr`
+//! which is produced by `listen_on` itself.
+//!
+//! Only the connections whose output is given to the handler of the swarm are reported. The ones
+//! opened with `dial_custom_handler`, `dial_any` or `probe_protocol` are managed by their caller.
+
+use std::io::Error as IoError;
+use std::sync::Arc;
+use {Endpoint, Multiaddr};
+
+/// Event that happened in a swarm.
+///
+/// The errors are wrapped in an `Arc` so that the events can be cloned for each subscription.
+#[derive(Debug, Clone)]
+pub enum SwarmEvent {
+    /// A connection, or a substream over an existing muxed connection, has been upgraded and its
+    /// output has been given to the handler.
+    ConnectionEstablished {
+        /// Address of the remote.
+        remote_addr: Multiaddr,
+        /// Whether we dialed the remote or the remote dialed us.
+        endpoint: Endpoint,
+    },
+    /// The future returned by the handler for a connection has finished.
+    ConnectionClosed {
+        /// Address of the remote.
+        remote_addr: Multiaddr,
+        /// Whether we dialed the remote or the remote dialed us.
+        endpoint: Endpoint,
+        /// Error produced by the handler, or `None` if it finished successfully.
+        cause: Option<Arc<IoError>>,
+    },
+    /// A remote has opened a connection, which is now being upgraded.
+    IncomingConnection {
+        /// Address of the listener that accepted the connection, or `None` for a substream
+        /// opened over an existing muxed connection.
+        listen_addr: Option<Multiaddr>,
+    },
+    /// Dialing a remote, or upgrading the connection to it, has failed.
+    DialFailure {
+        /// Address that was dialed.
+        address: Multiaddr,
+        /// Reason of the failure.
+        error: Arc<IoError>,
+    },
+    /// The swarm has started listening on a new address.
+    NewListenAddr {
+        /// Address we are listening on.
+        address: Multiaddr,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate libp2p_tcp_transport;
+
+    use super::SwarmEvent;
+    use futures::{Future, Stream};
+    use self::libp2p_tcp_transport::TcpConfig;
+    use std::io::Error as IoError;
+    use std::net::TcpListener;
+    use swarm::swarm;
+    use tokio_core::reactor::Core;
+    use {Endpoint, Multiaddr, PlainTextConfig, Transport};
+
+    #[test]
+    fn incoming_connection() {
+        let mut core = Core::new().unwrap();
+        let transport = TcpConfig::new(core.handle()).with_dummy_muxing();
+        let (controller, future) =
+            swarm(transport, PlainTextConfig, |_, _| Ok::<_, IoError>(()));
+        let events = controller.events();
+        let addr = controller.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        core.handle().spawn(future.map_err(|_| ()));
+
+        let dial = TcpConfig::new(core.handle())
+            .with_upgrade(PlainTextConfig)
+            .dial(addr.clone())
+            .unwrap_or_else(|_| panic!())
+            .map(|_| ());
+        let events = events.take(4).collect().map_err(|_| -> IoError { panic!() });
+        let (_, events) = core.run(dial.join(events)).unwrap();
+
+        match events[0] {
+            SwarmEvent::NewListenAddr { ref address } => assert_eq!(*address, addr),
+            _ => panic!(),
+        }
+        match events[1] {
+            SwarmEvent::IncomingConnection { ref listen_addr } => {
+                assert_eq!(listen_addr.as_ref(), Some(&addr))
+            }
+            _ => panic!(),
+        }
+        match events[2] {
+            SwarmEvent::ConnectionEstablished { endpoint, .. } => {
+                assert_eq!(endpoint, Endpoint::Listener)
+            }
+            _ => panic!(),
+        }
+        match events[3] {
+            SwarmEvent::ConnectionClosed { endpoint, ref cause, .. } => {
+                assert_eq!(endpoint, Endpoint::Listener);
+                assert!(cause.is_none());
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn dial_failure() {
+        let mut core = Core::new().unwrap();
+        let transport = TcpConfig::new(core.handle()).with_dummy_muxing();
+        let (controller, future) =
+            swarm(transport, PlainTextConfig, |_, _| Ok::<_, IoError>(()));
+        core.handle().spawn(future.map_err(|_| ()));
+
+        // Nothing listens on this port anymore.
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap();
+
+        let events = controller.events();
+        controller.dial_to_handler(addr.clone(), PlainTextConfig).unwrap();
+        let (event, _) = core.run(events.into_future().map_err(|_| ())).unwrap();
+        match event {
+            Some(SwarmEvent::DialFailure { ref address, .. }) => assert_eq!(*address, addr),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn subscriptions_only_get_later_events() {
+        let core = Core::new().unwrap();
+        let transport = TcpConfig::new(core.handle()).with_dummy_muxing();
+        let (controller, _future) =
+            swarm(transport, PlainTextConfig, |_, _| Ok::<_, IoError>(()));
+
+        let first = controller.events();
+        let addr1 = controller.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let second = controller.events();
+        let addr2 = controller.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        drop(controller);
+
+        let addresses = |events: Vec<SwarmEvent>| {
+            events
+                .into_iter()
+                .map(|event| match event {
+                    SwarmEvent::NewListenAddr { address } => address,
+                    _ => panic!(),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(addresses(first.take(2).collect().wait().unwrap()), vec![addr1, addr2.clone()]);
+        assert_eq!(addresses(second.take(1).collect().wait().unwrap()), vec![addr2]);
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains `FallbackTransport`, which combines two transports and dials with the second one if
+//! dialing with the first one fails.
+
+use futures::{future, Future, IntoFuture};
+use std::error;
+use std::fmt;
+use std::io::Error as IoError;
+use transport::{EitherListenStream, EitherListenUpgrade, EitherSocket};
+use {LocalIdentity, Multiaddr, MuxedTransport, Transport};
+
+/// Combination of two transports, for example TCP and WebSocket.
+///
+/// Contrary to `Transport::or_transport`, which only uses the second transport for the
+/// multiaddresses that the first one doesn't support, a `FallbackTransport` also dials with the
+/// second transport if dialing with the first one has started but fails. This is useful when
+/// both transports support the same multiaddresses, but one of them may be blocked by the
+/// network, such as a firewall that only lets WebSocket connections through a proxy.
+///
+/// Listening uses the first transport that supports the multiaddress.
+///
+/// If both dialing attempts fail, the error is of kind of the second failure and wraps a
+/// `FallbackError` that contains both.
+#[derive(Debug, Copy, Clone)]
+pub struct FallbackTransport<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> FallbackTransport<A, B> {
+    /// Builds a `FallbackTransport` that tries `first`, then `second`.
+    #[inline]
+    pub fn new(first: A, second: B) -> FallbackTransport<A, B> {
+        FallbackTransport {
+            first: first,
+            second: second,
+        }
+    }
+}
+
+impl<A, B> Transport for FallbackTransport<A, B>
+where
+    A: Transport,
+    B: Transport + 'static, // TODO: 'static :-/
+    A::RawConn: 'static,    // TODO: 'static :-/
+    B::RawConn: 'static,    // TODO: 'static :-/
+    <A::Dial as IntoFuture>::Future: 'static, // TODO: 'static :-/
+    <B::Dial as IntoFuture>::Future: 'static, // TODO: 'static :-/
+{
+    type RawConn = EitherSocket<A::RawConn, B::RawConn>;
+    type Listener = EitherListenStream<A::Listener, B::Listener>;
+    type ListenerUpgrade = EitherListenUpgrade<A::ListenerUpgrade, B::ListenerUpgrade>;
+    type Dial = Box<Future<Item = (Self::RawConn, Multiaddr), Error = IoError>>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+        let (first, addr) = match self.first.listen_on(addr) {
+            Ok((listener, addr)) => return Ok((EitherListenStream::First(listener), addr)),
+            Err(err) => err,
+        };
+
+        match self.second.listen_on(addr) {
+            Ok((listener, addr)) => Ok((EitherListenStream::Second(listener), addr)),
+            Err((second, addr)) => Err((FallbackTransport::new(first, second), addr)),
+        }
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+        let second = self.second;
+        let (first, addr) = match self.first.dial(addr.clone()) {
+            Ok(dial) => {
+                let future = dial.into_future().then(move |result| match result {
+                    Ok((socket, addr)) => {
+                        let output = (EitherSocket::First(socket), addr);
+                        Box::new(future::ok(output)) as Box<Future<Item = _, Error = _>>
+                    }
+                    Err(first_err) => dial_second(second, addr, first_err),
+                });
+                return Ok(Box::new(future) as Box<_>);
+            }
+            Err(err) => err,
+        };
+
+        match second.dial(addr) {
+            Ok(dial) => {
+                let future = dial.into_future()
+                    .map(|(socket, addr)| (EitherSocket::Second(socket), addr));
+                Ok(Box::new(future) as Box<_>)
+            }
+            Err((second, addr)) => Err((FallbackTransport::new(first, second), addr)),
+        }
+    }
+
+    #[inline]
+    fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.first
+            .nat_traversal(server, observed)
+            .or_else(|| self.second.nat_traversal(server, observed))
+    }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        let first = self.first.local_identity();
+        if first.public_key().is_some() {
+            return first;
+        }
+
+        self.second.local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.first.reports_peer_ids() && self.second.reports_peer_ids()
+    }
+}
+
+// Dials `addr` with `second` after dialing with the first transport failed with `first_err`.
+fn dial_second<A, B>(
+    second: B,
+    addr: Multiaddr,
+    first_err: IoError,
+) -> Box<Future<Item = (EitherSocket<A, B::RawConn>, Multiaddr), Error = IoError>>
+where
+    A: 'static,             // TODO: 'static :-/
+    B: Transport + 'static, // TODO: 'static :-/
+    B::RawConn: 'static,    // TODO: 'static :-/
+    <B::Dial as IntoFuture>::Future: 'static, // TODO: 'static :-/
+{
+    debug!("Dialing {} failed, falling back to the second transport: {}", addr, first_err);
+
+    match second.dial(addr) {
+        Ok(dial) => {
+            let future = dial.into_future()
+                .map(|(socket, addr)| (EitherSocket::Second(socket), addr))
+                .map_err(move |second_err| {
+                    let kind = second_err.kind();
+                    IoError::new(kind, FallbackError {
+                        first: first_err,
+                        second: second_err,
+                    })
+                });
+            Box::new(future)
+        }
+        // The second transport doesn't support the multiaddress. Only the first error matters.
+        Err(_) => Box::new(future::err(first_err)),
+    }
+}
+
+impl<A, B> MuxedTransport for FallbackTransport<A, B>
+where
+    A: MuxedTransport,
+    B: MuxedTransport + 'static, // TODO: 'static :-/
+    A::Incoming: 'static,        // TODO: 'static :-/
+    B::Incoming: 'static,        // TODO: 'static :-/
+    A::IncomingUpgrade: 'static, // TODO: 'static :-/
+    B::IncomingUpgrade: 'static, // TODO: 'static :-/
+    A::RawConn: 'static,         // TODO: 'static :-/
+    B::RawConn: 'static,         // TODO: 'static :-/
+    <A::Dial as IntoFuture>::Future: 'static, // TODO: 'static :-/
+    <B::Dial as IntoFuture>::Future: 'static, // TODO: 'static :-/
+{
+    type Incoming = Box<Future<Item = Self::IncomingUpgrade, Error = IoError>>;
+    type IncomingUpgrade =
+        Box<Future<Item = (EitherSocket<A::RawConn, B::RawConn>, Multiaddr), Error = IoError>>;
+
+    #[inline]
+    fn next_incoming(self) -> Self::Incoming {
+        let first = self.first.next_incoming().map(|out| {
+            let fut = out.map(move |(v, addr)| (EitherSocket::First(v), addr));
+            Box::new(fut) as Box<Future<Item = _, Error = _>>
+        });
+        let second = self.second.next_incoming().map(|out| {
+            let fut = out.map(move |(v, addr)| (EitherSocket::Second(v), addr));
+            Box::new(fut) as Box<Future<Item = _, Error = _>>
+        });
+        let future = first.select(second).map(|(i, _)| i).map_err(|(e, _)| e);
+        Box::new(future) as Box<_>
+    }
+}
+
+/// Error produced by a `FallbackTransport` when dialing failed with both transports.
+#[derive(Debug)]
+pub struct FallbackError {
+    first: IoError,
+    second: IoError,
+}
+
+impl FallbackError {
+    /// Returns the error produced by the first transport.
+    #[inline]
+    pub fn first(&self) -> &IoError {
+        &self.first
+    }
+
+    /// Returns the error produced by the second transport.
+    #[inline]
+    pub fn second(&self) -> &IoError {
+        &self.second
+    }
+}
+
+impl fmt::Display for FallbackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "dialing failed with both transports: {}; {}", self.first, self.second)
+    }
+}
+
+impl error::Error for FallbackError {
+    #[inline]
+    fn description(&self) -> &str {
+        "dialing failed with both transports"
+    }
+
+    #[inline]
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&self.second)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FallbackError, FallbackTransport};
+    use futures::{future, Future};
+    use futures::future::FutureResult;
+    use futures::stream::Empty;
+    use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind};
+    use transport::EitherSocket;
+    use {Multiaddr, Transport};
+
+    // Transport that supports the multiaddresses if `supported` is true, and whose dialing
+    // attempts fail with `error` if it is `Some`.
+    #[derive(Debug, Copy, Clone)]
+    struct Mock {
+        supported: bool,
+        error: Option<IoErrorKind>,
+    }
+
+    impl Transport for Mock {
+        type RawConn = Cursor<Vec<u8>>;
+        type Listener = Empty<Self::ListenerUpgrade, IoError>;
+        type ListenerUpgrade = FutureResult<(Self::RawConn, Multiaddr), IoError>;
+        type Dial = FutureResult<(Self::RawConn, Multiaddr), IoError>;
+
+        fn listen_on(
+            self,
+            addr: Multiaddr,
+        ) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+            Err((self, addr))
+        }
+
+        fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+            if !self.supported {
+                return Err((self, addr));
+            }
+            match self.error {
+                Some(kind) => Ok(future::err(kind.into())),
+                None => Ok(future::ok((Cursor::new(Vec::new()), addr))),
+            }
+        }
+
+        fn nat_traversal(&self, _: &Multiaddr, _: &Multiaddr) -> Option<Multiaddr> {
+            None
+        }
+    }
+
+    fn mock(supported: bool, error: Option<IoErrorKind>) -> Mock {
+        Mock {
+            supported: supported,
+            error: error,
+        }
+    }
+
+    fn addr() -> Multiaddr {
+        "/ip4/127.0.0.1/tcp/1234".parse().unwrap()
+    }
+
+    #[test]
+    fn first_succeeds() {
+        let transport = FallbackTransport::new(mock(true, None), mock(true, None));
+        let (socket, _) = transport.dial(addr()).unwrap_or_else(|_| panic!()).wait().unwrap();
+        match socket {
+            EitherSocket::First(_) => (),
+            EitherSocket::Second(_) => panic!(),
+        }
+    }
+
+    #[test]
+    fn falls_back_when_dialing_fails() {
+        let first = mock(true, Some(IoErrorKind::ConnectionRefused));
+        let transport = FallbackTransport::new(first, mock(true, None));
+        let (socket, _) = transport.dial(addr()).unwrap_or_else(|_| panic!()).wait().unwrap();
+        match socket {
+            EitherSocket::First(_) => panic!(),
+            EitherSocket::Second(_) => (),
+        }
+    }
+
+    #[test]
+    fn falls_back_when_not_supported() {
+        let transport = FallbackTransport::new(mock(false, None), mock(true, None));
+        let (socket, _) = transport.dial(addr()).unwrap_or_else(|_| panic!()).wait().unwrap();
+        match socket {
+            EitherSocket::First(_) => panic!(),
+            EitherSocket::Second(_) => (),
+        }
+    }
+
+    #[test]
+    fn both_fail() {
+        let first = mock(true, Some(IoErrorKind::ConnectionRefused));
+        let second = mock(true, Some(IoErrorKind::TimedOut));
+        let transport = FallbackTransport::new(first, second);
+        let err = match transport.dial(addr()).unwrap_or_else(|_| panic!()).wait() {
+            Ok(_) => panic!(),
+            Err(err) => err,
+        };
+
+        assert_eq!(err.kind(), IoErrorKind::TimedOut);
+        let inner = err.get_ref().unwrap().downcast_ref::<FallbackError>().unwrap();
+        assert_eq!(inner.first().kind(), IoErrorKind::ConnectionRefused);
+        assert_eq!(inner.second().kind(), IoErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn first_error_kept_if_second_not_supported() {
+        let first = mock(true, Some(IoErrorKind::ConnectionRefused));
+        let transport = FallbackTransport::new(first, mock(false, None));
+        let err = match transport.dial(addr()).unwrap_or_else(|_| panic!()).wait() {
+            Ok(_) => panic!(),
+            Err(err) => err,
+        };
+        assert_eq!(err.kind(), IoErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn neither_supported() {
+        let transport = FallbackTransport::new(mock(false, None), mock(false, None));
+        assert!(transport.dial(addr()).is_err());
+    }
+}
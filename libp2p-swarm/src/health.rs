@@ -0,0 +1,215 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Summary of the state of a node, for readiness probes.
+//!
+//! See `SwarmController::health`. The `HealthReport` can be turned into a JSON document with
+//! `to_json`, which an application can serve on the endpoint polled by its orchestrator.
+//!
+//! The report only covers the listeners, the connections and the services. It says nothing about
+//! NAT reachability, the DHT, pubsub or bootstrapping, as there is no implementation of them to
+//! query. Contrary to `self_check`, building the report doesn't perform any network operation
+//! and is cheap enough to be done on every probe.
+
+use limits::ConnectionCounts;
+use services::ServiceStatus;
+use Multiaddr;
+
+/// Report produced by `SwarmController::health`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    /// Addresses that were successfully passed to `listen_on`.
+    pub listeners: Vec<Multiaddr>,
+    /// Number of pending connections, open connections and established substreams.
+    pub connections: ConnectionCounts,
+    /// Number of established substreams that the node wants to have, if any.
+    pub target_connections: Option<usize>,
+    /// Status of each of the services registered with `SwarmController::services`.
+    pub services: Vec<(String, ServiceStatus)>,
+}
+
+impl HealthReport {
+    /// Returns true if the node is listening on at least one address, has reached its target
+    /// number of connections, and none of its services has failed or been aborted.
+    ///
+    /// Nodes that only dial should look at the individual fields instead, as they are never
+    /// considered ready by this method.
+    pub fn is_ready(&self) -> bool {
+        let target_reached = match self.target_connections {
+            Some(target) => self.connections.substreams >= target,
+            None => true,
+        };
+
+        let services_ok = self.services.iter().all(|&(_, ref status)| match *status {
+            ServiceStatus::Aborted | ServiceStatus::Failed(_) => false,
+            _ => true,
+        });
+
+        !self.listeners.is_empty() && target_reached && services_ok
+    }
+
+    /// Turns the report into a JSON document.
+    ///
+    /// ```text
+    /// {
+    ///   "ready": false,
+    ///   "listeners": ["/ip4/0.0.0.0/tcp/4001"],
+    ///   "connections": {
+    ///     "established": 1, "substreams": 3, "target": 8, "pending_dials": 2,
+    ///     "pending_incoming": 0
+    ///   },
+    ///   "services": { "identify": { "status": "failed", "error": "..." } }
+    /// }
+    /// ```
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        json.push_str("{\"ready\":");
+        json.push_str(if self.is_ready() { "true" } else { "false" });
+
+        json.push_str(",\"listeners\":[");
+        for (n, addr) in self.listeners.iter().enumerate() {
+            if n != 0 {
+                json.push(',');
+            }
+            push_string(&mut json, &addr.to_string());
+        }
+
+        json.push_str("],\"connections\":{\"established\":");
+        json.push_str(&self.connections.established.to_string());
+        json.push_str(",\"substreams\":");
+        json.push_str(&self.connections.substreams.to_string());
+        json.push_str(",\"target\":");
+        match self.target_connections {
+            Some(target) => json.push_str(&target.to_string()),
+            None => json.push_str("null"),
+        }
+        json.push_str(",\"pending_dials\":");
+        json.push_str(&self.connections.pending_dials.to_string());
+        json.push_str(",\"pending_incoming\":");
+        json.push_str(&self.connections.pending_incoming.to_string());
+
+        json.push_str("},\"services\":{");
+        for (n, &(ref name, ref status)) in self.services.iter().enumerate() {
+            if n != 0 {
+                json.push(',');
+            }
+            let (status, error) = match *status {
+                ServiceStatus::Running => ("running", None),
+                ServiceStatus::Stopping => ("stopping", None),
+                ServiceStatus::Stopped => ("stopped", None),
+                ServiceStatus::Aborted => ("aborted", None),
+                ServiceStatus::Failed(ref err) => ("failed", Some(err)),
+            };
+            push_string(&mut json, name);
+            json.push_str(":{\"status\":");
+            push_string(&mut json, status);
+            json.push_str(",\"error\":");
+            match error {
+                Some(error) => push_string(&mut json, error),
+                None => json.push_str("null"),
+            }
+            json.push('}');
+        }
+        json.push_str("}}");
+
+        json
+    }
+}
+
+// Appends `s` to `json` as a JSON string literal.
+fn push_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HealthReport;
+    use limits::ConnectionCounts;
+    use services::ServiceStatus;
+
+    fn report() -> HealthReport {
+        HealthReport {
+            listeners: vec!["/ip4/0.0.0.0/tcp/4001".parse().unwrap()],
+            connections: ConnectionCounts {
+                pending_dials: 2,
+                pending_incoming: 0,
+                established: 1,
+                substreams: 3,
+            },
+            target_connections: Some(3),
+            services: vec![("identify".to_owned(), ServiceStatus::Running)],
+        }
+    }
+
+    #[test]
+    fn is_ready() {
+        assert!(report().is_ready());
+
+        let mut no_listener = report();
+        no_listener.listeners.clear();
+        assert!(!no_listener.is_ready());
+
+        let mut target_not_reached = report();
+        target_not_reached.target_connections = Some(4);
+        assert!(!target_not_reached.is_ready());
+        target_not_reached.target_connections = None;
+        assert!(target_not_reached.is_ready());
+
+        let mut failed = report();
+        failed.services.push(("ping".to_owned(), ServiceStatus::Failed("oops".to_owned())));
+        assert!(!failed.is_ready());
+
+        let mut stopped = report();
+        stopped.services[0].1 = ServiceStatus::Stopped;
+        assert!(stopped.is_ready());
+    }
+
+    #[test]
+    fn to_json() {
+        let mut report = report();
+        report.target_connections = None;
+        let failed = ServiceStatus::Failed("bad \"pong\"\n".to_owned());
+        report.services.push(("ping".to_owned(), failed));
+
+        assert_eq!(
+            report.to_json(),
+            concat!(
+                r#"{"ready":false,"listeners":["/ip4/0.0.0.0/tcp/4001"],"#,
+                r#""connections":{"established":1,"substreams":3,"target":null,"#,
+                r#""pending_dials":2,"#,
+                r#""pending_incoming":0},"services":{"#,
+                r#""identify":{"status":"running","error":null},"#,
+                r#""ping":{"status":"failed","error":"bad \"pong\"\n"}}}"#
+            )
+        );
+    }
+}
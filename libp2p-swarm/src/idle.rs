@@ -0,0 +1,393 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Closing of the connections that have been idle for too long.
+//!
+//! Wrap the upgrade with an `IdleTimeout` in order to close the connections on which nothing has
+//! been read or written for a given duration. Protocols that need a quiet connection to stay
+//! open hold a `KeepAlive` for it.
+
+use futures::{Async, Future, Poll};
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_io::{AsyncRead, AsyncWrite};
+use {Clock, ConnectionUpgrade, Endpoint, LocalIdentity, Multiaddr, TokioClock};
+
+/// Tracks the activity of connections, and closes the ones on which nothing has been read or
+/// written for longer than a configurable duration.
+///
+/// The connections are tracked by wrapping the upgrade of the transport with `wrap`, usually
+/// before the muxing upgrade so that the whole connection is concerned. Once a connection is
+/// idle, the operations on its socket fail with an error of kind `TimedOut`, which closes it.
+///
+/// Protocols that are long-lived but quiet, such as pub-sub, can prevent their connection from
+/// being closed by holding a `KeepAlive` obtained from `keep_alive`.
+///
+/// Cloning an `IdleTimeout` gives access to the same connections.
+#[derive(Clone)]
+pub struct IdleTimeout<Ck = TokioClock> {
+    idle: Duration,
+    clock: Ck,
+    connections: Arc<Mutex<HashMap<Multiaddr, Arc<Activity>>>>,
+}
+
+// Activity of a connection.
+struct Activity {
+    last: Mutex<Instant>,
+    // Number of `KeepAlive`s that exist for this connection.
+    votes: AtomicUsize,
+}
+
+impl IdleTimeout {
+    /// Builds an `IdleTimeout` that closes the connections idle for longer than `idle`.
+    #[inline]
+    pub fn new(idle: Duration) -> IdleTimeout {
+        IdleTimeout::with_clock(idle, TokioClock::new())
+    }
+}
+
+impl<Ck> IdleTimeout<Ck>
+where
+    Ck: Clock,
+{
+    /// Same as `new`, but uses the given `Clock` in order to wait.
+    #[inline]
+    pub fn with_clock(idle: Duration, clock: Ck) -> IdleTimeout<Ck> {
+        IdleTimeout {
+            idle: idle,
+            clock: clock,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Wraps around an upgrade so that the connections it upgrades are tracked.
+    #[inline]
+    pub fn wrap<U>(&self, upgrade: U) -> IdleUpgrade<U, Ck> {
+        IdleUpgrade {
+            upgrade: upgrade,
+            timeout: self.clone(),
+        }
+    }
+
+    /// Prevents the connection with `addr` from being closed until the `KeepAlive` is destroyed.
+    /// Returns `None` if there is no such connection.
+    ///
+    /// Each protocol that needs the connection should hold its own `KeepAlive`. The connection is
+    /// closed once it has been idle and all of them have been destroyed.
+    pub fn keep_alive(&self, addr: &Multiaddr) -> Option<KeepAlive> {
+        let connections = self.connections.lock().unwrap();
+        connections.get(addr).map(|activity| {
+            activity.votes.fetch_add(1, Ordering::SeqCst);
+            KeepAlive {
+                activity: activity.clone(),
+            }
+        })
+    }
+
+    /// Returns the time elapsed since the last activity on the connection with `addr`, or `None`
+    /// if there is no such connection.
+    pub fn idle_for(&self, addr: &Multiaddr) -> Option<Duration> {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .get(addr)
+            .map(|activity| self.elapsed_since(&activity.last))
+    }
+
+    // Returns the time elapsed on the clock since the instant stored in `last`.
+    fn elapsed_since(&self, last: &Mutex<Instant>) -> Duration {
+        let last = *last.lock().unwrap();
+        let now = self.clock.now();
+        if now > last {
+            now.duration_since(last)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    // Starts tracking the activity of `socket`, which is the connection with `remote_addr`.
+    fn track_socket<C>(&self, socket: C, remote_addr: &Multiaddr) -> IdleSocket<C, Ck> {
+        let activity = Arc::new(Activity {
+            last: Mutex::new(self.clock.now()),
+            votes: AtomicUsize::new(0),
+        });
+
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(remote_addr.clone(), activity.clone());
+
+        IdleSocket {
+            inner: socket,
+            activity: activity,
+            remote_addr: remote_addr.clone(),
+            timeout: self.clone(),
+            delay: None,
+        }
+    }
+}
+
+/// Vote for keeping a connection alive. Returned by `IdleTimeout::keep_alive`.
+///
+/// The vote is withdrawn when the `KeepAlive` is destroyed.
+pub struct KeepAlive {
+    activity: Arc<Activity>,
+}
+
+impl Drop for KeepAlive {
+    #[inline]
+    fn drop(&mut self) {
+        self.activity.votes.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Upgrade returned by `IdleTimeout::wrap`.
+#[derive(Clone)]
+pub struct IdleUpgrade<U, Ck = TokioClock> {
+    upgrade: U,
+    timeout: IdleTimeout<Ck>,
+}
+
+impl<C, U, Ck> ConnectionUpgrade<C> for IdleUpgrade<U, Ck>
+where
+    C: AsyncRead + AsyncWrite,
+    U: ConnectionUpgrade<IdleSocket<C, Ck>>,
+    Ck: Clock,
+{
+    type NamesIter = U::NamesIter;
+    type UpgradeIdentifier = U::UpgradeIdentifier;
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        self.upgrade.protocol_names()
+    }
+
+    type Output = U::Output;
+    type Future = U::Future;
+
+    fn upgrade(
+        self,
+        socket: C,
+        id: Self::UpgradeIdentifier,
+        ty: Endpoint,
+        remote_addr: &Multiaddr,
+        local_identity: &LocalIdentity,
+    ) -> Self::Future {
+        let socket = self.timeout.track_socket(socket, remote_addr);
+        self.upgrade
+            .upgrade(socket, id, ty, remote_addr, local_identity)
+    }
+}
+
+/// Socket of a connection tracked by an `IdleTimeout`.
+pub struct IdleSocket<C, Ck = TokioClock>
+where
+    Ck: Clock,
+{
+    inner: C,
+    activity: Arc<Activity>,
+    remote_addr: Multiaddr,
+    timeout: IdleTimeout<Ck>,
+    // Resolves when the connection may have become idle.
+    delay: Option<Ck::Delay>,
+}
+
+impl<C, Ck> IdleSocket<C, Ck>
+where
+    Ck: Clock,
+{
+    // Called when the inner socket isn't ready. Returns an error if the connection is idle, and
+    // otherwise makes sure that the current task is woken up once it may become idle.
+    fn check_idle(&mut self) -> Result<(), IoError> {
+        loop {
+            if self.delay.is_none() {
+                let elapsed = self.timeout.elapsed_since(&self.activity.last);
+                let idle = self.timeout.idle;
+                let remaining = if elapsed < idle {
+                    idle - elapsed
+                } else {
+                    Duration::from_secs(0)
+                };
+                self.delay = Some(self.timeout.clock.delay(remaining));
+            }
+
+            match self.delay.as_mut().expect("set above").poll()? {
+                Async::NotReady => return Ok(()),
+                Async::Ready(()) => self.delay = None,
+            }
+
+            let elapsed = self.timeout.elapsed_since(&self.activity.last);
+            if elapsed < self.timeout.idle {
+                continue;
+            }
+
+            if self.activity.votes.load(Ordering::SeqCst) != 0 {
+                // Someone wants to keep the connection. Checks again after another period.
+                *self.activity.last.lock().unwrap() = self.timeout.clock.now();
+                continue;
+            }
+
+            debug!(target: "libp2p-swarm", "Closing connection with {} after {:?} of inactivity",
+                   self.remote_addr, elapsed);
+            return Err(IoError::new(IoErrorKind::TimedOut, "connection idle for too long"));
+        }
+    }
+
+    // Handles the result of an operation on the inner socket.
+    fn track<T>(&mut self, result: Result<T, IoError>) -> Result<T, IoError> {
+        match result {
+            Ok(value) => {
+                *self.activity.last.lock().unwrap() = self.timeout.clock.now();
+                Ok(value)
+            }
+            Err(ref err) if err.kind() == IoErrorKind::WouldBlock => {
+                self.check_idle()?;
+                Err(IoErrorKind::WouldBlock.into())
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<C, Ck> Drop for IdleSocket<C, Ck>
+where
+    Ck: Clock,
+{
+    fn drop(&mut self) {
+        let mut connections = self.timeout.connections.lock().unwrap();
+        // A new connection with the same address may have replaced ours.
+        let ours = connections
+            .get(&self.remote_addr)
+            .map_or(false, |activity| Arc::ptr_eq(activity, &self.activity));
+        if ours {
+            connections.remove(&self.remote_addr);
+        }
+    }
+}
+
+impl<C, Ck> Read for IdleSocket<C, Ck>
+where
+    C: Read,
+    Ck: Clock,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let result = self.inner.read(buf);
+        self.track(result)
+    }
+}
+
+impl<C, Ck> AsyncRead for IdleSocket<C, Ck>
+where
+    C: AsyncRead,
+    Ck: Clock,
+{
+    #[inline]
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<C, Ck> Write for IdleSocket<C, Ck>
+where
+    C: Write,
+    Ck: Clock,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let result = self.inner.write(buf);
+        self.track(result)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+
+impl<C, Ck> AsyncWrite for IdleSocket<C, Ck>
+where
+    C: AsyncWrite,
+    Ck: Clock,
+{
+    #[inline]
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdleTimeout;
+    use futures::{future, Future};
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read};
+    use std::time::Duration;
+    use libp2p_core::ManualClock;
+    use Multiaddr;
+
+    // Socket on which nothing ever happens.
+    struct Silent;
+    impl Read for Silent {
+        fn read(&mut self, _: &mut [u8]) -> Result<usize, IoError> {
+            Err(IoErrorKind::WouldBlock.into())
+        }
+    }
+
+    // Reads from `socket` within a task, and returns the kind of the error.
+    fn read_error<R: Read>(socket: &mut R) -> IoErrorKind {
+        future::lazy(|| Ok::<_, ()>(socket.read(&mut [0; 8])))
+            .wait()
+            .unwrap()
+            .unwrap_err()
+            .kind()
+    }
+
+    #[test]
+    fn idle_connection_closes() {
+        let clock = ManualClock::new();
+        let timeout = IdleTimeout::with_clock(Duration::from_secs(10), clock.clone());
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/10000".parse().unwrap();
+        let mut socket = timeout.track_socket(Silent, &addr);
+
+        assert_eq!(read_error(&mut socket), IoErrorKind::WouldBlock);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(timeout.idle_for(&addr), Some(Duration::from_secs(5)));
+        assert_eq!(read_error(&mut socket), IoErrorKind::WouldBlock);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(read_error(&mut socket), IoErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn keep_alive_keeps_connection() {
+        let clock = ManualClock::new();
+        let timeout = IdleTimeout::with_clock(Duration::from_secs(10), clock.clone());
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/10000".parse().unwrap();
+        let mut socket = timeout.track_socket(Silent, &addr);
+        let keep_alive = timeout.keep_alive(&addr).unwrap();
+
+        assert_eq!(read_error(&mut socket), IoErrorKind::WouldBlock);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(read_error(&mut socket), IoErrorKind::WouldBlock);
+
+        drop(keep_alive);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(read_error(&mut socket), IoErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,760 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Limits on the number of connections handled by a swarm.
+//!
+//! The number of pending connections, of open muxed connections and of established substreams
+//! can be bounded by creating the swarm with `swarm_with_limits` and a `ConnectionCounter`.
+//! Dialing above a limit fails with a `ConnectionLimit` error, and the incoming connections above
+//! a limit are dropped.
+//!
+//! The swarm only sees the substreams that it gives to its handler. The muxed connections are
+//! counted by wrapping the transport that is below the muxer with `count_connections`:
+//!
+//! ```ignore
+//! let counter = ConnectionCounter::new(ConnectionLimits::default().with_max_established(50));
+//! let transport = counter
+//!     .count_connections(TcpConfig::new(handle).with_upgrade(secio))
+//!     .with_upgrade(MultiplexConfig)
+//!     .into_connection_reuse();
+//! let (controller, future) = swarm_with_limits(transport, upgrade, handler, counter)?;
+//! ```
+
+use bans::peer_id_of;
+use futures::{Async, Future, IntoFuture, Poll, Stream};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use tokio_io::{AsyncRead, AsyncWrite};
+use {LocalIdentity, Multiaddr, PeerId, Transport};
+
+/// Maximum number of connections that a swarm accepts. By default, nothing is limited.
+///
+/// Pass this to `ConnectionCounter::new`. Once a limit is reached, new dialing attempts are
+/// rejected with a `ConnectionLimit` error, and new incoming connections are dropped.
+///
+/// The limits on the established connections apply to the muxed connections, whether we dialed
+/// them or they dialed us, and are only enforced on the transports wrapped with
+/// `ConnectionCounter::count_connections`. The limits on the substreams apply to the outputs of
+/// the upgrades given to the handler of the swarm. For example, running identify and ping with a
+/// remote over a single TCP connection counts as one connection and two substreams. The pending
+/// dials and incoming connections include the substreams being opened over an existing
+/// connection.
+///
+/// The per-peer limit identifies the remotes by the peer ID that their multiaddress ends with,
+/// which requires a transport that reports them, such as the `IdentifyTransport`. See
+/// `Transport::reports_peer_ids`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    max_pending_dials: Option<usize>,
+    max_pending_incoming: Option<usize>,
+    max_established: Option<usize>,
+    max_substreams: Option<usize>,
+    max_substreams_per_peer: Option<usize>,
+}
+
+impl ConnectionLimits {
+    /// Limits the number of dialing attempts that are in progress.
+    #[inline]
+    pub fn with_max_pending_dials(mut self, max: usize) -> ConnectionLimits {
+        self.max_pending_dials = Some(max);
+        self
+    }
+
+    /// Limits the number of incoming connections that are being upgraded.
+    #[inline]
+    pub fn with_max_pending_incoming(mut self, max: usize) -> ConnectionLimits {
+        self.max_pending_incoming = Some(max);
+        self
+    }
+
+    /// Limits the total number of open muxed connections, both incoming and outgoing.
+    #[inline]
+    pub fn with_max_established(mut self, max: usize) -> ConnectionLimits {
+        self.max_established = Some(max);
+        self
+    }
+
+    /// Limits the total number of substreams whose upgrade has finished, both incoming and
+    /// outgoing.
+    #[inline]
+    pub fn with_max_substreams(mut self, max: usize) -> ConnectionLimits {
+        self.max_substreams = Some(max);
+        self
+    }
+
+    /// Limits the number of substreams whose upgrade has finished for each peer.
+    #[inline]
+    pub fn with_max_substreams_per_peer(mut self, max: usize) -> ConnectionLimits {
+        self.max_substreams_per_peer = Some(max);
+        self
+    }
+}
+
+/// Reason why a connection was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionLimit {
+    /// Too many dialing attempts are in progress.
+    PendingDials {
+        /// The configured limit.
+        limit: usize,
+    },
+    /// Too many incoming connections are being upgraded.
+    PendingIncoming {
+        /// The configured limit.
+        limit: usize,
+    },
+    /// Too many muxed connections are open.
+    Established {
+        /// The configured limit.
+        limit: usize,
+    },
+    /// Too many substreams are established.
+    Substreams {
+        /// The configured limit.
+        limit: usize,
+    },
+    /// Too many substreams are established with this peer.
+    SubstreamsPerPeer {
+        /// The remote.
+        peer: PeerId,
+        /// The configured limit.
+        limit: usize,
+    },
+}
+
+impl fmt::Display for ConnectionLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConnectionLimit::PendingDials { limit } => {
+                write!(f, "too many pending dials (limit: {})", limit)
+            }
+            ConnectionLimit::PendingIncoming { limit } => {
+                write!(f, "too many pending incoming connections (limit: {})", limit)
+            }
+            ConnectionLimit::Established { limit } => {
+                write!(f, "too many open connections (limit: {})", limit)
+            }
+            ConnectionLimit::Substreams { limit } => {
+                write!(f, "too many established substreams (limit: {})", limit)
+            }
+            ConnectionLimit::SubstreamsPerPeer { ref peer, limit } => {
+                write!(f, "too many substreams with {:?} (limit: {})", peer, limit)
+            }
+        }
+    }
+}
+
+impl error::Error for ConnectionLimit {
+    #[inline]
+    fn description(&self) -> &str {
+        "connection limit reached"
+    }
+}
+
+/// Error produced by `swarm_with_limits` when some of the `ConnectionLimits` can't be enforced.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LimitsError {
+    /// A per-peer limit is set, but the transport doesn't report the peer IDs of the remotes.
+    PeerIdsNotReported,
+    /// A limit of established connections is set, but `count_connections` was never called.
+    ConnectionsNotCounted,
+}
+
+impl fmt::Display for LimitsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LimitsError::PeerIdsNotReported => {
+                write!(f, "per-peer limit set but the transport doesn't report the peer IDs")
+            }
+            LimitsError::ConnectionsNotCounted => {
+                write!(f, "connection limit set but the connections aren't counted")
+            }
+        }
+    }
+}
+
+impl error::Error for LimitsError {
+    #[inline]
+    fn description(&self) -> &str {
+        "unenforceable connection limits"
+    }
+}
+
+/// Error produced when asking a `SwarmController` to dial.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DialError {
+    /// The transport doesn't support this multiaddress.
+    MultiaddrNotSupported(Multiaddr),
+    /// A limit of the swarm has been reached.
+    Limit(ConnectionLimit),
+    /// The multiaddress belongs to a peer that is banned.
+    Banned(PeerId),
+}
+
+impl fmt::Display for DialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DialError::MultiaddrNotSupported(ref addr) => {
+                write!(f, "multiaddress not supported: {}", addr)
+            }
+            DialError::Limit(ref limit) => write!(f, "{}", limit),
+            DialError::Banned(ref peer) => write!(f, "{:?} is banned", peer),
+        }
+    }
+}
+
+impl error::Error for DialError {
+    fn description(&self) -> &str {
+        match *self {
+            DialError::MultiaddrNotSupported(_) => "multiaddress not supported",
+            DialError::Limit(_) => "connection limit reached",
+            DialError::Banned(_) => "the peer is banned",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            DialError::Limit(ref limit) => Some(limit),
+            _ => None,
+        }
+    }
+}
+
+/// Counts the connections of a swarm and enforces its `ConnectionLimits`.
+///
+/// Each successful call returns a `ConnectionGuard` that must be kept alive for as long as the
+/// connection is in the state it was counted in.
+#[derive(Debug, Clone)]
+pub struct ConnectionCounter {
+    limits: ConnectionLimits,
+    counts: Arc<Mutex<Counts>>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    pending_dials: usize,
+    pending_incoming: usize,
+    established: usize,
+    substreams: usize,
+    per_peer: HashMap<PeerId, usize>,
+    // True once `count_connections` has been called.
+    counting_connections: bool,
+}
+
+impl ConnectionCounter {
+    /// Creates a counter that enforces the given limits.
+    #[inline]
+    pub fn new(limits: ConnectionLimits) -> ConnectionCounter {
+        ConnectionCounter {
+            limits: limits,
+            counts: Arc::new(Mutex::new(Default::default())),
+        }
+    }
+
+    /// Returns the limits that are enforced.
+    #[inline]
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    /// Returns the number of connections that are currently counted.
+    pub fn counts(&self) -> ConnectionCounts {
+        let counts = self.counts.lock().unwrap();
+        ConnectionCounts {
+            pending_dials: counts.pending_dials,
+            pending_incoming: counts.pending_incoming,
+            established: counts.established,
+            substreams: counts.substreams,
+        }
+    }
+
+    /// Wraps around `transport` so that each connection it produces is counted as an
+    /// established connection until it is closed.
+    ///
+    /// `transport` should be the one on top of which the muxer is applied, so that each muxed
+    /// connection is counted once independently of its number of substreams. Dialing fails and
+    /// the incoming connections are dropped once `with_max_established` is reached.
+    pub fn count_connections<T>(&self, transport: T) -> CountConnections<T> {
+        self.counts.lock().unwrap().counting_connections = true;
+        CountConnections {
+            inner: transport,
+            counter: self.clone(),
+        }
+    }
+
+    /// Checks that the limits can be enforced on the connections produced by `transport`.
+    ///
+    /// This is called by `swarm_with_limits` with the transport of the swarm.
+    pub fn check_transport<T>(&self, transport: &T) -> Result<(), LimitsError>
+    where
+        T: Transport,
+    {
+        if self.limits.max_substreams_per_peer.is_some() && !transport.reports_peer_ids() {
+            return Err(LimitsError::PeerIdsNotReported);
+        }
+        let counting = self.counts.lock().unwrap().counting_connections;
+        if self.limits.max_established.is_some() && !counting {
+            return Err(LimitsError::ConnectionsNotCounted);
+        }
+        Ok(())
+    }
+
+    /// Counts a new dialing attempt towards `addr`.
+    ///
+    /// Also fails if dialing would be pointless because we are already at the limit of
+    /// established substreams, either in total or with the peer that `addr` ends with.
+    pub fn pending_dial(&self, addr: &Multiaddr) -> Result<ConnectionGuard, ConnectionLimit> {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(limit) = self.limits.max_pending_dials {
+            if counts.pending_dials >= limit {
+                return Err(ConnectionLimit::PendingDials { limit: limit });
+            }
+        }
+        self.check_substreams(&counts, peer_id_of(addr).as_ref())?;
+        counts.pending_dials += 1;
+        Ok(self.guard(GuardKind::PendingDial))
+    }
+
+    /// Counts a new incoming connection whose upgrade is in progress.
+    pub fn pending_incoming(&self) -> Result<ConnectionGuard, ConnectionLimit> {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(limit) = self.limits.max_pending_incoming {
+            if counts.pending_incoming >= limit {
+                return Err(ConnectionLimit::PendingIncoming { limit: limit });
+            }
+        }
+        counts.pending_incoming += 1;
+        Ok(self.guard(GuardKind::PendingIncoming))
+    }
+
+    /// Counts a new open muxed connection.
+    pub fn established(&self) -> Result<ConnectionGuard, ConnectionLimit> {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(limit) = self.limits.max_established {
+            if counts.established >= limit {
+                return Err(ConnectionLimit::Established { limit: limit });
+            }
+        }
+        counts.established += 1;
+        Ok(self.guard(GuardKind::Established))
+    }
+
+    /// Counts a new established substream with `addr`.
+    ///
+    /// The substream counts towards the per-peer limit of the peer that `addr` ends with, if any.
+    pub fn substream(&self, addr: &Multiaddr) -> Result<ConnectionGuard, ConnectionLimit> {
+        let mut counts = self.counts.lock().unwrap();
+        let peer = peer_id_of(addr);
+        self.check_substreams(&counts, peer.as_ref())?;
+        counts.substreams += 1;
+        if let Some(ref peer) = peer {
+            *counts.per_peer.entry(peer.clone()).or_insert(0) += 1;
+        }
+        Ok(self.guard(GuardKind::Substream(peer)))
+    }
+
+    fn check_substreams(&self, counts: &Counts, peer: Option<&PeerId>)
+        -> Result<(), ConnectionLimit>
+    {
+        if let Some(limit) = self.limits.max_substreams {
+            if counts.substreams >= limit {
+                return Err(ConnectionLimit::Substreams { limit: limit });
+            }
+        }
+        if let (Some(limit), Some(peer)) = (self.limits.max_substreams_per_peer, peer) {
+            if counts.per_peer.get(peer).map(|n| *n).unwrap_or(0) >= limit {
+                return Err(ConnectionLimit::SubstreamsPerPeer {
+                    peer: peer.clone(),
+                    limit: limit,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn guard(&self, kind: GuardKind) -> ConnectionGuard {
+        ConnectionGuard {
+            counts: self.counts.clone(),
+            kind: kind,
+        }
+    }
+}
+
+/// Number of connections counted by a `ConnectionCounter` at a given moment.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionCounts {
+    /// Dialing attempts whose upgrade isn't finished.
+    pub pending_dials: usize,
+    /// Incoming connections whose upgrade isn't finished.
+    pub pending_incoming: usize,
+    /// Muxed connections that are open. Always 0 if `count_connections` isn't used.
+    pub established: usize,
+    /// Substreams that have been upgraded and passed to the handler.
+    pub substreams: usize,
+}
+
+/// Keeps a connection counted by a `ConnectionCounter`. Destroying it uncounts the connection.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    counts: Arc<Mutex<Counts>>,
+    kind: GuardKind,
+}
+
+#[derive(Debug)]
+enum GuardKind {
+    PendingDial,
+    PendingIncoming,
+    Established,
+    Substream(Option<PeerId>),
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        match self.kind {
+            GuardKind::PendingDial => counts.pending_dials -= 1,
+            GuardKind::PendingIncoming => counts.pending_incoming -= 1,
+            GuardKind::Established => counts.established -= 1,
+            GuardKind::Substream(ref peer) => {
+                counts.substreams -= 1;
+                let peer = match *peer {
+                    Some(ref peer) => peer,
+                    None => return,
+                };
+                let remove = match counts.per_peer.get_mut(peer) {
+                    Some(n) => {
+                        *n -= 1;
+                        *n == 0
+                    }
+                    None => false,
+                };
+                if remove {
+                    counts.per_peer.remove(peer);
+                }
+            }
+        }
+    }
+}
+
+/// Implements the `Transport` trait. Counts the connections of the underlying transport.
+///
+/// Created with `ConnectionCounter::count_connections`.
+#[derive(Debug, Clone)]
+pub struct CountConnections<T> {
+    inner: T,
+    counter: ConnectionCounter,
+}
+
+impl<T> Transport for CountConnections<T>
+where
+    T: Transport + 'static,       // TODO: 'static :-/
+    T::RawConn: 'static,          // TODO: 'static :-/
+    T::Listener: 'static,         // TODO: 'static :-/
+    T::ListenerUpgrade: 'static,  // TODO: 'static :-/
+    <T::Dial as IntoFuture>::Future: 'static, // TODO: 'static :-/
+{
+    type RawConn = CountedConnection<T::RawConn>;
+    type Listener = Box<Stream<Item = Self::ListenerUpgrade, Error = IoError>>;
+    type ListenerUpgrade = Box<Future<Item = (Self::RawConn, Multiaddr), Error = IoError>>;
+    type Dial = Box<Future<Item = (Self::RawConn, Multiaddr), Error = IoError>>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+        let counter = self.counter;
+        match self.inner.listen_on(addr) {
+            Ok((listener, addr)) => {
+                let listener_counter = counter.clone();
+                let listener = listener.map(move |upgrade| {
+                    let counter = listener_counter.clone();
+                    let upgrade = upgrade.and_then(move |(socket, addr)| {
+                        let counted = count(&counter, socket);
+                        if counted.is_err() {
+                            debug!("Dropping incoming connection from {}", addr);
+                        }
+                        counted.map(|socket| (socket, addr))
+                    });
+                    Box::new(upgrade) as Box<Future<Item = _, Error = _>>
+                });
+                Ok((Box::new(listener) as Box<_>, addr))
+            }
+            Err((inner, addr)) => {
+                let transport = CountConnections {
+                    inner: inner,
+                    counter: counter,
+                };
+                Err((transport, addr))
+            }
+        }
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+        let counter = self.counter;
+        match self.inner.dial(addr) {
+            Ok(dial) => {
+                let dial = dial.into_future().and_then(move |(socket, addr)| {
+                    count(&counter, socket).map(|socket| (socket, addr))
+                });
+                Ok(Box::new(dial) as Box<_>)
+            }
+            Err((inner, addr)) => {
+                let transport = CountConnections {
+                    inner: inner,
+                    counter: counter,
+                };
+                Err((transport, addr))
+            }
+        }
+    }
+
+    #[inline]
+    fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.nat_traversal(server, observed)
+    }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.inner.local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.inner.reports_peer_ids()
+    }
+}
+
+// Counts `socket` as an established connection, or drops it if the limit is reached.
+fn count<S>(counter: &ConnectionCounter, socket: S) -> Result<CountedConnection<S>, IoError> {
+    match counter.established() {
+        Ok(guard) => Ok(CountedConnection {
+            inner: socket,
+            guard: Some(guard),
+        }),
+        Err(limit) => Err(IoError::new(IoErrorKind::Other, limit)),
+    }
+}
+
+/// Connection produced by a `CountConnections`.
+///
+/// The connection stops being counted once it is destroyed, reaches the end of the stream, or
+/// produces an error. This matters as a `ConnectionReuse` keeps the muxers of the connections
+/// closed by the remote, and therefore their socket, until they are replaced.
+pub struct CountedConnection<S> {
+    inner: S,
+    guard: Option<ConnectionGuard>,
+}
+
+impl<S> CountedConnection<S> {
+    // Uncounts the connection if `result` shows that it is closed.
+    fn check<R>(&mut self, result: &Result<R, IoError>, eof: bool) {
+        let closed = match *result {
+            Ok(_) => eof,
+            Err(ref err) => err.kind() != IoErrorKind::WouldBlock,
+        };
+        if closed {
+            self.guard = None;
+        }
+    }
+}
+
+impl<S> Read for CountedConnection<S>
+where
+    S: Read,
+{
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let result = self.inner.read(buf);
+        let eof = !buf.is_empty() && result.as_ref().ok() == Some(&0);
+        self.check(&result, eof);
+        result
+    }
+}
+
+impl<S> AsyncRead for CountedConnection<S>
+where
+    S: AsyncRead,
+{
+    #[inline]
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<S> Write for CountedConnection<S>
+where
+    S: Write,
+{
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let result = self.inner.write(buf);
+        self.check(&result, false);
+        result
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), IoError> {
+        let result = self.inner.flush();
+        self.check(&result, false);
+        result
+    }
+}
+
+impl<S> AsyncWrite for CountedConnection<S>
+where
+    S: AsyncWrite,
+{
+    #[inline]
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        let result = self.inner.shutdown();
+        let done = match result {
+            Ok(Async::Ready(())) => true,
+            _ => false,
+        };
+        self.check(&result, done);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate libp2p_tcp_transport;
+
+    use super::{ConnectionCounter, ConnectionCounts, ConnectionLimit, ConnectionLimits};
+    use super::LimitsError;
+    use futures::{Future, Stream};
+    use multiaddr::AddrComponent;
+    use self::libp2p_tcp_transport::TcpConfig;
+    use tokio_core::reactor::Core;
+    use tokio_io::io::read_to_end;
+    use {Multiaddr, PeerId, Transport};
+
+    fn addr_of(base: &str, peer: &PeerId) -> Multiaddr {
+        let mut addr: Multiaddr = base.parse().unwrap();
+        addr.append(AddrComponent::P2P(peer.clone().into_bytes()));
+        addr
+    }
+
+    #[test]
+    fn pending_dials() {
+        let counter = ConnectionCounter::new(ConnectionLimits::default().with_max_pending_dials(1));
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/1".parse().unwrap();
+
+        let guard = counter.pending_dial(&addr).unwrap();
+        assert_eq!(
+            counter.pending_dial(&addr).unwrap_err(),
+            ConnectionLimit::PendingDials { limit: 1 }
+        );
+        drop(guard);
+        assert!(counter.pending_dial(&addr).is_ok());
+    }
+
+    #[test]
+    fn substreams_per_peer() {
+        let limits = ConnectionLimits::default()
+            .with_max_substreams(4)
+            .with_max_substreams_per_peer(2);
+        let counter = ConnectionCounter::new(limits);
+        let peer1 = PeerId::from_public_key(&[1, 2, 3]);
+        let peer2 = PeerId::from_public_key(&[4, 5, 6]);
+        let addr1 = addr_of("/ip4/1.2.3.4/tcp/1", &peer1);
+        let addr1_other = addr_of("/ip4/1.2.3.4/tcp/2", &peer1);
+        let addr2 = addr_of("/ip4/1.2.3.4/tcp/1", &peer2);
+        let unknown: Multiaddr = "/ip4/5.6.7.8/tcp/1".parse().unwrap();
+
+        // Both addresses of the first peer count against the same limit.
+        let _a = counter.substream(&addr1).unwrap();
+        let b = counter.substream(&addr1_other).unwrap();
+        assert_eq!(
+            counter.substream(&addr1).unwrap_err(),
+            ConnectionLimit::SubstreamsPerPeer { peer: peer1.clone(), limit: 2 }
+        );
+        // Dialing is refused as well, as the substream couldn't be counted anyway.
+        assert!(counter.pending_dial(&addr1_other).is_err());
+
+        // The remotes whose peer ID is unknown only count against the total.
+        let _c = counter.substream(&unknown).unwrap();
+        let _d = counter.substream(&addr2).unwrap();
+        assert_eq!(
+            counter.substream(&addr2).unwrap_err(),
+            ConnectionLimit::Substreams { limit: 4 }
+        );
+
+        drop(b);
+        assert!(counter.substream(&addr1).is_ok());
+        assert_eq!(counter.counts(), ConnectionCounts {
+            pending_dials: 0,
+            pending_incoming: 0,
+            established: 0,
+            substreams: 4,
+        });
+    }
+
+    #[test]
+    fn established_connections() {
+        let mut core = Core::new().unwrap();
+        let counter = ConnectionCounter::new(ConnectionLimits::default().with_max_established(1));
+        let (listener, addr) = TcpConfig::new(core.handle())
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap_or_else(|_| panic!());
+        let transport = counter.count_connections(TcpConfig::new(core.handle()));
+
+        let dial = transport.clone().dial(addr.clone()).unwrap_or_else(|_| panic!());
+        let (first, _) = core.run(dial).unwrap();
+        assert_eq!(counter.counts().established, 1);
+
+        let dial = transport.clone().dial(addr.clone()).unwrap_or_else(|_| panic!());
+        let err = core.run(dial).err().unwrap();
+        let limit = err.get_ref().and_then(|err| err.downcast_ref::<ConnectionLimit>());
+        assert_eq!(limit, Some(&ConnectionLimit::Established { limit: 1 }));
+
+        // Once the remote closes the connection, it isn't counted any more even though the
+        // socket is still alive.
+        let accepted = listener.and_then(|upgrade| upgrade).into_future();
+        let (accepted, _listener) = core.run(accepted.map_err(|(err, _)| err)).unwrap();
+        drop(accepted);
+        let (first, _) = core.run(read_to_end(first, Vec::new())).unwrap();
+        assert_eq!(counter.counts().established, 0);
+
+        assert!(core.run(transport.dial(addr).unwrap_or_else(|_| panic!())).is_ok());
+        drop(first);
+    }
+
+    #[test]
+    fn unenforceable_limits() {
+        let core = Core::new().unwrap();
+        let transport = TcpConfig::new(core.handle());
+
+        let per_peer = ConnectionLimits::default().with_max_substreams_per_peer(1);
+        assert_eq!(
+            ConnectionCounter::new(per_peer).check_transport(&transport),
+            Err(LimitsError::PeerIdsNotReported)
+        );
+
+        let counter = ConnectionCounter::new(ConnectionLimits::default().with_max_established(1));
+        assert_eq!(
+            counter.check_transport(&transport),
+            Err(LimitsError::ConnectionsNotCounted)
+        );
+        let transport = counter.count_connections(transport);
+        assert_eq!(counter.check_transport(&transport), Ok(()));
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Parsing of a list of addresses to listen on from a single string, for configuration files and
+//! command-line options.
+//!
+//! A `ListenSpec` is parsed from a comma-separated list such as
+//! `/ip4/0.0.0.0/tcp/4001,/ip6/::/tcp/4001`. All the invalid entries are reported at once, so
+//! that the user can fix them in one go. Pass the result to `SwarmController::listen_on_all`.
+
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+use std::vec::IntoIter as VecIntoIter;
+use {multiaddr, Multiaddr};
+
+/// List of multiaddresses to listen on, parsed from a comma-separated string such as
+/// `/ip4/0.0.0.0/tcp/4001,/ip6/::/tcp/4001`.
+///
+/// Whitespace around each entry is ignored. If some entries are invalid, parsing fails with a
+/// `ListenSpecError` that reports all of them, and not only the first one.
+///
+/// The addresses can then be passed to `SwarmController::listen_on_all`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenSpec {
+    addrs: Vec<Multiaddr>,
+}
+
+impl ListenSpec {
+    /// Returns the addresses, in the order in which they appear in the string.
+    #[inline]
+    pub fn addrs(&self) -> &[Multiaddr] {
+        &self.addrs
+    }
+}
+
+impl FromStr for ListenSpec {
+    type Err = ListenSpecError;
+
+    fn from_str(spec: &str) -> Result<ListenSpec, ListenSpecError> {
+        let mut addrs = Vec::new();
+        let mut errors = Vec::new();
+
+        for (index, entry) in spec.split(',').enumerate() {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                errors.push(ListenSpecEntryError {
+                    index: index,
+                    entry: entry.to_owned(),
+                    error: None,
+                });
+                continue;
+            }
+
+            match entry.parse::<Multiaddr>() {
+                Ok(addr) => addrs.push(addr),
+                Err(err) => errors.push(ListenSpecEntryError {
+                    index: index,
+                    entry: entry.to_owned(),
+                    error: Some(err),
+                }),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(ListenSpec { addrs: addrs })
+        } else {
+            Err(ListenSpecError { errors: errors })
+        }
+    }
+}
+
+impl IntoIterator for ListenSpec {
+    type Item = Multiaddr;
+    type IntoIter = VecIntoIter<Multiaddr>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.addrs.into_iter()
+    }
+}
+
+/// Error while parsing a `ListenSpec`. Contains one entry for each invalid address.
+#[derive(Debug)]
+pub struct ListenSpecError {
+    errors: Vec<ListenSpecEntryError>,
+}
+
+impl ListenSpecError {
+    /// Returns the errors, in the order in which the invalid entries appear in the string.
+    #[inline]
+    pub fn errors(&self) -> &[ListenSpecEntryError] {
+        &self.errors
+    }
+}
+
+impl fmt::Display for ListenSpecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid listen addresses: ")?;
+        for (n, error) in self.errors.iter().enumerate() {
+            if n != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ListenSpecError {
+    #[inline]
+    fn description(&self) -> &str {
+        "invalid listen addresses"
+    }
+}
+
+/// Invalid entry of a `ListenSpec`.
+#[derive(Debug)]
+pub struct ListenSpecEntryError {
+    index: usize,
+    entry: String,
+    error: Option<multiaddr::Error>,
+}
+
+impl ListenSpecEntryError {
+    /// Returns the position of the entry in the list, starting from 0.
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the entry, without the surrounding whitespace.
+    #[inline]
+    pub fn entry(&self) -> &str {
+        &self.entry
+    }
+
+    /// Returns the reason why the entry couldn't be parsed, or `None` if the entry is empty.
+    #[inline]
+    pub fn multiaddr_error(&self) -> Option<&multiaddr::Error> {
+        self.error.as_ref()
+    }
+}
+
+impl fmt::Display for ListenSpecEntryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.error {
+            Some(ref err) => write!(f, "entry #{} ({:?}): {}", self.index, self.entry, err),
+            None => write!(f, "entry #{} is empty", self.index),
+        }
+    }
+}
+
+impl error::Error for ListenSpecEntryError {
+    #[inline]
+    fn description(&self) -> &str {
+        "invalid listen address"
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        self.error.as_ref().map(|err| err as &error::Error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ListenSpec;
+    use Multiaddr;
+
+    #[test]
+    fn valid_entries() {
+        let spec: ListenSpec = " /ip4/0.0.0.0/tcp/4001 ,/ip6/::/tcp/4001".parse().unwrap();
+        let expected: Vec<Multiaddr> = vec![
+            "/ip4/0.0.0.0/tcp/4001".parse().unwrap(),
+            "/ip6/::/tcp/4001".parse().unwrap(),
+        ];
+        assert_eq!(spec.addrs(), &expected[..]);
+        assert_eq!(spec.into_iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn empty_string() {
+        let err = "".parse::<ListenSpec>().unwrap_err();
+        assert_eq!(err.errors().len(), 1);
+        assert_eq!(err.errors()[0].index(), 0);
+        assert_eq!(err.errors()[0].entry(), "");
+        assert!(err.errors()[0].multiaddr_error().is_none());
+    }
+
+    #[test]
+    fn trailing_comma() {
+        let err = "/ip4/0.0.0.0/tcp/4001,".parse::<ListenSpec>().unwrap_err();
+        assert_eq!(err.errors().len(), 1);
+        assert_eq!(err.errors()[0].index(), 1);
+        assert!(err.errors()[0].multiaddr_error().is_none());
+    }
+
+    #[test]
+    fn reports_all_invalid_entries() {
+        let spec = "foo,/ip4/0.0.0.0/tcp/4001, ,/ip4/1.2.3.4/tcp/bar";
+        let err = spec.parse::<ListenSpec>().unwrap_err();
+        let errors = err.errors();
+        assert_eq!(errors.len(), 3);
+
+        assert_eq!(errors[0].index(), 0);
+        assert_eq!(errors[0].entry(), "foo");
+        assert!(errors[0].multiaddr_error().is_some());
+
+        assert_eq!(errors[1].index(), 2);
+        assert!(errors[1].multiaddr_error().is_none());
+
+        assert_eq!(errors[2].index(), 3);
+        assert_eq!(errors[2].entry(), "/ip4/1.2.3.4/tcp/bar");
+        assert!(errors[2].multiaddr_error().is_some());
+
+        assert_eq!(
+            err.to_string(),
+            "invalid listen addresses: entry #0 (\"foo\"): ".to_owned()
+                + &errors[0].multiaddr_error().unwrap().to_string()
+                + ", entry #2 is empty, entry #3 (\"/ip4/1.2.3.4/tcp/bar\"): "
+                + &errors[2].multiaddr_error().unwrap().to_string()
+        );
+    }
+}
@@ -0,0 +1,559 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains `ConnectionPool`, which reuses the connection to a peer when dialing its peer ID.
+//!
+//! A `ConnectionReuse` opens a new substream on an existing connection only when the exact same
+//! multiaddress is dialed again. A behaviour that wants to talk to a peer, however, usually only
+//! knows its `PeerId` and dials `/p2p/<peer>`, or any of the addresses of the peer followed by
+//! `/p2p/<peer>`. Without a pool, this dials a new TCP connection and negotiates secio and the
+//! multiplexing protocol again, even though we are already connected to the peer.
+//!
+//! The `ConnectionPool` remembers, for each peer ID, the address of a connection that is open
+//! with it. Dialing a multiaddress that contains the `/p2p/` component of a known peer dials that
+//! address instead, which makes the underlying `ConnectionReuse` open a substream on the
+//! existing connection. If that fails, for example because the connection has been closed in the
+//! meanwhile, the multiaddress is dialed as usual.
+//!
+//! The pool only trusts the peer IDs that the underlying transport reports in the multiaddress
+//! of the connections it opens, such as the `/p2p/` addresses produced by an `IdentifyTransport`
+//! once the remote has proven its identity. The typical stack is therefore a `ConnectionPool`
+//! around an `IdentifyTransport` around a `ConnectionReuse`. The `/p2p/` component of the dialed
+//! multiaddress is only used to look up the pool, and dialing fails if the remote turns out to
+//! be another peer. The peer ID of a remote that connected to us can be registered with
+//! `ConnectionPool::associate`, once it has been verified.
+//!
+//! An entry of the pool is removed once all the substreams opened through the pool with the peer
+//! have been closed, at which point the pool can no longer tell whether the connection is open.
+
+use bans::peer_id_of;
+use futures::{future, Future, IntoFuture, Poll, Stream};
+use multiaddr::AddrComponent;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use tokio_io::{AsyncRead, AsyncWrite};
+use {LocalIdentity, Multiaddr, MuxedTransport, PeerId, Transport};
+
+/// Wraps around a transport, normally an `IdentifyTransport` on top of a `ConnectionReuse`, and
+/// redirects the dialing attempts to peers that we are already connected to towards the
+/// existing connection.
+///
+/// Cloning it is cheap, and all the clones share the same pool.
+#[derive(Debug, Clone)]
+pub struct ConnectionPool<T> {
+    inner: T,
+    peers: Arc<Mutex<HashMap<PeerId, PoolEntry>>>,
+}
+
+#[derive(Debug)]
+struct PoolEntry {
+    // Address that the connection with the peer was opened with.
+    addr: Multiaddr,
+    // Number of substreams with the peer, opened through the pool, that are still alive.
+    substreams: usize,
+}
+
+impl<T> ConnectionPool<T> {
+    /// Creates an empty pool on top of `inner`.
+    #[inline]
+    pub fn new(inner: T) -> ConnectionPool<T> {
+        ConnectionPool {
+            inner: inner,
+            peers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Records that the connection opened with `addr` is with `peer`, so that dialing `peer`
+    /// reuses it. `addr` is the address reported when the connection was opened, such as the
+    /// address of a remote that connected to us.
+    ///
+    /// The pool trusts the caller, who must have verified the identity of the remote first.
+    pub fn associate(&self, peer: PeerId, addr: Multiaddr) {
+        let mut peers = self.peers.lock().unwrap();
+        let entry = peers.entry(peer).or_insert_with(|| PoolEntry {
+            addr: addr.clone(),
+            substreams: 0,
+        });
+        entry.addr = addr;
+    }
+
+    /// Forgets the connection with `peer`, so that dialing it opens a new connection. Returns
+    /// the address of the connection that was known, if any.
+    #[inline]
+    pub fn forget(&self, peer: &PeerId) -> Option<Multiaddr> {
+        self.peers.lock().unwrap().remove(peer).map(|entry| entry.addr)
+    }
+
+    /// Returns the address of the connection with `peer`, if one is known.
+    #[inline]
+    pub fn connection_to(&self, peer: &PeerId) -> Option<Multiaddr> {
+        self.peers.lock().unwrap().get(peer).map(|entry| entry.addr.clone())
+    }
+}
+
+impl<T> Transport for ConnectionPool<T>
+where
+    T: Transport + Clone + 'static, // TODO: 'static :-/
+    T::RawConn: 'static,            // TODO: 'static :-/
+    T::Listener: 'static,           // TODO: 'static :-/
+    T::ListenerUpgrade: 'static,    // TODO: 'static :-/
+    <T::Dial as IntoFuture>::Future: 'static, // TODO: 'static :-/
+{
+    type RawConn = PooledSocket<T::RawConn>;
+    type Listener = Box<Stream<Item = Self::ListenerUpgrade, Error = IoError>>;
+    type ListenerUpgrade = Box<Future<Item = (Self::RawConn, Multiaddr), Error = IoError>>;
+    type Dial = Box<Future<Item = (Self::RawConn, Multiaddr), Error = IoError>>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+        match self.inner.listen_on(addr) {
+            Ok((listener, addr)) => {
+                let listener = listener.map(|upgrade| {
+                    let upgrade = upgrade
+                        .map(|(socket, addr)| (PooledSocket::untracked(socket), addr));
+                    Box::new(upgrade) as Box<Future<Item = _, Error = _>>
+                });
+                Ok((Box::new(listener) as Box<_>, addr))
+            }
+            Err((inner, addr)) => {
+                let pool = ConnectionPool {
+                    inner: inner,
+                    peers: self.peers,
+                };
+                Err((pool, addr))
+            }
+        }
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+        let peer = peer_id_of(&addr);
+        let existing = peer.as_ref().and_then(|peer| self.connection_to(peer));
+
+        if let (Some(peer), Some(existing)) = (peer.clone(), existing) {
+            trace!("Reusing the connection to {:?} at {} for {}", peer, existing, addr);
+            let peers = self.peers.clone();
+            match dial_recorded(self.inner.clone(), peers, Some(peer.clone()), existing.clone()) {
+                Ok(reuse) => {
+                    let inner = self.inner;
+                    let peers = self.peers;
+                    let future = reuse.or_else(move |err| {
+                        debug!("Failed to reuse the connection to {:?} ({}) ; dialing {}",
+                               peer, err, addr);
+                        forget_if(&peers, &peer, &existing);
+                        let to_dial = dialable(&addr);
+                        match dial_recorded(inner, peers, Some(peer), to_dial) {
+                            Ok(dial) => dial,
+                            Err(_) => Box::new(future::err(err)) as Box<_>,
+                        }
+                    });
+                    return Ok(Box::new(future) as Box<_>);
+                }
+                Err(_) => forget_if(&self.peers, &peer, &existing),
+            }
+        }
+
+        let peers = self.peers.clone();
+        match dial_recorded(self.inner, peers, peer, dialable(&addr)) {
+            Ok(dial) => Ok(dial),
+            Err((inner, _)) => {
+                let pool = ConnectionPool {
+                    inner: inner,
+                    peers: self.peers,
+                };
+                Err((pool, addr))
+            }
+        }
+    }
+
+    #[inline]
+    fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.nat_traversal(server, observed)
+    }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.inner.local_identity()
+    }
+
+    #[inline]
+    fn reports_peer_ids(&self) -> bool {
+        self.inner.reports_peer_ids()
+    }
+}
+
+impl<T> MuxedTransport for ConnectionPool<T>
+where
+    T: MuxedTransport + Clone + 'static, // TODO: 'static :-/
+    T::RawConn: 'static,                 // TODO: 'static :-/
+    T::Listener: 'static,                // TODO: 'static :-/
+    T::ListenerUpgrade: 'static,         // TODO: 'static :-/
+    T::Incoming: 'static,                // TODO: 'static :-/
+    T::IncomingUpgrade: 'static,         // TODO: 'static :-/
+    <T::Dial as IntoFuture>::Future: 'static, // TODO: 'static :-/
+{
+    type Incoming = Box<Future<Item = Self::IncomingUpgrade, Error = IoError>>;
+    type IncomingUpgrade = Box<Future<Item = (Self::RawConn, Multiaddr), Error = IoError>>;
+
+    #[inline]
+    fn next_incoming(self) -> Self::Incoming {
+        let future = self.inner.next_incoming().map(|incoming| {
+            let incoming = incoming.map(|(socket, addr)| (PooledSocket::untracked(socket), addr));
+            Box::new(incoming) as Box<Future<Item = _, Error = _>>
+        });
+        Box::new(future) as Box<_>
+    }
+}
+
+/// Substream opened through a `ConnectionPool`.
+///
+/// The pool keeps the connection with a peer as long as one of the substreams opened with it
+/// through the pool is alive.
+pub struct PooledSocket<S> {
+    inner: S,
+    // The pool and the peer whose substream count includes this substream, if any.
+    entry: Option<(Arc<Mutex<HashMap<PeerId, PoolEntry>>>, PeerId)>,
+}
+
+impl<S> PooledSocket<S> {
+    #[inline]
+    fn untracked(inner: S) -> PooledSocket<S> {
+        PooledSocket {
+            inner: inner,
+            entry: None,
+        }
+    }
+}
+
+impl<S> Drop for PooledSocket<S> {
+    fn drop(&mut self) {
+        if let Some((ref peers, ref peer)) = self.entry {
+            let mut peers = peers.lock().unwrap();
+            let remove = match peers.get_mut(peer) {
+                Some(entry) => {
+                    entry.substreams = entry.substreams.saturating_sub(1);
+                    entry.substreams == 0
+                }
+                None => false,
+            };
+            if remove {
+                trace!("No substream left with {:?} ; removing it from the pool", peer);
+                peers.remove(peer);
+            }
+        }
+    }
+}
+
+impl<S> Read for PooledSocket<S>
+where
+    S: Read,
+{
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S> AsyncRead for PooledSocket<S>
+where
+    S: AsyncRead,
+{
+    #[inline]
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<S> Write for PooledSocket<S>
+where
+    S: Write,
+{
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.inner.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+
+impl<S> AsyncWrite for PooledSocket<S>
+where
+    S: AsyncWrite,
+{
+    #[inline]
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
+
+// The "Rust doesn't have impl Trait yet" tax.
+type PooledDial<S> = Box<Future<Item = (PooledSocket<S>, Multiaddr), Error = IoError>>;
+
+// Dials `to_dial` with `inner`. If the underlying transport reports the peer ID of the remote,
+// checks that it is `expected` and records the connection in the pool.
+fn dial_recorded<T>(
+    inner: T,
+    peers: Arc<Mutex<HashMap<PeerId, PoolEntry>>>,
+    expected: Option<PeerId>,
+    to_dial: Multiaddr,
+) -> Result<PooledDial<T::RawConn>, (T, Multiaddr)>
+where
+    T: Transport + 'static, // TODO: 'static :-/
+    T::RawConn: 'static,    // TODO: 'static :-/
+    <T::Dial as IntoFuture>::Future: 'static, // TODO: 'static :-/
+{
+    let dial = inner.dial(to_dial.clone())?;
+
+    let future = dial.into_future().and_then(move |(socket, reported)| {
+        let confirmed = match peer_id_of(&reported) {
+            Some(confirmed) => confirmed,
+            // The transport doesn't know who the remote is, so there is nothing to record.
+            None => return Ok((PooledSocket::untracked(socket), reported)),
+        };
+
+        if let Some(expected) = expected {
+            if expected != confirmed {
+                let msg = format!("expected {:?}, but reached {:?}", expected, confirmed);
+                return Err(IoError::new(IoErrorKind::Other, msg));
+            }
+        }
+
+        {
+            let mut lock = peers.lock().unwrap();
+            let entry = lock.entry(confirmed.clone()).or_insert_with(|| PoolEntry {
+                addr: to_dial.clone(),
+                substreams: 0,
+            });
+            entry.addr = to_dial;
+            entry.substreams += 1;
+        }
+
+        let socket = PooledSocket {
+            inner: socket,
+            entry: Some((peers, confirmed)),
+        };
+        Ok((socket, reported))
+    });
+
+    Ok(Box::new(future) as Box<_>)
+}
+
+// Removes the entry of `peer`, unless it has been replaced with another address in the meanwhile.
+fn forget_if(peers: &Mutex<HashMap<PeerId, PoolEntry>>, peer: &PeerId, addr: &Multiaddr) {
+    let mut peers = peers.lock().unwrap();
+    let remove = peers.get(peer).map_or(false, |entry| entry.addr == *addr);
+    if remove {
+        peers.remove(peer);
+    }
+}
+
+// Returns the address to pass to the underlying transport in order to dial `addr`.
+//
+// The underlying transport doesn't necessarily support the `/p2p/` component, so it is removed.
+// An address that consists only of it is passed as it is, as a transport such as
+// `IdentifyTransport` knows how to reach the peer.
+fn dialable(addr: &Multiaddr) -> Multiaddr {
+    let stripped: Multiaddr = addr.iter()
+        .filter(|component| match *component {
+            AddrComponent::P2P(_) | AddrComponent::IPFS(_) => false,
+            _ => true,
+        })
+        .collect();
+
+    if stripped.iter().next().is_some() {
+        stripped
+    } else {
+        addr.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectionPool;
+    use futures::{future, Future};
+    use futures::future::FutureResult;
+    use futures::stream::Empty;
+    use multiaddr::AddrComponent;
+    use std::collections::HashSet;
+    use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind};
+    use std::sync::{Arc, Mutex};
+    use {Multiaddr, PeerId, Transport};
+
+    // Transport that reaches `peer` at every address except the ones in `unreachable`, and
+    // reports it in the `/p2p/` form like an `IdentifyTransport` does.
+    #[derive(Clone)]
+    struct Mock {
+        peer: PeerId,
+        unreachable: Arc<Mutex<HashSet<Multiaddr>>>,
+        dialed: Arc<Mutex<Vec<Multiaddr>>>,
+    }
+
+    impl Mock {
+        fn new(peer: &PeerId) -> Mock {
+            Mock {
+                peer: peer.clone(),
+                unreachable: Default::default(),
+                dialed: Default::default(),
+            }
+        }
+    }
+
+    impl Transport for Mock {
+        type RawConn = Cursor<Vec<u8>>;
+        type Listener = Empty<Self::ListenerUpgrade, IoError>;
+        type ListenerUpgrade = FutureResult<(Self::RawConn, Multiaddr), IoError>;
+        type Dial = FutureResult<(Self::RawConn, Multiaddr), IoError>;
+
+        fn listen_on(
+            self,
+            addr: Multiaddr,
+        ) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+            Err((self, addr))
+        }
+
+        fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+            self.dialed.lock().unwrap().push(addr.clone());
+            if self.unreachable.lock().unwrap().contains(&addr) {
+                return Ok(future::err(IoErrorKind::ConnectionRefused.into()));
+            }
+            let reported = AddrComponent::P2P(self.peer.clone().into_bytes()).into();
+            Ok(future::ok((Cursor::new(Vec::new()), reported)))
+        }
+
+        fn nat_traversal(&self, _: &Multiaddr, _: &Multiaddr) -> Option<Multiaddr> {
+            None
+        }
+    }
+
+    fn peer(key: u8) -> PeerId {
+        PeerId::from_public_key(&[key; 32])
+    }
+
+    fn addr_of(base: &str, peer: &PeerId) -> Multiaddr {
+        let mut addr: Multiaddr = base.parse().unwrap();
+        addr.append(AddrComponent::P2P(peer.clone().into_bytes()));
+        addr
+    }
+
+    #[test]
+    fn reuses_the_connection() {
+        let a = peer(1);
+        let mock = Mock::new(&a);
+        let pool = ConnectionPool::new(mock.clone());
+        let tcp: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+
+        let (_socket, _) = pool.clone().dial(addr_of("/ip4/127.0.0.1/tcp/1", &a))
+            .unwrap_or_else(|_| panic!())
+            .wait()
+            .unwrap();
+        assert_eq!(pool.connection_to(&a), Some(tcp.clone()));
+
+        // Another address of the same peer goes to the existing connection.
+        let (_socket2, _) = pool.clone().dial(addr_of("/ip4/10.0.0.1/tcp/2", &a))
+            .unwrap_or_else(|_| panic!())
+            .wait()
+            .unwrap();
+        assert_eq!(*mock.dialed.lock().unwrap(), vec![tcp.clone(), tcp]);
+    }
+
+    #[test]
+    fn wrong_peer_not_recorded() {
+        let a = peer(1);
+        let b = peer(2);
+        let pool = ConnectionPool::new(Mock::new(&b));
+
+        let result = pool.clone().dial(addr_of("/ip4/127.0.0.1/tcp/1", &a))
+            .unwrap_or_else(|_| panic!())
+            .wait();
+        assert!(result.is_err());
+        assert_eq!(pool.connection_to(&a), None);
+        assert_eq!(pool.connection_to(&b), None);
+    }
+
+    #[test]
+    fn forged_suffix_not_trusted() {
+        let a = peer(1);
+        let b = peer(2);
+        let mock = Mock::new(&b);
+        let pool = ConnectionPool::new(mock.clone());
+
+        // `b` is reachable at this address, but the dialed multiaddress claims that it is `a`.
+        let forged = addr_of("/ip4/10.0.0.6/tcp/6", &a);
+        assert!(pool.clone().dial(forged).unwrap_or_else(|_| panic!()).wait().is_err());
+
+        let (_socket, _) = pool.clone().dial(addr_of("/ip4/127.0.0.1/tcp/1", &b))
+            .unwrap_or_else(|_| panic!())
+            .wait()
+            .unwrap();
+
+        // Dialing `a` must not be redirected to either of the connections with `b`.
+        let result = pool.clone().dial(addr_of("/ip4/10.0.0.7/tcp/7", &a))
+            .unwrap_or_else(|_| panic!())
+            .wait();
+        assert!(result.is_err());
+        let expected: Vec<Multiaddr> = vec![
+            "/ip4/10.0.0.6/tcp/6".parse().unwrap(),
+            "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+            "/ip4/10.0.0.7/tcp/7".parse().unwrap(),
+        ];
+        assert_eq!(*mock.dialed.lock().unwrap(), expected);
+        assert_eq!(pool.connection_to(&a), None);
+        assert_eq!(pool.connection_to(&b), Some("/ip4/127.0.0.1/tcp/1".parse().unwrap()));
+    }
+
+    #[test]
+    fn removed_once_substreams_closed() {
+        let a = peer(1);
+        let pool = ConnectionPool::new(Mock::new(&a));
+
+        let dial = || {
+            pool.clone().dial(addr_of("/ip4/127.0.0.1/tcp/1", &a))
+                .unwrap_or_else(|_| panic!())
+                .wait()
+                .unwrap()
+        };
+        let first = dial();
+        let second = dial();
+        drop(first);
+        assert!(pool.connection_to(&a).is_some());
+        drop(second);
+        assert_eq!(pool.connection_to(&a), None);
+    }
+
+    #[test]
+    fn falls_back_to_fresh_dial() {
+        let a = peer(1);
+        let mock = Mock::new(&a);
+        let pool = ConnectionPool::new(mock.clone());
+        let gone: Multiaddr = "/ip4/10.0.0.1/tcp/5".parse().unwrap();
+        mock.unreachable.lock().unwrap().insert(gone.clone());
+        pool.associate(a.clone(), gone.clone());
+
+        let (_socket, _) = pool.clone().dial(addr_of("/ip4/127.0.0.1/tcp/1", &a))
+            .unwrap_or_else(|_| panic!())
+            .wait()
+            .unwrap();
+        let tcp: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        assert_eq!(*mock.dialed.lock().unwrap(), vec![gone, tcp.clone()]);
+        assert_eq!(pool.connection_to(&a), Some(tcp));
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Checking whether a remote supports a protocol, without running the protocol.
+//!
+//! See `SwarmController::probe_protocol`, which negotiates the protocol on a new substream and
+//! closes it right away. `SwarmController::probe_protocol_cached` instead answers from a
+//! `ProtocolCache` filled with the information gathered by *identify* when it can.
+
+use bytes::Bytes;
+use futures::future::{self, FutureResult};
+use multistream_select::ProtocolChoiceError;
+use std::io::Error as IoError;
+use std::iter;
+use tokio_io::{AsyncRead, AsyncWrite};
+use {ConnectionUpgrade, Endpoint, LocalIdentity, Multiaddr};
+
+/// Upgrade that negotiates a single protocol, then immediately closes the substream.
+///
+/// Used by `SwarmController::probe_protocol`. If the remote doesn't support the protocol, the
+/// upgrade fails during the negotiation, before `upgrade` is called.
+#[derive(Debug, Clone)]
+pub struct ProbeUpgrade {
+    protocol: Bytes,
+}
+
+impl ProbeUpgrade {
+    /// Builds a `ProbeUpgrade` for the protocol of the given name.
+    #[inline]
+    pub fn new<N>(protocol: N) -> ProbeUpgrade
+    where
+        N: Into<Bytes>,
+    {
+        ProbeUpgrade {
+            protocol: protocol.into(),
+        }
+    }
+}
+
+impl<C> ConnectionUpgrade<C> for ProbeUpgrade
+where
+    C: AsyncRead + AsyncWrite,
+{
+    type NamesIter = iter::Once<(Bytes, ())>;
+    type UpgradeIdentifier = ();
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        iter::once((self.protocol.clone(), ()))
+    }
+
+    type Output = ();
+    type Future = FutureResult<(), IoError>;
+
+    #[inline]
+    fn upgrade(
+        self,
+        socket: C,
+        _: (),
+        _: Endpoint,
+        _: &Multiaddr,
+        _: &LocalIdentity,
+    ) -> Self::Future {
+        // Dropping the socket closes the substream.
+        drop(socket);
+        future::ok(())
+    }
+}
+
+/// Answer to `SwarmController::probe_protocol`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProbeResult {
+    /// The remote supports the protocol.
+    Supported(ProbeSource),
+    /// The remote doesn't support the protocol.
+    Unsupported(ProbeSource),
+}
+
+impl ProbeResult {
+    /// Returns true if the remote supports the protocol.
+    #[inline]
+    pub fn is_supported(&self) -> bool {
+        match *self {
+            ProbeResult::Supported(_) => true,
+            ProbeResult::Unsupported(_) => false,
+        }
+    }
+
+    /// Returns where the answer comes from.
+    #[inline]
+    pub fn source(&self) -> ProbeSource {
+        match *self {
+            ProbeResult::Supported(source) | ProbeResult::Unsupported(source) => source,
+        }
+    }
+}
+
+/// Where the answer of a probe comes from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProbeSource {
+    /// The answer was found in a `ProtocolCache`, without contacting the remote. It can be
+    /// outdated if the remote changed its protocols since it was last identified.
+    Cache,
+    /// The answer was obtained by negotiating the protocol with the remote.
+    Negotiation,
+}
+
+/// Information about the protocols supported by remotes, for example the one gathered by the
+/// *identify* protocol and stored in a peerstore.
+///
+/// Implemented on closures, which makes it possible to look into whatever storage contains the
+/// information.
+pub trait ProtocolCache {
+    /// Returns whether the remote at `addr` supports `protocol`, or `None` if this is unknown.
+    fn supports(&self, addr: &Multiaddr, protocol: &[u8]) -> Option<bool>;
+}
+
+impl<F> ProtocolCache for F
+where
+    F: Fn(&Multiaddr, &[u8]) -> Option<bool>,
+{
+    #[inline]
+    fn supports(&self, addr: &Multiaddr, protocol: &[u8]) -> Option<bool> {
+        self(addr, protocol)
+    }
+}
+
+/// Turns the outcome of dialing with a `ProbeUpgrade` into a `ProbeResult`.
+pub fn probe_outcome<T>(outcome: Result<T, IoError>) -> Result<ProbeResult, IoError> {
+    let err = match outcome {
+        Ok(_) => return Ok(ProbeResult::Supported(ProbeSource::Negotiation)),
+        Err(err) => err,
+    };
+
+    let unsupported = match err.get_ref().and_then(|e| e.downcast_ref::<ProtocolChoiceError>()) {
+        Some(&ProtocolChoiceError::NoProtocolFound) => true,
+        _ => false,
+    };
+
+    if unsupported {
+        Ok(ProbeResult::Unsupported(ProbeSource::Negotiation))
+    } else {
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate libp2p_tcp_transport;
+
+    use super::{probe_outcome, ProbeResult, ProbeSource};
+    use futures::Future;
+    use multistream_select::ProtocolChoiceError;
+    use self::libp2p_tcp_transport::TcpConfig;
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+    use swarm::swarm;
+    use tokio_core::reactor::Core;
+    use {Multiaddr, PlainTextConfig, Transport};
+
+    #[test]
+    fn outcome() {
+        assert_eq!(
+            probe_outcome(Ok(())).unwrap(),
+            ProbeResult::Supported(ProbeSource::Negotiation)
+        );
+
+        let unsupported = IoError::new(IoErrorKind::Other, ProtocolChoiceError::NoProtocolFound);
+        assert_eq!(
+            probe_outcome::<()>(Err(unsupported)).unwrap(),
+            ProbeResult::Unsupported(ProbeSource::Negotiation)
+        );
+
+        let unreachable = IoError::new(IoErrorKind::ConnectionRefused, "refused");
+        let err = probe_outcome::<()>(Err(unreachable)).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn probe_remote() {
+        let mut core = Core::new().unwrap();
+        let transport = TcpConfig::new(core.handle()).with_dummy_muxing();
+        let (listener, future) =
+            swarm(transport.clone(), PlainTextConfig, |_, _| Ok::<_, IoError>(()));
+        let addr = listener.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        core.handle().spawn(future.map_err(|_| ()));
+
+        let (prober, _prober_future) =
+            swarm(transport, PlainTextConfig, |_, _| Ok::<_, IoError>(()));
+        let supported = prober.probe_protocol(addr.clone(), "/plaintext/1.0.0").unwrap();
+        let result = core.run(supported).unwrap();
+        assert_eq!(result, ProbeResult::Supported(ProbeSource::Negotiation));
+
+        let unsupported = prober.probe_protocol(addr, "/unknown/1.0.0").unwrap();
+        let result = core.run(unsupported).unwrap();
+        assert_eq!(result, ProbeResult::Unsupported(ProbeSource::Negotiation));
+    }
+
+    #[test]
+    fn cached_answer() {
+        let core = Core::new().unwrap();
+        let transport = TcpConfig::new(core.handle()).with_dummy_muxing();
+        let (controller, _future) =
+            swarm(transport, PlainTextConfig, |_, _| Ok::<_, IoError>(()));
+
+        // Nothing listens on this address, so the answer can only come from the cache.
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let cache = |_: &Multiaddr, protocol: &[u8]| Some(protocol == &b"/known/1.0.0"[..]);
+
+        let result = controller
+            .probe_protocol_cached(addr.clone(), "/known/1.0.0", &cache)
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert_eq!(result, ProbeResult::Supported(ProbeSource::Cache));
+        assert!(result.is_supported());
+
+        let result = controller
+            .probe_protocol_cached(addr, "/other/1.0.0", &cache)
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert_eq!(result, ProbeResult::Unsupported(ProbeSource::Cache));
+        assert_eq!(result.source(), ProbeSource::Cache);
+    }
+}
@@ -0,0 +1,492 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Reconnects to the nodes that the application wants to stay connected to.
+//!
+//! A `Redial` watches a set of multiaddresses. Whenever dialing one of them fails, or a
+//! connection that we dialed to one of them closes, it waits for some time and then asks for the
+//! address to be dialed again. The delay starts at `BackoffConfig::with_initial_delay` and is
+//! doubled after each failed attempt, up to `with_max_delay`. A random jitter is applied to it,
+//! so that nodes that lost their connections at the same time, for example because of a network
+//! outage, don't all reconnect at the same moment. A successful connection resets the delay.
+//!
+//! The addresses are matched with the ones of the `SwarmEvent`s: watch the address that is
+//! dialed, which is also the one reported by the events unless the transport rewrites it. Watch
+//! a `/p2p/` address in order to redial a specific peer.
+//!
+//! `Redial::drive` feeds the events of a swarm to the `Redial` and dials the addresses when it's
+//! time, so that the application doesn't need a reconnection loop of its own. Intentionally
+//! closing a connection without calling `unwatch` first makes it reconnect.
+
+use events::SwarmEvent;
+use futures::{Async, Future, Poll, Stream};
+use futures::task::{self, Task};
+use limits::DialError;
+use rand;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::Error as IoError;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use {Clock, Endpoint, Multiaddr, TokioClock};
+
+/// Configuration of the delays between the attempts of a `Redial`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BackoffConfig {
+    initial_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+    max_attempts: Option<u32>,
+}
+
+impl Default for BackoffConfig {
+    #[inline]
+    fn default() -> BackoffConfig {
+        BackoffConfig {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5 * 60),
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl BackoffConfig {
+    /// Sets the delay before the first attempt. Defaults to 1 second.
+    #[inline]
+    pub fn with_initial_delay(mut self, delay: Duration) -> BackoffConfig {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// Sets the maximum delay between two attempts. Defaults to 5 minutes.
+    #[inline]
+    pub fn with_max_delay(mut self, delay: Duration) -> BackoffConfig {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Sets the jitter, as a fraction of the delay. A jitter of 0.2 makes each delay a random
+    /// value between 80% and 120% of its nominal value. Defaults to 0.2.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `jitter` isn't between 0 and 1.
+    #[inline]
+    pub fn with_jitter(mut self, jitter: f64) -> BackoffConfig {
+        assert!(jitter >= 0.0 && jitter <= 1.0, "the jitter must be between 0 and 1");
+        self.jitter = jitter;
+        self
+    }
+
+    /// Gives up on an address after this number of consecutive failed attempts. By default, the
+    /// address is dialed again forever.
+    #[inline]
+    pub fn with_max_attempts(mut self, max: u32) -> BackoffConfig {
+        self.max_attempts = Some(max);
+        self
+    }
+
+    /// Returns the delay before the attempt number `attempt`, starting from 1. `random` is a
+    /// number between 0 and 1 that determines the jitter.
+    pub fn delay(&self, attempt: u32, random: f64) -> Duration {
+        // Doubling the delay more than 31 times overflows, and is much larger than any sensible
+        // maximum anyway.
+        let exponent = cmp::min(attempt.saturating_sub(1), 31);
+        let nominal = self.initial_delay
+            .checked_mul(1 << exponent)
+            .map_or(self.max_delay, |delay| cmp::min(delay, self.max_delay));
+
+        let factor = 1.0 + self.jitter * (2.0 * random - 1.0);
+        let nanos = (duration_to_nanos(nominal) as f64 * factor) as u64;
+        cmp::min(nanos_to_duration(nanos), self.max_delay)
+    }
+}
+
+#[inline]
+fn duration_to_nanos(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(u64::from(duration.subsec_nanos()))
+}
+
+#[inline]
+fn nanos_to_duration(nanos: u64) -> Duration {
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+/// Event produced by a `Redial`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RedialEvent {
+    /// It is time to dial this address again.
+    Redial(Multiaddr),
+    /// The maximum number of attempts has been reached, and the address isn't watched anymore.
+    GaveUp(Multiaddr),
+}
+
+/// Schedules the dialing attempts of the addresses that must stay connected.
+///
+/// Implements `Stream`, which produces the addresses to dial when it's time. Cloning it is
+/// cheap, and all the clones share the same state; only one of them should be polled.
+pub struct Redial<Ck = TokioClock>
+where
+    Ck: Clock,
+{
+    inner: Arc<Mutex<RedialInner<Ck>>>,
+}
+
+impl<Ck> Clone for Redial<Ck>
+where
+    Ck: Clock,
+{
+    #[inline]
+    fn clone(&self) -> Self {
+        Redial {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+struct RedialInner<Ck>
+where
+    Ck: Clock,
+{
+    config: BackoffConfig,
+    clock: Ck,
+    // For each watched address, the number of consecutive failed attempts and the delay before
+    // the next attempt, if one is scheduled.
+    targets: HashMap<Multiaddr, (u32, Option<Ck::Delay>)>,
+    gave_up: VecDeque<Multiaddr>,
+    // Task that polls the stream, to notify when an attempt is scheduled.
+    task: Option<Task>,
+}
+
+impl Redial<TokioClock> {
+    /// Creates a `Redial` that doesn't watch any address yet.
+    #[inline]
+    pub fn new(config: BackoffConfig) -> Redial<TokioClock> {
+        Redial::with_clock(config, TokioClock::new())
+    }
+}
+
+impl<Ck> Redial<Ck>
+where
+    Ck: Clock,
+{
+    /// Same as `new`, but uses the given `Clock` in order to wait.
+    pub fn with_clock(config: BackoffConfig, clock: Ck) -> Redial<Ck> {
+        Redial {
+            inner: Arc::new(Mutex::new(RedialInner {
+                config: config,
+                clock: clock,
+                targets: HashMap::new(),
+                gave_up: VecDeque::new(),
+                task: None,
+            })),
+        }
+    }
+
+    /// Starts watching `addr`. Nothing happens until dialing it fails or its connection closes.
+    pub fn watch(&self, addr: Multiaddr) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.targets.entry(addr).or_insert((0, None));
+    }
+
+    /// Stops watching `addr`, and cancels the attempt that is scheduled for it, if any. Returns
+    /// false if it wasn't watched.
+    pub fn unwatch(&self, addr: &Multiaddr) -> bool {
+        self.inner.lock().unwrap().targets.remove(addr).is_some()
+    }
+
+    /// Returns the number of consecutive failed attempts for `addr`, or `None` if it isn't
+    /// watched.
+    pub fn attempts(&self, addr: &Multiaddr) -> Option<u32> {
+        self.inner.lock().unwrap().targets.get(addr).map(|&(attempts, _)| attempts)
+    }
+
+    /// Updates the state of the watched addresses according to an event of the swarm.
+    pub fn inject_event(&self, event: &SwarmEvent) {
+        match *event {
+            SwarmEvent::DialFailure { ref address, .. } => self.inject_failure(address),
+            SwarmEvent::ConnectionClosed {
+                ref remote_addr,
+                endpoint: Endpoint::Dialer,
+                ..
+            } => self.inject_failure(remote_addr),
+            SwarmEvent::ConnectionEstablished {
+                ref remote_addr,
+                endpoint: Endpoint::Dialer,
+            } => {
+                let mut inner = self.inner.lock().unwrap();
+                if let Some(target) = inner.targets.get_mut(remote_addr) {
+                    *target = (0, None);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Schedules a new attempt for `addr`, if it is watched and no attempt is scheduled yet. To
+    /// be called when dialing it fails or its connection closes.
+    pub fn inject_failure(&self, addr: &Multiaddr) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        let give_up = match inner.targets.get_mut(addr) {
+            Some(&mut (_, Some(_))) | None => return,
+            Some(&mut (ref mut attempts, ref mut delay)) => {
+                *attempts += 1;
+                if inner.config.max_attempts.map_or(false, |max| *attempts > max) {
+                    true
+                } else {
+                    let duration = inner.config.delay(*attempts, rand::random());
+                    *delay = Some(inner.clock.delay(duration));
+                    false
+                }
+            }
+        };
+
+        if give_up {
+            inner.targets.remove(addr);
+            inner.gave_up.push_back(addr.clone());
+        }
+
+        if let Some(task) = inner.task.take() {
+            task.notify();
+        }
+    }
+
+    /// Returns a future that feeds `events` to this `Redial`, and calls `dial` with each address
+    /// that must be dialed again. A `dial` that fails right away counts as a failed attempt.
+    ///
+    /// The future finishes once `events` ends. It is typically built with `events()` and
+    /// `dial_to_handler` of the `SwarmController`.
+    #[inline]
+    pub fn drive<S, F>(self, events: S, dial: F) -> RedialFuture<Ck, S, F>
+    where
+        S: Stream<Item = SwarmEvent>,
+        F: FnMut(Multiaddr) -> Result<(), DialError>,
+    {
+        RedialFuture {
+            redial: self,
+            events: events,
+            dial: dial,
+        }
+    }
+}
+
+impl<Ck> Stream for Redial<Ck>
+where
+    Ck: Clock,
+{
+    type Item = RedialEvent;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Option<RedialEvent>, IoError> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(addr) = inner.gave_up.pop_front() {
+            return Ok(Async::Ready(Some(RedialEvent::GaveUp(addr))));
+        }
+
+        let mut due = None;
+        for (addr, &mut (_, ref mut delay)) in inner.targets.iter_mut() {
+            let is_due = match *delay {
+                Some(ref mut delay) => delay.poll()?.is_ready(),
+                None => false,
+            };
+            if is_due {
+                *delay = None;
+                due = Some(addr.clone());
+                break;
+            }
+        }
+
+        match due {
+            Some(addr) => Ok(Async::Ready(Some(RedialEvent::Redial(addr)))),
+            None => {
+                inner.task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// Future returned by `Redial::drive`.
+#[must_use = "futures do nothing unless polled"]
+pub struct RedialFuture<Ck, S, F>
+where
+    Ck: Clock,
+{
+    redial: Redial<Ck>,
+    events: S,
+    dial: F,
+}
+
+impl<Ck, S, F> Future for RedialFuture<Ck, S, F>
+where
+    Ck: Clock,
+    S: Stream<Item = SwarmEvent>,
+    F: FnMut(Multiaddr) -> Result<(), DialError>,
+{
+    type Item = ();
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<(), IoError> {
+        loop {
+            match self.events.poll() {
+                Ok(Async::Ready(Some(event))) => self.redial.inject_event(&event),
+                Ok(Async::Ready(None)) | Err(_) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => break,
+            }
+        }
+
+        while let Async::Ready(Some(event)) = self.redial.poll()? {
+            match event {
+                RedialEvent::Redial(addr) => {
+                    debug!("Dialing {} again", addr);
+                    if let Err(err) = (self.dial)(addr.clone()) {
+                        debug!("Failed to dial {} again: {}", addr, err);
+                        self.redial.inject_failure(&addr);
+                    }
+                }
+                RedialEvent::GaveUp(addr) => debug!("Giving up on dialing {}", addr),
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BackoffConfig, Redial, RedialEvent};
+    use futures::{future, Async, Future, Stream};
+    use libp2p_core::ManualClock;
+    use std::io::ErrorKind as IoErrorKind;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use {Endpoint, Multiaddr, SwarmEvent};
+
+    // Polls `redial` from within a task, and returns the event it produced, if any.
+    fn next<Ck: ::Clock>(redial: &mut Redial<Ck>) -> Option<RedialEvent> {
+        let polled = future::lazy(|| Ok::<_, ()>(redial.poll())).wait().unwrap();
+        match polled.unwrap() {
+            Async::Ready(Some(event)) => Some(event),
+            Async::Ready(None) => panic!("a redial stream never ends"),
+            Async::NotReady => None,
+        }
+    }
+
+    fn no_jitter() -> BackoffConfig {
+        BackoffConfig::default()
+            .with_initial_delay(Duration::from_secs(1))
+            .with_jitter(0.0)
+    }
+
+    fn dial_failure(addr: &Multiaddr) -> SwarmEvent {
+        SwarmEvent::DialFailure {
+            address: addr.clone(),
+            error: Arc::new(IoErrorKind::ConnectionRefused.into()),
+        }
+    }
+
+    #[test]
+    fn delay_doubles() {
+        let config = no_jitter();
+        assert_eq!(config.delay(1, 0.5), Duration::from_secs(1));
+        assert_eq!(config.delay(2, 0.5), Duration::from_secs(2));
+        assert_eq!(config.delay(3, 0.5), Duration::from_secs(4));
+        assert_eq!(config.delay(4, 0.5), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn delay_capped() {
+        let config = no_jitter().with_max_delay(Duration::from_secs(10));
+        assert_eq!(config.delay(5, 0.5), Duration::from_secs(10));
+        assert_eq!(config.delay(1000, 0.5), Duration::from_secs(10));
+
+        // The jitter doesn't go beyond the maximum either.
+        let config = config.with_jitter(1.0);
+        assert_eq!(config.delay(10, 1.0), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn jitter_bounds() {
+        let config = BackoffConfig::default()
+            .with_initial_delay(Duration::from_secs(10))
+            .with_jitter(0.2);
+        assert_eq!(config.delay(1, 0.0), Duration::from_secs(8));
+        assert_eq!(config.delay(1, 0.5), Duration::from_secs(10));
+        assert_eq!(config.delay(1, 1.0), Duration::from_secs(12));
+    }
+
+    #[test]
+    fn redials_then_gives_up() {
+        let clock = ManualClock::new();
+        let mut redial = Redial::with_clock(no_jitter().with_max_attempts(2), clock.clone());
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        redial.watch(addr.clone());
+
+        redial.inject_event(&dial_failure(&addr));
+        assert_eq!(next(&mut redial), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(next(&mut redial), Some(RedialEvent::Redial(addr.clone())));
+
+        redial.inject_event(&dial_failure(&addr));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(next(&mut redial), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(next(&mut redial), Some(RedialEvent::Redial(addr.clone())));
+
+        redial.inject_event(&dial_failure(&addr));
+        assert_eq!(next(&mut redial), Some(RedialEvent::GaveUp(addr.clone())));
+        assert_eq!(redial.attempts(&addr), None);
+    }
+
+    #[test]
+    fn success_resets_attempts() {
+        let clock = ManualClock::new();
+        let mut redial = Redial::with_clock(no_jitter(), clock.clone());
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        redial.watch(addr.clone());
+
+        redial.inject_event(&dial_failure(&addr));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(next(&mut redial), Some(RedialEvent::Redial(addr.clone())));
+        assert_eq!(redial.attempts(&addr), Some(1));
+
+        redial.inject_event(&SwarmEvent::ConnectionEstablished {
+            remote_addr: addr.clone(),
+            endpoint: Endpoint::Dialer,
+        });
+        assert_eq!(redial.attempts(&addr), Some(0));
+
+        // The next disconnection starts again from the initial delay.
+        redial.inject_event(&SwarmEvent::ConnectionClosed {
+            remote_addr: addr.clone(),
+            endpoint: Endpoint::Dialer,
+            cause: None,
+        });
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(next(&mut redial), Some(RedialEvent::Redial(addr)));
+    }
+}
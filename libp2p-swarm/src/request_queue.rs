@@ -0,0 +1,391 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Bounds the number of requests of each peer that are processed at the same time.
+//!
+//! A protocol handler that spawns a future for each inbound request can easily be flooded by a
+//! single peer opening many substreams. A `RequestQueue` lets at most a given number of requests
+//! of each peer run concurrently, and queues a limited number of the others in the order in which
+//! they arrived. Requests that don't fit in the queue are rejected right away with a `QueueFull`
+//! error, after which the handler typically answers with a "busy" response if the protocol has
+//! one, or drops the substream otherwise.
+//!
+//! The peers are identified by a key of any type. This is usually the `PeerId` of the remote,
+//! or its multiaddress for transports that don't authenticate it.
+//!
+//! ```
+//! extern crate futures;
+//! extern crate libp2p_swarm;
+//!
+//! use futures::Future;
+//! use libp2p_swarm::RequestQueue;
+//!
+//! # fn main() {
+//! // At most 4 concurrent requests per peer, and 16 more waiting.
+//! let queue = RequestQueue::new(4, 16);
+//!
+//! match queue.enqueue("peer") {
+//!     Ok(slot) => {
+//!         let response = slot.and_then(|permit| {
+//!             // Process the request, then drop `permit` once it is done.
+//!             drop(permit);
+//!             Ok(())
+//!         });
+//!         response.wait().unwrap();
+//!     }
+//!     Err(full) => println!("rejecting the request: {}", full),
+//! }
+//! # }
+//! ```
+
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+use std::collections::{HashMap, VecDeque};
+use std::error;
+use std::fmt;
+use std::hash::Hash;
+use std::io::Error as IoError;
+use std::sync::{Arc, Mutex};
+
+/// Queue of the requests of each peer. Cloning it is cheap, and all the clones share the same
+/// queues.
+#[derive(Debug, Clone)]
+pub struct RequestQueue<K>
+where
+    K: Hash + Eq,
+{
+    max_concurrent: usize,
+    max_queued: usize,
+    peers: Arc<Mutex<HashMap<K, PeerQueue>>>,
+}
+
+// State of the requests of a peer. Removed once the peer has no request anymore.
+#[derive(Debug, Default)]
+struct PeerQueue {
+    // Number of `RequestPermit`s that are alive.
+    active: usize,
+    // The `RequestSlot`s that wait for a permit, in order. Each of them is identified by a number,
+    // and stores the task to notify once it can get its permit.
+    waiting: VecDeque<(u64, Option<Task>)>,
+    next_id: u64,
+}
+
+impl PeerQueue {
+    // Wakes up the first waiting slot if a permit is available for it.
+    fn notify_first(&self, max_concurrent: usize) {
+        if self.active >= max_concurrent {
+            return;
+        }
+        if let Some(&(_, Some(ref task))) = self.waiting.front() {
+            task.notify();
+        }
+    }
+}
+
+impl<K> RequestQueue<K>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Creates a queue that lets `max_concurrent` requests of each peer run at the same time, and
+    /// up to `max_queued` others wait for their turn.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `max_concurrent` is 0.
+    pub fn new(max_concurrent: usize, max_queued: usize) -> RequestQueue<K> {
+        assert!(max_concurrent > 0, "max_concurrent must not be 0");
+        RequestQueue {
+            max_concurrent: max_concurrent,
+            max_queued: max_queued,
+            peers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Adds a request of `peer` to its queue. Returns a future that produces a `RequestPermit`
+    /// once the request can be processed, or an error if the queue of the peer is full.
+    ///
+    /// Dropping the `RequestSlot` before it produces the permit removes the request from the
+    /// queue.
+    pub fn enqueue(&self, peer: K) -> Result<RequestSlot<K>, QueueFull> {
+        let mut peers = self.peers.lock().unwrap();
+        let queue = peers.entry(peer.clone()).or_insert_with(PeerQueue::default);
+
+        // The slots at the front of the queue may not have been polled yet, even though a permit
+        // is available for them.
+        let available = self.max_concurrent.saturating_sub(queue.active);
+        if queue.waiting.len() >= self.max_queued + available {
+            return Err(QueueFull {
+                max_concurrent: self.max_concurrent,
+                max_queued: self.max_queued,
+            });
+        }
+
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.waiting.push_back((id, None));
+
+        Ok(RequestSlot {
+            queue: Some(self.clone()),
+            peer: peer,
+            id: id,
+        })
+    }
+
+    /// Returns the number of requests of `peer` that are being processed, and the number of those
+    /// that are waiting.
+    pub fn load(&self, peer: &K) -> (usize, usize) {
+        match self.peers.lock().unwrap().get(peer) {
+            Some(queue) => (queue.active, queue.waiting.len()),
+            None => (0, 0),
+        }
+    }
+
+    // Runs `f` on the queue of `peer`, then removes it if the peer has no request anymore.
+    fn with_peer<F, T>(&self, peer: &K, f: F) -> T
+    where
+        F: FnOnce(&mut PeerQueue) -> T,
+    {
+        let mut peers = self.peers.lock().unwrap();
+        let (result, is_empty) = {
+            let queue = peers
+                .get_mut(peer)
+                .expect("the queue of a peer is alive as long as it has a slot or a permit");
+            let result = f(queue);
+            (result, queue.active == 0 && queue.waiting.is_empty())
+        };
+        if is_empty {
+            peers.remove(peer);
+        }
+        result
+    }
+}
+
+/// Error returned by `RequestQueue::enqueue` when a peer has too many requests already.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct QueueFull {
+    /// The configured number of concurrent requests per peer.
+    pub max_concurrent: usize,
+    /// The configured number of waiting requests per peer.
+    pub max_queued: usize,
+}
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "too many requests from the peer (limit: {} running, {} waiting)",
+            self.max_concurrent, self.max_queued
+        )
+    }
+}
+
+impl error::Error for QueueFull {
+    #[inline]
+    fn description(&self) -> &str {
+        "too many requests from the peer"
+    }
+}
+
+/// Future that produces a `RequestPermit` once it is the turn of the request. Returned by
+/// `RequestQueue::enqueue`.
+///
+/// Never produces an error. The error type is `IoError` so that it can easily be chained with
+/// the processing of the request.
+#[must_use = "futures do nothing unless polled"]
+pub struct RequestSlot<K>
+where
+    K: Hash + Eq + Clone,
+{
+    // `None` once the permit has been produced.
+    queue: Option<RequestQueue<K>>,
+    peer: K,
+    id: u64,
+}
+
+impl<K> Future for RequestSlot<K>
+where
+    K: Hash + Eq + Clone,
+{
+    type Item = RequestPermit<K>;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<RequestPermit<K>, IoError> {
+        let granted = {
+            let queue = self.queue.as_ref().expect("future polled after completion");
+            let max_concurrent = queue.max_concurrent;
+            let id = self.id;
+            queue.with_peer(&self.peer, |peer_queue| {
+                let is_first = peer_queue.waiting.front().map(|&(first, _)| first) == Some(id);
+                if is_first && peer_queue.active < max_concurrent {
+                    peer_queue.waiting.pop_front();
+                    peer_queue.active += 1;
+                    // Another permit may be available for the next slot.
+                    peer_queue.notify_first(max_concurrent);
+                    return true;
+                }
+
+                if let Some(entry) = peer_queue.waiting.iter_mut().find(|entry| entry.0 == id) {
+                    entry.1 = Some(task::current());
+                }
+                false
+            })
+        };
+
+        if !granted {
+            return Ok(Async::NotReady);
+        }
+
+        Ok(Async::Ready(RequestPermit {
+            queue: self.queue.take().expect("checked above"),
+            peer: self.peer.clone(),
+        }))
+    }
+}
+
+impl<K> Drop for RequestSlot<K>
+where
+    K: Hash + Eq + Clone,
+{
+    fn drop(&mut self) {
+        if let Some(ref queue) = self.queue {
+            let max_concurrent = queue.max_concurrent;
+            let id = self.id;
+            queue.with_peer(&self.peer, |peer_queue| {
+                peer_queue.waiting.retain(|&(other, _)| other != id);
+                peer_queue.notify_first(max_concurrent);
+            });
+        }
+    }
+}
+
+/// Allows processing a request. The next request of the peer is allowed to run once this
+/// permit is dropped.
+pub struct RequestPermit<K>
+where
+    K: Hash + Eq + Clone,
+{
+    queue: RequestQueue<K>,
+    peer: K,
+}
+
+impl<K> Drop for RequestPermit<K>
+where
+    K: Hash + Eq + Clone,
+{
+    fn drop(&mut self) {
+        let max_concurrent = self.queue.max_concurrent;
+        self.queue.with_peer(&self.peer, |peer_queue| {
+            peer_queue.active -= 1;
+            peer_queue.notify_first(max_concurrent);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueueFull, RequestQueue};
+    use futures::executor::{self, Notify, NotifyHandle};
+    use futures::{Async, Future};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // Records whether the task has been notified.
+    struct Flag(AtomicBool);
+    impl Notify for Flag {
+        fn notify(&self, _: usize) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn is_ready<F: Future>(future: &mut F) -> bool {
+        let flag = NotifyHandle::from(Arc::new(Flag(AtomicBool::new(false))));
+        let mut spawned = executor::spawn(future);
+        match spawned.poll_future_notify(&flag, 0) {
+            Ok(Async::Ready(_)) => true,
+            Ok(Async::NotReady) => false,
+            Err(_) => panic!("a request slot never fails"),
+        }
+    }
+
+    #[test]
+    fn concurrency_bound() {
+        let queue = RequestQueue::new(2, 5);
+        let mut slots = (0..3).map(|_| queue.enqueue("a").unwrap()).collect::<Vec<_>>();
+        let mut permits = Vec::new();
+        for slot in slots.iter_mut().take(2) {
+            permits.push(slot.poll().unwrap());
+        }
+        assert!(!is_ready(&mut slots[2]));
+        assert_eq!(queue.load(&"a"), (2, 1));
+        // Other peers have their own limit.
+        assert!(is_ready(&mut queue.enqueue("b").unwrap()));
+    }
+
+    #[test]
+    fn queue_overflow() {
+        let queue = RequestQueue::new(1, 1);
+        let _first = queue.enqueue("a").unwrap();
+        let _second = queue.enqueue("a").unwrap();
+        assert_eq!(
+            queue.enqueue("a").err(),
+            Some(QueueFull {
+                max_concurrent: 1,
+                max_queued: 1,
+            })
+        );
+        assert!(queue.enqueue("b").is_ok());
+    }
+
+    #[test]
+    fn dropped_slot_removed() {
+        let queue = RequestQueue::new(1, 2);
+        let mut first = queue.enqueue("a").unwrap();
+        let second = queue.enqueue("a").unwrap();
+        let mut third = queue.enqueue("a").unwrap();
+        let permit = first.poll().unwrap();
+        assert!(!is_ready(&mut third));
+
+        drop(second);
+        assert_eq!(queue.load(&"a"), (1, 1));
+        drop(permit);
+        assert!(is_ready(&mut third));
+    }
+
+    #[test]
+    fn next_woken_on_permit_drop() {
+        let queue = RequestQueue::new(1, 1);
+        let mut first = queue.enqueue("a").unwrap();
+        let second = queue.enqueue("a").unwrap();
+        let permit = first.poll().unwrap();
+
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let handle = NotifyHandle::from(flag.clone());
+        let mut second = executor::spawn(second);
+        assert_eq!(second.poll_future_notify(&handle, 0).unwrap().is_ready(), false);
+        assert!(!flag.0.load(Ordering::SeqCst));
+
+        drop(permit);
+        assert!(flag.0.load(Ordering::SeqCst));
+        let permit = second.poll_future_notify(&handle, 0).unwrap();
+        assert!(permit.is_ready());
+        drop(permit);
+        assert_eq!(queue.load(&"a"), (0, 0));
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Diagnostic of the health of a node, for the use of node operators.
+//!
+//! See `SwarmController::self_check`.
+
+use std::io::Error as IoError;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use {LocalIdentity, Multiaddr, PeerId};
+
+/// Report produced by `SwarmController::self_check`.
+#[derive(Debug)]
+pub struct SelfCheckReport {
+    /// Result of dialing each of the addresses the swarm is listening on.
+    pub listeners: Vec<(Multiaddr, ListenerCheck)>,
+    /// Whether the system clock looks correct.
+    pub clock: ClockCheck,
+    /// Whether the identity of the transport matches the expected peer ID.
+    pub identity: IdentityCheck,
+}
+
+impl SelfCheckReport {
+    /// Returns true if none of the checks found a problem.
+    ///
+    /// An unknown identity is not considered as a problem, as it is the normal situation for
+    /// transports that don't perform any encryption.
+    pub fn is_ok(&self) -> bool {
+        let listeners_ok = self.listeners.iter().all(|&(_, ref check)| match *check {
+            ListenerCheck::Reachable => true,
+            _ => false,
+        });
+
+        let identity_ok = match self.identity {
+            IdentityCheck::Consistent | IdentityCheck::Unknown => true,
+            IdentityCheck::Mismatch { .. } => false,
+        };
+
+        listeners_ok && self.clock == ClockCheck::Ok && identity_ok
+    }
+}
+
+/// Result of dialing one of our own listening addresses.
+#[derive(Debug)]
+pub enum ListenerCheck {
+    /// We successfully connected to the address.
+    Reachable,
+    /// Dialing the address failed.
+    Unreachable(IoError),
+    /// The transport doesn't support dialing this address.
+    NotDialable,
+}
+
+/// Sanity check of the system clock.
+///
+/// Many parts of the network, such as the expiration of the addresses in the peer store, rely on
+/// the system time. A clock that is obviously wrong usually indicates a misconfigured machine.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClockCheck {
+    /// The clock looks correct.
+    Ok,
+    /// The clock is set to a time before this code was written.
+    TooEarly(SystemTime),
+    /// The clock is set to a time that is unrealistically far in the future.
+    TooLate(SystemTime),
+}
+
+impl ClockCheck {
+    /// Checks the current value of the system clock.
+    pub fn now() -> ClockCheck {
+        // 2018-01-01 and 2100-01-01.
+        let min = UNIX_EPOCH + Duration::from_secs(1_514_764_800);
+        let max = UNIX_EPOCH + Duration::from_secs(4_102_444_800);
+
+        let now = SystemTime::now();
+        if now < min {
+            ClockCheck::TooEarly(now)
+        } else if now > max {
+            ClockCheck::TooLate(now)
+        } else {
+            ClockCheck::Ok
+        }
+    }
+}
+
+/// Consistency check between the identity of the transport and the expected peer ID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentityCheck {
+    /// The public key of the transport corresponds to the expected peer ID.
+    Consistent,
+    /// The transport doesn't know the public key of the local node.
+    Unknown,
+    /// The public key of the transport corresponds to another peer ID.
+    Mismatch {
+        /// Peer ID that was passed to `self_check`.
+        expected: PeerId,
+        /// Peer ID derived from the public key of the transport.
+        actual: PeerId,
+    },
+}
+
+impl IdentityCheck {
+    /// Checks whether `identity` corresponds to `expected`.
+    ///
+    /// The public key of `identity` must be in its protobuf encoding, as returned by
+    /// `SecioKeyPair::local_identity`, so that the peer ID derived from it is the same as the one
+    /// remotes derive from our identify information.
+    pub fn new(identity: &LocalIdentity, expected: &PeerId) -> IdentityCheck {
+        match identity.public_key() {
+            Some(key) if expected.is_public_key(key) => IdentityCheck::Consistent,
+            Some(key) => IdentityCheck::Mismatch {
+                expected: expected.clone(),
+                actual: PeerId::from_public_key(key),
+            },
+            None => IdentityCheck::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate libp2p_identify;
+    extern crate libp2p_secio;
+    extern crate libp2p_tcp_transport;
+
+    use super::{ClockCheck, IdentityCheck, ListenerCheck, SelfCheckReport};
+    use futures::Future;
+    use self::libp2p_identify::PublicKey;
+    use self::libp2p_secio::SecioKeyPair;
+    use self::libp2p_tcp_transport::TcpConfig;
+    use std::io::Error as IoError;
+    use swarm::swarm;
+    use tokio_core::reactor::Core;
+    use {LocalIdentity, PeerId, PlainTextConfig, Transport};
+
+    #[test]
+    fn clock() {
+        assert_eq!(ClockCheck::now(), ClockCheck::Ok);
+    }
+
+    #[test]
+    fn identity() {
+        let expected = PeerId::from_public_key(&[1, 2, 3]);
+        assert_eq!(
+            IdentityCheck::new(&LocalIdentity::unknown(), &expected),
+            IdentityCheck::Unknown
+        );
+        assert_eq!(
+            IdentityCheck::new(&LocalIdentity::new(vec![1, 2, 3]), &expected),
+            IdentityCheck::Consistent
+        );
+        assert_eq!(
+            IdentityCheck::new(&LocalIdentity::new(vec![4, 5, 6]), &expected),
+            IdentityCheck::Mismatch {
+                expected: expected.clone(),
+                actual: PeerId::from_public_key(&[4, 5, 6]),
+            }
+        );
+    }
+
+    #[test]
+    fn identity_of_secio_key() {
+        // The peer ID that remotes derive from our identify information is the one to expect.
+        let public = include_bytes!("../../libp2p-secio/tests/test-public-key.der").to_vec();
+        let key = SecioKeyPair::rsa_from_pkcs8(
+            &include_bytes!("../../libp2p-secio/tests/test-private-key.pk8")[..],
+            public.clone(),
+        ).unwrap();
+
+        let expected = PublicKey::Rsa(public.clone()).to_peer_id();
+        assert_eq!(IdentityCheck::new(&key.local_identity(), &expected), IdentityCheck::Consistent);
+
+        // The hash of the raw DER key isn't the peer ID of the node.
+        let raw = PeerId::from_public_key(&public);
+        match IdentityCheck::new(&key.local_identity(), &raw) {
+            IdentityCheck::Mismatch { actual, .. } => assert_eq!(actual, expected),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn report_is_ok() {
+        let report = |listener, identity| SelfCheckReport {
+            listeners: vec![("/ip4/127.0.0.1/tcp/1".parse().unwrap(), listener)],
+            clock: ClockCheck::Ok,
+            identity: identity,
+        };
+
+        assert!(report(ListenerCheck::Reachable, IdentityCheck::Unknown).is_ok());
+        assert!(!report(ListenerCheck::NotDialable, IdentityCheck::Consistent).is_ok());
+        let mismatch = IdentityCheck::Mismatch {
+            expected: PeerId::from_public_key(&[1, 2, 3]),
+            actual: PeerId::from_public_key(&[4, 5, 6]),
+        };
+        assert!(!report(ListenerCheck::Reachable, mismatch).is_ok());
+    }
+
+    #[test]
+    fn self_check_swarm() {
+        let mut core = Core::new().unwrap();
+        let transport = TcpConfig::new(core.handle())
+            .with_local_identity(LocalIdentity::new(vec![1, 2, 3]))
+            .with_dummy_muxing();
+        let (controller, future) =
+            swarm(transport, PlainTextConfig, |_, _| Ok::<_, IoError>(()));
+        let addr = controller.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        core.handle().spawn(future.map_err(|_| ()));
+
+        let report = core.run(controller.self_check(&PeerId::from_public_key(&[1, 2, 3])))
+            .unwrap();
+        assert_eq!(report.listeners.len(), 1);
+        assert_eq!(report.listeners[0].0, addr);
+        match report.listeners[0].1 {
+            ListenerCheck::Reachable => (),
+            ref other => panic!("{:?}", other),
+        }
+        assert_eq!(report.identity, IdentityCheck::Consistent);
+        assert!(report.is_ok());
+    }
+}
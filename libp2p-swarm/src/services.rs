@@ -0,0 +1,460 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Lifecycle of the background tasks of the protocols, such as the periodic identification of
+//! the remotes.
+//!
+//! Each background task implements the `Service` trait and is registered under a name with the
+//! `ServicesController` returned by `SwarmController::services`. The tasks are then driven by the
+//! `SwarmFuture`, and can be stopped, aborted or restarted individually without touching the
+//! rest of the node.
+
+use futures::{future, Async, Future, Poll, Stream};
+use futures::sync::mpsc;
+use futures::task;
+use std::collections::HashMap;
+use std::io::Error as IoError;
+use std::sync::{Arc, Mutex};
+
+/// Background task of a protocol.
+///
+/// Implemented on closures that return the future of the task, for services that don't need to
+/// do anything in order to stop.
+pub trait Service {
+    /// Returns the future that performs the work of the service. Called again every time the
+    /// service is restarted.
+    ///
+    /// The service is considered stopped when the future finishes, and failed if it produces an
+    /// error.
+    fn start(&mut self) -> Box<Future<Item = (), Error = IoError>>;
+
+    /// Asks the service to stop gracefully. The future returned by `start` keeps being polled
+    /// until the one returned by this method finishes, then is destroyed.
+    ///
+    /// The default implementation stops immediately.
+    #[inline]
+    fn stop(&mut self) -> Box<Future<Item = (), Error = IoError>> {
+        Box::new(future::ok(()))
+    }
+}
+
+impl<F> Service for F
+where
+    F: FnMut() -> Box<Future<Item = (), Error = IoError>>,
+{
+    #[inline]
+    fn start(&mut self) -> Box<Future<Item = (), Error = IoError>> {
+        (*self)()
+    }
+}
+
+/// Status of a service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceStatus {
+    /// The service is running.
+    Running,
+    /// The service has been asked to stop and is finishing its work.
+    Stopping,
+    /// The service has been stopped, or its task finished by itself.
+    Stopped,
+    /// The service has been aborted.
+    Aborted,
+    /// The task of the service, or stopping it, produced an error.
+    Failed(String),
+}
+
+impl ServiceStatus {
+    /// Returns true if the service is running or stopping.
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        match *self {
+            ServiceStatus::Running | ServiceStatus::Stopping => true,
+            _ => false,
+        }
+    }
+}
+
+// Messages sent by the `ServicesController` to the `ServicesFuture`.
+enum Command {
+    Register(String, Box<Service>),
+    Start(String),
+    Stop(String),
+    Restart(String),
+    Abort(String),
+}
+
+/// Allows registering and controlling the services of a swarm. Cloning it is cheap.
+///
+/// The commands are processed the next time the swarm future is polled. Until then, `status`
+/// returns the previous status of the service.
+#[derive(Clone)]
+pub struct ServicesController {
+    commands: mpsc::UnboundedSender<Command>,
+    statuses: Arc<Mutex<HashMap<String, ServiceStatus>>>,
+}
+
+impl ServicesController {
+    /// Registers a service and starts it.
+    ///
+    /// If a service with the same name exists, it is aborted and replaced.
+    #[inline]
+    pub fn register<S>(&self, name: &str, service: S)
+    where
+        S: Service + 'static, // TODO: 'static :-/
+    {
+        self.send(Command::Register(name.to_owned(), Box::new(service)));
+    }
+
+    /// Starts a service that is not running. Has no effect if it is running.
+    #[inline]
+    pub fn start(&self, name: &str) {
+        self.send(Command::Start(name.to_owned()));
+    }
+
+    /// Asks a service to stop gracefully.
+    #[inline]
+    pub fn stop(&self, name: &str) {
+        self.send(Command::Stop(name.to_owned()));
+    }
+
+    /// Stops a service gracefully, then starts it again. Starts it if it is not running.
+    #[inline]
+    pub fn restart(&self, name: &str) {
+        self.send(Command::Restart(name.to_owned()));
+    }
+
+    /// Destroys the task of a service immediately, without giving it a chance to stop
+    /// gracefully.
+    #[inline]
+    pub fn abort(&self, name: &str) {
+        self.send(Command::Abort(name.to_owned()));
+    }
+
+    /// Returns the status of a service, or `None` if no service has been registered with this
+    /// name.
+    #[inline]
+    pub fn status(&self, name: &str) -> Option<ServiceStatus> {
+        self.statuses.lock().unwrap().get(name).cloned()
+    }
+
+    /// Returns the name and status of all the services, sorted by name.
+    pub fn statuses(&self) -> Vec<(String, ServiceStatus)> {
+        let mut statuses = self.statuses
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, status)| (name.clone(), status.clone()))
+            .collect::<Vec<_>>();
+        statuses.sort_by(|a, b| a.0.cmp(&b.0));
+        statuses
+    }
+
+    #[inline]
+    fn send(&self, command: Command) {
+        // Ignoring errors if the receiver has been closed, because in that situation nothing is
+        // going to be processed anyway.
+        let _ = self.commands.unbounded_send(command);
+    }
+}
+
+/// Drives the services registered through a `ServicesController`. Never finishes.
+///
+/// Polled by the `SwarmFuture`.
+pub struct ServicesFuture {
+    commands: mpsc::UnboundedReceiver<Command>,
+    statuses: Arc<Mutex<HashMap<String, ServiceStatus>>>,
+    entries: HashMap<String, Entry>,
+}
+
+struct Entry {
+    service: Box<Service>,
+    task: Option<Box<Future<Item = (), Error = IoError>>>,
+    stopping: Option<Box<Future<Item = (), Error = IoError>>>,
+    // If true, the service is started again once it has stopped.
+    restart: bool,
+}
+
+/// Builds a `ServicesController` and the `ServicesFuture` that drives the services.
+pub fn services() -> (ServicesController, ServicesFuture) {
+    let (tx, rx) = mpsc::unbounded();
+    let statuses = Arc::new(Mutex::new(HashMap::new()));
+
+    let controller = ServicesController {
+        commands: tx,
+        statuses: statuses.clone(),
+    };
+
+    let future = ServicesFuture {
+        commands: rx,
+        statuses: statuses,
+        entries: HashMap::new(),
+    };
+
+    (controller, future)
+}
+
+impl ServicesFuture {
+    fn process(&mut self, command: Command) {
+        match command {
+            Command::Register(name, service) => {
+                debug!("Registering service {:?}", name);
+                self.entries.insert(
+                    name.clone(),
+                    Entry {
+                        service: service,
+                        task: None,
+                        stopping: None,
+                        restart: false,
+                    },
+                );
+                self.start(&name);
+            }
+            Command::Start(name) => {
+                let running = self.entries.get(&name).map(|e| e.task.is_some());
+                if running == Some(false) {
+                    self.start(&name);
+                }
+            }
+            Command::Stop(name) => self.stop(&name, false),
+            Command::Restart(name) => {
+                let running = self.entries.get(&name).map(|e| e.task.is_some());
+                match running {
+                    Some(true) => self.stop(&name, true),
+                    Some(false) => self.start(&name),
+                    None => (),
+                }
+            }
+            Command::Abort(name) => {
+                if let Some(entry) = self.entries.get_mut(&name) {
+                    debug!("Aborting service {:?}", name);
+                    entry.task = None;
+                    entry.stopping = None;
+                    entry.restart = false;
+                } else {
+                    return;
+                }
+                self.set_status(&name, ServiceStatus::Aborted);
+            }
+        }
+    }
+
+    fn start(&mut self, name: &str) {
+        if let Some(entry) = self.entries.get_mut(name) {
+            debug!("Starting service {:?}", name);
+            entry.task = Some(entry.service.start());
+        } else {
+            return;
+        }
+        self.set_status(name, ServiceStatus::Running);
+    }
+
+    fn stop(&mut self, name: &str, restart: bool) {
+        if let Some(entry) = self.entries.get_mut(name) {
+            if entry.task.is_none() {
+                return;
+            }
+            entry.restart = restart;
+            if entry.stopping.is_some() {
+                return;
+            }
+            debug!("Stopping service {:?}", name);
+            entry.stopping = Some(entry.service.stop());
+        } else {
+            return;
+        }
+        self.set_status(name, ServiceStatus::Stopping);
+    }
+
+    #[inline]
+    fn set_status(&self, name: &str, status: ServiceStatus) {
+        self.statuses.lock().unwrap().insert(name.to_owned(), status);
+    }
+}
+
+impl Future for ServicesFuture {
+    type Item = ();
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<(), IoError> {
+        loop {
+            match self.commands.poll() {
+                Ok(Async::Ready(Some(command))) => self.process(command),
+                // The controller has been destroyed, but the services keep running.
+                Ok(Async::Ready(None)) | Err(_) => break,
+                Ok(Async::NotReady) => break,
+            }
+        }
+
+        let mut finished = Vec::new();
+        for (name, entry) in self.entries.iter_mut() {
+            let stopped = match entry.stopping.as_mut().map(|s| s.poll()) {
+                Some(Ok(Async::Ready(()))) => Some(ServiceStatus::Stopped),
+                Some(Err(err)) => Some(ServiceStatus::Failed(err.to_string())),
+                Some(Ok(Async::NotReady)) | None => None,
+            };
+            if let Some(status) = stopped {
+                entry.task = None;
+                entry.stopping = None;
+                finished.push((name.clone(), status, entry.restart));
+                continue;
+            }
+
+            let status = match entry.task.as_mut().map(|t| t.poll()) {
+                Some(Ok(Async::Ready(()))) => ServiceStatus::Stopped,
+                Some(Err(err)) => ServiceStatus::Failed(err.to_string()),
+                Some(Ok(Async::NotReady)) | None => continue,
+            };
+            entry.task = None;
+            entry.stopping = None;
+            finished.push((name.clone(), status, entry.restart));
+        }
+
+        for (name, status, restart) in finished {
+            debug!("Service {:?} is now {:?}", name, status);
+            self.set_status(&name, status);
+            if restart {
+                if let Some(entry) = self.entries.get_mut(&name) {
+                    entry.restart = false;
+                }
+                self.start(&name);
+                // Make sure that the new task gets polled.
+                task::current().notify();
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{services, Service, ServicesFuture, ServiceStatus};
+    use futures::{future, Async, Future};
+    use std::cell::Cell;
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+    use std::rc::Rc;
+
+    // Service whose task finishes once `done` is set, and that counts how many times it has
+    // been started.
+    #[derive(Clone, Default)]
+    struct Counting {
+        starts: Rc<Cell<u32>>,
+        done: Rc<Cell<bool>>,
+    }
+
+    impl Service for Counting {
+        fn start(&mut self) -> Box<Future<Item = (), Error = IoError>> {
+            self.starts.set(self.starts.get() + 1);
+            let done = self.done.clone();
+            Box::new(future::poll_fn(move || {
+                if done.get() {
+                    Ok(Async::Ready(()))
+                } else {
+                    Ok(Async::NotReady)
+                }
+            }))
+        }
+    }
+
+    fn poll(future: &mut ServicesFuture) {
+        future::lazy(|| Ok::<_, ()>(future.poll())).wait().unwrap().unwrap();
+    }
+
+    #[test]
+    fn register_and_finish() {
+        let (controller, mut future) = services();
+        let service = Counting::default();
+        controller.register("test", service.clone());
+        assert_eq!(controller.status("test"), None);
+
+        poll(&mut future);
+        assert_eq!(controller.status("test"), Some(ServiceStatus::Running));
+        assert_eq!(service.starts.get(), 1);
+
+        service.done.set(true);
+        poll(&mut future);
+        assert_eq!(controller.status("test"), Some(ServiceStatus::Stopped));
+    }
+
+    #[test]
+    fn stop_and_start() {
+        let (controller, mut future) = services();
+        let service = Counting::default();
+        controller.register("test", service.clone());
+        poll(&mut future);
+
+        controller.stop("test");
+        poll(&mut future);
+        assert_eq!(controller.status("test"), Some(ServiceStatus::Stopped));
+
+        controller.start("test");
+        poll(&mut future);
+        assert_eq!(controller.status("test"), Some(ServiceStatus::Running));
+        assert_eq!(service.starts.get(), 2);
+
+        // Starting a running service has no effect.
+        controller.start("test");
+        poll(&mut future);
+        assert_eq!(service.starts.get(), 2);
+    }
+
+    #[test]
+    fn restart() {
+        let (controller, mut future) = services();
+        let service = Counting::default();
+        controller.register("test", service.clone());
+        poll(&mut future);
+
+        controller.restart("test");
+        poll(&mut future);
+        assert_eq!(controller.status("test"), Some(ServiceStatus::Running));
+        assert_eq!(service.starts.get(), 2);
+    }
+
+    #[test]
+    fn abort() {
+        let (controller, mut future) = services();
+        controller.register("test", Counting::default());
+        poll(&mut future);
+
+        controller.abort("test");
+        poll(&mut future);
+        assert_eq!(controller.status("test"), Some(ServiceStatus::Aborted));
+        assert!(!controller.status("test").unwrap().is_active());
+    }
+
+    #[test]
+    fn failure() {
+        let (controller, mut future) = services();
+        controller.register("failing", || -> Box<Future<Item = (), Error = IoError>> {
+            Box::new(future::err(IoError::new(IoErrorKind::Other, "oops")))
+        });
+        controller.register("running", Counting::default());
+        poll(&mut future);
+
+        assert_eq!(
+            controller.statuses(),
+            vec![
+                ("failing".to_owned(), ServiceStatus::Failed("oops".to_owned())),
+                ("running".to_owned(), ServiceStatus::Running),
+            ]
+        );
+    }
+}
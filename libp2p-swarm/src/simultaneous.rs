@@ -0,0 +1,86 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Tie-breaking between the connections that two nodes open to each other at the same moment.
+//!
+//! When two nodes dial each other simultaneously, each of them ends up with two connections to
+//! the other: the one it dialed and the one it accepted. The swarm keeps only one of them, and
+//! both nodes pick the same one without having to communicate: the connection dialed by the node
+//! with the greatest peer ID survives, and the other one is closed.
+//!
+//! The swarm knows the peer ID of the remote through the `/p2p/` component of the address of
+//! the connection (see the `bans` module), and its own one through `Transport::local_identity`.
+//! Without them, both connections are kept. The local identity is unknown unless the transport
+//! is wrapped with `Transport::with_local_identity`, for example with the identity returned by
+//! `SecioKeyPair::local_identity` in the `libp2p-secio` crate.
+//!
+//! A TCP simultaneous open, where the two dialing attempts end up as a single connection, is a
+//! different situation: both sides believe to be the dialer of the same socket. This must be
+//! resolved before negotiating any protocol on that socket, with the `select_role` function of
+//! the `multistream-select` crate.
+
+use {Endpoint, PeerId};
+
+/// Returns the endpoint, from the point of view of `local`, of the connection to keep when
+/// `local` and `remote` have connected to each other simultaneously.
+///
+/// Returns `None` if both peer IDs are equal, in which case there is nothing to decide.
+pub fn kept_endpoint(local: &PeerId, remote: &PeerId) -> Option<Endpoint> {
+    if local.as_bytes() > remote.as_bytes() {
+        Some(Endpoint::Dialer)
+    } else if local.as_bytes() < remote.as_bytes() {
+        Some(Endpoint::Listener)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::kept_endpoint;
+    use {Endpoint, PeerId};
+
+    #[test]
+    fn greatest_peer_id_keeps_its_dial() {
+        let a = PeerId::from_public_key(&[1, 2, 3]);
+        let b = PeerId::from_public_key(&[4, 5, 6]);
+        let (greatest, lowest) = if a.as_bytes() > b.as_bytes() { (a, b) } else { (b, a) };
+
+        assert_eq!(kept_endpoint(&greatest, &lowest), Some(Endpoint::Dialer));
+        assert_eq!(kept_endpoint(&lowest, &greatest), Some(Endpoint::Listener));
+    }
+
+    #[test]
+    fn both_sides_keep_the_same_connection() {
+        let a = PeerId::from_public_key(&[1, 2, 3]);
+        let b = PeerId::from_public_key(&[4, 5, 6]);
+
+        // The connection dialed by `a` is the dialer side for `a` and the listener side for `b`.
+        let kept_by_a = kept_endpoint(&a, &b).unwrap();
+        let kept_by_b = kept_endpoint(&b, &a).unwrap();
+        assert_ne!(kept_by_a, kept_by_b);
+    }
+
+    #[test]
+    fn same_peer_id() {
+        let a = PeerId::from_public_key(&[1, 2, 3]);
+        assert_eq!(kept_endpoint(&a, &a.clone()), None);
+    }
+}
@@ -18,10 +18,22 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use std::io::Error as IoError;
+use bans::{peer_id_of, PeerBans};
+use bytes::Bytes;
+use events::SwarmEvent;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use futures::{future, Async, Future, IntoFuture, Poll, Stream};
 use futures::sync::mpsc;
-use {ConnectionUpgrade, Multiaddr, MuxedTransport, UpgradedNode};
+use health::HealthReport;
+use limits::{ConnectionCounter, ConnectionGuard, ConnectionLimits, DialError, LimitsError};
+use probe::{probe_outcome, ProbeResult, ProbeSource, ProbeUpgrade, ProtocolCache};
+use self_check::{ClockCheck, IdentityCheck, ListenerCheck, SelfCheckReport};
+use services::{services, ServicesController, ServicesFuture};
+use simultaneous::kept_endpoint;
+use {ConnectionUpgrade, Endpoint, Multiaddr, MuxedTransport, PeerId, UpgradedNode};
+use {dial_any, DialAnyError};
 
 /// Creates a swarm.
 ///
@@ -31,11 +43,46 @@ use {ConnectionUpgrade, Multiaddr, MuxedTransport, UpgradedNode};
 /// Produces a `SwarmController` and an implementation of `Future`. The controller can be used to
 /// control, and the `Future` must be driven to completion in order for things to work.
 ///
+/// The connections that two nodes open to each other simultaneously are only deduplicated if
+/// `transport.local_identity()` is known, which isn't the case by default. When using secio, pass
+/// `SecioKeyPair::local_identity()` to `Transport::with_local_identity`.
+///
 pub fn swarm<T, C, H, F>(
     transport: T,
     upgrade: C,
     handler: H,
 ) -> (SwarmController<T, C>, SwarmFuture<T, C, H, F::Future>)
+where
+    T: MuxedTransport + Clone + 'static, // TODO: 'static :-/
+    C: ConnectionUpgrade<T::RawConn> + Clone + 'static, // TODO: 'static :-/
+    C::NamesIter: Clone,                 // TODO: not elegant
+    H: FnMut(C::Output, Multiaddr) -> F,
+    F: IntoFuture<Item = (), Error = IoError>,
+{
+    let counter = ConnectionCounter::new(ConnectionLimits::default());
+    match swarm_with_limits(transport, upgrade, handler, counter) {
+        Ok(swarm) => swarm,
+        Err(_) => unreachable!("no limit is set, therefore all of them can be enforced"),
+    }
+}
+
+/// Same as `swarm`, but the number of connections is limited according to the limits of
+/// `counter`.
+///
+/// Dialing while a limit is reached fails with `DialError::Limit`. Incoming connections above
+/// the limits are dropped. A dial whose output can't be counted because a limit was reached in
+/// the meanwhile is reported as a `DialFailure` event. See `ConnectionLimits` for what is
+/// counted.
+///
+/// Returns an error if a per-peer limit is set while `transport` doesn't report the peer IDs of
+/// the remotes, or if a limit of established connections is set while none of the transports are
+/// wrapped with `counter.count_connections`.
+pub fn swarm_with_limits<T, C, H, F>(
+    transport: T,
+    upgrade: C,
+    handler: H,
+    counter: ConnectionCounter,
+) -> Result<(SwarmController<T, C>, SwarmFuture<T, C, H, F::Future>), LimitsError>
 where
     T: MuxedTransport + Clone + 'static, // TODO: 'static :-/
     C: ConnectionUpgrade<T::RawConn> + Clone + 'static, // TODO: 'static :-/
@@ -46,8 +93,21 @@ where
     let (new_dialers_tx, new_dialers_rx) = mpsc::unbounded();
     let (new_listeners_tx, new_listeners_rx) = mpsc::unbounded();
     let (new_toprocess_tx, new_toprocess_rx) = mpsc::unbounded();
+    let (new_bans_tx, new_bans_rx) = mpsc::unbounded();
+
+    counter.check_transport(&transport)?;
 
     let upgraded = transport.clone().with_upgrade(upgrade);
+    let (services_controller, services_future) = services();
+    let event_subscribers = Arc::new(Mutex::new(Vec::new()));
+    let bans = PeerBans::new();
+    let local_peer_id = transport
+        .local_identity()
+        .public_key()
+        .map(PeerId::from_public_key);
+    if local_peer_id.is_none() {
+        debug!("Local identity unknown; simultaneous connections won't be deduplicated");
+    }
 
     let future = SwarmFuture {
         upgraded: upgraded.clone(),
@@ -60,17 +120,29 @@ where
         new_dialers: new_dialers_rx,
         to_process: Vec::new(),
         new_toprocess: new_toprocess_rx,
+        counter: counter.clone(),
+        services: services_future,
+        event_subscribers: event_subscribers.clone(),
+        bans: bans.clone(),
+        new_bans: new_bans_rx,
+        local_peer_id: local_peer_id,
     };
 
     let controller = SwarmController {
         transport: transport,
         upgraded: upgraded,
+        listen_addrs: Arc::new(Mutex::new(Vec::new())),
         new_listeners: new_listeners_tx,
         new_dialers: new_dialers_tx,
         new_toprocess: new_toprocess_tx,
+        counter: counter,
+        services: services_controller,
+        event_subscribers: event_subscribers,
+        bans: bans,
+        new_bans: new_bans_tx,
     };
 
-    (controller, future)
+    Ok((controller, future))
 }
 
 /// Allows control of what the swarm is doing.
@@ -81,16 +153,28 @@ where
 {
     transport: T,
     upgraded: UpgradedNode<T, C>,
-    new_listeners: mpsc::UnboundedSender<
+    // Addresses we have successfully started listening on.
+    listen_addrs: Arc<Mutex<Vec<Multiaddr>>>,
+    new_listeners: mpsc::UnboundedSender<(
         Box<
             Stream<
                 Item = Box<Future<Item = (C::Output, Multiaddr), Error = IoError>>,
                 Error = IoError,
             >,
         >,
-    >,
-    new_dialers: mpsc::UnboundedSender<Box<Future<Item = (C::Output, Multiaddr), Error = IoError>>>,
+        Multiaddr,
+    )>,
+    new_dialers: mpsc::UnboundedSender<(
+        Box<Future<Item = (C::Output, Multiaddr), Error = IoError>>,
+        Multiaddr,
+    )>,
     new_toprocess: mpsc::UnboundedSender<Box<Future<Item = (), Error = IoError>>>,
+    counter: ConnectionCounter,
+    services: ServicesController,
+    event_subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<SwarmEvent>>>>,
+    bans: PeerBans,
+    // Used to tell the swarm future that a peer has been banned.
+    new_bans: mpsc::UnboundedSender<PeerId>,
 }
 
 impl<T, C> SwarmController<T, C>
@@ -103,25 +187,32 @@ where
     /// upgraded using the `upgrade`, and the output is sent to the handler that was passed when
     /// calling `swarm`.
     // TODO: consider returning a future so that errors can be processed?
-    pub fn dial_to_handler<Du>(&self, multiaddr: Multiaddr, upgrade: Du) -> Result<(), Multiaddr>
+    pub fn dial_to_handler<Du>(&self, multiaddr: Multiaddr, upgrade: Du) -> Result<(), DialError>
     where
         Du: ConnectionUpgrade<T::RawConn> + Clone + 'static, // TODO: 'static :-/
         Du::Output: Into<C::Output>,
     {
+        if let Some(peer) = self.bans.banned_peer_of(&multiaddr) {
+            return Err(DialError::Banned(peer));
+        }
+        let guard = self.counter.pending_dial(&multiaddr).map_err(DialError::Limit)?;
         match self.transport
             .clone()
             .with_upgrade(upgrade)
             .dial(multiaddr.clone())
         {
             Ok(dial) => {
-                let dial = Box::new(dial.map(|(d, client_addr)| (d.into(), client_addr)))
-                    as Box<Future<Item = _, Error = _>>;
+                let dial = dial.then(move |result| {
+                    drop(guard);
+                    result.map(|(d, client_addr)| (d.into(), client_addr))
+                });
+                let dial = Box::new(dial) as Box<Future<Item = _, Error = _>>;
                 // Ignoring errors if the receiver has been closed, because in that situation
                 // nothing is going to be processed anyway.
-                let _ = self.new_dialers.unbounded_send(dial);
+                let _ = self.new_dialers.unbounded_send((dial, multiaddr));
                 Ok(())
             }
-            Err((_, multiaddr)) => Err(multiaddr),
+            Err((_, multiaddr)) => Err(DialError::MultiaddrNotSupported(multiaddr)),
         }
     }
 
@@ -136,24 +227,156 @@ where
         multiaddr: Multiaddr,
         upgrade: Du,
         and_then: Df,
-    ) -> Result<(), Multiaddr>
+    ) -> Result<(), DialError>
     where
         Du: ConnectionUpgrade<T::RawConn> + 'static, // TODO: 'static :-/
         Df: FnOnce(Du::Output, Multiaddr) -> Dfu + 'static, // TODO: 'static :-/
         Dfu: IntoFuture<Item = (), Error = IoError> + 'static, // TODO: 'static :-/
     {
+        if let Some(peer) = self.bans.banned_peer_of(&multiaddr) {
+            return Err(DialError::Banned(peer));
+        }
+        let pending = self.counter.pending_dial(&multiaddr).map_err(DialError::Limit)?;
+        let counter = self.counter.clone();
+        let bans = self.bans.clone();
         match self.transport.clone().with_upgrade(upgrade).dial(multiaddr) {
             Ok(dial) => {
-                let dial = Box::new(dial.and_then(|(d, m)| and_then(d, m))) as Box<_>;
+                let dial = dial.then(move |result| {
+                    drop(pending);
+                    let (d, m) = result?;
+                    if bans.banned_peer_of(&m).is_some() {
+                        return Err(banned_error());
+                    }
+                    let established = counter
+                        .substream(&m)
+                        .map_err(|limit| IoError::new(IoErrorKind::Other, limit))?;
+                    Ok::<_, IoError>((d, m, established))
+                });
+                let dial = dial.and_then(|(d, m, established)| {
+                    and_then(d, m).into_future().then(move |result| {
+                        drop(established);
+                        result
+                    })
+                });
+                let dial = Box::new(dial) as Box<_>;
                 // Ignoring errors if the receiver has been closed, because in that situation
                 // nothing is going to be processed anyway.
                 let _ = self.new_toprocess.unbounded_send(dial);
                 Ok(())
             }
+            Err((_, multiaddr)) => Err(DialError::MultiaddrNotSupported(multiaddr)),
+        }
+    }
+
+    /// Dials the candidate multiaddresses of a node one by one, in order, until one of them
+    /// succeeds. Each connection is upgraded using `upgrade`.
+    ///
+    /// Contrary to `dial_to_handler`, the output of the upgrade is not given to the handler that
+    /// was passed at initialization. Instead the returned future must be driven to completion,
+    /// and produces either the output of the first successful attempt or the reason why each
+    /// candidate failed.
+    pub fn dial_any<I, Du>(
+        &self,
+        multiaddrs: I,
+        upgrade: Du,
+    ) -> Box<Future<Item = (Du::Output, Multiaddr), Error = DialAnyError>>
+    where
+        I: IntoIterator<Item = Multiaddr>,
+        I::IntoIter: 'static, // TODO: 'static :-/
+        Du: ConnectionUpgrade<T::RawConn> + Clone + 'static, // TODO: 'static :-/
+    {
+        let upgraded = self.transport.clone().with_upgrade(upgrade);
+        let future = dial_any(multiaddrs, move |addr| {
+            upgraded.clone().dial(addr).map_err(|(_, addr)| addr)
+        });
+
+        // The "Rust doesn't have impl Trait yet" tax.
+        Box::new(future)
+    }
+
+    /// Checks whether the node at `multiaddr` supports `protocol`, without running the protocol.
+    ///
+    /// The node is dialed, and the protocol is negotiated with multistream-select on a new
+    /// substream that is closed as soon as the negotiation succeeds. The handler of the swarm is
+    /// not involved. If the transport supports connection reuse, an existing connection is used.
+    ///
+    /// The future only produces an error if the remote couldn't be reached, or if the
+    /// negotiation failed for another reason than the protocol being unsupported.
+    pub fn probe_protocol<N>(
+        &self,
+        multiaddr: Multiaddr,
+        protocol: N,
+    ) -> Result<Box<Future<Item = ProbeResult, Error = IoError>>, Multiaddr>
+    where
+        N: Into<Bytes>,
+    {
+        let upgrade = ProbeUpgrade::new(protocol);
+        match self.transport.clone().with_upgrade(upgrade).dial(multiaddr) {
+            Ok(dial) => Ok(Box::new(dial.then(probe_outcome))),
             Err((_, multiaddr)) => Err(multiaddr),
         }
     }
 
+    /// Same as `probe_protocol`, but first looks for the answer in `cache`. The remote is only
+    /// contacted if the cache doesn't know whether it supports the protocol.
+    pub fn probe_protocol_cached<N, Pc>(
+        &self,
+        multiaddr: Multiaddr,
+        protocol: N,
+        cache: &Pc,
+    ) -> Result<Box<Future<Item = ProbeResult, Error = IoError>>, Multiaddr>
+    where
+        N: Into<Bytes>,
+        Pc: ?Sized + ProtocolCache,
+    {
+        let protocol = protocol.into();
+        let result = match cache.supports(&multiaddr, &protocol) {
+            Some(true) => ProbeResult::Supported(ProbeSource::Cache),
+            Some(false) => ProbeResult::Unsupported(ProbeSource::Cache),
+            None => return self.probe_protocol(multiaddr, protocol),
+        };
+
+        Ok(Box::new(future::ok(result)))
+    }
+
+    /// Returns the controller of the background services of the swarm. The services run as part
+    /// of the `SwarmFuture`.
+    #[inline]
+    pub fn services(&self) -> &ServicesController {
+        &self.services
+    }
+
+    /// Bans `peer` for `duration`, or until `unban_peer` is called if `duration` is `None`.
+    ///
+    /// The connections with this peer that have been given to the handler are closed, and the
+    /// new connections with it are refused until the ban expires. See the `bans` module.
+    pub fn ban_peer(&self, peer: PeerId, duration: Option<Duration>) {
+        self.bans.ban(peer.clone(), duration);
+        // Ignoring errors if the receiver has been closed, because in that situation there is no
+        // connection to close anyway.
+        let _ = self.new_bans.unbounded_send(peer);
+    }
+
+    /// Lifts the ban of `peer`. Returns false if it wasn't banned.
+    #[inline]
+    pub fn unban_peer(&self, peer: &PeerId) -> bool {
+        self.bans.unban(peer)
+    }
+
+    /// Returns the list of banned peers of the swarm.
+    #[inline]
+    pub fn bans(&self) -> &PeerBans {
+        &self.bans
+    }
+
+    /// Returns a stream of the events that happen in the swarm from now on. See the `events`
+    /// module.
+    pub fn events(&self) -> mpsc::UnboundedReceiver<SwarmEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.event_subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
     /// Adds a multiaddr to listen on. All the incoming connections will use the `upgrade` that
     /// was passed to `swarm`.
     pub fn listen_on(&self, multiaddr: Multiaddr) -> Result<Multiaddr, Multiaddr> {
@@ -161,12 +384,88 @@ where
             Ok((listener, new_addr)) => {
                 // Ignoring errors if the receiver has been closed, because in that situation
                 // nothing is going to be processed anyway.
-                let _ = self.new_listeners.unbounded_send(listener);
+                let _ = self.new_listeners.unbounded_send((listener, new_addr.clone()));
+                self.listen_addrs.lock().unwrap().push(new_addr.clone());
+                broadcast(&self.event_subscribers, SwarmEvent::NewListenAddr {
+                    address: new_addr.clone(),
+                });
                 Ok(new_addr)
             }
             Err((_, multiaddr)) => Err(multiaddr),
         }
     }
+
+    /// Calls `listen_on` with each of the multiaddrs, for example the ones of a `ListenSpec`.
+    /// Returns the result of each call, in the same order.
+    pub fn listen_on_all<I>(&self, multiaddrs: I) -> Vec<Result<Multiaddr, Multiaddr>>
+    where
+        I: IntoIterator<Item = Multiaddr>,
+    {
+        multiaddrs
+            .into_iter()
+            .map(|multiaddr| self.listen_on(multiaddr))
+            .collect()
+    }
+
+    /// Returns a summary of the state of the node, meant to be used by readiness probes.
+    ///
+    /// `target_connections` is the number of established substreams below which the node isn't
+    /// considered ready. See the `health` module.
+    pub fn health(&self, target_connections: Option<usize>) -> HealthReport {
+        HealthReport {
+            listeners: self.listen_addrs.lock().unwrap().clone(),
+            connections: self.counter.counts(),
+            target_connections: target_connections,
+            services: self.services.statuses(),
+        }
+    }
+
+    /// Runs a series of diagnostics on the node and returns a report.
+    ///
+    /// - Each address passed to `listen_on` is dialed with the raw transport, in order to check
+    ///   that it can be reached. This only checks that our own machine can reach the address,
+    ///   not whether it is reachable from the outside. Dialing may hang forever unless the
+    ///   transport has a timeout (see `Transport::with_timeout`).
+    /// - The system clock is checked for obviously wrong values.
+    /// - The public key of the transport is checked against `local_peer_id`.
+    ///
+    /// The swarm future must be running for the listeners to accept the connections.
+    pub fn self_check(
+        &self,
+        local_peer_id: &PeerId,
+    ) -> Box<Future<Item = SelfCheckReport, Error = IoError>> {
+        let listen_addrs = self.listen_addrs.lock().unwrap().clone();
+        let listeners = listen_addrs.into_iter().map(|addr| {
+            match self.transport.clone().dial(addr.clone()) {
+                Ok(dial) => {
+                    let future = dial.then(move |result| {
+                        let check = match result {
+                            Ok(_) => ListenerCheck::Reachable,
+                            Err(err) => ListenerCheck::Unreachable(err),
+                        };
+                        Ok((addr, check))
+                    });
+                    Box::new(future) as Box<Future<Item = _, Error = IoError>>
+                }
+                Err((_, addr)) => {
+                    Box::new(future::ok((addr, ListenerCheck::NotDialable))) as Box<_>
+                }
+            }
+        });
+
+        let clock = ClockCheck::now();
+        let identity = IdentityCheck::new(&self.transport.local_identity(), local_peer_id);
+
+        let future = future::join_all(listeners.collect::<Vec<_>>()).map(move |listeners| {
+            SelfCheckReport {
+                listeners: listeners,
+                clock: clock,
+                identity: identity,
+            }
+        });
+
+        Box::new(future)
+    }
 }
 
 /// Future that must be driven to completion in order for the swarm to work.
@@ -177,31 +476,53 @@ where
 {
     upgraded: UpgradedNode<T, C>,
     handler: H,
-    new_listeners: mpsc::UnboundedReceiver<
+    new_listeners: mpsc::UnboundedReceiver<(
         Box<
             Stream<
                 Item = Box<Future<Item = (C::Output, Multiaddr), Error = IoError>>,
                 Error = IoError,
             >,
         >,
-    >,
+        Multiaddr,
+    )>,
     next_incoming: Box<
         Future<Item = Box<Future<Item = (C::Output, Multiaddr), Error = IoError>>, Error = IoError>,
     >,
-    listeners: Vec<
+    // Each listener is stored alongside with the address it listens on.
+    listeners: Vec<(
         Box<
             Stream<
                 Item = Box<Future<Item = (C::Output, Multiaddr), Error = IoError>>,
                 Error = IoError,
             >,
         >,
-    >,
-    listeners_upgrade: Vec<Box<Future<Item = (C::Output, Multiaddr), Error = IoError>>>,
-    dialers: Vec<Box<Future<Item = (C::Output, Multiaddr), Error = IoError>>>,
-    new_dialers:
-        mpsc::UnboundedReceiver<Box<Future<Item = (C::Output, Multiaddr), Error = IoError>>>,
-    to_process: Vec<future::Either<F, Box<Future<Item = (), Error = IoError>>>>,
+        Multiaddr,
+    )>,
+    listeners_upgrade: Vec<(
+        Box<Future<Item = (C::Output, Multiaddr), Error = IoError>>,
+        ConnectionGuard,
+    )>,
+    // Each dialing attempt is stored alongside with the address that is dialed.
+    dialers: Vec<(Box<Future<Item = (C::Output, Multiaddr), Error = IoError>>, Multiaddr)>,
+    new_dialers: mpsc::UnboundedReceiver<(
+        Box<Future<Item = (C::Output, Multiaddr), Error = IoError>>,
+        Multiaddr,
+    )>,
+    // The guard and the remote are `None` for the futures passed to `dial_custom_handler`, as
+    // they count themselves and aren't reported in the events.
+    to_process: Vec<(
+        future::Either<F, Box<Future<Item = (), Error = IoError>>>,
+        Option<ConnectionGuard>,
+        Option<(Multiaddr, Endpoint)>,
+    )>,
     new_toprocess: mpsc::UnboundedReceiver<Box<Future<Item = (), Error = IoError>>>,
+    counter: ConnectionCounter,
+    services: ServicesFuture,
+    event_subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<SwarmEvent>>>>,
+    bans: PeerBans,
+    new_bans: mpsc::UnboundedReceiver<PeerId>,
+    // Used to choose between the connections opened simultaneously with a peer.
+    local_peer_id: Option<PeerId>,
 }
 
 impl<T, C, H, If, F> Future for SwarmFuture<T, C, H, F>
@@ -218,11 +539,21 @@ where
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         let handler = &mut self.handler;
+        let subscribers = &self.event_subscribers;
+        let local_peer_id = self.local_peer_id.as_ref();
 
         match self.next_incoming.poll() {
             Ok(Async::Ready(connec)) => {
                 self.next_incoming = self.upgraded.clone().next_incoming();
-                self.listeners_upgrade.push(connec);
+                match self.counter.pending_incoming() {
+                    Ok(guard) => {
+                        self.listeners_upgrade.push((connec, guard));
+                        broadcast(subscribers, SwarmEvent::IncomingConnection {
+                            listen_addr: None,
+                        });
+                    }
+                    Err(limit) => debug!("Dropping incoming substream: {}", limit),
+                }
             }
             Ok(Async::NotReady) => {}
             // TODO: may not be the best idea because we're killing the whole server
@@ -253,7 +584,7 @@ where
 
         match self.new_toprocess.poll() {
             Ok(Async::Ready(Some(new_toprocess))) => {
-                self.to_process.push(future::Either::B(new_toprocess));
+                self.to_process.push((future::Either::B(new_toprocess), None, None));
             }
             Ok(Async::Ready(None)) | Err(_) => {
                 // New to-process sender has been closed.
@@ -261,15 +592,44 @@ where
             Ok(Async::NotReady) => {}
         };
 
+        while let Ok(Async::Ready(Some(peer))) = self.new_bans.poll() {
+            for n in (0..self.to_process.len()).rev() {
+                let is_banned = match self.to_process[n].2 {
+                    Some((ref remote_addr, _)) => peer_id_of(remote_addr).as_ref() == Some(&peer),
+                    None => false,
+                };
+                if !is_banned {
+                    continue;
+                }
+
+                let (_, _, remote) = self.to_process.swap_remove(n);
+                let (remote_addr, endpoint) = remote.expect("checked above");
+                debug!("Closing connection with banned peer {:?}", peer);
+                broadcast(subscribers, SwarmEvent::ConnectionClosed {
+                    remote_addr: remote_addr,
+                    endpoint: endpoint,
+                    cause: Some(Arc::new(banned_error())),
+                });
+            }
+        }
+
         for n in (0..self.listeners.len()).rev() {
-            let mut listener = self.listeners.swap_remove(n);
+            let (mut listener, listen_addr) = self.listeners.swap_remove(n);
             match listener.poll() {
                 Ok(Async::Ready(Some(upgrade))) => {
-                    self.listeners.push(listener);
-                    self.listeners_upgrade.push(upgrade);
+                    match self.counter.pending_incoming() {
+                        Ok(guard) => {
+                            self.listeners_upgrade.push((upgrade, guard));
+                            broadcast(subscribers, SwarmEvent::IncomingConnection {
+                                listen_addr: Some(listen_addr.clone()),
+                            });
+                        }
+                        Err(limit) => debug!("Dropping incoming connection: {}", limit),
+                    }
+                    self.listeners.push((listener, listen_addr));
                 }
                 Ok(Async::NotReady) => {
-                    self.listeners.push(listener);
+                    self.listeners.push((listener, listen_addr));
                 }
                 Ok(Async::Ready(None)) => {}
                 Err(_err) => {} // Ignoring errors
@@ -277,45 +637,505 @@ where
         }
 
         for n in (0..self.listeners_upgrade.len()).rev() {
-            let mut upgrade = self.listeners_upgrade.swap_remove(n);
+            let (mut upgrade, pending) = self.listeners_upgrade.swap_remove(n);
             match upgrade.poll() {
                 Ok(Async::Ready((output, client_addr))) => {
-                    self.to_process.push(future::Either::A(
-                        handler(output, client_addr).into_future(),
-                    ));
+                    drop(pending);
+                    if let Some(peer) = self.bans.banned_peer_of(&client_addr) {
+                        debug!("Closing incoming connection from banned peer {:?}", peer);
+                        continue;
+                    }
+                    if !break_simultaneous_tie(
+                        &mut self.to_process,
+                        local_peer_id,
+                        &client_addr,
+                        Endpoint::Listener,
+                        subscribers,
+                    ) {
+                        debug!("Closing duplicate incoming connection from {}", client_addr);
+                        continue;
+                    }
+                    match self.counter.substream(&client_addr) {
+                        Ok(guard) => {
+                            broadcast(subscribers, SwarmEvent::ConnectionEstablished {
+                                remote_addr: client_addr.clone(),
+                                endpoint: Endpoint::Listener,
+                            });
+                            let remote = (client_addr.clone(), Endpoint::Listener);
+                            self.to_process.push((
+                                future::Either::A(handler(output, client_addr).into_future()),
+                                Some(guard),
+                                Some(remote),
+                            ));
+                        }
+                        Err(limit) => debug!("Closing incoming connection: {}", limit),
+                    }
                 }
                 Ok(Async::NotReady) => {
-                    self.listeners_upgrade.push(upgrade);
+                    self.listeners_upgrade.push((upgrade, pending));
                 }
                 Err(_err) => {} // Ignoring errors
             }
         }
 
         for n in (0..self.dialers.len()).rev() {
-            let mut dialer = self.dialers.swap_remove(n);
+            let (mut dialer, dialed_addr) = self.dialers.swap_remove(n);
             match dialer.poll() {
                 Ok(Async::Ready((output, addr))) => {
-                    self.to_process
-                        .push(future::Either::A(handler(output, addr).into_future()));
+                    if self.bans.banned_peer_of(&addr).is_some() {
+                        debug!("Closing outgoing connection to banned remote {}", addr);
+                        broadcast(subscribers, SwarmEvent::DialFailure {
+                            address: dialed_addr,
+                            error: Arc::new(banned_error()),
+                        });
+                        continue;
+                    }
+                    if !break_simultaneous_tie(
+                        &mut self.to_process,
+                        local_peer_id,
+                        &addr,
+                        Endpoint::Dialer,
+                        subscribers,
+                    ) {
+                        debug!("Closing duplicate outgoing connection to {}", addr);
+                        broadcast(subscribers, SwarmEvent::DialFailure {
+                            address: dialed_addr,
+                            error: Arc::new(duplicate_error()),
+                        });
+                        continue;
+                    }
+                    match self.counter.substream(&addr) {
+                        Ok(guard) => {
+                            broadcast(subscribers, SwarmEvent::ConnectionEstablished {
+                                remote_addr: addr.clone(),
+                                endpoint: Endpoint::Dialer,
+                            });
+                            let remote = (addr.clone(), Endpoint::Dialer);
+                            self.to_process.push((
+                                future::Either::A(handler(output, addr).into_future()),
+                                Some(guard),
+                                Some(remote),
+                            ));
+                        }
+                        Err(limit) => {
+                            debug!("Closing outgoing connection: {}", limit);
+                            broadcast(subscribers, SwarmEvent::DialFailure {
+                                address: dialed_addr,
+                                error: Arc::new(IoError::new(IoErrorKind::Other, limit)),
+                            });
+                        }
+                    }
                 }
                 Ok(Async::NotReady) => {
-                    self.dialers.push(dialer);
+                    self.dialers.push((dialer, dialed_addr));
+                }
+                Err(err) => {
+                    broadcast(subscribers, SwarmEvent::DialFailure {
+                        address: dialed_addr,
+                        error: Arc::new(err),
+                    });
                 }
-                Err(_err) => {} // Ignoring errors
             }
         }
 
         for n in (0..self.to_process.len()).rev() {
-            let mut to_process = self.to_process.swap_remove(n);
-            match to_process.poll() {
-                Ok(Async::Ready(())) => {}
-                Ok(Async::NotReady) => self.to_process.push(to_process),
-                Err(_err) => {} // Ignoring errors
+            let (mut to_process, guard, remote) = self.to_process.swap_remove(n);
+            let cause = match to_process.poll() {
+                Ok(Async::Ready(())) => None,
+                Ok(Async::NotReady) => {
+                    self.to_process.push((to_process, guard, remote));
+                    continue;
+                }
+                Err(err) => Some(Arc::new(err)),
+            };
+            if let Some((remote_addr, endpoint)) = remote {
+                broadcast(subscribers, SwarmEvent::ConnectionClosed {
+                    remote_addr: remote_addr,
+                    endpoint: endpoint,
+                    cause: cause,
+                });
             }
         }
 
+        // The services never finish and their errors only affect their own status.
+        let _ = self.services.poll();
+
         // TODO: we never return `Ok(Ready)` because there's no way to know whether
         //       `next_incoming()` can produce anything more in the future
         Ok(Async::NotReady)
     }
 }
+
+// Error produced for the connections that are closed or refused because the remote is banned.
+#[inline]
+fn banned_error() -> IoError {
+    IoError::new(IoErrorKind::Other, "the remote is banned")
+}
+
+// Called when a connection with `remote_addr` has been established as `endpoint`. If a
+// connection with the same peer has already been established in the other direction, closes
+// the one that loses the tie-breaking of the `simultaneous` module. Returns false if the new
+// connection is the one to close, in which case the caller must drop it.
+//
+// On top of a `ConnectionReuse`, each substream is a connection of its own for the swarm, so all
+// the connections with the peer in the losing direction are closed, and not just the first one.
+fn break_simultaneous_tie<F>(
+    to_process: &mut Vec<(F, Option<ConnectionGuard>, Option<(Multiaddr, Endpoint)>)>,
+    local_peer_id: Option<&PeerId>,
+    remote_addr: &Multiaddr,
+    endpoint: Endpoint,
+    subscribers: &Mutex<Vec<mpsc::UnboundedSender<SwarmEvent>>>,
+) -> bool {
+    let remote = match peer_id_of(remote_addr) {
+        Some(remote) => remote,
+        None => return true,
+    };
+    let kept = match local_peer_id.and_then(|local| kept_endpoint(local, &remote)) {
+        Some(kept) => kept,
+        None => return true,
+    };
+
+    let existing = (0..to_process.len())
+        .filter(|&n| match to_process[n].2 {
+            Some((ref addr, other_endpoint)) => {
+                other_endpoint != endpoint && peer_id_of(addr).as_ref() == Some(&remote)
+            }
+            None => false,
+        })
+        .collect::<Vec<_>>();
+    if existing.is_empty() {
+        return true;
+    }
+
+    if kept != endpoint {
+        return false;
+    }
+
+    // Going backwards, so that `swap_remove` doesn't move the elements we have yet to remove.
+    for n in existing.into_iter().rev() {
+        let (_, _, closed) = to_process.swap_remove(n);
+        let (closed_addr, closed_endpoint) = closed.expect("checked above");
+        debug!("Closing duplicate connection with {}", closed_addr);
+        broadcast(subscribers, SwarmEvent::ConnectionClosed {
+            remote_addr: closed_addr,
+            endpoint: closed_endpoint,
+            cause: Some(Arc::new(duplicate_error())),
+        });
+    }
+    true
+}
+
+// Error produced for the connections that are closed because another connection with the same
+// peer has been opened simultaneously.
+#[inline]
+fn duplicate_error() -> IoError {
+    IoError::new(IoErrorKind::Other, "simultaneous connection with the same peer")
+}
+
+// Sends `event` to all the subscriptions created with `SwarmController::events`. The
+// subscriptions that have been destroyed are removed.
+fn broadcast(subscribers: &Mutex<Vec<mpsc::UnboundedSender<SwarmEvent>>>, event: SwarmEvent) {
+    let mut subscribers = subscribers.lock().unwrap();
+    subscribers.retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate libp2p_tcp_transport;
+
+    use super::{break_simultaneous_tie, swarm};
+    use futures::{future, Future, Stream};
+    use futures::sync::mpsc;
+    use limits::DialError;
+    use multiaddr::AddrComponent;
+    use self::libp2p_tcp_transport::TcpConfig;
+    use std::cell::Cell;
+    use std::io::Error as IoError;
+    use std::rc::Rc;
+    use std::sync::Mutex;
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::Core;
+    use tokio_io::io::read_to_end;
+    use {Endpoint, Multiaddr, PeerId, PlainTextConfig, SwarmEvent, Transport};
+
+    // Transport that reports all its connections as being with `peer`, like an
+    // `IdentifyTransport` does.
+    #[derive(Clone)]
+    struct Identified {
+        inner: TcpConfig,
+        peer: PeerId,
+    }
+
+    impl Transport for Identified {
+        type RawConn = TcpStream;
+        type Listener = Box<Stream<Item = Self::ListenerUpgrade, Error = IoError>>;
+        type ListenerUpgrade = Box<Future<Item = (TcpStream, Multiaddr), Error = IoError>>;
+        type Dial = Box<Future<Item = (TcpStream, Multiaddr), Error = IoError>>;
+
+        fn listen_on(
+            self,
+            addr: Multiaddr,
+        ) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+            let peer = self.peer.clone();
+            match self.inner.clone().listen_on(addr) {
+                Ok((listener, addr)) => {
+                    let listener = listener.map(move |upgrade| {
+                        let peer = peer.clone();
+                        let upgrade =
+                            upgrade.map(move |(conn, addr)| (conn, with_peer(addr, &peer)));
+                        Box::new(upgrade) as Box<Future<Item = _, Error = _>>
+                    });
+                    Ok((Box::new(listener) as Box<_>, addr))
+                }
+                Err((_, addr)) => Err((self, addr)),
+            }
+        }
+
+        fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+            let peer = self.peer.clone();
+            match self.inner.clone().dial(addr) {
+                Ok(dial) => {
+                    let dial = dial.map(move |(conn, addr)| (conn, with_peer(addr, &peer)));
+                    Ok(Box::new(dial) as Box<_>)
+                }
+                Err((_, addr)) => Err((self, addr)),
+            }
+        }
+
+        fn nat_traversal(&self, _: &Multiaddr, _: &Multiaddr) -> Option<Multiaddr> {
+            None
+        }
+    }
+
+    fn with_peer(mut addr: Multiaddr, peer: &PeerId) -> Multiaddr {
+        addr.append(AddrComponent::P2P(peer.clone().into_bytes()));
+        addr
+    }
+
+    // Runs `core` until `events` produces an event for which `filter` returns true.
+    fn wait_for<F>(core: &mut Core, events: &mut mpsc::UnboundedReceiver<SwarmEvent>, filter: F)
+    where
+        F: FnMut(&SwarmEvent) -> bool,
+    {
+        let next = events.by_ref().filter(filter).into_future().map_err(|_| ());
+        let (event, _) = core.run(next).unwrap();
+        assert!(event.is_some());
+    }
+
+    #[test]
+    fn refuses_incoming_from_banned_peer() {
+        let mut core = Core::new().unwrap();
+        let banned = PeerId::from_public_key(&[1, 2, 3]);
+        let transport = Identified {
+            inner: TcpConfig::new(core.handle()),
+            peer: banned.clone(),
+        };
+        let handled = Rc::new(Cell::new(0));
+        let handled2 = handled.clone();
+        let handler = move |_: TcpStream, _: Multiaddr| {
+            handled2.set(handled2.get() + 1);
+            Ok::<_, IoError>(())
+        };
+        let (controller, future) = swarm(transport.with_dummy_muxing(), PlainTextConfig, handler);
+        controller.ban_peer(banned, None);
+        let addr = controller.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        core.handle().spawn(future.map_err(|_| ()));
+
+        // The connection is closed without being given to the handler.
+        let dial = TcpConfig::new(core.handle())
+            .with_upgrade(PlainTextConfig)
+            .dial(addr)
+            .unwrap_or_else(|_| panic!())
+            .and_then(|(socket, _)| read_to_end(socket, Vec::new()));
+        core.run(dial).unwrap();
+        assert_eq!(handled.get(), 0);
+    }
+
+    #[test]
+    fn refuses_outgoing_to_banned_peer() {
+        let mut core = Core::new().unwrap();
+        let banned = PeerId::from_public_key(&[1, 2, 3]);
+        let transport = Identified {
+            inner: TcpConfig::new(core.handle()),
+            peer: banned.clone(),
+        };
+        let handled = Rc::new(Cell::new(0));
+        let handled2 = handled.clone();
+        let handler = move |_: TcpStream, _: Multiaddr| {
+            handled2.set(handled2.get() + 1);
+            Ok::<_, IoError>(())
+        };
+        let (controller, future) = swarm(transport.with_dummy_muxing(), PlainTextConfig, handler);
+        let mut events = controller.events();
+        controller.ban_peer(banned.clone(), None);
+        core.handle().spawn(future.map_err(|_| ()));
+
+        let (listener, addr) = TcpConfig::new(core.handle())
+            .with_upgrade(PlainTextConfig)
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap_or_else(|_| panic!());
+        core.handle().spawn(listener.for_each(|upgrade| upgrade.map(|_| ())).map_err(|_| ()));
+
+        // Dialing an address that contains the peer ID is refused immediately.
+        match controller.dial_to_handler(with_peer(addr.clone(), &banned), PlainTextConfig) {
+            Err(DialError::Banned(ref peer)) if *peer == banned => (),
+            _ => panic!(),
+        }
+
+        // Otherwise the connection is closed once the transport reports the peer ID.
+        controller.dial_to_handler(addr, PlainTextConfig).unwrap();
+        wait_for(&mut core, &mut events, |event| match *event {
+            SwarmEvent::DialFailure { .. } => true,
+            _ => false,
+        });
+        assert_eq!(handled.get(), 0);
+    }
+
+    #[test]
+    fn ban_closes_handler() {
+        let mut core = Core::new().unwrap();
+        let peer = PeerId::from_public_key(&[1, 2, 3]);
+        let transport = Identified {
+            inner: TcpConfig::new(core.handle()),
+            peer: peer.clone(),
+        };
+        let (controller, future) = swarm(transport.with_dummy_muxing(), PlainTextConfig, |_, _| {
+            future::empty::<(), IoError>()
+        });
+        let mut events = controller.events();
+        let addr = controller.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        core.handle().spawn(future.map_err(|_| ()));
+
+        let dial = TcpConfig::new(core.handle())
+            .with_upgrade(PlainTextConfig)
+            .dial(addr)
+            .unwrap_or_else(|_| panic!());
+        let (socket, _) = core.run(dial).unwrap();
+        wait_for(&mut core, &mut events, |event| match *event {
+            SwarmEvent::ConnectionEstablished { .. } => true,
+            _ => false,
+        });
+
+        controller.ban_peer(peer, None);
+        wait_for(&mut core, &mut events, |event| match *event {
+            SwarmEvent::ConnectionClosed { ref cause, .. } => cause.is_some(),
+            _ => false,
+        });
+        // Dropping the future of the handler has closed the socket.
+        core.run(read_to_end(socket, Vec::new())).unwrap();
+    }
+
+    fn addr_of(peer: &PeerId) -> Multiaddr {
+        let mut addr: Multiaddr = "/ip4/127.0.0.1/tcp/1234".parse().unwrap();
+        addr.append(AddrComponent::P2P(peer.clone().into_bytes()));
+        addr
+    }
+
+    // Returns `(greatest, lowest)` peer IDs.
+    fn peers() -> (PeerId, PeerId) {
+        let a = PeerId::from_public_key(&[1, 2, 3]);
+        let b = PeerId::from_public_key(&[4, 5, 6]);
+        if a.as_bytes() > b.as_bytes() {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+
+    #[test]
+    fn closes_existing_connection() {
+        let (local, remote) = peers();
+        let (tx, rx) = mpsc::unbounded();
+        let subscribers = Mutex::new(vec![tx]);
+        let mut to_process = vec![((), None, Some((addr_of(&remote), Endpoint::Listener)))];
+
+        // The local node has the greatest peer ID, so the connection it dialed is kept.
+        let kept = break_simultaneous_tie(
+            &mut to_process,
+            Some(&local),
+            &addr_of(&remote),
+            Endpoint::Dialer,
+            &subscribers,
+        );
+        assert!(kept);
+        assert!(to_process.is_empty());
+
+        drop(subscribers);
+        match rx.wait().next() {
+            Some(Ok(SwarmEvent::ConnectionClosed { endpoint, cause, .. })) => {
+                assert_eq!(endpoint, Endpoint::Listener);
+                assert!(cause.is_some());
+            }
+            _ => panic!("expected a ConnectionClosed event"),
+        }
+    }
+
+    #[test]
+    fn closes_all_existing_substreams() {
+        let (local, remote) = peers();
+        let other = PeerId::from_public_key(&[7, 8, 9]);
+        let subscribers = Mutex::new(Vec::new());
+        let mut to_process = vec![
+            ((), None, Some((addr_of(&remote), Endpoint::Listener))),
+            ((), None, Some((addr_of(&other), Endpoint::Listener))),
+            ((), None, Some((addr_of(&remote), Endpoint::Dialer))),
+            ((), None, Some((addr_of(&remote), Endpoint::Listener))),
+        ];
+
+        let kept = break_simultaneous_tie(
+            &mut to_process,
+            Some(&local),
+            &addr_of(&remote),
+            Endpoint::Dialer,
+            &subscribers,
+        );
+        assert!(kept);
+        let mut remaining = to_process
+            .into_iter()
+            .map(|(_, _, remote)| remote.unwrap())
+            .collect::<Vec<_>>();
+        remaining.sort_by_key(|&(_, endpoint)| endpoint == Endpoint::Listener);
+        assert_eq!(
+            remaining,
+            vec![
+                (addr_of(&remote), Endpoint::Dialer),
+                (addr_of(&other), Endpoint::Listener),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_new_connection() {
+        let (remote, local) = peers();
+        let subscribers = Mutex::new(Vec::new());
+        let mut to_process = vec![((), None, Some((addr_of(&remote), Endpoint::Listener)))];
+
+        // The local node has the lowest peer ID, so the connection it accepted is kept.
+        let kept = break_simultaneous_tie(
+            &mut to_process,
+            Some(&local),
+            &addr_of(&remote),
+            Endpoint::Dialer,
+            &subscribers,
+        );
+        assert!(!kept);
+        assert_eq!(to_process.len(), 1);
+    }
+
+    #[test]
+    fn keeps_both_without_local_identity() {
+        let (_, remote) = peers();
+        let subscribers = Mutex::new(Vec::new());
+        let mut to_process = vec![((), None, Some((addr_of(&remote), Endpoint::Listener)))];
+
+        let kept = break_simultaneous_tie(
+            &mut to_process,
+            None,
+            &addr_of(&remote),
+            Endpoint::Dialer,
+            &subscribers,
+        );
+        assert!(kept);
+        assert_eq!(to_process.len(), 1);
+    }
+}
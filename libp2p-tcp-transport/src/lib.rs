@@ -46,24 +46,41 @@
 //! # }
 //! ```
 //!
-//! The `TcpConfig` structs implements the `Transport` trait of the `swarm` library. See the
-//! documentation of `swarm` and of libp2p in general to learn how to use the `Transport` trait.
+//! The `TcpConfig` structs implements the `Transport` trait of the `libp2p-core` library. See
+//! the documentation of `libp2p-core` and of libp2p in general to learn how to use the
+//! `Transport` trait.
+//!
+//! On a host with multiple network interfaces, `with_source_ip` makes the outgoing connections
+//! use a specific local IP address, and therefore leave through the corresponding interface.
+//! Since a `TcpConfig` is cheap to clone, this can be done for a single dialing attempt by
+//! calling `with_source_ip` on a clone.
+//!
+//! Similarly, `with_marking` sets the type of service and the priority of the sockets, so that
+//! the network can prioritize some classes of connections over others. See `SocketMarking`.
 
 extern crate futures;
-extern crate libp2p_swarm as swarm;
+#[cfg(unix)]
+extern crate libc;
+extern crate libp2p_core;
 extern crate multiaddr;
+extern crate net2;
 extern crate tokio_core;
 extern crate tokio_io;
 
+mod marking;
+
+pub use marking::SocketMarking;
+
 use std::io::Error as IoError;
 use std::iter;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream as StdTcpStream};
 use tokio_core::reactor::Handle;
 use tokio_core::net::{TcpListener, TcpStream};
 use futures::future::{self, Future, FutureResult, IntoFuture};
 use futures::stream::Stream;
 use multiaddr::{AddrComponent, Multiaddr, ToMultiaddr};
-use swarm::Transport;
+use libp2p_core::Transport;
+use net2::TcpBuilder;
 
 /// Represents the configuration for a TCP/IP transport capability for libp2p.
 ///
@@ -73,6 +90,10 @@ use swarm::Transport;
 #[derive(Debug, Clone)]
 pub struct TcpConfig {
     event_loop: Handle,
+    // Local addresses to bind the outgoing sockets to, if any.
+    source_ipv4: Option<Ipv4Addr>,
+    source_ipv6: Option<Ipv6Addr>,
+    marking: SocketMarking,
 }
 
 impl TcpConfig {
@@ -80,7 +101,35 @@ impl TcpConfig {
     /// connections will be created with.
     #[inline]
     pub fn new(handle: Handle) -> TcpConfig {
-        TcpConfig { event_loop: handle }
+        TcpConfig {
+            event_loop: handle,
+            source_ipv4: None,
+            source_ipv6: None,
+            marking: SocketMarking::new(),
+        }
+    }
+
+    /// Binds the sockets of the outgoing connections to `ip` before connecting, instead of
+    /// letting the operating system choose the source address.
+    ///
+    /// An IPv4 address only applies to the IPv4 destinations, and an IPv6 address to the IPv6
+    /// ones. Call this method twice in order to set both. The source port is always chosen by the
+    /// operating system. Dialing fails if `ip` isn't an address of the local machine.
+    #[inline]
+    pub fn with_source_ip(mut self, ip: IpAddr) -> TcpConfig {
+        match ip {
+            IpAddr::V4(ip) => self.source_ipv4 = Some(ip),
+            IpAddr::V6(ip) => self.source_ipv6 = Some(ip),
+        }
+        self
+    }
+
+    /// Applies `marking` to the sockets of the outgoing connections and of the connections
+    /// accepted by the listeners. Opening a connection fails if the marking can't be applied.
+    #[inline]
+    pub fn with_marking(mut self, marking: SocketMarking) -> TcpConfig {
+        self.marking = marking;
+        self
     }
 }
 
@@ -109,13 +158,15 @@ impl Transport for TcpConfig {
                 Err(_) => addr,
             };
 
+            let marking = self.marking;
             let future = future::result(listener)
-                .map(|listener| {
+                .map(move |listener| {
                     // Pull out a stream of sockets for incoming connections
-                    listener.incoming().map(|(sock, addr)| {
+                    listener.incoming().map(move |(sock, addr)| {
+                        let marked = marking.apply(&sock, addr.is_ipv6());
                         let addr = addr.to_multiaddr()
                             .expect("generating a multiaddr from a socket addr never fails");
-                        Ok((sock, addr)).into_future()
+                        marked.map(|()| (sock, addr)).into_future()
                     })
                 })
                 .flatten_stream();
@@ -130,8 +181,24 @@ impl Transport for TcpConfig {
     /// or gives back the multiaddress.
     fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
         if let Ok(socket_addr) = multiaddr_to_socketaddr(&addr) {
-            let fut = TcpStream::connect(&socket_addr, &self.event_loop).map(|t| (t, addr));
-            Ok(Box::new(fut) as Box<_>)
+            let source = match socket_addr {
+                SocketAddr::V4(_) => self.source_ipv4.map(IpAddr::V4),
+                SocketAddr::V6(_) => self.source_ipv6.map(IpAddr::V6),
+            };
+
+            let fut: Box<Future<Item = TcpStream, Error = IoError>> =
+                if source.is_none() && self.marking.is_empty() {
+                    Box::new(TcpStream::connect(&socket_addr, &self.event_loop))
+                } else {
+                    match outgoing_socket(&socket_addr, source, &self.marking) {
+                        Ok(socket) => {
+                            TcpStream::connect_stream(socket, &socket_addr, &self.event_loop)
+                        }
+                        Err(err) => Box::new(future::err(err)),
+                    }
+                };
+
+            Ok(Box::new(fut.map(|t| (t, addr))) as Box<_>)
         } else {
             Err((self, addr))
         }
@@ -167,6 +234,27 @@ impl Transport for TcpConfig {
     }
 }
 
+// Builds a socket that is ready to be connected to `dest`. The socket is bound to `source`, or
+// to the unspecified address if it is `None`, and to a port chosen by the operating system.
+fn outgoing_socket(
+    dest: &SocketAddr,
+    source: Option<IpAddr>,
+    marking: &SocketMarking,
+) -> Result<StdTcpStream, IoError> {
+    let (builder, unspecified) = match *dest {
+        SocketAddr::V4(_) => (TcpBuilder::new_v4()?, IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))),
+        SocketAddr::V6(_) => {
+            let builder = TcpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            (builder, IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)))
+        }
+    };
+    builder.bind(&SocketAddr::new(source.unwrap_or(unspecified), 0))?;
+    let socket = builder.to_tcp_stream()?;
+    marking.apply(&socket, dest.is_ipv6())?;
+    Ok(socket)
+}
+
 // This type of logic should probably be moved into the multiaddr package
 fn multiaddr_to_socketaddr(addr: &Multiaddr) -> Result<SocketAddr, ()> {
     let protocols: Vec<_> = addr.iter().collect();
@@ -188,15 +276,15 @@ fn multiaddr_to_socketaddr(addr: &Multiaddr) -> Result<SocketAddr, ()> {
 
 #[cfg(test)]
 mod tests {
-    use super::{multiaddr_to_socketaddr, TcpConfig};
+    use super::{multiaddr_to_socketaddr, SocketMarking, TcpConfig};
     use std;
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
     use tokio_core::reactor::Core;
     use tokio_io;
     use futures::Future;
     use futures::stream::Stream;
-    use multiaddr::Multiaddr;
-    use swarm::Transport;
+    use multiaddr::{Multiaddr, ToMultiaddr};
+    use libp2p_core::Transport;
 
     #[test]
     fn multiaddr_to_tcp_conversion() {
@@ -295,6 +383,72 @@ mod tests {
         std::thread::sleep(std::time::Duration::from_millis(100));
     }
 
+    #[test]
+    fn dial_from_source_ip() {
+        let mut core = Core::new().unwrap();
+        let tcp = TcpConfig::new(core.handle()).with_source_ip("127.0.0.1".parse().unwrap());
+
+        let (listener, addr) = tcp.clone()
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let incoming = listener
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(|(sock, _)| sock.unwrap());
+        let dial = tcp.dial(addr).unwrap();
+
+        let ((_, remote_addr), (sock, _)) = core.run(incoming.join(dial)).unwrap();
+        let local_addr = sock.local_addr().unwrap();
+        assert_eq!(local_addr.ip(), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        assert_eq!(remote_addr, local_addr.to_multiaddr().unwrap());
+    }
+
+    #[test]
+    fn dial_from_foreign_source_ip_fails() {
+        let mut core = Core::new().unwrap();
+        // 192.0.2.0/24 is reserved for documentation and can't be a local address.
+        let tcp = TcpConfig::new(core.handle()).with_source_ip("192.0.2.1".parse().unwrap());
+        let dial = tcp.dial("/ip4/127.0.0.1/tcp/12346".parse().unwrap()).unwrap();
+        assert!(core.run(dial).is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn dial_with_marking() {
+        use libc;
+        use std::mem;
+        use std::os::unix::io::AsRawFd;
+
+        let mut core = Core::new().unwrap();
+        let marking = SocketMarking::new().with_dscp(46).with_priority(6);
+        let tcp = TcpConfig::new(core.handle()).with_marking(marking);
+
+        let (listener, addr) = tcp.clone()
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let incoming = listener
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(|(sock, _)| sock.unwrap());
+        let dial = tcp.dial(addr).unwrap();
+        let ((incoming, _), (outgoing, _)) = core.run(incoming.join(dial)).unwrap();
+
+        let get = |fd, level, name| unsafe {
+            let mut value: libc::c_int = 0;
+            let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let ret = libc::getsockopt(fd, level, name,
+                                       &mut value as *mut libc::c_int as *mut libc::c_void,
+                                       &mut len);
+            assert_eq!(ret, 0);
+            value
+        };
+        for socket in &[incoming, outgoing] {
+            let fd = socket.as_raw_fd();
+            assert_eq!(get(fd, libc::IPPROTO_IP, libc::IP_TOS), 46 << 2);
+            assert_eq!(get(fd, libc::SOL_SOCKET, libc::SO_PRIORITY), 6);
+        }
+    }
+
     #[test]
     fn replace_port_0_in_returned_multiaddr_ipv4() {
         let core = Core::new().unwrap();
@@ -0,0 +1,178 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Marking of the packets of TCP connections, so that the network can prioritize them.
+//!
+//! The *type of service* byte of the IP header, whose upper six bits are the DSCP value, is read
+//! by routers that implement differentiated services. On Linux, the priority of a socket also
+//! decides in which queue of the local traffic control its packets are put.
+//!
+//! Setting the type of service of IPv6 sockets and the priority of sockets is only supported on
+//! Linux. On the other platforms, opening a connection with these options fails.
+
+use std::io::Error as IoError;
+#[cfg(not(target_os = "linux"))]
+use std::io::ErrorKind as IoErrorKind;
+#[cfg(unix)]
+use libc;
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// Options to apply to the sockets of a `TcpConfig`. The default doesn't change anything.
+///
+/// Each class of connections, such as the consensus traffic and the bulk synchronization of a
+/// blockchain node, should use its own clone of the `TcpConfig` with different markings.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct SocketMarking {
+    tos: Option<u8>,
+    priority: Option<u32>,
+}
+
+impl SocketMarking {
+    /// Creates a marking that doesn't change anything.
+    #[inline]
+    pub fn new() -> SocketMarking {
+        SocketMarking::default()
+    }
+
+    /// Sets the type of service byte of the packets (`IP_TOS`, or `IPV6_TCLASS` for IPv6).
+    #[inline]
+    pub fn with_tos(mut self, tos: u8) -> SocketMarking {
+        self.tos = Some(tos);
+        self
+    }
+
+    /// Sets the DSCP value of the packets, which is the type of service shifted by two bits.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `dscp` doesn't fit in six bits.
+    #[inline]
+    pub fn with_dscp(self, dscp: u8) -> SocketMarking {
+        assert!(dscp < 64, "a DSCP value must fit in six bits");
+        self.with_tos(dscp << 2)
+    }
+
+    /// Sets the Linux priority of the sockets (`SO_PRIORITY`).
+    #[inline]
+    pub fn with_priority(mut self, priority: u32) -> SocketMarking {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Returns the type of service byte, if set.
+    #[inline]
+    pub fn tos(&self) -> Option<u8> {
+        self.tos
+    }
+
+    /// Returns the priority, if set.
+    #[inline]
+    pub fn priority(&self) -> Option<u32> {
+        self.priority
+    }
+
+    /// Returns true if the marking doesn't change anything.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.tos.is_none() && self.priority.is_none()
+    }
+
+    /// Applies the marking to `socket`. `ipv6` indicates whether `socket` is an IPv6 socket.
+    #[cfg(unix)]
+    pub fn apply<S>(&self, socket: &S, ipv6: bool) -> Result<(), IoError>
+    where
+        S: AsRawFd,
+    {
+        let fd = socket.as_raw_fd();
+        if let Some(tos) = self.tos {
+            if ipv6 {
+                set_tclass(fd, tos)?;
+            } else {
+                set_int_option(fd, libc::IPPROTO_IP, libc::IP_TOS, tos as libc::c_int)?;
+            }
+        }
+        if let Some(priority) = self.priority {
+            set_priority(fd, priority)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the marking to `socket`. `ipv6` indicates whether `socket` is an IPv6 socket.
+    #[cfg(not(unix))]
+    pub fn apply<S>(&self, _: &S, _: bool) -> Result<(), IoError> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(unsupported("marking sockets"))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_tclass(fd: RawFd, tos: u8) -> Result<(), IoError> {
+    set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos as libc::c_int)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_tclass(_: RawFd, _: u8) -> Result<(), IoError> {
+    Err(unsupported("setting the traffic class of IPv6 sockets"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_priority(fd: RawFd, priority: u32) -> Result<(), IoError> {
+    set_int_option(fd, libc::SOL_SOCKET, libc::SO_PRIORITY, priority as libc::c_int)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_priority(_: RawFd, _: u32) -> Result<(), IoError> {
+    Err(unsupported("setting the priority of sockets"))
+}
+
+#[cfg(unix)]
+fn set_int_option(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> Result<(), IoError> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(IoError::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported(what: &str) -> IoError {
+    IoError::new(IoErrorKind::Other, format!("{} is not supported on this platform", what))
+}
@@ -29,7 +29,7 @@ use std::iter;
 use std::sync::{Arc, Mutex};
 use stdweb::{self, Reference};
 use stdweb::web::TypedArray;
-use swarm::Transport;
+use libp2p_core::Transport;
 use tokio_io::{AsyncRead, AsyncWrite};
 
 /// Represents the configuration for a websocket transport capability for libp2p.
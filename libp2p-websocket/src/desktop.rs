@@ -22,7 +22,7 @@ use futures::{stream, Future, IntoFuture, Sink, Stream};
 use multiaddr::{AddrComponent, Multiaddr};
 use rw_stream_sink::RwStreamSink;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
-use swarm::Transport;
+use libp2p_core::{LocalIdentity, Transport};
 use websocket::client::builder::ClientBuilder;
 use websocket::message::OwnedMessage;
 use websocket::server::upgrade::async::IntoWs;
@@ -217,6 +217,11 @@ where
                 result
             })
     }
+
+    #[inline]
+    fn local_identity(&self) -> LocalIdentity {
+        self.transport.local_identity()
+    }
 }
 
 #[cfg(test)]
@@ -227,7 +232,7 @@ mod tests {
     use WsConfig;
     use futures::{Future, Stream};
     use multiaddr::Multiaddr;
-    use swarm::Transport;
+    use libp2p_core::Transport;
 
     #[test]
     fn dialer_connects_to_listener_ipv4() {
@@ -25,8 +25,8 @@
 
 //! Implementation of the libp2p `Transport` trait for Websockets.
 //!
-//! See the documentation of `swarm` and of libp2p in general to learn how to use the `Transport`
-//! trait.
+//! See the documentation of `libp2p-core` and of libp2p in general to learn how to use the
+//! `Transport` trait.
 //!
 //! This library is used in a different way depending on whether you are compiling for emscripten
 //! or for a different operating system.
@@ -55,12 +55,12 @@
 //! `WsConfig::new()` function.
 //!
 //! ```
-//! extern crate libp2p_swarm;
+//! extern crate libp2p_core;
 //! extern crate libp2p_tcp_transport;
 //! extern crate libp2p_websocket;
 //! extern crate tokio_core;
 //!
-//! use libp2p_swarm::{Multiaddr, Transport};
+//! use libp2p_core::{Multiaddr, Transport};
 //! use libp2p_tcp_transport::TcpConfig;
 //! use libp2p_websocket::WsConfig;
 //! use tokio_core::reactor::Core;
@@ -75,7 +75,7 @@
 //!
 
 extern crate futures;
-extern crate libp2p_swarm as swarm;
+extern crate libp2p_core;
 extern crate multiaddr;
 extern crate rw_stream_sink;
 extern crate tokio_io;
@@ -18,7 +18,7 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use swarm::Endpoint;
+use libp2p_core::Endpoint;
 
 const FLAG_BITS: usize = 3;
 const FLAG_MASK: usize = (1usize << FLAG_BITS) - 1;
@@ -24,7 +24,7 @@ extern crate bytes;
 extern crate error_chain;
 extern crate futures;
 extern crate futures_mutex;
-extern crate libp2p_swarm as swarm;
+extern crate libp2p_core;
 extern crate num_bigint;
 extern crate num_traits;
 extern crate parking_lot;
@@ -41,8 +41,8 @@ use bytes::Bytes;
 use futures::{Async, Future, Poll};
 use futures::future::{self, FutureResult};
 use header::MultiplexHeader;
-use swarm::muxing::StreamMuxer;
-use swarm::{ConnectionUpgrade, Endpoint, Multiaddr};
+use libp2p_core::muxing::StreamMuxer;
+use libp2p_core::{ConnectionUpgrade, Endpoint, LocalIdentity, Multiaddr};
 use futures_mutex::Mutex;
 use read::{read_stream, MultiplexReadState};
 use shared::{buf_from_slice, ByteBuf, MultiplexShared};
@@ -348,7 +348,7 @@ where
     type NamesIter = iter::Once<(Bytes, ())>;
 
     #[inline]
-    fn upgrade(self, i: C, _: (), end: Endpoint, _: &Multiaddr) -> Self::Future {
+    fn upgrade(self, i: C, _: (), end: Endpoint, _: &Multiaddr, _: &LocalIdentity) -> Self::Future {
         future::ok(Multiplex::new(i, end))
     }
 
@@ -20,7 +20,7 @@
 
 extern crate bytes;
 extern crate futures;
-extern crate libp2p_swarm as swarm;
+extern crate libp2p_core;
 extern crate libp2p_tcp_transport as tcp;
 extern crate multiplex;
 extern crate tokio_core;
@@ -30,7 +30,7 @@ use futures::future::Future;
 use futures::{Sink, Stream};
 use std::sync::mpsc;
 use std::thread;
-use swarm::{StreamMuxer, Transport};
+use libp2p_core::{StreamMuxer, Transport};
 use tcp::TcpConfig;
 use tokio_core::reactor::Core;
 use tokio_io::codec::length_delimited::Framed;
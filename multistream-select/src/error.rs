@@ -36,6 +36,9 @@ pub enum ProtocolChoiceError {
 
     /// We don't support any protocol in common with the remote.
     NoProtocolFound,
+
+    /// Both sides of a simultaneous open passed the same tie-breaker to `select_role`.
+    RoleConflict,
 }
 
 impl From<MultistreamSelectError> for ProtocolChoiceError {
@@ -63,6 +66,9 @@ impl error::Error for ProtocolChoiceError {
             ProtocolChoiceError::NoProtocolFound => {
                 "we don't support any protocol in common with the remote"
             }
+            ProtocolChoiceError::RoleConflict => {
+                "both sides of a simultaneous open used the same tie-breaker"
+            }
         }
     }
 
@@ -0,0 +1,345 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `dialer_select_proto_lazy` code, which allows the dialer to use a protocol
+//! without waiting for the listener to accept it.
+//!
+//! This is the "V1-lazy" variant of `multistream-select`. When the dialer proposes a single
+//! protocol, there is nothing to choose and the round-trip of the negotiation can be skipped:
+//! the handshake and the proposal are sent together with the first bytes of the payload, and the
+//! answer of the listener is only checked when the dialer reads from the socket. The listener
+//! side is unchanged, and `listener_select_proto` can be used as usual.
+//!
+//! If the listener refuses the protocol, it answers "not available" and then interprets the
+//! payload as a message, which most likely fails. On the dialer's side, reading from the socket
+//! produces an error that wraps `ProtocolChoiceError::NoProtocolFound`. Protocols for which
+//! sending the payload to a node that doesn't support them is a problem shouldn't use this.
+//!
+//! > **Note**: The upgrades of `libp2p-core` still negotiate with `dialer_select_proto`, as they
+//! >           are given the socket itself rather than a `LazyNegotiated`. This function is meant
+//! >           for code that negotiates its substreams by hand.
+
+use ProtocolChoiceError;
+use futures::{Async, Poll};
+use protocol::MULTISTREAM_PROTOCOL_WITH_LF;
+use protocol::MultistreamSelectError;
+use std::cmp;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+use varint;
+
+// Maximum number of bytes of the payload that are queued along with the proposal. The rest of
+// the payload is written once the proposal has been sent.
+const MAX_QUEUED_PAYLOAD: usize = 8 * 1024;
+
+/// Starts negotiating `protocol` on `inner`, and immediately returns a socket that uses it.
+///
+/// Nothing is sent before the first time the socket is written to, read from or flushed. As
+/// with any buffered writer, call `flush` to make sure that the data has been sent.
+pub fn dialer_select_proto_lazy<R>(
+    inner: R,
+    protocol: &[u8],
+) -> Result<LazyNegotiated<R>, ProtocolChoiceError> {
+    if !protocol.starts_with(b"/") {
+        return Err(MultistreamSelectError::WrongProtocolName.into());
+    }
+
+    let mut protocol = protocol.to_owned();
+    protocol.push(b'\n');
+
+    let mut messages = Vec::new();
+    push_frame(&mut messages, MULTISTREAM_PROTOCOL_WITH_LF);
+    let handshake_len = messages.len();
+    push_frame(&mut messages, &protocol);
+
+    // The listener answers with the same handshake, then acknowledges with the same name.
+    Ok(LazyNegotiated {
+        inner: inner,
+        pending: messages.clone(),
+        pending_pos: 0,
+        payload_queued: false,
+        expected: messages,
+        expected_handshake_len: handshake_len,
+        received: 0,
+    })
+}
+
+// Appends `data` to `out`, prefixed with its length.
+fn push_frame(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&varint::encode(data.len()));
+    out.extend_from_slice(data);
+}
+
+/// Socket produced by `dialer_select_proto_lazy`.
+pub struct LazyNegotiated<R> {
+    inner: R,
+    // Data that must be written before anything else. Contains the handshake and the proposal,
+    // followed by the beginning of the payload. Emptied once it has been entirely written.
+    pending: Vec<u8>,
+    // Number of bytes of `pending` already written.
+    pending_pos: usize,
+    // True if the beginning of the payload has been appended to `pending`.
+    payload_queued: bool,
+    // Data that the listener sends if it accepts the protocol.
+    expected: Vec<u8>,
+    // Number of bytes of `expected` that belong to the handshake.
+    expected_handshake_len: usize,
+    // Number of bytes of `expected` that have been received.
+    received: usize,
+}
+
+impl<R> LazyNegotiated<R> {
+    /// Returns true if the listener has accepted the protocol.
+    #[inline]
+    pub fn is_confirmed(&self) -> bool {
+        self.received == self.expected.len()
+    }
+}
+
+impl<R> LazyNegotiated<R>
+where
+    R: Write,
+{
+    // Writes the content of `pending`. Returns an error of kind `WouldBlock` if it can't be
+    // entirely written now.
+    fn write_pending(&mut self) -> Result<(), IoError> {
+        while self.pending_pos < self.pending.len() {
+            match self.inner.write(&self.pending[self.pending_pos..])? {
+                0 => return Err(IoErrorKind::WriteZero.into()),
+                num_written => self.pending_pos += num_written,
+            }
+        }
+
+        if !self.pending.is_empty() {
+            self.pending = Vec::new();
+            self.pending_pos = 0;
+        }
+        Ok(())
+    }
+}
+
+impl<R> Read for LazyNegotiated<R>
+where
+    R: Read + Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        // The listener doesn't answer before it has received the proposal.
+        self.write_pending()?;
+
+        while self.received < self.expected.len() {
+            // We never read more than the answer, so that no data of the payload is lost.
+            let mut answer = [0; 32];
+            let max = cmp::min(answer.len(), self.expected.len() - self.received);
+            let num_read = self.inner.read(&mut answer[..max])?;
+            if num_read == 0 {
+                return Err(IoError::new(
+                    IoErrorKind::UnexpectedEof,
+                    "substream closed during the negotiation",
+                ));
+            }
+
+            let expected = &self.expected[self.received..self.received + num_read];
+            if let Some(offset) = answer.iter().zip(expected).position(|(a, b)| a != b) {
+                let err = if self.received + offset < self.expected_handshake_len {
+                    ProtocolChoiceError::from(MultistreamSelectError::FailedHandshake)
+                } else {
+                    ProtocolChoiceError::NoProtocolFound
+                };
+                return Err(IoError::new(IoErrorKind::InvalidData, err));
+            }
+
+            self.received += num_read;
+        }
+
+        self.inner.read(buf)
+    }
+}
+
+impl<R> AsyncRead for LazyNegotiated<R>
+where
+    R: AsyncRead + AsyncWrite,
+{
+    #[inline]
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<R> Write for LazyNegotiated<R>
+where
+    R: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        if !self.pending.is_empty() {
+            if !self.payload_queued {
+                // Sending the beginning of the payload in the same packet as the proposal is the
+                // whole point of the lazy negotiation.
+                let queued = cmp::min(buf.len(), MAX_QUEUED_PAYLOAD);
+                self.pending.extend_from_slice(&buf[..queued]);
+                self.payload_queued = true;
+                match self.write_pending() {
+                    Ok(()) => (),
+                    Err(ref err) if err.kind() == IoErrorKind::WouldBlock => (),
+                    Err(err) => return Err(err),
+                }
+                return Ok(queued);
+            }
+
+            self.write_pending()?;
+        }
+
+        self.inner.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+impl<R> AsyncWrite for LazyNegotiated<R>
+where
+    R: AsyncWrite,
+{
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        match self.write_pending() {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == IoErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(err) => return Err(err),
+        }
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tokio_core;
+
+    use {listener_select_proto, ProtocolChoiceError};
+    use bytes::Bytes;
+    use futures::{Future, Stream};
+    use self::tokio_core::net::{TcpListener, TcpStream};
+    use self::tokio_core::reactor::Core;
+    use futures::{Async, Poll};
+    use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind, Write};
+    use super::{dialer_select_proto_lazy, MAX_QUEUED_PAYLOAD};
+    use tokio_io::AsyncWrite;
+    use tokio_io::io::{read_exact, write_all};
+
+    // Socket whose write buffer is always full.
+    struct Full;
+    impl Write for Full {
+        fn write(&mut self, _: &[u8]) -> Result<usize, IoError> {
+            Err(IoErrorKind::WouldBlock.into())
+        }
+        fn flush(&mut self) -> Result<(), IoError> {
+            Ok(())
+        }
+    }
+    impl AsyncWrite for Full {
+        fn shutdown(&mut self) -> Poll<(), IoError> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn queued_payload_bounded() {
+        let mut socket = dialer_select_proto_lazy(Cursor::new(Vec::new()), b"/proto").unwrap();
+        let payload = vec![5; MAX_QUEUED_PAYLOAD * 2];
+        assert_eq!(socket.write(&payload).unwrap(), MAX_QUEUED_PAYLOAD);
+        assert_eq!(socket.write(&payload).unwrap(), payload.len());
+    }
+
+    #[test]
+    fn shutdown_not_ready() {
+        let mut socket = dialer_select_proto_lazy(Full, b"/proto").unwrap();
+        assert_eq!(socket.shutdown().unwrap(), Async::NotReady);
+    }
+
+    #[test]
+    fn payload_sent_with_proposal() {
+        let mut core = Core::new().unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e.into())
+            .and_then(move |(connec, _)| {
+                let protos = vec![(Bytes::from("/proto"), <Bytes as PartialEq>::eq, ())];
+                listener_select_proto(connec.unwrap().0, protos.into_iter())
+            })
+            .and_then(|(_, socket)| read_exact(socket, [0; 5]).from_err())
+            .and_then(|(socket, payload)| {
+                assert_eq!(&payload, b"hello");
+                write_all(socket, b"world").from_err()
+            });
+
+        let client = TcpStream::connect(&listener_addr, &core.handle())
+            .from_err()
+            .and_then(|connec| dialer_select_proto_lazy(connec, b"/proto"))
+            .and_then(|socket| {
+                assert!(!socket.is_confirmed());
+                write_all(socket, b"hello").from_err()
+            })
+            .and_then(|(socket, _)| read_exact(socket, [0; 5]).from_err())
+            .map(|(socket, response)| {
+                assert!(socket.is_confirmed());
+                assert_eq!(&response, b"world");
+            });
+
+        core.run(server.join(client)).unwrap();
+    }
+
+    #[test]
+    fn refused_protocol() {
+        let mut core = Core::new().unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e.into())
+            .and_then(move |(connec, _)| {
+                let protos = vec![(Bytes::from("/other"), <Bytes as PartialEq>::eq, ())];
+                listener_select_proto(connec.unwrap().0, protos.into_iter())
+            });
+        // The listener is stuck trying to interpret the payload, and is never going to finish.
+        core.handle().spawn(server.map(|_| ()).map_err(|_| ()));
+
+        let client = TcpStream::connect(&listener_addr, &core.handle())
+            .and_then(|connec| {
+                dialer_select_proto_lazy(connec, b"/proto").map_err(|_| panic!())
+            })
+            .and_then(|socket| write_all(socket, b"hello"))
+            .and_then(|(socket, _)| read_exact(socket, [0; 5]));
+
+        let err = core.run(client).err().expect("negotiation must fail");
+        match err.get_ref().and_then(|e| e.downcast_ref::<ProtocolChoiceError>()) {
+            Some(&ProtocolChoiceError::NoProtocolFound) => (),
+            _ => panic!(),
+        }
+    }
+}
@@ -40,6 +40,13 @@
 //! supports, or suggest a protocol. If a protocol is suggested, the listener can either accept (by
 //! answering with the same protocol name) or refuse the choice (by answering "not available").
 //!
+//! When the dialer proposes a single protocol, it can use `dialer_select_proto_lazy` to send the
+//! first bytes of the protocol together with the proposal, instead of waiting one round-trip for
+//! the listener to accept it. The upgrades of `libp2p-core` don't do this yet.
+//!
+//! If both sides opened the connection at the same time and believe they are the dialer, they
+//! can first call `select_role` to decide which of them plays this role.
+//!
 //! ## Examples
 //!
 //! For a dialer:
@@ -126,12 +133,16 @@ extern crate varint;
 
 mod dialer_select;
 mod error;
+mod lazy;
 mod length_delimited;
 mod listener_select;
+mod simultaneous_open;
 mod tests;
 
 pub mod protocol;
 
 pub use self::dialer_select::dialer_select_proto;
 pub use self::error::ProtocolChoiceError;
+pub use self::lazy::{dialer_select_proto_lazy, LazyNegotiated};
 pub use self::listener_select::listener_select_proto;
+pub use self::simultaneous_open::{select_role, Role, SIMULTANEOUS_CONNECT_PREFIX};
@@ -26,7 +26,8 @@ mod dialer;
 mod error;
 mod listener;
 
-const MULTISTREAM_PROTOCOL_WITH_LF: &'static [u8] = b"/multistream/1.0.0\n";
+/// Handshake message of the protocol, sent by both sides before anything else.
+pub const MULTISTREAM_PROTOCOL_WITH_LF: &'static [u8] = b"/multistream/1.0.0\n";
 
 pub use self::dialer::Dialer;
 pub use self::error::MultistreamSelectError;
@@ -0,0 +1,198 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Contains the `select_role` code, which resolves the role conflict of a simultaneous open.
+//!
+//! Multistream-select is asymmetric: the dialer proposes protocols and the listener accepts or
+//! refuses them. When two nodes open a connection to each other at the same moment, for example
+//! with a TCP simultaneous open, both sides believe that they are the dialer and the negotiation
+//! can't succeed. Before negotiating anything, both sides can then call `select_role` in order to
+//! determine which of them plays the role of the dialer.
+//!
+//! Each side proposes `/libp2p/simultaneous-connect/` followed by a tie-breaker encoded in
+//! hexadecimal, and receives the proposal of the remote in exchange. The side whose tie-breaker
+//! is the greatest becomes the initiator. Using the peer IDs of the nodes as tie-breakers makes
+//! the outcome deterministic.
+
+use ProtocolChoiceError;
+use bytes::{Bytes, BytesMut};
+use futures::{Future, Sink, Stream};
+use protocol::Dialer;
+use protocol::DialerToListenerMessage;
+use protocol::ListenerToDialerMessage;
+use std::cmp::Ordering;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Prefix of the messages exchanged by `select_role`.
+pub const SIMULTANEOUS_CONNECT_PREFIX: &'static [u8] = b"/libp2p/simultaneous-connect/";
+
+/// Role that a node plays for the rest of the negotiation, as determined by `select_role`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Role {
+    /// The node must use `dialer_select_proto`.
+    Initiator,
+    /// The node must use `listener_select_proto`.
+    Responder,
+}
+
+/// Determines which side of a simultaneous open plays the role of the dialer.
+///
+/// Both sides must call this function, with different tie-breakers. On success, returns the role
+/// of the local node and the socket, on which a new negotiation can start. Fails with
+/// `ProtocolChoiceError::RoleConflict` if both tie-breakers are equal.
+// TODO: remove the Box once -> impl Trait lands
+pub fn select_role<'a, R>(
+    inner: R,
+    tie_breaker: &[u8],
+) -> Box<Future<Item = (Role, R), Error = ProtocolChoiceError> + 'a>
+where
+    R: AsyncRead + AsyncWrite + 'a,
+{
+    let local = tie_breaker.to_owned();
+    let proposal = DialerToListenerMessage::ProtocolRequest {
+        name: encode_proposal(tie_breaker),
+    };
+
+    let future = Dialer::new(inner)
+        .from_err()
+        .and_then(move |dialer| dialer.send(proposal).from_err())
+        .and_then(|dialer| dialer.into_future().map_err(|(e, _)| e.into()))
+        .and_then(move |(msg, dialer)| {
+            // The proposal of the remote is parsed as an acknowledgement by the `Dialer`.
+            let remote = match msg {
+                Some(ListenerToDialerMessage::ProtocolAck { name }) => decode_proposal(&name)
+                    .ok_or(ProtocolChoiceError::UnexpectedMessage)?,
+                _ => return Err(ProtocolChoiceError::UnexpectedMessage),
+            };
+
+            let role = match local.cmp(&remote) {
+                Ordering::Greater => Role::Initiator,
+                Ordering::Less => Role::Responder,
+                Ordering::Equal => return Err(ProtocolChoiceError::RoleConflict),
+            };
+
+            Ok((role, dialer.into_inner()))
+        });
+
+    // The "Rust doesn't have impl Trait yet" tax.
+    Box::new(future)
+}
+
+fn encode_proposal(tie_breaker: &[u8]) -> Bytes {
+    const HEX: &'static [u8] = b"0123456789abcdef";
+    let mut name = BytesMut::from(SIMULTANEOUS_CONNECT_PREFIX);
+    for byte in tie_breaker {
+        name.extend_from_slice(&[HEX[(byte >> 4) as usize], HEX[(byte & 0xf) as usize]]);
+    }
+    name.freeze()
+}
+
+fn decode_proposal(name: &[u8]) -> Option<Vec<u8>> {
+    if !name.starts_with(SIMULTANEOUS_CONNECT_PREFIX) {
+        return None;
+    }
+
+    let hex = &name[SIMULTANEOUS_CONNECT_PREFIX.len()..];
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    hex.chunks(2)
+        .map(|pair| Some((digit(pair[0])? << 4) | digit(pair[1])?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tokio_core;
+    use {dialer_select_proto, listener_select_proto, ProtocolChoiceError};
+    use bytes::Bytes;
+    use futures::{Future, Stream};
+    use self::tokio_core::net::{TcpListener, TcpStream};
+    use self::tokio_core::reactor::Core;
+    use super::{decode_proposal, encode_proposal, select_role, Role};
+
+    #[test]
+    fn proposal_round_trip() {
+        let proposal = encode_proposal(&[0x00, 0x7f, 0xa5, 0xff]);
+        assert_eq!(&proposal[..], &b"/libp2p/simultaneous-connect/007fa5ff"[..]);
+        assert_eq!(decode_proposal(&proposal), Some(vec![0x00, 0x7f, 0xa5, 0xff]));
+        assert_eq!(decode_proposal(b"/libp2p/simultaneous-connect/7"), None);
+        assert_eq!(decode_proposal(b"/echo/1.0.0"), None);
+    }
+
+    #[test]
+    fn roles_then_negotiation() {
+        let mut core = Core::new().unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        // The side that accepted the TCP connection has the greatest tie-breaker, and therefore
+        // ends up playing the role of the dialer.
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e.into())
+            .and_then(move |(connec, _)| select_role(connec.unwrap().0, b"\x02"))
+            .and_then(|(role, socket)| {
+                assert_eq!(role, Role::Initiator);
+                let protos = vec![(Bytes::from("/proto"), <Bytes as PartialEq>::eq, 1)];
+                dialer_select_proto(socket, protos.into_iter()).map(|(proto, _)| proto)
+            });
+
+        let client = TcpStream::connect(&listener_addr, &core.handle())
+            .from_err()
+            .and_then(move |connec| select_role(connec, b"\x01"))
+            .and_then(|(role, socket)| {
+                assert_eq!(role, Role::Responder);
+                let protos = vec![(Bytes::from("/proto"), <Bytes as PartialEq>::eq, 2)];
+                listener_select_proto(socket, protos.into_iter()).map(|(proto, _)| proto)
+            });
+
+        let (server_proto, client_proto) = core.run(server.join(client)).unwrap();
+        assert_eq!(server_proto, 1);
+        assert_eq!(client_proto, 2);
+    }
+
+    #[test]
+    fn equal_tie_breakers_fail() {
+        let mut core = Core::new().unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e.into())
+            .and_then(move |(connec, _)| select_role(connec.unwrap().0, b"same"));
+
+        let client = TcpStream::connect(&listener_addr, &core.handle())
+            .from_err()
+            .and_then(move |connec| select_role(connec, b"same"));
+
+        match core.run(server.join(client)) {
+            Err(ProtocolChoiceError::RoleConflict) => (),
+            _ => panic!(),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Codec for frames that contain a value encoded with CBOR.
+
+use bytes::BytesMut;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_cbor;
+use std::io;
+use std::marker::PhantomData;
+use tokio_io::codec::{Decoder, Encoder};
+use VarintCodec;
+
+/// Codec whose frames contain a value encoded with CBOR, and are prefixed with their length as a
+/// varint.
+///
+/// `In` is the type of the values that are decoded, and `Out` the type of the values that are
+/// encoded. Decoding produces an error of kind `InvalidData` if a frame isn't a valid `In`, or if
+/// it is larger than the limit passed to `with_max_len`, in which case the error wraps a
+/// `FrameTooLarge`.
+#[derive(Debug)]
+pub struct CborCodec<In, Out = In> {
+    framing: VarintCodec<Vec<u8>>,
+    marker: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> CborCodec<In, Out> {
+    /// Builds a codec that refuses to decode frames larger than `max_len` bytes.
+    #[inline]
+    pub fn with_max_len(max_len: usize) -> CborCodec<In, Out> {
+        CborCodec {
+            framing: VarintCodec::with_max_len(max_len),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> Default for CborCodec<In, Out> {
+    #[inline]
+    fn default() -> CborCodec<In, Out> {
+        CborCodec {
+            framing: VarintCodec::default(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> Decoder for CborCodec<In, Out>
+where
+    In: DeserializeOwned,
+{
+    type Item = In;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.framing.decode(src)? {
+            Some(frame) => serde_cbor::from_slice(&frame)
+                .map(Some)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<In, Out> Encoder for CborCodec<In, Out>
+where
+    Out: Serialize,
+{
+    type Item = Out;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Out, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let bytes = serde_cbor::to_vec(&item)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        self.framing.encode(bytes, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CborCodec;
+    use bytes::BytesMut;
+    use std::io;
+    use tokio_io::codec::{Decoder, Encoder};
+
+    #[test]
+    fn round_trip() {
+        let mut codec = CborCodec::<(u32, String)>::default();
+        let mut buf = BytesMut::new();
+        codec.encode((5, "hello".to_owned()), &mut buf).unwrap();
+
+        // Incomplete frames aren't decoded.
+        let mut partial = BytesMut::from(&buf[..buf.len() - 1]);
+        let mut other = CborCodec::<(u32, String)>::default();
+        assert!(other.decode(&mut partial).unwrap().is_none());
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some((5, "hello".to_owned())));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn invalid_value() {
+        let mut codec = CborCodec::<String, u32>::default();
+        let mut buf = BytesMut::new();
+        codec.encode(5, &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Codec for frames that all have the same size.
+
+use bytes::{BufMut, BytesMut, IntoBuf};
+use std::io;
+use std::marker::PhantomData;
+use tokio_io::codec::{Decoder, Encoder};
+
+/// Codec whose frames all have the same size, and therefore don't have any prefix.
+///
+/// Encoding a frame of another size produces an error of kind `InvalidInput`.
+#[derive(Debug)]
+pub struct FixedSizeCodec<W> {
+    frame_len: usize,
+    marker: PhantomData<W>,
+}
+
+impl<T> FixedSizeCodec<T> {
+    /// Builds a codec whose frames are `frame_len` bytes long.
+    #[inline]
+    pub fn new(frame_len: usize) -> FixedSizeCodec<T> {
+        FixedSizeCodec {
+            frame_len: frame_len,
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the size of the frames.
+    #[inline]
+    pub fn frame_len(&self) -> usize {
+        self.frame_len
+    }
+}
+
+impl<T> Decoder for FixedSizeCodec<T> {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    #[inline]
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() >= self.frame_len {
+            Ok(Some(src.split_to(self.frame_len)))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<D> Encoder for FixedSizeCodec<D>
+where
+    D: IntoBuf + AsRef<[u8]>,
+{
+    type Item = D;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: D, dst: &mut BytesMut) -> Result<(), io::Error> {
+        if item.as_ref().len() != self.frame_len {
+            let msg = format!(
+                "frame of {} bytes instead of {} bytes",
+                item.as_ref().len(),
+                self.frame_len
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+
+        dst.reserve(self.frame_len);
+        dst.put(item);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FixedSizeCodec;
+    use bytes::BytesMut;
+    use futures::{Future, Stream};
+    use std::io;
+    use tokio_io::codec::{Encoder, FramedRead};
+
+    #[test]
+    fn frames_split() {
+        let codec = FixedSizeCodec::<Vec<u8>>::new(2);
+        let frames = FramedRead::new(&[1, 2, 3, 4][..], codec).collect().wait().unwrap();
+        assert_eq!(frames, vec![&[1, 2][..], &[3, 4][..]]);
+    }
+
+    #[test]
+    fn wrong_size_refused() {
+        let mut codec = FixedSizeCodec::<Vec<u8>>::new(2);
+        let err = codec.encode(vec![1, 2, 3], &mut BytesMut::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
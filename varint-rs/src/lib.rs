@@ -21,6 +21,12 @@
 #![warn(missing_docs)]
 
 //! Encoding and decoding state machines for protobuf varints
+//!
+//! Also contains codecs for custom protocols: `VarintCodec` and `U32Codec` for frames prefixed
+//! with their length, `FixedSizeCodec` for frames of a constant size, and `CborCodec` for frames
+//! that contain a value encoded with CBOR. All of them produce `io::Error`s, and the ones whose
+//! frames have a variable length accept a maximum length. `CborCodec` requires the `cbor`
+//! feature.
 
 // TODO: Non-allocating `BigUint`?
 extern crate bytes;
@@ -29,6 +35,10 @@ extern crate error_chain;
 extern crate futures;
 extern crate num_bigint;
 extern crate num_traits;
+#[cfg(feature = "cbor")]
+extern crate serde;
+#[cfg(feature = "cbor")]
+extern crate serde_cbor;
 extern crate tokio_io;
 
 use bytes::{BufMut, Bytes, BytesMut, IntoBuf};
@@ -37,6 +47,8 @@ use num_bigint::BigUint;
 use num_traits::ToPrimitive;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::{Decoder, Encoder};
+use std::error::Error as StdError;
+use std::fmt;
 use std::io;
 use std::io::prelude::*;
 use std::marker::PhantomData;
@@ -62,6 +74,15 @@ mod errors {
 }
 
 pub use errors::{Error, ErrorKind};
+#[cfg(feature = "cbor")]
+pub use cbor::CborCodec;
+pub use fixed_size::FixedSizeCodec;
+pub use u32_codec::U32Codec;
+
+#[cfg(feature = "cbor")]
+mod cbor;
+mod fixed_size;
+mod u32_codec;
 
 const USABLE_BITS_PER_BYTE: usize = 7;
 
@@ -404,22 +425,63 @@ impl<T: Default + DecoderHelper> Decoder for VarintDecoder<T> {
     }
 }
 
+/// Codec whose frames are prefixed with their length, as a varint.
 #[derive(Debug)]
 pub struct VarintCodec<W> {
     inner: VarintCodecInner,
+    max_len: Option<usize>,
     marker: PhantomData<W>,
 }
 
+impl<T> VarintCodec<T> {
+    /// Builds a codec that refuses to decode frames larger than `max_len` bytes.
+    ///
+    /// When the length prefix of a frame is larger than `max_len`, decoding produces an error of
+    /// kind `InvalidData` that wraps a `FrameTooLarge`. This prevents a remote from making us
+    /// buffer an arbitrary amount of data.
+    #[inline]
+    pub fn with_max_len(max_len: usize) -> VarintCodec<T> {
+        VarintCodec {
+            inner: VarintCodecInner::WaitingForLen(VarintDecoder::default()),
+            max_len: Some(max_len),
+            marker: PhantomData,
+        }
+    }
+}
+
 impl<T> Default for VarintCodec<T> {
     #[inline]
     fn default() -> VarintCodec<T> {
         VarintCodec {
             inner: VarintCodecInner::WaitingForLen(VarintDecoder::default()),
+            max_len: None,
             marker: PhantomData,
         }
     }
 }
 
+/// Error produced by a `VarintCodec` when a frame is larger than the maximum allowed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameTooLarge {
+    /// Length announced by the frame.
+    pub len: usize,
+    /// Maximum length that the codec accepts.
+    pub max: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "frame of {} bytes exceeds the maximum of {} bytes", self.len, self.max)
+    }
+}
+
+impl StdError for FrameTooLarge {
+    #[inline]
+    fn description(&self) -> &str {
+        "frame too large"
+    }
+}
+
 #[derive(Debug)]
 enum VarintCodecInner {
     WaitingForLen(VarintDecoder<usize>),
@@ -449,6 +511,14 @@ impl<T> Decoder for VarintCodec<T> {
                         return Ok(None);
                     }
                     Some(len) => {
+                        if let Some(max) = self.max_len {
+                            if len > max {
+                                self.inner =
+                                    VarintCodecInner::WaitingForLen(VarintDecoder::default());
+                                let err = FrameTooLarge { len: len, max: max };
+                                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                            }
+                        }
                         self.inner = VarintCodecInner::WaitingForData(len);
                     }
                 },
@@ -509,7 +579,7 @@ pub fn encode<T: EncoderHelper + Bits>(input: T) -> Bytes {
 
 #[cfg(test)]
 mod tests {
-    use super::{decode, EncoderState, VarintDecoder};
+    use super::{decode, EncoderState, FrameTooLarge, VarintCodec, VarintDecoder};
     use tokio_io::codec::FramedRead;
     use num_bigint::BigUint;
     use futures::{Future, Stream};
@@ -647,4 +717,26 @@ mod tests {
             .wait()
             .unwrap();
     }
+
+    #[test]
+    fn frame_too_large() {
+        let codec = VarintCodec::<Vec<u8>>::with_max_len(2);
+        let result = FramedRead::new(&[3, 1, 2, 3][..], codec)
+            .into_future()
+            .map_err(|(err, _)| err)
+            .wait();
+
+        let err = result.err().unwrap();
+        assert_eq!(
+            err.get_ref().and_then(|e| e.downcast_ref::<FrameTooLarge>()),
+            Some(&FrameTooLarge { len: 3, max: 2 })
+        );
+
+        let codec = VarintCodec::<Vec<u8>>::with_max_len(3);
+        let frames = FramedRead::new(&[3, 1, 2, 3][..], codec)
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(frames, vec![&[1, 2, 3][..]]);
+    }
 }
@@ -0,0 +1,161 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Codec for frames prefixed with their length, as a big-endian 32-bit integer.
+
+use bytes::{BufMut, BytesMut, IntoBuf};
+use std::io;
+use std::marker::PhantomData;
+use std::u32;
+use tokio_io::codec::{Decoder, Encoder};
+use FrameTooLarge;
+
+// Number of bytes of the length prefix.
+const PREFIX_LEN: usize = 4;
+
+/// Codec whose frames are prefixed with their length, as a big-endian `u32`.
+///
+/// Same as `VarintCodec`, but for protocols that use a fixed-size prefix. Decoding produces an
+/// error of kind `InvalidData` that wraps a `FrameTooLarge` if a frame is larger than the limit
+/// passed to `with_max_len`.
+#[derive(Debug)]
+pub struct U32Codec<W> {
+    // Length of the frame being received, if its prefix has been decoded.
+    pending_len: Option<usize>,
+    max_len: Option<usize>,
+    marker: PhantomData<W>,
+}
+
+impl<T> U32Codec<T> {
+    /// Builds a codec that refuses to decode frames larger than `max_len` bytes.
+    #[inline]
+    pub fn with_max_len(max_len: usize) -> U32Codec<T> {
+        U32Codec {
+            pending_len: None,
+            max_len: Some(max_len),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Default for U32Codec<T> {
+    #[inline]
+    fn default() -> U32Codec<T> {
+        U32Codec {
+            pending_len: None,
+            max_len: None,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Decoder for U32Codec<T> {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let len = match self.pending_len {
+            Some(len) => len,
+            None => {
+                if src.len() < PREFIX_LEN {
+                    return Ok(None);
+                }
+
+                let prefix = src.split_to(PREFIX_LEN);
+                let len = prefix.iter().fold(0usize, |len, &byte| (len << 8) | byte as usize);
+                if let Some(max) = self.max_len {
+                    if len > max {
+                        let err = FrameTooLarge { len: len, max: max };
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                    }
+                }
+                len
+            }
+        };
+
+        if src.len() >= len {
+            self.pending_len = None;
+            Ok(Some(src.split_to(len)))
+        } else {
+            self.pending_len = Some(len);
+            Ok(None)
+        }
+    }
+}
+
+impl<D> Encoder for U32Codec<D>
+where
+    D: IntoBuf + AsRef<[u8]>,
+{
+    type Item = D;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: D, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let len = item.as_ref().len();
+        if len > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame is too large for a 32-bit length prefix",
+            ));
+        }
+
+        let prefix = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        dst.reserve(PREFIX_LEN + len);
+        dst.put_slice(&prefix);
+        dst.put(item);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::U32Codec;
+    use futures::{Future, Stream};
+    use tokio_io::codec::{Encoder, FramedRead};
+    use FrameTooLarge;
+    use bytes::BytesMut;
+
+    #[test]
+    fn round_trip() {
+        let mut encoded = BytesMut::new();
+        let mut codec = U32Codec::<Vec<u8>>::default();
+        codec.encode(vec![1, 2, 3], &mut encoded).unwrap();
+        codec.encode(vec![], &mut encoded).unwrap();
+        assert_eq!(&encoded[..], &[0, 0, 0, 3, 1, 2, 3, 0, 0, 0, 0][..]);
+
+        let frames = FramedRead::new(&encoded[..], codec).collect().wait().unwrap();
+        assert_eq!(frames, vec![&[1, 2, 3][..], &[][..]]);
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let codec = U32Codec::<Vec<u8>>::with_max_len(2);
+        let err = FramedRead::new(&[0, 0, 0, 3, 1, 2, 3][..], codec)
+            .into_future()
+            .map_err(|(err, _)| err)
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.get_ref().and_then(|e| e.downcast_ref::<FrameTooLarge>()),
+            Some(&FrameTooLarge { len: 3, max: 2 })
+        );
+    }
+}